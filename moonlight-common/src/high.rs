@@ -4,7 +4,7 @@
 
 use std::{
     io,
-    net::{Ipv4Addr, SocketAddrV4},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
 };
 
 use pem::Pem;
@@ -23,6 +23,13 @@ use crate::{
 };
 
 pub async fn broadcast_magic_packet(mac: MacAddress) -> Result<(), io::Error> {
+    let broadcast = SocketAddrV4::new(Ipv4Addr::new(255, 255, 255, 255), 9);
+
+    send_magic_packet(mac, broadcast.into()).await
+}
+
+/// Sends the magic packet to a specific address, e.g. the directed broadcast address of another subnet
+pub async fn send_magic_packet(mac: MacAddress, address: SocketAddr) -> Result<(), io::Error> {
     let mut magic_packet = [0u8; 6 * 17];
 
     magic_packet[0..6].copy_from_slice(&[255, 255, 255, 255, 255, 255]);
@@ -30,12 +37,13 @@ pub async fn broadcast_magic_packet(mac: MacAddress) -> Result<(), io::Error> {
         magic_packet[(i * 6)..((i + 1) * 6)].copy_from_slice(&mac.to_bytes());
     }
 
-    let broadcast = SocketAddrV4::new(Ipv4Addr::new(255, 255, 255, 255), 9);
-
-    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    let socket = match address {
+        SocketAddr::V4(_) => UdpSocket::bind("0.0.0.0:0").await?,
+        SocketAddr::V6(_) => UdpSocket::bind("[::]:0").await?,
+    };
 
     socket.set_broadcast(true)?;
-    socket.send_to(&magic_packet, &broadcast).await?;
+    socket.send_to(&magic_packet, &address).await?;
 
     Ok(())
 }
@@ -75,6 +83,7 @@ pub struct MoonlightHost<Client> {
     client: Client,
     address: String,
     http_port: u16,
+    https_port_override: Option<u16>,
     tried_connect: bool,
     cache_info: Option<HostInfo>,
     // Paired
@@ -104,6 +113,7 @@ where
             client_unique_id: unique_id.unwrap_or_else(|| DEFAULT_UNIQUE_ID.to_string()),
             address,
             http_port,
+            https_port_override: None,
             tried_connect: false,
             cache_info: None,
             paired: None,
@@ -118,7 +128,13 @@ where
     }
 
     pub fn http_address(&self) -> String {
-        format!("{}:{}", self.address, self.http_port)
+        Self::build_hostport(&self.address, self.http_port)
+    }
+
+    /// Uses this https port instead of the one reported by the host, e.g. for hosts behind a NAT
+    pub fn set_https_port_override(&mut self, https_port: Option<u16>) {
+        self.https_port_override = https_port;
+        self.clear_cache();
     }
 
     async fn host_info(&mut self) -> Result<&HostInfo, HostError<C::Error>> {
@@ -138,7 +154,11 @@ where
                 uuid: Uuid::new_v4(),
             };
 
-            let info = host_info(&mut self.client, false, &http_address, Some(client_info)).await?;
+            let mut info =
+                host_info(&mut self.client, false, &http_address, Some(client_info)).await?;
+            if let Some(https_port_override) = self.https_port_override {
+                info.https_port = https_port_override;
+            }
 
             https_port = Some(info.https_port);
 
@@ -154,8 +174,11 @@ where
                 unique_id: &self.client_unique_id,
                 uuid: Uuid::new_v4(),
             };
-            self.cache_info =
-                Some(host_info(&mut self.client, true, &https_address, Some(client_info)).await?);
+            let mut info =
+                host_info(&mut self.client, true, &https_address, Some(client_info)).await?;
+            info.https_port = https_port;
+
+            self.cache_info = Some(info);
         }
 
         let Some(info) = &self.cache_info else {
@@ -177,8 +200,16 @@ where
         Ok(info.https_port)
     }
 
+    /// IPv6 addresses must be bracketed in front of a port
+    fn build_hostport(address: &str, port: u16) -> String {
+        if address.contains(':') && !address.starts_with('[') {
+            format!("[{address}]:{port}")
+        } else {
+            format!("{address}:{port}")
+        }
+    }
     fn build_https_address(address: &str, https_port: u16) -> String {
-        format!("{address}:{https_port}")
+        Self::build_hostport(address, https_port)
     }
     pub async fn https_address(&mut self) -> Result<String, HostError<C::Error>> {
         let https_port = self.https_port().await?;
@@ -286,7 +317,10 @@ where
             uuid: Uuid::new_v4(),
         };
 
-        let info = host_info(&mut self.client, true, &https_address, Some(client_info)).await?;
+        let mut info = host_info(&mut self.client, true, &https_address, Some(client_info)).await?;
+        if let Some(https_port_override) = self.https_port_override {
+            info.https_port = https_port_override;
+        }
 
         let pair_status = info.pair_status;
         self.cache_info = Some(info);
@@ -349,6 +383,7 @@ where
             &device_name,
             server_version,
             pin,
+            |_| {},
         )
         .await?;
 
@@ -125,6 +125,14 @@ fn xml_root_node<'doc, C>(doc: &'doc Document) -> Result<Node<'doc, 'doc>, ApiEr
     Ok(root)
 }
 
+/// A resolution and refresh rate the host can stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisplayMode {
+    pub width: u32,
+    pub height: u32,
+    pub refresh_rate: u32,
+}
+
 #[derive(Debug, Clone)]
 pub struct HostInfo {
     pub host_name: String,
@@ -141,6 +149,38 @@ pub struct HostInfo {
     pub current_game: u32,
     pub state_string: String,
     pub state: ServerState,
+    /// Empty if the host doesn't report its display modes, e.g. Sunshine
+    pub display_modes: Vec<DisplayMode>,
+}
+
+fn display_mode<'doc, C: RequestClient>(
+    node: Node<'doc, 'doc>,
+) -> Result<DisplayMode, ApiError<C::Error>> {
+    Ok(DisplayMode {
+        width: xml_child_text::<C>(node, "Width")?.parse()?,
+        height: xml_child_text::<C>(node, "Height")?.parse()?,
+        refresh_rate: xml_child_text::<C>(node, "RefreshRate")?.parse()?,
+    })
+}
+
+fn display_modes<'doc, C: RequestClient>(root: Node<'doc, 'doc>) -> Vec<DisplayMode> {
+    let Some(list) = root
+        .children()
+        .find(|node| node.tag_name().name() == "SupportedDisplayMode")
+    else {
+        return Vec::new();
+    };
+
+    list.children()
+        .filter(|node| node.tag_name().name() == "DisplayMode")
+        .filter_map(|node| {
+            let mode = display_mode::<C>(node).ok();
+            if mode.is_none() {
+                warn!("failed to parse a display mode of the host");
+            }
+            mode
+        })
+        .collect()
 }
 
 pub async fn host_info<C: RequestClient>(
@@ -203,6 +243,7 @@ pub async fn host_info<C: RequestClient>(
         current_game: xml_child_text::<C>(root, "currentgame")?.parse()?,
         state: ServerState::from_str(&state_string)?,
         state_string,
+        display_modes: display_modes::<C>(root),
     })
 }
 
@@ -177,6 +177,21 @@ pub struct PairSuccess<C: RequestClient> {
     pub server_certificate: Pem,
 }
 
+/// The stages of the pairing handshake in the order they're executed
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PairStage {
+    /// Exchanging the client and server certificates, this waits until the pin was entered on the host
+    ServerCertificate,
+    /// Sending the encrypted challenge of the client
+    ClientChallenge,
+    /// Answering the challenge of the server and verifying the pin
+    ServerChallenge,
+    /// Sending the signed pairing secret of the client
+    ClientPairingSecret,
+    /// Confirming the pairing over https
+    PairingConfirm,
+}
+
 #[derive(Debug, Error)]
 pub enum PairError<RequestError> {
     #[error("{0}")]
@@ -208,6 +223,7 @@ pub async fn host_pair<C: RequestClient>(
     device_name: &str,
     server_version: ServerVersion,
     pin: PairPin,
+    mut on_stage: impl FnMut(PairStage),
 ) -> Result<PairSuccess<C>, PairError<C::Error>> {
     let client_cert = X509::from_der(client_certificate_pem.contents())?;
     let client_private_key = PKey::private_key_from_der(client_private_key_pem.contents())?;
@@ -225,6 +241,8 @@ pub async fn host_pair<C: RequestClient>(
 
     let aes_key = generate_aes_key(hash_algorithm, salt, pin);
 
+    on_stage(PairStage::ServerCertificate);
+
     let server_response1 = host_pair1(
         client,
         http_address,
@@ -253,6 +271,8 @@ pub async fn host_pair<C: RequestClient>(
 
     let encrypted_challenge = encrypt_aes(&aes_key, &challenge)?;
 
+    on_stage(PairStage::ClientChallenge);
+
     let server_response2 = host_pair2(
         client,
         http_address,
@@ -296,6 +316,8 @@ pub async fn host_pair<C: RequestClient>(
         &challenge_response_hash[0..hash_algorithm.hash_len()],
     )?;
 
+    on_stage(PairStage::ServerChallenge);
+
     let server_response3 = host_pair3(
         client,
         http_address,
@@ -351,6 +373,8 @@ pub async fn host_pair<C: RequestClient>(
     client_pairing_secret.extend_from_slice(&client_secret);
     client_pairing_secret.extend_from_slice(&sign_data(&client_private_key, &client_secret)?);
 
+    on_stage(PairStage::ClientPairingSecret);
+
     let server_response4 = host_pair4(
         client,
         http_address,
@@ -368,6 +392,8 @@ pub async fn host_pair<C: RequestClient>(
         return Err(PairError::Failed);
     }
 
+    on_stage(PairStage::PairingConfirm);
+
     // Required for us to show as paired
     let mut new_client = C::with_certificates(
         client_private_key_pem,
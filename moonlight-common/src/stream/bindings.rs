@@ -504,7 +504,7 @@ pub enum MouseButtonAction {
 }
 
 #[repr(i32)]
-#[derive(Debug, Clone, Copy, FromPrimitive)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, FromPrimitive)]
 pub enum MouseButton {
     Left = BUTTON_LEFT as i32,
     Middle = BUTTON_MIDDLE as i32,
@@ -0,0 +1,49 @@
+//! Joins a room as a spectator and prints what happens in it, the video and audio are skipped.
+//!
+//! `cargo run -p moonlight-web-client --example join_room -- http://localhost:8080 <room id>`
+
+use std::{env, process::exit};
+
+use moonlight_web_client::{
+    Client, ClientError, StreamEvent,
+    api_bindings::{GuestStreamQuery, StreamServerMessage},
+};
+
+#[tokio::main]
+async fn main() -> Result<(), ClientError> {
+    let [_, url, room_id] = &env::args().collect::<Vec<_>>()[..] else {
+        eprintln!("usage: join_room <url> <room id>");
+        exit(1);
+    };
+
+    let client = Client::new(url)?;
+    let mut session = client
+        .guest_stream(&GuestStreamQuery {
+            room_id: room_id.clone(),
+            player_name: Some("join_room example".to_string()),
+            discord_auth: false,
+            spectate: true,
+            invite: None,
+        })
+        .await?;
+
+    let joined = session.joined().await?;
+    println!(
+        "Joined {} streaming {} with {} players",
+        joined.room.room_id,
+        joined.room.app_name,
+        joined.room.players.len()
+    );
+
+    while let Some(event) = session.next().await {
+        match event? {
+            StreamEvent::Message(StreamServerMessage::RoomClosed) => {
+                println!("The host closed the room");
+            }
+            StreamEvent::Message(message) => println!("{message:?}"),
+            StreamEvent::Binary(_) => {}
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,42 @@
+//! Lists the hosts of a user and their apps.
+//!
+//! `cargo run -p moonlight-web-client --example list_hosts -- http://localhost:8080 <user> <password>`
+
+use std::{env, process::exit};
+
+use moonlight_web_client::{Client, ClientError};
+
+#[tokio::main]
+async fn main() -> Result<(), ClientError> {
+    let [_, url, name, password] = &env::args().collect::<Vec<_>>()[..] else {
+        eprintln!("usage: list_hosts <url> <user> <password>");
+        exit(1);
+    };
+
+    let mut client = Client::new(url)?;
+    client.login(name, password).await?;
+
+    let capabilities = client.capabilities().await?;
+    println!("Web server version {}", capabilities.version);
+
+    let mut hosts = client.hosts().await?;
+    for host in &hosts.initial().hosts {
+        println!(
+            "Cached: {} ({}) {:?}",
+            host.name, host.host_id, host.server_state
+        );
+    }
+
+    // The hosts are sent again once they responded
+    while let Some(host) = hosts.next().await? {
+        println!("{} ({}) {:?}", host.name, host.host_id, host.server_state);
+
+        if host.server_state.is_some() {
+            for app in client.apps(host.host_id).await?.apps {
+                println!("  - {} ({})", app.title, app.app_id);
+            }
+        }
+    }
+
+    client.logout().await
+}
@@ -0,0 +1,547 @@
+//! Typed client of the HTTP and WebSocket api of the web server.
+//!
+//! All requests and responses are the types of [common::api_bindings], so they can't drift apart from the server.
+//! Builds for native targets and, with the `wasm` feature, for wasm32 where the browser handles the session cookie.
+
+use common::api_bindings::{
+    DeleteHostQuery, DeleteUserRequest, DetailedUser, ExportedUser, GetAppImageQuery,
+    GetAppsDetailedResponse, GetAppsQuery, GetAppsResponse, GetAuditLogResponse,
+    GetCapabilitiesResponse, GetHostQuery, GetHostResponse, GetHostSharesQuery,
+    GetHostSharesResponse, GetHostUptimeQuery, GetHostUptimeResponse, GetHostsResponse,
+    GetInactiveUsersQuery, GetMetricsResponse, GetUserQuery, GetUserSettingsQuery,
+    GetUserSettingsResponse, GetUsersExportQuery, GetUsersResponse, GetWakeUpStatusQuery,
+    GetWakeUpStatusResponse, GuestStreamQuery, ImportedUser, PatchHostRequest, PatchUserRequest,
+    PostCancelRequest, PostCancelResponse, PostHostInviteRequest, PostHostInviteResponse,
+    PostHostRequest, PostHostResponse, PostHostUnpairRemoteRequest, PostHostUnpairRemoteResponse,
+    PostImportMoonlightQtRequest, PostImportResponse, PostLoginRequest, PostPairRequest,
+    PostPairResponse1, PostPairResponse2, PostUserRequest, PostUsersImportQuery,
+    PostUsersImportResponse, PostWakeUpRequest, PutHostAppVisibilityRequest, PutHostSharesRequest,
+    PutUserSettingsRequest, RoomInfo, RoomJoinFailure, StreamClientMessage, UndetailedHost,
+    UsersExportHeader,
+};
+use reqwest::{
+    Method, RequestBuilder, Response, StatusCode, Url,
+    header::{HeaderMap, SET_COOKIE},
+};
+use serde::{Serialize, de::DeserializeOwned};
+use thiserror::Error;
+
+mod socket;
+pub mod stream;
+pub mod streamed;
+
+pub use common::api_bindings;
+
+pub use crate::{
+    stream::{Joined, StreamEvent, StreamSession},
+    streamed::StreamedResponse,
+};
+
+/// The name of the cookie the web server stores the session in
+const SESSION_COOKIE: &str = "mlSession";
+
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error("invalid url: {0}")]
+    Url(#[from] url::ParseError),
+    #[error("request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("the server responded with {status}: {body}")]
+    Status { status: StatusCode, body: String },
+    #[error("the login response didn't contain a session")]
+    MissingSession,
+    #[error("the response ended before its first value")]
+    EmptyResponse,
+    #[error("web socket error: {0}")]
+    WebSocket(String),
+    #[error("failed to serialize or deserialize a message: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("failed to join the room: {}", .0.reason())]
+    JoinFailed(RoomJoinFailure),
+    #[error("the stream closed before the room was joined")]
+    StreamClosed,
+}
+
+/// A client of one web server, cloning it shares the connection pool
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    api_url: Url,
+    session: Option<String>,
+}
+
+impl Client {
+    /// The base url is the address the web interface is served at, e.g. `https://example.com/moonlight/`
+    pub fn new(base_url: &str) -> Result<Self, ClientError> {
+        let mut base_url = Url::parse(base_url)?;
+        if !base_url.path().ends_with('/') {
+            base_url.set_path(&format!("{}/", base_url.path()));
+        }
+
+        Ok(Self {
+            http: reqwest::Client::new(),
+            api_url: base_url.join("api/")?,
+            session: None,
+        })
+    }
+
+    /// Uses a session of an earlier login
+    pub fn with_session(mut self, session: String) -> Self {
+        self.session = Some(session);
+        self
+    }
+
+    /// The session token of the login, it can be stored to skip the next login
+    pub fn session(&self) -> Option<&str> {
+        self.session.as_deref()
+    }
+
+    // -- Auth
+
+    pub async fn login(&mut self, name: &str, password: &str) -> Result<(), ClientError> {
+        let response = self
+            .send(
+                self.request(Method::POST, "login")?
+                    .json(&PostLoginRequest {
+                        name: name.to_string(),
+                        password: password.to_string(),
+                    }),
+            )
+            .await?;
+
+        match session_cookie(response.headers()) {
+            Some(session) => self.session = Some(session),
+            // Browsers don't expose the cookie, but send it with every request
+            None if cfg!(target_arch = "wasm32") => {}
+            None => return Err(ClientError::MissingSession),
+        }
+
+        Ok(())
+    }
+
+    pub async fn logout(&mut self) -> Result<(), ClientError> {
+        self.send(self.request(Method::POST, "logout")?).await?;
+        self.session = None;
+
+        Ok(())
+    }
+
+    /// Fails with [StatusCode::UNAUTHORIZED] if the session expired
+    pub async fn authenticate(&self) -> Result<(), ClientError> {
+        self.send(self.request(Method::GET, "authenticate")?)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn capabilities(&self) -> Result<GetCapabilitiesResponse, ClientError> {
+        self.get("capabilities", &()).await
+    }
+
+    // -- User
+
+    /// The logged in user if the query is empty
+    pub async fn user(&self, query: &GetUserQuery) -> Result<DetailedUser, ClientError> {
+        self.get("user", query).await
+    }
+
+    pub async fn user_settings(
+        &self,
+        query: &GetUserSettingsQuery,
+    ) -> Result<GetUserSettingsResponse, ClientError> {
+        self.get("user/settings", query).await
+    }
+
+    pub async fn put_user_settings(
+        &self,
+        request: &PutUserSettingsRequest,
+    ) -> Result<(), ClientError> {
+        self.send_json(Method::PUT, "user/settings", request).await
+    }
+
+    // -- Host
+
+    /// The initial response contains the cached hosts, the updated hosts follow once the hosts responded
+    pub async fn hosts(
+        &self,
+    ) -> Result<StreamedResponse<GetHostsResponse, UndetailedHost>, ClientError> {
+        let response = self.send(self.request(Method::GET, "hosts")?).await?;
+
+        StreamedResponse::new(response).await
+    }
+
+    pub async fn host(&self, host_id: u32) -> Result<GetHostResponse, ClientError> {
+        self.get("host", &GetHostQuery { host_id }).await
+    }
+
+    pub async fn host_uptime(
+        &self,
+        query: &GetHostUptimeQuery,
+    ) -> Result<GetHostUptimeResponse, ClientError> {
+        self.get("host/uptime", query).await
+    }
+
+    pub async fn add_host(
+        &self,
+        request: &PostHostRequest,
+    ) -> Result<PostHostResponse, ClientError> {
+        let response = self
+            .send(self.request(Method::POST, "host")?.json(request))
+            .await?;
+
+        Ok(response.json().await?)
+    }
+
+    pub async fn patch_host(&self, request: &PatchHostRequest) -> Result<(), ClientError> {
+        self.send_json(Method::PATCH, "host", request).await
+    }
+
+    pub async fn delete_host(&self, host_id: u32) -> Result<(), ClientError> {
+        self.send(
+            self.request(Method::DELETE, "host")?
+                .query(&DeleteHostQuery { host_id }),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn unpair_remote_host(
+        &self,
+        host_id: u32,
+    ) -> Result<PostHostUnpairRemoteResponse, ClientError> {
+        let response = self
+            .send(
+                self.request(Method::POST, "host/unpair_remote")?
+                    .json(&PostHostUnpairRemoteRequest { host_id }),
+            )
+            .await?;
+
+        Ok(response.json().await?)
+    }
+
+    pub async fn host_shares(&self, host_id: u32) -> Result<GetHostSharesResponse, ClientError> {
+        self.get("host/shares", &GetHostSharesQuery { host_id })
+            .await
+    }
+
+    pub async fn put_host_shares(&self, request: &PutHostSharesRequest) -> Result<(), ClientError> {
+        self.send_json(Method::PUT, "host/shares", request).await
+    }
+
+    /// The initial response contains the pin, the stages of the pairing follow
+    pub async fn pair(
+        &self,
+        request: &PostPairRequest,
+    ) -> Result<StreamedResponse<PostPairResponse1, PostPairResponse2>, ClientError> {
+        let response = self
+            .send(self.request(Method::POST, "pair")?.json(request))
+            .await?;
+
+        StreamedResponse::new(response).await
+    }
+
+    pub async fn wake_host(&self, host_id: u32) -> Result<(), ClientError> {
+        self.send_json(Method::POST, "host/wake", &PostWakeUpRequest { host_id })
+            .await
+    }
+
+    pub async fn wake_status(&self, host_id: u32) -> Result<GetWakeUpStatusResponse, ClientError> {
+        self.get("host/wake/status", &GetWakeUpStatusQuery { host_id })
+            .await
+    }
+
+    // -- Apps
+
+    pub async fn apps(&self, host_id: u32) -> Result<GetAppsResponse, ClientError> {
+        self.get("apps", &GetAppsQuery { host_id }).await
+    }
+
+    pub async fn apps_detailed(
+        &self,
+        host_id: u32,
+    ) -> Result<GetAppsDetailedResponse, ClientError> {
+        self.get("apps/detailed", &GetAppsQuery { host_id }).await
+    }
+
+    pub async fn put_app_visibility(
+        &self,
+        request: &PutHostAppVisibilityRequest,
+    ) -> Result<(), ClientError> {
+        self.send_json(Method::PUT, "host/apps/visibility", request)
+            .await
+    }
+
+    /// The box art of the app
+    pub async fn app_image(&self, query: &GetAppImageQuery) -> Result<Vec<u8>, ClientError> {
+        let response = self
+            .send(self.request(Method::GET, "app/image")?.query(query))
+            .await?;
+
+        Ok(response.bytes().await?.to_vec())
+    }
+
+    // -- Stream
+
+    pub async fn cancel_host(&self, host_id: u32) -> Result<PostCancelResponse, ClientError> {
+        let response = self
+            .send(
+                self.request(Method::POST, "host/cancel")?
+                    .json(&PostCancelRequest { host_id }),
+            )
+            .await?;
+
+        Ok(response.json().await?)
+    }
+
+    /// A signed link which lets guests join the room until it expires
+    pub async fn create_invite(
+        &self,
+        request: &PostHostInviteRequest,
+    ) -> Result<PostHostInviteResponse, ClientError> {
+        let response = self
+            .send(self.request(Method::POST, "host/invite")?.json(request))
+            .await?;
+
+        Ok(response.json().await?)
+    }
+
+    /// Admins also see who created the rooms
+    pub async fn rooms(&self) -> Result<Vec<RoomInfo>, ClientError> {
+        self.get("rooms", &()).await
+    }
+
+    /// Connects as the logged in user and sends `Init`, `JoinRoom` or `JoinAsSpectator`,
+    /// [StreamSession::joined] waits until the room was joined
+    pub async fn stream(&self, first: &StreamClientMessage) -> Result<StreamSession, ClientError> {
+        let url = self.websocket_url("host/stream", &())?;
+
+        let mut session = StreamSession::connect(url, self.session.as_deref()).await?;
+        session.send(first).await?;
+
+        Ok(session)
+    }
+
+    /// Joins a room without logging in, [StreamSession::joined] waits until the room was joined
+    pub async fn guest_stream(
+        &self,
+        query: &GuestStreamQuery,
+    ) -> Result<StreamSession, ClientError> {
+        let url = self.websocket_url("guest/stream", query)?;
+
+        StreamSession::connect(url, None).await
+    }
+
+    // -- Admin
+
+    pub async fn add_user(&self, request: &PostUserRequest) -> Result<DetailedUser, ClientError> {
+        let response = self
+            .send(self.request(Method::POST, "user")?.json(request))
+            .await?;
+
+        Ok(response.json().await?)
+    }
+
+    pub async fn patch_user(&self, request: &PatchUserRequest) -> Result<(), ClientError> {
+        self.send_json(Method::PATCH, "user", request).await
+    }
+
+    pub async fn delete_user(&self, user_id: u32) -> Result<(), ClientError> {
+        self.send_json(Method::DELETE, "user", &DeleteUserRequest { id: user_id })
+            .await
+    }
+
+    pub async fn users(&self) -> Result<GetUsersResponse, ClientError> {
+        self.get("users", &()).await
+    }
+
+    pub async fn inactive_users(&self, days: u32) -> Result<GetUsersResponse, ClientError> {
+        self.get("users/inactive", &GetInactiveUsersQuery { days })
+            .await
+    }
+
+    pub async fn audit_log(&self) -> Result<GetAuditLogResponse, ClientError> {
+        self.get("audit", &()).await
+    }
+
+    pub async fn metrics(&self) -> Result<GetMetricsResponse, ClientError> {
+        self.get("metrics", &()).await
+    }
+
+    /// Invalidates all invites of rooms
+    pub async fn rotate_invite_secret(&self) -> Result<(), ClientError> {
+        self.send_json(Method::POST, "invite_secret/rotate", &())
+            .await
+    }
+
+    pub async fn import_moonlight_qt(
+        &self,
+        request: &PostImportMoonlightQtRequest,
+    ) -> Result<PostImportResponse, ClientError> {
+        let response = self
+            .send(
+                self.request(Method::POST, "import/moonlight-qt")?
+                    .json(request),
+            )
+            .await?;
+
+        Ok(response.json().await?)
+    }
+
+    /// Exporting the secrets sends the confirmation header the server requires for them
+    pub async fn export_users(
+        &self,
+        query: &GetUsersExportQuery,
+    ) -> Result<StreamedResponse<UsersExportHeader, ExportedUser>, ClientError> {
+        let mut request = self.request(Method::GET, "users/export")?.query(query);
+        if query.include_secrets {
+            request = request.header("X-Confirm-Export-Secrets", "true");
+        }
+        let response = self.send(request).await?;
+
+        StreamedResponse::new(response).await
+    }
+
+    /// The export contains the lines of [Self::export_users], the results of the lines follow the initial response
+    pub async fn import_users(
+        &self,
+        query: &PostUsersImportQuery,
+        export: String,
+    ) -> Result<StreamedResponse<PostUsersImportResponse, ImportedUser>, ClientError> {
+        let response = self
+            .send(
+                self.request(Method::POST, "users/import")?
+                    .query(query)
+                    .header("Content-Type", "application/x-ndjson")
+                    .body(export),
+            )
+            .await?;
+
+        StreamedResponse::new(response).await
+    }
+
+    fn request(&self, method: Method, path: &str) -> Result<RequestBuilder, ClientError> {
+        let mut request = self.http.request(method, self.api_url.join(path)?);
+        if let Some(session) = &self.session {
+            request = request.bearer_auth(session);
+        }
+
+        Ok(request)
+    }
+
+    async fn send(&self, request: RequestBuilder) -> Result<Response, ClientError> {
+        let response = request.send().await?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(ClientError::Status { status, body });
+        }
+
+        Ok(response)
+    }
+
+    async fn get<T>(&self, path: &str, query: &impl Serialize) -> Result<T, ClientError>
+    where
+        T: DeserializeOwned,
+    {
+        let response = self
+            .send(self.request(Method::GET, path)?.query(query))
+            .await?;
+
+        Ok(response.json().await?)
+    }
+
+    /// Sends a request whose response has no body
+    async fn send_json(
+        &self,
+        method: Method,
+        path: &str,
+        request: &impl Serialize,
+    ) -> Result<(), ClientError> {
+        self.send(self.request(method, path)?.json(request)).await?;
+
+        Ok(())
+    }
+
+    fn websocket_url(&self, path: &str, query: &impl Serialize) -> Result<Url, ClientError> {
+        let request = self
+            .http
+            .get(self.api_url.join(path)?)
+            .query(query)
+            .build()?;
+        let mut url = request.url().clone();
+
+        let scheme = if url.scheme() == "https" { "wss" } else { "ws" };
+        // Both are special schemes, so this can't fail
+        let _ = url.set_scheme(scheme);
+
+        Ok(url)
+    }
+}
+
+fn session_cookie(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(SET_COOKIE)
+        .iter()
+        .filter_map(|cookie| cookie.to_str().ok())
+        .find_map(|cookie| {
+            let (name, rest) = cookie.split_once('=')?;
+            let value = rest.split(';').next()?;
+
+            (name.trim() == SESSION_COOKIE).then(|| value.to_string())
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use common::api_bindings::GuestStreamQuery;
+    use reqwest::header::{HeaderMap, HeaderValue, SET_COOKIE};
+
+    use crate::{Client, session_cookie};
+
+    #[test]
+    fn test_urls() {
+        let client = Client::new("https://example.com/moonlight").expect("invalid url");
+        assert_eq!(
+            client.api_url.as_str(),
+            "https://example.com/moonlight/api/"
+        );
+
+        let url = client
+            .websocket_url(
+                "guest/stream",
+                &GuestStreamQuery {
+                    room_id: "ABC123".to_string(),
+                    player_name: Some("Player Two".to_string()),
+                    discord_auth: false,
+                    spectate: false,
+                    invite: None,
+                },
+            )
+            .expect("invalid url");
+        assert_eq!(
+            url.as_str(),
+            "wss://example.com/moonlight/api/guest/stream?room_id=ABC123&player_name=Player+Two&discord_auth=false&spectate=false"
+        );
+
+        let client = Client::new("http://127.0.0.1:8080").expect("invalid url");
+        let url = client
+            .websocket_url("host/stream", &())
+            .expect("invalid url");
+        assert_eq!(url.as_str(), "ws://127.0.0.1:8080/api/host/stream");
+    }
+
+    #[test]
+    fn test_session_cookie() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(session_cookie(&headers), None);
+
+        headers.append(SET_COOKIE, HeaderValue::from_static("other=1; Path=/"));
+        headers.append(
+            SET_COOKIE,
+            HeaderValue::from_static("mlSession=abc.def; Path=/; HttpOnly; SameSite=Strict"),
+        );
+        assert_eq!(session_cookie(&headers).as_deref(), Some("abc.def"));
+    }
+}
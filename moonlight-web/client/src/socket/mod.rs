@@ -0,0 +1,20 @@
+//! The web socket of the platform, tungstenite on native targets and the browser on wasm32.
+
+#[cfg(not(target_arch = "wasm32"))]
+mod native;
+#[cfg(all(target_arch = "wasm32", feature = "wasm"))]
+mod wasm;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use native::Socket;
+#[cfg(all(target_arch = "wasm32", feature = "wasm"))]
+pub use wasm::Socket;
+
+#[cfg(all(target_arch = "wasm32", not(feature = "wasm")))]
+compile_error!("the `wasm` feature is required to build for wasm32");
+
+/// A message of the web socket, pings and pongs are handled by the socket itself
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+}
@@ -0,0 +1,69 @@
+use futures::{SinkExt, StreamExt};
+use reqwest::Url;
+use tokio::net::TcpStream;
+use tokio_tungstenite::{
+    MaybeTlsStream, WebSocketStream, connect_async,
+    tungstenite::{
+        self, Message,
+        client::IntoClientRequest,
+        http::{HeaderValue, header::AUTHORIZATION},
+    },
+};
+
+use crate::{ClientError, socket::Frame};
+
+pub struct Socket {
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl Socket {
+    pub async fn connect(url: Url, session: Option<&str>) -> Result<Self, ClientError> {
+        let mut request = url
+            .as_str()
+            .into_client_request()
+            .map_err(websocket_error)?;
+
+        if let Some(session) = session {
+            let bearer = HeaderValue::from_str(&format!("Bearer {session}"))
+                .map_err(|err| ClientError::WebSocket(err.to_string()))?;
+            request.headers_mut().insert(AUTHORIZATION, bearer);
+        }
+
+        let (socket, _) = connect_async(request).await.map_err(websocket_error)?;
+
+        Ok(Self { socket })
+    }
+
+    pub async fn send_text(&mut self, text: String) -> Result<(), ClientError> {
+        self.socket
+            .send(Message::text(text))
+            .await
+            .map_err(websocket_error)
+    }
+
+    /// None once the socket is closed
+    pub async fn recv(&mut self) -> Option<Result<Frame, ClientError>> {
+        loop {
+            let message = match self.socket.next().await? {
+                Ok(message) => message,
+                Err(err) => return Some(Err(websocket_error(err))),
+            };
+
+            match message {
+                Message::Text(text) => return Some(Ok(Frame::Text(text.as_str().to_string()))),
+                Message::Binary(data) => return Some(Ok(Frame::Binary(data.to_vec()))),
+                Message::Close(_) => return None,
+                // Pings are answered by tungstenite
+                Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => {}
+            }
+        }
+    }
+
+    pub async fn close(&mut self) -> Result<(), ClientError> {
+        self.socket.close(None).await.map_err(websocket_error)
+    }
+}
+
+fn websocket_error(err: tungstenite::Error) -> ClientError {
+    ClientError::WebSocket(err.to_string())
+}
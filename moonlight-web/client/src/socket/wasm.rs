@@ -0,0 +1,43 @@
+use futures::{SinkExt, StreamExt};
+use gloo_net::websocket::{Message, WebSocketError, futures::WebSocket};
+use reqwest::Url;
+
+use crate::{ClientError, socket::Frame};
+
+pub struct Socket {
+    socket: WebSocket,
+}
+
+impl Socket {
+    /// Browsers can't set headers of web sockets, the session cookie authenticates the connection instead
+    pub async fn connect(url: Url, _session: Option<&str>) -> Result<Self, ClientError> {
+        let socket =
+            WebSocket::open(url.as_str()).map_err(|err| ClientError::WebSocket(err.to_string()))?;
+
+        Ok(Self { socket })
+    }
+
+    pub async fn send_text(&mut self, text: String) -> Result<(), ClientError> {
+        self.socket
+            .send(Message::Text(text))
+            .await
+            .map_err(|err| ClientError::WebSocket(err.to_string()))
+    }
+
+    /// None once the socket is closed
+    pub async fn recv(&mut self) -> Option<Result<Frame, ClientError>> {
+        match self.socket.next().await? {
+            Ok(Message::Text(text)) => Some(Ok(Frame::Text(text))),
+            Ok(Message::Bytes(data)) => Some(Ok(Frame::Binary(data))),
+            Err(WebSocketError::ConnectionClose(_)) => None,
+            Err(err) => Some(Err(ClientError::WebSocket(err.to_string()))),
+        }
+    }
+
+    pub async fn close(&mut self) -> Result<(), ClientError> {
+        self.socket
+            .close()
+            .await
+            .map_err(|err| ClientError::WebSocket(err.to_string()))
+    }
+}
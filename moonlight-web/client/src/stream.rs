@@ -0,0 +1,240 @@
+use std::collections::VecDeque;
+
+use common::api_bindings::{PlayerSlot, RoomInfo, StreamClientMessage, StreamServerMessage};
+use futures::{Stream, stream};
+use reqwest::Url;
+
+use crate::{
+    ClientError,
+    socket::{Frame, Socket},
+};
+
+#[derive(Debug)]
+pub enum StreamEvent {
+    Message(StreamServerMessage),
+    /// Video and audio of the web socket transport
+    Binary(Vec<u8>),
+}
+
+/// The room the session joined
+#[derive(Debug)]
+pub struct Joined {
+    pub room: RoomInfo,
+    /// None for spectators
+    pub player_slot: Option<PlayerSlot>,
+}
+
+/// The web socket of a stream, it sends and receives [StreamClientMessage]s and [StreamServerMessage]s
+pub struct StreamSession {
+    socket: Socket,
+    /// Received while waiting until the room was joined
+    pending: VecDeque<StreamEvent>,
+}
+
+impl StreamSession {
+    pub(crate) async fn connect(url: Url, session: Option<&str>) -> Result<Self, ClientError> {
+        let socket = Socket::connect(url, session).await?;
+
+        Ok(Self {
+            socket,
+            pending: VecDeque::new(),
+        })
+    }
+
+    /// Waits until the room was created or joined.
+    /// The messages received before are returned by [Self::next], they tell why the stream closed if it fails with [ClientError::StreamClosed]
+    pub async fn joined(&mut self) -> Result<Joined, ClientError> {
+        loop {
+            let event = self.recv().await.ok_or(ClientError::StreamClosed)??;
+
+            match event {
+                StreamEvent::Message(
+                    StreamServerMessage::RoomCreated { room, player_slot }
+                    | StreamServerMessage::RoomJoined { room, player_slot },
+                ) => {
+                    return Ok(Joined {
+                        room,
+                        player_slot: Some(player_slot),
+                    });
+                }
+                StreamEvent::Message(StreamServerMessage::SpectatorJoined { room }) => {
+                    return Ok(Joined {
+                        room,
+                        player_slot: None,
+                    });
+                }
+                StreamEvent::Message(StreamServerMessage::RoomJoinFailed { kind, .. }) => {
+                    return Err(ClientError::JoinFailed(kind));
+                }
+                event => self.pending.push_back(event),
+            }
+        }
+    }
+
+    pub async fn send(&mut self, message: &StreamClientMessage) -> Result<(), ClientError> {
+        let json = serde_json::to_string(message)?;
+
+        self.socket.send_text(json).await
+    }
+
+    /// None once the server closed the stream
+    pub async fn next(&mut self) -> Option<Result<StreamEvent, ClientError>> {
+        if let Some(event) = self.pending.pop_front() {
+            return Some(Ok(event));
+        }
+
+        self.recv().await
+    }
+
+    /// The received events, the session can't send anymore
+    pub fn into_events(self) -> impl Stream<Item = Result<StreamEvent, ClientError>> {
+        stream::unfold(self, |mut session| async move {
+            let event = session.next().await?;
+
+            Some((event, session))
+        })
+    }
+
+    pub async fn close(&mut self) -> Result<(), ClientError> {
+        self.socket.close().await
+    }
+
+    async fn recv(&mut self) -> Option<Result<StreamEvent, ClientError>> {
+        let event = match self.socket.recv().await? {
+            Ok(Frame::Text(text)) => serde_json::from_str(&text)
+                .map(StreamEvent::Message)
+                .map_err(ClientError::from),
+            Ok(Frame::Binary(data)) => Ok(StreamEvent::Binary(data)),
+            Err(err) => Err(err),
+        };
+
+        Some(event)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use common::api_bindings::{
+        ControllerMode, PlayerSlot, RoomInfo, RoomJoinFailure, StreamServerMessage,
+    };
+    use futures::SinkExt;
+    use reqwest::Url;
+    use tokio::{net::TcpListener, spawn};
+    use tokio_tungstenite::{accept_async, tungstenite::Message};
+
+    use crate::{
+        ClientError,
+        stream::{StreamEvent, StreamSession},
+    };
+
+    fn room() -> RoomInfo {
+        RoomInfo {
+            room_id: "ABC123".to_string(),
+            host_id: 1,
+            app_id: 2,
+            app_name: "Desktop".to_string(),
+            players: Vec::new(),
+            max_players: 4,
+            participants: Vec::new(),
+            spectator_count: 0,
+            max_spectators: 8,
+            controller_mode: ControllerMode::default(),
+            allow_multiple_gamepads_per_peer: false,
+            created_by: None,
+            created_by_name: None,
+            created_at: None,
+            resources: None,
+            revision: 0,
+        }
+    }
+
+    /// Accepts a single web socket which receives the messages and is closed afterwards
+    async fn connect(messages: Vec<StreamServerMessage>) -> StreamSession {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind");
+        let address = listener.local_addr().expect("no local address");
+
+        spawn(async move {
+            let (stream, _) = listener.accept().await.expect("failed to accept");
+            let mut socket = accept_async(stream).await.expect("failed to accept socket");
+
+            for message in messages {
+                let json = serde_json::to_string(&message).expect("failed to serialize");
+                socket
+                    .send(Message::text(json))
+                    .await
+                    .expect("failed to send");
+            }
+            let _ = socket.close(None).await;
+        });
+
+        let url = Url::parse(&format!("ws://{address}/")).expect("invalid url");
+        StreamSession::connect(url, None)
+            .await
+            .expect("failed to connect")
+    }
+
+    #[tokio::test]
+    async fn test_joined() {
+        let mut session = connect(vec![
+            StreamServerMessage::QueuePosition { position: 1 },
+            StreamServerMessage::RoomJoined {
+                room: room(),
+                player_slot: PlayerSlot::PLAYER_2,
+            },
+            StreamServerMessage::QueuePosition { position: 2 },
+        ])
+        .await;
+
+        let joined = session.joined().await.expect("failed to join");
+        assert_eq!(joined.room.room_id, "ABC123");
+        assert_eq!(joined.player_slot, Some(PlayerSlot::PLAYER_2));
+
+        // The messages before and after joining are kept in order
+        assert!(matches!(
+            session.next().await,
+            Some(Ok(StreamEvent::Message(
+                StreamServerMessage::QueuePosition { position: 1 }
+            )))
+        ));
+        assert!(matches!(
+            session.next().await,
+            Some(Ok(StreamEvent::Message(
+                StreamServerMessage::QueuePosition { position: 2 }
+            )))
+        ));
+        assert!(session.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_joined_as_spectator() {
+        let mut session =
+            connect(vec![StreamServerMessage::SpectatorJoined { room: room() }]).await;
+
+        let joined = session.joined().await.expect("failed to join");
+        assert_eq!(joined.player_slot, None);
+    }
+
+    #[tokio::test]
+    async fn test_join_failed() {
+        let mut session = connect(vec![RoomJoinFailure::Full.into()]).await;
+        assert!(matches!(
+            session.joined().await,
+            Err(ClientError::JoinFailed(RoomJoinFailure::Full))
+        ));
+
+        // Closed before the room was joined
+        let mut session = connect(vec![StreamServerMessage::QueuePosition { position: 1 }]).await;
+        assert!(matches!(
+            session.joined().await,
+            Err(ClientError::StreamClosed)
+        ));
+        assert!(matches!(
+            session.next().await,
+            Some(Ok(StreamEvent::Message(
+                StreamServerMessage::QueuePosition { position: 1 }
+            )))
+        ));
+    }
+}
@@ -0,0 +1,104 @@
+use std::marker::PhantomData;
+
+use reqwest::Response;
+use serde::de::DeserializeOwned;
+
+use crate::ClientError;
+
+/// A response the server sends line by line: first the initial value, then the others as they become available
+pub struct StreamedResponse<Initial, Other> {
+    initial: Initial,
+    response: Response,
+    buffer: Vec<u8>,
+    _other: PhantomData<Other>,
+}
+
+impl<Initial, Other> StreamedResponse<Initial, Other>
+where
+    Initial: DeserializeOwned,
+    Other: DeserializeOwned,
+{
+    pub(crate) async fn new(mut response: Response) -> Result<Self, ClientError> {
+        let mut buffer = Vec::new();
+
+        let line = next_line(&mut response, &mut buffer)
+            .await?
+            .ok_or(ClientError::EmptyResponse)?;
+        let initial = serde_json::from_slice(&line)?;
+
+        Ok(Self {
+            initial,
+            response,
+            buffer,
+            _other: PhantomData,
+        })
+    }
+
+    pub fn initial(&self) -> &Initial {
+        &self.initial
+    }
+
+    pub fn into_initial(self) -> Initial {
+        self.initial
+    }
+
+    /// None once the server finished the response
+    pub async fn next(&mut self) -> Result<Option<Other>, ClientError> {
+        let Some(line) = next_line(&mut self.response, &mut self.buffer).await? else {
+            return Ok(None);
+        };
+
+        Ok(Some(serde_json::from_slice(&line)?))
+    }
+}
+
+async fn next_line(
+    response: &mut Response,
+    buffer: &mut Vec<u8>,
+) -> Result<Option<Vec<u8>>, ClientError> {
+    loop {
+        if let Some(line) = take_line(buffer) {
+            return Ok(Some(line));
+        }
+
+        match response.chunk().await? {
+            Some(chunk) => buffer.extend_from_slice(&chunk),
+            // Every value ends with a new line, so the rest is incomplete
+            None => return Ok(None),
+        }
+    }
+}
+
+/// Removes the first complete line including its new line from the received bytes
+fn take_line(buffer: &mut Vec<u8>) -> Option<Vec<u8>> {
+    let end = buffer.iter().position(|byte| *byte == b'\n')?;
+
+    Some(buffer.drain(..=end).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::streamed::take_line;
+
+    #[test]
+    fn test_take_line() {
+        let mut buffer = Vec::new();
+        assert_eq!(take_line(&mut buffer), None);
+
+        // A value split across chunks is only complete with its new line
+        buffer.extend_from_slice(b"{\"a\":");
+        assert_eq!(take_line(&mut buffer), None);
+        buffer.extend_from_slice(b"1}\n{\"b\"");
+        assert_eq!(take_line(&mut buffer).as_deref(), Some(&b"{\"a\":1}\n"[..]));
+        assert_eq!(take_line(&mut buffer), None);
+        assert_eq!(buffer, b"{\"b\"");
+
+        // Multiple values in one chunk
+        buffer.extend_from_slice(b":2}\n[]\n\n");
+        assert_eq!(take_line(&mut buffer).as_deref(), Some(&b"{\"b\":2}\n"[..]));
+        assert_eq!(take_line(&mut buffer).as_deref(), Some(&b"[]\n"[..]));
+        assert_eq!(take_line(&mut buffer).as_deref(), Some(&b"\n"[..]));
+        assert_eq!(take_line(&mut buffer), None);
+        assert!(buffer.is_empty());
+    }
+}
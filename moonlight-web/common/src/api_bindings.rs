@@ -1,40 +1,51 @@
-use std::fmt::{Display, Formatter};
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter},
+};
 
+#[cfg(feature = "server")]
 use moonlight_common::{
     ServerState,
     stream::bindings::{
         Colorspace, ControllerButtons, ControllerCapabilities, KeyModifiers, MouseButton,
-        SupportedVideoFormats,
+        ServerCodeModeSupport, SupportedVideoFormats,
     },
 };
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use ts_rs::TS;
 
 use crate::{api_bindings_ext::TsAny, ts_consts};
 
-const EXPORT_PATH: &str = "../../web-server/web/api_bindings.ts";
+pub(crate) const EXPORT_PATH: &str = "../../web-server/web/api_bindings.ts";
+
+/// Unique identifier for a connected peer/client
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct PeerId(#[ts(type = "number")] pub u64);
 
-#[derive(Serialize, Deserialize, Debug, TS, Clone)]
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema, Clone)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct ConfigJs {
     pub path_prefix: String,
     pub default_settings: Option<TsAny>,
 }
 
-#[derive(Serialize, Deserialize, Debug, TS, Clone)]
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema, Clone)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct PostLoginRequest {
     pub name: String,
     pub password: String,
 }
 
-#[derive(Serialize, Deserialize, Debug, TS, Clone, Copy)]
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema, Clone, Copy)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub enum HostState {
     Free,
     Busy,
 }
 
+#[cfg(feature = "server")]
 impl From<ServerState> for HostState {
     fn from(value: ServerState) -> Self {
         match value {
@@ -44,13 +55,16 @@ impl From<ServerState> for HostState {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, TS)]
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub enum PairStatus {
     NotPaired,
     Paired,
+    /// Paired, but the host rejects our certificate, e.g. because it regenerated its own. It must be paired again
+    PairedButRejected,
 }
 
+#[cfg(feature = "server")]
 impl From<moonlight_common::PairStatus> for PairStatus {
     fn from(value: moonlight_common::PairStatus) -> Self {
         use moonlight_common::PairStatus as MlPairStatus;
@@ -61,14 +75,16 @@ impl From<moonlight_common::PairStatus> for PairStatus {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, TS)]
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub enum HostOwner {
     ThisUser,
     Global,
+    /// Owned by another user who shared it with this user
+    Shared,
 }
 
-#[derive(Serialize, Deserialize, Debug, TS)]
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct UndetailedHost {
     pub host_id: u32,
@@ -77,9 +93,11 @@ pub struct UndetailedHost {
     pub paired: PairStatus,
     /// None if offline else the state
     pub server_state: Option<HostState>,
+    /// The host came back online too often within the last hour, e.g. because of a flaky network
+    pub flapping: bool,
 }
 
-#[derive(Serialize, Deserialize, Debug, TS)]
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct DetailedHost {
     pub host_id: u32,
@@ -87,70 +105,255 @@ pub struct DetailedHost {
     pub name: String,
     pub paired: PairStatus,
     pub server_state: Option<HostState>,
+    /// The name reported by the host, `name` is the display name if one is set
+    pub host_name: String,
+    /// The address used to connect to the host, this is `external_address` if one is set
     pub address: String,
     pub http_port: u16,
+    /// The https port used to connect to the host, this is `https_port_override` if one is set
     pub https_port: u16,
     pub external_port: u16,
+    pub https_port_override: Option<u16>,
+    pub external_address: Option<String>,
     pub version: String,
     pub gfe_version: String,
     pub unique_id: String,
     pub mac: Option<String>,
     pub local_ip: String,
     pub current_game: u32,
+    /// The title of `current_game`, None if nothing is running or the app is hidden from the user
+    pub current_game_name: Option<String>,
     pub max_luma_pixels_hevc: u32,
     pub server_codec_mode_support: u32,
+    /// Decoded from the raw fields above, None if the host is offline
+    pub capabilities: Option<HostCapabilities>,
+    /// If the reachability of the host is also checked in the background, only admins can change this
+    pub reachability_probe: bool,
+    /// The modes the host can stream, empty if it's offline or doesn't report them
+    pub display_modes: Vec<HostDisplayMode>,
+}
+
+/// A resolution and refresh rate the host can stream
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema, Clone, Copy, PartialEq, Eq)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct HostDisplayMode {
+    pub width: u32,
+    pub height: u32,
+    pub refresh_rate: u32,
+}
+
+#[cfg(feature = "server")]
+impl From<moonlight_common::network::DisplayMode> for HostDisplayMode {
+    fn from(value: moonlight_common::network::DisplayMode) -> Self {
+        Self {
+            width: value.width,
+            height: value.height,
+            refresh_rate: value.refresh_rate,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema, Clone, Copy, PartialEq, Eq)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub enum HostVideoCodec {
+    H264,
+    H264High8_444,
+    H265,
+    H265Main10,
+    H265Rext8_444,
+    H265Rext10_444,
+    Av1Main8,
+    Av1Main10,
+    Av1High8_444,
+    Av1High10_444,
+}
+
+#[cfg(feature = "server")]
+impl HostVideoCodec {
+    /// Every codec with its flag in the host info and in the supported formats of a stream
+    pub const ALL: [(HostVideoCodec, ServerCodeModeSupport, SupportedVideoFormats); 10] = [
+        (
+            Self::H264,
+            ServerCodeModeSupport::H264,
+            SupportedVideoFormats::H264,
+        ),
+        (
+            Self::H264High8_444,
+            ServerCodeModeSupport::H264_HIGH8_444,
+            SupportedVideoFormats::H264_HIGH8_444,
+        ),
+        (
+            Self::H265,
+            ServerCodeModeSupport::HEVC,
+            SupportedVideoFormats::H265,
+        ),
+        (
+            Self::H265Main10,
+            ServerCodeModeSupport::HEVC_MAIN10,
+            SupportedVideoFormats::H265_MAIN10,
+        ),
+        (
+            Self::H265Rext8_444,
+            ServerCodeModeSupport::HEVC_REXT8_444,
+            SupportedVideoFormats::H265_REXT8_444,
+        ),
+        (
+            Self::H265Rext10_444,
+            ServerCodeModeSupport::HEVC_REXT10_444,
+            SupportedVideoFormats::H265_REXT10_444,
+        ),
+        (
+            Self::Av1Main8,
+            ServerCodeModeSupport::AV1_MAIN8,
+            SupportedVideoFormats::AV1_MAIN8,
+        ),
+        (
+            Self::Av1Main10,
+            ServerCodeModeSupport::AV1_MAIN10,
+            SupportedVideoFormats::AV1_MAIN10,
+        ),
+        (
+            Self::Av1High8_444,
+            ServerCodeModeSupport::AV1_HIGH8_444,
+            SupportedVideoFormats::AV1_HIGH8_444,
+        ),
+        (
+            Self::Av1High10_444,
+            ServerCodeModeSupport::AV1_HIGH10_444,
+            SupportedVideoFormats::AV1_HIGH10_444,
+        ),
+    ];
+}
+
+/// What the host can stream
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema, Clone, PartialEq, Eq)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct HostCapabilities {
+    pub video_codecs: Vec<HostVideoCodec>,
+    /// The video codecs as flags of `StreamSupportedVideoCodecs`
+    pub supported_video_formats: u32,
+    pub hdr: bool,
+    pub yuv444: bool,
+    /// If resolutions above 4K can be streamed
+    pub above_4k: bool,
+}
+
+#[cfg(feature = "server")]
+impl HostCapabilities {
+    /// Nvidia hosts (GeForce Experience) need HEVC Main10 for resolutions above 4K
+    pub fn new(server_codec_mode_support: u32, is_nvidia: bool) -> Self {
+        let support = ServerCodeModeSupport::from_bits_truncate(server_codec_mode_support);
+
+        let mut video_codecs = Vec::new();
+        let mut supported_video_formats = SupportedVideoFormats::empty();
+        for (codec, mode, format) in HostVideoCodec::ALL {
+            if support.contains(mode) {
+                video_codecs.push(codec);
+                supported_video_formats |= format;
+            }
+        }
+
+        Self {
+            video_codecs,
+            supported_video_formats: supported_video_formats.bits(),
+            hdr: support
+                .intersects(ServerCodeModeSupport::HEVC_MAIN10 | ServerCodeModeSupport::AV1_MAIN10),
+            yuv444: support.intersects(
+                ServerCodeModeSupport::H264_HIGH8_444
+                    | ServerCodeModeSupport::HEVC_REXT8_444
+                    | ServerCodeModeSupport::HEVC_REXT10_444
+                    | ServerCodeModeSupport::AV1_HIGH8_444
+                    | ServerCodeModeSupport::AV1_HIGH10_444,
+            ),
+            above_4k: support.contains(ServerCodeModeSupport::HEVC_MAIN10) || !is_nvidia,
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, TS)]
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct App {
     pub app_id: u32,
     pub title: String,
     pub is_hdr_supported: bool,
+    /// If the app is hidden from other users, None if the user can't change which apps are hidden
+    pub hidden: Option<bool>,
 }
 
+#[cfg(feature = "server")]
 impl From<moonlight_common::network::App> for App {
     fn from(value: moonlight_common::network::App) -> Self {
         Self {
             app_id: value.id,
             title: value.title,
             is_hdr_supported: value.is_hdr_supported,
+            hidden: None,
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, TS)]
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct GetHostsResponse {
     pub hosts: Vec<UndetailedHost>,
 }
 
-#[derive(Serialize, Deserialize, Debug, TS)]
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct GetHostQuery {
     pub host_id: u32,
 }
 
-#[derive(Serialize, Deserialize, Debug, TS)]
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct GetHostResponse {
     pub host: DetailedHost,
 }
 
-#[derive(Serialize, Deserialize, Debug, TS)]
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct GetHostUptimeQuery {
+    pub host_id: u32,
+    /// How far back the samples go, defaults to 24 hours
+    pub hours: Option<u32>,
+}
+
+/// Recorded whenever the web server tried to reach the host
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct HostReachabilitySample {
+    /// Seconds since the unix epoch
+    #[ts(type = "number")]
+    pub timestamp: u64,
+    pub online: bool,
+    /// How long the host took to respond, None if it was offline
+    pub latency_ms: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct GetHostUptimeResponse {
+    /// Oldest first
+    pub samples: Vec<HostReachabilitySample>,
+    /// How much of the time the host was online in percent, None if there are no samples
+    pub availability_percent: Option<f64>,
+    pub flapping: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct PostHostRequest {
     pub address: String,
     pub http_port: Option<u16>,
 }
 
-#[derive(Serialize, Deserialize, Debug, TS)]
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct PostHostResponse {
     pub host: DetailedHost,
 }
 
-#[derive(Serialize, Deserialize, Debug, TS)]
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct PatchHostRequest {
     /// The host id of the host to change
@@ -158,21 +361,80 @@ pub struct PatchHostRequest {
     /// Option<Option<u32>> are not supported
     pub change_owner: bool,
     pub owner: Option<u32>,
+    #[serde(default)]
+    pub change_mac: bool,
+    /// The mac address used to wake the host, e.g. "01:23:45:67:89:AB"
+    #[serde(default)]
+    pub mac: Option<String>,
+    #[serde(default)]
+    pub change_display_name: bool,
+    /// None or an empty name shows the name reported by the host
+    #[serde(default)]
+    pub display_name: Option<String>,
+    #[serde(default)]
+    pub change_https_port_override: bool,
+    /// None uses the https port reported by the host
+    #[serde(default)]
+    pub https_port_override: Option<u16>,
+    #[serde(default)]
+    pub change_external_address: bool,
+    /// None or an empty address connects to the address the host was added with
+    #[serde(default)]
+    pub external_address: Option<String>,
+    /// Only admins can change this
+    #[serde(default)]
+    pub change_reachability_probe: bool,
+    /// Checks if the host is reachable in the background, not only when a user requests it
+    #[serde(default)]
+    pub reachability_probe: bool,
+}
+
+/// Only the owner of the host or an admin can change which apps are hidden
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct PutHostAppVisibilityRequest {
+    pub host_id: u32,
+    /// These apps can't be seen or started by other users, the list replaces the previous one
+    pub hidden_app_ids: Vec<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct GetHostSharesQuery {
+    pub host_id: u32,
+}
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct GetHostSharesResponse {
+    pub user_names: Vec<String>,
+}
+
+/// Only the owner of the host or an admin can change who the host is shared with, global hosts can't be shared
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct PutHostSharesRequest {
+    pub host_id: u32,
+    /// These users can use the host without owning it, the list replaces the previous one
+    pub user_names: Vec<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug, TS)]
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct DeleteHostQuery {
     pub host_id: u32,
 }
 
-#[derive(Serialize, Deserialize, Debug, TS)]
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct PostPairRequest {
     pub host_id: u32,
+    /// The 4 digit pin shown by the host.
+    /// If not set the server generates a pin which must be entered on the host.
+    #[serde(default)]
+    pub pin: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug, TS)]
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub enum PostPairResponse1 {
     InternalServerError,
@@ -180,32 +442,128 @@ pub enum PostPairResponse1 {
     Pin(String),
 }
 
-#[derive(Serialize, Deserialize, Debug, TS)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub enum PairStage {
+    /// Waiting for the pin to be entered on the host
+    ServerCertificate,
+    ClientChallenge,
+    ServerChallenge,
+    ClientPairingSecret,
+    PairingConfirm,
+}
+
+#[cfg(feature = "server")]
+impl From<moonlight_common::pair::PairStage> for PairStage {
+    fn from(value: moonlight_common::pair::PairStage) -> Self {
+        use moonlight_common::pair::PairStage as MlPairStage;
+        match value {
+            MlPairStage::ServerCertificate => Self::ServerCertificate,
+            MlPairStage::ClientChallenge => Self::ClientChallenge,
+            MlPairStage::ServerChallenge => Self::ServerChallenge,
+            MlPairStage::ClientPairingSecret => Self::ClientPairingSecret,
+            MlPairStage::PairingConfirm => Self::PairingConfirm,
+        }
+    }
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub enum PostPairResponse2 {
-    PairError,
+    /// Sent when the pairing handshake enters a new stage, followed by more responses
+    Stage(PairStage),
+    PairError {
+        /// The stage in which pairing failed, none if it failed before the handshake started
+        stage: Option<PairStage>,
+    },
     Paired(DetailedHost),
 }
 
-#[derive(Serialize, Deserialize, Debug, TS)]
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct PostWakeUpRequest {
     pub host_id: u32,
 }
 
-#[derive(Serialize, Deserialize, Debug, TS)]
+/// Removes the pairing of a host on the host and in the web server.
+/// If the host was already deleted, this retries its pending unpair
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct PostHostUnpairRemoteRequest {
+    pub host_id: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct PostHostUnpairRemoteResponse {
+    pub result: RemoteUnpairResult,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema, Clone, Copy, PartialEq, Eq)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub enum RemoteUnpairResult {
+    Unpaired,
+    /// The unpair is retried in the background until the host is reachable
+    HostOffline,
+    /// The host rejected the unpair, it must be removed in the host software
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct GetWakeUpStatusQuery {
+    pub host_id: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub enum WakeUpStatus {
+    Offline,
+    /// A magic packet was sent recently, but the host is not yet online
+    Waking,
+    Online,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct GetWakeUpStatusResponse {
+    pub status: WakeUpStatus,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct GetAppsQuery {
     pub host_id: u32,
 }
 
-#[derive(Serialize, Deserialize, Debug, TS)]
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct GetAppsResponse {
     pub apps: Vec<App>,
 }
 
-#[derive(Serialize, Deserialize, Debug, TS)]
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct DetailedApp {
+    pub app_id: u32,
+    pub title: String,
+    pub is_hdr_supported: bool,
+    /// If the box art is already cached by the web server
+    pub image_cached: bool,
+    /// Changes when the cached box art changes, so the client can skip fetching images it already has
+    pub image_hash: Option<String>,
+    /// If the app is hidden from other users, None if the user can't change which apps are hidden
+    pub hidden: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct GetAppsDetailedResponse {
+    pub apps: Vec<DetailedApp>,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct GetAppImageQuery {
     pub host_id: u32,
@@ -214,33 +572,71 @@ pub struct GetAppImageQuery {
     pub force_refresh: bool,
 }
 
-#[derive(Serialize, Deserialize, Debug, TS)]
+/// Query parameters of the guest stream endpoint
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema, Clone)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct GuestStreamQuery {
+    pub room_id: String,
+    pub player_name: Option<String>,
+    /// The first message of the client is [StreamClientMessage::DiscordAuth].
+    /// The access token isn't part of the url so it doesn't end up in logs.
+    #[serde(default)]
+    pub discord_auth: bool,
+    /// Join as a spectator instead of a player
+    #[serde(default)]
+    pub spectate: bool,
+    /// The signed invite of the room, see [PostHostInviteResponse],
+    /// or the token of a slot invite, see `StreamClientMessage::CreateSlotInvite`
+    pub invite: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct PostHostInviteRequest {
+    pub room_id: String,
+    /// Shorter than the configured invite lifetime, longer values are capped
+    #[ts(type = "number | null")]
+    pub valid_for_secs: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct PostHostInviteResponse {
+    /// The join page with the room and the invite, relative to the origin of the web server
+    pub url: String,
+    pub invite: String,
+    /// Milliseconds since the unix epoch
+    #[ts(type = "number")]
+    pub expires_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct PostCancelRequest {
     pub host_id: u32,
 }
 
-#[derive(Serialize, Deserialize, Debug, TS)]
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct PostCancelResponse {
     pub success: bool,
 }
 
-#[derive(Serialize, Deserialize, Debug, TS)]
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema, Clone, Copy, PartialEq, Eq)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub enum UserRole {
     User,
     Admin,
 }
 
-#[derive(Serialize, Deserialize, Debug, TS)]
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct GetUserQuery {
     pub name: Option<String>,
     pub user_id: Option<u32>,
 }
 
-#[derive(Serialize, Deserialize, Debug, TS)]
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct DetailedUser {
     pub id: u32,
@@ -248,9 +644,19 @@ pub struct DetailedUser {
     pub name: String,
     pub role: UserRole,
     pub client_unique_id: String,
+    /// The device name hosts show for the pairings of this user, the default name if it wasn't changed
+    pub pair_device_name: String,
+    /// The default stream settings of this user applied over the default settings of the server
+    pub default_settings: Option<TsAny>,
+    /// Milliseconds since the unix epoch, None if the user never logged in
+    #[ts(type = "number | null")]
+    pub last_login: Option<u64>,
+    /// Milliseconds since the unix epoch, None if the user never started a stream
+    #[ts(type = "number | null")]
+    pub last_stream: Option<u64>,
 }
 
-#[derive(Serialize, Deserialize, Debug, TS)]
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct PostUserRequest {
     pub name: String,
@@ -259,7 +665,7 @@ pub struct PostUserRequest {
     pub client_unique_id: String,
 }
 
-#[derive(Serialize, Deserialize, Debug, TS)]
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct PatchUserRequest {
     /// The user id of the user to change
@@ -267,24 +673,324 @@ pub struct PatchUserRequest {
     pub password: Option<String>,
     pub role: Option<UserRole>,
     pub client_unique_id: Option<String>,
+    /// Option<Option<String>> are not supported
+    #[serde(default)]
+    pub change_pair_device_name: bool,
+    /// None or an empty name uses the default name, only used by pairings made after the change
+    #[serde(default)]
+    pub pair_device_name: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug, TS)]
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct DeleteUserRequest {
     pub id: u32,
 }
 
-#[derive(Serialize, Deserialize, Debug, TS)]
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct GetInactiveUsersQuery {
+    /// Users which neither logged in nor streamed within this many days
+    pub days: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct GetUsersResponse {
     pub users: Vec<DetailedUser>,
 }
 
+/// An action admins can review later
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct AuditLogEntry {
+    /// Milliseconds since the unix epoch
+    #[ts(type = "number")]
+    pub time: u64,
+    /// The admin or user who made the request
+    pub actor_id: u32,
+    pub action: AuditLogAction,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub enum AuditLogAction {
+    /// A request an admin made while impersonating a user
+    Impersonation {
+        user_id: u32,
+        method: String,
+        path: String,
+    },
+    /// The pairing of the web server was removed on the host, e.g. because the host or its owner was deleted
+    RemoteUnpair {
+        host_id: u32,
+        address: String,
+        result: RemoteUnpairResult,
+    },
+    /// A user created a room and started a stream in it
+    StreamStarted {
+        room_id: String,
+        host_id: u32,
+        app_id: u32,
+        app_name: String,
+    },
+    /// An admin exported the users with their password hashes and the pair info of their hosts
+    UsersExported { include_hosts: bool },
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct GetAuditLogResponse {
+    /// Oldest first
+    pub entries: Vec<AuditLogEntry>,
+}
+
+/// Counters since the web server started
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct AppImageCacheMetrics {
+    #[ts(type = "number")]
+    pub hits: u64,
+    #[ts(type = "number")]
+    pub misses: u64,
+    pub entries: usize,
+    /// The total bytes of all cached box arts
+    pub size: usize,
+}
+
+/// The video frames and audio samples the streamer queued for a peer
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema, Clone)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct PeerQueueMetrics {
+    pub peer_id: PeerId,
+    pub video: usize,
+    pub audio: usize,
+    /// The bytes held back for the stream delay of the peer
+    pub delayed_bytes: usize,
+}
+
+/// The last resources a streamer reported, it reports them every 30 seconds
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema, Clone)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct StreamerResourceMetrics {
+    /// The resident memory in bytes, None if the streamer can't measure it on its platform
+    #[ts(type = "number | null")]
+    pub rss_bytes: Option<u64>,
+    pub queues: Vec<PeerQueueMetrics>,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct StreamerMetrics {
+    pub room_id: String,
+    pub resources: StreamerResourceMetrics,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct GetMetricsResponse {
+    pub app_image_cache: AppImageCacheMetrics,
+    /// The streamers which already reported their resources
+    pub streamers: Vec<StreamerMetrics>,
+}
+
+/// What this server supports, readable without logging in.
+/// Only contains flags and limits, no secrets or paths of the config.
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct GetCapabilitiesResponse {
+    /// The version of the web server
+    pub version: String,
+    pub transports: Vec<TransportType>,
+    /// The video codecs the streamer can forward, the host and the browser must also support them
+    pub video_codecs: Vec<HostVideoCodec>,
+    pub max_players: u8,
+    pub max_spectators: u32,
+    /// If spectators wait in a queue when a room is full
+    pub spectator_queue: bool,
+    /// If the Discord Activity is configured
+    pub discord: bool,
+    /// If users are logged in by a header of the reverse proxy
+    pub forwarded_header_login: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct GetUserSettingsQuery {
+    /// Only admins can query the settings of other users
+    pub user_id: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct GetUserSettingsResponse {
+    /// The settings stored for this user
+    pub settings: Option<TsAny>,
+    /// The user settings applied over the default settings of the server
+    pub merged_settings: Option<TsAny>,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct PutUserSettingsRequest {
+    /// None resets the settings to the default settings of the server
+    pub settings: Option<TsAny>,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct PostImportMoonlightQtRequest {
+    /// The Moonlight Qt config directory or the config file itself on the server
+    pub path: String,
+    /// The user which will own the imported hosts, the hosts are global if this is none
+    pub owner: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct PostImportResponse {
+    pub hosts: Vec<ImportedHost>,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct ImportedHost {
+    pub name: String,
+    pub address: Option<String>,
+    pub http_port: u16,
+    pub result: ImportHostResult,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub enum ImportHostResult {
+    Imported { host_id: u32, paired: bool },
+    Skipped { reason: String },
+    Failed { error: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct GetUsersExportQuery {
+    /// Includes the password hashes and the pair info of the hosts, this requires the `X-Confirm-Export-Secrets: true` header
+    #[serde(default)]
+    pub include_secrets: bool,
+    /// Includes the hosts owned by the users, global hosts are never exported
+    #[serde(default)]
+    pub include_hosts: bool,
+}
+
+/// The first line of a user export, every following line is an [ExportedUser]
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct UsersExportHeader {
+    pub version: u32,
+    pub include_secrets: bool,
+    pub include_hosts: bool,
+}
+
+/// A line of a user export, the import accepts the same lines
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct ExportedUser {
+    /// The id on the exporting server, imported users get a new id
+    pub id: u32,
+    pub name: String,
+    pub role: UserRole,
+    pub client_unique_id: String,
+    /// Only exported with secrets, imported users without a password can't log in with a password
+    #[serde(default)]
+    pub password: Option<ExportedPassword>,
+    #[serde(default)]
+    pub hosts: Vec<ExportedHost>,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct ExportedPassword {
+    /// Hex encoded
+    pub salt: String,
+    /// Hex encoded
+    pub hash: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct ExportedHost {
+    pub name: String,
+    pub address: String,
+    pub http_port: u16,
+    pub mac: Option<String>,
+    /// Only exported with secrets
+    #[serde(default)]
+    pub pair_info: Option<ExportedPairInfo>,
+}
+
+/// The pem encoded certificates of a paired host
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct ExportedPairInfo {
+    pub client_private_key: String,
+    pub client_certificate: String,
+    pub server_certificate: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema, Default, Clone, Copy, PartialEq, Eq)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub enum UserImportConflict {
+    /// Users whose name already exists aren't imported
+    #[default]
+    Skip,
+    /// Replaces the role, the client unique id and the password of the existing user
+    Overwrite,
+    /// Imports the user with a free name, e.g. `alice-2`
+    Rename,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct PostUsersImportQuery {
+    /// What happens with users whose name already exists
+    #[serde(default)]
+    pub conflict: UserImportConflict,
+    /// Imports the hosts of the users, otherwise the hosts of the lines are ignored
+    #[serde(default)]
+    pub include_hosts: bool,
+}
+
+/// The first line of the import response, every following line is an [ImportedUser]
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct PostUsersImportResponse {
+    pub conflict: UserImportConflict,
+    pub include_hosts: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct ImportedUser {
+    /// The line of the import, starting at 1
+    pub line: u32,
+    /// None if the line couldn't be parsed
+    pub name: Option<String>,
+    pub result: ImportUserResult,
+    pub hosts: Vec<ImportHostResult>,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub enum ImportUserResult {
+    Created { user_id: u32 },
+    Overwritten { user_id: u32 },
+    Renamed { user_id: u32, name: String },
+    Skipped { reason: String },
+    Failed { error: String },
+}
+
 // -- Stream
 
 /// Player slot for multi-player streaming (1-4)
-#[derive(Serialize, Deserialize, Debug, TS, Clone, Copy, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema, Clone, Copy, PartialEq, Eq)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct PlayerSlot(pub u8);
 
@@ -309,7 +1015,7 @@ impl PlayerSlot {
 }
 
 /// Role of a participant in a room
-#[derive(Serialize, Deserialize, Debug, TS, Clone, Copy, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema, Clone, Copy, PartialEq, Eq)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub enum RoomRole {
     /// Host - Player 1 who started the room, has full control
@@ -334,10 +1040,83 @@ impl RoomRole {
     }
 }
 
+/// Which input a participant of a room may send to the host
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema, Clone, Copy, PartialEq, Eq)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct PeerPermissions {
+    pub allow_keyboard: bool,
+    pub allow_mouse: bool,
+    pub allow_gamepad: bool,
+    pub allow_touch: bool,
+}
+
+impl PeerPermissions {
+    pub const NONE: PeerPermissions = PeerPermissions {
+        allow_keyboard: false,
+        allow_mouse: false,
+        allow_gamepad: false,
+        allow_touch: false,
+    };
+    pub const ALL: PeerPermissions = PeerPermissions {
+        allow_keyboard: true,
+        allow_mouse: true,
+        allow_gamepad: true,
+        allow_touch: true,
+    };
+
+    /// The permissions of a participant that joined or changed its role.
+    /// The host can always use everything, spectators never can use anything.
+    pub fn default_for(role: RoomRole, guests_keyboard_mouse_enabled: bool) -> Self {
+        match role {
+            RoomRole::Host => Self::ALL,
+            RoomRole::Player => Self {
+                allow_keyboard: guests_keyboard_mouse_enabled,
+                allow_mouse: guests_keyboard_mouse_enabled,
+                allow_gamepad: true,
+                allow_touch: guests_keyboard_mouse_enabled,
+            },
+            RoomRole::Spectator => Self::NONE,
+        }
+    }
+
+    /// Used by `SetGuestsKeyboardMouseEnabled`, touch was always part of that toggle
+    pub fn set_keyboard_mouse(&mut self, enabled: bool) {
+        self.allow_keyboard = enabled;
+        self.allow_mouse = enabled;
+        self.allow_touch = enabled;
+    }
+}
+
+/// How the gamepads of the players are mapped to the gamepads of the host
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema, Clone, Copy, PartialEq, Eq, Default)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub enum ControllerMode {
+    /// Every player controls the gamepad of its own player slot
+    #[default]
+    PerPlayer,
+    /// All players control the first gamepad together, their inputs are merged
+    Shared,
+}
+
+/// Which video frames the streamer forwards to a peer.
+/// All peers share the one stream encoded by the host, so sending a lower resolution to some of them
+/// (simulcast) would need encoder support and isn't done.
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema, Clone, Copy, PartialEq, Eq, Default)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub enum PeerVideoMode {
+    /// Every frame of the stream
+    #[default]
+    Full,
+    /// Only the keyframes the stream sends anyway, no extra ones are requested. Cuts the bandwidth of spectators, e.g. on a phone
+    Thumbnail,
+}
+
 /// Information about a participant in a room (player or spectator)
-#[derive(Serialize, Deserialize, Debug, TS, Clone)]
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema, Clone)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct RoomParticipant {
+    /// Used by the host to change the permissions of this participant
+    pub peer_id: PeerId,
     /// Player slot (0-3) if a player, None if spectator
     pub slot: Option<PlayerSlot>,
     /// Role in the room
@@ -348,19 +1127,24 @@ pub struct RoomParticipant {
     pub discord_user_id: Option<String>,
     /// Discord avatar URL
     pub discord_avatar: Option<String>,
+    /// The input this participant may send
+    pub permissions: PeerPermissions,
 }
 
 /// Information about a player in a room (legacy compatibility)
-#[derive(Serialize, Deserialize, Debug, TS, Clone)]
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema, Clone)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct RoomPlayer {
     pub slot: PlayerSlot,
     pub name: Option<String>,
     pub is_host: bool,
+    /// Set for an additional gamepad of a player on the same machine,
+    /// the slot of the player whose connection it shares
+    pub shares_connection_with: Option<PlayerSlot>,
 }
 
 /// Information about a streaming room
-#[derive(Serialize, Deserialize, Debug, TS, Clone)]
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema, Clone)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct RoomInfo {
     pub room_id: String,
@@ -373,9 +1157,67 @@ pub struct RoomInfo {
     pub participants: Vec<RoomParticipant>,
     /// Number of spectators currently watching
     pub spectator_count: usize,
+    /// How many spectators can watch at the same time
+    pub max_spectators: usize,
+    /// How the gamepads of the players are mapped
+    pub controller_mode: ControllerMode,
+    /// Whether a player can use multiple gamepads, each one takes a player slot
+    pub allow_multiple_gamepads_per_peer: bool,
+    /// The id of the user who started the stream, only included for admins
+    pub created_by: Option<u32>,
+    /// The name of the user who started the stream, only included for admins
+    pub created_by_name: Option<String>,
+    /// Milliseconds since the unix epoch, only included for admins
+    #[ts(type = "number | null")]
+    pub created_at: Option<u64>,
+    /// The last resources the streamer reported, only included for admins
+    pub resources: Option<StreamerResourceMetrics>,
+    /// Increases with every change of the room, clients ignore updates older than the info they have
+    #[ts(type = "number")]
+    pub revision: u64,
+}
+
+/// Why a client couldn't join a room
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema, Clone, Copy, PartialEq, Eq)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub enum RoomJoinFailure {
+    /// No room with this id exists
+    NotFound,
+    /// All player slots are taken, joining as a spectator might still work
+    Full,
+    /// The password of the room didn't match, rooms can't have a password yet
+    WrongPassword,
+    /// The room already has as many spectators as it allows
+    SpectatorLimit,
+    /// The room was closed before the client could join
+    Closed,
+    /// The slot invite was used by another client or expired before the client could join
+    InviteUsed,
+}
+
+impl RoomJoinFailure {
+    pub fn reason(self) -> &'static str {
+        match self {
+            Self::NotFound => "Room not found",
+            Self::Full => "Room is full",
+            Self::WrongPassword => "Wrong room password",
+            Self::SpectatorLimit => "Spectator limit reached",
+            Self::Closed => "Room closed",
+            Self::InviteUsed => "Invite was already used",
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, TS, Clone, Copy, PartialEq, Eq)]
+impl From<RoomJoinFailure> for StreamServerMessage {
+    fn from(kind: RoomJoinFailure) -> Self {
+        Self::RoomJoinFailed {
+            kind,
+            reason: kind.reason().to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema, Clone, Copy, PartialEq, Eq)]
 #[ts(export, export_to = EXPORT_PATH)]
 #[serde(rename_all = "lowercase")]
 pub enum TransportChannelMethod {
@@ -414,7 +1256,46 @@ ts_consts!(
     pub const CONTROLLER15: u8 = 25;
 );
 
-#[derive(Serialize, Deserialize, Debug, TS, Clone, Copy, PartialEq, Eq)]
+/// How a WebRTC data channel delivers its messages.
+///
+/// Ordered and reliable channels never lose a message, but a lost packet holds back
+/// every following message until it's retransmitted, which shows up as latency spikes on lossy links.
+/// Input where only the latest state matters, e.g. absolute mouse positions or controller axes,
+/// should rather drop old messages than wait for them.
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema, Clone, Copy, PartialEq, Eq)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct DataChannelOptions {
+    /// Messages are delivered in the order they were sent
+    pub ordered: bool,
+    /// When lost messages are given up, None retransmits them until they arrive
+    pub retransmits: Option<DataChannelRetransmits>,
+}
+
+impl DataChannelOptions {
+    pub const RELIABLE_ORDERED: Self = Self {
+        ordered: true,
+        retransmits: None,
+    };
+    pub const RELIABLE_UNORDERED: Self = Self {
+        ordered: false,
+        retransmits: None,
+    };
+    pub const UNRELIABLE_UNORDERED: Self = Self {
+        ordered: false,
+        retransmits: Some(DataChannelRetransmits::MaxRetransmits(0)),
+    };
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema, Clone, Copy, PartialEq, Eq)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub enum DataChannelRetransmits {
+    /// Retransmit a lost message at most this many times, 0 never retransmits
+    MaxRetransmits(u16),
+    /// Retransmit a lost message for at most this many milliseconds, e.g. the interval of a frame
+    MaxPacketLifeTime(u16),
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema, Clone, Copy, PartialEq, Eq)]
 #[ts(export, export_to = EXPORT_PATH)]
 #[serde(rename_all = "lowercase")]
 pub enum RtcSdpType {
@@ -425,14 +1306,14 @@ pub enum RtcSdpType {
     Unspecified,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, TS)]
+#[derive(Serialize, Deserialize, Debug, Clone, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct RtcSessionDescription {
     pub ty: RtcSdpType,
     pub sdp: String,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, TS)]
+#[derive(Serialize, Deserialize, Debug, Clone, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct RtcIceCandidate {
     pub candidate: String,
@@ -441,27 +1322,32 @@ pub struct RtcIceCandidate {
     pub username_fragment: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, TS)]
+#[derive(Serialize, Deserialize, Debug, Clone, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub enum StreamSignalingMessage {
     Description(RtcSessionDescription),
     AddIceCandidate(RtcIceCandidate),
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, TS)]
+#[derive(Serialize, Deserialize, Debug, Clone, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub enum TransportType {
     WebRTC,
     WebSocket,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, TS)]
+#[derive(Serialize, Deserialize, Debug, Clone, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub enum StreamClientMessage {
     /// Initialize a new stream session (creates room as host/Player 1)
     Init {
         host_id: u32,
-        app_id: u32,
+        /// Preferred over the app name when both are set
+        #[serde(default)]
+        app_id: Option<u32>,
+        /// Matched against the app titles ignoring the case, app ids can change when the host software is reinstalled
+        #[serde(default)]
+        app_name: Option<String>,
         video_frame_queue_size: usize,
         audio_sample_queue_size: usize,
     },
@@ -469,6 +1355,9 @@ pub enum StreamClientMessage {
     JoinRoom {
         room_id: String,
         player_name: Option<String>,
+        /// Used to fetch the Discord profile, the player name is used if this fails
+        #[serde(default)]
+        discord_access_token: Option<String>,
         video_frame_queue_size: usize,
         audio_sample_queue_size: usize,
     },
@@ -481,16 +1370,74 @@ pub enum StreamClientMessage {
         video_frame_queue_size: usize,
         audio_sample_queue_size: usize,
     },
+    /// The first message on the guest stream if [GuestStreamQuery::discord_auth] is set
+    DiscordAuth {
+        /// Used to fetch the Discord profile, the player name is used if this fails
+        access_token: String,
+    },
     /// Request to become a player (spectator -> player promotion)
     RequestPlayerSlot,
     /// Release player slot and become spectator again
     ReleasePlayerSlot,
     /// Leave the current room
     LeaveRoom,
-    /// Host-only: Set whether other players can use keyboard/mouse
+    /// Keeps an otherwise silent spectator from being disconnected as idle, e.g. after an `IdleWarning`
+    KeepAlive,
+    /// Host-only: Set whether all current and future guests can use keyboard/mouse
     SetGuestsKeyboardMouseEnabled {
         enabled: bool,
     },
+    /// Host-only: Set which input a single player can use, the host and spectators can't be changed
+    SetPeerPermissions {
+        peer_id: PeerId,
+        permissions: PeerPermissions,
+    },
+    /// Host-only: Create a single use invite which puts the guest into the slot with the name,
+    /// the host receives the token in `SlotInviteCreated`
+    CreateSlotInvite {
+        slot: PlayerSlot,
+        name: String,
+        /// Seconds until the invite expires, capped at the configured invite lifetime
+        expires_in: u32,
+    },
+    /// Host-only: Invalidate a slot invite which wasn't used yet
+    RevokeInvite {
+        token: String,
+    },
+    /// Host-only: Delay the stream of all spectators, 0 disables the delay
+    SetSpectatorDelay {
+        seconds: u32,
+    },
+    /// Host-only: Change how many spectators can watch at the same time
+    SetMaxSpectators {
+        max_spectators: u32,
+    },
+    /// Host-only: Change how the gamepads of the players are mapped, the connected gamepads are reconnected
+    SetControllerMode {
+        mode: ControllerMode,
+    },
+    /// Host-only: Set whether a player can use multiple gamepads from one browser,
+    /// disabling it releases the additional slots of all players
+    SetMultipleGamepadsPerPeer {
+        enabled: bool,
+    },
+    /// How many gamepads are connected to the browser of this player,
+    /// every gamepad after the first one takes another player slot if the host allows it
+    SetLocalGamepadCount {
+        count: u8,
+    },
+    /// Spectator-only: Choose which video frames this peer receives, players always get the full stream
+    SetVideoMode {
+        mode: PeerVideoMode,
+    },
+    /// Host-only: Stop the stream for everyone, also quits the app on the host if quit_app is set
+    StopStream {
+        quit_app: bool,
+    },
+    /// Host-only: Stop sending video and audio to everyone, the Moonlight session and the transports stay alive
+    PauseStream,
+    /// Host-only: Send video and audio again after `PauseStream`
+    ResumeStream,
     /// Host-only: Promote a spectator to player
     PromoteToPlayer {
         discord_user_id: String,
@@ -511,10 +1458,74 @@ pub enum StreamClientMessage {
         video_supported_formats: u32,
         video_colorspace: StreamColorspace,
         video_color_range_full: bool,
+        #[serde(default)]
+        keyboard_mode: KeyboardMode,
+        #[serde(default)]
+        keyboard_layout: KeyboardLayout,
+        /// The virtual key code mapping of [KeyboardLayout::Custom]
+        #[serde(default)]
+        custom_keyboard_layout: HashMap<u16, u16>,
+        /// Replaces what is sent for a virtual key code, applied before the keyboard mode
+        #[serde(default)]
+        key_overrides: HashMap<u16, KeyOverride>,
+        /// The input types the app receives in this stream, checked before the permissions of the peers
+        #[serde(default = "default_allow_input")]
+        allow_keyboard: bool,
+        #[serde(default = "default_allow_input")]
+        allow_mouse: bool,
+        #[serde(default = "default_allow_input")]
+        allow_gamepad: bool,
+        #[serde(default = "default_allow_input")]
+        allow_touch: bool,
     },
 }
 
-#[derive(Serialize, Deserialize, Debug, TS, Clone, Default)]
+fn default_allow_input() -> bool {
+    true
+}
+
+/// How the key presses of a client are sent to the host
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema, Clone, Copy, Default, PartialEq, Eq)]
+#[ts(export, export_to = EXPORT_PATH)]
+#[serde(rename_all = "snake_case")]
+#[ts(rename_all = "snake_case")]
+pub enum KeyboardMode {
+    /// The virtual key codes are sent as is, the typed characters only match on a US layout
+    #[default]
+    VkPassthrough,
+    /// Printable characters are typed as text, all other keys are sent as virtual key codes
+    TextFallback,
+}
+
+/// Remaps the virtual key codes of the client before they are sent to the host.
+/// The browser reports the virtual key code of the physical key on a US layout,
+/// a layout translates it into the key code which types the same character on a US host.
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema, Clone, Copy, Default, PartialEq, Eq)]
+#[ts(export, export_to = EXPORT_PATH)]
+#[serde(rename_all = "snake_case")]
+#[ts(rename_all = "snake_case")]
+pub enum KeyboardLayout {
+    /// The virtual key codes are sent as is
+    #[default]
+    Passthrough,
+    /// French AZERTY
+    Azerty,
+    /// German QWERTZ
+    Qwertz,
+    /// Uses the mapping sent by the client
+    Custom,
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema, Clone, PartialEq, Eq)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub enum KeyOverride {
+    /// Sends this virtual key code instead
+    Key(u16),
+    /// Types this text when the key is pressed
+    Text(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema, Clone, Default)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct RtcIceServer {
     #[serde(skip)]
@@ -538,13 +1549,13 @@ impl Display for RtcIceServer {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, TS)]
+#[derive(Serialize, Deserialize, Debug, Clone, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct StreamCapabilities {
     pub touch: bool,
 }
 
-#[derive(Serialize, Deserialize, Debug, TS)]
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema, Clone, Copy, PartialEq, Eq)]
 #[ts(export, export_to = EXPORT_PATH)]
 #[serde(rename_all = "camelCase")]
 #[ts(rename_all = "camelCase")]
@@ -555,23 +1566,49 @@ pub enum LogMessageType {
     InformError,
 }
 
-#[derive(Serialize, Deserialize, Debug, TS)]
+/// The decoded error code of a terminated moonlight connection
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema, Clone, Copy, PartialEq, Eq)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub enum TerminationReason {
+    Graceful,
+    HostShutdown,
+    NetworkLost,
+    NoVideoReceived,
+    Unauthorized,
+    /// The streamer aborted the start because a stage didn't complete in time, starting again might work
+    StageTimeout,
+    /// The streamer used more memory than the configured limit for too long
+    MemoryLimit,
+    Other(i32),
+}
+
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub enum StreamServerMessage {
     Setup {
         ice_servers: Vec<RtcIceServer>,
+        /// The options of the WebRTC data channels the client creates, keyed by the channel label
+        data_channels: HashMap<String, DataChannelOptions>,
     },
     WebRtc(StreamSignalingMessage),
     // Optional Info
     UpdateApp {
         app: App,
+        /// What the host can stream, None if it couldn't be queried
+        host_capabilities: Option<HostCapabilities>,
     },
     DebugLog {
         message: String,
         ty: Option<LogMessageType>,
     },
     ConnectionComplete {
+        /// Incremented every time the streamer starts the stream, setup of an older epoch was superseded and must be ignored
+        epoch: u32,
         capabilities: StreamCapabilities,
+        /// Stats are only sent after the client requested them on the stats channel
+        available_stats: Vec<StreamerStatsType>,
+        /// How long each stage of the stream start took, in the order they ran
+        stage_timings: Vec<StreamStageTiming>,
         /// Use VideoSupportedCodec to figure this out
         format: u32,
         width: u32,
@@ -586,6 +1623,44 @@ pub enum StreamServerMessage {
     },
     ConnectionTerminated {
         error_code: i32,
+        reason: TerminationReason,
+        /// A short description of the reason which can be shown to the user
+        hint: String,
+    },
+    /// The stream can't start because the host can't encode any video codec the client can decode
+    NoCommonVideoCodec {
+        /// Flags of `StreamSupportedVideoCodecs`
+        client_video_formats: u32,
+        /// Flags of `StreamSupportedVideoCodecs`
+        host_video_formats: u32,
+    },
+    /// The stream can't start because the host didn't respond, the streamer wasn't started
+    HostOffline {
+        host_id: u32,
+    },
+    /// The stream can't start because the streamer of this host is already streaming
+    HostBusy {
+        /// The app id of the game running on the host, None if it couldn't be queried
+        current_game: Option<u32>,
+    },
+    /// The host refused to launch the requested app because it is running a different one
+    AppAlreadyRunning {
+        /// The app id of the game running on the host
+        current_game: u32,
+    },
+    /// The stream wasn't started because the host has no display mode close to the requested one
+    UnsupportedDisplayMode {
+        width: u32,
+        height: u32,
+        fps: u32,
+        supported_modes: Vec<HostDisplayMode>,
+    },
+    /// The stream wasn't started because the host can't stream resolutions this large
+    ResolutionTooHigh {
+        width: u32,
+        height: u32,
+        max_width: u32,
+        max_height: u32,
     },
     /// Room created successfully (sent to host/Player 1)
     RoomCreated {
@@ -603,6 +1678,8 @@ pub enum StreamServerMessage {
     },
     /// Failed to join room
     RoomJoinFailed {
+        kind: RoomJoinFailure,
+        /// A description of the kind for humans
         reason: String,
     },
     /// Player left the room
@@ -615,10 +1692,36 @@ pub enum StreamServerMessage {
     GuestsKeyboardMouseEnabled {
         enabled: bool,
     },
+    /// Only sent to the host, the guest joins with the token as the `invite` of the guest stream
+    SlotInviteCreated {
+        token: String,
+        slot: PlayerSlot,
+        name: String,
+        /// Milliseconds since the unix epoch
+        #[ts(type = "number")]
+        expires_at: u64,
+    },
+    /// The stream delay of spectators changed
+    SpectatorDelay {
+        seconds: u32,
+    },
+    /// The host paused or resumed the stream, no video and audio is sent while it's paused.
+    /// Also sent to clients which join a paused stream
+    StreamPaused {
+        paused: bool,
+    },
     /// Successfully joined as spectator
     SpectatorJoined {
         room: RoomInfo,
     },
+    /// The room has no free spectator slot, waiting at this position (starting at 1) in the queue
+    QueuePosition {
+        position: usize,
+    },
+    /// This spectator didn't send anything for a while and is disconnected unless it sends something, e.g. `KeepAlive`
+    IdleWarning {
+        seconds_remaining: u32,
+    },
     /// Promoted from spectator to player
     PromotedToPlayer {
         player_slot: PlayerSlot,
@@ -628,6 +1731,10 @@ pub enum StreamServerMessage {
     DemotedToSpectator {
         room: RoomInfo,
     },
+    /// Confirms `SetVideoMode`, also sent when a promoted spectator is switched back to the full stream
+    VideoModeChanged {
+        mode: PeerVideoMode,
+    },
     /// Player slot request result
     PlayerSlotRequestResult {
         granted: bool,
@@ -651,23 +1758,79 @@ pub enum StreamServerMessage {
     },
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, TS)]
+#[derive(Serialize, Deserialize, Debug, Clone, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub enum GeneralServerMessage {
-    ConnectionStatusUpdate { status: ConnectionStatus },
+    ConnectionStatusUpdate {
+        status: ConnectionStatus,
+    },
+    /// Confirms `SetMouseMode`, the streamer ignores the mouse movement of the other mode from now on
+    MouseModeChanged {
+        relative: Option<bool>,
+    },
+    /// Someone joined, left or got new rights in the room.
+    /// Only sent to the host so it can show it over the stream, e.g. while in fullscreen.
+    RoomEvent {
+        kind: RoomEventKind,
+        name: Option<String>,
+        slot: Option<PlayerSlot>,
+    },
+    /// A graded view of the connection to the host, only sent when the level changed and held for a moment
+    QualityReport {
+        level: QualityLevel,
+        /// None if moonlight couldn't estimate it yet
+        rtt_ms: Option<f64>,
+        /// The frames lost on the way from the host in the last measurement
+        loss_pct: f64,
+    },
+    /// The host allowed or disallowed the keyboard and mouse of this peer,
+    /// the keys and mouse buttons it held were released on the host
+    InputPermissionChanged {
+        keyboard_mouse: bool,
+    },
 }
 
-#[derive(Serialize, Deserialize, Debug, TS)]
+/// Combines the round trip time, the lost frames and the [ConnectionStatus] moonlight reports
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, TS, JsonSchema,
+)]
 #[ts(export, export_to = EXPORT_PATH)]
-pub enum GeneralClientMessage {}
+pub enum QualityLevel {
+    Poor,
+    Fair,
+    Good,
+    Excellent,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub enum RoomEventKind {
+    Joined,
+    Left,
+    /// A spectator became a player
+    Promoted,
+    /// The host changed which input a player can use
+    PermissionsChanged,
+}
 
-#[derive(Serialize, Deserialize, Debug, Clone, TS)]
+#[derive(Serialize, Deserialize, Debug, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub enum GeneralClientMessage {
+    /// Relative mouse movement is sent while the browser has pointer lock, absolute positions otherwise.
+    /// None if the client sends both, e.g. when pointing and dragging.
+    SetMouseMode { relative: Option<bool> },
+    /// Host-only: Whether the host receives `RoomEvent`s, enabled by default
+    SetRoomEventsEnabled { enabled: bool },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub enum ConnectionStatus {
     Ok,
     Poor,
 }
 
+#[cfg(feature = "server")]
 impl From<moonlight_common::stream::bindings::ConnectionStatus> for ConnectionStatus {
     fn from(value: moonlight_common::stream::bindings::ConnectionStatus) -> Self {
         use moonlight_common::stream::bindings::ConnectionStatus;
@@ -678,7 +1841,7 @@ impl From<moonlight_common::stream::bindings::ConnectionStatus> for ConnectionSt
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, TS)]
+#[derive(Serialize, Deserialize, Debug, Clone, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct StatsHostProcessingLatency {
     pub min_host_processing_latency_ms: f64,
@@ -686,7 +1849,25 @@ pub struct StatsHostProcessingLatency {
     pub avg_host_processing_latency_ms: f64,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, TS)]
+/// How long a stage of the stream start took, e.g. launching the app or the RTSP handshake
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct StreamStageTiming {
+    pub stage: String,
+    #[ts(type = "number")]
+    pub duration_ms: u64,
+}
+
+/// The stats a client can request from the streamer, look at `StreamerStatsUpdate`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub enum StreamerStatsType {
+    Rtt,
+    Video,
+    FrameLatency,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub enum StreamerStatsUpdate {
     Rtt {
@@ -699,6 +1880,15 @@ pub enum StreamerStatsUpdate {
         max_streamer_processing_time_ms: f64,
         avg_streamer_processing_time_ms: f64,
     },
+    /// The time from the streamer sending a frame to the client rendering it,
+    /// estimated from the frames the client reported since the last update.
+    /// It includes the shortest way of a report back to the streamer.
+    FrameLatency {
+        p50_ms: f64,
+        p95_ms: f64,
+        p99_ms: f64,
+        samples: u32,
+    },
 }
 
 // Virtual-Key Codes
@@ -1161,6 +2351,7 @@ ts_consts!(
 );
 
 // Key Modifiers
+#[cfg(feature = "server")]
 ts_consts!(
     pub StreamKeyModifiers(export_bindings_key_modifiers: EXPORT_PATH):
 
@@ -1171,6 +2362,7 @@ ts_consts!(
 );
 
 // Mouse Buttons
+#[cfg(feature = "server")]
 ts_consts!(
     pub StreamMouseButton(export_bindings_mouse_buttons: EXPORT_PATH):
 
@@ -1182,6 +2374,7 @@ ts_consts!(
 );
 
 // Controller Buttons
+#[cfg(feature = "server")]
 ts_consts!(
     pub StreamControllerButton(export_bindings_controller_buttons: EXPORT_PATH):
 
@@ -1209,6 +2402,7 @@ ts_consts!(
 );
 
 // Controller Buttons
+#[cfg(feature = "server")]
 ts_consts!(
     pub StreamControllerCapabilities(export_bindings_controller_capabilities: EXPORT_PATH):
 
@@ -1216,7 +2410,7 @@ ts_consts!(
     pub const CAPABILITY_TRIGGER_RUMBLE: u16 = ControllerCapabilities::TRIGGER_RUMBLE.bits();
 );
 
-#[derive(Serialize, Deserialize, Debug, Clone, TS)]
+#[derive(Serialize, Deserialize, Debug, Clone, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub enum StreamColorspace {
     Rec601,
@@ -1224,6 +2418,7 @@ pub enum StreamColorspace {
     Rec2020,
 }
 
+#[cfg(feature = "server")]
 impl From<StreamColorspace> for Colorspace {
     fn from(value: StreamColorspace) -> Self {
         match value {
@@ -1235,6 +2430,7 @@ impl From<StreamColorspace> for Colorspace {
 }
 
 // Video Supported Codec
+#[cfg(feature = "server")]
 ts_consts!(
     pub StreamSupportedVideoCodecs(export_bindings_supported_video_codecs: EXPORT_PATH):
 
@@ -1252,40 +2448,101 @@ ts_consts!(
 
 // Discord Activity API types
 
-#[derive(Serialize, Deserialize, Debug, Clone, TS)]
+#[derive(Serialize, Deserialize, Debug, Clone, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct PostDiscordTokenRequest {
     pub code: String,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, TS)]
+#[derive(Serialize, Deserialize, Debug, Clone, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct PostDiscordTokenResponse {
     pub access_token: String,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, TS)]
+#[derive(Serialize, Deserialize, Debug, Clone, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct GetDiscordRoomQuery {
     pub instance_id: String,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, TS)]
+#[derive(Serialize, Deserialize, Debug, Clone, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct GetDiscordRoomResponse {
     pub room_id: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, TS)]
+#[derive(Serialize, Deserialize, Debug, Clone, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct PostDiscordRoomRequest {
     pub instance_id: String,
     pub host_id: u32,
     pub app_id: u32,
+    /// The channel the activity was launched in
+    #[serde(default)]
+    pub channel_id: Option<String>,
+    /// The guild the activity was launched in, None for dms
+    #[serde(default)]
+    pub guild_id: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, TS)]
+#[derive(Serialize, Deserialize, Debug, Clone, TS, JsonSchema)]
 #[ts(export, export_to = EXPORT_PATH)]
 pub struct PostDiscordRoomResponse {
     pub room_id: String,
 }
+
+#[derive(Serialize, Deserialize, Debug, Clone, TS, JsonSchema)]
+#[ts(export, export_to = EXPORT_PATH)]
+pub struct DeleteDiscordRoomQuery {
+    pub instance_id: String,
+}
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+    use moonlight_common::stream::bindings::{ServerCodeModeSupport, SupportedVideoFormats};
+
+    use crate::api_bindings::{HostCapabilities, HostVideoCodec};
+
+    #[test]
+    fn test_host_capabilities() {
+        let capabilities = HostCapabilities::new(
+            (ServerCodeModeSupport::H264
+                | ServerCodeModeSupport::HEVC
+                | ServerCodeModeSupport::HEVC_MAIN10)
+                .bits(),
+            true,
+        );
+        assert_eq!(
+            capabilities.video_codecs,
+            vec![
+                HostVideoCodec::H264,
+                HostVideoCodec::H265,
+                HostVideoCodec::H265Main10
+            ]
+        );
+        assert_eq!(
+            capabilities.supported_video_formats,
+            (SupportedVideoFormats::H264
+                | SupportedVideoFormats::H265
+                | SupportedVideoFormats::H265_MAIN10)
+                .bits()
+        );
+        assert!(capabilities.hdr);
+        assert!(!capabilities.yuv444);
+        assert!(capabilities.above_4k);
+
+        let capabilities =
+            HostCapabilities::new(ServerCodeModeSupport::H264_HIGH8_444.bits(), true);
+        assert_eq!(
+            capabilities.video_codecs,
+            vec![HostVideoCodec::H264High8_444]
+        );
+        assert!(!capabilities.hdr);
+        assert!(capabilities.yuv444);
+        assert!(!capabilities.above_4k);
+
+        // Sunshine doesn't have the 4K limitation
+        assert!(HostCapabilities::new(0, false).above_4k);
+    }
+}
@@ -1,17 +1,22 @@
 use std::{
+    collections::HashMap,
     fmt::Display,
-    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
-    num::ParseIntError,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    num::{NonZeroUsize, ParseIntError},
     str::FromStr,
     time::Duration,
 };
 
+use ipnet::IpNet;
 use log::LevelFilter;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use thiserror::Error;
 
-use crate::api_bindings::RtcIceServer;
+use crate::{
+    api_bindings::{DataChannelOptions, RtcIceServer},
+    ipc::DEFAULT_IPC_CHANNEL_CAPACITY,
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
@@ -23,27 +28,109 @@ pub struct Config {
     pub web_server: WebServerConfig,
     #[serde(default)]
     pub moonlight: MoonlightConfig,
-    #[serde(default = "default_streamer_path")]
-    pub streamer_path: String,
+    /// Deprecated: use `streamer.path`, see [Config::migrate_deprecated]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub streamer_path: Option<String>,
+    /// How many messages are queued between the web server and a streamer before sending waits.
+    /// Video frames sent over the WebSocket transport are dropped instead of waiting
+    #[serde(default = "default_streamer_ipc_capacity")]
+    pub streamer_ipc_capacity: NonZeroUsize,
+    #[serde(default, alias = "streamer_process")]
+    pub streamer: StreamerProcessConfig,
     #[serde(default)]
     pub log: LogConfig,
     #[serde(default)]
     pub default_settings: Option<Value>,
     #[serde(default)]
     pub discord: Option<DiscordConfig>,
+    #[serde(default)]
+    pub rooms: RoomConfig,
+    /// Records the raw video of every stream into this directory to help with bug reports.
+    /// This stores everything the users see on the server, so only enable it while debugging!
+    #[serde(default)]
+    pub record_path: Option<String>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             data_storage: Default::default(),
-            streamer_path: default_streamer_path(),
+            streamer_path: None,
+            streamer_ipc_capacity: default_streamer_ipc_capacity(),
+            streamer: Default::default(),
             web_server: Default::default(),
             moonlight: Default::default(),
             webrtc: Default::default(),
             log: Default::default(),
             default_settings: Default::default(),
             discord: Default::default(),
+            rooms: Default::default(),
+            record_path: Default::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Moves the values of deprecated options into their replacements.
+    /// Returns the names of the deprecated options which were used.
+    pub fn migrate_deprecated(&mut self) -> Vec<&'static str> {
+        let mut deprecated = Vec::new();
+
+        if let Some(streamer_path) = self.streamer_path.take() {
+            self.streamer.path = streamer_path;
+            deprecated.push("streamer_path");
+        }
+
+        deprecated
+    }
+}
+
+// -- Streamer Process Config
+
+/// How the streamer processes are started and how the operating system schedules them,
+/// e.g. to run them under a wrapper or to pin the streamers of a busy server to specific cpus
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StreamerProcessConfig {
+    /// The streamer executable or a wrapper which starts it, e.g. "firejail".
+    /// Paths without a directory are searched in the `PATH`.
+    #[serde(default = "default_streamer_path")]
+    pub path: String,
+    /// Passed before the arguments of the web server, e.g. the streamer path when using a wrapper
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Added to the environment the web server passes on
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    #[serde(default)]
+    pub working_dir: Option<String>,
+    /// The niceness of the streamers from -20 (highest priority) to 19 (lowest priority).
+    /// A higher priority than the web server needs the privileges to raise it.
+    #[serde(default)]
+    pub nice: Option<i32>,
+    /// The indices of the cpus the streamers are allowed to run on.
+    /// Only supported on Linux.
+    #[serde(default)]
+    pub cpu_affinity: Option<Vec<usize>>,
+    /// The resident memory in bytes above which a streamer only sends keyframes
+    /// and stops the stream if it stays above for the `memory_grace_period`.
+    /// Only supported on Linux.
+    #[serde(default)]
+    pub memory_limit: Option<u64>,
+    #[serde(default = "default_memory_grace_period")]
+    pub memory_grace_period: Duration,
+}
+
+impl Default for StreamerProcessConfig {
+    fn default() -> Self {
+        Self {
+            path: default_streamer_path(),
+            args: Default::default(),
+            env: Default::default(),
+            working_dir: Default::default(),
+            nice: Default::default(),
+            cpu_affinity: Default::default(),
+            memory_limit: Default::default(),
+            memory_grace_period: default_memory_grace_period(),
         }
     }
 }
@@ -67,6 +154,9 @@ pub struct DiscordConfig {
 pub struct LogConfig {
     pub level_filter: LevelFilter,
     pub file_path: Option<String>,
+    /// Removes the last part of client ips before they're logged.
+    #[serde(default)]
+    pub anonymize_ips: bool,
 }
 
 impl Default for LogConfig {
@@ -74,6 +164,7 @@ impl Default for LogConfig {
         Self {
             level_filter: default_level_filter(),
             file_path: None,
+            anonymize_ips: false,
         }
     }
 }
@@ -90,6 +181,9 @@ pub enum StorageConfig {
     Json {
         path: String,
         session_expiration_check_interval: Duration,
+        /// Changes are batched and written at most once per interval
+        #[serde(default = "default_json_write_interval")]
+        write_interval: Duration,
     },
 }
 
@@ -98,6 +192,7 @@ impl Default for StorageConfig {
         StorageConfig::Json {
             path: "server/data.json".to_string(),
             session_expiration_check_interval: default_session_expiration_check_interval(),
+            write_interval: default_json_write_interval(),
         }
     }
 }
@@ -105,6 +200,9 @@ impl Default for StorageConfig {
 fn default_session_expiration_check_interval() -> Duration {
     Duration::from_mins(5)
 }
+fn default_json_write_interval() -> Duration {
+    Duration::from_millis(500)
+}
 
 // -- WebRTC Config
 
@@ -120,6 +218,23 @@ pub struct WebRtcConfig {
     pub network_types: Vec<WebRtcNetworkType>,
     #[serde(default = "default_include_loopback_candidates")]
     pub include_loopback_candidates: bool,
+    /// Only these interfaces are used for ICE candidates, every interface if empty
+    #[serde(default)]
+    pub interface_allowlist: Vec<WebRtcInterfaceFilter>,
+    /// These interfaces are never used for ICE candidates, e.g. docker bridges or vpn tunnels
+    #[serde(default)]
+    pub interface_blocklist: Vec<WebRtcInterfaceFilter>,
+    /// Don't resolve or gather mDNS (".local") candidates
+    #[serde(default)]
+    pub disable_mdns: bool,
+    /// If false, guests only receive server reflexive and relay candidates so they can't see local addresses.
+    /// The host of the room always receives host candidates to stream over the lan.
+    #[serde(default = "default_guest_host_candidates")]
+    pub guest_host_candidates: bool,
+    /// Overrides the options of the data channels, keyed by the channel label e.g. "mouse_absolute".
+    /// The video and audio of the host are sent as media tracks and can't be configured here.
+    #[serde(default)]
+    pub data_channels: HashMap<String, DataChannelOptions>,
 }
 
 impl Default for WebRtcConfig {
@@ -130,10 +245,123 @@ impl Default for WebRtcConfig {
             nat_1to1: None,
             network_types: default_network_types(),
             include_loopback_candidates: default_include_loopback_candidates(),
+            interface_allowlist: Vec::new(),
+            interface_blocklist: Vec::new(),
+            disable_mdns: false,
+            guest_host_candidates: default_guest_host_candidates(),
+            data_channels: HashMap::new(),
         }
     }
 }
 
+impl WebRtcConfig {
+    /// The options of every data channel with the overrides of the config applied
+    pub fn data_channel_options(&self) -> HashMap<String, DataChannelOptions> {
+        let mut options = default_data_channel_options();
+        options.extend(
+            self.data_channels
+                .iter()
+                .map(|(label, channel)| (label.clone(), *channel)),
+        );
+
+        options
+    }
+
+    /// If ICE candidates may be gathered on the interface with this name
+    pub fn is_interface_allowed(&self, name: &str) -> bool {
+        self.is_allowed(|filter| filter.matches_name(name))
+    }
+
+    /// If ICE candidates may be gathered on this address
+    pub fn is_ip_allowed(&self, ip: IpAddr) -> bool {
+        self.is_allowed(|filter| filter.matches_ip(ip))
+    }
+
+    fn is_allowed(&self, matches: impl Fn(&WebRtcInterfaceFilter) -> Option<bool>) -> bool {
+        let mut allowlist = self
+            .interface_allowlist
+            .iter()
+            .filter_map(&matches)
+            .peekable();
+        let allowed = allowlist.peek().is_none() || allowlist.any(|matched| matched);
+
+        allowed
+            && !self
+                .interface_blocklist
+                .iter()
+                .filter_map(&matches)
+                .any(|matched| matched)
+    }
+}
+
+/// Selects a network interface by its name or the cidr range of its addresses.
+/// A name ending with "*" matches every interface starting with it, e.g. "docker*".
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum WebRtcInterfaceFilter {
+    Range(IpNet),
+    Name(String),
+}
+
+impl WebRtcInterfaceFilter {
+    /// None if this filter doesn't select by name
+    pub fn matches_name(&self, name: &str) -> Option<bool> {
+        let Self::Name(pattern) = self else {
+            return None;
+        };
+
+        Some(match pattern.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == pattern,
+        })
+    }
+
+    /// None if this filter doesn't select by address
+    pub fn matches_ip(&self, ip: IpAddr) -> Option<bool> {
+        let Self::Range(range) = self else {
+            return None;
+        };
+
+        Some(range.contains(&ip))
+    }
+}
+
+/// Input which only carries the latest state can be dropped,
+/// everything else must arrive to not e.g. leave keys or buttons pressed on the host.
+fn default_data_channel_options() -> HashMap<String, DataChannelOptions> {
+    let mut options = HashMap::from([
+        ("general".to_string(), DataChannelOptions::RELIABLE_ORDERED),
+        ("stats".to_string(), DataChannelOptions::RELIABLE_ORDERED),
+        (
+            "mouse_reliable".to_string(),
+            DataChannelOptions::RELIABLE_ORDERED,
+        ),
+        (
+            "mouse_absolute".to_string(),
+            DataChannelOptions::UNRELIABLE_UNORDERED,
+        ),
+        // Relative movements add up, so none of them can be lost
+        (
+            "mouse_relative".to_string(),
+            DataChannelOptions::RELIABLE_UNORDERED,
+        ),
+        ("keyboard".to_string(), DataChannelOptions::RELIABLE_ORDERED),
+        ("touch".to_string(), DataChannelOptions::RELIABLE_ORDERED),
+        (
+            "controllers".to_string(),
+            DataChannelOptions::RELIABLE_ORDERED,
+        ),
+    ]);
+    for controller in 0..16 {
+        options.insert(
+            format!("controller{controller}"),
+            DataChannelOptions::UNRELIABLE_UNORDERED,
+        );
+    }
+
+    options
+}
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum WebRtcNetworkType {
     #[serde(rename = "udp4")]
@@ -239,6 +467,9 @@ fn default_network_types() -> Vec<WebRtcNetworkType> {
 fn default_include_loopback_candidates() -> bool {
     true
 }
+fn default_guest_host_candidates() -> bool {
+    true
+}
 
 // -- Web Server Config
 
@@ -247,6 +478,10 @@ pub struct WebServerConfig {
     // TODO: create streamer overwrite for ice servers
     #[serde(default = "default_bind_address")]
     pub bind_address: SocketAddr,
+    /// Path of a unix domain socket to listen on instead of `bind_address`, e.g. for a reverse proxy on the same machine.
+    /// The socket is only accessible by the user and group of the web server.
+    #[serde(default)]
+    pub bind_unix: Option<String>,
     pub certificate: Option<ConfigSsl>,
     #[serde(default)]
     pub url_path_prefix: String,
@@ -254,10 +489,47 @@ pub struct WebServerConfig {
     pub session_cookie_secure: bool,
     #[serde(default = "default_session_cookie_expiration")]
     pub session_cookie_expiration: Duration,
+    /// Every authenticated request extends the session to `session_cookie_expiration` from then on,
+    /// but never beyond `session_max_lifetime` after the login
+    #[serde(default)]
+    pub session_sliding_expiration: bool,
+    /// How long a session with a sliding expiration lasts at most
+    #[serde(default = "default_session_max_lifetime")]
+    pub session_max_lifetime: Duration,
+    /// Sessions which weren't used for this long are logged out, e.g. on shared devices
+    #[serde(default)]
+    pub session_idle_timeout: Option<Duration>,
     pub first_login_create_admin: bool,
     pub first_login_assign_global_hosts: bool,
     pub default_user_id: Option<u32>,
     pub forwarded_header: Option<ForwardedHeaders>,
+    /// Proxies which are allowed to set the `X-Forwarded-For` and `Forwarded` headers.
+    /// The client ip is only read from those headers when the direct peer is inside one of these ranges.
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpNet>,
+    /// Serve a Swagger UI for the OpenAPI document of the api at `/api/docs`.
+    #[serde(default)]
+    pub openapi_swagger_ui: bool,
+    /// Which other origins can use the api, by default only the pages of this web server can.
+    #[serde(default)]
+    pub cors: CorsConfig,
+    /// Signs the invite links of rooms, a secret is generated and stored with the data if this isn't set.
+    /// Changing it invalidates all invite links.
+    #[serde(default)]
+    pub invite_secret: Option<String>,
+    /// How long an invite link is valid at most
+    #[serde(default = "default_invite_lifetime")]
+    pub invite_lifetime: Duration,
+    /// Guests can only join with an invite link, the room id alone isn't enough.
+    /// Rooms of Discord Activities can still be joined without one.
+    #[serde(default)]
+    pub require_guest_invites: bool,
+    /// Spectators which didn't send anything for this long are warned that they'll be disconnected, players are never idle
+    #[serde(default = "default_spectator_idle_warning")]
+    pub spectator_idle_warning: Duration,
+    /// Idle spectators are disconnected after this, unless they send something after the warning
+    #[serde(default = "default_spectator_idle_timeout")]
+    pub spectator_idle_timeout: Duration,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -270,14 +542,26 @@ impl Default for WebServerConfig {
     fn default() -> Self {
         Self {
             bind_address: default_bind_address(),
+            bind_unix: None,
             certificate: None,
             url_path_prefix: "".to_string(),
             session_cookie_secure: default_session_cookie_secure(),
             session_cookie_expiration: default_session_cookie_expiration(),
+            session_sliding_expiration: false,
+            session_max_lifetime: default_session_max_lifetime(),
+            session_idle_timeout: None,
             first_login_create_admin: true,
             first_login_assign_global_hosts: true,
             default_user_id: None,
             forwarded_header: None,
+            trusted_proxies: Vec::new(),
+            openapi_swagger_ui: false,
+            cors: Default::default(),
+            invite_secret: None,
+            invite_lifetime: default_invite_lifetime(),
+            require_guest_invites: false,
+            spectator_idle_warning: default_spectator_idle_warning(),
+            spectator_idle_timeout: default_spectator_idle_timeout(),
         }
     }
 }
@@ -293,6 +577,26 @@ fn default_session_cookie_expiration() -> Duration {
 
     Duration::from_secs(DAY_SECONDS)
 }
+fn default_session_max_lifetime() -> Duration {
+    Duration::from_hours(30 * 24)
+}
+fn default_invite_lifetime() -> Duration {
+    Duration::from_hours(24)
+}
+fn default_spectator_idle_warning() -> Duration {
+    Duration::from_mins(10)
+}
+fn default_spectator_idle_timeout() -> Duration {
+    Duration::from_mins(15)
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CorsConfig {
+    /// Origins of frontends which aren't served by this web server, e.g. `https://example.com`.
+    /// The Discord Activity proxy is allowed automatically when Discord is configured.
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ForwardedHeaders {
@@ -314,14 +618,89 @@ fn default_forwarded_headers_auto_create_user() -> bool {
     true
 }
 
+// -- Rooms
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomConfig {
+    /// How many spectators can watch a room at the same time, the host of a room can change this for their room
+    #[serde(default = "default_max_spectators")]
+    pub max_spectators: usize,
+    /// Spectators joining a full room wait in a queue until a slot frees instead of being rejected
+    #[serde(default = "default_spectator_queue")]
+    pub spectator_queue: bool,
+    /// How often rooms without clients or a streamer are removed
+    #[serde(default = "default_room_cleanup_interval")]
+    pub cleanup_interval: Duration,
+    /// Persists the rooms into this directory so the streams keep running when the web server restarts.
+    /// The clients reconnect to their room after the restart. Only supported on unix
+    #[serde(default)]
+    pub runtime_path: Option<String>,
+    /// How long a streamer of a persisted room waits for the restarted web server before it stops
+    #[serde(default = "default_streamer_reattach_timeout")]
+    pub streamer_reattach_timeout: Duration,
+}
+
+impl Default for RoomConfig {
+    fn default() -> Self {
+        Self {
+            max_spectators: default_max_spectators(),
+            spectator_queue: default_spectator_queue(),
+            cleanup_interval: default_room_cleanup_interval(),
+            runtime_path: Default::default(),
+            streamer_reattach_timeout: default_streamer_reattach_timeout(),
+        }
+    }
+}
+
+fn default_max_spectators() -> usize {
+    16
+}
+fn default_spectator_queue() -> bool {
+    true
+}
+fn default_room_cleanup_interval() -> Duration {
+    Duration::from_mins(1)
+}
+fn default_streamer_reattach_timeout() -> Duration {
+    Duration::from_mins(1)
+}
+
 // -- Moonlight
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MoonlightConfig {
     #[serde(default = "default_moonlight_http_port")]
     pub default_http_port: u16,
+    /// The hosts list the pairings of a user as "<pair_device_name> (<user name>)" unless the user changed the name
     #[serde(default = "default_pair_device_name")]
     pub pair_device_name: String,
+    /// How many hosts can be paired at the same time, a single host can only be paired once at a time
+    #[serde(default = "default_max_concurrent_pairings")]
+    pub max_concurrent_pairings: usize,
+    /// The address the Wake-on-LAN magic packet is sent to, e.g. the directed broadcast address of the hosts VLAN.
+    /// If this is not set the packet is broadcast to 255.255.255.255:9
+    #[serde(default)]
+    pub wake_on_lan_address: Option<SocketAddr>,
+    /// Rejects hosts whose address resolves to this machine or a private network (loopback, private, link-local),
+    /// so users of a public instance can't use the server to send requests into its network
+    #[serde(default)]
+    pub block_private_host_ranges: bool,
+    #[serde(default)]
+    pub app_image_cache: AppImageCacheConfig,
+    /// How long the streamer waits for a stage of the stream start before it aborts the start,
+    /// e.g. when the host stops responding during the RTSP handshake
+    #[serde(default = "default_stage_timeout")]
+    pub stage_timeout: Duration,
+    /// A host is shown as flapping when it came back online more often than this within an hour
+    #[serde(default = "default_host_flap_threshold")]
+    pub host_flap_threshold: usize,
+    /// How often the hosts which an admin enabled the reachability probe for are checked
+    #[serde(default = "default_host_probe_interval")]
+    pub host_probe_interval: Duration,
+    /// Checks that the host responds before the room is created and the streamer is started,
+    /// disable it for hosts which don't answer the check in time but can still stream
+    #[serde(default = "default_host_start_check")]
+    pub host_start_check: bool,
 }
 
 impl Default for MoonlightConfig {
@@ -329,6 +708,34 @@ impl Default for MoonlightConfig {
         Self {
             default_http_port: default_moonlight_http_port(),
             pair_device_name: default_pair_device_name(),
+            max_concurrent_pairings: default_max_concurrent_pairings(),
+            wake_on_lan_address: None,
+            block_private_host_ranges: false,
+            app_image_cache: Default::default(),
+            stage_timeout: default_stage_timeout(),
+            host_flap_threshold: default_host_flap_threshold(),
+            host_probe_interval: default_host_probe_interval(),
+            host_start_check: default_host_start_check(),
+        }
+    }
+}
+
+/// The box arts of the apps are cached in memory so they aren't requested from the host every time
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppImageCacheConfig {
+    /// The total bytes of all cached box arts, the least recently used ones are evicted first
+    #[serde(default = "default_app_image_cache_max_size")]
+    pub max_size: usize,
+    /// How long a box art is cached before it's requested from the host again
+    #[serde(default = "default_app_image_cache_ttl")]
+    pub ttl: Duration,
+}
+
+impl Default for AppImageCacheConfig {
+    fn default() -> Self {
+        Self {
+            max_size: default_app_image_cache_max_size(),
+            ttl: default_app_image_cache_ttl(),
         }
     }
 }
@@ -341,6 +748,154 @@ fn default_pair_device_name() -> String {
     "roth".to_string()
 }
 
+fn default_max_concurrent_pairings() -> usize {
+    4
+}
+
+fn default_app_image_cache_max_size() -> usize {
+    64 * 1024 * 1024
+}
+fn default_app_image_cache_ttl() -> Duration {
+    Duration::from_hours(1)
+}
+fn default_stage_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+fn default_host_flap_threshold() -> usize {
+    3
+}
+fn default_host_probe_interval() -> Duration {
+    Duration::from_mins(5)
+}
+fn default_host_start_check() -> bool {
+    true
+}
+
+fn default_memory_grace_period() -> Duration {
+    Duration::from_mins(1)
+}
+
 fn default_streamer_path() -> String {
     "./streamer".to_string()
 }
+fn default_streamer_ipc_capacity() -> NonZeroUsize {
+    DEFAULT_IPC_CHANNEL_CAPACITY
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+
+    use crate::config::{Config, WebRtcInterfaceFilter};
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().expect("invalid ip address")
+    }
+
+    #[test]
+    fn test_interface_filter() {
+        let range = WebRtcInterfaceFilter::Range("172.16.0.0/12".parse().expect("invalid range"));
+        assert_eq!(range.matches_ip(ip("172.17.0.1")), Some(true));
+        assert_eq!(range.matches_ip(ip("192.168.0.1")), Some(false));
+        assert_eq!(range.matches_ip(ip("fd00::1")), Some(false));
+        assert_eq!(range.matches_name("eth0"), None);
+
+        let name = WebRtcInterfaceFilter::Name("docker*".to_string());
+        assert_eq!(name.matches_name("docker0"), Some(true));
+        assert_eq!(name.matches_name("eth0"), Some(false));
+        assert_eq!(name.matches_ip(ip("172.17.0.1")), None);
+
+        let name = WebRtcInterfaceFilter::Name("tun0".to_string());
+        assert_eq!(name.matches_name("tun0"), Some(true));
+        assert_eq!(name.matches_name("tun01"), Some(false));
+    }
+
+    #[test]
+    fn test_webrtc_interface_options() {
+        let config: Config = serde_json::from_str(
+            r#"{
+                "webrtc": {
+                    "interface_allowlist": ["192.168.0.0/16", "fd00::/8"],
+                    "interface_blocklist": ["docker*", "tun0"],
+                    "disable_mdns": true,
+                    "guest_host_candidates": false
+                }
+            }"#,
+        )
+        .expect("failed to parse config");
+
+        let webrtc = &config.webrtc;
+        assert_eq!(
+            webrtc.interface_allowlist,
+            [
+                WebRtcInterfaceFilter::Range("192.168.0.0/16".parse().expect("invalid range")),
+                WebRtcInterfaceFilter::Range("fd00::/8".parse().expect("invalid range")),
+            ]
+        );
+        assert_eq!(
+            webrtc.interface_blocklist,
+            [
+                WebRtcInterfaceFilter::Name("docker*".to_string()),
+                WebRtcInterfaceFilter::Name("tun0".to_string()),
+            ]
+        );
+        assert!(webrtc.disable_mdns);
+        assert!(!webrtc.guest_host_candidates);
+
+        // Names in the allowlist are checked against the name only, ranges against the address only
+        assert!(webrtc.is_interface_allowed("eth0"));
+        assert!(!webrtc.is_interface_allowed("docker0"));
+        assert!(webrtc.is_ip_allowed(ip("192.168.1.5")));
+        assert!(!webrtc.is_ip_allowed(ip("10.8.0.2")));
+
+        let config: Config = serde_json::from_str("{}").expect("failed to parse config");
+        assert!(config.webrtc.is_interface_allowed("docker0"));
+        assert!(config.webrtc.is_ip_allowed(ip("10.8.0.2")));
+        assert!(!config.webrtc.disable_mdns);
+        assert!(config.webrtc.guest_host_candidates);
+    }
+
+    #[test]
+    fn test_deprecated_streamer_options() {
+        let mut config: Config = serde_json::from_str(
+            r#"{
+                "streamer_path": "/usr/bin/streamer",
+                "streamer_process": { "nice": 5 }
+            }"#,
+        )
+        .expect("failed to parse config");
+
+        assert_eq!(config.migrate_deprecated(), ["streamer_path"]);
+
+        assert_eq!(config.streamer_path, None);
+        assert_eq!(config.streamer.path, "/usr/bin/streamer");
+        assert_eq!(config.streamer.nice, Some(5));
+    }
+
+    #[test]
+    fn test_streamer_options() {
+        let config: Config = serde_json::from_str(
+            r#"{
+                "streamer": {
+                    "path": "taskset",
+                    "args": ["-c", "2", "./streamer"],
+                    "env": { "RUST_BACKTRACE": "1" },
+                    "working_dir": "/srv"
+                }
+            }"#,
+        )
+        .expect("failed to parse config");
+
+        assert_eq!(config.streamer.path, "taskset");
+        assert_eq!(config.streamer.args, ["-c", "2", "./streamer"]);
+        assert_eq!(
+            config
+                .streamer
+                .env
+                .get("RUST_BACKTRACE")
+                .map(String::as_str),
+            Some("1")
+        );
+        assert_eq!(config.streamer.working_dir.as_deref(), Some("/srv"));
+    }
+}
@@ -1,36 +1,66 @@
 use std::{
     marker::PhantomData,
-    sync::atomic::{AtomicUsize, Ordering},
+    num::NonZeroUsize,
+    pin::pin,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    },
+    time::Duration,
 };
 
 use bytes::Bytes;
 use log::{LevelFilter, info, trace, warn};
 use pem::Pem;
 use serde::{Deserialize, Serialize, de::DeserializeOwned};
+#[cfg(unix)]
+use std::{io, path::Path};
+use thiserror::Error;
 use tokio::{
     io::{
-        AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader, Lines, Stdin, Stdout,
+        AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, Split,
+        Stdin,
     },
     process::{ChildStderr, ChildStdin, ChildStdout},
     spawn,
-    sync::mpsc::{Receiver, Sender, channel},
+    sync::{
+        Notify,
+        mpsc::{Receiver, Sender, channel, error::TrySendError},
+    },
+};
+#[cfg(unix)]
+use tokio::{
+    net::{UnixListener, UnixStream, unix::OwnedWriteHalf},
+    select,
+    time::{Instant, sleep, timeout},
 };
 
+pub use crate::api_bindings::PeerId;
 use crate::{
-    api_bindings::{PlayerSlot, RoomRole, StreamClientMessage, StreamServerMessage},
+    api_bindings::{
+        ControllerMode, PeerPermissions, PlayerSlot, RoomRole, StreamClientMessage,
+        StreamServerMessage,
+    },
     config::WebRtcConfig,
 };
 
+#[cfg(feature = "ipc-multiplex")]
+pub mod multiplex;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StreamerConfig {
     pub webrtc: WebRtcConfig,
     pub log_level: LevelFilter,
+    /// The directory to record the raw video to, disabled if none
+    pub record_path: Option<String>,
+    /// How long a stage of the moonlight stream start may take before the start is aborted
+    pub stage_timeout: Duration,
+    /// The resident memory in bytes above which the streamer only sends keyframes, None if unlimited
+    pub memory_limit: Option<u64>,
+    /// How long the memory may stay above the limit before the stream is stopped
+    pub memory_grace_period: Duration,
 }
 
-/// Unique identifier for a connected peer/client
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
-pub struct PeerId(pub u64);
-
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ServerIpcMessage {
@@ -38,6 +68,8 @@ pub enum ServerIpcMessage {
         config: StreamerConfig,
         host_address: String,
         host_http_port: u16,
+        /// Used instead of the https port reported by the host
+        host_https_port_override: Option<u16>,
         client_unique_id: Option<String>,
         client_private_key: Pem,
         client_certificate: Pem,
@@ -55,6 +87,8 @@ pub enum ServerIpcMessage {
         role: RoomRole,
         video_frame_queue_size: usize,
         audio_sample_queue_size: usize,
+        /// The name shown to the other participants
+        name: Option<String>,
     },
     /// A peer's role has changed (spectator <-> player)
     PeerRoleChanged {
@@ -83,6 +117,32 @@ pub enum ServerIpcMessage {
     SetGuestsKeyboardMouseEnabled {
         enabled: bool,
     },
+    /// Set which input a single peer can use
+    SetPeerPermissions {
+        peer_id: PeerId,
+        permissions: PeerPermissions,
+    },
+    /// Delay the video and audio of all spectators, zero disables the delay
+    SetSpectatorDelay {
+        delay: Duration,
+    },
+    /// Change how the gamepads of the peers are mapped to the gamepads of the host
+    SetControllerMode {
+        mode: ControllerMode,
+    },
+    /// Set the player slots of the additional gamepads of a peer, the gamepad with id 1 uses the first slot
+    SetLocalGamepadSlots {
+        peer_id: PeerId,
+        slots: Vec<PlayerSlot>,
+    },
+    /// Stop the Moonlight stream gracefully, the clients receive `ConnectionTerminated` with error code 0
+    StopStream,
+    /// Stop or continue sending video and audio to the peers, the Moonlight stream keeps running
+    SetStreamPaused {
+        paused: bool,
+    },
+    /// The web server restarted and connected to the ipc socket again, all peers of the old connection are gone
+    Reattached,
     Stop,
 }
 
@@ -106,6 +166,13 @@ pub enum StreamerIpcMessage {
     PeerReady {
         peer_id: PeerId,
     },
+    /// Sent periodically with the resources the streamer uses
+    ResourceReport {
+        /// The resident memory in bytes, None if it can't be measured on this platform
+        rss_bytes: Option<u64>,
+        /// The queued video frames, audio samples and delayed bytes of each peer
+        queues: Vec<(PeerId, usize, usize, usize)>,
+    },
     Stop,
 }
 
@@ -113,11 +180,32 @@ pub enum StreamerIpcMessage {
 // Stdin: message passing
 // Stdout: message passing
 // Stderr: logging
+//
+// When the rooms are persisted the streamer listens on a unix socket for the messages instead,
+// so the web server can connect to it again after a restart.
+
+/// The streamer listens on this unix socket for the ipc instead of using stdin / stdout
+pub const STREAMER_IPC_SOCKET_ARG: &str = "--ipc-socket";
+/// How long (in seconds) the streamer waits for the web server to connect to the ipc socket again before it stops
+pub const STREAMER_REATTACH_TIMEOUT_ARG: &str = "--reattach-timeout";
+/// How many messages the streamer queues for the web server, see [DEFAULT_IPC_CHANNEL_CAPACITY].
+/// The ipc exists before the streamer receives its [StreamerConfig], so this is passed as an argument
+pub const STREAMER_IPC_CAPACITY_ARG: &str = "--ipc-capacity";
+/// The id of the room a streamer belongs to, so its logs can be matched with the logs of the web server
+pub const STREAMER_ROOM_ID_ENV: &str = "MOONLIGHT_WEB_ROOM_ID";
+
+/// How many messages are queued for the other side before sending waits
+pub const DEFAULT_IPC_CHANNEL_CAPACITY: NonZeroUsize = NonZeroUsize::MIN.saturating_add(9);
+
+/// Every message starts with this byte and ends with a newline (like RFC 7464 json text sequences).
+/// Json never contains it unescaped, so everything in front of it was written by someone else, e.g. a stray print
+const IPC_FRAME_START: u8 = 0x1E;
 
 static CHILD_COUNTER: AtomicUsize = AtomicUsize::new(0);
 
 pub async fn create_child_ipc<Message, ChildMessage>(
     log_target: &str,
+    capacity: NonZeroUsize,
     stdin: ChildStdin,
     stdout: ChildStdout,
     stderr: Option<ChildStderr>,
@@ -142,11 +230,56 @@ where
         });
     }
 
-    let (sender, receiver) = channel::<Message>(10);
+    create_logged_ipc(log_target, capacity, stdout, stdin)
+}
+
+/// Connects to the ipc socket of a streamer which was started with [STREAMER_IPC_SOCKET_ARG].
+/// The streamer might not listen yet, so this retries until the timeout elapsed.
+#[cfg(unix)]
+pub async fn connect_child_ipc<Message, ChildMessage>(
+    log_target: &str,
+    socket_path: &Path,
+    connect_timeout: Duration,
+    capacity: NonZeroUsize,
+) -> io::Result<(IpcSender<Message>, IpcReceiver<ChildMessage>)>
+where
+    Message: Send + Serialize + 'static,
+    ChildMessage: DeserializeOwned,
+{
+    let deadline = Instant::now() + connect_timeout;
+
+    let stream = loop {
+        match UnixStream::connect(socket_path).await {
+            Ok(stream) => break stream,
+            Err(err) if Instant::now() >= deadline => return Err(err),
+            Err(_) => sleep(Duration::from_millis(50)).await,
+        }
+    };
+
+    let id = CHILD_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let log_target = format!("{log_target} {id}");
+
+    let (read, write) = stream.into_split();
+    Ok(create_logged_ipc(log_target, capacity, read, write))
+}
+
+fn create_logged_ipc<Message, ChildMessage>(
+    log_target: String,
+    capacity: NonZeroUsize,
+    read: impl AsyncRead + Send + Unpin + 'static,
+    write: impl AsyncWrite + Send + Unpin + 'static,
+) -> (IpcSender<Message>, IpcReceiver<ChildMessage>)
+where
+    Message: Send + Serialize + 'static,
+    ChildMessage: DeserializeOwned,
+{
+    let (sender, receiver) = channel::<Message>(capacity.get());
+    let pipe = Arc::new(Pipe::default());
 
     let sender_log_format = format!("{log_target}: ");
+    let sender_pipe = pipe.clone();
     spawn(async move {
-        ipc_sender(stdin, receiver, &sender_log_format).await;
+        ipc_sender(write, receiver, &sender_pipe, &sender_log_format).await;
     });
 
     let log_target = format!("{log_target}: ");
@@ -154,85 +287,211 @@ where
         IpcSender {
             sender,
             log_target: log_target.clone(),
+            backpressure: Default::default(),
+            pipe,
         },
         IpcReceiver {
             errored: false,
-            read: create_lines(stdout),
+            read: create_lines(read),
             phantom: Default::default(),
             log_target,
+            #[cfg(unix)]
+            reattach: None,
         },
     )
 }
 
+/// The stdout can be any writer in case stdout was moved, e.g. to stop libraries from printing into the ipc
 pub async fn create_process_ipc<ParentMessage, Message>(
     stdin: Stdin,
-    stdout: Stdout,
+    stdout: impl AsyncWrite + Send + Unpin + 'static,
+    capacity: NonZeroUsize,
 ) -> (IpcSender<Message>, IpcReceiver<ParentMessage>)
 where
     ParentMessage: DeserializeOwned,
     Message: Send + Serialize + 'static,
 {
-    let (sender, receiver) = channel::<Message>(10);
+    create_ipc(stdin, stdout, capacity)
+}
+
+/// Creates an ipc over any reader and writer, e.g. an in memory duplex stream.
+pub fn create_ipc<ReceiveMessage, Message>(
+    read: impl AsyncRead + Send + Unpin + 'static,
+    write: impl AsyncWrite + Send + Unpin + 'static,
+    capacity: NonZeroUsize,
+) -> (IpcSender<Message>, IpcReceiver<ReceiveMessage>)
+where
+    ReceiveMessage: DeserializeOwned,
+    Message: Send + Serialize + 'static,
+{
+    let (sender, receiver) = channel::<Message>(capacity.get());
+    let pipe = Arc::new(Pipe::default());
 
+    let sender_pipe = pipe.clone();
     spawn(async move {
-        ipc_sender(stdout, receiver, "").await;
+        ipc_sender(write, receiver, &sender_pipe, "").await;
     });
 
     (
         IpcSender {
             sender,
             log_target: "".to_string(),
+            backpressure: Default::default(),
+            pipe,
         },
         IpcReceiver {
             errored: false,
-            read: create_lines(stdin),
+            read: create_lines(read),
             phantom: Default::default(),
             log_target: "".to_string(),
+            #[cfg(unix)]
+            reattach: None,
         },
     )
 }
+
+/// The streamer side of an ipc over a unix socket, waits until the web server connected.
+///
+/// When the connection closes the receiver waits up to `reattach_timeout` for the web server to connect again.
+/// Messages which are sent while no web server is connected are dropped.
+#[cfg(unix)]
+pub async fn create_socket_ipc<ParentMessage, Message>(
+    listener: UnixListener,
+    reattach_timeout: Duration,
+    capacity: NonZeroUsize,
+) -> io::Result<(IpcSender<Message>, IpcReceiver<ParentMessage>)>
+where
+    ParentMessage: DeserializeOwned,
+    Message: Send + Serialize + 'static,
+{
+    let (stream, _) = listener.accept().await?;
+    let (read, write) = stream.into_split();
+
+    let (sender, receiver) = channel::<Message>(capacity.get());
+    let (writer_sender, writer_receiver) = channel::<OwnedWriteHalf>(1);
+
+    spawn(async move {
+        socket_ipc_sender(write, writer_receiver, receiver).await;
+    });
+
+    // The pipe never breaks, the messages are dropped until the web server connects again
+    Ok((
+        IpcSender {
+            sender,
+            log_target: "".to_string(),
+            backpressure: Default::default(),
+            pipe: Default::default(),
+        },
+        IpcReceiver {
+            errored: false,
+            read: create_lines(read),
+            phantom: Default::default(),
+            log_target: "".to_string(),
+            reattach: Some(Reattach {
+                listener,
+                timeout: reattach_timeout,
+                writers: writer_sender,
+            }),
+        },
+    ))
+}
+
+#[cfg(unix)]
+async fn socket_ipc_sender<Message>(
+    write: OwnedWriteHalf,
+    mut writers: Receiver<OwnedWriteHalf>,
+    mut receiver: Receiver<Message>,
+) where
+    Message: Serialize,
+{
+    let mut write = Some(write);
+
+    loop {
+        select! {
+            biased;
+            Some(new_write) = writers.recv() => {
+                write = Some(new_write);
+            }
+            message = receiver.recv() => {
+                let Some(message) = message else {
+                    return;
+                };
+
+                if let Some(current_write) = &mut write
+                    && !write_message(current_write, message, "").await
+                {
+                    write = None;
+                }
+            }
+        }
+    }
+}
+
 fn create_lines(
     read: impl AsyncRead + Send + Unpin + 'static,
-) -> Lines<Box<dyn AsyncBufRead + Send + Unpin + 'static>> {
-    (Box::new(BufReader::new(read)) as Box<dyn AsyncBufRead + Send + Unpin + 'static>).lines()
+) -> Split<Box<dyn AsyncBufRead + Send + Unpin + 'static>> {
+    // Split instead of lines because garbage might not be valid utf8
+    (Box::new(BufReader::new(read)) as Box<dyn AsyncBufRead + Send + Unpin + 'static>).split(b'\n')
 }
 
+/// Marks the pipe as broken when writing fails, before the queued messages are dropped
 async fn ipc_sender<Message>(
     mut write: impl AsyncWriteExt + Unpin,
     mut receiver: Receiver<Message>,
+    pipe: &Pipe,
     log_target: &str,
 ) where
     Message: Serialize,
 {
     while let Some(value) = receiver.recv().await {
-        let mut json = match serde_json::to_string(&value) {
-            Ok(value) => value,
-            Err(err) => {
-                warn!("[Ipc]: failed to encode message: {err:?}");
-                continue;
-            }
-        };
+        if !write_message(&mut write, value, log_target).await {
+            pipe.set_broken();
+            return;
+        }
+    }
+}
+
+/// Returns false if the writer is broken, messages which can't be encoded are skipped
+async fn write_message<Message>(
+    write: &mut (impl AsyncWriteExt + Unpin),
+    value: Message,
+    log_target: &str,
+) -> bool
+where
+    Message: Serialize,
+{
+    let mut json = match serde_json::to_string(&value) {
+        Ok(value) => value,
+        Err(err) => {
+            warn!("[Ipc]: failed to encode message: {err:?}");
+            return true;
+        }
+    };
 
-        trace!("{log_target}[Ipc] sending {json}");
+    trace!("{log_target}[Ipc] sending {json}");
 
-        json.push('\n');
+    json.insert(0, IPC_FRAME_START as char);
+    json.push('\n');
 
-        if let Err(err) = write.write_all(json.as_bytes()).await {
-            warn!("{log_target}[Ipc]: failed to write message length: {err:?}");
-            return;
-        };
+    if let Err(err) = write.write_all(json.as_bytes()).await {
+        warn!("{log_target}[Ipc]: failed to write message length: {err:?}");
+        return false;
+    };
 
-        if let Err(err) = write.flush().await {
-            warn!("{log_target}[Ipc]: failed to flush: {err:?}");
-            return;
-        }
+    if let Err(err) = write.flush().await {
+        warn!("{log_target}[Ipc]: failed to flush: {err:?}");
+        return false;
     }
+
+    true
 }
 
 #[derive(Debug)]
 pub struct IpcSender<Message> {
     sender: Sender<Message>,
     log_target: String,
+    backpressure: Arc<Backpressure>,
+    pipe: Arc<Pipe>,
 }
 
 impl<Message> Clone for IpcSender<Message> {
@@ -240,66 +499,502 @@ impl<Message> Clone for IpcSender<Message> {
         Self {
             sender: self.sender.clone(),
             log_target: self.log_target.clone(),
+            backpressure: self.backpressure.clone(),
+            pipe: self.pipe.clone(),
         }
     }
 }
 
+/// Shared by all clones of an [IpcSender]
+#[derive(Debug, Default)]
+struct Backpressure {
+    /// Set while the channel is almost full, so the warning is only logged once
+    near_full: AtomicBool,
+    dropped: AtomicU64,
+}
+
+/// Shared by all clones of an [IpcSender] and the task writing its messages
+#[derive(Debug, Default)]
+struct Pipe {
+    /// Set once writing failed, nothing can be sent afterwards
+    broken: AtomicBool,
+    broken_notify: Notify,
+}
+
+impl Pipe {
+    fn set_broken(&self) {
+        self.broken.store(true, Ordering::Release);
+        self.broken_notify.notify_waiters();
+    }
+    fn is_broken(&self) -> bool {
+        self.broken.load(Ordering::Acquire)
+    }
+}
+
 impl<Message> IpcSender<Message>
 where
     Message: Serialize + Send + 'static,
 {
-    pub async fn send(&mut self, message: Message) {
+    /// Returns false if the message can't be sent because the pipe broke or the other side closed
+    pub async fn send(&mut self, message: Message) -> bool {
+        if self.is_broken() {
+            return false;
+        }
+        self.check_backpressure();
+
         if self.sender.send(message).await.is_err() {
             warn!("{}[Ipc] failed to send message", self.log_target);
+            return false;
         }
+        true
     }
-    pub fn blocking_send(&mut self, message: Message) {
+    /// Returns false if the message can't be sent because the pipe broke or the other side closed
+    pub fn blocking_send(&mut self, message: Message) -> bool {
+        if self.is_broken() {
+            return false;
+        }
+        self.check_backpressure();
+
         if self.sender.blocking_send(message).is_err() {
             warn!("{}[Ipc] failed to send message", self.log_target);
+            return false;
+        }
+        true
+    }
+    /// Drops the message instead of waiting when the channel is full, e.g. for video frames which are stale by the time they could be sent.
+    /// The channel can only drop the newest message, so the queued messages are sent in order.
+    ///
+    /// Returns false if the message was dropped
+    pub fn send_lossy(&mut self, message: Message) -> bool {
+        if self.is_broken() {
+            return false;
+        }
+        self.check_backpressure();
+
+        match self.sender.try_send(message) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                self.backpressure.dropped.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+            Err(TrySendError::Closed(_)) => {
+                warn!("{}[Ipc] failed to send message", self.log_target);
+                false
+            }
+        }
+    }
+
+    /// How many messages are waiting to be written
+    pub fn queued_messages(&self) -> usize {
+        self.sender.max_capacity() - self.sender.capacity()
+    }
+    /// How many messages [Self::send_lossy] dropped because the channel was full
+    pub fn dropped_messages(&self) -> u64 {
+        self.backpressure.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Warns when the channel is almost full, the other side doesn't read the messages fast enough
+    fn check_backpressure(&self) {
+        let capacity = self.sender.max_capacity();
+        let queued = self.queued_messages();
+
+        if queued >= capacity - capacity / 4 {
+            if !self.backpressure.near_full.swap(true, Ordering::Relaxed) {
+                warn!(
+                    "{}[Ipc]: the channel is almost full ({queued} of {capacity} messages queued), consider increasing the ipc channel capacity",
+                    self.log_target
+                );
+            }
+        } else if queued <= capacity / 2
+            && self.backpressure.near_full.swap(false, Ordering::Relaxed)
+        {
+            info!(
+                "{}[Ipc]: the channel drained again, {} messages were dropped so far",
+                self.log_target,
+                self.dropped_messages()
+            );
+        }
+    }
+    /// True if the other side can't receive messages anymore, e.g. because the process exited
+    pub fn is_closed(&self) -> bool {
+        self.sender.is_closed() || self.is_broken()
+    }
+    /// True if writing a message failed, the queued and all later messages are dropped
+    pub fn is_broken(&self) -> bool {
+        self.pipe.is_broken()
+    }
+    /// Waits until writing a message failed, e.g. to notice a dead streamer without waiting for its output to end
+    pub async fn broken(&self) {
+        let mut notified = pin!(self.pipe.broken_notify.notified());
+        // Registers the waiter before checking, so a break in between isn't missed
+        notified.as_mut().enable();
+
+        if self.is_broken() {
+            return;
         }
+        notified.await;
     }
 }
 
 pub struct IpcReceiver<Message> {
     errored: bool,
-    read: Lines<Box<dyn AsyncBufRead + Send + Unpin>>,
+    read: Split<Box<dyn AsyncBufRead + Send + Unpin>>,
     phantom: PhantomData<Message>,
     log_target: String,
+    #[cfg(unix)]
+    reattach: Option<Reattach>,
+}
+
+#[derive(Debug, Error)]
+pub enum IpcRecvError {
+    /// The other side closed the ipc or reading from it failed, no more messages will be received
+    #[error("the ipc was closed")]
+    Closed,
+    /// A message couldn't be deserialized, the following messages can still be received
+    #[error("failed to deserialize ipc message: {0}")]
+    Invalid(serde_json::Error),
+}
+
+/// Accepts a new connection on the ipc socket when the old one closed
+#[cfg(unix)]
+struct Reattach {
+    listener: UnixListener,
+    timeout: Duration,
+    /// The write half of a new connection is passed to the sender task
+    writers: Sender<OwnedWriteHalf>,
 }
 
 impl<Message> IpcReceiver<Message>
 where
     Message: DeserializeOwned,
 {
+    /// Returns None when the other side closed the ipc, invalid messages are skipped
     pub async fn recv(&mut self) -> Option<Message> {
+        loop {
+            match self.try_recv().await {
+                Ok(message) => return Some(message),
+                Err(IpcRecvError::Invalid(err)) => {
+                    warn!(
+                        "{}[Ipc]: failed to deserialize message, skipping it: {err:?}",
+                        self.log_target
+                    );
+                }
+                Err(IpcRecvError::Closed) => return None,
+            }
+        }
+    }
+
+    /// Receives the next message, a message which can't be deserialized is returned as [IpcRecvError::Invalid]
+    /// and the receiver can still be used afterwards.
+    pub async fn try_recv(&mut self) -> Result<Message, IpcRecvError> {
         if self.errored {
-            return None;
+            return Err(IpcRecvError::Closed);
         }
 
-        let line = match self.read.next_line().await {
-            Ok(Some(value)) => value,
-            Ok(None) => return None,
-            Err(err) => {
-                self.errored = true;
+        loop {
+            let line = match self.read.next_segment().await {
+                Ok(Some(value)) => value,
+                Ok(None) => {
+                    if self.reattach().await {
+                        continue;
+                    }
+                    return Err(IpcRecvError::Closed);
+                }
+                Err(err) => {
+                    warn!("{}[Ipc]: failed to read next line {err:?}", self.log_target);
+
+                    if self.reattach().await {
+                        continue;
+                    }
+                    self.errored = true;
+
+                    return Err(IpcRecvError::Closed);
+                }
+            };
+
+            let Some(frame_start) = line.iter().rposition(|byte| *byte == IPC_FRAME_START) else {
+                warn!(
+                    "{}[Ipc]: skipping line without a message: {}",
+                    self.log_target,
+                    String::from_utf8_lossy(&line)
+                );
+                continue;
+            };
+            if frame_start > 0 {
+                warn!(
+                    "{}[Ipc]: skipping garbage in front of a message: {}",
+                    self.log_target,
+                    String::from_utf8_lossy(&line[..frame_start])
+                );
+            }
+
+            let frame = &line[(frame_start + 1)..];
 
-                warn!("{}[Ipc]: failed to read next line {err:?}", self.log_target);
+            trace!(
+                "{}[Ipc] received {}",
+                self.log_target,
+                String::from_utf8_lossy(frame)
+            );
 
-                return None;
-            }
+            return serde_json::from_slice::<Message>(frame).map_err(IpcRecvError::Invalid);
+        }
+    }
+
+    /// Waits for the other side to connect to the ipc socket again, false if it didn't within the timeout
+    #[cfg(unix)]
+    async fn reattach(&mut self) -> bool {
+        let Some(reattach) = &self.reattach else {
+            return false;
         };
 
-        trace!("{}[Ipc] received {line}", self.log_target);
+        info!(
+            "{}[Ipc]: connection closed, waiting {:?} for a reconnect",
+            self.log_target, reattach.timeout
+        );
 
-        match serde_json::from_str::<Message>(&line) {
-            Ok(value) => Some(value),
-            Err(err) => {
+        let stream = match timeout(reattach.timeout, reattach.listener.accept()).await {
+            Ok(Ok((stream, _))) => stream,
+            Ok(Err(err)) => {
                 warn!(
-                    "{}[Ipc]: failed to deserialize message: {err:?}",
+                    "{}[Ipc]: failed to accept connection: {err:?}",
                     self.log_target
                 );
-
-                None
+                return false;
+            }
+            Err(_) => {
+                info!("{}[Ipc]: nobody reconnected in time", self.log_target);
+                return false;
             }
+        };
+
+        let (read, write) = stream.into_split();
+        if reattach.writers.send(write).await.is_err() {
+            return false;
+        }
+        self.read = create_lines(read);
+
+        info!("{}[Ipc]: reconnected", self.log_target);
+
+        true
+    }
+    #[cfg(not(unix))]
+    async fn reattach(&mut self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::marker::PhantomData;
+
+    use std::time::Duration;
+
+    use serde::{Deserialize, Serialize};
+    use tokio::{
+        io::{duplex, empty},
+        sync::mpsc::channel,
+        time::timeout,
+    };
+
+    use crate::ipc::{
+        DEFAULT_IPC_CHANNEL_CAPACITY, IPC_FRAME_START, IpcReceiver, IpcRecvError, IpcSender, Pipe,
+        create_ipc, create_lines, ipc_sender,
+    };
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct TestMessage {
+        value: u32,
+    }
+
+    fn receiver(input: Vec<u8>) -> IpcReceiver<TestMessage> {
+        IpcReceiver {
+            errored: false,
+            read: create_lines(std::io::Cursor::new(input)),
+            phantom: PhantomData,
+            log_target: "".to_string(),
+            #[cfg(unix)]
+            reattach: None,
         }
     }
+
+    fn frame(json: &str) -> Vec<u8> {
+        let mut frame = vec![IPC_FRAME_START];
+        frame.extend_from_slice(json.as_bytes());
+        frame.push(b'\n');
+        frame
+    }
+
+    #[tokio::test]
+    async fn test_skip_garbage_between_messages() {
+        let mut input = Vec::new();
+        input.extend_from_slice(b"garbage from a native library\n");
+        input.extend(frame(r#"{"value":1}"#));
+        input.extend_from_slice(b"\xFF\xFE invalid utf8\n");
+        // A print without a newline in front of a message
+        input.extend_from_slice(b"no newline");
+        input.extend(frame(r#"{"value":2}"#));
+        input.extend(frame(r#"{"value":"#));
+        input.extend(frame(r#"{"value":3}"#));
+        input.extend_from_slice(b"trailing garbage without newline");
+
+        let mut receiver = receiver(input);
+
+        assert_eq!(receiver.recv().await, Some(TestMessage { value: 1 }));
+        assert_eq!(receiver.recv().await, Some(TestMessage { value: 2 }));
+        assert_eq!(receiver.recv().await, Some(TestMessage { value: 3 }));
+        assert_eq!(receiver.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_message_is_recoverable() {
+        let mut input = Vec::new();
+        input.extend(frame(r#"{"value":"not a number"}"#));
+        input.extend(frame(r#"{"value":1}"#));
+
+        let mut receiver = receiver(input);
+
+        assert!(matches!(
+            receiver.try_recv().await,
+            Err(IpcRecvError::Invalid(_))
+        ));
+        assert_eq!(
+            receiver.try_recv().await.ok(),
+            Some(TestMessage { value: 1 })
+        );
+        assert!(matches!(
+            receiver.try_recv().await,
+            Err(IpcRecvError::Closed)
+        ));
+    }
+
+    #[test]
+    fn test_send_lossy_drops_when_full() {
+        let (sender, mut receiver) = channel(2);
+        let mut sender = IpcSender {
+            sender,
+            log_target: "".to_string(),
+            backpressure: Default::default(),
+            pipe: Default::default(),
+        };
+
+        assert!(sender.send_lossy(TestMessage { value: 1 }));
+        assert!(sender.send_lossy(TestMessage { value: 2 }));
+        assert!(!sender.send_lossy(TestMessage { value: 3 }));
+        assert_eq!(sender.queued_messages(), 2);
+        assert_eq!(sender.dropped_messages(), 1);
+
+        assert_eq!(receiver.try_recv().ok(), Some(TestMessage { value: 1 }));
+        assert!(sender.send_lossy(TestMessage { value: 4 }));
+        assert_eq!(receiver.try_recv().ok(), Some(TestMessage { value: 2 }));
+        assert_eq!(receiver.try_recv().ok(), Some(TestMessage { value: 4 }));
+    }
+
+    #[tokio::test]
+    async fn test_sender_frames_messages() {
+        let (sender, receiver) = channel(10);
+        sender
+            .send(TestMessage { value: 4 })
+            .await
+            .expect("failed to send message");
+        drop(sender);
+
+        let mut output = Vec::new();
+        let pipe = Pipe::default();
+        ipc_sender(&mut output, receiver, &pipe, "").await;
+
+        assert_eq!(output, frame(r#"{"value":4}"#));
+        assert!(!pipe.is_broken());
+    }
+
+    #[tokio::test]
+    async fn test_broken_writer_is_reported() {
+        // Writing fails once the reading side is gone, like a pipe to an exited process
+        let (write, read) = duplex(64);
+        drop(read);
+
+        let (mut sender, _receiver) =
+            create_ipc::<TestMessage, TestMessage>(empty(), write, DEFAULT_IPC_CHANNEL_CAPACITY);
+        assert!(!sender.is_broken());
+
+        // Queued before the writer noticed the broken pipe
+        assert!(sender.send(TestMessage { value: 1 }).await);
+
+        timeout(Duration::from_secs(1), sender.broken())
+            .await
+            .expect("the broken pipe wasn't reported");
+        // Also returns for waiters which start after the pipe broke
+        timeout(Duration::from_secs(1), sender.clone().broken())
+            .await
+            .expect("the broken pipe wasn't reported");
+
+        assert!(sender.is_broken());
+        assert!(sender.is_closed());
+        assert!(!sender.send(TestMessage { value: 2 }).await);
+        assert!(!sender.send_lossy(TestMessage { value: 3 }));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_socket_reattach() {
+        use std::time::Duration;
+
+        use tokio::net::UnixListener;
+
+        use crate::ipc::{
+            DEFAULT_IPC_CHANNEL_CAPACITY, IpcReceiver, IpcSender, connect_child_ipc,
+            create_socket_ipc,
+        };
+
+        let dir = tempfile::tempdir().expect("failed to create test directory");
+        let socket_path = dir.path().join("ipc.sock");
+        let listener = UnixListener::bind(&socket_path).expect("failed to bind socket");
+
+        let streamer = tokio::spawn(create_socket_ipc::<TestMessage, TestMessage>(
+            listener,
+            Duration::from_millis(500),
+            DEFAULT_IPC_CHANNEL_CAPACITY,
+        ));
+
+        let connect = async || -> (IpcSender<TestMessage>, IpcReceiver<TestMessage>) {
+            connect_child_ipc(
+                "Test",
+                &socket_path,
+                Duration::from_secs(1),
+                DEFAULT_IPC_CHANNEL_CAPACITY,
+            )
+            .await
+            .expect("failed to connect")
+        };
+
+        let (mut parent_sender, parent_receiver) = connect().await;
+        let (mut streamer_sender, mut streamer_receiver) = streamer
+            .await
+            .expect("streamer task failed")
+            .expect("failed to accept connection");
+
+        parent_sender.send(TestMessage { value: 1 }).await;
+        assert_eq!(
+            streamer_receiver.recv().await,
+            Some(TestMessage { value: 1 })
+        );
+
+        // The web server restarts
+        drop(parent_sender);
+        drop(parent_receiver);
+
+        let (mut parent_sender, mut parent_receiver) = connect().await;
+        parent_sender.send(TestMessage { value: 2 }).await;
+        assert_eq!(
+            streamer_receiver.recv().await,
+            Some(TestMessage { value: 2 })
+        );
+
+        streamer_sender.send(TestMessage { value: 3 }).await;
+        assert_eq!(parent_receiver.recv().await, Some(TestMessage { value: 3 }));
+
+        // Nobody reconnects within the timeout
+        drop(parent_sender);
+        drop(parent_receiver);
+        assert_eq!(streamer_receiver.recv().await, None);
+    }
 }
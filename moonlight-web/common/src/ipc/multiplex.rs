@@ -0,0 +1,187 @@
+//! Routing for a streamer which hosts the streams of multiple rooms over a single ipc.
+//!
+//! Every message is wrapped into a [RoomIpcMessage] and both sides split the messages by their room,
+//! so each room can keep using the message set of a single streamer.
+//!
+//! This is only the routing, the streamer still hosts a single stream per process.
+//! It's built with the `ipc-multiplex` feature until the streamer uses it.
+
+use std::{collections::HashMap, sync::Arc};
+
+use log::warn;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use tokio::{
+    spawn,
+    sync::{
+        Mutex,
+        mpsc::{Receiver, Sender, channel},
+    },
+};
+
+use crate::ipc::{IpcReceiver, IpcSender};
+
+/// A message of one room on an ipc which is shared by multiple rooms
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoomIpcMessage<Message> {
+    pub room_id: String,
+    pub message: Message,
+}
+
+/// Sends the messages of a single room over a shared ipc
+#[derive(Debug)]
+pub struct RoomIpcSender<Message> {
+    room_id: String,
+    sender: IpcSender<RoomIpcMessage<Message>>,
+}
+
+impl<Message> Clone for RoomIpcSender<Message> {
+    fn clone(&self) -> Self {
+        Self {
+            room_id: self.room_id.clone(),
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+impl<Message> RoomIpcSender<Message>
+where
+    Message: Serialize + Send + 'static,
+{
+    pub fn new(room_id: String, sender: IpcSender<RoomIpcMessage<Message>>) -> Self {
+        Self { room_id, sender }
+    }
+
+    pub fn room_id(&self) -> &str {
+        &self.room_id
+    }
+
+    pub async fn send(&mut self, message: Message) {
+        let message = self.wrap(message);
+        self.sender.send(message).await;
+    }
+    /// See [IpcSender::send_lossy], the queue is shared with all other rooms
+    pub fn send_lossy(&mut self, message: Message) -> bool {
+        let message = self.wrap(message);
+        self.sender.send_lossy(message)
+    }
+
+    fn wrap(&self, message: Message) -> RoomIpcMessage<Message> {
+        RoomIpcMessage {
+            room_id: self.room_id.clone(),
+            message,
+        }
+    }
+}
+
+/// Splits the messages of a shared ipc into one channel per room.
+///
+/// A room which doesn't receive its messages blocks the messages of all other rooms once its channel is full,
+/// the same way a single streamer blocks its ipc.
+pub struct IpcDemultiplexer<Message> {
+    rooms: Arc<Mutex<HashMap<String, Sender<Message>>>>,
+    capacity: usize,
+}
+
+impl<Message> IpcDemultiplexer<Message>
+where
+    Message: DeserializeOwned + Send + 'static,
+{
+    /// Routes the messages of the receiver until the other side closed the ipc.
+    /// The channels of all rooms are closed afterwards.
+    pub fn new(mut receiver: IpcReceiver<RoomIpcMessage<Message>>, capacity: usize) -> Self {
+        let rooms = Arc::new(Mutex::new(HashMap::<String, Sender<Message>>::new()));
+
+        spawn({
+            let rooms = rooms.clone();
+
+            async move {
+                while let Some(RoomIpcMessage { room_id, message }) = receiver.recv().await {
+                    let sender = rooms.lock().await.get(&room_id).cloned();
+                    let Some(sender) = sender else {
+                        warn!("[Ipc]: dropping message of unknown room {room_id}");
+                        continue;
+                    };
+
+                    if sender.send(message).await.is_err() {
+                        // The room stopped receiving without removing itself
+                        rooms.lock().await.remove(&room_id);
+                    }
+                }
+
+                rooms.lock().await.clear();
+            }
+        });
+
+        Self { rooms, capacity }
+    }
+
+    /// Receives the messages of the room from now on, a room which was already added only receives them in the new channel
+    pub async fn add_room(&self, room_id: String) -> Receiver<Message> {
+        let (sender, receiver) = channel(self.capacity);
+        self.rooms.lock().await.insert(room_id, sender);
+
+        receiver
+    }
+    /// The messages of the room are dropped from now on
+    pub async fn remove_room(&self, room_id: &str) {
+        self.rooms.lock().await.remove(room_id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{duplex, empty, sink};
+
+    use crate::ipc::{
+        DEFAULT_IPC_CHANNEL_CAPACITY, IpcReceiver, IpcSender, create_ipc,
+        multiplex::{IpcDemultiplexer, RoomIpcMessage, RoomIpcSender},
+    };
+
+    fn shared_ipc() -> (
+        IpcSender<RoomIpcMessage<u32>>,
+        IpcReceiver<RoomIpcMessage<u32>>,
+    ) {
+        let (server, streamer) = duplex(4096);
+        let capacity = DEFAULT_IPC_CHANNEL_CAPACITY;
+
+        let (sender, _) = create_ipc::<RoomIpcMessage<u32>, _>(empty(), server, capacity);
+        let (_, receiver) = create_ipc::<_, RoomIpcMessage<u32>>(streamer, sink(), capacity);
+
+        (sender, receiver)
+    }
+
+    #[tokio::test]
+    async fn test_route_messages_by_room() {
+        let (sender, receiver) = shared_ipc();
+        let demultiplexer = IpcDemultiplexer::new(receiver, 10);
+
+        let mut room_a = demultiplexer.add_room("a".to_string()).await;
+        let mut room_b = demultiplexer.add_room("b".to_string()).await;
+
+        let mut sender_a = RoomIpcSender::new("a".to_string(), sender.clone());
+        let mut sender_b = RoomIpcSender::new("b".to_string(), sender.clone());
+        let mut sender_unknown = RoomIpcSender::new("unknown".to_string(), sender);
+
+        sender_a.send(1).await;
+        sender_unknown.send(2).await;
+        sender_b.send(3).await;
+        sender_a.send(4).await;
+
+        assert_eq!(room_a.recv().await, Some(1));
+        assert_eq!(room_a.recv().await, Some(4));
+        assert_eq!(room_b.recv().await, Some(3));
+
+        demultiplexer.remove_room("a").await;
+        sender_a.send(5).await;
+        sender_b.send(6).await;
+
+        assert_eq!(room_b.recv().await, Some(6));
+        assert_eq!(room_a.recv().await, None);
+
+        // Closing the ipc closes all rooms
+        drop(sender_a);
+        drop(sender_b);
+        drop(sender_unknown);
+        assert_eq!(room_b.recv().await, None);
+    }
+}
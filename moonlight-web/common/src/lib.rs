@@ -1,14 +1,26 @@
-use std::fmt::{self, Display, Formatter};
+#[cfg(feature = "server")]
+use std::{
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+};
 
+#[cfg(feature = "server")]
 use log::warn;
+#[cfg(feature = "server")]
 use moonlight_common::stream::bindings::{Colorspace, SupportedVideoFormats};
 use serde::{Deserialize, Serialize};
 
+#[cfg(feature = "server")]
+use crate::api_bindings::{KeyOverride, KeyboardLayout, KeyboardMode};
+
 pub mod api_bindings;
 pub mod api_bindings_ext;
+#[cfg(feature = "server")]
 pub mod config;
+#[cfg(feature = "server")]
 pub mod ipc;
 
+#[cfg(feature = "server")]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StreamSettings {
     pub bitrate: u32,
@@ -20,8 +32,34 @@ pub struct StreamSettings {
     pub video_supported_formats: SupportedVideoFormats,
     pub video_colorspace: Colorspace,
     pub video_color_range_full: bool,
+    pub keyboard_mode: KeyboardMode,
+    pub keyboard_layout: KeyboardLayout,
+    pub custom_keyboard_layout: HashMap<u16, u16>,
+    pub key_overrides: HashMap<u16, KeyOverride>,
+    pub allowed_inputs: AllowedInputs,
+}
+
+/// The input types the streamed app receives, independent of the permissions of the peers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AllowedInputs {
+    pub keyboard: bool,
+    pub mouse: bool,
+    pub gamepad: bool,
+    pub touch: bool,
+}
+
+impl Default for AllowedInputs {
+    fn default() -> Self {
+        Self {
+            keyboard: true,
+            mouse: true,
+            gamepad: true,
+            touch: true,
+        }
+    }
 }
 
+#[cfg(feature = "server")]
 impl Display for StreamSettings {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(
@@ -32,6 +70,7 @@ impl Display for StreamSettings {
     }
 }
 
+#[cfg(feature = "server")]
 pub fn serialize_json<T>(message: &T) -> Option<String>
 where
     T: Serialize,
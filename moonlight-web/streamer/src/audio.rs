@@ -1,6 +1,6 @@
-use std::sync::Weak;
+use std::sync::{Weak, atomic::Ordering};
 
-use log::{debug, error, warn};
+use log::{debug, warn};
 use moonlight_common::stream::{
     audio::AudioDecoder,
     bindings::{AudioConfig, Capabilities, OpusMultistreamConfig},
@@ -27,24 +27,14 @@ impl AudioDecoder for StreamAudioDecoder {
         {
             let mut stream_info = stream.stream_setup.blocking_lock();
             stream_info.audio = Some(stream_config.clone());
+            stream_info.audio_config = Some(audio_config);
         }
 
         // Setup audio on all peer transports
         stream.runtime.clone().block_on(async move {
-            let transports = stream.peer_transports.read().await;
-            if transports.is_empty() {
-                error!("Failed to setup audio because no transports are connected!");
-                return -1;
-            }
-
-            let mut result = 0i32;
-            for (_peer_id, transport) in transports.iter() {
-                let r = transport.sender.setup_audio(audio_config, stream_config.clone()).await;
-                if r != 0 {
-                    result = r;
-                }
-            }
-            result
+            stream
+                .setup_audio_transports(audio_config, stream_config)
+                .await
         })
     }
 
@@ -57,6 +47,10 @@ impl AudioDecoder for StreamAudioDecoder {
             return;
         };
 
+        if stream.paused.load(Ordering::Relaxed) {
+            return;
+        }
+
         stream.runtime.clone().block_on(async move {
             let transports = stream.peer_transports.read().await;
 
@@ -65,8 +59,20 @@ impl AudioDecoder for StreamAudioDecoder {
                 return;
             }
 
+            let peer_delays = stream.peer_delays.read().await;
+
             // Send to all peer transports
             for (peer_id, transport) in transports.iter() {
+                if !transport.readiness.is_ready() {
+                    continue;
+                }
+
+                // Delayed by the same amount as the video to stay in sync
+                if let Some(peer_delay) = peer_delays.get(peer_id) {
+                    peer_delay.delayed.push_audio(data);
+                    continue;
+                }
+
                 if let Err(err) = transport.sender.send_audio_sample(data).await {
                     warn!("Failed to send audio sample to peer {:?}: {err}", peer_id);
                 }
@@ -1,8 +1,13 @@
 use common::{
-    api_bindings::{RtcIceServer, RtcSdpType},
+    api_bindings::{
+        DataChannelOptions, DataChannelRetransmits, HostCapabilities, HostVideoCodec, RtcIceServer,
+        RtcSdpType, StreamServerMessage, TerminationReason,
+    },
     config::{WebRtcNat1To1IceCandidateType, WebRtcNetworkType},
 };
+use moonlight_common::stream::bindings::{SupportedVideoFormats, TerminationError};
 use webrtc::{
+    data_channel::data_channel_init::RTCDataChannelInit,
     ice::network_type::NetworkType,
     ice_transport::{ice_candidate_type::RTCIceCandidateType, ice_server::RTCIceServer},
     peer_connection::sdp::sdp_type::RTCSdpType,
@@ -26,6 +31,21 @@ pub fn into_webrtc_ice(value: RtcIceServer) -> RTCIceServer {
     }
 }
 
+pub fn into_webrtc_data_channel_init(value: DataChannelOptions) -> RTCDataChannelInit {
+    let (max_retransmits, max_packet_life_time) = match value.retransmits {
+        None => (None, None),
+        Some(DataChannelRetransmits::MaxRetransmits(count)) => (Some(count), None),
+        Some(DataChannelRetransmits::MaxPacketLifeTime(millis)) => (None, Some(millis)),
+    };
+
+    RTCDataChannelInit {
+        ordered: Some(value.ordered),
+        max_retransmits,
+        max_packet_life_time,
+        ..Default::default()
+    }
+}
+
 pub fn into_webrtc_ice_candidate(value: WebRtcNat1To1IceCandidateType) -> RTCIceCandidateType {
     match value {
         WebRtcNat1To1IceCandidateType::Host => RTCIceCandidateType::Host,
@@ -41,3 +61,213 @@ pub fn into_webrtc_network_type(value: WebRtcNetworkType) -> NetworkType {
         WebRtcNetworkType::Tcp6 => NetworkType::Tcp6,
     }
 }
+
+const GRACEFUL: i32 = TerminationError::Graceful as i32;
+const NO_VIDEO_TRAFFIC: i32 = TerminationError::NoVideoTraffic as i32;
+const NO_VIDEO_FRAME: i32 = TerminationError::NoVideoFrame as i32;
+const UNEXPECTED_EARLY_TERMINATION: i32 = TerminationError::UnexpectedEarlyTermination as i32;
+const PROTECTED_CONTENT: i32 = TerminationError::ProtectedContent as i32;
+const FRAME_CONVERSION: i32 = TerminationError::FrameConversion as i32;
+/// Sent by the streamer itself when a stage of the stream start didn't complete in time.
+/// Moonlight only uses -100 to -104, so this won't collide with its codes
+pub const STAGE_TIMEOUT: i32 = -200;
+/// Sent by the streamer itself when its memory stayed above the configured limit
+pub const MEMORY_LIMIT: i32 = -201;
+
+/// Decodes the error code moonlight reports when the connection is terminated
+pub fn from_termination_error_code(error_code: i32) -> TerminationReason {
+    match error_code {
+        GRACEFUL => TerminationReason::Graceful,
+        UNEXPECTED_EARLY_TERMINATION => TerminationReason::HostShutdown,
+        NO_VIDEO_FRAME => TerminationReason::NetworkLost,
+        NO_VIDEO_TRAFFIC => TerminationReason::NoVideoReceived,
+        PROTECTED_CONTENT => TerminationReason::Unauthorized,
+        STAGE_TIMEOUT => TerminationReason::StageTimeout,
+        MEMORY_LIMIT => TerminationReason::MemoryLimit,
+        error_code => TerminationReason::Other(error_code),
+    }
+}
+
+pub fn termination_hint(reason: TerminationReason) -> &'static str {
+    match reason {
+        TerminationReason::Graceful => "The stream was stopped",
+        TerminationReason::HostShutdown => {
+            "The host ended the stream unexpectedly, check if the app is still running on the host"
+        }
+        TerminationReason::NetworkLost => {
+            "The network connection is too slow, try a lower bitrate or a faster connection"
+        }
+        TerminationReason::NoVideoReceived => {
+            "No video was received from the host, check the firewall and port forwarding of the host"
+        }
+        TerminationReason::Unauthorized => "The host refused to stream protected content",
+        TerminationReason::StageTimeout => {
+            "The host stopped responding while starting the stream, try again"
+        }
+        TerminationReason::MemoryLimit => {
+            "The stream used more memory than the server allows, try a lower resolution or bitrate"
+        }
+        TerminationReason::Other(FRAME_CONVERSION) => {
+            "The host failed to encode the video, try another video codec"
+        }
+        TerminationReason::Other(_) => "The connection to the host was terminated",
+    }
+}
+
+pub fn connection_terminated_message(error_code: i32) -> StreamServerMessage {
+    let reason = from_termination_error_code(error_code);
+
+    StreamServerMessage::ConnectionTerminated {
+        error_code,
+        reason,
+        hint: termination_hint(reason).to_string(),
+    }
+}
+
+/// The video formats the host can encode, decoded from the `ServerCodecModeSupport` of the host info
+pub fn host_video_formats(server_codec_mode_support: u32) -> SupportedVideoFormats {
+    // Nvidia only limits the resolution, not the codecs
+    let capabilities = HostCapabilities::new(server_codec_mode_support, false);
+
+    SupportedVideoFormats::from_bits_truncate(capabilities.supported_video_formats)
+}
+
+/// The video formats both the client and the host support, empty if the stream can't start
+pub fn negotiate_video_formats(
+    client_formats: SupportedVideoFormats,
+    server_codec_mode_support: u32,
+) -> SupportedVideoFormats {
+    client_formats & host_video_formats(server_codec_mode_support)
+}
+
+/// The video codecs in the formats, e.g. for logs
+pub fn video_codecs(formats: SupportedVideoFormats) -> Vec<HostVideoCodec> {
+    HostVideoCodec::ALL
+        .into_iter()
+        .filter(|(_, _, format)| formats.contains(*format))
+        .map(|(codec, _, _)| codec)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use common::api_bindings::{HostVideoCodec, TerminationReason};
+    use moonlight_common::stream::bindings::{
+        ServerCodeModeSupport, SupportedVideoFormats, TerminationError,
+    };
+
+    use crate::convert::{
+        MEMORY_LIMIT, STAGE_TIMEOUT, from_termination_error_code, host_video_formats,
+        negotiate_video_formats, termination_hint, video_codecs,
+    };
+
+    #[test]
+    fn test_host_video_formats() {
+        let support = ServerCodeModeSupport::H264
+            | ServerCodeModeSupport::HEVC
+            | ServerCodeModeSupport::HEVC_MAIN10;
+
+        assert_eq!(
+            host_video_formats(support.bits()).bits(),
+            (SupportedVideoFormats::H264
+                | SupportedVideoFormats::H265
+                | SupportedVideoFormats::H265_MAIN10)
+                .bits()
+        );
+        assert!(host_video_formats(0).is_empty());
+    }
+
+    #[test]
+    fn test_negotiate_video_formats() {
+        // The host GPU doesn't support AV1
+        let host = (ServerCodeModeSupport::H264 | ServerCodeModeSupport::HEVC).bits();
+
+        let client = SupportedVideoFormats::H264
+            | SupportedVideoFormats::H265
+            | SupportedVideoFormats::AV1_MAIN8;
+        let formats = negotiate_video_formats(client, host);
+        assert_eq!(
+            formats.bits(),
+            (SupportedVideoFormats::H264 | SupportedVideoFormats::H265).bits()
+        );
+        assert_eq!(
+            video_codecs(formats),
+            vec![HostVideoCodec::H264, HostVideoCodec::H265]
+        );
+
+        // A client which can only decode AV1
+        assert!(negotiate_video_formats(SupportedVideoFormats::AV1_MAIN8, host).is_empty());
+    }
+
+    #[test]
+    fn test_termination_reason() {
+        let known = [
+            (TerminationError::Graceful, TerminationReason::Graceful),
+            (
+                TerminationError::NoVideoTraffic,
+                TerminationReason::NoVideoReceived,
+            ),
+            (
+                TerminationError::NoVideoFrame,
+                TerminationReason::NetworkLost,
+            ),
+            (
+                TerminationError::UnexpectedEarlyTermination,
+                TerminationReason::HostShutdown,
+            ),
+            (
+                TerminationError::ProtectedContent,
+                TerminationReason::Unauthorized,
+            ),
+            (
+                TerminationError::FrameConversion,
+                TerminationReason::Other(TerminationError::FrameConversion as i32),
+            ),
+        ];
+
+        for (error, reason) in known {
+            assert_eq!(
+                from_termination_error_code(error as i32),
+                reason,
+                "{error:?}"
+            );
+        }
+
+        assert_eq!(
+            from_termination_error_code(STAGE_TIMEOUT),
+            TerminationReason::StageTimeout
+        );
+        assert_eq!(
+            from_termination_error_code(MEMORY_LIMIT),
+            TerminationReason::MemoryLimit
+        );
+
+        assert_eq!(
+            from_termination_error_code(-1),
+            TerminationReason::Other(-1)
+        );
+    }
+
+    #[test]
+    fn test_termination_hint() {
+        let generic = termination_hint(TerminationReason::Other(-1));
+
+        assert_ne!(
+            termination_hint(TerminationReason::Other(
+                TerminationError::FrameConversion as i32
+            )),
+            generic
+        );
+        for reason in [
+            TerminationReason::Graceful,
+            TerminationReason::HostShutdown,
+            TerminationReason::NetworkLost,
+            TerminationReason::NoVideoReceived,
+            TerminationReason::Unauthorized,
+            TerminationReason::StageTimeout,
+            TerminationReason::MemoryLimit,
+        ] {
+            assert_ne!(termination_hint(reason), generic, "{reason:?}");
+        }
+    }
+}
@@ -0,0 +1,423 @@
+//! Holds back video and audio for peers which should see the stream later than the players,
+//! e.g. spectators of a tournament which shouldn't be able to help the players.
+
+use std::{
+    collections::VecDeque,
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use log::warn;
+use moonlight_common::stream::bindings::{
+    BufferType, Colorspace, FrameType, VideoDataBuffer, VideoDecodeUnit,
+};
+use tokio::{select, sync::Notify, time::sleep_until};
+
+/// The maximum amount of media bytes buffered per delayed peer
+pub const MAX_DELAY_BUFFER_SIZE: usize = 64 * 1024 * 1024;
+
+/// A copy of a [VideoDecodeUnit] which can outlive the decoder callback
+pub struct OwnedVideoUnit {
+    /// The id the peer reports the frame with once it's rendered
+    frame_id: u32,
+    frame_number: i32,
+    frame_type: FrameType,
+    frame_processing_latency: Option<Duration>,
+    receive_time: Duration,
+    enqueue_time: Duration,
+    presentation_time: Duration,
+    hdr_active: bool,
+    color_space: Colorspace,
+    buffers: Vec<(BufferType, Vec<u8>)>,
+}
+
+impl OwnedVideoUnit {
+    pub fn new(unit: &VideoDecodeUnit, frame_id: u32) -> Self {
+        Self {
+            frame_id,
+            frame_number: unit.frame_number,
+            frame_type: unit.frame_type,
+            frame_processing_latency: unit.frame_processing_latency,
+            receive_time: unit.receive_time,
+            enqueue_time: unit.enqueue_time,
+            presentation_time: unit.presentation_time,
+            hdr_active: unit.hdr_active,
+            color_space: unit.color_space,
+            buffers: unit
+                .buffers
+                .iter()
+                .map(|buffer| (buffer.ty, buffer.data.to_vec()))
+                .collect(),
+        }
+    }
+
+    pub fn frame_id(&self) -> u32 {
+        self.frame_id
+    }
+
+    pub fn buffers(&self) -> Vec<VideoDataBuffer<'_>> {
+        self.buffers
+            .iter()
+            .map(|(ty, data)| VideoDataBuffer { ty: *ty, data })
+            .collect()
+    }
+
+    /// Creates the unit again, the buffers must come from [OwnedVideoUnit::buffers]
+    pub fn as_unit<'a>(&self, buffers: &'a [VideoDataBuffer<'a>]) -> VideoDecodeUnit<'a> {
+        VideoDecodeUnit {
+            frame_number: self.frame_number,
+            frame_type: self.frame_type,
+            frame_processing_latency: self.frame_processing_latency,
+            receive_time: self.receive_time,
+            enqueue_time: self.enqueue_time,
+            presentation_time: self.presentation_time,
+            hdr_active: self.hdr_active,
+            color_space: self.color_space,
+            buffers,
+        }
+    }
+}
+
+pub enum DelayedMedia {
+    Video(OwnedVideoUnit),
+    Audio(Vec<u8>),
+}
+
+/// A time indexed queue of media which is released after the delay.
+///
+/// When the buffer is full all frames except keyframes are dropped,
+/// because frames after a dropped frame can't be decoded until the next keyframe.
+pub struct DelayBuffer {
+    delay: Duration,
+    max_size: usize,
+    queue: VecDeque<(Instant, usize, DelayedMedia)>,
+    size: usize,
+    keyframes_only: bool,
+    idr_requested: bool,
+}
+
+impl DelayBuffer {
+    pub fn new(delay: Duration, max_size: usize) -> Self {
+        Self {
+            delay,
+            max_size,
+            queue: VecDeque::new(),
+            size: 0,
+            keyframes_only: false,
+            idr_requested: false,
+        }
+    }
+
+    pub fn delay(&self) -> Duration {
+        self.delay
+    }
+
+    /// A shorter delay skips the buffered media instead of releasing it all at once.
+    pub fn set_delay(&mut self, delay: Duration) {
+        if delay < self.delay && !self.queue.is_empty() {
+            self.queue.clear();
+            self.size = 0;
+            self.keyframes_only = true;
+            self.idr_requested = false;
+        }
+
+        self.delay = delay;
+    }
+
+    /// Returns true if a keyframe should be requested from the host.
+    pub fn push_video(&mut self, now: Instant, unit: &VideoDecodeUnit, frame_id: u32) -> bool {
+        let size = unit
+            .buffers
+            .iter()
+            .map(|buffer| buffer.data.len())
+            .sum::<usize>();
+        let is_keyframe = matches!(unit.frame_type, FrameType::Idr);
+
+        if self.keyframes_only && !is_keyframe {
+            // Only request a keyframe once there's enough space for it
+            if !self.idr_requested && self.size <= self.max_size / 2 {
+                self.idr_requested = true;
+                return true;
+            }
+            return false;
+        }
+
+        if self.size + size > self.max_size {
+            if !self.keyframes_only {
+                warn!("Stream delay buffer is full, dropping frames until the next keyframe");
+            }
+            self.keyframes_only = true;
+            self.idr_requested = false;
+            return false;
+        }
+
+        self.keyframes_only = false;
+        self.push(
+            now,
+            size,
+            DelayedMedia::Video(OwnedVideoUnit::new(unit, frame_id)),
+        );
+
+        false
+    }
+
+    pub fn push_audio(&mut self, now: Instant, data: &[u8]) {
+        if self.size + data.len() > self.max_size {
+            return;
+        }
+
+        self.push(now, data.len(), DelayedMedia::Audio(data.to_vec()));
+    }
+
+    fn push(&mut self, now: Instant, size: usize, media: DelayedMedia) {
+        self.size += size;
+        self.queue.push_back((now, size, media));
+    }
+
+    /// Pops the oldest media if its delay is over
+    pub fn pop_ready(&mut self, now: Instant) -> Option<DelayedMedia> {
+        let (pushed_at, _, _) = self.queue.front()?;
+        if *pushed_at + self.delay > now {
+            return None;
+        }
+
+        let (_, size, media) = self.queue.pop_front()?;
+        self.size -= size;
+
+        Some(media)
+    }
+
+    /// The time at which the oldest media should be released
+    pub fn next_release(&self) -> Option<Instant> {
+        self.queue
+            .front()
+            .map(|(pushed_at, _, _)| *pushed_at + self.delay)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// The bytes of the buffered media
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+/// The [DelayBuffer] of a peer and a way to wait for the next media to be released
+pub struct DelayedPeer {
+    buffer: Mutex<DelayBuffer>,
+    changed: Notify,
+}
+
+impl DelayedPeer {
+    pub fn new(delay: Duration) -> Self {
+        Self {
+            buffer: Mutex::new(DelayBuffer::new(delay, MAX_DELAY_BUFFER_SIZE)),
+            changed: Notify::new(),
+        }
+    }
+
+    fn buffer(&self) -> MutexGuard<'_, DelayBuffer> {
+        self.buffer.lock().expect("delay buffer mutex poisoned")
+    }
+
+    pub fn delay(&self) -> Duration {
+        self.buffer().delay()
+    }
+
+    /// See [DelayBuffer::size]
+    pub fn size(&self) -> usize {
+        self.buffer().size()
+    }
+
+    pub fn set_delay(&self, delay: Duration) {
+        self.buffer().set_delay(delay);
+        self.changed.notify_one();
+    }
+
+    /// Returns true if a keyframe should be requested from the host.
+    pub fn push_video(&self, unit: &VideoDecodeUnit, frame_id: u32) -> bool {
+        let mut buffer = self.buffer();
+
+        let was_empty = buffer.is_empty();
+        let request_idr = buffer.push_video(Instant::now(), unit, frame_id);
+
+        if was_empty {
+            self.changed.notify_one();
+        }
+
+        request_idr
+    }
+
+    pub fn push_audio(&self, data: &[u8]) {
+        let mut buffer = self.buffer();
+
+        let was_empty = buffer.is_empty();
+        buffer.push_audio(Instant::now(), data);
+
+        if was_empty {
+            self.changed.notify_one();
+        }
+    }
+
+    /// Waits until the oldest media should be sent to the peer
+    pub async fn next_ready(&self) -> DelayedMedia {
+        loop {
+            let next_release = {
+                let mut buffer = self.buffer();
+                if let Some(media) = buffer.pop_ready(Instant::now()) {
+                    return media;
+                }
+                buffer.next_release()
+            };
+
+            match next_release {
+                Some(next_release) => {
+                    select! {
+                        _ = sleep_until(next_release.into()) => {}
+                        _ = self.changed.notified() => {}
+                    }
+                }
+                None => self.changed.notified().await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use moonlight_common::stream::bindings::{
+        BufferType, Colorspace, FrameType, VideoDataBuffer, VideoDecodeUnit,
+    };
+
+    use crate::delay::{DelayBuffer, DelayedMedia};
+
+    fn with_unit<R>(
+        frame_number: i32,
+        frame_type: FrameType,
+        size: usize,
+        f: impl FnOnce(&VideoDecodeUnit) -> R,
+    ) -> R {
+        let data = vec![0u8; size];
+        let buffers = [VideoDataBuffer {
+            ty: BufferType::PicData,
+            data: &data,
+        }];
+
+        f(&VideoDecodeUnit {
+            frame_number,
+            frame_type,
+            frame_processing_latency: None,
+            receive_time: Duration::ZERO,
+            enqueue_time: Duration::ZERO,
+            presentation_time: Duration::ZERO,
+            hdr_active: false,
+            color_space: Colorspace::Rec709,
+            buffers: &buffers,
+        })
+    }
+
+    fn push_frame(
+        buffer: &mut DelayBuffer,
+        now: Instant,
+        frame_number: i32,
+        frame_type: FrameType,
+        size: usize,
+    ) -> bool {
+        with_unit(frame_number, frame_type, size, |unit| {
+            buffer.push_video(now, unit, frame_number as u32)
+        })
+    }
+
+    fn frame_number(media: Option<DelayedMedia>) -> Option<i32> {
+        match media? {
+            DelayedMedia::Video(unit) => Some(unit.frame_number),
+            DelayedMedia::Audio(_) => None,
+        }
+    }
+
+    #[test]
+    fn test_release_after_delay() {
+        let start = Instant::now();
+        let delay = Duration::from_secs(30);
+        let mut buffer = DelayBuffer::new(delay, 1000);
+
+        push_frame(&mut buffer, start, 1, FrameType::Idr, 10);
+        buffer.push_audio(start + Duration::from_millis(5), &[1, 2, 3]);
+        push_frame(
+            &mut buffer,
+            start + Duration::from_millis(16),
+            2,
+            FrameType::PFrame,
+            10,
+        );
+
+        assert!(buffer.pop_ready(start + Duration::from_secs(29)).is_none());
+        assert_eq!(buffer.next_release(), Some(start + delay));
+
+        let now = start + delay + Duration::from_millis(5);
+        assert_eq!(frame_number(buffer.pop_ready(now)), Some(1));
+        assert!(matches!(
+            buffer.pop_ready(now),
+            Some(DelayedMedia::Audio(data)) if data == [1, 2, 3]
+        ));
+        assert!(buffer.pop_ready(now).is_none());
+
+        assert_eq!(
+            frame_number(buffer.pop_ready(now + Duration::from_millis(11))),
+            Some(2)
+        );
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.size(), 0);
+    }
+
+    #[test]
+    fn test_full_buffer_keeps_keyframes_only() {
+        let now = Instant::now();
+        let mut buffer = DelayBuffer::new(Duration::from_secs(1), 100);
+
+        assert!(!push_frame(&mut buffer, now, 1, FrameType::Idr, 60));
+        // Doesn't fit anymore
+        assert!(!push_frame(&mut buffer, now, 2, FrameType::PFrame, 60));
+        // Frames after a dropped frame are useless, even if they fit
+        assert!(!push_frame(&mut buffer, now, 3, FrameType::PFrame, 10));
+        assert_eq!(buffer.size(), 60);
+
+        // Once there's space again a keyframe is requested, but only once
+        let later = now + Duration::from_secs(1);
+        assert_eq!(frame_number(buffer.pop_ready(later)), Some(1));
+        assert!(push_frame(&mut buffer, later, 4, FrameType::PFrame, 10));
+        assert!(!push_frame(&mut buffer, later, 5, FrameType::PFrame, 10));
+
+        // The keyframe resumes the stream
+        assert!(!push_frame(&mut buffer, later, 6, FrameType::Idr, 60));
+        assert!(!push_frame(&mut buffer, later, 7, FrameType::PFrame, 10));
+
+        let released = later + Duration::from_secs(1);
+        assert_eq!(frame_number(buffer.pop_ready(released)), Some(6));
+        assert_eq!(frame_number(buffer.pop_ready(released)), Some(7));
+    }
+
+    #[test]
+    fn test_shorter_delay_skips_buffered_media() {
+        let now = Instant::now();
+        let mut buffer = DelayBuffer::new(Duration::from_secs(30), 1000);
+
+        push_frame(&mut buffer, now, 1, FrameType::Idr, 10);
+        push_frame(&mut buffer, now, 2, FrameType::PFrame, 10);
+
+        buffer.set_delay(Duration::from_secs(5));
+        assert!(buffer.is_empty());
+
+        // The stream can only continue at a keyframe
+        assert!(push_frame(&mut buffer, now, 3, FrameType::PFrame, 10));
+        assert!(!push_frame(&mut buffer, now, 4, FrameType::Idr, 10));
+        assert_eq!(
+            frame_number(buffer.pop_ready(now + Duration::from_secs(5))),
+            Some(4)
+        );
+    }
+}
@@ -0,0 +1,193 @@
+//! Matches the frames a client reports as rendered with the time they were sent, to estimate the latency from the streamer to the screen.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use common::api_bindings::StreamerStatsUpdate;
+
+/// How many sent frames are remembered, reports of older frames are ignored
+const SENT_FRAMES_CAPACITY: usize = 256;
+/// How many reports are kept between two stats updates
+const SAMPLES_CAPACITY: usize = 512;
+
+#[derive(Debug)]
+struct FrameSample {
+    /// From sending the frame to the report arriving
+    round_trip: Duration,
+    arrival: Instant,
+    /// When the client rendered the frame in ms of its own clock
+    client_render_ms: u32,
+}
+
+/// The send times of the frames of a peer and the latencies of the frames it rendered
+#[derive(Debug, Default)]
+pub struct FrameTimings {
+    /// The ids of the sent frames with their send time, oldest first.
+    /// The ids wrap around, so they're only compared for equality.
+    sent: VecDeque<(u32, Instant)>,
+    samples: Vec<FrameSample>,
+}
+
+impl FrameTimings {
+    pub fn sent(&mut self, frame_id: u32, now: Instant) {
+        if self.sent.len() >= SENT_FRAMES_CAPACITY {
+            self.sent.pop_front();
+        }
+        self.sent.push_back((frame_id, now));
+    }
+
+    /// Records the latency of a rendered frame, false if the frame is unknown or too old
+    pub fn rendered(&mut self, frame_id: u32, client_render_ms: u32, now: Instant) -> bool {
+        let Some(index) = self.sent.iter().position(|(id, _)| *id == frame_id) else {
+            return false;
+        };
+        let (_, sent) = self.sent[index];
+
+        // The frames are rendered in order, so the earlier ones won't be reported anymore
+        self.sent.drain(..=index);
+
+        if self.samples.len() < SAMPLES_CAPACITY {
+            self.samples.push(FrameSample {
+                round_trip: now.saturating_duration_since(sent),
+                arrival: now,
+                client_render_ms,
+            });
+        }
+
+        true
+    }
+
+    /// The latency percentiles of the frames rendered since the last call, None if no frame was reported
+    pub fn take_stats(&mut self) -> Option<StreamerStatsUpdate> {
+        let first = self.samples.first()?;
+
+        // How much longer a report took back to the streamer than the fastest one,
+        // the time between rendering and sending a report on the client isn't part of the latency
+        let report_delays = self
+            .samples
+            .iter()
+            .map(|sample| {
+                // The samples are recorded in the order they arrived
+                let arrival_ms = (sample.arrival - first.arrival).as_secs_f64() * 1000.0;
+                let render_ms = sample.client_render_ms.wrapping_sub(first.client_render_ms) as i32;
+
+                arrival_ms - render_ms as f64
+            })
+            .collect::<Vec<_>>();
+        let fastest_report = report_delays.iter().copied().fold(f64::INFINITY, f64::min);
+
+        let mut latencies_ms = self
+            .samples
+            .iter()
+            .zip(report_delays)
+            .map(|(sample, report_delay)| {
+                (sample.round_trip.as_secs_f64() * 1000.0 - (report_delay - fastest_report))
+                    .max(0.0)
+            })
+            .collect::<Vec<_>>();
+        latencies_ms.sort_by(f64::total_cmp);
+
+        self.samples.clear();
+
+        Some(StreamerStatsUpdate::FrameLatency {
+            p50_ms: percentile(&latencies_ms, 50.0),
+            p95_ms: percentile(&latencies_ms, 95.0),
+            p99_ms: percentile(&latencies_ms, 99.0),
+            samples: latencies_ms.len() as u32,
+        })
+    }
+}
+
+/// The nearest rank percentile of the sorted values
+fn percentile(sorted: &[f64], percentile: f64) -> f64 {
+    let rank = (percentile / 100.0 * sorted.len() as f64).ceil() as usize;
+
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use common::api_bindings::StreamerStatsUpdate;
+
+    use crate::frame_timing::{FrameTimings, SENT_FRAMES_CAPACITY};
+
+    fn percentiles(timings: &mut FrameTimings) -> (f64, f64, f64, u32) {
+        match timings.take_stats() {
+            Some(StreamerStatsUpdate::FrameLatency {
+                p50_ms,
+                p95_ms,
+                p99_ms,
+                samples,
+            }) => (p50_ms, p95_ms, p99_ms, samples),
+            stats => panic!("unexpected stats {stats:?}"),
+        }
+    }
+
+    #[test]
+    fn test_frame_ids_wrap_around() {
+        let mut timings = FrameTimings::default();
+        let start = Instant::now();
+
+        // The ids wrap around while the frames are sent
+        let mut frame_id = u32::MAX - 4;
+        for i in 0..10 {
+            timings.sent(frame_id, start + Duration::from_millis(i));
+            frame_id = frame_id.wrapping_add(1);
+        }
+
+        let rendered = start + Duration::from_millis(30);
+        assert!(timings.rendered(u32::MAX - 1, 30, rendered));
+        assert!(timings.rendered(2, 30, rendered));
+        // Reported twice or reported after a later frame
+        assert!(!timings.rendered(2, 30, rendered));
+        assert!(!timings.rendered(u32::MAX, 30, rendered));
+
+        let (p50_ms, _, p99_ms, samples) = percentiles(&mut timings);
+        assert_eq!(samples, 2);
+        assert!((p50_ms - 23.0).abs() < 0.01);
+        assert!((p99_ms - 27.0).abs() < 0.01);
+
+        // The stats are only sent once
+        assert!(timings.take_stats().is_none());
+    }
+
+    #[test]
+    fn test_old_frames_are_forgotten() {
+        let mut timings = FrameTimings::default();
+        let start = Instant::now();
+
+        for frame_id in 0..SENT_FRAMES_CAPACITY as u32 + 1 {
+            timings.sent(frame_id, start);
+        }
+
+        assert!(!timings.rendered(0, 0, start));
+        assert!(timings.rendered(1, 0, start));
+    }
+
+    #[test]
+    fn test_slow_reports_are_corrected() {
+        let mut timings = FrameTimings::default();
+        let start = Instant::now();
+
+        for i in 0..4u32 {
+            let sent = start + Duration::from_millis(i as u64 * 10);
+            timings.sent(i, sent);
+
+            // Every frame is rendered after 20ms and the report needs 5ms,
+            // except the last report which the client sent 40ms late
+            let render_ms = 1000 + i * 10 + 20;
+            let arrival = sent + Duration::from_millis(if i == 3 { 65 } else { 25 });
+            assert!(timings.rendered(i, render_ms, arrival));
+        }
+
+        let (p50_ms, p95_ms, p99_ms, samples) = percentiles(&mut timings);
+        assert_eq!(samples, 4);
+        assert!((p50_ms - 25.0).abs() < 0.01);
+        assert!((p95_ms - 25.0).abs() < 0.01);
+        assert!((p99_ms - 25.0).abs() < 0.01);
+    }
+}
@@ -0,0 +1,354 @@
+//! Translates the key presses of a client into the input which is sent to the host.
+//!
+//! The browser reports the virtual key code of the physical key as if the layout was US,
+//! so on e.g. AZERTY or QWERTZ layouts the host types a different character than the client pressed.
+//! In [KeyboardMode::TextFallback] printable characters are typed as text instead,
+//! a [KeyboardLayout] remaps the key codes which are sent to the host.
+
+use std::collections::{HashMap, HashSet};
+
+use common::api_bindings::{KeyOverride, KeyboardLayout, KeyboardMode};
+use moonlight_common::stream::bindings::{KeyAction, KeyModifiers};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyInput {
+    /// Send a key event with this virtual key code
+    Key(u16),
+    /// Type this text
+    Text(String),
+}
+
+#[derive(Default)]
+pub struct KeyboardTranslator {
+    mode: KeyboardMode,
+    /// Maps the key codes of the client to the key codes sent to the host
+    layout: HashMap<u16, u16>,
+    overrides: HashMap<u16, KeyOverride>,
+    /// The key codes which were sent to the host for the currently pressed keys of the client
+    pressed: HashMap<u16, u16>,
+    /// The currently pressed keys of the client which were typed as text
+    typed: HashSet<u16>,
+}
+
+impl KeyboardTranslator {
+    pub fn new(
+        mode: KeyboardMode,
+        layout: HashMap<u16, u16>,
+        overrides: HashMap<u16, KeyOverride>,
+    ) -> Self {
+        Self {
+            mode,
+            layout,
+            overrides,
+            pressed: HashMap::new(),
+            typed: HashSet::new(),
+        }
+    }
+
+    /// Returns what to send to the host for this key event, None if nothing should be sent.
+    /// `text` is the character the client typed with this key.
+    pub fn translate(
+        &mut self,
+        action: KeyAction,
+        modifiers: KeyModifiers,
+        key: u16,
+        text: Option<&str>,
+    ) -> Option<KeyInput> {
+        match action {
+            KeyAction::Down => Some(self.key_down(modifiers, key, text)),
+            KeyAction::Up => self.key_up(key),
+        }
+    }
+
+    fn key_down(&mut self, modifiers: KeyModifiers, key: u16, text: Option<&str>) -> KeyInput {
+        match self.overrides.get(&key) {
+            Some(KeyOverride::Key(override_key)) => {
+                self.pressed.insert(key, *override_key);
+                return KeyInput::Key(*override_key);
+            }
+            Some(KeyOverride::Text(text)) => {
+                self.typed.insert(key);
+                return KeyInput::Text(text.clone());
+            }
+            None => {}
+        }
+
+        if self.mode == KeyboardMode::TextFallback
+            && !is_shortcut(modifiers)
+            && let Some(text) = text.filter(|text| is_printable(text))
+        {
+            self.typed.insert(key);
+            return KeyInput::Text(text.to_string());
+        }
+
+        let layout_key = self.layout_key(key);
+        self.pressed.insert(key, layout_key);
+        KeyInput::Key(layout_key)
+    }
+
+    fn key_up(&mut self, key: u16) -> Option<KeyInput> {
+        // Release the key that was actually pressed on the host, even if the modifiers changed in between
+        if let Some(sent_key) = self.pressed.remove(&key) {
+            return Some(KeyInput::Key(sent_key));
+        }
+        // Text has no key to release
+        if self.typed.remove(&key) {
+            return None;
+        }
+
+        Some(KeyInput::Key(self.layout_key(key)))
+    }
+
+    fn layout_key(&self, key: u16) -> u16 {
+        self.layout.get(&key).copied().unwrap_or(key)
+    }
+}
+
+const VK_KEY_A: u16 = 0x41;
+const VK_KEY_M: u16 = 0x4D;
+const VK_KEY_Q: u16 = 0x51;
+const VK_KEY_W: u16 = 0x57;
+const VK_KEY_Y: u16 = 0x59;
+const VK_KEY_Z: u16 = 0x5A;
+const VK_OEM_1: u16 = 0xBA;
+const VK_OEM_COMMA: u16 = 0xBC;
+
+/// The physical keys of an AZERTY layout which type different letters than on a US layout
+const AZERTY_LAYOUT: [(u16, u16); 7] = [
+    (VK_KEY_A, VK_KEY_Q),
+    (VK_KEY_Q, VK_KEY_A),
+    (VK_KEY_W, VK_KEY_Z),
+    (VK_KEY_Z, VK_KEY_W),
+    (VK_OEM_1, VK_KEY_M),
+    (VK_KEY_M, VK_OEM_COMMA),
+    (VK_OEM_COMMA, VK_OEM_1),
+];
+/// The physical keys of a QWERTZ layout which type different letters than on a US layout
+const QWERTZ_LAYOUT: [(u16, u16); 2] = [(VK_KEY_Y, VK_KEY_Z), (VK_KEY_Z, VK_KEY_Y)];
+
+/// Returns the key code mapping of this layout, `custom` is only used by [KeyboardLayout::Custom]
+pub fn layout_mapping(layout: KeyboardLayout, custom: &HashMap<u16, u16>) -> HashMap<u16, u16> {
+    match layout {
+        KeyboardLayout::Passthrough => HashMap::new(),
+        KeyboardLayout::Azerty => HashMap::from(AZERTY_LAYOUT),
+        KeyboardLayout::Qwertz => HashMap::from(QWERTZ_LAYOUT),
+        KeyboardLayout::Custom => custom.clone(),
+    }
+}
+
+/// Ctrl, Alt or Meta combinations are shortcuts which must reach the host as keys.
+/// Ctrl together with Alt is how browsers report AltGr, which types characters on many layouts.
+fn is_shortcut(modifiers: KeyModifiers) -> bool {
+    let alt_gr = modifiers.contains(KeyModifiers::CTRL | KeyModifiers::ALT);
+
+    modifiers.intersects(KeyModifiers::CTRL | KeyModifiers::ALT | KeyModifiers::META) && !alt_gr
+}
+
+fn is_printable(text: &str) -> bool {
+    let mut chars = text.chars();
+
+    matches!((chars.next(), chars.next()), (Some(char), None) if !char.is_control())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use common::api_bindings::{KeyOverride, KeyboardLayout, KeyboardMode};
+    use moonlight_common::stream::bindings::{KeyAction, KeyModifiers};
+
+    use crate::keyboard::{KeyInput, KeyboardTranslator, layout_mapping};
+
+    const VK_SHIFT: u16 = 0xA0;
+    const VK_LEFT: u16 = 0x25;
+    const VK_KEY_0: u16 = 0x30;
+    const VK_KEY_A: u16 = 0x41;
+    const VK_KEY_C: u16 = 0x43;
+    const VK_KEY_Q: u16 = 0x51;
+    const VK_KEY_Y: u16 = 0x59;
+    const VK_KEY_Z: u16 = 0x5A;
+    const VK_OEM_3: u16 = 0xC0;
+    const VK_OEM_7: u16 = 0xDE;
+
+    fn down(
+        translator: &mut KeyboardTranslator,
+        modifiers: KeyModifiers,
+        key: u16,
+        text: Option<&str>,
+    ) -> Option<KeyInput> {
+        translator.translate(KeyAction::Down, modifiers, key, text)
+    }
+    fn up(translator: &mut KeyboardTranslator, key: u16, text: Option<&str>) -> Option<KeyInput> {
+        translator.translate(KeyAction::Up, KeyModifiers::empty(), key, text)
+    }
+
+    fn text(text: &str) -> Option<KeyInput> {
+        Some(KeyInput::Text(text.to_string()))
+    }
+
+    #[test]
+    fn test_vk_passthrough() {
+        let mut translator = KeyboardTranslator::default();
+
+        // AZERTY: the physical Q key types an "a"
+        assert_eq!(
+            down(&mut translator, KeyModifiers::empty(), VK_KEY_Q, Some("a")),
+            Some(KeyInput::Key(VK_KEY_Q))
+        );
+        assert_eq!(
+            up(&mut translator, VK_KEY_Q, Some("a")),
+            Some(KeyInput::Key(VK_KEY_Q))
+        );
+    }
+
+    #[test]
+    fn test_text_fallback_layouts() {
+        let mut translator =
+            KeyboardTranslator::new(KeyboardMode::TextFallback, HashMap::new(), HashMap::new());
+
+        // AZERTY: the physical Q key types an "a"
+        assert_eq!(
+            down(&mut translator, KeyModifiers::empty(), VK_KEY_Q, Some("a")),
+            text("a")
+        );
+        assert_eq!(up(&mut translator, VK_KEY_Q, Some("a")), None);
+
+        // QWERTZ: the physical Y key types an uppercase "Z" while shift is held
+        assert_eq!(
+            down(&mut translator, KeyModifiers::SHIFT, VK_SHIFT, None),
+            Some(KeyInput::Key(VK_SHIFT))
+        );
+        assert_eq!(
+            down(&mut translator, KeyModifiers::SHIFT, VK_KEY_Y, Some("Z")),
+            text("Z")
+        );
+        assert_eq!(up(&mut translator, VK_KEY_Y, Some("Z")), None);
+        assert_eq!(
+            up(&mut translator, VK_SHIFT, None),
+            Some(KeyInput::Key(VK_SHIFT))
+        );
+
+        // AZERTY: AltGr + 0 types an "@"
+        assert_eq!(
+            down(
+                &mut translator,
+                KeyModifiers::CTRL | KeyModifiers::ALT,
+                VK_KEY_0,
+                Some("@")
+            ),
+            text("@")
+        );
+        assert_eq!(up(&mut translator, VK_KEY_0, Some("@")), None);
+
+        // Keys without a printable character stay keys
+        assert_eq!(
+            down(&mut translator, KeyModifiers::empty(), VK_LEFT, None),
+            Some(KeyInput::Key(VK_LEFT))
+        );
+        assert_eq!(
+            up(&mut translator, VK_LEFT, None),
+            Some(KeyInput::Key(VK_LEFT))
+        );
+    }
+
+    #[test]
+    fn test_text_fallback_shortcuts() {
+        let mut translator =
+            KeyboardTranslator::new(KeyboardMode::TextFallback, HashMap::new(), HashMap::new());
+
+        // Ctrl + C must copy instead of typing a "c"
+        assert_eq!(
+            down(&mut translator, KeyModifiers::CTRL, VK_KEY_C, Some("c")),
+            Some(KeyInput::Key(VK_KEY_C))
+        );
+        // Ctrl was released before the key, the key still has to be released on the host
+        assert_eq!(
+            up(&mut translator, VK_KEY_C, Some("c")),
+            Some(KeyInput::Key(VK_KEY_C))
+        );
+
+        // Dead keys and other multi character keys aren't printable
+        assert_eq!(
+            down(
+                &mut translator,
+                KeyModifiers::empty(),
+                VK_OEM_7,
+                Some("Dead")
+            ),
+            Some(KeyInput::Key(VK_OEM_7))
+        );
+    }
+
+    #[test]
+    fn test_key_overrides() {
+        let overrides = HashMap::from([
+            (VK_OEM_7, KeyOverride::Key(VK_OEM_3)),
+            (VK_KEY_A, KeyOverride::Text("ä".to_string())),
+        ]);
+
+        for mode in [KeyboardMode::VkPassthrough, KeyboardMode::TextFallback] {
+            let mut translator = KeyboardTranslator::new(mode, HashMap::new(), overrides.clone());
+
+            assert_eq!(
+                down(&mut translator, KeyModifiers::empty(), VK_OEM_7, Some("'")),
+                Some(KeyInput::Key(VK_OEM_3))
+            );
+            assert_eq!(
+                up(&mut translator, VK_OEM_7, Some("'")),
+                Some(KeyInput::Key(VK_OEM_3))
+            );
+
+            assert_eq!(
+                down(&mut translator, KeyModifiers::empty(), VK_KEY_A, Some("a")),
+                text("ä")
+            );
+            assert_eq!(up(&mut translator, VK_KEY_A, Some("a")), None);
+        }
+    }
+
+    #[test]
+    fn test_keyboard_layouts() {
+        // QWERTZ: the physical Y key is a "z"
+        let mut translator = KeyboardTranslator::new(
+            KeyboardMode::VkPassthrough,
+            layout_mapping(KeyboardLayout::Qwertz, &HashMap::new()),
+            HashMap::new(),
+        );
+        assert_eq!(
+            down(&mut translator, KeyModifiers::CTRL, VK_KEY_Y, Some("z")),
+            Some(KeyInput::Key(VK_KEY_Z))
+        );
+        assert_eq!(
+            up(&mut translator, VK_KEY_Y, Some("z")),
+            Some(KeyInput::Key(VK_KEY_Z))
+        );
+        assert_eq!(
+            down(&mut translator, KeyModifiers::empty(), VK_KEY_C, Some("c")),
+            Some(KeyInput::Key(VK_KEY_C))
+        );
+
+        // The custom layout is only used when selected
+        let custom = HashMap::from([(VK_KEY_Q, VK_KEY_A)]);
+        assert!(layout_mapping(KeyboardLayout::Passthrough, &custom).is_empty());
+
+        let mut translator = KeyboardTranslator::new(
+            KeyboardMode::TextFallback,
+            layout_mapping(KeyboardLayout::Custom, &custom),
+            HashMap::new(),
+        );
+        // Text is typed as is, shortcuts are remapped
+        assert_eq!(
+            down(&mut translator, KeyModifiers::empty(), VK_KEY_Q, Some("a")),
+            text("a")
+        );
+        assert_eq!(up(&mut translator, VK_KEY_Q, Some("a")), None);
+        assert_eq!(
+            down(&mut translator, KeyModifiers::CTRL, VK_KEY_Q, Some("a")),
+            Some(KeyInput::Key(VK_KEY_A))
+        );
+        assert_eq!(
+            up(&mut translator, VK_KEY_Q, Some("a")),
+            Some(KeyInput::Key(VK_KEY_A))
+        );
+    }
+}
@@ -2,34 +2,44 @@
 #![feature(async_fn_traits)]
 
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    io,
+    num::NonZeroUsize,
     panic,
+    path::PathBuf,
     process::exit,
     sync::{
-        Arc, Weak,
-        atomic::{AtomicBool, Ordering},
+        Arc, Mutex as StdMutex, MutexGuard, PoisonError, Weak,
+        atomic::{AtomicBool, AtomicU32, Ordering},
     },
+    time::{Duration, Instant},
 };
 
 use common::{
-    StreamSettings,
-    api_bindings::{GeneralServerMessage, LogMessageType, StreamClientMessage, TransportType},
+    AllowedInputs, StreamSettings,
+    api_bindings::{
+        ControllerMode, GeneralClientMessage, GeneralServerMessage, LogMessageType, PeerVideoMode,
+        PlayerSlot, RoomEventKind, RoomRole, StreamClientMessage, TransportChannelId,
+        TransportType,
+    },
     ipc::{
-        IpcReceiver, IpcSender, ServerIpcMessage, StreamerConfig, StreamerIpcMessage,
-        create_process_ipc,
+        DEFAULT_IPC_CHANNEL_CAPACITY, IpcReceiver, IpcSender, STREAMER_IPC_CAPACITY_ARG,
+        STREAMER_IPC_SOCKET_ARG, STREAMER_REATTACH_TIMEOUT_ARG, STREAMER_ROOM_ID_ENV,
+        ServerIpcMessage, StreamerConfig, StreamerIpcMessage, create_process_ipc,
     },
 };
 use log::{LevelFilter, debug, error, info, trace, warn};
 use moonlight_common::{
     MoonlightError,
     high::{HostError, MoonlightHost},
-    network::backend::reqwest::ReqwestClient,
+    network::{ApiError, backend::reqwest::ReqwestClient},
     pair::ClientAuth,
     stream::{
         MoonlightInstance, MoonlightStream,
         bindings::{
-            ActiveGamepads, ColorRange, ConnectionStatus, ControllerButtons, EncryptionFlags,
-            HostFeatures, OpusMultistreamConfig, Stage, VideoFormat,
+            ActiveGamepads, AudioConfig, ColorRange, ConnectionStatus, ControllerButtons,
+            EncryptionFlags, FrameType, HostFeatures, KeyAction, KeyFlags, KeyModifiers,
+            MouseButton, MouseButtonAction, OpusMultistreamConfig, Stage, VideoFormat,
         },
         connection::ConnectionListener,
         video::VideoSetup,
@@ -37,42 +47,238 @@ use moonlight_common::{
 };
 use simplelog::{ColorChoice, TermLogger, TerminalMode};
 use tokio::{
-    io::{stdin, stdout},
+    io::{AsyncWrite, stdin, stdout},
     runtime::Handle,
     spawn,
-    sync::{Mutex, Notify, RwLock},
-    task::spawn_blocking,
+    sync::{
+        Mutex, Notify, RwLock,
+        mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+    },
+    task::{JoinHandle, spawn_blocking},
+    time::sleep,
 };
 
-use common::api_bindings::{StreamCapabilities, StreamServerMessage};
+use common::api_bindings::{
+    StreamCapabilities, StreamServerMessage, StreamStageTiming, StreamerStatsType,
+};
 
 use crate::{
     audio::StreamAudioDecoder,
-    peer_manager::PeerManager,
+    convert::{
+        MEMORY_LIMIT, STAGE_TIMEOUT, connection_terminated_message, host_video_formats,
+        negotiate_video_formats, video_codecs,
+    },
+    delay::{DelayedMedia, DelayedPeer, OwnedVideoUnit},
+    frame_timing::FrameTimings,
+    keyboard::{KeyInput, KeyboardTranslator, layout_mapping},
+    peer_manager::{
+        GamepadArrival, GamepadState, HeldInput, PeerManager, SHARED_GAMEPAD_MERGE_INTERVAL,
+    },
+    quality::{QUALITY_MEASURE_INTERVAL, QualityMonitor},
+    resources::{
+        MemoryAction, MemoryGuard, RESOURCE_REPORT_INTERVAL, RESOURCE_SAMPLE_INTERVAL, rss_bytes,
+    },
     transport::{
-        InboundPacket, OutboundPacket, TransportError, TransportEvent, TransportEvents,
-        TransportSender, web_socket, webrtc,
+        DeliveryClass, InboundPacket, OutboundPacket, TransportError, TransportEvent,
+        TransportEvents, TransportReadiness, TransportSender, web_socket, webrtc,
     },
     video::StreamVideoDecoder,
 };
 use common::ipc::PeerId;
 
 /// Holds transport sender and events for a peer
+/// How often the setup of a peer whose setup failed is retried
+const PEER_SETUP_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
 struct PeerTransport {
     sender: Box<dyn TransportSender + Send + Sync + 'static>,
+    /// Set by the events task once the transport can receive the stream
+    readiness: Arc<TransportReadiness>,
+    /// When the frames were sent, to match them with the frames the peer reports as rendered
+    frame_timings: StdMutex<FrameTimings>,
     // The events task handle - kept alive while transport is active
     _events_task: tokio::task::JoinHandle<()>,
 }
 
+/// False if the input type of the packet is disabled for the stream, other packets are always allowed
+fn input_allowed(allowed: AllowedInputs, packet: &InboundPacket) -> bool {
+    match packet {
+        InboundPacket::MouseMove { .. }
+        | InboundPacket::MousePosition { .. }
+        | InboundPacket::MouseButton { .. }
+        | InboundPacket::HighResScroll { .. }
+        | InboundPacket::Scroll { .. } => allowed.mouse,
+        InboundPacket::Key { .. } | InboundPacket::Text { .. } => allowed.keyboard,
+        InboundPacket::ControllerConnected { .. }
+        | InboundPacket::ControllerDisconnected { .. }
+        | InboundPacket::ControllerState { .. } => allowed.gamepad,
+        InboundPacket::Touch { .. } => allowed.touch,
+        InboundPacket::General { .. }
+        | InboundPacket::SetStatsInterval { .. }
+        | InboundPacket::FrameRendered { .. } => true,
+    }
+}
+
+/// True if the host refused to launch because another app is running
+fn is_app_running_error<E>(err: &HostError<E>) -> bool {
+    // Sunshine answers with "An app is already running on this host"
+    matches!(
+        err,
+        HostError::Api(ApiError::InvalidXmlStatusCode { message: Some(message) })
+            if message.to_lowercase().contains("already running")
+    )
+}
+
+/// Holds the delay buffer of a peer and the task which releases it
+struct PeerDelay {
+    delayed: Arc<DelayedPeer>,
+    release_task: tokio::task::JoinHandle<()>,
+}
+
 pub type RequestClient = ReqwestClient;
 
 mod audio;
 mod buffer;
 mod convert;
+mod delay;
+mod frame_timing;
+mod keyboard;
 mod peer_manager;
+mod quality;
+mod recording;
+mod resources;
 mod transport;
 mod video;
 
+/// Moves the ipc to a duplicate of stdout and points stdout to stderr,
+/// so the moonlight library or a stray print can't write into the ipc
+#[cfg(unix)]
+fn take_ipc_stdout() -> Box<dyn AsyncWrite + Send + Unpin> {
+    use std::os::fd::FromRawFd;
+
+    // SAFETY: duplicating the standard file descriptors has no preconditions
+    let ipc_fd = unsafe { libc::dup(libc::STDOUT_FILENO) };
+    if ipc_fd < 0 {
+        eprintln!("failed to duplicate stdout: {}", io::Error::last_os_error());
+        return Box::new(stdout());
+    }
+    // SAFETY: see above
+    if unsafe { libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) } < 0 {
+        eprintln!(
+            "failed to redirect stdout to stderr: {}",
+            io::Error::last_os_error()
+        );
+
+        // SAFETY: the duplicate isn't used by anything else
+        unsafe { libc::close(ipc_fd) };
+        return Box::new(stdout());
+    }
+
+    // SAFETY: we're the only owner of this new file descriptor
+    let file = unsafe { std::fs::File::from_raw_fd(ipc_fd) };
+    Box::new(tokio::fs::File::from_std(file))
+}
+#[cfg(not(unix))]
+fn take_ipc_stdout() -> Box<dyn AsyncWrite + Send + Unpin> {
+    Box::new(stdout())
+}
+
+/// The arguments the web server started us with
+struct IpcArgs {
+    /// The ipc socket path and reattach timeout if we were started with [STREAMER_IPC_SOCKET_ARG]
+    socket: Option<(PathBuf, Duration)>,
+    capacity: NonZeroUsize,
+}
+
+fn ipc_args() -> IpcArgs {
+    let mut socket_path = None;
+    let mut reattach_timeout = Duration::from_secs(60);
+    let mut capacity = DEFAULT_IPC_CHANNEL_CAPACITY;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            STREAMER_IPC_SOCKET_ARG => socket_path = args.next().map(PathBuf::from),
+            STREAMER_REATTACH_TIMEOUT_ARG => {
+                if let Some(secs) = args.next().and_then(|secs| secs.parse().ok()) {
+                    reattach_timeout = Duration::from_secs(secs);
+                }
+            }
+            STREAMER_IPC_CAPACITY_ARG => {
+                if let Some(value) = args.next().and_then(|value| value.parse().ok()) {
+                    capacity = value;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    IpcArgs {
+        socket: socket_path.map(|socket_path| (socket_path, reattach_timeout)),
+        capacity,
+    }
+}
+
+/// Only the owner can connect to the ipc socket
+#[cfg(unix)]
+const IPC_SOCKET_MODE: u32 = 0o600;
+
+#[cfg(unix)]
+async fn create_ipc(
+    args: IpcArgs,
+) -> (IpcSender<StreamerIpcMessage>, IpcReceiver<ServerIpcMessage>) {
+    use std::{fs::Permissions, os::unix::fs::PermissionsExt};
+
+    use common::ipc::create_socket_ipc;
+    use tokio::net::UnixListener;
+
+    let Some((socket_path, reattach_timeout)) = args.socket else {
+        return create_process_ipc(stdin(), take_ipc_stdout(), args.capacity).await;
+    };
+
+    // A socket of a previous streamer with the same path
+    let _ = std::fs::remove_file(&socket_path);
+    let listener = match UnixListener::bind(&socket_path) {
+        Ok(listener) => listener,
+        Err(err) => {
+            eprintln!("failed to bind the ipc socket {socket_path:?}: {err}");
+            exit(1);
+        }
+    };
+    // Only the web server, which runs as the same user, may connect to the streamer
+    if let Err(err) =
+        std::fs::set_permissions(&socket_path, Permissions::from_mode(IPC_SOCKET_MODE))
+    {
+        eprintln!("failed to restrict the permissions of the ipc socket {socket_path:?}: {err}");
+        let _ = std::fs::remove_file(&socket_path);
+        exit(1);
+    }
+
+    match create_socket_ipc(listener, reattach_timeout, args.capacity).await {
+        Ok(ipc) => ipc,
+        Err(err) => {
+            eprintln!("failed to accept the ipc connection: {err}");
+            let _ = std::fs::remove_file(&socket_path);
+            exit(1);
+        }
+    }
+}
+#[cfg(not(unix))]
+async fn create_ipc(
+    args: IpcArgs,
+) -> (IpcSender<StreamerIpcMessage>, IpcReceiver<ServerIpcMessage>) {
+    create_process_ipc(stdin(), take_ipc_stdout(), args.capacity).await
+}
+
+fn is_video_frame(message: &StreamerIpcMessage) -> bool {
+    matches!(
+        message,
+        StreamerIpcMessage::WebSocketTransport(data)
+            | StreamerIpcMessage::PeerWebSocketTransport { data, .. }
+            if data.first() == Some(&TransportChannelId::HOST_VIDEO)
+    )
+}
+
 #[tokio::main]
 async fn main() {
     let default_panic = panic::take_hook();
@@ -82,8 +288,7 @@ async fn main() {
     }));
 
     // At this point we're authenticated
-    let (mut ipc_sender, mut ipc_receiver) =
-        create_process_ipc::<ServerIpcMessage, StreamerIpcMessage>(stdin(), stdout()).await;
+    let (mut ipc_sender, mut ipc_receiver) = create_ipc(ipc_args()).await;
 
     // Send stage
     ipc_sender
@@ -99,6 +304,7 @@ async fn main() {
         config,
         host_address,
         host_http_port,
+        host_https_port_override,
         client_unique_id,
         client_private_key,
         client_certificate,
@@ -112,6 +318,7 @@ async fn main() {
                 config,
                 host_address,
                 host_http_port,
+                host_https_port_override,
                 client_unique_id,
                 client_private_key,
                 client_certificate,
@@ -124,6 +331,7 @@ async fn main() {
                     config,
                     host_address,
                     host_http_port,
+                    host_https_port_override,
                     client_unique_id,
                     client_private_key,
                     client_certificate,
@@ -133,6 +341,8 @@ async fn main() {
                     audio_sample_queue_size,
                 );
             }
+            // The web server is gone before we were initialized
+            None => exit(0),
             _ => continue,
         }
     };
@@ -148,6 +358,10 @@ async fn main() {
     )
     .expect("failed to init logger");
 
+    if let Ok(room_id) = std::env::var(STREAMER_ROOM_ID_ENV) {
+        info!("[Stream]: Streaming room {room_id}");
+    }
+
     // Send stage
     ipc_sender
         .send(StreamerIpcMessage::WebSocket(
@@ -161,6 +375,7 @@ async fn main() {
     // -- Create the host and pair it
     let mut host = MoonlightHost::new(host_address, host_http_port, client_unique_id)
         .expect("failed to create host");
+    host.set_https_port_override(host_https_port_override);
 
     host.set_pairing_info(
         &ClientAuth {
@@ -190,10 +405,15 @@ async fn main() {
     .await
     .expect("failed to create connection");
 
+    spawn(StreamConnection::monitor_resources(Arc::downgrade(
+        &connection,
+    )));
+
     // Send Info for streamer
     ipc_sender
         .send(StreamerIpcMessage::WebSocket(StreamServerMessage::Setup {
             ice_servers: connection.config.webrtc.ice_servers.clone(),
+            data_channels: connection.config.webrtc.data_channel_options(),
         }))
         .await;
 
@@ -212,6 +432,47 @@ struct StreamInfo {
 struct StreamSetup {
     video: Option<VideoSetup>,
     audio: Option<OpusMultistreamConfig>,
+    /// Kept with the audio so the setup can be retried for a single transport
+    audio_config: Option<AudioConfig>,
+}
+
+/// The input sent to the host which isn't forwarded from a peer, tests replace the stream with a fake
+trait HostInput: Send + Sync {
+    fn send_keyboard_event_non_standard(
+        &self,
+        key_code: i16,
+        key_action: KeyAction,
+        modifiers: KeyModifiers,
+        flags: KeyFlags,
+    ) -> Result<(), MoonlightError>;
+
+    fn send_mouse_button(
+        &self,
+        action: MouseButtonAction,
+        button: MouseButton,
+    ) -> Result<(), MoonlightError>;
+}
+
+impl HostInput for MoonlightStream {
+    fn send_keyboard_event_non_standard(
+        &self,
+        key_code: i16,
+        key_action: KeyAction,
+        modifiers: KeyModifiers,
+        flags: KeyFlags,
+    ) -> Result<(), MoonlightError> {
+        MoonlightStream::send_keyboard_event_non_standard(
+            self, key_code, key_action, modifiers, flags,
+        )
+    }
+
+    fn send_mouse_button(
+        &self,
+        action: MouseButtonAction,
+        button: MouseButton,
+    ) -> Result<(), MoonlightError> {
+        MoonlightStream::send_mouse_button(self, action, button)
+    }
 }
 
 struct StreamConnection {
@@ -227,6 +488,10 @@ struct StreamConnection {
     // Stream
     pub stream: RwLock<Option<MoonlightStream>>,
     pub active_gamepads: RwLock<ActiveGamepads>,
+    /// Configured by the settings of the stream
+    pub keyboard: Mutex<KeyboardTranslator>,
+    /// The input types the host gets, configured by the settings of the stream
+    pub allowed_inputs: RwLock<AllowedInputs>,
     /// Per-peer transports - each peer can have their own WebRTC or WebSocket transport
     pub peer_transports: RwLock<HashMap<PeerId, PeerTransport>>,
     pub terminate: Notify,
@@ -235,6 +500,25 @@ struct StreamConnection {
     pub peer_manager: RwLock<PeerManager>,
     /// Current peer context for input handling (set during packet processing)
     pub current_peer_id: RwLock<Option<PeerId>>,
+    /// Peers which see the stream later than the players, e.g. spectators
+    pub peer_delays: RwLock<HashMap<PeerId, PeerDelay>>,
+    /// Spectators which only receive the keyframes of the stream
+    pub thumbnail_peers: RwLock<HashSet<PeerId>>,
+    /// The last keyframe of the stream, a peer in the thumbnail mode gets it instead of a new one when its transport is ready
+    pub cached_keyframe: StdMutex<Option<Arc<OwnedVideoUnit>>>,
+    /// Set while the memory is above the limit, all peers only receive the keyframes
+    pub keyframe_only: AtomicBool,
+    /// Set when a peer needs a keyframe, the video decoder requests it from the host
+    pub request_idr: AtomicBool,
+    /// Set by the host, no video and audio is sent to the peers while the stream is paused
+    pub paused: AtomicBool,
+    /// Incremented by every start of the stream, sent in `ConnectionComplete`
+    pub stream_epoch: AtomicU32,
+    /// Measured by the video decoder, reported to the clients while the stream runs
+    pub quality: StdMutex<QualityMonitor>,
+    /// Gets the input instead of the stream, which can't be started without a host
+    #[cfg(test)]
+    pub fake_host_input: std::sync::OnceLock<Arc<dyn HostInput>>,
 }
 
 impl StreamConnection {
@@ -256,16 +540,29 @@ impl StreamConnection {
             stream_setup: Mutex::new(StreamSetup {
                 video: None,
                 audio: None,
+                audio_config: None,
             }),
             video_frame_queue_size,
             audio_sample_queue_size,
             stream: RwLock::new(None),
             active_gamepads: RwLock::new(ActiveGamepads::empty()),
+            keyboard: Mutex::new(KeyboardTranslator::default()),
+            allowed_inputs: RwLock::new(AllowedInputs::default()),
             peer_transports: RwLock::new(HashMap::new()),
             terminate: Notify::default(),
             is_terminating: AtomicBool::new(false),
             peer_manager: RwLock::new(PeerManager::new()),
             current_peer_id: RwLock::new(None),
+            peer_delays: RwLock::new(HashMap::new()),
+            thumbnail_peers: RwLock::new(HashSet::new()),
+            cached_keyframe: StdMutex::new(None),
+            keyframe_only: AtomicBool::new(false),
+            request_idr: AtomicBool::new(false),
+            paused: AtomicBool::new(false),
+            stream_epoch: AtomicU32::new(0),
+            #[cfg(test)]
+            fake_host_input: std::sync::OnceLock::new(),
+            quality: StdMutex::new(QualityMonitor::default()),
         });
 
         spawn({
@@ -285,6 +582,12 @@ impl StreamConnection {
 
                     this.on_ipc_message(message).await;
                 }
+
+                // The web server is gone and didn't reconnect
+                if let Some(this) = this.upgrade() {
+                    info!("[Stream]: the ipc closed, stopping");
+                    this.stop().await;
+                }
             }
         });
 
@@ -306,11 +609,14 @@ impl StreamConnection {
             transports.remove(&peer_id).map(|pt| pt.sender)
         };
 
+        let readiness = Arc::new(TransportReadiness::default());
+
         // Spawn task to handle events from this peer's transport
         let events_task = spawn({
             let mut ipc_sender = this.ipc_sender.clone();
             let this = Arc::downgrade(&this);
             let peer_id = peer_id;
+            let readiness = readiness.clone();
 
             async move {
                 loop {
@@ -330,6 +636,18 @@ impl StreamConnection {
                                 }
                                 other => other,
                             };
+
+                            // Stale video frames aren't worth blocking the other messages on,
+                            // the dropped frames are repaired by the next keyframe
+                            if is_video_frame(&peer_message) {
+                                if !ipc_sender.send_lossy(peer_message)
+                                    && let Some(this) = this.upgrade()
+                                {
+                                    this.request_idr.store(true, Ordering::Relaxed);
+                                }
+                                continue;
+                            }
+
                             ipc_sender.send(peer_message).await;
                         }
                         Ok(TransportEvent::StartStream { settings }) => {
@@ -365,6 +683,23 @@ impl StreamConnection {
 
                             this.on_packet(packet).await;
                         }
+                        Ok(TransportEvent::Ready) => {
+                            let Some(this) = this.upgrade() else {
+                                warn!(
+                                    "Failed to get stream connection, stopping listening to events"
+                                );
+                                return;
+                            };
+
+                            readiness.set_connected();
+                            // The retry of the failed setup makes the transport ready once it succeeds
+                            if readiness.has_setup_failed() {
+                                continue;
+                            }
+
+                            readiness.set_ready();
+                            this.on_peer_ready(peer_id).await;
+                        }
                         Err(TransportError::Closed) | Ok(TransportEvent::Closed) => {
                             info!("Transport closed for peer {:?}", peer_id);
                             break;
@@ -401,6 +736,8 @@ impl StreamConnection {
             let mut transports = this.peer_transports.write().await;
             transports.insert(peer_id, PeerTransport {
                 sender: new_sender,
+                readiness,
+                frame_timings: StdMutex::new(FrameTimings::default()),
                 _events_task: events_task,
             });
         }
@@ -414,261 +751,748 @@ impl StreamConnection {
         }
     }
 
-    /// Remove transport for a peer (called when peer disconnects)
-    async fn remove_peer_transport(&self, peer_id: PeerId) {
-        let transport = {
-            let mut transports = self.peer_transports.write().await;
-            transports.remove(&peer_id)
+    /// The transport of a peer can receive the stream, it starts with the next keyframe
+    async fn on_peer_ready(&self, peer_id: PeerId) {
+        info!("Transport of peer {:?} is ready", peer_id);
+
+        // The peer joined in the middle of the stream and can't decode the frames until the next keyframe
+        if !self.send_cached_keyframe(peer_id).await {
+            self.request_keyframe().await;
+        }
+
+        self.flush_room_events().await;
+
+        // The quality is only reported when it changes, the peer would show none until then
+        let report = self
+            .quality
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .last_report();
+        if let Some(message) = report {
+            self.send_to_peer(peer_id, OutboundPacket::General { message })
+                .await;
+        }
+
+        let mut ipc_sender = self.ipc_sender.clone();
+        ipc_sender
+            .send(StreamerIpcMessage::PeerReady { peer_id })
+            .await;
+
+        // Sent after the stream the peer gets with `PeerReady`
+        if self.paused.load(Ordering::Relaxed) {
+            ipc_sender
+                .send(StreamerIpcMessage::PeerWebSocket {
+                    peer_id,
+                    message: StreamServerMessage::StreamPaused { paused: true },
+                })
+                .await;
+        }
+    }
+
+    /// Sends the last keyframe to a peer in the thumbnail mode, so the host doesn't have to send a new one to every peer.
+    /// False if the peer needs a new keyframe.
+    async fn send_cached_keyframe(&self, peer_id: PeerId) -> bool {
+        if !self.thumbnail_peers.read().await.contains(&peer_id) {
+            return false;
+        }
+        let Some(keyframe) = self
+            .cached_keyframe
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+        else {
+            return false;
         };
 
-        if let Some(transport) = transport {
-            if let Err(err) = transport.sender.close().await {
-                warn!("Failed to close transport for peer {:?}: {err:?}", peer_id);
+        let transports = self.peer_transports.read().await;
+        let Some(transport) = transports.get(&peer_id) else {
+            return false;
+        };
+        if !transport.readiness.accepts_video(FrameType::Idr) {
+            return false;
+        }
+
+        let buffers = keyframe.buffers();
+        match transport
+            .sender
+            .send_video_unit(&keyframe.as_unit(&buffers), keyframe.frame_id())
+            .await
+        {
+            Ok(_) => true,
+            Err(err) => {
+                warn!("Failed to send the cached keyframe to peer {peer_id:?}: {err}");
+                transport.readiness.wait_for_keyframe();
+                false
             }
         }
     }
-    /// Send packet to all connected peer transports
-    async fn try_send_packet(&self, packet: OutboundPacket, packet_ty: &str, should_warn: bool) {
+
+    /// Asks the host for a keyframe right away instead of with the result of the next frame,
+    /// the next frame still asks for it in case the stream isn't running yet
+    async fn request_keyframe(&self) {
+        self.request_idr.store(true, Ordering::Relaxed);
+
+        if let Some(stream) = self.stream.read().await.as_ref() {
+            stream.request_idr_frame();
+        }
+    }
+
+    /// Sets up the video on every transport, succeeds if at least one transport could set it up.
+    /// The setup of the other transports is retried so a single peer can't fail the stream.
+    async fn setup_video_transports(self: &Arc<Self>, setup: VideoSetup) -> i32 {
         let transports = self.peer_transports.read().await;
+        if transports.is_empty() {
+            error!("Failed to setup video because no transports are connected!");
+            return -1;
+        }
+
+        let mut result = -1;
+        for (peer_id, transport) in transports.iter() {
+            let r = transport.sender.setup_video(setup).await;
+            if r == 0 {
+                result = 0;
+            } else {
+                self.on_peer_setup_failed(*peer_id, &transport.readiness, "video", r)
+                    .await;
+            }
+        }
 
+        if result != 0 {
+            error!("Failed to setup video on every transport");
+        }
+        result
+    }
+
+    /// Sets up the audio like [Self::setup_video_transports]
+    async fn setup_audio_transports(
+        self: &Arc<Self>,
+        audio_config: AudioConfig,
+        stream_config: OpusMultistreamConfig,
+    ) -> i32 {
+        let transports = self.peer_transports.read().await;
         if transports.is_empty() {
-            debug!("Dropping packet {packet:?} because no transports are connected!");
-            return;
+            error!("Failed to setup audio because no transports are connected!");
+            return -1;
         }
 
-        // Serialize packet once, send to all transports
+        let mut result = -1;
         for (peer_id, transport) in transports.iter() {
-            // Clone the packet for each transport (OutboundPacket is small/cheap to clone)
-            let packet_clone = match &packet {
-                OutboundPacket::General { message } => OutboundPacket::General { message: message.clone() },
-                OutboundPacket::Stats(stats) => OutboundPacket::Stats(stats.clone()),
-                OutboundPacket::ControllerRumble { controller_number, low_frequency_motor, high_frequency_motor } => {
-                    OutboundPacket::ControllerRumble {
-                        controller_number: *controller_number,
-                        low_frequency_motor: *low_frequency_motor,
-                        high_frequency_motor: *high_frequency_motor,
-                    }
-                }
-                OutboundPacket::ControllerTriggerRumble { controller_number, left_trigger_motor, right_trigger_motor } => {
-                    OutboundPacket::ControllerTriggerRumble {
-                        controller_number: *controller_number,
-                        left_trigger_motor: *left_trigger_motor,
-                        right_trigger_motor: *right_trigger_motor,
-                    }
-                }
-            };
+            let r = transport
+                .sender
+                .setup_audio(audio_config, stream_config.clone())
+                .await;
+            if r == 0 {
+                result = 0;
+            } else {
+                self.on_peer_setup_failed(*peer_id, &transport.readiness, "audio", r)
+                    .await;
+            }
+        }
 
-            if let Err(err) = transport.sender.send(packet_clone).await {
-                if should_warn {
-                    warn!("Failed to send outbound packet to peer {:?}: {packet_ty}, {err:?}", peer_id);
-                } else {
-                    debug!("Failed to send outbound packet to peer {:?}: {packet_ty}, {err:?}", peer_id);
-                }
+        if result != 0 {
+            error!("Failed to setup audio on every transport");
+        }
+        result
+    }
+
+    /// Sets up the video and audio of the stream on the transport, the media and error code if it failed
+    async fn setup_peer(&self, transport: &PeerTransport) -> Result<(), (&'static str, i32)> {
+        let (video, audio) = {
+            let setup = self.stream_setup.lock().await;
+            (setup.video, setup.audio_config.zip(setup.audio.clone()))
+        };
+
+        if let Some(video) = video {
+            let r = transport.sender.setup_video(video).await;
+            if r != 0 {
+                return Err(("video", r));
             }
         }
+        if let Some((audio_config, stream_config)) = audio {
+            let r = transport
+                .sender
+                .setup_audio(audio_config, stream_config)
+                .await;
+            if r != 0 {
+                return Err(("audio", r));
+            }
+        }
+
+        Ok(())
     }
 
-    async fn on_packet(&self, packet: InboundPacket) {
-        let stream = self.stream.read().await;
-        let Some(stream) = stream.as_ref() else {
-            warn!("Failed to send packet {packet:?} because of missing stream");
+    /// The peer doesn't get the stream until the retry of its setup succeeds, the other peers aren't affected
+    async fn on_peer_setup_failed(
+        self: &Arc<Self>,
+        peer_id: PeerId,
+        readiness: &Arc<TransportReadiness>,
+        media: &str,
+        error_code: i32,
+    ) {
+        warn!("Failed to setup {media} for peer {peer_id:?} with {error_code}, retrying");
+
+        // The retry of an earlier failure is still running
+        if !readiness.set_setup_failed() {
             return;
-        };
+        }
 
-        // Get current peer ID for input filtering/mapping
-        let current_peer = *self.current_peer_id.read().await;
-        let peer_manager = self.peer_manager.read().await;
+        spawn(Self::retry_peer_setup(
+            Arc::downgrade(self),
+            peer_id,
+            readiness.clone(),
+        ));
 
-        // Helper to check if current peer can use keyboard/mouse
-        let can_use_keyboard_mouse = current_peer
-            .map(|pid| peer_manager.can_use_keyboard_mouse(pid))
-            .unwrap_or(true); // Allow if no peer context (backwards compat)
+        let mut ipc_sender = self.ipc_sender.clone();
+        ipc_sender
+            .send(StreamerIpcMessage::PeerWebSocket {
+                peer_id,
+                message: StreamServerMessage::DebugLog {
+                    message: format!(
+                        "Failed to setup the {media} of this connection ({error_code}), retrying"
+                    ),
+                    ty: Some(LogMessageType::InformError),
+                },
+            })
+            .await;
+    }
 
-        let err = match packet {
-            InboundPacket::General { message } => {
-                // currently there are no packets associated with that
-                match message {}
-            }
-            InboundPacket::MousePosition {
-                x,
-                y,
-                reference_width,
-                reference_height,
-            } => {
-                if !can_use_keyboard_mouse {
-                    debug!("Ignoring mouse position from non-Player 1");
-                    return;
-                }
-                stream
-                    .send_mouse_position(x, y, reference_width, reference_height)
-                    .err()
-            }
-            InboundPacket::MouseButton { action, button } => {
-                if !can_use_keyboard_mouse {
-                    debug!("Ignoring mouse button from non-Player 1");
-                    return;
-                }
-                stream.send_mouse_button(action, button).err()
-            }
-            InboundPacket::MouseMove { delta_x, delta_y } => {
-                if !can_use_keyboard_mouse {
-                    debug!("Ignoring mouse move from non-Player 1");
-                    return;
-                }
-                stream.send_mouse_move(delta_x, delta_y).err()
+    /// Retries the setup of the peer until it succeeds or the transport is gone,
+    /// the transport is ready afterwards if it already sent [TransportEvent::Ready]
+    async fn retry_peer_setup(
+        this: Weak<Self>,
+        peer_id: PeerId,
+        readiness: Arc<TransportReadiness>,
+    ) {
+        let mut attempt = 1;
+        loop {
+            sleep(PEER_SETUP_RETRY_INTERVAL).await;
+
+            let Some(this) = this.upgrade() else {
+                return;
+            };
+            if this.is_terminating.load(Ordering::Acquire) {
+                return;
             }
-            InboundPacket::HighResScroll { delta_x, delta_y } => {
-                if !can_use_keyboard_mouse {
-                    debug!("Ignoring scroll from non-Player 1");
+
+            let result = {
+                let transports = this.peer_transports.read().await;
+                // A new transport of the peer is set up on its own
+                let Some(transport) = transports
+                    .get(&peer_id)
+                    .filter(|transport| Arc::ptr_eq(&transport.readiness, &readiness))
+                else {
                     return;
-                }
-                let mut err = None;
-                if delta_y != 0 {
-                    err = stream.send_high_res_scroll(delta_y).err()
-                }
-                if delta_x != 0 {
-                    err = stream.send_high_res_horizontal_scroll(delta_x).err()
-                }
-                err
-            }
-            InboundPacket::Scroll { delta_x, delta_y } => {
-                if !can_use_keyboard_mouse {
-                    debug!("Ignoring scroll from non-Player 1");
+                };
+
+                this.setup_peer(transport).await
+            };
+
+            match result {
+                Ok(()) => {
+                    info!("Set up peer {peer_id:?} after {attempt} retries");
+
+                    readiness.clear_setup_failed();
+                    if readiness.is_connected() {
+                        readiness.set_ready();
+                        this.on_peer_ready(peer_id).await;
+                    }
                     return;
                 }
-                let mut err = None;
-                if delta_y != 0 {
-                    err = stream.send_scroll(delta_y).err();
-                }
-                if delta_x != 0 {
-                    err = stream.send_horizontal_scroll(delta_x).err();
-                }
-                err
-            }
-            InboundPacket::Key {
-                action,
-                modifiers,
-                key,
-                flags,
-            } => {
-                if !can_use_keyboard_mouse {
-                    debug!("Ignoring keyboard from non-Player 1");
-                    return;
+                Err((media, error_code)) => {
+                    warn!(
+                        "Retry {attempt} of the {media} setup for peer {peer_id:?} failed with {error_code}"
+                    );
                 }
-                stream
-                    .send_keyboard_event_non_standard(key as i16, action, modifiers, flags)
-                    .err()
             }
-            InboundPacket::Text { text } => {
-                if !can_use_keyboard_mouse {
-                    debug!("Ignoring text input from non-Player 1");
-                    return;
-                }
-                stream.send_text(&text).err()
+
+            attempt += 1;
+        }
+    }
+
+    /// Only spectators can use the thumbnail mode, the peer is told which mode it got
+    async fn set_peer_video_mode(&self, peer_id: PeerId, mode: PeerVideoMode) {
+        if mode == PeerVideoMode::Thumbnail && !self.peer_manager.read().await.is_spectator(peer_id)
+        {
+            warn!("Peer {peer_id:?} tried to use the thumbnail video mode but isn't a spectator");
+            return;
+        }
+
+        let changed = match mode {
+            PeerVideoMode::Full => self.thumbnail_peers.write().await.remove(&peer_id),
+            PeerVideoMode::Thumbnail => self.thumbnail_peers.write().await.insert(peer_id),
+        };
+        if !changed {
+            return;
+        }
+        debug!("Peer {peer_id:?} uses the video mode {mode:?}");
+
+        // The peer skipped the frames since the last keyframe
+        if mode == PeerVideoMode::Full {
+            if let Some(transport) = self.peer_transports.read().await.get(&peer_id) {
+                transport.readiness.wait_for_keyframe();
             }
-            InboundPacket::Touch {
-                pointer_id,
-                x,
-                y,
-                pressure_or_distance,
-                contact_area_major,
-                contact_area_minor,
-                rotation,
-                event_type,
-            } => {
-                if !can_use_keyboard_mouse {
-                    debug!("Ignoring touch from non-Player 1");
-                    return;
+            self.request_keyframe().await;
+        }
+
+        let mut ipc_sender = self.ipc_sender.clone();
+        ipc_sender
+            .send(StreamerIpcMessage::PeerWebSocket {
+                peer_id,
+                message: StreamServerMessage::VideoModeChanged { mode },
+            })
+            .await;
+    }
+
+    /// Tells the host that someone joined, left or got new rights, queued until the transport of the host is ready
+    async fn send_room_event(
+        &self,
+        kind: RoomEventKind,
+        name: Option<String>,
+        slot: Option<PlayerSlot>,
+    ) {
+        let queued = self
+            .peer_manager
+            .write()
+            .await
+            .queue_room_event(GeneralServerMessage::RoomEvent { kind, name, slot });
+        if !queued {
+            return;
+        }
+
+        self.flush_room_events().await;
+    }
+
+    /// Sends the queued room events if the transport of the host is ready
+    async fn flush_room_events(&self) {
+        let Some(host) = self.peer_manager.read().await.host() else {
+            return;
+        };
+
+        let ready = self
+            .peer_transports
+            .read()
+            .await
+            .get(&host)
+            .is_some_and(|transport| transport.readiness.is_ready());
+        if !ready {
+            return;
+        }
+
+        let events = self.peer_manager.write().await.take_room_events();
+        for message in events {
+            self.send_to_peer(host, OutboundPacket::General { message })
+                .await;
+        }
+    }
+
+    /// Remove transport for a peer (called when peer disconnects)
+    async fn remove_peer_transport(&self, peer_id: PeerId) {
+        let transport = {
+            let mut transports = self.peer_transports.write().await;
+            transports.remove(&peer_id)
+        };
+
+        if let Some(transport) = transport {
+            if let Err(err) = transport.sender.close().await {
+                warn!("Failed to close transport for peer {:?}: {err:?}", peer_id);
+            }
+        }
+    }
+    /// Creates, updates or removes the delay buffers so they match the stream delay of every peer
+    async fn sync_peer_delays(self: &Arc<Self>) {
+        let stream_delays = {
+            let peer_manager = self.peer_manager.read().await;
+            peer_manager.stream_delays().collect::<HashMap<_, _>>()
+        };
+
+        let mut peer_delays = self.peer_delays.write().await;
+
+        peer_delays.retain(|peer_id, peer_delay| {
+            let Some(delay) = stream_delays.get(peer_id) else {
+                peer_delay.release_task.abort();
+                return false;
+            };
+            if !delay.is_zero() {
+                return true;
+            }
+
+            // The live stream can only be decoded starting at a keyframe
+            debug!("Peer {:?} is no longer delayed", peer_id);
+            peer_delay.release_task.abort();
+            self.request_idr.store(true, Ordering::Relaxed);
+            false
+        });
+
+        for (peer_id, delay) in stream_delays {
+            if delay.is_zero() {
+                continue;
+            }
+
+            if let Some(peer_delay) = peer_delays.get(&peer_id) {
+                if peer_delay.delayed.delay() != delay {
+                    peer_delay.delayed.set_delay(delay);
                 }
-                stream
-                    .send_touch(
-                        pointer_id,
-                        x,
-                        y,
-                        pressure_or_distance,
-                        contact_area_major,
-                        contact_area_minor,
-                        rotation,
-                        event_type,
-                    )
-                    .err()
+                continue;
             }
-            InboundPacket::ControllerConnected {
-                id,
-                ty,
-                supported_buttons,
-                capabilities,
-            } => {
-                // Map the browser's gamepad ID to the actual slot based on player
-                let mapped_id = if let Some(peer_id) = current_peer {
-                    match peer_manager.map_gamepad_id(peer_id, id) {
-                        Some(mapped) => mapped,
-                        None => {
-                            debug!("Ignoring gamepad {} from peer {:?}", id, peer_id);
-                            return;
+
+            debug!("Delaying the stream of peer {:?} by {:?}", peer_id, delay);
+            let delayed = Arc::new(DelayedPeer::new(delay));
+            let release_task = spawn(Self::release_delayed(
+                Arc::downgrade(self),
+                peer_id,
+                delayed.clone(),
+            ));
+            peer_delays.insert(
+                peer_id,
+                PeerDelay {
+                    delayed,
+                    release_task,
+                },
+            );
+        }
+    }
+
+    /// Sends the video and audio of a delayed peer once the delay is over
+    async fn release_delayed(this: Weak<Self>, peer_id: PeerId, delayed: Arc<DelayedPeer>) {
+        loop {
+            let media = delayed.next_ready().await;
+
+            let Some(this) = this.upgrade() else {
+                return;
+            };
+            let transports = this.peer_transports.read().await;
+            let Some(transport) = transports
+                .get(&peer_id)
+                .filter(|transport| transport.readiness.is_ready())
+            else {
+                continue;
+            };
+
+            match media {
+                DelayedMedia::Video(unit) => {
+                    let buffers = unit.buffers();
+                    match transport
+                        .sender
+                        .send_video_unit(&unit.as_unit(&buffers), unit.frame_id())
+                        .await
+                    {
+                        Ok(_) => transport
+                            .frame_timings
+                            .lock()
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .sent(unit.frame_id(), Instant::now()),
+                        Err(err) => {
+                            warn!(
+                                "Failed to send delayed video decode unit to peer {:?}: {err}",
+                                peer_id
+                            );
                         }
                     }
-                } else {
-                    id // No peer context, use original ID
-                };
+                }
+                DelayedMedia::Audio(data) => {
+                    if let Err(err) = transport.sender.send_audio_sample(&data).await {
+                        warn!(
+                            "Failed to send delayed audio sample to peer {:?}: {err}",
+                            peer_id
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// Send the packet to all connected peer transports
+    async fn broadcast_packet(&self, packet: OutboundPacket) {
+        let transports = self.peer_transports.read().await;
+
+        if transports.is_empty() {
+            debug!("Dropping packet {packet:?} because no transports are connected!");
+            return;
+        }
+
+        for (peer_id, transport) in transports.iter() {
+            Self::send_packet(*peer_id, transport, packet.clone()).await;
+        }
+    }
+
+    /// Send the packet only to the transport of this peer
+    async fn send_to_peer(&self, peer_id: PeerId, packet: OutboundPacket) {
+        let transports = self.peer_transports.read().await;
+
+        let Some(transport) = transports.get(&peer_id) else {
+            debug!("Dropping packet {packet:?} because peer {peer_id:?} has no transport!");
+            return;
+        };
+
+        Self::send_packet(peer_id, transport, packet).await;
+    }
+
+    /// Send the packet of a gamepad of the host, e.g. rumble, to the peers using that gamepad
+    async fn send_to_gamepad_owners(&self, packet: OutboundPacket) {
+        let Some(gamepad_slot) = packet.controller_number() else {
+            warn!("Tried to send packet {packet:?} to the owners of a gamepad without a gamepad");
+            return;
+        };
+
+        let owners = self.peer_manager.read().await.gamepad_owners(gamepad_slot);
+
+        for (peer_id, browser_gamepad_id) in owners {
+            self.send_to_peer(peer_id, packet.clone().with_controller_number(browser_gamepad_id))
+                .await;
+        }
+    }
+
+    async fn send_packet(peer_id: PeerId, transport: &PeerTransport, packet: OutboundPacket) {
+        let channel = packet.channel();
+        let class = packet.delivery_class();
+
+        if let Err(err) = transport.sender.send(packet).await {
+            match class {
+                DeliveryClass::Reliable | DeliveryClass::Latest => warn!(
+                    "Failed to send outbound packet on channel {channel:?} to peer {peer_id:?}: {err:?}"
+                ),
+                DeliveryClass::BestEffort => debug!(
+                    "Failed to send outbound packet on channel {channel:?} to peer {peer_id:?}: {err:?}"
+                ),
+            }
+        }
+    }
 
-                let Some(gamepad) = ActiveGamepads::from_id(mapped_id) else {
-                    warn!("Failed to add gamepad because it is out of range: {mapped_id}");
+    /// The stats are only collected and sent for the peers which requested them
+    async fn set_stats_interval(&self, millis: Option<u32>, frame_sample_interval: Option<u16>) {
+        let Some(peer_id) = *self.current_peer_id.read().await else {
+            warn!("Failed to set the stats interval because the peer is unknown");
+            return;
+        };
+
+        let interval = millis.map(|millis| Duration::from_millis(millis.into()));
+        debug!(
+            "Peer {peer_id:?} requested stats every {interval:?}, reporting every {frame_sample_interval:?} rendered frames"
+        );
+
+        self.peer_manager.write().await.set_stats_interval(
+            peer_id,
+            interval,
+            frame_sample_interval,
+        );
+    }
+
+    /// Records the latency of a frame the current peer rendered for its stats
+    async fn on_frame_rendered(&self, frame_id: u32, client_render_ts: u32) {
+        let now = Instant::now();
+
+        let Some(peer_id) = *self.current_peer_id.read().await else {
+            warn!("Failed to record a rendered frame because the peer is unknown");
+            return;
+        };
+
+        if !self.peer_manager.read().await.reports_frames(peer_id) {
+            debug!("Ignoring rendered frame of peer {peer_id:?} which didn't enable the reports");
+            return;
+        }
+
+        let transports = self.peer_transports.read().await;
+        let Some(transport) = transports.get(&peer_id) else {
+            return;
+        };
+
+        if !transport
+            .frame_timings
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .rendered(frame_id, client_render_ts, now)
+        {
+            trace!("Peer {peer_id:?} rendered the unknown frame {frame_id}");
+        }
+    }
+
+    /// Handles a message of the general channel of the current peer
+    async fn on_general_message(&self, message: GeneralClientMessage) {
+        let Some(peer_id) = *self.current_peer_id.read().await else {
+            warn!("Failed to handle general message {message:?} because the peer is unknown");
+            return;
+        };
+
+        match message {
+            GeneralClientMessage::SetMouseMode { relative } => {
+                if !self
+                    .peer_manager
+                    .write()
+                    .await
+                    .set_relative_mouse(peer_id, relative)
+                {
+                    warn!("Failed to set the mouse mode of unknown peer {peer_id:?}");
                     return;
-                };
+                }
 
-                let mut active_gamepads = self.active_gamepads.write().await;
+                self.send_to_peer(
+                    peer_id,
+                    OutboundPacket::General {
+                        message: GeneralServerMessage::MouseModeChanged { relative },
+                    },
+                )
+                .await;
+            }
+            GeneralClientMessage::SetRoomEventsEnabled { enabled } => {
+                let mut peer_manager = self.peer_manager.write().await;
+                if peer_manager.host() != Some(peer_id) {
+                    warn!("Peer {peer_id:?} tried to change the room events but isn't the host");
+                    return;
+                }
 
-                active_gamepads.insert(gamepad);
+                peer_manager.set_room_events_enabled(enabled);
+            }
+        }
+    }
 
-                stream
-                    .send_controller_arrival(
-                        mapped_id,
-                        *active_gamepads,
-                        ty,
-                        supported_buttons,
-                        capabilities,
-                    )
-                    .err()
+    /// Applies the permissions and the gamepad mapping of the current peer.
+    /// Returns None if the packet must be dropped.
+    async fn map_peer_packet(&self, mut packet: InboundPacket) -> Option<InboundPacket> {
+        let Some(peer_id) = *self.current_peer_id.read().await else {
+            // Allow if no peer context (backwards compat)
+            return Some(packet);
+        };
+
+        let peer_manager = self.peer_manager.read().await;
+
+        match &mut packet {
+            InboundPacket::General { .. }
+            | InboundPacket::SetStatsInterval { .. }
+            | InboundPacket::FrameRendered { .. } => {}
+            InboundPacket::MouseMove { .. }
+            | InboundPacket::MousePosition { .. }
+            | InboundPacket::MouseButton { .. }
+            | InboundPacket::HighResScroll { .. }
+            | InboundPacket::Scroll { .. } => {
+                if !peer_manager.permissions(peer_id).allow_mouse {
+                    debug!("Ignoring mouse input from peer {peer_id:?}");
+                    return None;
+                }
             }
-            InboundPacket::ControllerDisconnected { id } => {
+            InboundPacket::Key { .. } | InboundPacket::Text { .. } => {
+                if !peer_manager.permissions(peer_id).allow_keyboard {
+                    debug!("Ignoring keyboard input from peer {peer_id:?}");
+                    return None;
+                }
+            }
+            InboundPacket::Touch { .. } => {
+                if !peer_manager.permissions(peer_id).allow_touch {
+                    debug!("Ignoring touch input from peer {peer_id:?}");
+                    return None;
+                }
+            }
+            InboundPacket::ControllerConnected { id, .. }
+            | InboundPacket::ControllerDisconnected { id }
+            | InboundPacket::ControllerState { id, .. } => {
                 // Map the browser's gamepad ID to the actual slot based on player
-                let mapped_id = if let Some(peer_id) = current_peer {
-                    match peer_manager.map_gamepad_id(peer_id, id) {
-                        Some(mapped) => mapped,
-                        None => {
-                            debug!("Ignoring gamepad disconnect {} from peer {:?}", id, peer_id);
-                            return;
-                        }
+                let Some(mapped_id) = peer_manager.map_gamepad_id(peer_id, *id) else {
+                    // Silently drop - this is frequent during gameplay
+                    trace!("Ignoring gamepad {id} from peer {peer_id:?}");
+
+                    // The browser can connect an additional gamepad before the web server assigned its local slot
+                    let browser_gamepad_id = *id;
+                    let pending = match packet {
+                        InboundPacket::ControllerConnected {
+                            ty,
+                            supported_buttons,
+                            capabilities,
+                            ..
+                        } => Some(Some(GamepadArrival {
+                            ty,
+                            supported_buttons,
+                            capabilities,
+                        })),
+                        InboundPacket::ControllerDisconnected { .. } => Some(None),
+                        _ => None,
+                    };
+                    if let Some(arrival) = pending
+                        && peer_manager.controller_mode() == ControllerMode::PerPlayer
+                    {
+                        drop(peer_manager);
+                        self.peer_manager.write().await.set_pending_gamepad(
+                            peer_id,
+                            browser_gamepad_id,
+                            arrival,
+                        );
                     }
-                } else {
-                    id
+                    return None;
                 };
 
-                let Some(gamepad) = ActiveGamepads::from_id(mapped_id) else {
-                    warn!("Failed to remove gamepad because it is out of range: {mapped_id}");
-                    return;
+                *id = mapped_id;
+            }
+        }
+
+        // Movement of the other mode can still be in flight while the browser switches the pointer lock
+        let relative_mouse = peer_manager.relative_mouse(peer_id);
+        match packet {
+            InboundPacket::MouseMove { .. } if relative_mouse == Some(false) => {
+                trace!("Ignoring mouse movement of peer {peer_id:?} in absolute mode");
+                return None;
+            }
+            InboundPacket::MousePosition { .. } if relative_mouse == Some(true) => {
+                trace!("Ignoring mouse position of peer {peer_id:?} in relative mode");
+                return None;
+            }
+            _ => {}
+        }
+
+        Some(packet)
+    }
+
+    /// Remembers the gamepad of the peer and handles all gamepads in the shared controller mode.
+    /// Returns true if the packet was handled.
+    async fn on_peer_gamepad_packet(
+        self: &Arc<Self>,
+        stream: &MoonlightStream,
+        peer_id: PeerId,
+        packet: &InboundPacket,
+    ) -> bool {
+        let mut peer_manager = self.peer_manager.write().await;
+
+        let err = match *packet {
+            InboundPacket::ControllerConnected {
+                id,
+                ty,
+                supported_buttons,
+                capabilities,
+            } => {
+                let arrival = GamepadArrival {
+                    ty,
+                    supported_buttons,
+                    capabilities,
                 };
+                if let Some(browser_gamepad_id) = peer_manager.browser_gamepad_id(peer_id, id) {
+                    peer_manager.set_peer_gamepad(peer_id, browser_gamepad_id, Some(arrival));
+                }
+
+                if peer_manager.controller_mode() == ControllerMode::PerPlayer {
+                    return false;
+                }
+                // The shared gamepad is only connected once
+                if !peer_manager
+                    .shared_gamepad_mut()
+                    .connect(peer_id, Instant::now())
+                {
+                    return true;
+                }
 
                 let mut active_gamepads = self.active_gamepads.write().await;
-                active_gamepads.remove(gamepad);
+                Self::connect_gamepad(stream, &mut active_gamepads, 0, arrival).err()
+            }
+            InboundPacket::ControllerDisconnected { id } => {
+                if let Some(browser_gamepad_id) = peer_manager.browser_gamepad_id(peer_id, id) {
+                    peer_manager.set_peer_gamepad(peer_id, browser_gamepad_id, None);
+                }
 
-                stream
-                    .send_multi_controller(
-                        mapped_id,
-                        *active_gamepads,
-                        ControllerButtons::empty(),
-                        0,
-                        0,
-                        0,
-                        0,
-                        0,
-                        0,
-                    )
+                if peer_manager.controller_mode() == ControllerMode::PerPlayer {
+                    return false;
+                }
+
+                self.disconnect_shared_gamepad(stream, &mut peer_manager, peer_id)
+                    .await
                     .err()
             }
             InboundPacket::ControllerState {
-                id,
                 buttons,
                 left_trigger,
                 right_trigger,
@@ -676,37 +1500,489 @@ impl StreamConnection {
                 left_stick_y,
                 right_stick_x,
                 right_stick_y,
+                ..
             } => {
-                // Map the browser's gamepad ID to the actual slot based on player
-                let mapped_id = if let Some(peer_id) = current_peer {
-                    match peer_manager.map_gamepad_id(peer_id, id) {
-                        Some(mapped) => mapped,
-                        None => {
-                            // Silently drop - this is frequent during gameplay
-                            return;
-                        }
-                    }
-                } else {
-                    id
-                };
+                if peer_manager.controller_mode() == ControllerMode::PerPlayer {
+                    return false;
+                }
 
-                let Some(gamepad) = ActiveGamepads::from_id(mapped_id) else {
-                    warn!("Failed to update gamepad state because it is out of range: {mapped_id}");
-                    return;
+                let state = GamepadState {
+                    buttons,
+                    left_trigger,
+                    right_trigger,
+                    left_stick_x,
+                    left_stick_y,
+                    right_stick_x,
+                    right_stick_y,
                 };
-
-                let active_gamepads = self.active_gamepads.read().await;
-                if !active_gamepads.contains(gamepad) {
-                    warn!(
-                        "Failed to send gamepad event for not registered gamepad, gamepad: {mapped_id}, currently active: {:?}",
-                        *active_gamepads
-                    );
-                    return;
+                if peer_manager
+                    .shared_gamepad_mut()
+                    .update(peer_id, state, Instant::now())
+                {
+                    spawn(Self::merge_shared_gamepad(Arc::downgrade(self)));
                 }
-
+                None
+            }
+            _ => return false,
+        };
+
+        if let Some(err) = err {
+            warn!("Failed to handle shared gamepad packet: {err:?}");
+        }
+        true
+    }
+
+    /// Remembers the keys and mouse buttons the peer holds on the host
+    async fn track_held_input(&self, peer_id: PeerId, packet: &InboundPacket) {
+        match packet {
+            InboundPacket::Key { action, key, .. } => {
+                self.peer_manager.write().await.set_key_held(
+                    peer_id,
+                    *key,
+                    matches!(action, KeyAction::Down),
+                );
+            }
+            InboundPacket::MouseButton { action, button } => {
+                self.peer_manager.write().await.set_mouse_button_held(
+                    peer_id,
+                    *button,
+                    matches!(action, MouseButtonAction::Press),
+                );
+            }
+            _ => {}
+        }
+    }
+
+    /// Releases the input the peer may not use anymore and tells it whether it can use keyboard and mouse.
+    /// Its own key up would be dropped, so the keys would stay pressed on the host.
+    async fn on_input_permission_changed(&self, peer_id: PeerId) {
+        let mut peer_manager = self.peer_manager.write().await;
+        let revoked = peer_manager.take_revoked_input(peer_id);
+        let permissions = peer_manager.permissions(peer_id);
+        drop(peer_manager);
+
+        self.release_held_input(peer_id, revoked).await;
+
+        self.send_to_peer(
+            peer_id,
+            OutboundPacket::General {
+                message: GeneralServerMessage::InputPermissionChanged {
+                    keyboard_mouse: permissions.allow_keyboard && permissions.allow_mouse,
+                },
+            },
+        )
+        .await;
+    }
+
+    async fn release_held_input(&self, peer_id: PeerId, held: HeldInput) {
+        if held.is_empty() {
+            return;
+        }
+        debug!("Releasing the input held by peer {peer_id:?}: {held:?}");
+
+        #[cfg(test)]
+        let fake_input = self.fake_host_input.get().map(|input| input.as_ref());
+        #[cfg(not(test))]
+        let fake_input = None;
+
+        let stream = self.stream.read().await;
+        let Some(input) = stream
+            .as_ref()
+            .map(|stream| stream as &dyn HostInput)
+            .or(fake_input)
+        else {
+            return;
+        };
+
+        let mut keyboard = self.keyboard.lock().await;
+        for key in held.keys {
+            // The translator knows which key was pressed on the host for the key of the client
+            let Some(KeyInput::Key(key)) =
+                keyboard.translate(KeyAction::Up, KeyModifiers::empty(), key, None)
+            else {
+                continue;
+            };
+
+            if let Err(err) = input.send_keyboard_event_non_standard(
+                key as i16,
+                KeyAction::Up,
+                KeyModifiers::empty(),
+                KeyFlags::empty(),
+            ) {
+                warn!("Failed to release key {key} of peer {peer_id:?}: {err:?}");
+            }
+        }
+        drop(keyboard);
+
+        for button in held.mouse_buttons {
+            if let Err(err) = input.send_mouse_button(MouseButtonAction::Release, button) {
+                warn!("Failed to release mouse button {button:?} of peer {peer_id:?}: {err:?}");
+            }
+        }
+    }
+
+    /// Sends the merged state of the shared gamepad once the states of all peers were collected for a moment
+    async fn merge_shared_gamepad(this: Weak<Self>) {
+        sleep(SHARED_GAMEPAD_MERGE_INTERVAL).await;
+
+        let Some(this) = this.upgrade() else {
+            return;
+        };
+
+        let stream = this.stream.read().await;
+        let Some(stream) = stream.as_ref() else {
+            return;
+        };
+        let mut peer_manager = this.peer_manager.write().await;
+        let Some(state) = peer_manager.shared_gamepad_mut().merge() else {
+            return;
+        };
+
+        let active_gamepads = this.active_gamepads.read().await;
+        if let Err(err) = Self::send_gamepad_state(stream, *active_gamepads, 0, state) {
+            warn!("Failed to send the state of the shared gamepad: {err:?}");
+        }
+    }
+
+    /// Removes the gamepad of a peer from the shared gamepad, which is disconnected together with the last one
+    async fn disconnect_shared_gamepad(
+        &self,
+        stream: &MoonlightStream,
+        peer_manager: &mut PeerManager,
+        peer_id: PeerId,
+    ) -> Result<(), MoonlightError> {
+        let shared_gamepad = peer_manager.shared_gamepad_mut();
+        let mut active_gamepads = self.active_gamepads.write().await;
+
+        if shared_gamepad.disconnect(peer_id) {
+            Self::disconnect_gamepad(stream, &mut active_gamepads, 0)
+        } else if let Some(state) = shared_gamepad.merge() {
+            Self::send_gamepad_state(stream, *active_gamepads, 0, state)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Disconnects all gamepads of the host and connects the gamepads of the peers again using the new mapping
+    async fn set_controller_mode(&self, mode: ControllerMode) {
+        let stream = self.stream.read().await;
+        let mut peer_manager = self.peer_manager.write().await;
+        if peer_manager.controller_mode() == mode {
+            return;
+        }
+
+        let mut active_gamepads = self.active_gamepads.write().await;
+        if let Some(stream) = stream.as_ref() {
+            for id in 0..u16::BITS as u8 {
+                if ActiveGamepads::from_id(id)
+                    .is_some_and(|gamepad| active_gamepads.contains(gamepad))
+                    && let Err(err) = Self::disconnect_gamepad(stream, &mut active_gamepads, id)
+                {
+                    warn!("Failed to disconnect gamepad {id}: {err:?}");
+                }
+            }
+        }
+        *active_gamepads = ActiveGamepads::empty();
+
+        peer_manager.set_controller_mode(mode);
+
+        let Some(stream) = stream.as_ref() else {
+            return;
+        };
+        let gamepads = peer_manager.peer_gamepads().collect::<Vec<_>>();
+        for (peer_id, browser_gamepad_id, arrival) in gamepads {
+            let Some(id) = peer_manager.map_gamepad_id(peer_id, browser_gamepad_id) else {
+                continue;
+            };
+            if mode == ControllerMode::Shared
+                && !peer_manager
+                    .shared_gamepad_mut()
+                    .connect(peer_id, Instant::now())
+            {
+                continue;
+            }
+
+            if let Err(err) = Self::connect_gamepad(stream, &mut active_gamepads, id, arrival) {
+                warn!("Failed to connect gamepad {id} of peer {peer_id:?}: {err:?}");
+            }
+        }
+    }
+
+    /// Disconnects the gamepads of the host which were used by the released local slots of a peer
+    /// and connects the gamepads of the peer which were waiting for a slot
+    async fn set_local_gamepad_slots(&self, peer_id: PeerId, slots: Vec<PlayerSlot>) {
+        let stream = self.stream.read().await;
+        let change = self
+            .peer_manager
+            .write()
+            .await
+            .set_local_slots(peer_id, slots);
+
+        let Some(stream) = stream.as_ref() else {
+            return;
+        };
+        let mut active_gamepads = self.active_gamepads.write().await;
+        for id in change.removed {
+            if ActiveGamepads::from_id(id).is_some_and(|gamepad| active_gamepads.contains(gamepad))
+                && let Err(err) = Self::disconnect_gamepad(stream, &mut active_gamepads, id)
+            {
+                warn!("Failed to disconnect gamepad {id} of peer {peer_id:?}: {err:?}");
+            }
+        }
+        for (id, arrival) in change.added {
+            if let Err(err) = Self::connect_gamepad(stream, &mut active_gamepads, id, arrival) {
+                warn!("Failed to connect gamepad {id} of peer {peer_id:?}: {err:?}");
+            }
+        }
+    }
+
+    fn connect_gamepad(
+        stream: &MoonlightStream,
+        active_gamepads: &mut ActiveGamepads,
+        id: u8,
+        arrival: GamepadArrival,
+    ) -> Result<(), MoonlightError> {
+        if let Some(gamepad) = ActiveGamepads::from_id(id) {
+            active_gamepads.insert(gamepad);
+        }
+
+        stream.send_controller_arrival(
+            id,
+            *active_gamepads,
+            arrival.ty,
+            arrival.supported_buttons,
+            arrival.capabilities,
+        )
+    }
+    fn disconnect_gamepad(
+        stream: &MoonlightStream,
+        active_gamepads: &mut ActiveGamepads,
+        id: u8,
+    ) -> Result<(), MoonlightError> {
+        if let Some(gamepad) = ActiveGamepads::from_id(id) {
+            active_gamepads.remove(gamepad);
+        }
+
+        Self::send_gamepad_state(stream, *active_gamepads, id, GamepadState::NEUTRAL)
+    }
+    fn send_gamepad_state(
+        stream: &MoonlightStream,
+        active_gamepads: ActiveGamepads,
+        id: u8,
+        state: GamepadState,
+    ) -> Result<(), MoonlightError> {
+        stream.send_multi_controller(
+            id,
+            active_gamepads,
+            state.buttons,
+            state.left_trigger,
+            state.right_trigger,
+            state.left_stick_x,
+            state.left_stick_y,
+            state.right_stick_x,
+            state.right_stick_y,
+        )
+    }
+
+    async fn on_packet(self: &Arc<Self>, packet: InboundPacket) {
+        let Some(packet) = self.map_peer_packet(packet).await else {
+            return;
+        };
+
+        // Stats and general messages can be sent before the stream started
+        match packet {
+            InboundPacket::SetStatsInterval {
+                millis,
+                frame_sample_interval,
+            } => {
+                self.set_stats_interval(millis, frame_sample_interval).await;
+                return;
+            }
+            InboundPacket::FrameRendered {
+                frame_id,
+                client_render_ts,
+            } => {
+                self.on_frame_rendered(frame_id, client_render_ts).await;
+                return;
+            }
+            InboundPacket::General { message } => {
+                self.on_general_message(message).await;
+                return;
+            }
+            _ => {}
+        }
+
+        if !input_allowed(*self.allowed_inputs.read().await, &packet) {
+            trace!("Ignoring input {packet:?} which is disabled for this stream");
+            return;
+        }
+
+        let stream = self.stream.read().await;
+        let Some(stream) = stream.as_ref() else {
+            warn!("Failed to send packet {packet:?} because of missing stream");
+            return;
+        };
+
+        if let Some(peer_id) = *self.current_peer_id.read().await {
+            if self.on_peer_gamepad_packet(stream, peer_id, &packet).await {
+                return;
+            }
+            self.track_held_input(peer_id, &packet).await;
+        }
+
+        let err = match packet {
+            InboundPacket::MousePosition {
+                x,
+                y,
+                reference_width,
+                reference_height,
+            } => stream
+                .send_mouse_position(x, y, reference_width, reference_height)
+                .err(),
+            InboundPacket::MouseButton { action, button } => {
+                stream.send_mouse_button(action, button).err()
+            }
+            InboundPacket::MouseMove { delta_x, delta_y } => {
+                stream.send_mouse_move(delta_x, delta_y).err()
+            }
+            InboundPacket::HighResScroll { delta_x, delta_y } => {
+                let mut err = None;
+                if delta_y != 0 {
+                    err = stream.send_high_res_scroll(delta_y).err()
+                }
+                if delta_x != 0 {
+                    err = stream.send_high_res_horizontal_scroll(delta_x).err()
+                }
+                err
+            }
+            InboundPacket::Scroll { delta_x, delta_y } => {
+                let mut err = None;
+                if delta_y != 0 {
+                    err = stream.send_scroll(delta_y).err();
+                }
+                if delta_x != 0 {
+                    err = stream.send_horizontal_scroll(delta_x).err();
+                }
+                err
+            }
+            InboundPacket::Key {
+                action,
+                modifiers,
+                key,
+                flags,
+                text,
+            } => {
+                let input =
+                    self.keyboard
+                        .lock()
+                        .await
+                        .translate(action, modifiers, key, text.as_deref());
+
+                match input {
+                    Some(KeyInput::Key(key)) => stream
+                        .send_keyboard_event_non_standard(key as i16, action, modifiers, flags)
+                        .err(),
+                    Some(KeyInput::Text(text)) => stream.send_text(&text).err(),
+                    None => None,
+                }
+            }
+            InboundPacket::Text { text } => stream.send_text(&text).err(),
+            InboundPacket::Touch {
+                pointer_id,
+                x,
+                y,
+                pressure_or_distance,
+                contact_area_major,
+                contact_area_minor,
+                rotation,
+                event_type,
+            } => stream
+                .send_touch(
+                    pointer_id,
+                    x,
+                    y,
+                    pressure_or_distance,
+                    contact_area_major,
+                    contact_area_minor,
+                    rotation,
+                    event_type,
+                )
+                .err(),
+            InboundPacket::ControllerConnected {
+                id,
+                ty,
+                supported_buttons,
+                capabilities,
+            } => {
+                let Some(gamepad) = ActiveGamepads::from_id(id) else {
+                    warn!("Failed to add gamepad because it is out of range: {id}");
+                    return;
+                };
+
+                let mut active_gamepads = self.active_gamepads.write().await;
+
+                active_gamepads.insert(gamepad);
+
+                stream
+                    .send_controller_arrival(
+                        id,
+                        *active_gamepads,
+                        ty,
+                        supported_buttons,
+                        capabilities,
+                    )
+                    .err()
+            }
+            InboundPacket::ControllerDisconnected { id } => {
+                let Some(gamepad) = ActiveGamepads::from_id(id) else {
+                    warn!("Failed to remove gamepad because it is out of range: {id}");
+                    return;
+                };
+
+                let mut active_gamepads = self.active_gamepads.write().await;
+                active_gamepads.remove(gamepad);
+
+                stream
+                    .send_multi_controller(
+                        id,
+                        *active_gamepads,
+                        ControllerButtons::empty(),
+                        0,
+                        0,
+                        0,
+                        0,
+                        0,
+                        0,
+                    )
+                    .err()
+            }
+            InboundPacket::ControllerState {
+                id,
+                buttons,
+                left_trigger,
+                right_trigger,
+                left_stick_x,
+                left_stick_y,
+                right_stick_x,
+                right_stick_y,
+            } => {
+                let Some(gamepad) = ActiveGamepads::from_id(id) else {
+                    warn!("Failed to update gamepad state because it is out of range: {id}");
+                    return;
+                };
+
+                let active_gamepads = self.active_gamepads.read().await;
+                if !active_gamepads.contains(gamepad) {
+                    warn!(
+                        "Failed to send gamepad event for not registered gamepad, gamepad: {id}, currently active: {:?}",
+                        *active_gamepads
+                    );
+                    return;
+                }
+
                 stream
                     .send_multi_controller(
-                        mapped_id,
+                        id,
                         *active_gamepads,
                         buttons,
                         left_trigger,
@@ -718,6 +1994,9 @@ impl StreamConnection {
                     )
                     .err()
             }
+            InboundPacket::General { .. }
+            | InboundPacket::SetStatsInterval { .. }
+            | InboundPacket::FrameRendered { .. } => return,
         };
 
         if let Some(err) = err {
@@ -735,6 +2014,7 @@ impl StreamConnection {
                 role,
                 video_frame_queue_size,
                 audio_sample_queue_size,
+                name,
             } => {
                 info!(
                     "Peer {:?} connected as {:?} (slot: {:?})",
@@ -747,7 +2027,16 @@ impl StreamConnection {
                     role,
                     video_frame_queue_size,
                     audio_sample_queue_size,
+                    name.clone(),
                 );
+                drop(peer_manager);
+
+                self.sync_peer_delays().await;
+
+                if role != RoomRole::Host {
+                    self.send_room_event(RoomEventKind::Joined, name, player_slot)
+                        .await;
+                }
                 return;
             }
             ServerIpcMessage::PeerRoleChanged {
@@ -761,21 +2050,61 @@ impl StreamConnection {
                 );
                 let mut peer_manager = self.peer_manager.write().await;
                 peer_manager.update_peer_role(peer_id, new_role, player_slot);
+                let name = peer_manager
+                    .get_peer(peer_id)
+                    .and_then(|info| info.name.clone());
+                drop(peer_manager);
+
+                self.sync_peer_delays().await;
+
+                // Players always get the full stream
+                if !new_role.is_spectator() {
+                    self.set_peer_video_mode(peer_id, PeerVideoMode::Full).await;
+                }
+
+                if new_role == RoomRole::Player {
+                    self.send_room_event(RoomEventKind::Promoted, name, player_slot)
+                        .await;
+                }
                 return;
             }
             ServerIpcMessage::PeerDisconnected { peer_id } => {
                 info!("Peer {:?} disconnected", peer_id);
+                let stream = self.stream.read().await;
                 let mut peer_manager = self.peer_manager.write().await;
-                peer_manager.remove_peer(peer_id);
-                // Also remove their transport
-                self.remove_peer_transport(peer_id).await;
-                return;
-            }
-            ServerIpcMessage::PeerWebSocket { peer_id, message } => {
-                // Set current peer context for input handling
+                if peer_manager.controller_mode() == ControllerMode::Shared
+                    && let Some(stream) = stream.as_ref()
+                    && let Err(err) = self
+                        .disconnect_shared_gamepad(stream, &mut peer_manager, peer_id)
+                        .await
                 {
-                    let mut current = self.current_peer_id.write().await;
-                    *current = Some(peer_id);
+                    warn!(
+                        "Failed to disconnect the shared gamepad of peer {:?}: {err:?}",
+                        peer_id
+                    );
+                }
+                let info = peer_manager.remove_peer(peer_id);
+                drop(peer_manager);
+                drop(stream);
+
+                // Also remove their transport, delay buffer and video mode
+                self.remove_peer_transport(peer_id).await;
+                self.sync_peer_delays().await;
+                self.thumbnail_peers.write().await.remove(&peer_id);
+
+                if let Some(info) = info
+                    && info.role != RoomRole::Host
+                {
+                    self.send_room_event(RoomEventKind::Left, info.name, info.player_slot)
+                        .await;
+                }
+                return;
+            }
+            ServerIpcMessage::PeerWebSocket { peer_id, message } => {
+                // Set current peer context for input handling
+                {
+                    let mut current = self.current_peer_id.write().await;
+                    *current = Some(peer_id);
                 }
                 // Return message with peer context
                 (ServerIpcMessage::WebSocket(message), Some(peer_id))
@@ -792,7 +2121,92 @@ impl StreamConnection {
             ServerIpcMessage::SetGuestsKeyboardMouseEnabled { enabled } => {
                 info!("Setting guests keyboard/mouse enabled: {}", enabled);
                 let mut peer_manager = self.peer_manager.write().await;
-                peer_manager.set_guests_keyboard_mouse_enabled(enabled);
+                let guests = peer_manager.set_guests_keyboard_mouse_enabled(enabled);
+                drop(peer_manager);
+
+                for peer_id in guests {
+                    self.on_input_permission_changed(peer_id).await;
+                }
+                return;
+            }
+            ServerIpcMessage::SetPeerPermissions {
+                peer_id,
+                permissions,
+            } => {
+                info!(
+                    "Setting permissions of peer {:?}: {:?}",
+                    peer_id, permissions
+                );
+                let mut peer_manager = self.peer_manager.write().await;
+                if !peer_manager.set_peer_permissions(peer_id, permissions) {
+                    warn!("Cannot change the permissions of peer {:?}", peer_id);
+                    return;
+                }
+                let info = peer_manager.get_peer(peer_id).cloned();
+                drop(peer_manager);
+
+                self.on_input_permission_changed(peer_id).await;
+
+                if let Some(info) = info {
+                    self.send_room_event(
+                        RoomEventKind::PermissionsChanged,
+                        info.name,
+                        info.player_slot,
+                    )
+                    .await;
+                }
+                return;
+            }
+            ServerIpcMessage::SetSpectatorDelay { delay } => {
+                info!("Setting spectator delay: {:?}", delay);
+                let mut peer_manager = self.peer_manager.write().await;
+                peer_manager.set_spectator_delay(delay);
+                drop(peer_manager);
+
+                self.sync_peer_delays().await;
+                return;
+            }
+            ServerIpcMessage::SetControllerMode { mode } => {
+                info!("Setting controller mode: {:?}", mode);
+                self.set_controller_mode(mode).await;
+                return;
+            }
+            ServerIpcMessage::SetLocalGamepadSlots { peer_id, slots } => {
+                info!(
+                    "Setting local gamepad slots of peer {:?}: {:?}",
+                    peer_id, slots
+                );
+                self.set_local_gamepad_slots(peer_id, slots).await;
+                return;
+            }
+            ServerIpcMessage::StopStream => {
+                info!("[Stream]: the host requested to stop the stream");
+                self.stop_stream(0).await;
+                return;
+            }
+            ServerIpcMessage::SetStreamPaused { paused } => {
+                info!("[Stream]: setting the stream paused: {paused}");
+                self.set_paused(paused).await;
+                return;
+            }
+            ServerIpcMessage::Reattached => {
+                info!(
+                    "[Stream]: the web server reconnected, removing the peers of the old connection"
+                );
+                let peer_ids = {
+                    let mut peer_manager = self.peer_manager.write().await;
+                    let peer_ids = peer_manager.peer_ids().collect::<Vec<_>>();
+                    for peer_id in &peer_ids {
+                        peer_manager.remove_peer(*peer_id);
+                    }
+                    peer_ids
+                };
+
+                for peer_id in peer_ids {
+                    self.remove_peer_transport(peer_id).await;
+                }
+                self.sync_peer_delays().await;
+                self.thumbnail_peers.write().await.clear();
                 return;
             }
             other => (other, None),
@@ -808,15 +2222,24 @@ impl StreamConnection {
                 TransportType::WebRTC => {
                     info!("Peer {:?} trying WebRTC transport", peer_id);
 
-                    // Get peer info for queue sizes
-                    let (video_queue, audio_queue) = {
+                    // Get peer info for queue sizes and the candidate policy
+                    let (video_queue, audio_queue, role) = {
                         let peer_manager = self.peer_manager.read().await;
-                        peer_manager.get_peer_queue_sizes(peer_id)
-                            .unwrap_or((self.video_frame_queue_size, self.audio_sample_queue_size))
+                        let (video_queue, audio_queue) = peer_manager
+                            .get_peer_queue_sizes(peer_id)
+                            .unwrap_or((self.video_frame_queue_size, self.audio_sample_queue_size));
+                        // Unknown peers are treated like guests
+                        let role = peer_manager
+                            .get_peer(peer_id)
+                            .map(|info| info.role)
+                            .unwrap_or(RoomRole::Spectator);
+
+                        (video_queue, audio_queue, role)
                     };
 
                     let (sender, events) = match webrtc::new(
                         &self.config.webrtc,
+                        role,
                         video_queue,
                         audio_queue,
                     )
@@ -846,6 +2269,14 @@ impl StreamConnection {
             return; // SetTransport handled, don't forward to transport
         }
 
+        // The video mode belongs to the peer, not to its transport
+        if let ServerIpcMessage::WebSocket(StreamClientMessage::SetVideoMode { mode }) = &message
+            && let Some(peer_id) = current_peer_id
+        {
+            self.set_peer_video_mode(peer_id, *mode).await;
+            return;
+        }
+
         // Forward message to the appropriate peer's transport
         if let Some(peer_id) = current_peer_id {
             let transports = self.peer_transports.read().await;
@@ -869,6 +2300,9 @@ impl StreamConnection {
 
     // Start Moonlight Stream
     async fn start_stream(self: &Arc<Self>, settings: StreamSettings) -> Result<(), anyhow::Error> {
+        // A start which is still connecting is superseded by this one
+        let epoch = self.stream_epoch.fetch_add(1, Ordering::AcqRel) + 1;
+
         // We might already be streaming -> remove and wait for connection close firstly
         {
             let mut stream = self.stream.write().await;
@@ -880,6 +2314,13 @@ impl StreamConnection {
         }
         info!("Starting Moonlight stream with settings: {settings}");
 
+        *self.keyboard.lock().await = KeyboardTranslator::new(
+            settings.keyboard_mode,
+            layout_mapping(settings.keyboard_layout, &settings.custom_keyboard_layout),
+            settings.key_overrides.clone(),
+        );
+        *self.allowed_inputs.write().await = settings.allowed_inputs;
+
         // Send stage
         let mut ipc_sender = self.ipc_sender.clone();
         ipc_sender
@@ -893,19 +2334,69 @@ impl StreamConnection {
 
         let mut host = self.info.host.lock().await;
 
+        // Fail fast instead of letting moonlight fail in a stage if e.g. the host GPU can't encode AV1
+        let video_formats = match host.server_codec_mode_support_raw().await {
+            Ok(server_codec_mode_support) => {
+                let video_formats = negotiate_video_formats(
+                    settings.video_supported_formats,
+                    server_codec_mode_support,
+                );
+
+                if video_formats.is_empty() {
+                    ipc_sender
+                        .send(StreamerIpcMessage::WebSocket(
+                            StreamServerMessage::NoCommonVideoCodec {
+                                client_video_formats: settings.video_supported_formats.bits(),
+                                host_video_formats: host_video_formats(server_codec_mode_support)
+                                    .bits(),
+                            },
+                        ))
+                        .await;
+
+                    anyhow::bail!(
+                        "the host supports none of the video codecs of the client: {:?}",
+                        video_codecs(settings.video_supported_formats)
+                    );
+                }
+
+                ipc_sender
+                    .send(StreamerIpcMessage::WebSocket(
+                        StreamServerMessage::DebugLog {
+                            message: format!(
+                                "Video codecs supported by the client and the host: {:?}",
+                                video_codecs(video_formats)
+                            ),
+                            ty: None,
+                        },
+                    ))
+                    .await;
+
+                video_formats
+            }
+            Err(err) => {
+                warn!(
+                    "[Stream]: failed to get the video codecs of the host, using the ones of the client: {err:?}"
+                );
+                settings.video_supported_formats
+            }
+        };
+
         let video_decoder = StreamVideoDecoder {
             stream: Arc::downgrade(self),
-            supported_formats: settings.video_supported_formats,
+            supported_formats: video_formats,
             stats: Default::default(),
+            record_path: self.config.record_path.clone().map(PathBuf::from),
+            recorder: None,
+            last_keyframe: None,
+            next_frame_id: 0,
         };
 
         let audio_decoder = StreamAudioDecoder {
             stream: Arc::downgrade(self),
         };
 
-        let connection_listener = StreamConnectionListener {
-            stream: Arc::downgrade(self),
-        };
+        let connection_listener = StreamConnectionListener::new(self);
+        let stage_timings = connection_listener.stage_timings();
 
         let stream = match host
             .start_stream(
@@ -938,293 +2429,1982 @@ impl StreamConnection {
             Err(err) => {
                 warn!("[Stream]: failed to start moonlight stream: {err:?}");
 
-                #[allow(clippy::single_match)]
-                match err {
+                // The stage watchdog already told the clients why the start was aborted
+                if self.is_terminating.load(Ordering::Acquire) {
+                    return Err(err.into());
+                }
+
+                // The cached host info is from before the start, the running game might have changed since
+                host.clear_cache();
+                let current_game = host.current_game().await.ok();
+                match &err {
                     HostError::Moonlight(MoonlightError::ConnectionAlreadyExists) => {
                         ipc_sender
                             .send(StreamerIpcMessage::WebSocket(
-                                StreamServerMessage::DebugLog { message: "Failed to start stream because this streamer is already streaming".to_string(), ty: None },
+                                StreamServerMessage::HostBusy { current_game },
                             ))
                             .await;
                     }
+                    err if is_app_running_error(err) => {
+                        if let Some(current_game) =
+                            current_game.filter(|game| *game != 0 && *game != self.info.app_id)
+                        {
+                            ipc_sender
+                                .send(StreamerIpcMessage::WebSocket(
+                                    StreamServerMessage::AppAlreadyRunning { current_game },
+                                ))
+                                .await;
+                        }
+                    }
                     _ => {}
                 }
 
-                return Err(err.into());
+                return Err(err.into());
+            }
+        };
+
+        let host_features = stream.host_features().unwrap_or_else(|err| {
+            warn!("[Stream]: failed to get host features: {err:?}");
+            HostFeatures::empty()
+        });
+
+        let capabilities = StreamCapabilities {
+            touch: host_features.contains(HostFeatures::PEN_TOUCH_EVENTS),
+        };
+
+        let (video_setup, audio_setup) = {
+            let setup = self.stream_setup.lock().await;
+
+            let video = setup.video.unwrap_or_else(|| {
+                warn!("failed to query video setup information. Giving the browser guessed information");
+                VideoSetup { format: VideoFormat::H264, width: settings.width, height: settings.height, redraw_rate: settings.fps, flags: 0 }
+            });
+
+            let audio = setup.audio.clone().unwrap_or(OpusMultistreamConfig::STEREO);
+
+            (video, audio)
+        };
+
+        let stage_timings = stage_timings
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .timings();
+
+        info!(
+            "Stream uses these settings: {:?} with {}x{}x{}",
+            video_setup.format, video_setup.width, video_setup.height, video_setup.redraw_rate
+        );
+
+        // Checked while holding the stream so a newer start either sees and stops this stream or
+        // this start sees that it was superseded
+        let mut stream_guard = self.stream.write().await;
+        if self.stream_epoch.load(Ordering::Acquire) != epoch {
+            drop(stream_guard);
+
+            info!("[Stream]: stream start was superseded by a newer one, stopping it");
+            spawn_blocking(move || {
+                stream.stop();
+            });
+
+            return Ok(());
+        }
+
+        spawn(async move {
+            ipc_sender
+                .send(StreamerIpcMessage::WebSocket(
+                    StreamServerMessage::DebugLog {
+                        message: format!("Negotiated video codec: {:?}", video_setup.format),
+                        ty: None,
+                    },
+                ))
+                .await;
+
+            ipc_sender
+                .send(StreamerIpcMessage::WebSocket(
+                    StreamServerMessage::ConnectionComplete {
+                        epoch,
+                        capabilities,
+                        available_stats: vec![
+                            StreamerStatsType::Rtt,
+                            StreamerStatsType::Video,
+                            StreamerStatsType::FrameLatency,
+                        ],
+                        stage_timings,
+                        format: video_setup.format as u32,
+                        width: video_setup.width,
+                        height: video_setup.height,
+                        fps: video_setup.redraw_rate,
+                        audio_sample_rate: audio_setup.sample_rate,
+                        audio_channel_count: audio_setup.channel_count,
+                        audio_streams: audio_setup.streams,
+                        audio_coupled_streams: audio_setup.coupled_streams,
+                        audio_samples_per_frame: audio_setup.samples_per_frame,
+                        audio_mapping: audio_setup.mapping,
+                    },
+                ))
+                .await;
+        });
+
+        stream_guard.replace(stream);
+        drop(stream_guard);
+
+        *self.quality.lock().unwrap_or_else(PoisonError::into_inner) = QualityMonitor::default();
+        spawn(Self::report_quality(Arc::downgrade(self), epoch));
+
+        Ok(())
+    }
+
+    /// Measures the quality of the connection until the stream with the epoch stops
+    async fn report_quality(this: Weak<Self>, epoch: u32) {
+        loop {
+            sleep(QUALITY_MEASURE_INTERVAL).await;
+
+            let Some(this) = this.upgrade() else {
+                return;
+            };
+            if this.is_terminating.load(Ordering::Acquire)
+                || this.stream_epoch.load(Ordering::Acquire) != epoch
+            {
+                return;
+            }
+
+            let rtt = {
+                let stream = this.stream.read().await;
+                let Some(stream) = stream.as_ref() else {
+                    return;
+                };
+                stream.estimated_rtt_info().ok().map(|info| info.rtt)
+            };
+
+            let report = this
+                .quality
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .measure(Instant::now(), rtt);
+            if let Some(message) = report {
+                this.broadcast_packet(OutboundPacket::General { message })
+                    .await;
+            }
+        }
+    }
+
+    /// Stops or continues sending video and audio on request of the host, the Moonlight stream keeps running
+    async fn set_paused(&self, paused: bool) {
+        if self.paused.swap(paused, Ordering::Relaxed) == paused {
+            return;
+        }
+
+        // The frames after the pause refer to the ones the peers didn't get
+        if !paused {
+            for transport in self.peer_transports.read().await.values() {
+                transport.readiness.wait_for_keyframe();
+            }
+            self.request_keyframe().await;
+        }
+
+        let mut ipc_sender = self.ipc_sender.clone();
+        ipc_sender
+            .send(StreamerIpcMessage::WebSocket(
+                StreamServerMessage::StreamPaused { paused },
+            ))
+            .await;
+    }
+
+    /// Stops only sending keyframes after the memory recovered
+    async fn send_all_frames(&self) {
+        self.keyframe_only.store(false, Ordering::Release);
+
+        // Like after a pause the next frames refer to ones the peers didn't get
+        for transport in self.peer_transports.read().await.values() {
+            transport.readiness.wait_for_keyframe();
+        }
+        self.request_keyframe().await;
+    }
+
+    /// Reports the used resources to the web server and enforces the memory limit until the streamer stops
+    async fn monitor_resources(this: Weak<Self>) {
+        let mut memory_guard = this.upgrade().and_then(|this| {
+            this.config
+                .memory_limit
+                .map(|limit| MemoryGuard::new(limit, this.config.memory_grace_period))
+        });
+        let mut last_report: Option<Instant> = None;
+
+        loop {
+            sleep(RESOURCE_SAMPLE_INTERVAL).await;
+
+            let Some(this) = this.upgrade() else {
+                return;
+            };
+            if this.is_terminating.load(Ordering::Acquire) {
+                return;
+            }
+
+            let now = Instant::now();
+            let rss_bytes = rss_bytes();
+
+            let action = match (&mut memory_guard, rss_bytes) {
+                (Some(memory_guard), Some(rss_bytes)) => memory_guard.check(rss_bytes, now),
+                _ => MemoryAction::None,
+            };
+            match action {
+                MemoryAction::None => {}
+                MemoryAction::Trim => {
+                    warn!(
+                        "[Stream]: the streamer uses {} bytes which is above the memory limit, only sending keyframes",
+                        rss_bytes.unwrap_or_default()
+                    );
+
+                    this.keyframe_only.store(true, Ordering::Release);
+                    this.request_idr.store(true, Ordering::Release);
+
+                    let transports = this.peer_transports.read().await;
+                    for transport in transports.values() {
+                        transport.sender.trim_queues().await;
+                    }
+                }
+                MemoryAction::Recovered => {
+                    info!("[Stream]: the memory is below the limit again, sending all frames");
+
+                    this.send_all_frames().await;
+                }
+                MemoryAction::Terminate => {
+                    error!(
+                        "[Stream]: the memory stayed above the limit for {:?}, stopping the stream",
+                        this.config.memory_grace_period
+                    );
+
+                    this.stop_stream(MEMORY_LIMIT).await;
+                    return;
+                }
+            }
+
+            if last_report.is_none_or(|last| now - last >= RESOURCE_REPORT_INTERVAL) {
+                last_report = Some(now);
+
+                let delayed_bytes = {
+                    let peer_delays = this.peer_delays.read().await;
+                    peer_delays
+                        .iter()
+                        .map(|(peer_id, peer_delay)| (*peer_id, peer_delay.delayed.size()))
+                        .collect::<HashMap<_, _>>()
+                };
+
+                let mut queues = Vec::new();
+                {
+                    let transports = this.peer_transports.read().await;
+                    for (peer_id, transport) in transports.iter() {
+                        let (video, audio) = transport.sender.queue_lengths().await;
+                        let delayed_bytes = delayed_bytes.get(peer_id).copied().unwrap_or_default();
+                        queues.push((*peer_id, video, audio, delayed_bytes));
+                    }
+                }
+
+                let mut ipc_sender = this.ipc_sender.clone();
+                ipc_sender
+                    .send(StreamerIpcMessage::ResourceReport { rss_bytes, queues })
+                    .await;
+            }
+        }
+    }
+
+    /// Stops moonlight before the clients receive `ConnectionTerminated` with the error code,
+    /// it's 0 when the host requested the stop
+    async fn stop_stream(&self, error_code: i32) {
+        if self.is_terminating.load(Ordering::Acquire) {
+            debug!("[Stream]: stream is already terminating, won't stop twice");
+            return;
+        }
+
+        // Moonlight must be stopped before the clients are told that the stream ended cleanly
+        let stream = self.stream.write().await.take();
+        if let Some(stream) = stream
+            && let Err(err) = spawn_blocking(move || stream.stop()).await
+        {
+            warn!("[Stream]: failed to stop the moonlight stream: {err}");
+        }
+
+        let mut ipc_sender = self.ipc_sender.clone();
+        ipc_sender
+            .send(StreamerIpcMessage::WebSocket(
+                connection_terminated_message(error_code),
+            ))
+            .await;
+
+        self.stop().await;
+    }
+
+    async fn stop(&self) {
+        if self
+            .is_terminating
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            debug!("[Stream]: stream is already terminating, won't stop twice");
+            return;
+        }
+
+        debug!("[Stream]: Stopping...");
+
+        {
+            let mut stream = self.stream.write().await;
+            if let Some(stream) = stream.take() {
+                spawn_blocking(move || {
+                    stream.stop();
+                });
+            }
+        }
+
+        // Stop releasing delayed media
+        {
+            let mut peer_delays = self.peer_delays.write().await;
+            for (_, peer_delay) in peer_delays.drain() {
+                peer_delay.release_task.abort();
+            }
+        }
+
+        // Close all peer transports
+        {
+            let mut transports = self.peer_transports.write().await;
+            for (peer_id, transport) in transports.drain() {
+                if let Err(err) = transport.sender.close().await {
+                    warn!("Error whilst closing transport for peer {:?}: {err}", peer_id);
+                }
+            }
+        }
+
+        let mut ipc_sender = self.ipc_sender.clone();
+        ipc_sender.send(StreamerIpcMessage::Stop).await;
+
+        // TODO: should we terminate or wait for a new retry?
+        info!("Terminating Self");
+        self.terminate.notify_waiters();
+    }
+}
+
+/// The callbacks of moonlight, they're handled in order by [handle_connection_events]
+enum ConnectionEvent {
+    StageStarting(Stage),
+    StageComplete(Stage),
+    StageFailed {
+        stage: Stage,
+        error_code: i32,
+    },
+    ConnectionTerminated {
+        error_code: i32,
+    },
+    ConnectionStatusUpdate(ConnectionStatus),
+    ControllerRumble {
+        controller_number: u16,
+        low_frequency_motor: u16,
+        high_frequency_motor: u16,
+    },
+    ControllerRumbleTriggers {
+        controller_number: u16,
+        left_trigger_motor: u16,
+        right_trigger_motor: u16,
+    },
+}
+
+/// Moonlight can call the listener from any thread, including threads of the runtime.
+/// Blocking on the runtime in there would panic, so every callback only queues an event.
+struct StreamConnectionListener {
+    event_sender: UnboundedSender<ConnectionEvent>,
+    stage_timings: Arc<StdMutex<StageTimings>>,
+}
+
+impl StreamConnectionListener {
+    /// Create it right before starting the stream, the time until the first stage counts as launching the app
+    fn new(stream: &Arc<StreamConnection>) -> Self {
+        let (event_sender, event_receiver) = unbounded_channel();
+
+        stream.runtime.spawn(handle_connection_events(
+            Arc::downgrade(stream),
+            stream.stream_epoch.load(Ordering::Acquire),
+            event_receiver,
+        ));
+
+        Self {
+            event_sender,
+            stage_timings: Arc::new(StdMutex::new(StageTimings::new(Instant::now()))),
+        }
+    }
+
+    /// Moonlight owns the listener while streaming, so the timings are read through this
+    fn stage_timings(&self) -> Arc<StdMutex<StageTimings>> {
+        self.stage_timings.clone()
+    }
+    fn lock_stage_timings(&self) -> MutexGuard<'_, StageTimings> {
+        self.stage_timings
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn send_event(&self, event: ConnectionEvent) {
+        if self.event_sender.send(event).is_err() {
+            warn!("Failed to handle connection event because the event handler stopped");
+        }
+    }
+}
+
+/// How long the stages of the stream start took, sent in `ConnectionComplete`
+struct StageTimings {
+    /// When the current stage started
+    stage_start: Instant,
+    launched_app: bool,
+    timings: Vec<StreamStageTiming>,
+}
+
+impl StageTimings {
+    fn new(start: Instant) -> Self {
+        Self {
+            stage_start: start,
+            launched_app: false,
+            timings: Vec::new(),
+        }
+    }
+
+    fn stage_starting(&mut self, now: Instant) {
+        // Moonlight launches or resumes the app on the host before the first stage
+        if !self.launched_app {
+            self.launched_app = true;
+            self.push("Launch App", now);
+        }
+
+        self.stage_start = now;
+    }
+    fn stage_complete(&mut self, stage: &str, now: Instant) {
+        self.push(stage, now);
+    }
+
+    fn push(&mut self, stage: &str, now: Instant) {
+        self.timings.push(StreamStageTiming {
+            stage: stage.to_string(),
+            duration_ms: now.duration_since(self.stage_start).as_millis() as u64,
+        });
+    }
+
+    fn timings(&self) -> Vec<StreamStageTiming> {
+        self.timings.clone()
+    }
+}
+
+/// Handles the events of the listener until the listener is dropped
+async fn handle_connection_events(
+    stream: Weak<StreamConnection>,
+    epoch: u32,
+    mut event_receiver: UnboundedReceiver<ConnectionEvent>,
+) {
+    // Aborts the start if the current stage doesn't complete in time
+    let mut stage_watchdog: Option<JoinHandle<()>> = None;
+
+    while let Some(event) = event_receiver.recv().await {
+        let Some(stream) = stream.upgrade() else {
+            warn!("Failed to get stream because it is already deallocated");
+            break;
+        };
+
+        if matches!(
+            event,
+            ConnectionEvent::StageComplete(_)
+                | ConnectionEvent::StageFailed { .. }
+                | ConnectionEvent::ConnectionTerminated { .. }
+        ) && let Some(watchdog) = stage_watchdog.take()
+        {
+            watchdog.abort();
+        }
+
+        let mut ipc_sender = stream.ipc_sender.clone();
+
+        match event {
+            ConnectionEvent::StageStarting(stage) => {
+                let watchdog = spawn(stage_watchdog_timeout(
+                    Arc::downgrade(&stream),
+                    epoch,
+                    stage,
+                ));
+                if let Some(watchdog) = stage_watchdog.replace(watchdog) {
+                    watchdog.abort();
+                }
+
+                ipc_sender
+                    .send(StreamerIpcMessage::WebSocket(
+                        StreamServerMessage::DebugLog {
+                            message: format!("Starting Stage: {}", stage.name()),
+                            ty: None,
+                        },
+                    ))
+                    .await;
+            }
+            ConnectionEvent::StageComplete(stage) => {
+                ipc_sender
+                    .send(StreamerIpcMessage::WebSocket(
+                        StreamServerMessage::DebugLog {
+                            message: format!("Completed Stage: {}", stage.name()),
+                            ty: None,
+                        },
+                    ))
+                    .await;
+            }
+            ConnectionEvent::StageFailed { stage, error_code } => {
+                ipc_sender
+                    .send(StreamerIpcMessage::WebSocket(
+                        StreamServerMessage::DebugLog {
+                            message: format!(
+                                "Failed Stage: {} with error code {}",
+                                stage.name(),
+                                error_code
+                            ),
+                            ty: Some(LogMessageType::Fatal),
+                        },
+                    ))
+                    .await;
+            }
+            ConnectionEvent::ConnectionTerminated { error_code } => {
+                ipc_sender
+                    .send(StreamerIpcMessage::WebSocket(
+                        connection_terminated_message(error_code),
+                    ))
+                    .await;
+
+                stream.stop().await;
+            }
+            ConnectionEvent::ConnectionStatusUpdate(status) => {
+                stream
+                    .quality
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .set_status(status);
+
+                stream
+                    .broadcast_packet(OutboundPacket::General {
+                        message: GeneralServerMessage::ConnectionStatusUpdate {
+                            status: status.into(),
+                        },
+                    })
+                    .await;
+            }
+            ConnectionEvent::ControllerRumble {
+                controller_number,
+                low_frequency_motor,
+                high_frequency_motor,
+            } => {
+                stream
+                    .send_to_gamepad_owners(OutboundPacket::ControllerRumble {
+                        controller_number: controller_number as u8,
+                        low_frequency_motor,
+                        high_frequency_motor,
+                    })
+                    .await;
+            }
+            ConnectionEvent::ControllerRumbleTriggers {
+                controller_number,
+                left_trigger_motor,
+                right_trigger_motor,
+            } => {
+                stream
+                    .send_to_gamepad_owners(OutboundPacket::ControllerTriggerRumble {
+                        controller_number: controller_number as u8,
+                        left_trigger_motor,
+                        right_trigger_motor,
+                    })
+                    .await;
+            }
+        }
+    }
+
+    // The listener is dropped when moonlight stops, the stage won't complete anymore
+    if let Some(watchdog) = stage_watchdog {
+        watchdog.abort();
+    }
+}
+
+/// Aborts the start of the stream with the epoch when the stage is still running after the configured timeout
+async fn stage_watchdog_timeout(stream: Weak<StreamConnection>, epoch: u32, stage: Stage) {
+    let Some(stage_timeout) = stream.upgrade().map(|stream| stream.config.stage_timeout) else {
+        return;
+    };
+
+    sleep(stage_timeout).await;
+
+    let Some(stream) = stream.upgrade() else {
+        return;
+    };
+    // A deliberate stop or a newer start already ended this start
+    if stream.is_terminating.load(Ordering::Acquire)
+        || stream.stream_epoch.load(Ordering::Acquire) != epoch
+    {
+        return;
+    }
+
+    error!(
+        "[Stream]: stage {} didn't complete within {stage_timeout:?}, aborting the start",
+        stage.name()
+    );
+
+    let mut ipc_sender = stream.ipc_sender.clone();
+    ipc_sender
+        .send(StreamerIpcMessage::WebSocket(
+            StreamServerMessage::DebugLog {
+                message: format!(
+                    "Stage {} didn't complete within {}s",
+                    stage.name(),
+                    stage_timeout.as_secs()
+                ),
+                ty: Some(LogMessageType::Fatal),
+            },
+        ))
+        .await;
+
+    // Makes the blocking start of moonlight return
+    stream.moonlight.interrupt_connection();
+
+    ipc_sender
+        .send(StreamerIpcMessage::WebSocket(
+            connection_terminated_message(STAGE_TIMEOUT),
+        ))
+        .await;
+
+    stream.stop().await;
+}
+
+impl ConnectionListener for StreamConnectionListener {
+    fn stage_starting(&mut self, stage: Stage) {
+        self.lock_stage_timings().stage_starting(Instant::now());
+
+        self.send_event(ConnectionEvent::StageStarting(stage));
+    }
+
+    fn stage_complete(&mut self, stage: Stage) {
+        self.lock_stage_timings()
+            .stage_complete(stage.name(), Instant::now());
+
+        self.send_event(ConnectionEvent::StageComplete(stage));
+    }
+
+    fn stage_failed(&mut self, stage: Stage, error_code: i32) {
+        self.send_event(ConnectionEvent::StageFailed { stage, error_code });
+    }
+
+    fn connection_started(&mut self) {}
+
+    fn connection_terminated(&mut self, error_code: i32) {
+        self.send_event(ConnectionEvent::ConnectionTerminated { error_code });
+    }
+
+    fn log_message(&mut self, message: &str) {
+        info!(target: "moonlight", "{}", message.trim());
+    }
+
+    fn connection_status_update(&mut self, status: ConnectionStatus) {
+        self.send_event(ConnectionEvent::ConnectionStatusUpdate(status));
+    }
+
+    fn set_hdr_mode(&mut self, _hdr_enabled: bool) {}
+
+    fn controller_rumble(
+        &mut self,
+        controller_number: u16,
+        low_frequency_motor: u16,
+        high_frequency_motor: u16,
+    ) {
+        self.send_event(ConnectionEvent::ControllerRumble {
+            controller_number,
+            low_frequency_motor,
+            high_frequency_motor,
+        });
+    }
+
+    fn controller_rumble_triggers(
+        &mut self,
+        controller_number: u16,
+        left_trigger_motor: u16,
+        right_trigger_motor: u16,
+    ) {
+        self.send_event(ConnectionEvent::ControllerRumbleTriggers {
+            controller_number,
+            left_trigger_motor,
+            right_trigger_motor,
+        });
+    }
+
+    fn controller_set_motion_event_state(
+        &mut self,
+        _controller_number: u16,
+        _motion_type: u8,
+        _report_rate_hz: u16,
+    ) {
+        // unsupported: https://github.com/w3c/gamepad/issues/211
+    }
+
+    fn controller_set_adaptive_triggers(
+        &mut self,
+        _controller_number: u16,
+        _event_flags: u8,
+        _type_left: u8,
+        _type_right: u8,
+        _left: &mut u8,
+        _right: &mut u8,
+    ) {
+        // unsupported
+    }
+
+    fn controller_set_led(&mut self, _controller_number: u16, _r: u8, _g: u8, _b: u8) {
+        // unsupported
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{Arc, PoisonError, atomic::Ordering},
+        time::{Duration, Instant},
+    };
+
+    use bytes::Bytes;
+    use common::{
+        AllowedInputs,
+        api_bindings::{
+            GeneralClientMessage, GeneralServerMessage, LogMessageType, PeerPermissions,
+            PeerVideoMode, PlayerSlot, QualityLevel, RoomEventKind, RoomRole, StreamClientMessage,
+            StreamServerMessage, TerminationReason,
+        },
+        ipc::{PeerId, ServerIpcMessage, StreamerIpcMessage},
+    };
+    use moonlight_common::{
+        MoonlightError,
+        high::HostError,
+        network::ApiError,
+        stream::{
+            bindings::{
+                AudioConfig, BufferType, Colorspace, ConnectionStatus, ControllerButtons,
+                ControllerCapabilities, ControllerType, FrameType, KeyAction, KeyFlags,
+                KeyModifiers, MouseButton, OpusMultistreamConfig, Stage, VideoDataBuffer,
+                VideoDecodeUnit, VideoFormat,
+            },
+            connection::ConnectionListener,
+            video::VideoSetup,
+        },
+    };
+    use tokio::time::{sleep, timeout};
+
+    use crate::{
+        PEER_SETUP_RETRY_INTERVAL, StageTimings, StreamConnectionListener,
+        delay::OwnedVideoUnit,
+        input_allowed, is_app_running_error,
+        peer_manager::GamepadArrival,
+        quality::QUALITY_MEASURE_INTERVAL,
+        transport::{
+            InboundPacket, OutboundPacket, TransportEvent, TransportReadiness,
+            test::{
+                self as fake_transport, HostInputEvent, TestStreamConnection,
+                TestStreamConnectionBuilder,
+            },
+        },
+    };
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    async fn connect_peer(
+        test: &TestStreamConnection,
+        peer_id: PeerId,
+        player_slot: Option<PlayerSlot>,
+        role: RoomRole,
+    ) {
+        test.connection
+            .on_ipc_message(ServerIpcMessage::PeerConnected {
+                peer_id,
+                player_slot,
+                role,
+                video_frame_queue_size: 3,
+                audio_sample_queue_size: 20,
+                name: None,
+            })
+            .await;
+    }
+
+    async fn map_from(
+        test: &TestStreamConnection,
+        peer_id: PeerId,
+        packet: InboundPacket,
+    ) -> Option<InboundPacket> {
+        *test.connection.current_peer_id.write().await = Some(peer_id);
+        test.connection.map_peer_packet(packet).await
+    }
+
+    fn controller_connected(id: u8) -> InboundPacket {
+        InboundPacket::ControllerConnected {
+            id,
+            ty: ControllerType::Unknown,
+            supported_buttons: ControllerButtons::all(),
+            capabilities: ControllerCapabilities::empty(),
+        }
+    }
+
+    async fn connect_gamepad(test: &TestStreamConnection, peer_id: PeerId) {
+        test.connection.peer_manager.write().await.set_peer_gamepad(
+            peer_id,
+            0,
+            Some(GamepadArrival {
+                ty: ControllerType::Unknown,
+                supported_buttons: ControllerButtons::all(),
+                capabilities: ControllerCapabilities::empty(),
+            }),
+        );
+    }
+
+    fn key_down() -> InboundPacket {
+        InboundPacket::Key {
+            action: KeyAction::Down,
+            modifiers: KeyModifiers::empty(),
+            key: 0x41,
+            flags: KeyFlags::empty(),
+            text: Some("a".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_gamepad_slot_mapping() {
+        let test = TestStreamConnectionBuilder::new().build().await;
+
+        let peers = [
+            (PeerId(1), PlayerSlot::PLAYER_1, RoomRole::Host),
+            (PeerId(2), PlayerSlot::PLAYER_2, RoomRole::Player),
+            (PeerId(3), PlayerSlot::PLAYER_3, RoomRole::Player),
+        ];
+        for (peer_id, slot, role) in peers {
+            connect_peer(&test, peer_id, Some(slot), role).await;
+        }
+        connect_peer(&test, PeerId(4), None, RoomRole::Spectator).await;
+
+        // Every browser uses gamepad 0, which maps to the slot of the player
+        for (peer_id, slot, _) in peers {
+            let packet = map_from(&test, peer_id, controller_connected(0)).await;
+            assert!(
+                matches!(packet, Some(InboundPacket::ControllerConnected { id, .. }) if id == slot.gamepad_slot()),
+                "unexpected mapping for {peer_id:?}: {packet:?}"
+            );
+
+            let packet = map_from(
+                &test,
+                peer_id,
+                InboundPacket::ControllerDisconnected { id: 0 },
+            )
+            .await;
+            assert!(
+                matches!(packet, Some(InboundPacket::ControllerDisconnected { id }) if id == slot.gamepad_slot())
+            );
+        }
+
+        // Only gamepad 0 is allowed per player
+        assert!(
+            map_from(&test, PeerId(3), controller_connected(1))
+                .await
+                .is_none()
+        );
+        // Spectators can't use gamepads
+        assert!(
+            map_from(&test, PeerId(4), controller_connected(0))
+                .await
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_gamepad_before_local_slot() {
+        let test = TestStreamConnectionBuilder::new().build().await;
+
+        let player = PeerId(2);
+        connect_peer(&test, PeerId(1), Some(PlayerSlot::PLAYER_1), RoomRole::Host).await;
+        connect_peer(&test, player, Some(PlayerSlot::PLAYER_2), RoomRole::Player).await;
+
+        // The second gamepad arrives before the web server assigned its slot
+        assert!(
+            map_from(&test, player, controller_connected(1))
+                .await
+                .is_none()
+        );
+        assert!(
+            map_from(&test, player, controller_connected(2))
+                .await
+                .is_none()
+        );
+        // This one is disconnected again before it got a slot
+        assert!(
+            map_from(
+                &test,
+                player,
+                InboundPacket::ControllerDisconnected { id: 2 }
+            )
+            .await
+            .is_none()
+        );
+
+        test.connection
+            .set_local_gamepad_slots(player, vec![PlayerSlot::PLAYER_3, PlayerSlot::PLAYER_4])
+            .await;
+
+        let peer_manager = test.connection.peer_manager.read().await;
+        let gamepads = peer_manager
+            .peer_gamepads()
+            .map(|(peer_id, browser_gamepad_id, _)| (peer_id, browser_gamepad_id))
+            .collect::<Vec<_>>();
+        assert_eq!(gamepads, vec![(player, 1)]);
+        assert_eq!(
+            peer_manager.gamepad_owners(PlayerSlot::PLAYER_3.gamepad_slot()),
+            vec![(player, 1)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_keyboard_gating_toggle() {
+        let test = TestStreamConnectionBuilder::new().build().await;
+
+        let host = PeerId(1);
+        let guest = PeerId(2);
+        connect_peer(&test, host, Some(PlayerSlot::PLAYER_1), RoomRole::Host).await;
+        connect_peer(&test, guest, Some(PlayerSlot::PLAYER_2), RoomRole::Player).await;
+
+        assert!(map_from(&test, host, key_down()).await.is_some());
+        assert!(map_from(&test, guest, key_down()).await.is_none());
+
+        test.connection
+            .on_ipc_message(ServerIpcMessage::SetGuestsKeyboardMouseEnabled { enabled: true })
+            .await;
+
+        assert!(map_from(&test, host, key_down()).await.is_some());
+        assert!(map_from(&test, guest, key_down()).await.is_some());
+
+        test.connection
+            .on_ipc_message(ServerIpcMessage::SetGuestsKeyboardMouseEnabled { enabled: false })
+            .await;
+
+        assert!(map_from(&test, host, key_down()).await.is_some());
+        assert!(map_from(&test, guest, key_down()).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_peer_permissions_gating() {
+        let test = TestStreamConnectionBuilder::new().build().await;
+
+        let guest = PeerId(2);
+        connect_peer(&test, guest, Some(PlayerSlot::PLAYER_2), RoomRole::Player).await;
+
+        let mouse_move = || InboundPacket::MouseMove {
+            delta_x: 1,
+            delta_y: 1,
+        };
+
+        test.connection
+            .on_ipc_message(ServerIpcMessage::SetPeerPermissions {
+                peer_id: guest,
+                permissions: PeerPermissions {
+                    allow_mouse: true,
+                    ..PeerPermissions::NONE
+                },
+            })
+            .await;
+
+        assert!(map_from(&test, guest, mouse_move()).await.is_some());
+        assert!(map_from(&test, guest, key_down()).await.is_none());
+        assert!(
+            map_from(&test, guest, controller_connected(0))
+                .await
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_release_held_input_on_revoke() {
+        let test = TestStreamConnectionBuilder::new().build().await;
+
+        let guest = PeerId(2);
+        connect_peer(&test, guest, Some(PlayerSlot::PLAYER_2), RoomRole::Player).await;
+
+        let (sender, events, handle) = fake_transport::new();
+        test.connection
+            .set_peer_transport(guest, Box::new(sender), Box::new(events))
+            .await;
+
+        let set_enabled = |enabled| ServerIpcMessage::SetGuestsKeyboardMouseEnabled { enabled };
+        let held_input = || async {
+            test.connection
+                .peer_manager
+                .read()
+                .await
+                .get_peer(guest)
+                .map(|info| info.held_input.clone())
+                .unwrap_or_default()
+        };
+        let input_permission = |packets: Vec<OutboundPacket>| match packets.as_slice() {
+            [
+                OutboundPacket::General {
+                    message: GeneralServerMessage::InputPermissionChanged { keyboard_mouse },
+                },
+            ] => Some(*keyboard_mouse),
+            _ => None,
+        };
+
+        test.connection.on_ipc_message(set_enabled(true)).await;
+        assert_eq!(input_permission(handle.take_packets()), Some(true));
+
+        {
+            let mut peer_manager = test.connection.peer_manager.write().await;
+            peer_manager.set_key_held(guest, 0x57, true);
+            peer_manager.set_mouse_button_held(guest, MouseButton::Left, true);
+        }
+
+        // Granting again keeps the held input
+        test.connection.on_ipc_message(set_enabled(true)).await;
+        assert_eq!(input_permission(handle.take_packets()), Some(true));
+        assert!(!held_input().await.is_empty());
+
+        assert!(test.host_input.take_events().is_empty());
+
+        test.connection.on_ipc_message(set_enabled(false)).await;
+        assert_eq!(input_permission(handle.take_packets()), Some(false));
+        assert!(held_input().await.is_empty());
+        assert_eq!(
+            test.host_input.take_events(),
+            [
+                HostInputEvent::KeyUp(0x57),
+                HostInputEvent::MouseButtonRelease(MouseButton::Left),
+            ]
+        );
+
+        // Nothing is held anymore
+        test.connection.on_ipc_message(set_enabled(false)).await;
+        assert_eq!(input_permission(handle.take_packets()), Some(false));
+        assert!(test.host_input.take_events().is_empty());
+    }
+
+    #[test]
+    fn test_allowed_inputs() {
+        let allowed = AllowedInputs {
+            keyboard: false,
+            gamepad: false,
+            ..AllowedInputs::default()
+        };
+
+        assert!(input_allowed(
+            allowed,
+            &InboundPacket::MouseMove {
+                delta_x: 1,
+                delta_y: 1,
+            }
+        ));
+        assert!(!input_allowed(allowed, &key_down()));
+        assert!(!input_allowed(allowed, &controller_connected(0)));
+        assert!(input_allowed(
+            allowed,
+            &InboundPacket::SetStatsInterval {
+                millis: None,
+                frame_sample_interval: None,
+            }
+        ));
+        assert!(input_allowed(AllowedInputs::default(), &key_down()));
+    }
+
+    #[test]
+    fn test_app_running_error() {
+        let status = |message: Option<&str>| -> HostError<std::io::Error> {
+            HostError::Api(ApiError::InvalidXmlStatusCode {
+                message: message.map(str::to_string),
+            })
+        };
+
+        assert!(is_app_running_error(&status(Some(
+            "An app is already running on this host"
+        ))));
+        assert!(!is_app_running_error(&status(Some("Invalid uniqueid"))));
+        assert!(!is_app_running_error(&status(None)));
+        assert!(!is_app_running_error(
+            &HostError::<std::io::Error>::Moonlight(MoonlightError::ConnectionAlreadyExists)
+        ));
+        assert!(!is_app_running_error(
+            &HostError::<std::io::Error>::LikelyOffline
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_mouse_mode_gating() {
+        let test = TestStreamConnectionBuilder::new().build().await;
+
+        let host = PeerId(1);
+        connect_peer(&test, host, Some(PlayerSlot::PLAYER_1), RoomRole::Host).await;
+
+        let (sender, events, handle) = fake_transport::new();
+        test.connection
+            .set_peer_transport(host, Box::new(sender), Box::new(events))
+            .await;
+
+        let mouse_move = || InboundPacket::MouseMove {
+            delta_x: 1,
+            delta_y: 1,
+        };
+        let mouse_position = || InboundPacket::MousePosition {
+            x: 1,
+            y: 1,
+            reference_width: 4096,
+            reference_height: 4096,
+        };
+        let set_mouse_mode = |relative| InboundPacket::General {
+            message: GeneralClientMessage::SetMouseMode { relative },
+        };
+
+        // Both are accepted until the peer told its mode
+        assert!(map_from(&test, host, mouse_move()).await.is_some());
+        assert!(map_from(&test, host, mouse_position()).await.is_some());
+
+        *test.connection.current_peer_id.write().await = Some(host);
+        test.connection.on_packet(set_mouse_mode(Some(true))).await;
+
+        let packets = handle.take_packets();
+        assert!(matches!(
+            packets.as_slice(),
+            [OutboundPacket::General {
+                message: GeneralServerMessage::MouseModeChanged {
+                    relative: Some(true)
+                }
+            }]
+        ));
+        assert!(map_from(&test, host, mouse_move()).await.is_some());
+        assert!(map_from(&test, host, mouse_position()).await.is_none());
+
+        *test.connection.current_peer_id.write().await = Some(host);
+        test.connection.on_packet(set_mouse_mode(Some(false))).await;
+
+        assert_eq!(handle.take_packets().len(), 1);
+        assert!(map_from(&test, host, mouse_move()).await.is_none());
+        assert!(map_from(&test, host, mouse_position()).await.is_some());
+
+        // Pointing and dragging sends both
+        *test.connection.current_peer_id.write().await = Some(host);
+        test.connection.on_packet(set_mouse_mode(None)).await;
+
+        assert_eq!(handle.take_packets().len(), 1);
+        assert!(map_from(&test, host, mouse_move()).await.is_some());
+        assert!(map_from(&test, host, mouse_position()).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_room_events_wait_for_host() {
+        let mut test = TestStreamConnectionBuilder::new().build().await;
+
+        let host = PeerId(1);
+        let guest = PeerId(2);
+        connect_peer(&test, host, Some(PlayerSlot::PLAYER_1), RoomRole::Host).await;
+
+        let (sender, events, handle) = fake_transport::new();
+        test.connection
+            .set_peer_transport(host, Box::new(sender), Box::new(events))
+            .await;
+
+        // The host can't receive it yet
+        test.connection
+            .on_ipc_message(ServerIpcMessage::PeerConnected {
+                peer_id: guest,
+                player_slot: Some(PlayerSlot::PLAYER_2),
+                role: RoomRole::Player,
+                video_frame_queue_size: 3,
+                audio_sample_queue_size: 20,
+                name: Some("Guest".to_string()),
+            })
+            .await;
+        assert!(handle.take_packets().is_empty());
+
+        handle.inject_event(TransportEvent::Ready).await;
+        let message = timeout(TIMEOUT, test.ipc_receiver.recv())
+            .await
+            .expect("no ipc message received");
+        assert!(matches!(
+            message,
+            Some(StreamerIpcMessage::PeerReady { .. })
+        ));
+
+        let packets = handle.take_packets();
+        assert!(matches!(
+            packets.as_slice(),
+            [OutboundPacket::General {
+                message: GeneralServerMessage::RoomEvent {
+                    kind: RoomEventKind::Joined,
+                    name: Some(name),
+                    slot: Some(PlayerSlot::PLAYER_2),
+                }
+            }] if name == "Guest"
+        ));
+
+        // Only the host may silence them
+        let set_room_events = |enabled| InboundPacket::General {
+            message: GeneralClientMessage::SetRoomEventsEnabled { enabled },
+        };
+        *test.connection.current_peer_id.write().await = Some(guest);
+        test.connection.on_packet(set_room_events(false)).await;
+
+        test.connection
+            .on_ipc_message(ServerIpcMessage::PeerDisconnected { peer_id: guest })
+            .await;
+        assert!(matches!(
+            handle.take_packets().as_slice(),
+            [OutboundPacket::General {
+                message: GeneralServerMessage::RoomEvent {
+                    kind: RoomEventKind::Left,
+                    ..
+                }
+            }]
+        ));
+
+        *test.connection.current_peer_id.write().await = Some(host);
+        test.connection.on_packet(set_room_events(false)).await;
+
+        connect_peer(&test, guest, Some(PlayerSlot::PLAYER_2), RoomRole::Player).await;
+        assert!(handle.take_packets().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_thumbnail_video_mode() {
+        let mut test = TestStreamConnectionBuilder::new().build().await;
+
+        let player = PeerId(1);
+        let spectator = PeerId(2);
+        connect_peer(&test, player, Some(PlayerSlot::PLAYER_1), RoomRole::Host).await;
+        connect_peer(&test, spectator, None, RoomRole::Spectator).await;
+
+        let set_video_mode = |peer_id, mode| ServerIpcMessage::PeerWebSocket {
+            peer_id,
+            message: StreamClientMessage::SetVideoMode { mode },
+        };
+
+        // Players always get the full stream
+        test.connection
+            .on_ipc_message(set_video_mode(player, PeerVideoMode::Thumbnail))
+            .await;
+        assert!(test.connection.thumbnail_peers.read().await.is_empty());
+
+        test.connection
+            .on_ipc_message(set_video_mode(spectator, PeerVideoMode::Thumbnail))
+            .await;
+        assert!(
+            test.connection
+                .thumbnail_peers
+                .read()
+                .await
+                .contains(&spectator)
+        );
+        let message = timeout(TIMEOUT, test.ipc_receiver.recv())
+            .await
+            .expect("no ipc message received");
+        assert!(matches!(
+            message,
+            Some(StreamerIpcMessage::PeerWebSocket {
+                peer_id,
+                message: StreamServerMessage::VideoModeChanged {
+                    mode: PeerVideoMode::Thumbnail
+                },
+            }) if peer_id == spectator
+        ));
+
+        // A promoted spectator is switched back and waits for the next keyframe
+        test.connection
+            .on_ipc_message(ServerIpcMessage::PeerRoleChanged {
+                peer_id: spectator,
+                new_role: RoomRole::Player,
+                player_slot: Some(PlayerSlot::PLAYER_2),
+            })
+            .await;
+        assert!(test.connection.thumbnail_peers.read().await.is_empty());
+        assert!(test.connection.request_idr.load(Ordering::Relaxed));
+        let message = timeout(TIMEOUT, test.ipc_receiver.recv())
+            .await
+            .expect("no ipc message received");
+        assert!(matches!(
+            message,
+            Some(StreamerIpcMessage::PeerWebSocket {
+                message: StreamServerMessage::VideoModeChanged {
+                    mode: PeerVideoMode::Full
+                },
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_thumbnail_peer_gets_cached_keyframe() {
+        let mut test = TestStreamConnectionBuilder::new().build().await;
+
+        let spectator = PeerId(2);
+        connect_peer(&test, PeerId(1), Some(PlayerSlot::PLAYER_1), RoomRole::Host).await;
+        connect_peer(&test, spectator, None, RoomRole::Spectator).await;
+
+        test.connection
+            .on_ipc_message(ServerIpcMessage::PeerWebSocket {
+                peer_id: spectator,
+                message: StreamClientMessage::SetVideoMode {
+                    mode: PeerVideoMode::Thumbnail,
+                },
+            })
+            .await;
+        timeout(TIMEOUT, test.ipc_receiver.recv())
+            .await
+            .expect("no ipc message received");
+
+        let data = [0u8; 16];
+        let buffers = [VideoDataBuffer {
+            ty: BufferType::PicData,
+            data: &data,
+        }];
+        let keyframe = OwnedVideoUnit::new(
+            &VideoDecodeUnit {
+                frame_number: 1,
+                frame_type: FrameType::Idr,
+                frame_processing_latency: None,
+                receive_time: Duration::ZERO,
+                enqueue_time: Duration::ZERO,
+                presentation_time: Duration::ZERO,
+                hdr_active: false,
+                color_space: Colorspace::Rec709,
+                buffers: &buffers,
+            },
+            7,
+        );
+        *test.connection.cached_keyframe.lock().expect("poisoned") = Some(Arc::new(keyframe));
+
+        let (sender, events, handle) = fake_transport::new();
+        test.connection
+            .set_peer_transport(spectator, Box::new(sender), Box::new(events))
+            .await;
+        handle.inject_event(TransportEvent::Ready).await;
+        let message = timeout(TIMEOUT, test.ipc_receiver.recv())
+            .await
+            .expect("no ipc message received");
+        assert!(matches!(
+            message,
+            Some(StreamerIpcMessage::PeerReady { .. })
+        ));
+
+        // The spectator sees the last keyframe without the host sending a new one to everyone
+        assert_eq!(handle.take_video_frames(), vec![(FrameType::Idr, 7)]);
+        assert!(!test.connection.request_idr.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn test_transport_ready_notifies_web_server() {
+        let mut test = TestStreamConnectionBuilder::new().build().await;
+        let peer_id = PeerId(3);
+
+        let (sender, events, handle) = fake_transport::new();
+        test.connection
+            .set_peer_transport(peer_id, Box::new(sender), Box::new(events))
+            .await;
+        let readiness = test.connection.peer_transports.read().await[&peer_id]
+            .readiness
+            .clone();
+        assert!(!readiness.is_ready());
+
+        handle.inject_event(TransportEvent::Ready).await;
+
+        let message = timeout(TIMEOUT, test.ipc_receiver.recv())
+            .await
+            .expect("no ipc message received");
+        assert!(matches!(
+            message,
+            Some(StreamerIpcMessage::PeerReady { peer_id: PeerId(3) })
+        ));
+        assert!(readiness.is_ready());
+        // The peer can only decode the stream starting at a keyframe
+        assert!(test.connection.request_idr.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn test_ready_peer_gets_quality_report() {
+        let mut test = TestStreamConnectionBuilder::new().build().await;
+        let peer_id = PeerId(1);
+
+        {
+            let mut quality = test
+                .connection
+                .quality
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            let mut now = Instant::now();
+            while quality.last_report().is_none() {
+                now += QUALITY_MEASURE_INTERVAL;
+                quality.measure(now, Some(Duration::from_millis(20)));
+            }
+        }
+
+        let (sender, events, handle) = fake_transport::new();
+        test.connection
+            .set_peer_transport(peer_id, Box::new(sender), Box::new(events))
+            .await;
+        handle.inject_event(TransportEvent::Ready).await;
+        timeout(TIMEOUT, test.ipc_receiver.recv())
+            .await
+            .expect("no ipc message received");
+
+        assert!(handle.take_packets().iter().any(|packet| matches!(
+            packet,
+            OutboundPacket::General {
+                message: GeneralServerMessage::QualityReport {
+                    level: QualityLevel::Excellent,
+                    ..
+                }
+            }
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_memory_recovery_waits_for_keyframe() {
+        let mut test = TestStreamConnectionBuilder::new().build().await;
+        let peer_id = PeerId(3);
+
+        let (sender, events, handle) = fake_transport::new();
+        test.connection
+            .set_peer_transport(peer_id, Box::new(sender), Box::new(events))
+            .await;
+        handle.inject_event(TransportEvent::Ready).await;
+        timeout(TIMEOUT, test.ipc_receiver.recv())
+            .await
+            .expect("no ipc message received");
+
+        let readiness = test.connection.peer_transports.read().await[&peer_id]
+            .readiness
+            .clone();
+        assert!(readiness.accepts_video(FrameType::Idr));
+        assert!(readiness.accepts_video(FrameType::PFrame));
+
+        test.connection.keyframe_only.store(true, Ordering::Release);
+        test.connection.request_idr.store(false, Ordering::Relaxed);
+
+        // The frames after the recovery refer to frames which were dropped
+        test.connection.send_all_frames().await;
+        assert!(!test.connection.keyframe_only.load(Ordering::Acquire));
+        assert!(test.connection.request_idr.load(Ordering::Relaxed));
+        assert!(!readiness.accepts_video(FrameType::PFrame));
+        assert!(readiness.accepts_video(FrameType::Idr));
+    }
+
+    #[test]
+    fn test_transport_readiness_starts_with_keyframe() {
+        let readiness = TransportReadiness::default();
+        assert!(!readiness.accepts_video(FrameType::Idr));
+
+        readiness.set_ready();
+        assert!(!readiness.accepts_video(FrameType::PFrame));
+        assert!(readiness.accepts_video(FrameType::Idr));
+        assert!(readiness.accepts_video(FrameType::PFrame));
+    }
+
+    #[tokio::test]
+    async fn test_failed_peer_setup_is_retried() {
+        let mut test = TestStreamConnectionBuilder::new().build().await;
+
+        let mut handles = Vec::new();
+        for id in 1..=3 {
+            let (sender, events, handle) = fake_transport::new();
+            test.connection
+                .set_peer_transport(PeerId(id), Box::new(sender), Box::new(events))
+                .await;
+            handle.inject_event(TransportEvent::Ready).await;
+            let message = timeout(TIMEOUT, test.ipc_receiver.recv())
+                .await
+                .expect("no ipc message received");
+            assert!(matches!(message, Some(StreamerIpcMessage::PeerReady { .. })));
+            handles.push(handle);
+        }
+
+        let failing = PeerId(2);
+        handles[1].set_setup_result(-1);
+
+        let video = VideoSetup {
+            format: VideoFormat::H264,
+            width: 1920,
+            height: 1080,
+            redraw_rate: 60,
+            flags: 0,
+        };
+        {
+            let mut setup = test.connection.stream_setup.lock().await;
+            setup.video = Some(video);
+            setup.audio = Some(OpusMultistreamConfig::STEREO);
+            setup.audio_config = Some(AudioConfig::STEREO);
+        }
+
+        // A single failing peer doesn't fail the stream
+        assert_eq!(test.connection.setup_video_transports(video).await, 0);
+        assert_eq!(
+            test.connection
+                .setup_audio_transports(AudioConfig::STEREO, OpusMultistreamConfig::STEREO)
+                .await,
+            0
+        );
+
+        // The peer is only told once, the audio failed while the retry was already scheduled
+        let message = timeout(TIMEOUT, test.ipc_receiver.recv())
+            .await
+            .expect("no ipc message received");
+        assert!(matches!(
+            message,
+            Some(StreamerIpcMessage::PeerWebSocket {
+                peer_id,
+                message: StreamServerMessage::DebugLog {
+                    ty: Some(LogMessageType::InformError),
+                    ..
+                },
+            }) if peer_id == failing
+        ));
+
+        let readiness = {
+            let transports = test.connection.peer_transports.read().await;
+            [PeerId(1), failing, PeerId(3)].map(|peer_id| transports[&peer_id].readiness.clone())
+        };
+        assert!(readiness[0].is_ready());
+        assert!(!readiness[1].is_ready());
+        assert!(readiness[2].is_ready());
+
+        // Neither the failed retries nor another ready event make the transport ready
+        handles[1].inject_event(TransportEvent::Ready).await;
+        sleep(PEER_SETUP_RETRY_INTERVAL * 3 / 2).await;
+        assert!(!readiness[1].is_ready());
+        assert!(handles[1].video_setups().len() >= 2);
+
+        // The retry makes the transport ready once the setup succeeds
+        handles[1].set_setup_result(0);
+        let message = timeout(TIMEOUT, test.ipc_receiver.recv())
+            .await
+            .expect("no ipc message received");
+        assert!(matches!(
+            message,
+            Some(StreamerIpcMessage::PeerReady { peer_id }) if peer_id == failing
+        ));
+        assert!(readiness[1].is_ready());
+        assert!(!readiness[1].has_setup_failed());
+        assert_eq!(handles[1].audio_setups().len(), 2);
+        assert_eq!(handles[0].video_setups().len(), 1);
+
+        // The stream only fails if no transport could set it up
+        for handle in &handles {
+            handle.set_setup_result(-1);
+        }
+        assert_eq!(test.connection.setup_video_transports(video).await, -1);
+    }
+
+    #[tokio::test]
+    async fn test_transport_replacement_closes_old() {
+        let test = TestStreamConnectionBuilder::new().build().await;
+        let peer_id = PeerId(1);
+
+        let (old_sender, old_events, old_handle) = fake_transport::new();
+        test.connection
+            .set_peer_transport(peer_id, Box::new(old_sender), Box::new(old_events))
+            .await;
+
+        let (new_sender, new_events, new_handle) = fake_transport::new();
+        test.connection
+            .set_peer_transport(peer_id, Box::new(new_sender), Box::new(new_events))
+            .await;
+
+        timeout(TIMEOUT, old_handle.wait_closed())
+            .await
+            .expect("the old transport wasn't closed");
+        assert_eq!(old_handle.close_count(), 1);
+        assert_eq!(new_handle.close_count(), 0);
+
+        test.connection
+            .broadcast_packet(OutboundPacket::General {
+                message: GeneralServerMessage::ConnectionStatusUpdate {
+                    status: ConnectionStatus::Poor.into(),
+                },
+            })
+            .await;
+
+        assert!(old_handle.take_packets().is_empty());
+        assert_eq!(new_handle.take_packets().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_rumble_is_sent_to_gamepad_owner() {
+        let test = TestStreamConnectionBuilder::new().build().await;
+
+        let mut handles = Vec::new();
+        for (peer_id, slot, role) in [
+            (PeerId(1), PlayerSlot::PLAYER_1, RoomRole::Host),
+            (PeerId(2), PlayerSlot::PLAYER_2, RoomRole::Player),
+        ] {
+            let (sender, events, handle) = fake_transport::new();
+            test.connection
+                .set_peer_transport(peer_id, Box::new(sender), Box::new(events))
+                .await;
+            connect_peer(&test, peer_id, Some(slot), role).await;
+            connect_gamepad(&test, peer_id).await;
+            handles.push(handle);
+        }
+
+        // The second gamepad of the host belongs to the second player
+        test.connection
+            .send_to_gamepad_owners(OutboundPacket::ControllerRumble {
+                controller_number: 1,
+                low_frequency_motor: 1,
+                high_frequency_motor: 2,
+            })
+            .await;
+
+        assert!(handles[0].take_packets().is_empty());
+        let packets = handles[1].take_packets();
+        assert_eq!(packets.len(), 1);
+        // The browser of the player only knows its own gamepad 0
+        assert!(matches!(
+            packets[0],
+            OutboundPacket::ControllerRumble {
+                controller_number: 0,
+                low_frequency_motor: 1,
+                high_frequency_motor: 2,
             }
-        };
+        ));
 
-        let host_features = stream.host_features().unwrap_or_else(|err| {
-            warn!("[Stream]: failed to get host features: {err:?}");
-            HostFeatures::empty()
-        });
+        // Nobody uses the third gamepad
+        test.connection
+            .send_to_gamepad_owners(OutboundPacket::ControllerTriggerRumble {
+                controller_number: 2,
+                left_trigger_motor: 1,
+                right_trigger_motor: 2,
+            })
+            .await;
+        assert!(handles.iter().all(|handle| handle.take_packets().is_empty()));
 
-        let capabilities = StreamCapabilities {
-            touch: host_features.contains(HostFeatures::PEN_TOUCH_EVENTS),
-        };
+        // The connection status is for everyone
+        test.connection
+            .broadcast_packet(OutboundPacket::General {
+                message: GeneralServerMessage::ConnectionStatusUpdate {
+                    status: ConnectionStatus::Ok.into(),
+                },
+            })
+            .await;
+        for handle in &handles {
+            assert_eq!(handle.take_packets().len(), 1);
+        }
+    }
 
-        let (video_setup, audio_setup) = {
-            let setup = self.stream_setup.lock().await;
+    #[tokio::test]
+    async fn test_transport_ipc_is_routed_to_peer() {
+        let mut test = TestStreamConnectionBuilder::new().build().await;
+        let peer_id = PeerId(7);
 
-            let video = setup.video.unwrap_or_else(|| {
-                warn!("failed to query video setup information. Giving the browser guessed information");
-                VideoSetup { format: VideoFormat::H264, width: settings.width, height: settings.height, redraw_rate: settings.fps, flags: 0 }
-            });
+        let (sender, events, handle) = fake_transport::new();
+        test.connection
+            .set_peer_transport(peer_id, Box::new(sender), Box::new(events))
+            .await;
 
-            let audio = setup.audio.clone().unwrap_or(OpusMultistreamConfig::STEREO);
+        handle
+            .inject_event(TransportEvent::SendIpc(StreamerIpcMessage::WebSocket(
+                StreamServerMessage::DebugLog {
+                    message: "test".to_string(),
+                    ty: None,
+                },
+            )))
+            .await;
 
-            (video, audio)
-        };
+        let message = timeout(TIMEOUT, test.ipc_receiver.recv())
+            .await
+            .expect("no ipc message received");
+        assert!(matches!(
+            message,
+            Some(StreamerIpcMessage::PeerWebSocket {
+                peer_id: PeerId(7),
+                message: StreamServerMessage::DebugLog { .. },
+            })
+        ));
+    }
 
-        info!(
-            "Stream uses these settings: {:?} with {}x{}x{}",
-            video_setup.format, video_setup.width, video_setup.height, video_setup.redraw_rate
-        );
+    #[tokio::test]
+    async fn test_peer_ipc_is_forwarded_to_transport() {
+        let test = TestStreamConnectionBuilder::new().build().await;
 
-        spawn(async move {
-            ipc_sender
-                .send(StreamerIpcMessage::WebSocket(
-                    StreamServerMessage::ConnectionComplete {
-                        capabilities,
-                        format: video_setup.format as u32,
-                        width: video_setup.width,
-                        height: video_setup.height,
-                        fps: video_setup.redraw_rate,
-                        audio_sample_rate: audio_setup.sample_rate,
-                        audio_channel_count: audio_setup.channel_count,
-                        audio_streams: audio_setup.streams,
-                        audio_coupled_streams: audio_setup.coupled_streams,
-                        audio_samples_per_frame: audio_setup.samples_per_frame,
-                        audio_mapping: audio_setup.mapping,
-                    },
-                ))
-                .await;
-        });
+        let (sender, events, handle) = fake_transport::new();
+        test.connection
+            .set_peer_transport(PeerId(1), Box::new(sender), Box::new(events))
+            .await;
+        let (other_sender, other_events, other_handle) = fake_transport::new();
+        test.connection
+            .set_peer_transport(PeerId(2), Box::new(other_sender), Box::new(other_events))
+            .await;
 
-        let mut stream_guard = self.stream.write().await;
-        stream_guard.replace(stream);
+        test.connection
+            .on_ipc_message(ServerIpcMessage::PeerWebSocketTransport {
+                peer_id: PeerId(1),
+                data: Bytes::from_static(&[0]),
+            })
+            .await;
 
-        Ok(())
+        let messages = handle.take_ipc_messages();
+        assert_eq!(messages.len(), 1);
+        assert!(matches!(
+            messages[0],
+            ServerIpcMessage::WebSocketTransport(_)
+        ));
+        assert!(other_handle.take_ipc_messages().is_empty());
     }
 
-    async fn stop(&self) {
-        if self
-            .is_terminating
-            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
-            .is_err()
-        {
-            debug!("[Stream]: stream is already terminating, won't stop twice");
-            return;
-        }
+    #[tokio::test]
+    async fn test_stop_stream_terminates_cleanly() {
+        let mut test = TestStreamConnectionBuilder::new().build().await;
 
-        debug!("[Stream]: Stopping...");
+        let (sender, events, handle) = fake_transport::new();
+        test.connection
+            .set_peer_transport(PeerId(1), Box::new(sender), Box::new(events))
+            .await;
 
-        {
-            let mut stream = self.stream.write().await;
-            if let Some(stream) = stream.take() {
-                spawn_blocking(move || {
-                    stream.stop();
-                });
-            }
-        }
+        test.connection
+            .on_ipc_message(ServerIpcMessage::StopStream)
+            .await;
 
-        // Close all peer transports
-        {
-            let mut transports = self.peer_transports.write().await;
-            for (peer_id, transport) in transports.drain() {
-                if let Err(err) = transport.sender.close().await {
-                    warn!("Error whilst closing transport for peer {:?}: {err}", peer_id);
-                }
-            }
-        }
+        assert_eq!(handle.close_count(), 1);
 
-        let mut ipc_sender = self.ipc_sender.clone();
-        ipc_sender.send(StreamerIpcMessage::Stop).await;
+        let message = timeout(TIMEOUT, test.ipc_receiver.recv())
+            .await
+            .expect("no ipc message received");
+        assert!(matches!(
+            message,
+            Some(StreamerIpcMessage::WebSocket(
+                StreamServerMessage::ConnectionTerminated {
+                    error_code: 0,
+                    reason: TerminationReason::Graceful,
+                    ..
+                }
+            ))
+        ));
 
-        // TODO: should we terminate or wait for a new retry?
-        info!("Terminating Self");
-        self.terminate.notify_waiters();
+        let message = timeout(TIMEOUT, test.ipc_receiver.recv())
+            .await
+            .expect("no ipc message received");
+        assert!(matches!(message, Some(StreamerIpcMessage::Stop)));
     }
-}
 
-struct StreamConnectionListener {
-    stream: Weak<StreamConnection>,
-}
+    #[tokio::test]
+    async fn test_pause_stream() {
+        let mut test = TestStreamConnectionBuilder::new().build().await;
 
-impl ConnectionListener for StreamConnectionListener {
-    fn stage_starting(&mut self, stage: Stage) {
-        let Some(stream) = self.stream.upgrade() else {
-            warn!("Failed to get stream because it is already deallocated");
-            return;
-        };
+        for paused in [true, false] {
+            test.connection
+                .on_ipc_message(ServerIpcMessage::SetStreamPaused { paused })
+                .await;
+            assert_eq!(test.connection.paused.load(Ordering::Relaxed), paused);
 
-        let mut ipc_sender = stream.ipc_sender.clone();
+            let message = timeout(TIMEOUT, test.ipc_receiver.recv())
+                .await
+                .expect("no ipc message received");
+            assert!(matches!(
+                message,
+                Some(StreamerIpcMessage::WebSocket(StreamServerMessage::StreamPaused { paused: message_paused }))
+                    if message_paused == paused
+            ));
 
-        stream.runtime.spawn(async move {
-            ipc_sender
-                .send(StreamerIpcMessage::WebSocket(
-                    StreamServerMessage::DebugLog {
-                        message: format!("Starting Stage: {}", stage.name()),
-                        ty: None,
-                    },
-                ))
+            // Repeating it changes nothing
+            test.connection
+                .on_ipc_message(ServerIpcMessage::SetStreamPaused { paused })
                 .await;
-        });
+            assert!(
+                timeout(Duration::from_millis(50), test.ipc_receiver.recv())
+                    .await
+                    .is_err()
+            );
+        }
+
+        // The peers continue with a keyframe
+        assert!(test.connection.request_idr.load(Ordering::Relaxed));
     }
 
-    fn stage_complete(&mut self, stage: Stage) {
-        let Some(stream) = self.stream.upgrade() else {
-            warn!("Failed to get stream because it is already deallocated");
-            return;
-        };
+    #[tokio::test]
+    async fn test_connection_listener_in_runtime() {
+        let mut test = TestStreamConnectionBuilder::new().build().await;
 
-        let mut ipc_sender = stream.ipc_sender.clone();
-        ipc_sender.blocking_send(StreamerIpcMessage::WebSocket(
-            StreamServerMessage::DebugLog {
-                message: format!("Completed Stage: {}", stage.name()),
-                ty: None,
-            },
+        let (sender, events, handle) = fake_transport::new();
+        test.connection
+            .set_peer_transport(PeerId(1), Box::new(sender), Box::new(events))
+            .await;
+        connect_peer(&test, PeerId(1), Some(PlayerSlot::PLAYER_1), RoomRole::Host).await;
+        connect_gamepad(&test, PeerId(1)).await;
+
+        // Moonlight can call the listener from a runtime thread, this must not block on the runtime
+        let mut listener = StreamConnectionListener::new(&test.connection);
+        listener.connection_status_update(ConnectionStatus::Poor);
+        listener.controller_rumble(0, 1, 2);
+        listener.connection_terminated(-1);
+
+        let message = timeout(TIMEOUT, test.ipc_receiver.recv())
+            .await
+            .expect("no ipc message received");
+        assert!(matches!(
+            message,
+            Some(StreamerIpcMessage::WebSocket(
+                StreamServerMessage::ConnectionTerminated {
+                    error_code: -1,
+                    reason: TerminationReason::Other(-1),
+                    ..
+                }
+            ))
         ));
-    }
 
-    fn stage_failed(&mut self, stage: Stage, error_code: i32) {
-        let Some(stream) = self.stream.upgrade() else {
-            warn!("Failed to get stream because it is already deallocated");
-            return;
-        };
+        let message = timeout(TIMEOUT, test.ipc_receiver.recv())
+            .await
+            .expect("no ipc message received");
+        assert!(matches!(message, Some(StreamerIpcMessage::Stop)));
 
-        let mut ipc_sender = stream.ipc_sender.clone();
-        ipc_sender.blocking_send(StreamerIpcMessage::WebSocket(
-            StreamServerMessage::DebugLog {
-                message: format!(
-                    "Failed Stage: {} with error code {}",
-                    stage.name(),
-                    error_code
-                ),
-                ty: Some(LogMessageType::Fatal),
-            },
+        // The events are handled in the order moonlight sent them
+        let packets = handle.take_packets();
+        assert_eq!(packets.len(), 2);
+        assert!(matches!(packets[0], OutboundPacket::General { .. }));
+        assert!(matches!(
+            packets[1],
+            OutboundPacket::ControllerRumble {
+                controller_number: 0,
+                low_frequency_motor: 1,
+                high_frequency_motor: 2,
+            }
         ));
+        assert_eq!(handle.close_count(), 1);
     }
 
-    fn connection_started(&mut self) {}
+    #[tokio::test]
+    async fn test_stage_watchdog() {
+        const STAGE_TIMEOUT: Duration = Duration::from_millis(50);
 
-    fn connection_terminated(&mut self, error_code: i32) {
-        let Some(stream) = self.stream.upgrade() else {
-            warn!("Failed to get stream because it is already deallocated");
-            return;
-        };
+        async fn expect_debug_log(test: &mut TestStreamConnection) -> Option<LogMessageType> {
+            let message = timeout(TIMEOUT, test.ipc_receiver.recv())
+                .await
+                .expect("no ipc message received");
+            match message {
+                Some(StreamerIpcMessage::WebSocket(StreamServerMessage::DebugLog {
+                    ty, ..
+                })) => ty,
+                message => panic!("expected a debug log, got {message:?}"),
+            }
+        }
+        async fn expect_no_message(test: &mut TestStreamConnection) {
+            assert!(
+                timeout(STAGE_TIMEOUT * 4, test.ipc_receiver.recv())
+                    .await
+                    .is_err()
+            );
+        }
 
-        let mut ipc_sender = stream.ipc_sender.clone();
-        ipc_sender.blocking_send(StreamerIpcMessage::WebSocket(
-            StreamServerMessage::ConnectionTerminated { error_code },
+        // A stage which completes in time cancels the watchdog
+        let mut test = TestStreamConnectionBuilder::new()
+            .stage_timeout(STAGE_TIMEOUT)
+            .build()
+            .await;
+        let mut listener = StreamConnectionListener::new(&test.connection);
+        listener.stage_starting(Stage::RtspHandshake);
+        listener.stage_complete(Stage::RtspHandshake);
+
+        assert_eq!(expect_debug_log(&mut test).await, None);
+        assert_eq!(expect_debug_log(&mut test).await, None);
+        expect_no_message(&mut test).await;
+
+        // A stuck stage aborts the start
+        listener.stage_starting(Stage::ControlStreamInit);
+
+        assert_eq!(expect_debug_log(&mut test).await, None);
+        assert_eq!(
+            expect_debug_log(&mut test).await,
+            Some(LogMessageType::Fatal)
+        );
+        let message = timeout(TIMEOUT, test.ipc_receiver.recv())
+            .await
+            .expect("no ipc message received");
+        assert!(matches!(
+            message,
+            Some(StreamerIpcMessage::WebSocket(
+                StreamServerMessage::ConnectionTerminated {
+                    reason: TerminationReason::StageTimeout,
+                    ..
+                }
+            ))
         ));
+        let message = timeout(TIMEOUT, test.ipc_receiver.recv())
+            .await
+            .expect("no ipc message received");
+        assert!(matches!(message, Some(StreamerIpcMessage::Stop)));
 
-        stream.runtime.clone().block_on(async move {
-            stream.stop().await;
-        });
-    }
+        // The watchdog doesn't fire after a deliberate stop
+        let mut test = TestStreamConnectionBuilder::new()
+            .stage_timeout(STAGE_TIMEOUT)
+            .build()
+            .await;
+        let mut listener = StreamConnectionListener::new(&test.connection);
+        listener.stage_starting(Stage::RtspHandshake);
+        assert_eq!(expect_debug_log(&mut test).await, None);
 
-    fn log_message(&mut self, message: &str) {
-        info!(target: "moonlight", "{}", message.trim());
+        test.connection.stop().await;
+
+        let message = timeout(TIMEOUT, test.ipc_receiver.recv())
+            .await
+            .expect("no ipc message received");
+        assert!(matches!(message, Some(StreamerIpcMessage::Stop)));
+        expect_no_message(&mut test).await;
     }
 
-    fn connection_status_update(&mut self, status: ConnectionStatus) {
-        let Some(stream) = self.stream.upgrade() else {
-            warn!("Failed to get stream because it is already deallocated");
-            return;
-        };
+    #[tokio::test]
+    async fn test_stop_is_idempotent() {
+        let mut test = TestStreamConnectionBuilder::new().build().await;
 
-        stream.clone().runtime.block_on(async move {
-            stream
-                .try_send_packet(
-                    OutboundPacket::General {
-                        message: GeneralServerMessage::ConnectionStatusUpdate {
-                            status: status.into(),
-                        },
-                    },
-                    "connection status update",
-                    true,
-                )
-                .await
-        })
-    }
+        let (sender, events, handle) = fake_transport::new();
+        test.connection
+            .set_peer_transport(PeerId(1), Box::new(sender), Box::new(events))
+            .await;
 
-    fn set_hdr_mode(&mut self, _hdr_enabled: bool) {}
+        test.connection.stop().await;
+        test.connection.stop().await;
 
-    fn controller_rumble(
-        &mut self,
-        controller_number: u16,
-        low_frequency_motor: u16,
-        high_frequency_motor: u16,
-    ) {
-        let Some(stream) = self.stream.upgrade() else {
-            warn!("Failed to get stream because it is already deallocated");
-            return;
-        };
+        assert_eq!(handle.close_count(), 1);
+        assert!(test.connection.peer_transports.read().await.is_empty());
 
-        stream.runtime.clone().block_on(async move {
-            stream
-                .try_send_packet(
-                    OutboundPacket::ControllerRumble {
-                        controller_number: controller_number as u8,
-                        low_frequency_motor,
-                        high_frequency_motor,
-                    },
-                    "controller rumble",
-                    true,
-                )
-                .await;
-        });
-    }
+        // Messages are received in order, so the marker must directly follow the only stop
+        const MARKER: PeerId = PeerId(u64::MAX);
+        let mut ipc_sender = test.connection.ipc_sender.clone();
+        ipc_sender
+            .send(StreamerIpcMessage::PeerReady { peer_id: MARKER })
+            .await;
 
-    fn controller_rumble_triggers(
-        &mut self,
-        controller_number: u16,
-        left_trigger_motor: u16,
-        right_trigger_motor: u16,
-    ) {
-        let Some(stream) = self.stream.upgrade() else {
-            warn!("Failed to get stream because it is already deallocated");
-            return;
-        };
+        let message = timeout(TIMEOUT, test.ipc_receiver.recv())
+            .await
+            .expect("no ipc message received");
+        assert!(matches!(message, Some(StreamerIpcMessage::Stop)));
 
-        stream.runtime.clone().block_on(async move {
-            stream
-                .try_send_packet(
-                    OutboundPacket::ControllerTriggerRumble {
-                        controller_number: controller_number as u8,
-                        left_trigger_motor,
-                        right_trigger_motor,
-                    },
-                    "controller rumble triggers",
-                    true,
-                )
-                .await;
-        });
+        let message = timeout(TIMEOUT, test.ipc_receiver.recv())
+            .await
+            .expect("no ipc message received");
+        assert!(matches!(
+            message,
+            Some(StreamerIpcMessage::PeerReady { peer_id: MARKER })
+        ));
     }
 
-    fn controller_set_motion_event_state(
-        &mut self,
-        _controller_number: u16,
-        _motion_type: u8,
-        _report_rate_hz: u16,
-    ) {
-        // unsupported: https://github.com/w3c/gamepad/issues/211
-    }
+    #[test]
+    fn test_stage_timings() {
+        let start = Instant::now();
+        let mut timings = StageTimings::new(start);
 
-    fn controller_set_adaptive_triggers(
-        &mut self,
-        _controller_number: u16,
-        _event_flags: u8,
-        _type_left: u8,
-        _type_right: u8,
-        _left: &mut u8,
-        _right: &mut u8,
-    ) {
-        // unsupported
-    }
+        timings.stage_starting(start + Duration::from_millis(1500));
+        timings.stage_complete("RTSP handshake", start + Duration::from_millis(1550));
+        timings.stage_starting(start + Duration::from_millis(1560));
+        timings.stage_complete(
+            "Video stream initialization",
+            start + Duration::from_millis(1580),
+        );
 
-    fn controller_set_led(&mut self, _controller_number: u16, _r: u8, _g: u8, _b: u8) {
-        // unsupported
+        let stages = timings
+            .timings()
+            .into_iter()
+            .map(|timing| (timing.stage, timing.duration_ms))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            stages,
+            [
+                ("Launch App".to_string(), 1500),
+                ("RTSP handshake".to_string(), 50),
+                ("Video stream initialization".to_string(), 20),
+            ]
+        );
     }
 }
@@ -1,18 +1,44 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    mem,
+    time::{Duration, Instant},
+};
 
 use common::{
-    api_bindings::{PlayerSlot, RoomRole},
+    api_bindings::{ControllerMode, GeneralServerMessage, PeerPermissions, PlayerSlot, RoomRole},
     ipc::PeerId,
 };
 use log::{debug, warn};
+use moonlight_common::stream::bindings::{
+    ControllerButtons, ControllerCapabilities, ControllerType, MouseButton,
+};
+
+/// How long the gamepad states of all peers are collected before the merged state is sent in the shared controller mode
+pub const SHARED_GAMEPAD_MERGE_INTERVAL: Duration = Duration::from_millis(8);
+
+/// Stats aren't sent more often than this so they can't compete with the stream
+pub const MIN_STATS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The oldest room events are dropped if the host doesn't become ready for this many
+pub const MAX_QUEUED_ROOM_EVENTS: usize = 32;
 
 /// Manages the mapping between peers and their player slots/roles
 #[derive(Debug, Default)]
 pub struct PeerManager {
     /// Map from peer ID to peer info
     peers: HashMap<PeerId, PeerInfo>,
-    /// Whether guests (non-host players) can use keyboard/mouse
+    /// Whether guests (non-host players) can use keyboard/mouse when they join
     guests_keyboard_mouse_enabled: bool,
+    /// How long spectators see the stream after the players
+    spectator_delay: Duration,
+    /// How the gamepads of the peers are mapped to the gamepads of the host
+    controller_mode: ControllerMode,
+    /// The merged gamepad of all peers in the shared controller mode
+    shared_gamepad: SharedGamepad,
+    /// Set when the host doesn't want to be told when someone joins or leaves
+    room_events_muted: bool,
+    /// The room events which weren't sent yet because the transport of the host isn't ready
+    queued_room_events: VecDeque<GeneralServerMessage>,
 }
 
 #[derive(Debug, Clone)]
@@ -21,10 +47,169 @@ pub struct PeerInfo {
     pub player_slot: Option<PlayerSlot>,
     /// Role in the room
     pub role: RoomRole,
+    /// Which input this peer may send
+    pub permissions: PeerPermissions,
+    /// How long video and audio are held back for this peer, zero for players
+    pub stream_delay: Duration,
     #[allow(dead_code)]
     pub video_frame_queue_size: usize,
     #[allow(dead_code)]
     pub audio_sample_queue_size: usize,
+    /// The player slots of the additional gamepads of this peer, the gamepad with id 1 uses the first one
+    pub local_slots: Vec<PlayerSlot>,
+    /// The gamepads of this peer by their id in the browser,
+    /// kept to connect them again when the controller mode changes
+    pub gamepads: BTreeMap<u8, GamepadArrival>,
+    /// The additional gamepads which the browser connected before this peer got a local slot for them
+    pub pending_gamepads: BTreeMap<u8, GamepadArrival>,
+    /// How often this peer wants the stats of the stream, None if it doesn't show them
+    pub stats_interval: Option<Duration>,
+    /// Every how many rendered frames this peer reports a frame for the latency stats, None if it doesn't
+    pub frame_sample_interval: Option<u16>,
+    /// Whether this peer sends relative mouse movement or absolute positions,
+    /// None if it sends both or didn't tell the streamer yet
+    pub relative_mouse: Option<bool>,
+    /// The name shown to the other participants
+    pub name: Option<String>,
+    /// The keys and mouse buttons this peer pressed on the host and didn't release yet
+    pub held_input: HeldInput,
+}
+
+/// The keys and mouse buttons a peer holds on the host,
+/// they must be released when the peer loses the permission because its key up would be dropped
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HeldInput {
+    /// The key codes sent by the client, before they are translated for the host
+    pub keys: BTreeSet<u16>,
+    pub mouse_buttons: BTreeSet<MouseButton>,
+}
+
+impl HeldInput {
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty() && self.mouse_buttons.is_empty()
+    }
+}
+
+/// How a browser described its gamepad when it was connected
+#[derive(Debug, Clone, Copy)]
+pub struct GamepadArrival {
+    pub ty: ControllerType,
+    pub supported_buttons: ControllerButtons,
+    pub capabilities: ControllerCapabilities,
+}
+
+/// How the gamepads of the host change with the local slots of a peer
+#[derive(Debug, Default)]
+pub struct LocalSlotsChange {
+    /// These lost their owner and must be disconnected
+    pub removed: Vec<u8>,
+    /// The pending gamepads of the peer which got a slot, these must be connected
+    pub added: Vec<(u8, GamepadArrival)>,
+}
+
+/// The state of a gamepad as sent by a browser
+#[derive(Debug, Clone, Copy)]
+pub struct GamepadState {
+    pub buttons: ControllerButtons,
+    pub left_trigger: u8,
+    pub right_trigger: u8,
+    pub left_stick_x: i16,
+    pub left_stick_y: i16,
+    pub right_stick_x: i16,
+    pub right_stick_y: i16,
+}
+
+impl GamepadState {
+    /// No buttons pressed and all axes centered
+    pub const NEUTRAL: GamepadState = GamepadState {
+        buttons: ControllerButtons::empty(),
+        left_trigger: 0,
+        right_trigger: 0,
+        left_stick_x: 0,
+        left_stick_y: 0,
+        right_stick_x: 0,
+        right_stick_y: 0,
+    };
+}
+
+/// Merges the gamepads of all peers into the first gamepad of the host in the shared controller mode.
+///
+/// The buttons of all peers are combined, every axis uses the value of the peer
+/// which most recently sent a state with that axis moved.
+#[derive(Debug, Default)]
+pub struct SharedGamepad {
+    /// The latest state of every peer with a connected gamepad and when it was received
+    peers: HashMap<PeerId, (GamepadState, Instant)>,
+    /// Set when a state changed since the last merge
+    changed: bool,
+}
+
+impl SharedGamepad {
+    /// Adds the gamepad of a peer, true if it's the first one and the shared gamepad must be connected
+    pub fn connect(&mut self, peer_id: PeerId, now: Instant) -> bool {
+        let first = self.peers.is_empty();
+        self.peers
+            .entry(peer_id)
+            .or_insert((GamepadState::NEUTRAL, now));
+        first
+    }
+
+    /// Removes the gamepad of a peer, true if it was the last one and the shared gamepad must be disconnected
+    pub fn disconnect(&mut self, peer_id: PeerId) -> bool {
+        if self.peers.remove(&peer_id).is_none() {
+            return false;
+        }
+
+        // The buttons held by this peer must be released
+        self.changed = true;
+        self.peers.is_empty()
+    }
+
+    /// Updates the state of a peer, true if a merge needs to be scheduled
+    pub fn update(&mut self, peer_id: PeerId, state: GamepadState, now: Instant) -> bool {
+        let Some(entry) = self.peers.get_mut(&peer_id) else {
+            return false;
+        };
+        *entry = (state, now);
+
+        let scheduled = self.changed;
+        self.changed = true;
+        !scheduled
+    }
+
+    /// The merged state of all peers, None if nothing changed since the last merge
+    pub fn merge(&mut self) -> Option<GamepadState> {
+        if !self.changed {
+            return None;
+        }
+        self.changed = false;
+
+        let mut states = self.peers.values().collect::<Vec<_>>();
+        // The most recent state comes first
+        states.sort_by(|(_, a), (_, b)| b.cmp(a));
+
+        let latest = |axis: fn(&GamepadState) -> i16| {
+            states
+                .iter()
+                .map(|(state, _)| axis(state))
+                .find(|value| *value != 0)
+                .unwrap_or(0)
+        };
+
+        Some(GamepadState {
+            buttons: states
+                .iter()
+                .fold(ControllerButtons::empty(), |buttons, (state, _)| {
+                    buttons | state.buttons
+                }),
+            left_trigger: latest(|state| state.left_trigger as i16) as u8,
+            right_trigger: latest(|state| state.right_trigger as i16) as u8,
+            left_stick_x: latest(|state| state.left_stick_x),
+            left_stick_y: latest(|state| state.left_stick_y),
+            right_stick_x: latest(|state| state.right_stick_x),
+            right_stick_y: latest(|state| state.right_stick_y),
+        })
+    }
 }
 
 impl PeerManager {
@@ -32,13 +217,172 @@ impl PeerManager {
         Self {
             peers: HashMap::new(),
             guests_keyboard_mouse_enabled: false,
+            spectator_delay: Duration::ZERO,
+            controller_mode: ControllerMode::PerPlayer,
+            shared_gamepad: SharedGamepad::default(),
+            room_events_muted: false,
+            queued_room_events: VecDeque::new(),
+        }
+    }
+
+    pub fn controller_mode(&self) -> ControllerMode {
+        self.controller_mode
+    }
+
+    /// Set how the gamepads are mapped, the gamepads of the host must be disconnected before this
+    pub fn set_controller_mode(&mut self, mode: ControllerMode) {
+        debug!("Setting controller mode: {:?}", mode);
+        self.controller_mode = mode;
+        self.shared_gamepad = SharedGamepad::default();
+    }
+
+    /// The merged gamepad of all peers, only used in the shared controller mode
+    pub fn shared_gamepad_mut(&mut self) -> &mut SharedGamepad {
+        &mut self.shared_gamepad
+    }
+
+    /// Remember a gamepad of a peer by its id in the browser, None if it was disconnected
+    pub fn set_peer_gamepad(
+        &mut self,
+        peer_id: PeerId,
+        browser_gamepad_id: u8,
+        gamepad: Option<GamepadArrival>,
+    ) {
+        if let Some(info) = self.peers.get_mut(&peer_id) {
+            match gamepad {
+                Some(gamepad) => info.gamepads.insert(browser_gamepad_id, gamepad),
+                None => info.gamepads.remove(&browser_gamepad_id),
+            };
+        }
+    }
+
+    /// Get every connected gamepad of the peers together with its id in the browser
+    pub fn peer_gamepads(&self) -> impl Iterator<Item = (PeerId, u8, GamepadArrival)> + '_ {
+        self.peers.iter().flat_map(|(peer_id, info)| {
+            info.gamepads
+                .iter()
+                .map(|(browser_gamepad_id, arrival)| (*peer_id, *browser_gamepad_id, *arrival))
+        })
+    }
+
+    /// Remember an additional gamepad of a peer which has no local slot yet, None if it was disconnected.
+    /// The browser can connect it before the web server assigned the slot, it's mapped by [Self::set_local_slots].
+    pub fn set_pending_gamepad(
+        &mut self,
+        peer_id: PeerId,
+        browser_gamepad_id: u8,
+        gamepad: Option<GamepadArrival>,
+    ) {
+        let Some(info) = self.peers.get_mut(&peer_id) else {
+            return;
+        };
+        if browser_gamepad_id == 0 || info.player_slot.is_none() {
+            return;
+        }
+
+        match gamepad {
+            Some(gamepad) => info.pending_gamepads.insert(browser_gamepad_id, gamepad),
+            None => info.pending_gamepads.remove(&browser_gamepad_id),
+        };
+    }
+
+    /// Set the player slots of the additional gamepads of a peer.
+    /// The gamepads which lose their slot are pending until they get a slot again.
+    pub fn set_local_slots(&mut self, peer_id: PeerId, slots: Vec<PlayerSlot>) -> LocalSlotsChange {
+        let Some(info) = self.peers.get(&peer_id) else {
+            return LocalSlotsChange::default();
+        };
+        debug!("Setting local slots of peer {:?}: {:?}", peer_id, slots);
+
+        // The gamepads with an id past the new slots aren't mapped anymore
+        let removed_ids = info
+            .gamepads
+            .keys()
+            .copied()
+            .filter(|id| {
+                *id != 0 && slots.get(*id as usize - 1) != info.local_slots.get(*id as usize - 1)
+            })
+            .collect::<Vec<_>>();
+        let removed = removed_ids
+            .iter()
+            .filter_map(|id| self.map_gamepad_id(peer_id, *id))
+            .collect();
+
+        let Some(info) = self.peers.get_mut(&peer_id) else {
+            return LocalSlotsChange::default();
+        };
+        info.local_slots = slots;
+        for id in removed_ids {
+            if let Some(arrival) = info.gamepads.remove(&id) {
+                info.pending_gamepads.insert(id, arrival);
+            }
+        }
+
+        let pending = mem::take(&mut info.pending_gamepads);
+        let mut added = Vec::new();
+        for (browser_gamepad_id, arrival) in pending {
+            match self.map_gamepad_id(peer_id, browser_gamepad_id) {
+                Some(id) => {
+                    self.set_peer_gamepad(peer_id, browser_gamepad_id, Some(arrival));
+                    added.push((id, arrival));
+                }
+                None => self.set_pending_gamepad(peer_id, browser_gamepad_id, Some(arrival)),
+            }
+        }
+
+        LocalSlotsChange { removed, added }
+    }
+
+    /// Set the stream delay of all current and future spectators
+    pub fn set_spectator_delay(&mut self, delay: Duration) {
+        debug!("Setting spectator delay: {:?}", delay);
+        self.spectator_delay = delay;
+
+        for info in self.peers.values_mut() {
+            info.stream_delay = Self::stream_delay_for(info.role, delay);
+        }
+    }
+
+    /// Players are never delayed so they can react to what's happening
+    fn stream_delay_for(role: RoomRole, spectator_delay: Duration) -> Duration {
+        if role.is_spectator() {
+            spectator_delay
+        } else {
+            Duration::ZERO
         }
     }
 
-    /// Set whether guests can use keyboard/mouse
-    pub fn set_guests_keyboard_mouse_enabled(&mut self, enabled: bool) {
+    /// Set whether guests can use keyboard/mouse, this changes the permissions of all current guests.
+    /// Returns the guests whose permissions were changed.
+    pub fn set_guests_keyboard_mouse_enabled(&mut self, enabled: bool) -> Vec<PeerId> {
         debug!("Setting guests keyboard/mouse enabled: {}", enabled);
         self.guests_keyboard_mouse_enabled = enabled;
+
+        let mut guests = Vec::new();
+        for (peer_id, info) in &mut self.peers {
+            if info.role == RoomRole::Player {
+                info.permissions.set_keyboard_mouse(enabled);
+                guests.push(*peer_id);
+            }
+        }
+        guests
+    }
+
+    /// Set which input a player can use, false if the peer is unknown, the host or a spectator
+    pub fn set_peer_permissions(&mut self, peer_id: PeerId, permissions: PeerPermissions) -> bool {
+        let Some(info) = self.peers.get_mut(&peer_id) else {
+            return false;
+        };
+        if info.role != RoomRole::Player {
+            return false;
+        }
+
+        debug!(
+            "Setting permissions of peer {:?}: {:?}",
+            peer_id, permissions
+        );
+        info.permissions = permissions;
+        true
     }
 
     /// Get whether guests can use keyboard/mouse
@@ -55,6 +399,7 @@ impl PeerManager {
         role: RoomRole,
         video_frame_queue_size: usize,
         audio_sample_queue_size: usize,
+        name: Option<String>,
     ) {
         debug!(
             "Adding peer {:?} as {:?} (slot: {:?})",
@@ -65,8 +410,18 @@ impl PeerManager {
             PeerInfo {
                 player_slot,
                 role,
+                permissions: PeerPermissions::default_for(role, self.guests_keyboard_mouse_enabled),
+                stream_delay: Self::stream_delay_for(role, self.spectator_delay),
                 video_frame_queue_size,
                 audio_sample_queue_size,
+                local_slots: Vec::new(),
+                gamepads: BTreeMap::new(),
+                pending_gamepads: BTreeMap::new(),
+                stats_interval: None,
+                frame_sample_interval: None,
+                relative_mouse: None,
+                name,
+                held_input: HeldInput::default(),
             },
         );
     }
@@ -85,11 +440,17 @@ impl PeerManager {
             );
             info.role = new_role;
             info.player_slot = player_slot;
+            if player_slot.is_none() {
+                info.local_slots.clear();
+                info.pending_gamepads.clear();
+            }
+            info.permissions =
+                PeerPermissions::default_for(new_role, self.guests_keyboard_mouse_enabled);
+            info.stream_delay = Self::stream_delay_for(new_role, self.spectator_delay);
         }
     }
 
     /// Check if a peer is a spectator
-    #[allow(dead_code)]
     pub fn is_spectator(&self, peer_id: PeerId) -> bool {
         self.peers
             .get(&peer_id)
@@ -118,6 +479,41 @@ impl PeerManager {
         self.peers.get(&peer_id)
     }
 
+    /// The peer which created the room
+    pub fn host(&self) -> Option<PeerId> {
+        self.peers
+            .iter()
+            .find(|(_, info)| info.role == RoomRole::Host)
+            .map(|(peer_id, _)| *peer_id)
+    }
+
+    /// Set whether the host receives room events, the queued events are dropped when disabled
+    pub fn set_room_events_enabled(&mut self, enabled: bool) {
+        debug!("Setting room events enabled: {}", enabled);
+        self.room_events_muted = !enabled;
+        if !enabled {
+            self.queued_room_events.clear();
+        }
+    }
+
+    /// Remember a room event until the host can receive it, false if the host muted room events
+    pub fn queue_room_event(&mut self, message: GeneralServerMessage) -> bool {
+        if self.room_events_muted {
+            return false;
+        }
+
+        if self.queued_room_events.len() >= MAX_QUEUED_ROOM_EVENTS {
+            self.queued_room_events.pop_front();
+        }
+        self.queued_room_events.push_back(message);
+        true
+    }
+
+    /// Take the room events which should be sent to the host, oldest first
+    pub fn take_room_events(&mut self) -> Vec<GeneralServerMessage> {
+        self.queued_room_events.drain(..).collect()
+    }
+
     /// Get queue sizes for a peer
     pub fn get_peer_queue_sizes(&self, peer_id: PeerId) -> Option<(usize, usize)> {
         self.peers.get(&peer_id).map(|info| {
@@ -131,27 +527,12 @@ impl PeerManager {
         self.peers.get(&peer_id).and_then(|info| info.player_slot)
     }
 
-    /// Check if a peer can use keyboard/mouse
-    /// Only players can use KB/mouse. Spectators never can.
-    /// Among players: Host always can; guests can only if explicitly enabled
-    pub fn can_use_keyboard_mouse(&self, peer_id: PeerId) -> bool {
+    /// Get which input a peer can use, nothing if the peer is unknown
+    pub fn permissions(&self, peer_id: PeerId) -> PeerPermissions {
         self.peers
             .get(&peer_id)
-            .map(|info| {
-                // Spectators can never use keyboard/mouse
-                if info.role.is_spectator() {
-                    return false;
-                }
-
-                // Host can always use keyboard/mouse
-                if info.role.is_host() {
-                    return true;
-                }
-
-                // Other players can only if enabled
-                self.guests_keyboard_mouse_enabled
-            })
-            .unwrap_or(false)
+            .map(|info| info.permissions)
+            .unwrap_or(PeerPermissions::NONE)
     }
 
     /// Map a gamepad ID from a peer to the actual gamepad slot
@@ -165,27 +546,156 @@ impl PeerManager {
     /// - Player 3's gamepad 0 -> slot 2
     /// - Player 4's gamepad 0 -> slot 3
     ///
-    /// Each player only gets one gamepad slot.
+    /// A player with multiple gamepads on the same machine gets an additional slot for each one
+    /// if the host allows it, gamepad 1 uses the first local slot and so on.
+    /// In the shared controller mode all players use slot 0 with their first gamepad.
     /// Spectators cannot use gamepads.
     pub fn map_gamepad_id(&self, peer_id: PeerId, browser_gamepad_id: u8) -> Option<u8> {
         let info = self.peers.get(&peer_id)?;
 
         // Spectators cannot use gamepads
-        if info.role.is_spectator() {
+        if info.role.is_spectator() || !info.permissions.allow_gamepad {
             return None;
         }
 
-        // Only the first gamepad from each player is used
-        if browser_gamepad_id != 0 {
+        // Get the player slot (should exist since we checked for spectator)
+        let slot = info.player_slot?;
+
+        let slot = match (self.controller_mode, browser_gamepad_id) {
+            (ControllerMode::PerPlayer, 0) => Some(slot),
+            (ControllerMode::PerPlayer, id) => info.local_slots.get(id as usize - 1).copied(),
+            (ControllerMode::Shared, 0) => return Some(0),
+            // Only the first gamepad from each player is shared
+            (ControllerMode::Shared, _) => None,
+        };
+        if slot.is_none() {
             warn!(
-                "Peer {:?} tried to use gamepad {} but only gamepad 0 is supported per player",
+                "Peer {:?} tried to use gamepad {} but it has no player slot for it",
                 peer_id, browser_gamepad_id
             );
-            return None;
         }
 
-        // Get the player slot (should exist since we checked for spectator)
-        info.player_slot.map(|slot| slot.gamepad_slot())
+        slot.map(|slot| slot.gamepad_slot())
+    }
+
+    /// The id in the browser of the gamepad of a peer which is mapped to this gamepad of the host
+    pub fn browser_gamepad_id(&self, peer_id: PeerId, gamepad_slot: u8) -> Option<u8> {
+        let info = self.peers.get(&peer_id)?;
+
+        match self.controller_mode {
+            ControllerMode::PerPlayer => std::iter::once(info.player_slot?)
+                .chain(info.local_slots.iter().copied())
+                .position(|slot| slot.gamepad_slot() == gamepad_slot)
+                .map(|id| id as u8),
+            ControllerMode::Shared => (gamepad_slot == 0).then_some(0),
+        }
+    }
+
+    /// Get the peers with a gamepad connected which is mapped to this gamepad of the host,
+    /// together with the id of the gamepad in their browser.
+    /// These receive the rumble of the gamepad.
+    pub fn gamepad_owners(&self, gamepad_slot: u8) -> Vec<(PeerId, u8)> {
+        self.peers
+            .iter()
+            .filter_map(|(peer_id, info)| {
+                let id = self.browser_gamepad_id(*peer_id, gamepad_slot)?;
+                (info.gamepads.contains_key(&id)
+                    && self.map_gamepad_id(*peer_id, id) == Some(gamepad_slot))
+                .then_some((*peer_id, id))
+            })
+            .collect()
+    }
+
+    /// The rendered frames are only reported with the stats
+    pub fn set_stats_interval(
+        &mut self,
+        peer_id: PeerId,
+        interval: Option<Duration>,
+        frame_sample_interval: Option<u16>,
+    ) {
+        if let Some(peer) = self.peers.get_mut(&peer_id) {
+            peer.stats_interval = interval.map(|interval| interval.max(MIN_STATS_INTERVAL));
+            peer.frame_sample_interval = frame_sample_interval.filter(|_| interval.is_some());
+        }
+    }
+
+    /// Whether the peer reports the rendered frames, false if the peer is unknown
+    pub fn reports_frames(&self, peer_id: PeerId) -> bool {
+        self.peers
+            .get(&peer_id)
+            .is_some_and(|info| info.frame_sample_interval.is_some())
+    }
+
+    /// Set whether the peer sends relative mouse movement, false if the peer is unknown
+    pub fn set_relative_mouse(&mut self, peer_id: PeerId, relative: Option<bool>) -> bool {
+        let Some(info) = self.peers.get_mut(&peer_id) else {
+            return false;
+        };
+
+        debug!(
+            "Setting relative mouse of peer {:?}: {:?}",
+            peer_id, relative
+        );
+        info.relative_mouse = relative;
+        true
+    }
+
+    /// Remember whether a key of the peer is held on the host
+    pub fn set_key_held(&mut self, peer_id: PeerId, key: u16, held: bool) {
+        if let Some(info) = self.peers.get_mut(&peer_id) {
+            if held {
+                info.held_input.keys.insert(key);
+            } else {
+                info.held_input.keys.remove(&key);
+            }
+        }
+    }
+
+    /// Remember whether a mouse button of the peer is held on the host
+    pub fn set_mouse_button_held(&mut self, peer_id: PeerId, button: MouseButton, held: bool) {
+        if let Some(info) = self.peers.get_mut(&peer_id) {
+            if held {
+                info.held_input.mouse_buttons.insert(button);
+            } else {
+                info.held_input.mouse_buttons.remove(&button);
+            }
+        }
+    }
+
+    /// Takes the held keys and mouse buttons the peer isn't allowed to use anymore,
+    /// they must be released on the host
+    pub fn take_revoked_input(&mut self, peer_id: PeerId) -> HeldInput {
+        let Some(info) = self.peers.get_mut(&peer_id) else {
+            return HeldInput::default();
+        };
+
+        let mut revoked = HeldInput::default();
+        if !info.permissions.allow_keyboard {
+            revoked.keys = mem::take(&mut info.held_input.keys);
+        }
+        if !info.permissions.allow_mouse {
+            revoked.mouse_buttons = mem::take(&mut info.held_input.mouse_buttons);
+        }
+        revoked
+    }
+
+    /// Get whether the peer sends relative mouse movement, None if both are accepted
+    pub fn relative_mouse(&self, peer_id: PeerId) -> Option<bool> {
+        self.peers.get(&peer_id)?.relative_mouse
+    }
+
+    /// Get the stats interval of every peer which wants the stats
+    pub fn stats_intervals(&self) -> impl Iterator<Item = (PeerId, Duration)> + '_ {
+        self.peers
+            .iter()
+            .filter_map(|(peer_id, info)| Some((*peer_id, info.stats_interval?)))
+    }
+
+    /// Get the stream delay of every peer
+    pub fn stream_delays(&self) -> impl Iterator<Item = (PeerId, Duration)> + '_ {
+        self.peers
+            .iter()
+            .map(|(peer_id, info)| (*peer_id, info.stream_delay))
     }
 
     /// Get all peer IDs
@@ -209,6 +719,8 @@ impl PeerManager {
 
 #[cfg(test)]
 mod tests {
+    use common::api_bindings::RoomEventKind;
+
     use super::*;
 
     #[test]
@@ -219,9 +731,30 @@ mod tests {
         let peer2 = PeerId(2);
         let peer3 = PeerId(3);
 
-        manager.add_peer(peer1, PlayerSlot::PLAYER_1, 10, 10);
-        manager.add_peer(peer2, PlayerSlot::PLAYER_2, 10, 10);
-        manager.add_peer(peer3, PlayerSlot::PLAYER_3, 10, 10);
+        manager.add_peer(
+            peer1,
+            Some(PlayerSlot::PLAYER_1),
+            RoomRole::Host,
+            10,
+            10,
+            None,
+        );
+        manager.add_peer(
+            peer2,
+            Some(PlayerSlot::PLAYER_2),
+            RoomRole::Player,
+            10,
+            10,
+            None,
+        );
+        manager.add_peer(
+            peer3,
+            Some(PlayerSlot::PLAYER_3),
+            RoomRole::Player,
+            10,
+            10,
+            None,
+        );
 
         // Each player's gamepad 0 maps to their player slot
         assert_eq!(manager.map_gamepad_id(peer1, 0), Some(0));
@@ -239,21 +772,501 @@ mod tests {
         let peer1 = PeerId(1);
         let peer2 = PeerId(2);
 
-        manager.add_peer(peer1, PlayerSlot::PLAYER_1, 10, 10);
-        manager.add_peer(peer2, PlayerSlot::PLAYER_2, 10, 10);
+        manager.add_peer(
+            peer1,
+            Some(PlayerSlot::PLAYER_1),
+            RoomRole::Host,
+            10,
+            10,
+            None,
+        );
+        manager.add_peer(
+            peer2,
+            Some(PlayerSlot::PLAYER_2),
+            RoomRole::Player,
+            10,
+            10,
+            None,
+        );
 
         // By default, only Player 1 can use keyboard/mouse
-        assert!(manager.can_use_keyboard_mouse(peer1));
-        assert!(!manager.can_use_keyboard_mouse(peer2));
+        assert!(manager.permissions(peer1).allow_keyboard);
+        assert!(!manager.permissions(peer2).allow_keyboard);
+        assert!(!manager.permissions(peer2).allow_mouse);
 
         // Enable guests keyboard/mouse
         manager.set_guests_keyboard_mouse_enabled(true);
-        assert!(manager.can_use_keyboard_mouse(peer1));
-        assert!(manager.can_use_keyboard_mouse(peer2));
+        assert!(manager.permissions(peer1).allow_keyboard);
+        assert!(manager.permissions(peer2).allow_keyboard);
+        assert!(manager.permissions(peer2).allow_mouse);
 
         // Disable again
         manager.set_guests_keyboard_mouse_enabled(false);
-        assert!(manager.can_use_keyboard_mouse(peer1));
-        assert!(!manager.can_use_keyboard_mouse(peer2));
+        assert!(manager.permissions(peer1).allow_keyboard);
+        assert!(!manager.permissions(peer2).allow_keyboard);
+        assert!(!manager.permissions(peer2).allow_mouse);
+    }
+
+    #[test]
+    fn test_revoked_input() {
+        let mut manager = PeerManager::new();
+
+        let host = PeerId(1);
+        let guest = PeerId(2);
+
+        manager.add_peer(
+            host,
+            Some(PlayerSlot::PLAYER_1),
+            RoomRole::Host,
+            10,
+            10,
+            None,
+        );
+        manager.add_peer(
+            guest,
+            Some(PlayerSlot::PLAYER_2),
+            RoomRole::Player,
+            10,
+            10,
+            None,
+        );
+
+        assert_eq!(manager.set_guests_keyboard_mouse_enabled(true), vec![guest]);
+        for peer_id in [host, guest] {
+            manager.set_key_held(peer_id, 0x57, true);
+            manager.set_key_held(peer_id, 0x41, true);
+            manager.set_key_held(peer_id, 0x41, false);
+            manager.set_mouse_button_held(peer_id, MouseButton::Left, true);
+        }
+
+        // Nothing is released while the input is allowed
+        assert!(manager.take_revoked_input(guest).is_empty());
+
+        manager.set_guests_keyboard_mouse_enabled(false);
+        assert_eq!(
+            manager.take_revoked_input(guest),
+            HeldInput {
+                keys: BTreeSet::from([0x57]),
+                mouse_buttons: BTreeSet::from([MouseButton::Left]),
+            }
+        );
+        assert!(manager.take_revoked_input(guest).is_empty());
+        assert!(manager.take_revoked_input(host).is_empty());
+
+        // Only the revoked input is released
+        manager.set_guests_keyboard_mouse_enabled(true);
+        manager.set_key_held(guest, 0x57, true);
+        manager.set_mouse_button_held(guest, MouseButton::Right, true);
+        manager.set_peer_permissions(
+            guest,
+            PeerPermissions {
+                allow_mouse: true,
+                ..PeerPermissions::NONE
+            },
+        );
+        assert_eq!(
+            manager.take_revoked_input(guest),
+            HeldInput {
+                keys: BTreeSet::from([0x57]),
+                mouse_buttons: BTreeSet::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_peer_permissions() {
+        let mut manager = PeerManager::new();
+
+        let host = PeerId(1);
+        let player2 = PeerId(2);
+        let player3 = PeerId(3);
+        let spectator = PeerId(4);
+
+        manager.add_peer(
+            host,
+            Some(PlayerSlot::PLAYER_1),
+            RoomRole::Host,
+            10,
+            10,
+            None,
+        );
+        manager.add_peer(
+            player2,
+            Some(PlayerSlot::PLAYER_2),
+            RoomRole::Player,
+            10,
+            10,
+            None,
+        );
+        manager.add_peer(
+            player3,
+            Some(PlayerSlot::PLAYER_3),
+            RoomRole::Player,
+            10,
+            10,
+            None,
+        );
+        manager.add_peer(spectator, None, RoomRole::Spectator, 10, 10, None);
+
+        // Player 2 may use the mouse, player 3 may not even use a gamepad
+        let mouse_only = PeerPermissions {
+            allow_mouse: true,
+            ..PeerPermissions::NONE
+        };
+        assert!(manager.set_peer_permissions(player2, mouse_only));
+        assert!(manager.set_peer_permissions(player3, PeerPermissions::NONE));
+        assert_eq!(manager.permissions(player2), mouse_only);
+        assert_eq!(manager.map_gamepad_id(player2, 0), None);
+        assert_eq!(manager.map_gamepad_id(player3, 0), None);
+
+        // The host and spectators can't be changed
+        assert!(!manager.set_peer_permissions(host, PeerPermissions::NONE));
+        assert!(!manager.set_peer_permissions(spectator, PeerPermissions::ALL));
+        assert_eq!(manager.permissions(host), PeerPermissions::ALL);
+        assert_eq!(manager.permissions(spectator), PeerPermissions::NONE);
+
+        // The bulk toggle only changes keyboard and mouse
+        manager.set_guests_keyboard_mouse_enabled(true);
+        assert!(manager.permissions(player3).allow_keyboard);
+        assert!(!manager.permissions(player3).allow_gamepad);
+        assert_eq!(manager.permissions(spectator), PeerPermissions::NONE);
+
+        // Changing the role resets the permissions
+        manager.update_peer_role(spectator, RoomRole::Player, Some(PlayerSlot::PLAYER_4));
+        assert_eq!(
+            manager.permissions(spectator),
+            PeerPermissions::default_for(RoomRole::Player, true)
+        );
+    }
+
+    #[test]
+    fn test_spectator_delay() {
+        let mut manager = PeerManager::new();
+
+        let host = PeerId(1);
+        let spectator = PeerId(2);
+
+        manager.add_peer(
+            host,
+            Some(PlayerSlot::PLAYER_1),
+            RoomRole::Host,
+            10,
+            10,
+            None,
+        );
+        manager.add_peer(spectator, None, RoomRole::Spectator, 10, 10, None);
+
+        let delay_of = |manager: &PeerManager, peer_id| {
+            manager
+                .get_peer(peer_id)
+                .map(|info| info.stream_delay)
+                .expect("peer not found")
+        };
+
+        // Nobody is delayed by default
+        assert_eq!(delay_of(&manager, spectator), Duration::ZERO);
+
+        // Only spectators are delayed
+        manager.set_spectator_delay(Duration::from_secs(30));
+        assert_eq!(delay_of(&manager, host), Duration::ZERO);
+        assert_eq!(delay_of(&manager, spectator), Duration::from_secs(30));
+
+        // Late spectators get the delay too
+        let late_spectator = PeerId(3);
+        manager.add_peer(late_spectator, None, RoomRole::Spectator, 10, 10, None);
+        assert_eq!(delay_of(&manager, late_spectator), Duration::from_secs(30));
+
+        // Promoted spectators are live, demoted players are delayed
+        manager.update_peer_role(spectator, RoomRole::Player, Some(PlayerSlot::PLAYER_2));
+        assert_eq!(delay_of(&manager, spectator), Duration::ZERO);
+        manager.update_peer_role(spectator, RoomRole::Spectator, None);
+        assert_eq!(delay_of(&manager, spectator), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_shared_controller_mode() {
+        let mut manager = PeerManager::new();
+
+        let host = PeerId(1);
+        let player = PeerId(2);
+        let spectator = PeerId(3);
+
+        manager.add_peer(
+            host,
+            Some(PlayerSlot::PLAYER_1),
+            RoomRole::Host,
+            10,
+            10,
+            None,
+        );
+        manager.add_peer(
+            player,
+            Some(PlayerSlot::PLAYER_2),
+            RoomRole::Player,
+            10,
+            10,
+            None,
+        );
+        manager.add_peer(spectator, None, RoomRole::Spectator, 10, 10, None);
+
+        // Every player controls the first gamepad
+        manager.set_controller_mode(ControllerMode::Shared);
+        assert_eq!(manager.map_gamepad_id(host, 0), Some(0));
+        assert_eq!(manager.map_gamepad_id(player, 0), Some(0));
+        assert_eq!(manager.map_gamepad_id(player, 1), None);
+        assert_eq!(manager.map_gamepad_id(spectator, 0), None);
+
+        manager.set_controller_mode(ControllerMode::PerPlayer);
+        assert_eq!(manager.map_gamepad_id(player, 0), Some(1));
+    }
+
+    #[test]
+    fn test_gamepad_owners() {
+        let mut manager = PeerManager::new();
+
+        let host = PeerId(1);
+        let player = PeerId(2);
+        let spectator = PeerId(3);
+
+        manager.add_peer(
+            host,
+            Some(PlayerSlot::PLAYER_1),
+            RoomRole::Host,
+            10,
+            10,
+            None,
+        );
+        manager.add_peer(
+            player,
+            Some(PlayerSlot::PLAYER_2),
+            RoomRole::Player,
+            10,
+            10,
+            None,
+        );
+        manager.add_peer(spectator, None, RoomRole::Spectator, 10, 10, None);
+
+        let arrival = GamepadArrival {
+            ty: ControllerType::Unknown,
+            supported_buttons: ControllerButtons::all(),
+            capabilities: ControllerCapabilities::empty(),
+        };
+        for peer_id in [host, player, spectator] {
+            manager.set_peer_gamepad(peer_id, 0, Some(arrival));
+        }
+
+        // Only the player of the slot receives the rumble
+        assert_eq!(manager.gamepad_owners(0), vec![(host, 0)]);
+        assert_eq!(manager.gamepad_owners(1), vec![(player, 0)]);
+        assert!(manager.gamepad_owners(2).is_empty());
+
+        // Without a gamepad there's nothing to rumble
+        manager.set_peer_gamepad(player, 0, None);
+        assert!(manager.gamepad_owners(1).is_empty());
+
+        // Every player with a gamepad uses the shared gamepad
+        manager.set_peer_gamepad(player, 0, Some(arrival));
+        manager.set_controller_mode(ControllerMode::Shared);
+        let mut owners = manager.gamepad_owners(0);
+        owners.sort_by_key(|(peer_id, _)| peer_id.0);
+        assert_eq!(owners, vec![(host, 0), (player, 0)]);
+    }
+
+    #[test]
+    fn test_local_gamepads() {
+        let mut manager = PeerManager::new();
+
+        let host = PeerId(1);
+        let player = PeerId(2);
+
+        manager.add_peer(
+            host,
+            Some(PlayerSlot::PLAYER_1),
+            RoomRole::Host,
+            10,
+            10,
+            None,
+        );
+        manager.add_peer(
+            player,
+            Some(PlayerSlot::PLAYER_2),
+            RoomRole::Player,
+            10,
+            10,
+            None,
+        );
+
+        // Without local slots only the first gamepad is used
+        assert_eq!(manager.map_gamepad_id(player, 1), None);
+
+        // The second gamepad of the player uses the local slot
+        let change =
+            manager.set_local_slots(player, vec![PlayerSlot::PLAYER_3, PlayerSlot::PLAYER_4]);
+        assert!(change.removed.is_empty());
+        assert!(change.added.is_empty());
+        assert_eq!(manager.map_gamepad_id(player, 0), Some(1));
+        assert_eq!(manager.map_gamepad_id(player, 1), Some(2));
+        assert_eq!(manager.map_gamepad_id(player, 2), Some(3));
+        assert_eq!(manager.map_gamepad_id(player, 3), None);
+
+        let arrival = GamepadArrival {
+            ty: ControllerType::Unknown,
+            supported_buttons: ControllerButtons::all(),
+            capabilities: ControllerCapabilities::empty(),
+        };
+        manager.set_peer_gamepad(player, 0, Some(arrival));
+        manager.set_peer_gamepad(player, 2, Some(arrival));
+
+        // Rumble goes to the gamepad in the browser which is mapped to the slot
+        assert_eq!(manager.browser_gamepad_id(player, 3), Some(2));
+        assert_eq!(manager.gamepad_owners(3), vec![(player, 2)]);
+        // The gamepad of the first local slot isn't connected
+        assert!(manager.gamepad_owners(2).is_empty());
+
+        // Releasing a slot disconnects the gamepad which used it
+        let change = manager.set_local_slots(player, vec![PlayerSlot::PLAYER_3]);
+        assert_eq!(change.removed, vec![3]);
+        assert!(change.added.is_empty());
+        assert_eq!(manager.map_gamepad_id(player, 2), None);
+        assert!(manager.gamepad_owners(3).is_empty());
+
+        // It's connected again once it gets a slot
+        let change =
+            manager.set_local_slots(player, vec![PlayerSlot::PLAYER_3, PlayerSlot::PLAYER_4]);
+        assert!(change.removed.is_empty());
+        assert_eq!(
+            change.added.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+            vec![3]
+        );
+        assert_eq!(manager.gamepad_owners(3), vec![(player, 2)]);
+        manager.set_local_slots(player, vec![PlayerSlot::PLAYER_3]);
+
+        // Only the first gamepad is shared
+        manager.set_controller_mode(ControllerMode::Shared);
+        assert_eq!(manager.map_gamepad_id(player, 0), Some(0));
+        assert_eq!(manager.map_gamepad_id(player, 1), None);
+        manager.set_controller_mode(ControllerMode::PerPlayer);
+
+        // Spectators lose their local slots
+        manager.update_peer_role(player, RoomRole::Spectator, None);
+        manager.update_peer_role(player, RoomRole::Player, Some(PlayerSlot::PLAYER_2));
+        assert_eq!(manager.map_gamepad_id(player, 1), None);
+    }
+
+    fn state(buttons: ControllerButtons, left_stick_x: i16) -> GamepadState {
+        GamepadState {
+            buttons,
+            left_stick_x,
+            ..GamepadState::NEUTRAL
+        }
+    }
+
+    #[test]
+    fn test_shared_gamepad_merge() {
+        let mut shared = SharedGamepad::default();
+
+        let peer1 = PeerId(1);
+        let peer2 = PeerId(2);
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+
+        // Only the first gamepad connects the shared gamepad
+        assert!(shared.connect(peer1, at(0)));
+        assert!(!shared.connect(peer2, at(0)));
+        assert!(shared.merge().is_none());
+
+        // Only the first change in a merge interval schedules a merge
+        assert!(shared.update(peer1, state(ControllerButtons::A, 1000), at(1)));
+        assert!(!shared.update(peer2, state(ControllerButtons::B, -2000), at(2)));
+
+        // Buttons are combined, the latest moved axis wins
+        let merged = shared.merge().expect("missing merged state");
+        assert_eq!(
+            merged.buttons.bits(),
+            (ControllerButtons::A | ControllerButtons::B).bits()
+        );
+        assert_eq!(merged.left_stick_x, -2000);
+        assert!(shared.merge().is_none());
+
+        // A centered axis doesn't override the other peer
+        assert!(shared.update(peer2, state(ControllerButtons::empty(), 0), at(3)));
+        let merged = shared.merge().expect("missing merged state");
+        assert_eq!(merged.buttons.bits(), ControllerButtons::A.bits());
+        assert_eq!(merged.left_stick_x, 1000);
+
+        // The buttons of a disconnected peer are released
+        assert!(!shared.disconnect(peer1));
+        let merged = shared.merge().expect("missing merged state");
+        assert_eq!(merged.buttons.bits(), 0);
+        assert_eq!(merged.left_stick_x, 0);
+
+        // The state of an unknown gamepad is ignored
+        assert!(!shared.update(peer1, state(ControllerButtons::A, 0), at(4)));
+        assert!(shared.merge().is_none());
+
+        assert!(shared.disconnect(peer2));
+        assert!(!shared.disconnect(peer2));
+    }
+
+    #[test]
+    fn test_room_event_queue() {
+        let mut manager = PeerManager::new();
+
+        let host = PeerId(1);
+        let player = PeerId(2);
+        manager.add_peer(
+            player,
+            Some(PlayerSlot::PLAYER_2),
+            RoomRole::Player,
+            10,
+            10,
+            None,
+        );
+        assert_eq!(manager.host(), None);
+        manager.add_peer(
+            host,
+            Some(PlayerSlot::PLAYER_1),
+            RoomRole::Host,
+            10,
+            10,
+            Some("Host".to_string()),
+        );
+        assert_eq!(manager.host(), Some(host));
+
+        let event = |slot: u8| GeneralServerMessage::RoomEvent {
+            kind: RoomEventKind::Joined,
+            name: None,
+            slot: Some(PlayerSlot(slot)),
+        };
+        let slots = |events: Vec<GeneralServerMessage>| {
+            events
+                .into_iter()
+                .map(|event| match event {
+                    GeneralServerMessage::RoomEvent {
+                        slot: Some(slot), ..
+                    } => slot.0,
+                    _ => unreachable!(),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // Only the newest events are kept
+        for slot in 0..MAX_QUEUED_ROOM_EVENTS as u8 + 2 {
+            assert!(manager.queue_room_event(event(slot)));
+        }
+        let events = slots(manager.take_room_events());
+        assert_eq!(events.len(), MAX_QUEUED_ROOM_EVENTS);
+        assert_eq!(events.first(), Some(&2));
+        assert!(manager.take_room_events().is_empty());
+
+        // Muting drops the queued events
+        assert!(manager.queue_room_event(event(1)));
+        manager.set_room_events_enabled(false);
+        assert!(!manager.queue_room_event(event(2)));
+        assert!(manager.take_room_events().is_empty());
+
+        manager.set_room_events_enabled(true);
+        assert!(manager.queue_room_event(event(3)));
+        assert_eq!(slots(manager.take_room_events()), vec![3]);
     }
 }
@@ -0,0 +1,219 @@
+//! Grades the connection to the host so the clients can show more than the `Ok`/`Poor` of moonlight.
+
+use std::time::{Duration, Instant};
+
+use common::api_bindings::{GeneralServerMessage, QualityLevel};
+use moonlight_common::stream::bindings::ConnectionStatus;
+
+/// How often the quality is measured
+pub const QUALITY_MEASURE_INTERVAL: Duration = Duration::from_secs(1);
+/// A new level is only reported after it held this long, so short blips don't spam the clients
+const QUALITY_DEBOUNCE: Duration = Duration::from_secs(3);
+
+/// The highest round trip time in ms and frame loss in % of every level, checked from the best level down
+const LEVEL_THRESHOLDS: [(QualityLevel, f64, f64); 3] = [
+    (QualityLevel::Excellent, 40.0, 1.0),
+    (QualityLevel::Good, 80.0, 3.0),
+    (QualityLevel::Fair, 150.0, 8.0),
+];
+
+/// Counts the frames lost on the way from the host using the gaps in the frame numbers
+#[derive(Debug, Default)]
+pub struct QualityMonitor {
+    /// Set while moonlight reports a poor connection
+    poor: bool,
+    last_frame: Option<i32>,
+    received_frames: u32,
+    lost_frames: u32,
+    reported: Option<QualityLevel>,
+    /// Sent to the peers which become ready later
+    last_report: Option<GeneralServerMessage>,
+    /// A level which differs from the reported one and since when it was measured
+    pending: Option<(QualityLevel, Instant)>,
+}
+
+impl QualityMonitor {
+    pub fn set_status(&mut self, status: ConnectionStatus) {
+        self.poor = matches!(status, ConnectionStatus::Poor);
+    }
+
+    pub fn record_frame(&mut self, frame_number: i32) {
+        if let Some(last_frame) = self.last_frame {
+            // Repeated frames don't count
+            if frame_number <= last_frame {
+                return;
+            }
+            self.lost_frames += (frame_number - last_frame - 1) as u32;
+        }
+
+        self.last_frame = Some(frame_number);
+        self.received_frames += 1;
+    }
+
+    /// Measures the quality since the last measurement.
+    /// Returns the report for the clients once a new level held for [QUALITY_DEBOUNCE].
+    pub fn measure(&mut self, now: Instant, rtt: Option<Duration>) -> Option<GeneralServerMessage> {
+        let expected_frames = self.received_frames + self.lost_frames;
+        let loss_pct = if expected_frames == 0 {
+            0.0
+        } else {
+            self.lost_frames as f64 / expected_frames as f64 * 100.0
+        };
+        self.received_frames = 0;
+        self.lost_frames = 0;
+
+        let rtt_ms = rtt.map(|rtt| rtt.as_secs_f64() * 1000.0);
+        let level = grade(self.poor, rtt_ms, loss_pct);
+
+        if self.reported == Some(level) {
+            self.pending = None;
+            return None;
+        }
+
+        let since = match self.pending {
+            Some((pending, since)) if pending == level => since,
+            _ => {
+                self.pending = Some((level, now));
+                now
+            }
+        };
+        if now.duration_since(since) < QUALITY_DEBOUNCE {
+            return None;
+        }
+
+        self.reported = Some(level);
+        self.pending = None;
+
+        let report = GeneralServerMessage::QualityReport {
+            level,
+            rtt_ms,
+            loss_pct,
+        };
+        self.last_report = Some(report.clone());
+        Some(report)
+    }
+
+    /// The report which was last sent, a level is only reported when it changes
+    pub fn last_report(&self) -> Option<GeneralServerMessage> {
+        self.last_report.clone()
+    }
+}
+
+fn grade(poor: bool, rtt_ms: Option<f64>, loss_pct: f64) -> QualityLevel {
+    if poor {
+        return QualityLevel::Poor;
+    }
+
+    // Without an estimate only the lost frames count
+    let rtt_ms = rtt_ms.unwrap_or(0.0);
+
+    LEVEL_THRESHOLDS
+        .iter()
+        .find(|(_, max_rtt_ms, max_loss_pct)| rtt_ms <= *max_rtt_ms && loss_pct <= *max_loss_pct)
+        .map(|(level, _, _)| *level)
+        .unwrap_or(QualityLevel::Poor)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use common::api_bindings::{GeneralServerMessage, QualityLevel};
+    use moonlight_common::stream::bindings::ConnectionStatus;
+
+    use crate::quality::{QUALITY_MEASURE_INTERVAL, QualityMonitor};
+
+    const RTT: Duration = Duration::from_millis(20);
+
+    fn level(report: Option<GeneralServerMessage>) -> Option<QualityLevel> {
+        match report {
+            Some(GeneralServerMessage::QualityReport { level, .. }) => Some(level),
+            _ => None,
+        }
+    }
+
+    /// Measures every second until a report is sent, returns when it was sent
+    fn measure_until_report(
+        monitor: &mut QualityMonitor,
+        start: Instant,
+        rtt: Duration,
+    ) -> (Instant, QualityLevel) {
+        let mut now = start;
+        for _ in 0..10 {
+            now += QUALITY_MEASURE_INTERVAL;
+            if let Some(level) = level(monitor.measure(now, Some(rtt))) {
+                return (now, level);
+            }
+        }
+        panic!("no quality report was sent");
+    }
+
+    #[test]
+    fn test_lost_frames() {
+        let mut monitor = QualityMonitor::default();
+        let mut now = Instant::now();
+
+        let mut frame_number = 0;
+        let report = loop {
+            // 10 of 100 frames are lost in every measurement
+            for _ in 0..100 {
+                if frame_number % 10 != 5 {
+                    monitor.record_frame(frame_number);
+                    // Repeated frames don't count
+                    monitor.record_frame(frame_number);
+                }
+                frame_number += 1;
+            }
+
+            now += QUALITY_MEASURE_INTERVAL;
+            if let Some(report) = monitor.measure(now, Some(RTT)) {
+                break report;
+            }
+        };
+
+        let GeneralServerMessage::QualityReport {
+            level,
+            rtt_ms,
+            loss_pct,
+        } = report
+        else {
+            panic!("unexpected report: {report:?}");
+        };
+        assert_eq!(level, QualityLevel::Poor);
+        assert_eq!(rtt_ms, Some(20.0));
+        assert!((loss_pct - 10.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_levels_are_debounced() {
+        let mut monitor = QualityMonitor::default();
+        let start = Instant::now();
+
+        assert!(monitor.last_report().is_none());
+        let (now, first) = measure_until_report(&mut monitor, start, RTT);
+        assert_eq!(first, QualityLevel::Excellent);
+        assert!(now - start >= Duration::from_secs(3));
+        assert_eq!(level(monitor.last_report()), Some(QualityLevel::Excellent));
+
+        // A short spike isn't reported
+        let now = now + QUALITY_MEASURE_INTERVAL;
+        assert!(
+            monitor
+                .measure(now, Some(Duration::from_millis(200)))
+                .is_none()
+        );
+        let now = now + QUALITY_MEASURE_INTERVAL;
+        assert!(monitor.measure(now, Some(RTT)).is_none());
+
+        // A lasting change is
+        let (later, fair) = measure_until_report(&mut monitor, now, Duration::from_millis(100));
+        assert_eq!(fair, QualityLevel::Fair);
+        assert!(later - now >= Duration::from_secs(3));
+        assert_eq!(level(monitor.last_report()), Some(QualityLevel::Fair));
+
+        // Moonlight reporting a poor connection overrules the stats
+        monitor.set_status(ConnectionStatus::Poor);
+        let (_, poor) = measure_until_report(&mut monitor, later, RTT);
+        assert_eq!(poor, QualityLevel::Poor);
+    }
+}
@@ -0,0 +1,219 @@
+//! Records the encoded video of a stream to disk so decoder specific artifacts can be reproduced.
+//!
+//! Every stream creates three files with the same name in the record directory,
+//! the name contains the time, the process id of the streamer and a counter of its recordings:
+//! - `.h264` / `.h265` / `.obu`: the elementary stream exactly as the host sent it,
+//!   it can be remuxed with e.g. `ffmpeg -i <file> -c copy out.mkv`
+//! - `.json`: the video setup of the stream
+//! - `.index`: one line per frame: `frame_number,frame_type,presentation_time_ms,offset,size`
+
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicU32, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use moonlight_common::stream::{
+    bindings::{FrameType, VideoDecodeUnit, VideoFormat},
+    video::VideoSetup,
+};
+use serde_json::json;
+
+/// Restarted streams of the same streamer might be recorded within the same second
+static NEXT_RECORDING: AtomicU32 = AtomicU32::new(0);
+
+pub struct VideoRecorder {
+    data_path: PathBuf,
+    data: BufWriter<File>,
+    index: BufWriter<File>,
+    offset: u64,
+}
+
+impl VideoRecorder {
+    pub fn create(directory: &Path, setup: &VideoSetup) -> io::Result<Self> {
+        fs::create_dir_all(directory)?;
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let recording = NEXT_RECORDING.fetch_add(1, Ordering::Relaxed);
+        let name = format!("stream-{timestamp}-{}-{recording}", process::id());
+
+        let header = json!({
+            "format": format!("{:?}", setup.format),
+            "width": setup.width,
+            "height": setup.height,
+            "redraw_rate": setup.redraw_rate,
+            "flags": setup.flags,
+        });
+        fs::write(
+            directory.join(format!("{name}.json")),
+            serde_json::to_string_pretty(&header)?,
+        )?;
+
+        let data_path = directory.join(format!("{name}.{}", file_extension(setup.format)));
+        let data = BufWriter::new(File::create(&data_path)?);
+        let index = BufWriter::new(File::create(directory.join(format!("{name}.index")))?);
+
+        Ok(Self {
+            data_path,
+            data,
+            index,
+            offset: 0,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.data_path
+    }
+
+    pub fn write_unit(&mut self, unit: &VideoDecodeUnit) -> io::Result<()> {
+        let mut size = 0;
+        for buffer in unit.buffers {
+            self.data.write_all(buffer.data)?;
+            size += buffer.data.len() as u64;
+        }
+
+        let frame_type = match unit.frame_type {
+            FrameType::Idr => "idr",
+            FrameType::PFrame => "p",
+        };
+        writeln!(
+            self.index,
+            "{},{frame_type},{},{},{size}",
+            unit.frame_number,
+            unit.presentation_time.as_millis(),
+            self.offset,
+        )?;
+
+        self.offset += size;
+
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.data.flush()?;
+        self.index.flush()
+    }
+}
+
+fn file_extension(format: VideoFormat) -> &'static str {
+    match format {
+        VideoFormat::H264 | VideoFormat::H264High8_444 => "h264",
+        VideoFormat::H265
+        | VideoFormat::H265Main10
+        | VideoFormat::H265Rext8_444
+        | VideoFormat::H265Rext10_444 => "h265",
+        VideoFormat::Av1Main8
+        | VideoFormat::Av1Main10
+        | VideoFormat::Av1High8_444
+        | VideoFormat::Av1High10_444 => "obu",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, time::Duration};
+
+    use moonlight_common::stream::{
+        bindings::{
+            BufferType, Colorspace, FrameType, VideoDataBuffer, VideoDecodeUnit, VideoFormat,
+        },
+        video::VideoSetup,
+    };
+
+    use crate::recording::VideoRecorder;
+
+    fn write_unit(recorder: &mut VideoRecorder, frame_number: i32, frame_type: FrameType) {
+        let sps = [0, 0, 0, 1, 0x67];
+        let picture = [0, 0, 0, 1, 0x65, frame_number as u8];
+        let buffers = [
+            VideoDataBuffer {
+                ty: BufferType::Sps,
+                data: &sps,
+            },
+            VideoDataBuffer {
+                ty: BufferType::PicData,
+                data: &picture,
+            },
+        ];
+
+        recorder
+            .write_unit(&VideoDecodeUnit {
+                frame_number,
+                frame_type,
+                frame_processing_latency: None,
+                receive_time: Duration::ZERO,
+                enqueue_time: Duration::ZERO,
+                presentation_time: Duration::from_millis(frame_number as u64 * 16),
+                hdr_active: false,
+                color_space: Colorspace::Rec709,
+                buffers: &buffers,
+            })
+            .expect("failed to write unit");
+    }
+
+    #[test]
+    fn test_record_video() {
+        let dir = tempfile::tempdir().expect("failed to create test directory");
+        let directory = dir.path().join("recordings");
+
+        let mut recorder = VideoRecorder::create(
+            &directory,
+            &VideoSetup {
+                format: VideoFormat::H264,
+                width: 1920,
+                height: 1080,
+                redraw_rate: 60,
+                flags: 0,
+            },
+        )
+        .expect("failed to create recorder");
+
+        let data_path = recorder.path().to_path_buf();
+        assert_eq!(
+            data_path.extension().and_then(|ext| ext.to_str()),
+            Some("h264")
+        );
+
+        write_unit(&mut recorder, 1, FrameType::Idr);
+        write_unit(&mut recorder, 2, FrameType::PFrame);
+        recorder.finish().expect("failed to finish recording");
+
+        let data = fs::read(&data_path).expect("failed to read data");
+        assert_eq!(data.len(), 22);
+
+        let index =
+            fs::read_to_string(data_path.with_extension("index")).expect("failed to read index");
+        assert_eq!(index, "1,idr,16,0,11\n2,p,32,11,11\n");
+
+        let header =
+            fs::read_to_string(data_path.with_extension("json")).expect("failed to read header");
+        assert!(header.contains("\"width\": 1920"));
+        assert!(header.contains("\"format\": \"H264\""));
+    }
+
+    #[test]
+    fn test_recordings_dont_overwrite_each_other() {
+        let dir = tempfile::tempdir().expect("failed to create test directory");
+        let directory = dir.path();
+        let setup = VideoSetup {
+            format: VideoFormat::H264,
+            width: 1920,
+            height: 1080,
+            redraw_rate: 60,
+            flags: 0,
+        };
+
+        let first = VideoRecorder::create(directory, &setup).expect("failed to create recorder");
+        let second = VideoRecorder::create(directory, &setup).expect("failed to create recorder");
+        assert_ne!(first.path(), second.path());
+
+        first.finish().expect("failed to finish recording");
+        second.finish().expect("failed to finish recording");
+    }
+}
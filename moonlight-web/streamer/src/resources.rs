@@ -0,0 +1,137 @@
+//! Samples the memory of the streamer so the web server can spot leaks,
+//! and stops the stream before the streamer takes the memory of the whole server.
+
+use std::time::{Duration, Instant};
+
+/// How often the memory is compared with the limit
+pub const RESOURCE_SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+/// How often the resources are reported to the web server
+pub const RESOURCE_REPORT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The resident memory of this process in bytes, None if it can't be read on this platform
+pub fn rss_bytes() -> Option<u64> {
+    #[cfg(target_os = "linux")]
+    {
+        let status = std::fs::read_to_string("/proc/self/status").ok()?;
+
+        parse_vm_rss(&status)
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        None
+    }
+}
+
+/// Reads the `VmRSS:    1234 kB` line of `/proc/self/status`
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_vm_rss(status: &str) -> Option<u64> {
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kilobytes = line
+        .trim_start_matches("VmRSS:")
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+
+    Some(kilobytes * 1024)
+}
+
+/// What the streamer has to do after a memory sample
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryAction {
+    None,
+    /// The memory exceeded the limit, the peers only get keyframes until it recovers
+    Trim,
+    /// The memory is below the limit again, the peers get all frames again
+    Recovered,
+    /// The memory stayed above the limit for the grace period, the stream has to stop
+    Terminate,
+}
+
+/// Compares the samples with the limit, the stream only stops if the memory stays above it for the grace period
+#[derive(Debug)]
+pub struct MemoryGuard {
+    limit: u64,
+    grace_period: Duration,
+    /// When the memory exceeded the limit, None while it's below
+    exceeded_since: Option<Instant>,
+}
+
+impl MemoryGuard {
+    pub fn new(limit: u64, grace_period: Duration) -> Self {
+        Self {
+            limit,
+            grace_period,
+            exceeded_since: None,
+        }
+    }
+
+    pub fn check(&mut self, rss_bytes: u64, now: Instant) -> MemoryAction {
+        if rss_bytes <= self.limit {
+            return match self.exceeded_since.take() {
+                Some(_) => MemoryAction::Recovered,
+                None => MemoryAction::None,
+            };
+        }
+
+        match self.exceeded_since {
+            None => {
+                self.exceeded_since = Some(now);
+                MemoryAction::Trim
+            }
+            Some(since) if now.duration_since(since) >= self.grace_period => {
+                MemoryAction::Terminate
+            }
+            Some(_) => MemoryAction::None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use crate::resources::{MemoryAction, MemoryGuard, RESOURCE_SAMPLE_INTERVAL, parse_vm_rss};
+
+    const MB: u64 = 1024 * 1024;
+
+    #[test]
+    fn test_parse_vm_rss() {
+        let status = "Name:\tstreamer\nVmPeak:\t  204800 kB\nVmRSS:\t   51200 kB\nThreads:\t12\n";
+
+        assert_eq!(parse_vm_rss(status), Some(50 * MB));
+        assert_eq!(parse_vm_rss("Name:\tstreamer\n"), None);
+    }
+
+    #[test]
+    fn test_memory_guard() {
+        let mut guard = MemoryGuard::new(100 * MB, Duration::from_secs(20));
+        let mut now = Instant::now();
+
+        assert_eq!(guard.check(50 * MB, now), MemoryAction::None);
+
+        // Exceeding the limit trims once, a short spike recovers
+        now += RESOURCE_SAMPLE_INTERVAL;
+        assert_eq!(guard.check(150 * MB, now), MemoryAction::Trim);
+        now += RESOURCE_SAMPLE_INTERVAL;
+        assert_eq!(guard.check(150 * MB, now), MemoryAction::None);
+        now += RESOURCE_SAMPLE_INTERVAL;
+        assert_eq!(guard.check(80 * MB, now), MemoryAction::Recovered);
+        now += RESOURCE_SAMPLE_INTERVAL;
+        assert_eq!(guard.check(80 * MB, now), MemoryAction::None);
+
+        // The grace period starts again after recovering
+        now += RESOURCE_SAMPLE_INTERVAL;
+        let exceeded = now;
+        assert_eq!(guard.check(150 * MB, now), MemoryAction::Trim);
+        while now.duration_since(exceeded) + RESOURCE_SAMPLE_INTERVAL < Duration::from_secs(20) {
+            now += RESOURCE_SAMPLE_INTERVAL;
+            assert_eq!(guard.check(150 * MB, now), MemoryAction::None);
+        }
+
+        // Staying above the limit for the grace period stops the stream
+        now += RESOURCE_SAMPLE_INTERVAL;
+        assert_eq!(guard.check(150 * MB, now), MemoryAction::Terminate);
+    }
+}
@@ -1,6 +1,10 @@
-use std::ops::Range;
+use std::{
+    ops::Range,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 use async_trait::async_trait;
+use bytes::Bytes;
 use common::{
     StreamSettings,
     api_bindings::{
@@ -12,8 +16,8 @@ use log::warn;
 use moonlight_common::stream::{
     bindings::{
         AudioConfig, ControllerButtons, ControllerCapabilities, ControllerType, DecodeResult,
-        KeyAction, KeyFlags, KeyModifiers, MouseButton, MouseButtonAction, OpusMultistreamConfig,
-        TouchEventType, VideoDecodeUnit,
+        FrameType, KeyAction, KeyFlags, KeyModifiers, MouseButton, MouseButtonAction,
+        OpusMultistreamConfig, TouchEventType, VideoDecodeUnit,
     },
     video::VideoSetup,
 };
@@ -22,6 +26,8 @@ use thiserror::Error;
 
 use crate::buffer::ByteBuffer;
 
+#[cfg(test)]
+pub mod test;
 pub mod web_socket;
 pub mod webrtc;
 
@@ -29,6 +35,18 @@ pub mod webrtc;
 #[derive(Debug, Clone, Copy)]
 pub struct TransportChannel(pub u8);
 
+/// How hard a transport tries to deliver an [OutboundPacket]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryClass {
+    /// Waits until the transport accepted the packet and fails if the channel can't be used
+    Reliable,
+    /// The packet is dropped if the channel isn't open yet or the transport is busy
+    BestEffort,
+    /// Like [DeliveryClass::Reliable], but only the newest packet of its kind matters,
+    /// so a packet which wasn't sent yet may be replaced by a newer one
+    Latest,
+}
+
 #[derive(Debug, Error)]
 pub enum TransportError {
     #[error("the channel was closed")]
@@ -71,6 +89,8 @@ pub enum InboundPacket {
         modifiers: KeyModifiers,
         key: u16,
         flags: KeyFlags,
+        /// The character typed with this key on the layout of the client, used by the text fallback keyboard mode
+        text: Option<String>,
     },
     Text {
         text: String,
@@ -104,6 +124,17 @@ pub enum InboundPacket {
         rotation: Option<u16>,
         event_type: TouchEventType,
     },
+    /// How often the peer wants the stats of the stream, None stops them
+    SetStatsInterval {
+        millis: Option<u32>,
+        /// Every how many rendered frames the peer reports a frame, None if it doesn't report them
+        frame_sample_interval: Option<u16>,
+    },
+    /// The peer rendered the video frame with this id, the render time is in ms of the clock of the peer
+    FrameRendered {
+        frame_id: u32,
+        client_render_ts: u32,
+    },
 }
 
 impl InboundPacket {
@@ -158,8 +189,50 @@ impl InboundPacket {
                 Some(Self::General { message })
             }
             TransportChannel(TransportChannelId::STATS) => {
-                warn!("[InboundPacket]: tried to deserialize stats packet, this shouldn't happen");
-                None
+                if buffer.remaining() < 1 {
+                    warn!("[InboudPacket]: failed to read stats message");
+                    return None;
+                }
+
+                let ty = buffer.get_u8();
+                if ty == 0 {
+                    // Interval
+                    if buffer.remaining() < 4 {
+                        warn!("[InboudPacket]: failed to read stats interval message");
+                        return None;
+                    }
+
+                    // Zero disables the stats
+                    let millis = buffer.get_u32();
+                    // Older clients don't report the rendered frames
+                    let frame_sample_interval = if buffer.remaining() >= 2 {
+                        buffer.get_u16()
+                    } else {
+                        0
+                    };
+
+                    Some(InboundPacket::SetStatsInterval {
+                        millis: (millis != 0).then_some(millis),
+                        frame_sample_interval: (frame_sample_interval != 0)
+                            .then_some(frame_sample_interval),
+                    })
+                } else if ty == 1 {
+                    // Frame rendered
+                    if buffer.remaining() < 8 {
+                        warn!("[InboudPacket]: failed to read frame rendered message");
+                        return None;
+                    }
+
+                    Some(InboundPacket::FrameRendered {
+                        frame_id: buffer.get_u32(),
+                        client_render_ts: buffer.get_u32(),
+                    })
+                } else {
+                    warn!(
+                        "[InboundPacket]: tried to deserialize stats packet with type {ty}, this shouldn't happen"
+                    );
+                    None
+                }
             }
             TransportChannel(TransportChannelId::HOST_VIDEO) => {
                 warn!(
@@ -286,11 +359,24 @@ impl InboundPacket {
                         });
                     let key = buffer.get_u16();
 
+                    // Older clients don't send the typed text
+                    let mut text = None;
+                    if buffer.remaining() >= 1 {
+                        let len = buffer.get_u8();
+                        match buffer.get_utf8_raw(len as usize) {
+                            Ok(typed) => text = Some(typed.to_owned()),
+                            Err(_) => {
+                                warn!("[InboundPacket]: received invalid key text");
+                            }
+                        }
+                    }
+
                     Some(InboundPacket::Key {
                         action,
                         modifiers,
                         key,
                         flags: KeyFlags::empty(),
+                        text,
                     })
                 } else if ty == 1 {
                     if buffer.remaining() < 1 {
@@ -457,17 +543,19 @@ impl InboundPacket {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum OutboundPacket {
     General {
         message: GeneralServerMessage,
     },
     Stats(StreamerStatsUpdate),
+    /// The controller number is the gamepad of the peer this is sent to
     ControllerRumble {
         controller_number: u8,
         low_frequency_motor: u16,
         high_frequency_motor: u16,
     },
+    /// The controller number is the gamepad of the peer this is sent to
     ControllerTriggerRumble {
         controller_number: u8,
         left_trigger_motor: u16,
@@ -476,7 +564,55 @@ pub enum OutboundPacket {
 }
 
 impl OutboundPacket {
-    pub fn serialize(&self, raw_buffer: &mut Vec<u8>) -> Option<(TransportChannel, Range<usize>)> {
+    pub fn channel(&self) -> TransportChannel {
+        match self {
+            Self::General { .. } => TransportChannel(TransportChannelId::GENERAL),
+            Self::Stats(_) => TransportChannel(TransportChannelId::STATS),
+            // The controllers channel is the only reliable controller channel
+            Self::ControllerRumble { .. } | Self::ControllerTriggerRumble { .. } => {
+                TransportChannel(TransportChannelId::CONTROLLERS)
+            }
+        }
+    }
+
+    pub fn delivery_class(&self) -> DeliveryClass {
+        match self {
+            Self::General { .. } => DeliveryClass::Reliable,
+            // A newer update follows soon
+            Self::Stats(_) => DeliveryClass::BestEffort,
+            // A lost rumble would keep the gamepad vibrating, but every rumble replaces the last one
+            Self::ControllerRumble { .. } | Self::ControllerTriggerRumble { .. } => {
+                DeliveryClass::Latest
+            }
+        }
+    }
+
+    /// The gamepad this packet is meant for
+    pub fn controller_number(&self) -> Option<u8> {
+        match self {
+            Self::General { .. } | Self::Stats(_) => None,
+            Self::ControllerRumble {
+                controller_number, ..
+            }
+            | Self::ControllerTriggerRumble {
+                controller_number, ..
+            } => Some(*controller_number),
+        }
+    }
+    pub fn with_controller_number(mut self, number: u8) -> Self {
+        match &mut self {
+            Self::General { .. } | Self::Stats(_) => {}
+            Self::ControllerRumble {
+                controller_number, ..
+            }
+            | Self::ControllerTriggerRumble {
+                controller_number, ..
+            } => *controller_number = number,
+        }
+        self
+    }
+
+    pub fn serialize(&self, raw_buffer: &mut Vec<u8>) -> Option<Range<usize>> {
         match self {
             Self::General { message } => {
                 let Ok(text) = serde_json::to_string(&message) else {
@@ -490,10 +626,7 @@ impl OutboundPacket {
                 buffer.put_utf8_raw(&text);
 
                 buffer.flip();
-                Some((
-                    TransportChannel(TransportChannelId::GENERAL),
-                    buffer.into_raw().1,
-                ))
+                Some(buffer.into_raw().1)
             }
             Self::Stats(stats) => {
                 let Ok(text) = serde_json::to_string(&stats) else {
@@ -507,10 +640,7 @@ impl OutboundPacket {
                 buffer.put_utf8_raw(&text);
 
                 buffer.flip();
-                Some((
-                    TransportChannel(TransportChannelId::STATS),
-                    buffer.into_raw().1,
-                ))
+                Some(buffer.into_raw().1)
             }
             Self::ControllerRumble {
                 controller_number,
@@ -527,10 +657,7 @@ impl OutboundPacket {
                 buffer.put_u16(*high_frequency_motor);
 
                 buffer.flip();
-                Some((
-                    TransportChannel(TransportChannelId::CONTROLLER0 + controller_number),
-                    buffer.into_raw().1,
-                ))
+                Some(buffer.into_raw().1)
             }
             Self::ControllerTriggerRumble {
                 controller_number,
@@ -541,16 +668,13 @@ impl OutboundPacket {
                 let mut buffer = ByteBuffer::new(raw_buffer as &mut [u8]);
 
                 // Requires 6 bytes
-                buffer.put_u8(0);
+                buffer.put_u8(1);
                 buffer.put_u8(*controller_number);
                 buffer.put_u16(*left_trigger_motor);
                 buffer.put_u16(*right_trigger_motor);
 
                 buffer.flip();
-                Some((
-                    TransportChannel(TransportChannelId::CONTROLLER0 + controller_number),
-                    buffer.into_raw().1,
-                ))
+                Some(buffer.into_raw().1)
             }
         }
     }
@@ -558,12 +682,74 @@ impl OutboundPacket {
 
 #[derive(Debug)]
 pub enum TransportEvent {
-    StartStream { settings: StreamSettings },
+    StartStream {
+        settings: StreamSettings,
+    },
     RecvPacket(InboundPacket),
     SendIpc(StreamerIpcMessage),
+    /// The transport can receive the stream, e.g. the WebRTC data channels are open
+    Ready,
     Closed,
 }
 
+/// The video and audio are only sent to a transport after it sent [TransportEvent::Ready].
+/// The video starts with a keyframe because the frames before it can't be decoded.
+#[derive(Debug, Default)]
+pub struct TransportReadiness {
+    ready: AtomicBool,
+    waiting_for_keyframe: AtomicBool,
+    connected: AtomicBool,
+    setup_failed: AtomicBool,
+}
+
+impl TransportReadiness {
+    pub fn set_ready(&self) {
+        self.waiting_for_keyframe.store(true, Ordering::Relaxed);
+        self.ready.store(true, Ordering::Release);
+    }
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Acquire)
+    }
+    /// The transport sent [TransportEvent::Ready], it's only ready if its setup didn't fail
+    pub fn set_connected(&self) {
+        self.connected.store(true, Ordering::Release);
+    }
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Acquire)
+    }
+    /// The video or audio setup failed, the transport gets no stream until the retry of the setup succeeds.
+    /// True if it didn't fail before and the retry has to be scheduled.
+    pub fn set_setup_failed(&self) -> bool {
+        self.ready.store(false, Ordering::Release);
+        !self.setup_failed.swap(true, Ordering::AcqRel)
+    }
+    pub fn has_setup_failed(&self) -> bool {
+        self.setup_failed.load(Ordering::Acquire)
+    }
+    pub fn clear_setup_failed(&self) {
+        self.setup_failed.store(false, Ordering::Release);
+    }
+    /// Drops the video until the next keyframe, e.g. after frames were skipped for this transport
+    pub fn wait_for_keyframe(&self) {
+        self.waiting_for_keyframe.store(true, Ordering::Relaxed);
+    }
+
+    /// False if the frame shouldn't be sent to the transport
+    pub fn accepts_video(&self, frame_type: FrameType) -> bool {
+        if !self.is_ready() {
+            return false;
+        }
+
+        match frame_type {
+            FrameType::Idr => {
+                self.waiting_for_keyframe.store(false, Ordering::Relaxed);
+                true
+            }
+            FrameType::PFrame => !self.waiting_for_keyframe.load(Ordering::Relaxed),
+        }
+    }
+}
+
 #[async_trait]
 pub trait TransportEvents {
     async fn poll_event(&mut self) -> Result<TransportEvent, TransportError>;
@@ -571,9 +757,11 @@ pub trait TransportEvents {
 #[async_trait]
 pub trait TransportSender {
     async fn setup_video(&self, setup: VideoSetup) -> i32;
+    /// Sends the unit, the frame id lets the peer report when it rendered the frame
     async fn send_video_unit<'a>(
         &'a self,
         unit: &'a VideoDecodeUnit<'a>,
+        frame_id: u32,
     ) -> Result<DecodeResult, TransportError>;
 
     async fn setup_audio(
@@ -583,9 +771,34 @@ pub trait TransportSender {
     ) -> i32;
     async fn send_audio_sample(&self, data: &[u8]) -> Result<(), TransportError>;
 
-    async fn send(&self, packet: OutboundPacket) -> Result<(), TransportError>;
+    /// Sends the payload on the channel, how the [DeliveryClass] is honored depends on the transport
+    async fn send_on(
+        &self,
+        channel: TransportChannel,
+        payload: Bytes,
+        class: DeliveryClass,
+    ) -> Result<(), TransportError>;
+
+    /// Sends the packet on its channel with its delivery class
+    async fn send(&self, packet: OutboundPacket) -> Result<(), TransportError> {
+        let mut buffer = Vec::new();
+        let Some(range) = packet.serialize(&mut buffer) else {
+            return Ok(());
+        };
+        let payload = Bytes::from(buffer).slice(range);
+
+        self.send_on(packet.channel(), payload, packet.delivery_class())
+            .await
+    }
 
     async fn on_ipc_message(&self, message: ServerIpcMessage) -> Result<(), TransportError>;
 
+    /// The video frames and audio samples waiting to be sent, zero if the transport doesn't queue them
+    async fn queue_lengths(&self) -> (usize, usize) {
+        (0, 0)
+    }
+    /// Drops the waiting video frames and audio samples to free memory, the peer continues at the next keyframe
+    async fn trim_queues(&self) {}
+
     async fn close(&self) -> Result<(), TransportError>;
 }
@@ -0,0 +1,342 @@
+//! In process transport and stream connection used to test the streamer without network or moonlight.
+
+use std::{
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicI32, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use common::{
+    config::WebRtcConfig,
+    ipc::{
+        DEFAULT_IPC_CHANNEL_CAPACITY, IpcReceiver, IpcSender, ServerIpcMessage, StreamerConfig,
+        StreamerIpcMessage, create_ipc,
+    },
+};
+use log::LevelFilter;
+use moonlight_common::{
+    MoonlightError,
+    high::MoonlightHost,
+    stream::{
+        MoonlightInstance,
+        bindings::{
+            AudioConfig, DecodeResult, FrameType, KeyAction, KeyFlags, KeyModifiers, MouseButton,
+            MouseButtonAction, OpusMultistreamConfig, VideoDecodeUnit,
+        },
+        video::VideoSetup,
+    },
+};
+use tokio::{
+    io::{duplex, split},
+    sync::{
+        Mutex as AsyncMutex, Notify,
+        mpsc::{Receiver, Sender, channel},
+    },
+};
+
+use crate::{
+    HostInput, StreamConnection, StreamInfo,
+    transport::{
+        DeliveryClass, OutboundPacket, TransportChannel, TransportError, TransportEvent,
+        TransportEvents, TransportSender,
+    },
+};
+
+pub fn new() -> (
+    FakeTransportSender,
+    FakeTransportEvents,
+    FakeTransportHandle,
+) {
+    let (event_sender, event_receiver) = channel::<TransportEvent>(20);
+    let state = Arc::new(FakeTransportState::default());
+
+    (
+        FakeTransportSender {
+            state: state.clone(),
+        },
+        FakeTransportEvents { event_receiver },
+        FakeTransportHandle {
+            event_sender,
+            state,
+        },
+    )
+}
+
+/// Everything the streamer did with the transport
+#[derive(Default)]
+struct FakeTransportState {
+    /// Returned by the video and audio setup
+    setup_result: AtomicI32,
+    video_setups: Mutex<Vec<VideoSetup>>,
+    audio_setups: Mutex<Vec<OpusMultistreamConfig>>,
+    /// The type and id of the sent video frames
+    video_frames: Mutex<Vec<(FrameType, u32)>>,
+    packets: Mutex<Vec<OutboundPacket>>,
+    ipc_messages: Mutex<Vec<ServerIpcMessage>>,
+    close_count: AtomicUsize,
+    closed: Notify,
+}
+
+pub struct FakeTransportEvents {
+    event_receiver: Receiver<TransportEvent>,
+}
+
+#[async_trait]
+impl TransportEvents for FakeTransportEvents {
+    async fn poll_event(&mut self) -> Result<TransportEvent, TransportError> {
+        self.event_receiver
+            .recv()
+            .await
+            .ok_or(TransportError::Closed)
+    }
+}
+
+pub struct FakeTransportSender {
+    state: Arc<FakeTransportState>,
+}
+
+#[async_trait]
+impl TransportSender for FakeTransportSender {
+    async fn setup_video(&self, setup: VideoSetup) -> i32 {
+        lock(&self.state.video_setups).push(setup);
+        self.state.setup_result.load(Ordering::SeqCst)
+    }
+    async fn send_video_unit<'a>(
+        &'a self,
+        unit: &'a VideoDecodeUnit<'a>,
+        frame_id: u32,
+    ) -> Result<DecodeResult, TransportError> {
+        lock(&self.state.video_frames).push((unit.frame_type, frame_id));
+        Ok(DecodeResult::Ok)
+    }
+
+    async fn setup_audio(
+        &self,
+        _audio_config: AudioConfig,
+        stream_config: OpusMultistreamConfig,
+    ) -> i32 {
+        lock(&self.state.audio_setups).push(stream_config);
+        self.state.setup_result.load(Ordering::SeqCst)
+    }
+    async fn send_audio_sample(&self, _data: &[u8]) -> Result<(), TransportError> {
+        Ok(())
+    }
+
+    async fn send_on(
+        &self,
+        _channel: TransportChannel,
+        _payload: Bytes,
+        _class: DeliveryClass,
+    ) -> Result<(), TransportError> {
+        Ok(())
+    }
+    /// Keeps the packet instead of serializing it
+    async fn send(&self, packet: OutboundPacket) -> Result<(), TransportError> {
+        lock(&self.state.packets).push(packet);
+        Ok(())
+    }
+
+    async fn on_ipc_message(&self, message: ServerIpcMessage) -> Result<(), TransportError> {
+        lock(&self.state.ipc_messages).push(message);
+        Ok(())
+    }
+
+    async fn close(&self) -> Result<(), TransportError> {
+        self.state.close_count.fetch_add(1, Ordering::SeqCst);
+        self.state.closed.notify_one();
+        Ok(())
+    }
+}
+
+/// Used by the test to inject events and inspect what was sent over the transport
+pub struct FakeTransportHandle {
+    event_sender: Sender<TransportEvent>,
+    state: Arc<FakeTransportState>,
+}
+
+impl FakeTransportHandle {
+    pub async fn inject_event(&self, event: TransportEvent) {
+        self.event_sender
+            .send(event)
+            .await
+            .expect("the transport events were dropped");
+    }
+
+    /// The video and audio setup return this error code, 0 succeeds
+    pub fn set_setup_result(&self, result: i32) {
+        self.state.setup_result.store(result, Ordering::SeqCst);
+    }
+
+    pub fn video_setups(&self) -> Vec<VideoSetup> {
+        lock(&self.state.video_setups).clone()
+    }
+    pub fn audio_setups(&self) -> Vec<OpusMultistreamConfig> {
+        lock(&self.state.audio_setups).clone()
+    }
+    pub fn take_video_frames(&self) -> Vec<(FrameType, u32)> {
+        lock(&self.state.video_frames).drain(..).collect()
+    }
+    pub fn take_packets(&self) -> Vec<OutboundPacket> {
+        lock(&self.state.packets).drain(..).collect()
+    }
+    pub fn take_ipc_messages(&self) -> Vec<ServerIpcMessage> {
+        lock(&self.state.ipc_messages).drain(..).collect()
+    }
+
+    pub fn close_count(&self) -> usize {
+        self.state.close_count.load(Ordering::SeqCst)
+    }
+    /// Waits until the transport was closed at least once
+    pub async fn wait_closed(&self) {
+        if self.close_count() > 0 {
+            return;
+        }
+        self.state.closed.notified().await;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostInputEvent {
+    KeyDown(i16),
+    KeyUp(i16),
+    MouseButtonPress(MouseButton),
+    MouseButtonRelease(MouseButton),
+}
+
+/// Records the input the streamer sent to the host instead of a moonlight stream
+#[derive(Default)]
+pub struct FakeHostInput {
+    events: Mutex<Vec<HostInputEvent>>,
+}
+
+impl FakeHostInput {
+    pub fn take_events(&self) -> Vec<HostInputEvent> {
+        lock(&self.events).drain(..).collect()
+    }
+}
+
+impl HostInput for FakeHostInput {
+    fn send_keyboard_event_non_standard(
+        &self,
+        key_code: i16,
+        key_action: KeyAction,
+        _modifiers: KeyModifiers,
+        _flags: KeyFlags,
+    ) -> Result<(), MoonlightError> {
+        lock(&self.events).push(match key_action {
+            KeyAction::Down => HostInputEvent::KeyDown(key_code),
+            KeyAction::Up => HostInputEvent::KeyUp(key_code),
+        });
+        Ok(())
+    }
+
+    fn send_mouse_button(
+        &self,
+        action: MouseButtonAction,
+        button: MouseButton,
+    ) -> Result<(), MoonlightError> {
+        lock(&self.events).push(match action {
+            MouseButtonAction::Press => HostInputEvent::MouseButtonPress(button),
+            MouseButtonAction::Release => HostInputEvent::MouseButtonRelease(button),
+        });
+        Ok(())
+    }
+}
+
+const VIDEO_FRAME_QUEUE_SIZE: usize = 3;
+const AUDIO_SAMPLE_QUEUE_SIZE: usize = 20;
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().expect("fake transport mutex poisoned")
+}
+
+/// Builds a [StreamConnection] which talks to an unreachable host and an in memory ipc.
+pub struct TestStreamConnectionBuilder {
+    config: StreamerConfig,
+}
+
+impl TestStreamConnectionBuilder {
+    pub fn new() -> Self {
+        Self {
+            config: StreamerConfig {
+                webrtc: WebRtcConfig::default(),
+                log_level: LevelFilter::Debug,
+                record_path: None,
+                stage_timeout: Duration::from_secs(30),
+                memory_limit: None,
+                memory_grace_period: Duration::from_secs(60),
+            },
+        }
+    }
+
+    pub fn stage_timeout(mut self, stage_timeout: Duration) -> Self {
+        self.config.stage_timeout = stage_timeout;
+        self
+    }
+
+    pub async fn build(self) -> TestStreamConnection {
+        // The host is never contacted because no test starts a moonlight stream
+        let host = MoonlightHost::new("127.0.0.1".to_string(), 47989, None)
+            .expect("failed to create host");
+        let moonlight = MoonlightInstance::global().expect("failed to find moonlight");
+
+        let (streamer_io, server_io) = duplex(64 * 1024);
+
+        let (streamer_read, streamer_write) = split(streamer_io);
+        let (streamer_ipc_sender, streamer_ipc_receiver) =
+            create_ipc::<ServerIpcMessage, StreamerIpcMessage>(
+                streamer_read,
+                streamer_write,
+                DEFAULT_IPC_CHANNEL_CAPACITY,
+            );
+
+        let (server_read, server_write) = split(server_io);
+        let (ipc_sender, ipc_receiver) = create_ipc::<StreamerIpcMessage, ServerIpcMessage>(
+            server_read,
+            server_write,
+            DEFAULT_IPC_CHANNEL_CAPACITY,
+        );
+
+        let connection = StreamConnection::new(
+            moonlight,
+            StreamInfo {
+                host: AsyncMutex::new(host),
+                app_id: 0,
+            },
+            streamer_ipc_sender,
+            streamer_ipc_receiver,
+            self.config,
+            VIDEO_FRAME_QUEUE_SIZE,
+            AUDIO_SAMPLE_QUEUE_SIZE,
+        )
+        .await
+        .expect("failed to create stream connection");
+
+        let host_input = Arc::new(FakeHostInput::default());
+        if connection.fake_host_input.set(host_input.clone()).is_err() {
+            unreachable!("the host input of a new stream connection is already set");
+        }
+
+        TestStreamConnection {
+            connection,
+            host_input,
+            _ipc_sender: ipc_sender,
+            ipc_receiver,
+        }
+    }
+}
+
+/// A [StreamConnection] with the web server side of the ipc
+pub struct TestStreamConnection {
+    pub connection: Arc<StreamConnection>,
+    /// Gets the input the streamer sends to the host
+    pub host_input: Arc<FakeHostInput>,
+    /// Kept so the streamer doesn't see a closed ipc
+    _ipc_sender: IpcSender<ServerIpcMessage>,
+    /// Receive messages the streamer sent to the web server
+    pub ipc_receiver: IpcReceiver<StreamerIpcMessage>,
+}
@@ -1,7 +1,12 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
 use async_trait::async_trait;
 use bytes::Bytes;
 use common::{
-    StreamSettings,
+    AllowedInputs, StreamSettings,
     api_bindings::{StreamClientMessage, TransportChannelId},
     ipc::{ServerIpcMessage, StreamerIpcMessage},
 };
@@ -13,44 +18,120 @@ use moonlight_common::stream::{
     },
     video::VideoSetup,
 };
-use tokio::sync::mpsc::{Receiver, Sender, channel};
+use tokio::sync::mpsc::{Receiver, Sender, channel, error::TrySendError};
 
 use crate::{
     buffer::ByteBuffer,
     transport::{
-        InboundPacket, OutboundPacket, TransportChannel, TransportError, TransportEvent,
+        DeliveryClass, InboundPacket, TransportChannel, TransportError, TransportEvent,
         TransportEvents, TransportSender,
     },
 };
 
 pub async fn new() -> Result<(WebSocketTransportSender, WebSocketTransportEvents), anyhow::Error> {
-    let (event_sender, event_receiver) = channel::<TransportEvent>(20);
+    let (event_sender, event_receiver) = channel::<QueuedEvent>(20);
 
     // TODO: use the video_frame_queue_size with packet rtt info to estimate latency of pictures and request idr if too big
 
+    let coalesced = Arc::new(CoalescingQueue::default());
+
+    // The Web Socket is already connected, so the stream can be sent right away
+    event_sender
+        .try_send(QueuedEvent::Event(TransportEvent::Ready))
+        .expect("the new event channel has capacity");
+
     Ok((
-        WebSocketTransportSender { event_sender },
-        WebSocketTransportEvents { event_receiver },
+        WebSocketTransportSender {
+            event_sender,
+            coalesced: coalesced.clone(),
+        },
+        WebSocketTransportEvents {
+            event_receiver,
+            coalesced,
+        },
     ))
 }
 
+/// Packets of a different kind or for another gamepad on the same channel never replace each other,
+/// the payload starts with the kind and the gamepad
+type CoalesceKey = (u8, Option<u8>, Option<u8>);
+
+/// Everything is sent in the order it was queued in
+enum QueuedEvent {
+    Event(TransportEvent),
+    /// The frame queued with this generation in the [CoalescingQueue],
+    /// skipped if a newer frame of the key was queued after it
+    Coalesced(CoalesceKey, u64),
+}
+
+/// The newest [DeliveryClass::Latest] frame of every key which wasn't sent yet
+#[derive(Default)]
+struct CoalescingQueue {
+    frames: Mutex<CoalescedFrames>,
+}
+
+#[derive(Default)]
+struct CoalescedFrames {
+    next_generation: u64,
+    frames: HashMap<CoalesceKey, (u64, Bytes)>,
+}
+
+impl CoalescingQueue {
+    /// Replaces the queued frame of the key, returns the generation of the new frame
+    fn push(&self, key: CoalesceKey, data: Bytes) -> u64 {
+        let mut queue = self.frames.lock().expect("coalescing queue mutex poisoned");
+
+        let generation = queue.next_generation;
+        queue.next_generation += 1;
+
+        if queue.frames.insert(key, (generation, data)).is_some() {
+            trace!("Replacing superseded frame on channel {}", key.0);
+        }
+
+        generation
+    }
+
+    /// None if a newer frame of the key was queued after this generation
+    fn take(&self, key: CoalesceKey, generation: u64) -> Option<Bytes> {
+        let mut queue = self.frames.lock().expect("coalescing queue mutex poisoned");
+
+        match queue.frames.get(&key) {
+            Some((queued_generation, _)) if *queued_generation == generation => {
+                queue.frames.remove(&key).map(|(_, data)| data)
+            }
+            _ => None,
+        }
+    }
+}
+
 pub struct WebSocketTransportEvents {
-    event_receiver: Receiver<TransportEvent>,
+    event_receiver: Receiver<QueuedEvent>,
+    coalesced: Arc<CoalescingQueue>,
 }
 
 #[async_trait]
 impl TransportEvents for WebSocketTransportEvents {
     async fn poll_event(&mut self) -> Result<TransportEvent, TransportError> {
         trace!("Polling WebSocketEvents");
-        self.event_receiver
-            .recv()
-            .await
-            .ok_or(TransportError::Closed)
+        loop {
+            match self.event_receiver.recv().await {
+                Some(QueuedEvent::Event(event)) => return Ok(event),
+                Some(QueuedEvent::Coalesced(key, generation)) => {
+                    if let Some(data) = self.coalesced.take(key, generation) {
+                        return Ok(TransportEvent::SendIpc(
+                            StreamerIpcMessage::WebSocketTransport(data),
+                        ));
+                    }
+                }
+                None => return Err(TransportError::Closed),
+            }
+        }
     }
 }
 
 pub struct WebSocketTransportSender {
-    event_sender: Sender<TransportEvent>,
+    event_sender: Sender<QueuedEvent>,
+    coalesced: Arc<CoalescingQueue>,
 }
 
 #[async_trait]
@@ -62,8 +143,9 @@ impl TransportSender for WebSocketTransportSender {
     async fn send_video_unit<'a>(
         &'a self,
         unit: &'a VideoDecodeUnit<'a>,
+        frame_id: u32,
     ) -> Result<DecodeResult, TransportError> {
-        let mut new_buffer = vec![0; 5];
+        let mut new_buffer = vec![0; 9];
 
         let mut byte_buffer = ByteBuffer::new(new_buffer.as_mut_slice());
         byte_buffer.put_u8(TransportChannelId::HOST_VIDEO);
@@ -72,15 +154,16 @@ impl TransportSender for WebSocketTransportSender {
             FrameType::PFrame => 0,
         });
         byte_buffer.put_u32(unit.presentation_time.as_micros() as u32);
+        byte_buffer.put_u32(frame_id);
 
         for buffer in unit.buffers {
             new_buffer.extend_from_slice(buffer.data);
         }
         // TODO: ignore h264/h265 fillerdata?
         self.event_sender
-            .send(TransportEvent::SendIpc(
+            .send(QueuedEvent::Event(TransportEvent::SendIpc(
                 StreamerIpcMessage::WebSocketTransport(Bytes::from(new_buffer)),
-            ))
+            )))
             .await
             .unwrap();
 
@@ -104,35 +187,56 @@ impl TransportSender for WebSocketTransportSender {
         new_buffer.extend_from_slice(data);
 
         self.event_sender
-            .send(TransportEvent::SendIpc(
+            .send(QueuedEvent::Event(TransportEvent::SendIpc(
                 StreamerIpcMessage::WebSocketTransport(Bytes::from(new_buffer)),
-            ))
+            )))
             .await
             .unwrap();
 
         Ok(())
     }
 
-    async fn send(&self, packet: OutboundPacket) -> Result<(), TransportError> {
-        let mut new_buffer = Vec::new();
+    async fn send_on(
+        &self,
+        channel: TransportChannel,
+        payload: Bytes,
+        class: DeliveryClass,
+    ) -> Result<(), TransportError> {
+        let mut new_buffer = Vec::with_capacity(payload.len() + 1);
+        new_buffer.push(channel.0);
+        new_buffer.extend_from_slice(&payload);
+        let data = Bytes::from(new_buffer);
 
-        let (id, mut range) = packet.serialize(&mut new_buffer).unwrap();
+        let event = match class {
+            // A Web Socket is always reliable and ordered, so on slow links the packets would queue up.
+            // A queued frame is replaced instead of sending it late.
+            DeliveryClass::Latest => {
+                let key = (channel.0, payload.first().copied(), payload.get(1).copied());
+                let generation = self.coalesced.push(key, data);
 
-        if range.start == 0 {
-            new_buffer.resize(range.end - range.start + 1, 0);
-            new_buffer.copy_within(range.clone(), range.start + 1);
-            range.start += 1;
-        }
-        new_buffer[range.start - 1] = id.0;
+                QueuedEvent::Coalesced(key, generation)
+            }
+            DeliveryClass::Reliable | DeliveryClass::BestEffort => QueuedEvent::Event(
+                TransportEvent::SendIpc(StreamerIpcMessage::WebSocketTransport(data)),
+            ),
+        };
 
-        self.event_sender
-            .send(TransportEvent::SendIpc(
-                StreamerIpcMessage::WebSocketTransport(Bytes::from(new_buffer)),
-            ))
-            .await
-            .unwrap();
+        if class != DeliveryClass::BestEffort {
+            return self
+                .event_sender
+                .send(event)
+                .await
+                .map_err(|_| TransportError::Closed);
+        }
 
-        Ok(())
+        match self.event_sender.try_send(event) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                trace!("Dropping best effort frame on channel {}", channel.0);
+                Ok(())
+            }
+            Err(TrySendError::Closed(_)) => Err(TransportError::Closed),
+        }
     }
 
     async fn on_ipc_message(&self, message: ServerIpcMessage) -> Result<(), TransportError> {
@@ -153,7 +257,7 @@ impl TransportSender for WebSocketTransportSender {
                 };
 
                 self.event_sender
-                    .send(TransportEvent::RecvPacket(packet))
+                    .send(QueuedEvent::Event(TransportEvent::RecvPacket(packet)))
                     .await
                     .unwrap();
             }
@@ -167,6 +271,14 @@ impl TransportSender for WebSocketTransportSender {
                 video_supported_formats,
                 video_colorspace,
                 video_color_range_full,
+                keyboard_mode,
+                keyboard_layout,
+                custom_keyboard_layout,
+                key_overrides,
+                allow_keyboard,
+                allow_mouse,
+                allow_gamepad,
+                allow_touch,
             }) => {
                 let video_supported_formats = SupportedVideoFormats::from_bits(video_supported_formats).unwrap_or_else(|| {
                     warn!("Failed to deserialize SupportedVideoFormats: {video_supported_formats}, falling back to only H264");
@@ -174,7 +286,7 @@ impl TransportSender for WebSocketTransportSender {
                 });
 
                 self.event_sender
-                    .send(TransportEvent::StartStream {
+                    .send(QueuedEvent::Event(TransportEvent::StartStream {
                         settings: StreamSettings {
                             bitrate,
                             packet_size,
@@ -185,8 +297,18 @@ impl TransportSender for WebSocketTransportSender {
                             video_color_range_full,
                             video_colorspace: video_colorspace.into(),
                             play_audio_local,
+                            keyboard_mode,
+                            keyboard_layout,
+                            custom_keyboard_layout,
+                            key_overrides,
+                            allowed_inputs: AllowedInputs {
+                                keyboard: allow_keyboard,
+                                mouse: allow_mouse,
+                                gamepad: allow_gamepad,
+                                touch: allow_touch,
+                            },
                         },
-                    })
+                    }))
                     .await
                     .unwrap();
             }
@@ -200,3 +322,198 @@ impl TransportSender for WebSocketTransportSender {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use common::{
+        api_bindings::{StreamerStatsUpdate, TransportChannelId},
+        ipc::{ServerIpcMessage, StreamerIpcMessage},
+    };
+    use tokio::time::timeout;
+
+    use crate::transport::{
+        DeliveryClass, InboundPacket, OutboundPacket, TransportChannel, TransportEvent,
+        TransportEvents, TransportSender, web_socket,
+    };
+
+    async fn poll_frame(events: &mut web_socket::WebSocketTransportEvents) -> Bytes {
+        match events.poll_event().await.expect("transport closed") {
+            TransportEvent::SendIpc(StreamerIpcMessage::WebSocketTransport(data)) => data,
+            event => panic!("unexpected event {event:?}"),
+        }
+    }
+
+    fn rumble(controller_number: u8, low_frequency_motor: u16) -> OutboundPacket {
+        OutboundPacket::ControllerRumble {
+            controller_number,
+            low_frequency_motor,
+            high_frequency_motor: 0,
+        }
+    }
+
+    fn trigger_rumble(controller_number: u8, left_trigger_motor: u16) -> OutboundPacket {
+        OutboundPacket::ControllerTriggerRumble {
+            controller_number,
+            left_trigger_motor,
+            right_trigger_motor: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_coalesce_rumble() {
+        let (sender, mut events) = web_socket::new().await.expect("failed to create transport");
+        assert!(matches!(
+            events.poll_event().await,
+            Ok(TransportEvent::Ready)
+        ));
+
+        sender.send(rumble(0, 1)).await.expect("failed to send");
+        sender.send(rumble(1, 2)).await.expect("failed to send");
+        // Supersedes the first rumble of controller 0
+        sender.send(rumble(0, 3)).await.expect("failed to send");
+        // Another kind of rumble for the same controller
+        sender
+            .send(trigger_rumble(0, 4))
+            .await
+            .expect("failed to send");
+
+        // The newest rumble of controller 0 is sent where it was queued
+        for (kind, controller_number, value) in [(0, 1, 2u16), (0, 0, 3), (1, 0, 4)] {
+            let frame = poll_frame(&mut events).await;
+            assert_eq!(frame[0], TransportChannelId::CONTROLLERS);
+            assert_eq!(frame[1], kind);
+            assert_eq!(frame[2], controller_number);
+            assert_eq!(&frame[3..5], &value.to_be_bytes());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_coalesced_frames_keep_order() {
+        let (sender, mut events) = web_socket::new().await.expect("failed to create transport");
+        assert!(matches!(
+            events.poll_event().await,
+            Ok(TransportEvent::Ready)
+        ));
+
+        let general = TransportChannel(TransportChannelId::GENERAL);
+
+        sender.send(rumble(0, 1)).await.expect("failed to send");
+        sender
+            .send_on(general, Bytes::from_static(b"a"), DeliveryClass::Reliable)
+            .await
+            .expect("failed to send");
+        sender.send(rumble(0, 3)).await.expect("failed to send");
+        sender
+            .send_on(general, Bytes::from_static(b"b"), DeliveryClass::Reliable)
+            .await
+            .expect("failed to send");
+
+        // The superseded rumble is dropped, everything else keeps the order it was sent in
+        let frame = poll_frame(&mut events).await;
+        assert_eq!(&frame[..], &[TransportChannelId::GENERAL, b'a']);
+
+        let frame = poll_frame(&mut events).await;
+        assert_eq!(frame[0], TransportChannelId::CONTROLLERS);
+        assert_eq!(&frame[3..5], &3u16.to_be_bytes());
+
+        let frame = poll_frame(&mut events).await;
+        assert_eq!(&frame[..], &[TransportChannelId::GENERAL, b'b']);
+    }
+
+    #[tokio::test]
+    async fn test_newest_rumble_is_never_dropped() {
+        let (sender, mut events) = web_socket::new().await.expect("failed to create transport");
+        assert!(matches!(
+            events.poll_event().await,
+            Ok(TransportEvent::Ready)
+        ));
+
+        let stats = OutboundPacket::Stats(StreamerStatsUpdate::Rtt {
+            rtt_ms: 1.0,
+            rtt_variance_ms: 0.0,
+        });
+
+        // Fill the queue with best effort packets, the rumble waits for space instead of being dropped
+        for _ in 0..100 {
+            sender.send(stats.clone()).await.expect("failed to send");
+        }
+        let send_rumble = sender.send(rumble(0, 1));
+        let poll = async {
+            loop {
+                let frame = poll_frame(&mut events).await;
+                if frame[0] == TransportChannelId::CONTROLLERS {
+                    return frame;
+                }
+            }
+        };
+        let (result, frame) = timeout(Duration::from_secs(5), async {
+            tokio::join!(send_rumble, poll)
+        })
+        .await
+        .expect("the rumble wasn't sent");
+
+        result.expect("failed to send");
+        assert_eq!(&frame[3..5], &1u16.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_best_effort_is_dropped_when_busy() {
+        let (sender, mut events) = web_socket::new().await.expect("failed to create transport");
+        assert!(matches!(
+            events.poll_event().await,
+            Ok(TransportEvent::Ready)
+        ));
+
+        let stats = OutboundPacket::Stats(StreamerStatsUpdate::Rtt {
+            rtt_ms: 1.0,
+            rtt_variance_ms: 0.0,
+        });
+
+        // Nobody polls the events, so the queue fills up but sending never blocks
+        timeout(Duration::from_secs(5), async {
+            for _ in 0..100 {
+                sender.send(stats.clone()).await.expect("failed to send");
+            }
+        })
+        .await
+        .expect("sending best effort packets blocked");
+
+        let mut received = 0;
+        while let Ok(Ok(TransportEvent::SendIpc(StreamerIpcMessage::WebSocketTransport(frame)))) =
+            timeout(Duration::from_millis(100), events.poll_event()).await
+        {
+            assert_eq!(frame[0], TransportChannelId::STATS);
+            received += 1;
+        }
+        assert!(received > 0 && received < 100, "received {received} frames");
+    }
+
+    #[tokio::test]
+    async fn test_receive_stats_interval() {
+        let (sender, mut events) = web_socket::new().await.expect("failed to create transport");
+        assert!(matches!(
+            events.poll_event().await,
+            Ok(TransportEvent::Ready)
+        ));
+
+        for (millis, expected) in [(250u32, Some(250)), (0, None)] {
+            let mut frame = vec![TransportChannelId::STATS, 0];
+            frame.extend_from_slice(&millis.to_be_bytes());
+
+            sender
+                .on_ipc_message(ServerIpcMessage::WebSocketTransport(Bytes::from(frame)))
+                .await
+                .expect("failed to receive");
+
+            match events.poll_event().await.expect("transport closed") {
+                TransportEvent::RecvPacket(InboundPacket::SetStatsInterval { millis, .. }) => {
+                    assert_eq!(millis, expected);
+                }
+                event => panic!("unexpected event {event:?}"),
+            }
+        }
+    }
+}
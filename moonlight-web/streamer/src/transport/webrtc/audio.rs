@@ -45,6 +45,13 @@ impl WebRtcAudio {
             config: None,
         }
     }
+
+    pub async fn queue_len(&self) -> usize {
+        self.sender.queue_len().await
+    }
+    pub async fn clear_queue(&self) {
+        self.sender.clear_queue(true).await;
+    }
 }
 
 impl WebRtcAudio {
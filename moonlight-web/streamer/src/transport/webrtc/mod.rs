@@ -1,4 +1,5 @@
 use std::{
+    collections::HashMap,
     future::ready,
     pin::Pin,
     sync::{Arc, Weak},
@@ -8,9 +9,9 @@ use std::{
 use async_trait::async_trait;
 use bytes::Bytes;
 use common::{
-    StreamSettings,
+    AllowedInputs, StreamSettings,
     api_bindings::{
-        RtcIceCandidate, RtcSdpType, RtcSessionDescription, StreamClientMessage,
+        RoomRole, RtcIceCandidate, RtcSdpType, RtcSessionDescription, StreamClientMessage,
         StreamServerMessage, StreamSignalingMessage, TransportChannelId,
     },
     config::{PortRange, WebRtcConfig},
@@ -38,9 +39,13 @@ use webrtc::{
         setting_engine::SettingEngine,
     },
     data_channel::{RTCDataChannel, data_channel_message::DataChannelMessage},
-    ice::udp_network::{EphemeralUDP, UDPNetwork},
+    ice::{
+        mdns::MulticastDnsMode,
+        udp_network::{EphemeralUDP, UDPNetwork},
+    },
     ice_transport::{
         ice_candidate::{RTCIceCandidate, RTCIceCandidateInit},
+        ice_candidate_type::RTCIceCandidateType,
         ice_connection_state::RTCIceConnectionState,
     },
     interceptor::registry::Registry,
@@ -54,10 +59,11 @@ use webrtc::{
 
 use crate::{
     convert::{
-        from_webrtc_sdp, into_webrtc_ice, into_webrtc_ice_candidate, into_webrtc_network_type,
+        from_webrtc_sdp, into_webrtc_data_channel_init, into_webrtc_ice, into_webrtc_ice_candidate,
+        into_webrtc_network_type,
     },
     transport::{
-        InboundPacket, OutboundPacket, TransportChannel, TransportError, TransportEvent,
+        DeliveryClass, InboundPacket, TransportChannel, TransportError, TransportEvent,
         TransportEvents, TransportSender,
         webrtc::{
             audio::{WebRtcAudio, register_audio_codecs},
@@ -77,15 +83,19 @@ struct WebRtcInner {
     peer: Arc<RTCPeerConnection>,
     event_sender: Sender<TransportEvent>,
     general_channel: Arc<RTCDataChannel>,
-    stats_channel: Mutex<Option<Arc<RTCDataChannel>>>,
+    /// The channels created by the client which are used to send packets, e.g. stats
+    outbound_channels: Mutex<HashMap<u8, Arc<RTCDataChannel>>>,
     video: Mutex<WebRtcVideo>,
     audio: Mutex<WebRtcAudio>,
+    /// Guests might not be allowed to see the local addresses
+    send_host_candidates: bool,
     // Timeout / Terminate
     pub timeout_terminate_request: Mutex<Option<Instant>>,
 }
 
 pub async fn new(
     config: &WebRtcConfig,
+    role: RoomRole,
     video_frame_queue_size: usize,
     audio_sample_queue_size: usize,
 ) -> Result<(WebRTCTransportSender, WebRTCTransportEvents), anyhow::Error> {
@@ -128,6 +138,28 @@ pub async fn new(
 
     api_settings.set_include_loopback_candidate(config.include_loopback_candidates);
 
+    if !config.interface_allowlist.is_empty() || !config.interface_blocklist.is_empty() {
+        let interface_config = Arc::new(config.clone());
+        let ip_config = interface_config.clone();
+
+        api_settings.set_interface_filter(Box::new(move |name: &str| {
+            interface_config.is_interface_allowed(name)
+        }));
+        api_settings.set_ip_filter(Box::new(move |ip| ip_config.is_ip_allowed(ip)));
+    }
+    if config.disable_mdns {
+        api_settings.set_ice_multicast_dns_mode(MulticastDnsMode::Disabled);
+    }
+
+    let send_host_candidates = role.is_host() || config.guest_host_candidates;
+    debug!(
+        "[Stream]: ICE candidate policy for {role:?}: allowlist {:?}, blocklist {:?}, mdns {}, host candidates {}",
+        config.interface_allowlist,
+        config.interface_blocklist,
+        !config.disable_mdns,
+        send_host_candidates
+    );
+
     // -- Register media codecs
     // TODO: register them based on the sdp
     let mut api_media = MediaEngine::default();
@@ -152,14 +184,19 @@ pub async fn new(
 
     let peer = Arc::new(api.new_peer_connection(rtc_config).await?);
 
-    let general_channel = peer.create_data_channel("general", None).await?;
+    let general_options = config
+        .data_channel_options()
+        .get("general")
+        .copied()
+        .map(into_webrtc_data_channel_init);
+    let general_channel = peer.create_data_channel("general", general_options).await?;
 
     let runtime = Handle::current();
     let this_owned = Arc::new(WebRtcInner {
         peer: peer.clone(),
         event_sender,
         general_channel,
-        stats_channel: Mutex::new(None),
+        outbound_channels: Mutex::new(HashMap::new()),
         video: Mutex::new(WebRtcVideo::new(
             runtime.clone(),
             Arc::downgrade(&peer),
@@ -170,11 +207,23 @@ pub async fn new(
             Arc::downgrade(&peer),
             audio_sample_queue_size,
         )),
+        send_host_candidates,
         timeout_terminate_request: Mutex::new(None),
     });
 
     let this = Arc::downgrade(&this_owned);
 
+    // -- Readiness
+    // The general channel opens once the peer is connected, only then the stream is sent
+    let ready_sender = this_owned.event_sender.clone();
+    this_owned.general_channel.on_open(Box::new(move || {
+        Box::pin(async move {
+            if let Err(err) = ready_sender.send(TransportEvent::Ready).await {
+                warn!("Failed to send ready event to stream: {err:?}");
+            }
+        }) as Pin<Box<dyn Future<Output = ()> + Send + 'static>>
+    }));
+
     // -- Connection state
     peer.on_ice_connection_state_change(create_event_handler(
         this.clone(),
@@ -215,6 +264,24 @@ pub async fn new(
     ))
 }
 
+/// Removes the host candidates which are already part of the session description,
+/// so guests only get the candidates of STUN and TURN servers
+fn strip_host_candidates(sdp: &str) -> String {
+    sdp.split_inclusive('\n')
+        .filter(|line| {
+            let Some(candidate) = line.trim_end().strip_prefix("a=candidate:") else {
+                return true;
+            };
+
+            let mut parts = candidate
+                .split_whitespace()
+                .skip_while(|part| *part != "typ");
+            parts.next();
+            parts.next() != Some("host")
+        })
+        .collect()
+}
+
 // It compiling...
 #[allow(clippy::complexity)]
 fn create_event_handler<F, Args>(
@@ -258,10 +325,19 @@ fn create_channel_message_handler(
         + 'static,
 > {
     create_event_handler(inner, async move |inner, message: DataChannelMessage| {
-        let Some(packet) = InboundPacket::deserialize(channel, &message.data) else {
+        let Some(mut packet) = InboundPacket::deserialize(channel, &message.data) else {
             return;
         };
 
+        // The browser only knows the rtp timestamp of the rendered frame
+        if let InboundPacket::FrameRendered { frame_id, .. } = &mut packet {
+            let Some(id) = inner.video.lock().await.frame_id(*frame_id) else {
+                trace!("Ignoring rendered frame with unknown rtp timestamp {frame_id}");
+                return;
+            };
+            *frame_id = id;
+        }
+
         if let Err(err) = inner
             .event_sender
             .send(TransportEvent::RecvPacket(packet))
@@ -293,6 +369,15 @@ impl WebRtcInner {
     }
 
     // -- Handle Signaling
+    /// The session description can contain candidates which were gathered before it was created
+    fn guest_sdp(&self, sdp: String) -> String {
+        if self.send_host_candidates {
+            sdp
+        } else {
+            strip_host_candidates(&sdp)
+        }
+    }
+
     async fn send_answer(&self) -> bool {
         let local_description = match self.peer.create_answer(None).await {
             Err(err) => {
@@ -311,10 +396,8 @@ impl WebRtcInner {
             return false;
         }
 
-        debug!(
-            "[Signaling] Sending Local Description as Answer: {:?}",
-            local_description.sdp
-        );
+        let sdp = self.guest_sdp(local_description.sdp);
+        debug!("[Signaling] Sending Local Description as Answer: {sdp:?}");
 
         if let Err(err) = self
             .event_sender
@@ -322,7 +405,7 @@ impl WebRtcInner {
                 StreamServerMessage::WebRtc(StreamSignalingMessage::Description(
                     RtcSessionDescription {
                         ty: from_webrtc_sdp(local_description.sdp_type),
-                        sdp: local_description.sdp,
+                        sdp,
                     },
                 )),
             )))
@@ -351,10 +434,8 @@ impl WebRtcInner {
             return false;
         }
 
-        debug!(
-            "[Signaling] Sending Local Description as Offer: {:?}",
-            local_description.sdp
-        );
+        let sdp = self.guest_sdp(local_description.sdp);
+        debug!("[Signaling] Sending Local Description as Offer: {sdp:?}");
 
         if let Err(err) = self
             .event_sender
@@ -362,7 +443,7 @@ impl WebRtcInner {
                 StreamServerMessage::WebRtc(StreamSignalingMessage::Description(
                     RtcSessionDescription {
                         ty: from_webrtc_sdp(local_description.sdp_type),
-                        sdp: local_description.sdp,
+                        sdp,
                     },
                 )),
             )))
@@ -386,6 +467,14 @@ impl WebRtcInner {
                 video_supported_formats,
                 video_colorspace,
                 video_color_range_full,
+                keyboard_mode,
+                keyboard_layout,
+                custom_keyboard_layout,
+                key_overrides,
+                allow_keyboard,
+                allow_mouse,
+                allow_gamepad,
+                allow_touch,
             } => {
                 let video_supported_formats = SupportedVideoFormats::from_bits(video_supported_formats).unwrap_or_else(|| {
                     warn!("Failed to deserialize SupportedVideoFormats: {video_supported_formats}, falling back to only H264");
@@ -412,6 +501,16 @@ impl WebRtcInner {
                             video_color_range_full,
                             video_colorspace: video_colorspace.into(),
                             play_audio_local,
+                            keyboard_mode,
+                            keyboard_layout,
+                            custom_keyboard_layout,
+                            key_overrides,
+                            allowed_inputs: AllowedInputs {
+                                keyboard: allow_keyboard,
+                                mouse: allow_mouse,
+                                gamepad: allow_gamepad,
+                                touch: allow_touch,
+                            },
                         },
                     })
                     .await
@@ -476,6 +575,14 @@ impl WebRtcInner {
             return;
         };
 
+        if !self.send_host_candidates && candidate.typ == RTCIceCandidateType::Host {
+            trace!(
+                "[Signaling] Not sending host candidate to guest: {}",
+                candidate.address
+            );
+            return;
+        }
+
         let Ok(candidate_json) = candidate.to_json() else {
             return;
         };
@@ -512,26 +619,13 @@ impl WebRtcInner {
 
         match label {
             "stats" => {
-                let mut stats = self.stats_channel.lock().await;
-
-                channel.on_close({
-                    let this = Arc::downgrade(&self);
-
-                    Box::new(move ||{
-                        let this = this.clone();
-
-                        Box::pin(async move {
-                            let Some(this) = this.upgrade() else {
-                                warn!("Failed to close stats channel because the main type is already deallocated");
-                                return;
-                            };
-
-                            this.close_stats().await;
-                        })
-                    })
-                });
-
-                *stats = Some(channel);
+                // The client requests the stats on this channel
+                channel.on_message(create_channel_message_handler(
+                    inner,
+                    TransportChannel(TransportChannelId::STATS),
+                ));
+                self.add_outbound_channel(TransportChannel(TransportChannelId::STATS), channel)
+                    .await;
             }
             "mouse_reliable" | "mouse_absolute" | "mouse_relative" => {
                 channel.on_message(create_channel_message_handler(
@@ -556,6 +650,12 @@ impl WebRtcInner {
                     inner,
                     TransportChannel(TransportChannelId::CONTROLLERS),
                 ));
+                // Rumble is sent back on this channel
+                self.add_outbound_channel(
+                    TransportChannel(TransportChannelId::CONTROLLERS),
+                    channel,
+                )
+                .await;
             }
             _ if let Some(number) = label.strip_prefix("controller")
                 && let Ok(id) = number.parse::<usize>()
@@ -570,10 +670,33 @@ impl WebRtcInner {
         };
     }
 
-    async fn close_stats(&self) {
-        let mut stats = self.stats_channel.lock().await;
+    async fn add_outbound_channel(
+        self: &Arc<Self>,
+        id: TransportChannel,
+        channel: Arc<RTCDataChannel>,
+    ) {
+        let mut channels = self.outbound_channels.lock().await;
 
-        *stats = None;
+        channel.on_close({
+            let this = Arc::downgrade(self);
+
+            Box::new(move || {
+                let this = this.clone();
+
+                Box::pin(async move {
+                    let Some(this) = this.upgrade() else {
+                        warn!(
+                            "Failed to close channel {id:?} because the main type is already deallocated"
+                        );
+                        return;
+                    };
+
+                    this.outbound_channels.lock().await.remove(&id.0);
+                })
+            })
+        });
+
+        channels.insert(id.0, channel);
     }
 
     // -- Termination
@@ -639,9 +762,10 @@ impl TransportSender for WebRTCTransportSender {
     async fn send_video_unit<'a>(
         &'a self,
         unit: &'a VideoDecodeUnit<'a>,
+        frame_id: u32,
     ) -> Result<DecodeResult, TransportError> {
         let mut video = self.inner.video.lock().await;
-        Ok(video.send_decode_unit(unit).await)
+        Ok(video.send_decode_unit(unit, frame_id).await)
     }
 
     async fn setup_audio(
@@ -661,45 +785,43 @@ impl TransportSender for WebRTCTransportSender {
         Ok(())
     }
 
-    async fn send(&self, packet: OutboundPacket) -> Result<(), TransportError> {
-        let mut buffer = Vec::new();
-
-        let Some((channel, range)) = packet.serialize(&mut buffer) else {
-            warn!("Failed to serialize packet: {packet:?}");
-            return Ok(());
+    async fn send_on(
+        &self,
+        channel: TransportChannel,
+        payload: Bytes,
+        class: DeliveryClass,
+    ) -> Result<(), TransportError> {
+        let data_channel = match channel.0 {
+            TransportChannelId::GENERAL => Some(self.inner.general_channel.clone()),
+            id => self.inner.outbound_channels.lock().await.get(&id).cloned(),
         };
 
-        let bytes = Bytes::from(buffer);
-        let bytes = bytes.slice(range);
-
-        match channel.0 {
-            TransportChannelId::GENERAL => match self.inner.general_channel.send(&bytes).await {
-                Ok(_) => {}
-                Err(webrtc::Error::ErrDataChannelNotOpen) => {
-                    return Err(TransportError::ChannelClosed);
-                }
-                _ => {}
-            },
-            TransportChannelId::STATS => {
-                let stats = self.inner.stats_channel.lock().await;
-                if let Some(stats) = stats.as_ref() {
-                    match stats.send(&bytes).await {
-                        Ok(_) => {}
-                        Err(webrtc::Error::ErrDataChannelNotOpen) => {
-                            return Err(TransportError::ChannelClosed);
-                        }
-                        _ => {}
-                    }
-                } else {
-                    return Err(TransportError::ChannelClosed);
+        // The reliability of a data channel is chosen when it's created,
+        // so best effort only means that failing to send is fine
+        let Some(data_channel) = data_channel else {
+            return match class {
+                DeliveryClass::Reliable | DeliveryClass::Latest => {
+                    warn!("Cannot send data on channel {channel:?}");
+                    Err(TransportError::ChannelClosed)
                 }
+                DeliveryClass::BestEffort => Ok(()),
+            };
+        };
+
+        match (data_channel.send(&payload).await, class) {
+            (Ok(_), _) => Ok(()),
+            (Err(err), DeliveryClass::BestEffort) => {
+                trace!("Dropping best effort packet on channel {channel:?}: {err}");
+                Ok(())
             }
-            _ => {
-                warn!("Cannot send data on channel {channel:?}");
-                return Err(TransportError::ChannelClosed);
+            (
+                Err(webrtc::Error::ErrDataChannelNotOpen),
+                DeliveryClass::Reliable | DeliveryClass::Latest,
+            ) => Err(TransportError::ChannelClosed),
+            (Err(err), DeliveryClass::Reliable | DeliveryClass::Latest) => {
+                Err(TransportError::Implementation(err.into()))
             }
         }
-        Ok(())
     }
 
     async fn on_ipc_message(&self, message: ServerIpcMessage) -> Result<(), TransportError> {
@@ -709,6 +831,17 @@ impl TransportSender for WebRTCTransportSender {
         Ok(())
     }
 
+    async fn queue_lengths(&self) -> (usize, usize) {
+        let video = self.inner.video.lock().await.queue_len().await;
+        let audio = self.inner.audio.lock().await.queue_len().await;
+
+        (video, audio)
+    }
+    async fn trim_queues(&self) {
+        self.inner.video.lock().await.clear_queue().await;
+        self.inner.audio.lock().await.clear_queue().await;
+    }
+
     async fn close(&self) -> Result<(), TransportError> {
         self.inner
             .peer
@@ -719,3 +852,104 @@ impl TransportSender for WebRTCTransportSender {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+    use common::{
+        api_bindings::{
+            ConnectionStatus, GeneralServerMessage, RoomRole, StreamerStatsUpdate,
+            TransportChannelId,
+        },
+        config::WebRtcConfig,
+    };
+
+    use crate::transport::{
+        DeliveryClass, OutboundPacket, TransportChannel, TransportError, TransportSender, webrtc,
+        webrtc::strip_host_candidates,
+    };
+
+    #[test]
+    fn test_strip_host_candidates() {
+        let sdp = "v=0\r\n\
+            o=- 1 2 IN IP4 0.0.0.0\r\n\
+            m=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\n\
+            a=mid:0\r\n\
+            a=candidate:1 1 udp 2130706431 172.17.0.1 50000 typ host\r\n\
+            a=candidate:2 1 udp 2130706431 10.8.0.2 50001 typ host\r\n\
+            a=candidate:3 1 udp 1694498815 203.0.113.7 50000 typ srflx raddr 0.0.0.0 rport 50000\r\n\
+            a=candidate:4 1 udp 16777215 198.51.100.3 3478 typ relay raddr 203.0.113.7 rport 50000\r\n\
+            a=end-of-candidates\r\n";
+
+        assert_eq!(
+            strip_host_candidates(sdp),
+            "v=0\r\n\
+            o=- 1 2 IN IP4 0.0.0.0\r\n\
+            m=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\n\
+            a=mid:0\r\n\
+            a=candidate:3 1 udp 1694498815 203.0.113.7 50000 typ srflx raddr 0.0.0.0 rport 50000\r\n\
+            a=candidate:4 1 udp 16777215 198.51.100.3 3478 typ relay raddr 203.0.113.7 rport 50000\r\n\
+            a=end-of-candidates\r\n"
+        );
+        // Session descriptions without candidates stay the same
+        assert_eq!(
+            strip_host_candidates("v=0\r\na=mid:0\r\n"),
+            "v=0\r\na=mid:0\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_before_channels_open() {
+        let (sender, _events) = webrtc::new(&WebRtcConfig::default(), RoomRole::Host, 3, 20)
+            .await
+            .expect("failed to create transport");
+
+        // The client didn't create the stats channel yet
+        let stats = TransportChannel(TransportChannelId::STATS);
+        assert!(matches!(
+            sender
+                .send_on(stats, Bytes::from_static(b"{}"), DeliveryClass::Reliable)
+                .await,
+            Err(TransportError::ChannelClosed)
+        ));
+        assert!(
+            sender
+                .send_on(stats, Bytes::from_static(b"{}"), DeliveryClass::BestEffort)
+                .await
+                .is_ok()
+        );
+        assert!(
+            sender
+                .send(OutboundPacket::Stats(StreamerStatsUpdate::Rtt {
+                    rtt_ms: 1.0,
+                    rtt_variance_ms: 0.0,
+                }))
+                .await
+                .is_ok()
+        );
+
+        // The general channel only opens once the peer is connected
+        assert!(
+            sender
+                .send(OutboundPacket::General {
+                    message: GeneralServerMessage::ConnectionStatusUpdate {
+                        status: ConnectionStatus::Ok,
+                    },
+                })
+                .await
+                .is_err()
+        );
+
+        // Rumble needs the controllers channel of the client
+        assert!(matches!(
+            sender
+                .send(OutboundPacket::ControllerRumble {
+                    controller_number: 0,
+                    low_frequency_motor: 1,
+                    high_frequency_motor: 2,
+                })
+                .await,
+            Err(TransportError::ChannelClosed)
+        ));
+    }
+}
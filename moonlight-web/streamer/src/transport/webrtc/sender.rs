@@ -159,6 +159,10 @@ where
         result
     }
 
+    pub async fn queue_len(&self) -> usize {
+        self.queue.lock().await.len()
+    }
+
     /// Returns if the frame will be delivered
     pub async fn clear_queue(&self, clear_important: bool) {
         let mut queue = self.queue.lock().await;
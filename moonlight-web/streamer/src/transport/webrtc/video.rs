@@ -1,4 +1,5 @@
 use std::{
+    collections::VecDeque,
     io::Cursor,
     ops::Range,
     sync::{
@@ -55,6 +56,9 @@ mod annexb;
 mod h264;
 mod h265;
 
+/// How many frames the rtp timestamps are remembered for, to find the frames the browser rendered
+const FRAME_IDS_CAPACITY: usize = 256;
+
 enum VideoCodec {
     H264 {
         nal_reader: H264Reader<Cursor<Vec<u8>>>,
@@ -77,6 +81,8 @@ pub struct WebRtcVideo {
     clock_rate: u32,
     codec: Option<VideoCodec>,
     samples: Vec<BytesMut>,
+    /// The rtp timestamps of the sent frames with their frame id, oldest first
+    frame_ids: VecDeque<(u32, u32)>,
 }
 
 impl WebRtcVideo {
@@ -88,9 +94,19 @@ impl WebRtcVideo {
             codec: None,
             supported_video_formats: SupportedVideoFormats::empty(),
             samples: Default::default(),
+            frame_ids: Default::default(),
         }
     }
 
+    pub async fn queue_len(&self) -> usize {
+        self.sender.queue_len().await
+    }
+    /// The frames after the dropped ones can't be decoded, so a keyframe is requested
+    pub async fn clear_queue(&self) {
+        self.sender.clear_queue(true).await;
+        self.needs_idr.store(true, Ordering::Release);
+    }
+
     pub async fn set_codecs(&mut self, supported_codecs: SupportedVideoFormats) {
         self.supported_video_formats = supported_codecs;
     }
@@ -221,11 +237,29 @@ impl WebRtcVideo {
         true
     }
 
-    pub async fn send_decode_unit(&mut self, unit: &VideoDecodeUnit<'_>) -> DecodeResult {
+    /// The id of the frame sent with the rtp timestamp, None if it's unknown or too old
+    pub fn frame_id(&self, rtp_timestamp: u32) -> Option<u32> {
+        self.frame_ids
+            .iter()
+            .rev()
+            .find(|(timestamp, _)| *timestamp == rtp_timestamp)
+            .map(|(_, frame_id)| *frame_id)
+    }
+
+    pub async fn send_decode_unit(
+        &mut self,
+        unit: &VideoDecodeUnit<'_>,
+        frame_id: u32,
+    ) -> DecodeResult {
         trace!("Starting frame");
 
         let timestamp = (unit.presentation_time.as_secs_f64() * self.clock_rate as f64) as u32;
 
+        if self.frame_ids.len() >= FRAME_IDS_CAPACITY {
+            self.frame_ids.pop_front();
+        }
+        self.frame_ids.push_back((timestamp, frame_id));
+
         let mut full_frame = Vec::new();
         for buffer in unit.buffers {
             full_frame.extend_from_slice(buffer.data);
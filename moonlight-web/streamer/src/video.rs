@@ -1,23 +1,42 @@
 use std::{
-    sync::{Arc, Weak},
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, PoisonError, Weak, atomic::Ordering},
     time::{Duration, Instant},
 };
 
-use common::api_bindings::{StatsHostProcessingLatency, StreamerStatsUpdate};
-use log::{debug, error, warn};
+use common::{
+    api_bindings::{StatsHostProcessingLatency, StreamerStatsUpdate},
+    ipc::PeerId,
+};
+use log::{debug, info, warn};
 use moonlight_common::stream::{
     bindings::{
-        Capabilities, DecodeResult, EstimatedRttInfo, SupportedVideoFormats, VideoDecodeUnit,
+        Capabilities, DecodeResult, EstimatedRttInfo, FrameType, SupportedVideoFormats,
+        VideoDecodeUnit,
     },
     video::{VideoDecoder, VideoSetup},
 };
+use tokio::task::JoinHandle;
+
+use crate::{
+    StreamConnection, delay::OwnedVideoUnit, recording::VideoRecorder, transport::OutboundPacket,
+};
 
-use crate::{StreamConnection, transport::OutboundPacket};
+/// How often a keyframe is requested while the memory is above the limit and the peers only receive the keyframes
+const KEYFRAME_ONLY_INTERVAL: Duration = Duration::from_secs(2);
 
 pub(crate) struct StreamVideoDecoder {
     pub(crate) stream: Weak<StreamConnection>,
     pub(crate) supported_formats: SupportedVideoFormats,
     pub(crate) stats: VideoStats,
+    /// The directory to record the video to, disabled if none
+    pub(crate) record_path: Option<PathBuf>,
+    pub(crate) recorder: Option<VideoRecorder>,
+    /// When the last keyframe was sent or requested while the peers only receive the keyframes
+    pub(crate) last_keyframe: Option<Instant>,
+    /// The id of the next frame, wraps around
+    pub(crate) next_frame_id: u32,
 }
 
 impl VideoDecoder for StreamVideoDecoder {
@@ -31,35 +50,58 @@ impl VideoDecoder for StreamVideoDecoder {
             let mut stream_info = stream.stream_setup.blocking_lock();
             stream_info.video = Some(setup);
         }
+        // The keyframe of the previous setup can't be decoded anymore
+        *stream
+            .cached_keyframe
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = None;
 
-        // Setup video on all peer transports
-        stream.runtime.clone().block_on(async move {
-            let transports = stream.peer_transports.read().await;
-            if transports.is_empty() {
-                error!("Failed to setup video because no transports are connected!");
-                return -1;
-            }
-
-            let mut result = 0i32;
-            for (_peer_id, transport) in transports.iter() {
-                let r = transport.sender.setup_video(setup).await;
-                if r != 0 {
-                    result = r;
+        if let Some(record_path) = &self.record_path {
+            match VideoRecorder::create(record_path, &setup) {
+                Ok(recorder) => {
+                    info!("Recording video to {:?}", recorder.path());
+                    self.recorder = Some(recorder);
+                }
+                Err(err) => {
+                    warn!("Failed to start recording video to {record_path:?}: {err}");
                 }
             }
-            result
-        })
+        }
+
+        // Setup video on all peer transports
+        stream
+            .runtime
+            .clone()
+            .block_on(async move { stream.setup_video_transports(setup).await })
     }
 
     fn start(&mut self) {}
-    fn stop(&mut self) {}
+    fn stop(&mut self) {
+        if let Some(recorder) = self.recorder.take()
+            && let Err(err) = recorder.finish()
+        {
+            warn!("Failed to finish recording video: {err}");
+        }
+    }
 
     fn submit_decode_unit(&mut self, unit: VideoDecodeUnit<'_>) -> DecodeResult {
+        if let Some(recorder) = &mut self.recorder
+            && let Err(err) = recorder.write_unit(&unit)
+        {
+            warn!("Failed to record video, stopping the recording: {err}");
+            self.recorder = None;
+        }
+
         let Some(stream) = self.stream.upgrade() else {
             warn!("Failed to send video decode unit because stream is deallocated");
             return DecodeResult::Ok;
         };
 
+        // The peers get a keyframe when the host resumes the stream
+        if stream.paused.load(Ordering::Relaxed) {
+            return DecodeResult::Ok;
+        }
+
         stream.runtime.clone().block_on(async {
             let transports = stream.peer_transports.read().await;
 
@@ -71,9 +113,45 @@ impl VideoDecoder for StreamVideoDecoder {
             let start = Instant::now();
             let mut final_result = DecodeResult::Ok;
 
+            let frame_id = self.next_frame_id;
+            self.next_frame_id = self.next_frame_id.wrapping_add(1);
+
+            let peer_delays = stream.peer_delays.read().await;
+            let thumbnail_peers = stream.thumbnail_peers.read().await;
+            let keyframe_only = stream.keyframe_only.load(Ordering::Acquire);
+
             // Send to all peer transports
             for (peer_id, transport) in transports.iter() {
-                match transport.sender.send_video_unit(&unit).await {
+                // The transport is still connecting or can't decode the stream until the next keyframe
+                if !transport.readiness.accepts_video(unit.frame_type) {
+                    continue;
+                }
+
+                // Peers in the thumbnail mode only get the keyframes, all peers while the memory is above the limit
+                if matches!(unit.frame_type, FrameType::PFrame)
+                    && (keyframe_only || thumbnail_peers.contains(peer_id))
+                {
+                    continue;
+                }
+
+                // Delayed peers get the unit once the delay is over
+                if let Some(peer_delay) = peer_delays.get(peer_id) {
+                    if peer_delay.delayed.push_video(&unit, frame_id) {
+                        final_result = DecodeResult::NeedIdr;
+                    }
+                    continue;
+                }
+
+                let result = transport.sender.send_video_unit(&unit, frame_id).await;
+                if result.is_ok() {
+                    transport
+                        .frame_timings
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .sent(frame_id, Instant::now());
+                }
+
+                match result {
                     Err(err) => {
                         warn!("Failed to send video decode unit to peer {:?}: {err}", peer_id);
                     }
@@ -87,8 +165,35 @@ impl VideoDecoder for StreamVideoDecoder {
                 }
             }
 
+            if stream.request_idr.swap(false, Ordering::Relaxed) {
+                final_result = DecodeResult::NeedIdr;
+            }
+
+            // Without a new keyframe the peers would see a still image while the memory is above the limit.
+            // The peers in the thumbnail mode only get the keyframes the stream needs anyway.
+            if matches!(unit.frame_type, FrameType::Idr) {
+                self.last_keyframe = Some(start);
+                *stream
+                    .cached_keyframe
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner) =
+                    Some(Arc::new(OwnedVideoUnit::new(&unit, frame_id)));
+            } else if keyframe_only
+                && self
+                    .last_keyframe
+                    .is_none_or(|last| start - last >= KEYFRAME_ONLY_INTERVAL)
+            {
+                self.last_keyframe = Some(start);
+                final_result = DecodeResult::NeedIdr;
+            }
+
             let frame_processing_time = Instant::now() - start;
             self.stats.analyze(&stream, &unit, frame_processing_time);
+            stream
+                .quality
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .record_frame(unit.frame_number);
 
             final_result
         })
@@ -106,6 +211,8 @@ impl VideoDecoder for StreamVideoDecoder {
 #[derive(Debug, Default)]
 pub(crate) struct VideoStats {
     last_send: Option<Instant>,
+    /// When the peers which want the stats got them last
+    peer_sends: HashMap<PeerId, Instant>,
     min_host_processing_latency: Duration,
     max_host_processing_latency: Duration,
     total_host_processing_latency: Duration,
@@ -123,7 +230,16 @@ impl VideoStats {
         unit: &VideoDecodeUnit,
         frame_processing_time: Duration,
     ) {
-        if let Some(host_processing_latency) = unit.frame_processing_latency {
+        self.record(unit.frame_processing_latency, frame_processing_time);
+        self.flush(stream, Instant::now());
+    }
+
+    fn record(
+        &mut self,
+        host_processing_latency: Option<Duration>,
+        frame_processing_time: Duration,
+    ) {
+        if let Some(host_processing_latency) = host_processing_latency {
             self.min_host_processing_latency = self
                 .min_host_processing_latency
                 .min(host_processing_latency);
@@ -140,102 +256,336 @@ impl VideoStats {
             self.max_streamer_processing_time.max(frame_processing_time);
         self.total_streamer_processing_time += frame_processing_time;
         self.streamer_processing_time_frame_count += 1;
+    }
+
+    /// Sends the stats collected since the last send to the peers whose stats interval elapsed.
+    /// The stats are collected over the shortest interval of all peers.
+    fn flush(&mut self, stream: &Arc<StreamConnection>, now: Instant) -> Option<JoinHandle<()>> {
+        // Checked again with the next frame if the peers are being changed
+        let Ok(peer_manager) = stream.peer_manager.try_read() else {
+            return None;
+        };
+        let intervals = peer_manager.stats_intervals().collect::<Vec<_>>();
+        drop(peer_manager);
+
+        // Nobody shows the stats, so the next interval starts whenever a peer requests them
+        let Some(min_interval) = intervals.iter().map(|(_, interval)| *interval).min() else {
+            self.peer_sends.clear();
+            self.clear(now);
+            return None;
+        };
 
-        // Send in 1 sec intervall
         if self
             .last_send
-            .map(|last_send| last_send + Duration::from_secs(1) < Instant::now())
-            .unwrap_or(true)
+            .is_some_and(|last_send| now < last_send + min_interval)
         {
-            // Collect data
-            let has_host_processing_latency = self.host_processing_frame_count > 0;
-            let min_host_processing_latency = self.min_host_processing_latency;
-            let max_host_processing_latency = self.max_host_processing_latency;
-            let avg_host_processing_latency = self
-                .total_host_processing_latency
-                .checked_div(self.host_processing_frame_count as u32)
-                .unwrap_or(Duration::ZERO);
-
-            let min_streamer_processing_time = self.min_streamer_processing_time;
-            let max_streamer_processing_time = self.max_streamer_processing_time;
-            let avg_streamer_processing_time = self
-                .total_streamer_processing_time
-                .checked_div(self.streamer_processing_time_frame_count as u32)
-                .unwrap_or(Duration::ZERO);
-
-            // Send data
-            let runtime = stream.runtime.clone();
-
-            let stream = stream.clone();
-            runtime.spawn(async move {
-                stream
-                    .try_send_packet(
-                        OutboundPacket::Stats(StreamerStatsUpdate::Video {
-                            host_processing_latency: has_host_processing_latency.then_some(
-                                StatsHostProcessingLatency {
-                                    min_host_processing_latency_ms: min_host_processing_latency
-                                        .as_secs_f64()
-                                        * 1000.0,
-                                    max_host_processing_latency_ms: max_host_processing_latency
-                                        .as_secs_f64()
-                                        * 1000.0,
-                                    avg_host_processing_latency_ms: avg_host_processing_latency
-                                        .as_secs_f64()
-                                        * 1000.0,
-                                },
-                            ),
-                            min_streamer_processing_time_ms: min_streamer_processing_time
-                                .as_secs_f64()
-                                * 1000.0,
-                            max_streamer_processing_time_ms: max_streamer_processing_time
-                                .as_secs_f64()
-                                * 1000.0,
-                            avg_streamer_processing_time_ms: avg_streamer_processing_time
-                                .as_secs_f64()
-                                * 1000.0,
-                        }),
-                        "host / streamer processing latency",
-                        false,
-                    )
-                    .await;
-
-                // Send RTT info
-                let ml_stream_lock = stream.stream.read().await;
-                if let Some(ml_stream) = ml_stream_lock.as_ref() {
-                    let rtt = ml_stream.estimated_rtt_info();
-                    drop(ml_stream_lock);
-
-                    match rtt {
-                        Ok(EstimatedRttInfo { rtt, rtt_variance }) => {
-                            stream
-                                .try_send_packet(
-                                    OutboundPacket::Stats(StreamerStatsUpdate::Rtt {
-                                        rtt_ms: rtt.as_secs_f64() * 1000.0,
-                                        rtt_variance_ms: rtt_variance.as_secs_f64() * 1000.0,
-                                    }),
-                                    "estimated rtt info",
-                                    false,
-                                )
-                                .await;
-                        }
-                        Err(err) => {
-                            warn!("failed to get estimated rtt info: {err:?}");
+            return None;
+        }
+
+        self.peer_sends
+            .retain(|peer_id, _| intervals.iter().any(|(id, _)| id == peer_id));
+        let due_peers = intervals
+            .into_iter()
+            .filter(|(peer_id, interval)| {
+                self.peer_sends
+                    .get(peer_id)
+                    .is_none_or(|last_send| *last_send + *interval <= now)
+            })
+            .map(|(peer_id, _)| peer_id)
+            .collect::<Vec<_>>();
+        if due_peers.is_empty() {
+            return None;
+        }
+        for peer_id in &due_peers {
+            self.peer_sends.insert(*peer_id, now);
+        }
+
+        // Collect data
+        let has_host_processing_latency = self.host_processing_frame_count > 0;
+        let avg_host_processing_latency = self
+            .total_host_processing_latency
+            .checked_div(self.host_processing_frame_count as u32)
+            .unwrap_or(Duration::ZERO);
+        let avg_streamer_processing_time = self
+            .total_streamer_processing_time
+            .checked_div(self.streamer_processing_time_frame_count as u32)
+            .unwrap_or(Duration::ZERO);
+
+        let video = OutboundPacket::Stats(StreamerStatsUpdate::Video {
+            host_processing_latency: has_host_processing_latency.then_some(
+                StatsHostProcessingLatency {
+                    min_host_processing_latency_ms: self.min_host_processing_latency.as_secs_f64()
+                        * 1000.0,
+                    max_host_processing_latency_ms: self.max_host_processing_latency.as_secs_f64()
+                        * 1000.0,
+                    avg_host_processing_latency_ms: avg_host_processing_latency.as_secs_f64()
+                        * 1000.0,
+                },
+            ),
+            min_streamer_processing_time_ms: self.min_streamer_processing_time.as_secs_f64()
+                * 1000.0,
+            max_streamer_processing_time_ms: self.max_streamer_processing_time.as_secs_f64()
+                * 1000.0,
+            avg_streamer_processing_time_ms: avg_streamer_processing_time.as_secs_f64() * 1000.0,
+        });
+
+        self.clear(now);
+
+        // Send data
+        let stream = stream.clone();
+        Some(stream.runtime.clone().spawn(async move {
+            for peer_id in &due_peers {
+                stream.send_to_peer(*peer_id, video.clone()).await;
+            }
+
+            // Send the latency of the frames the peers reported as rendered
+            let transports = stream.peer_transports.read().await;
+            let frame_latencies = due_peers
+                .iter()
+                .filter_map(|peer_id| {
+                    let stats = transports
+                        .get(peer_id)?
+                        .frame_timings
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .take_stats()?;
+
+                    Some((*peer_id, OutboundPacket::Stats(stats)))
+                })
+                .collect::<Vec<_>>();
+            drop(transports);
+
+            for (peer_id, frame_latency) in frame_latencies {
+                stream.send_to_peer(peer_id, frame_latency).await;
+            }
+
+            // Send RTT info, only queried when a peer wants it
+            let ml_stream_lock = stream.stream.read().await;
+            if let Some(ml_stream) = ml_stream_lock.as_ref() {
+                let rtt = ml_stream.estimated_rtt_info();
+                drop(ml_stream_lock);
+
+                match rtt {
+                    Ok(EstimatedRttInfo { rtt, rtt_variance }) => {
+                        let rtt = OutboundPacket::Stats(StreamerStatsUpdate::Rtt {
+                            rtt_ms: rtt.as_secs_f64() * 1000.0,
+                            rtt_variance_ms: rtt_variance.as_secs_f64() * 1000.0,
+                        });
+
+                        for peer_id in &due_peers {
+                            stream.send_to_peer(*peer_id, rtt.clone()).await;
                         }
-                    };
+                    }
+                    Err(err) => {
+                        warn!("failed to get estimated rtt info: {err:?}");
+                    }
+                };
+            }
+        }))
+    }
+
+    fn clear(&mut self, now: Instant) {
+        self.min_host_processing_latency = Duration::MAX;
+        self.max_host_processing_latency = Duration::ZERO;
+        self.total_host_processing_latency = Duration::ZERO;
+        self.host_processing_frame_count = 0;
+        self.min_streamer_processing_time = Duration::MAX;
+        self.max_streamer_processing_time = Duration::ZERO;
+        self.total_streamer_processing_time = Duration::ZERO;
+        self.streamer_processing_time_frame_count = 0;
+
+        self.last_send = Some(now);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use common::{
+        api_bindings::{PlayerSlot, RoomRole, StreamerStatsUpdate},
+        ipc::{PeerId, ServerIpcMessage},
+    };
+
+    use crate::{
+        transport::{
+            InboundPacket, OutboundPacket,
+            test::{
+                self as fake_transport, FakeTransportHandle, TestStreamConnection,
+                TestStreamConnectionBuilder,
+            },
+        },
+        video::VideoStats,
+    };
+
+    const INTERVAL: Duration = Duration::from_millis(500);
+
+    async fn connect_peer(test: &TestStreamConnection, peer_id: PeerId) -> FakeTransportHandle {
+        let (sender, events, handle) = fake_transport::new();
+        test.connection
+            .set_peer_transport(peer_id, Box::new(sender), Box::new(events))
+            .await;
+        test.connection
+            .on_ipc_message(ServerIpcMessage::PeerConnected {
+                peer_id,
+                player_slot: Some(PlayerSlot::PLAYER_1),
+                role: RoomRole::Host,
+                video_frame_queue_size: 3,
+                audio_sample_queue_size: 20,
+            })
+            .await;
+        handle
+    }
+
+    async fn set_stats_interval(test: &TestStreamConnection, peer_id: PeerId, millis: Option<u32>) {
+        set_stats_interval_with_frames(test, peer_id, millis, None).await;
+    }
+    async fn set_stats_interval_with_frames(
+        test: &TestStreamConnection,
+        peer_id: PeerId,
+        millis: Option<u32>,
+        frame_sample_interval: Option<u16>,
+    ) {
+        *test.connection.current_peer_id.write().await = Some(peer_id);
+        test.connection
+            .on_packet(InboundPacket::SetStatsInterval {
+                millis,
+                frame_sample_interval,
+            })
+            .await;
+    }
+
+    /// Sends a frame to the peer and lets it report the frame as rendered
+    async fn render_frame(test: &TestStreamConnection, peer_id: PeerId, frame_id: u32) {
+        test.connection
+            .peer_transports
+            .read()
+            .await
+            .get(&peer_id)
+            .expect("peer not connected")
+            .frame_timings
+            .lock()
+            .expect("frame timings poisoned")
+            .sent(frame_id, Instant::now());
+
+        *test.connection.current_peer_id.write().await = Some(peer_id);
+        test.connection
+            .on_packet(InboundPacket::FrameRendered {
+                frame_id,
+                client_render_ts: 0,
+            })
+            .await;
+    }
+
+    async fn flush(test: &TestStreamConnection, stats: &mut VideoStats, now: Instant) {
+        stats.record(Some(Duration::from_millis(2)), Duration::from_millis(1));
+        if let Some(task) = stats.flush(&test.connection, now) {
+            task.await.expect("failed to send stats");
+        }
+    }
+
+    fn frame_latency_samples(handle: &FakeTransportHandle) -> Vec<u32> {
+        handle
+            .take_packets()
+            .into_iter()
+            .filter_map(|packet| match packet {
+                OutboundPacket::Stats(StreamerStatsUpdate::FrameLatency { samples, .. }) => {
+                    Some(samples)
                 }
-            });
-
-            // Clear data
-            self.min_host_processing_latency = Duration::MAX;
-            self.max_host_processing_latency = Duration::ZERO;
-            self.total_host_processing_latency = Duration::ZERO;
-            self.host_processing_frame_count = 0;
-            self.min_streamer_processing_time = Duration::MAX;
-            self.max_streamer_processing_time = Duration::ZERO;
-            self.total_streamer_processing_time = Duration::ZERO;
-            self.streamer_processing_time_frame_count = 0;
-
-            self.last_send = Some(Instant::now());
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn video_stats_count(handle: &FakeTransportHandle) -> usize {
+        handle
+            .take_packets()
+            .into_iter()
+            .filter(|packet| {
+                matches!(
+                    packet,
+                    OutboundPacket::Stats(StreamerStatsUpdate::Video { .. })
+                )
+            })
+            .count()
+    }
+
+    #[tokio::test]
+    async fn test_stats_are_opt_in() {
+        let test = TestStreamConnectionBuilder::new().build().await;
+        let viewer = connect_peer(&test, PeerId(1)).await;
+        let other = connect_peer(&test, PeerId(2)).await;
+
+        let mut stats = VideoStats::default();
+        let start = Instant::now();
+
+        // Nobody requested the stats
+        flush(&test, &mut stats, start).await;
+        flush(&test, &mut stats, start + INTERVAL).await;
+        assert_eq!(video_stats_count(&viewer), 0);
+
+        set_stats_interval(&test, PeerId(1), Some(INTERVAL.as_millis() as u32)).await;
+
+        // The first stats cover a full interval
+        flush(&test, &mut stats, start + INTERVAL + INTERVAL / 2).await;
+        assert_eq!(video_stats_count(&viewer), 0);
+        flush(&test, &mut stats, start + INTERVAL * 2).await;
+        assert_eq!(video_stats_count(&viewer), 1);
+        flush(&test, &mut stats, start + INTERVAL * 3).await;
+        assert_eq!(video_stats_count(&viewer), 1);
+
+        // Only the peer which requested them gets them
+        assert_eq!(video_stats_count(&other), 0);
+    }
+
+    #[tokio::test]
+    async fn test_disabling_stats_stops_them_within_one_interval() {
+        let test = TestStreamConnectionBuilder::new().build().await;
+        let viewer = connect_peer(&test, PeerId(1)).await;
+
+        let mut stats = VideoStats::default();
+        let start = Instant::now();
+
+        set_stats_interval(&test, PeerId(1), Some(INTERVAL.as_millis() as u32)).await;
+        flush(&test, &mut stats, start).await;
+        assert_eq!(video_stats_count(&viewer), 1);
+
+        set_stats_interval(&test, PeerId(1), None).await;
+        for i in 1..4 {
+            flush(&test, &mut stats, start + INTERVAL * i).await;
         }
+        assert_eq!(video_stats_count(&viewer), 0);
+    }
+
+    #[tokio::test]
+    async fn test_frame_latency_is_reported_with_the_stats() {
+        let test = TestStreamConnectionBuilder::new().build().await;
+        let sampling = connect_peer(&test, PeerId(1)).await;
+        let other = connect_peer(&test, PeerId(2)).await;
+
+        let mut stats = VideoStats::default();
+        let start = Instant::now();
+
+        set_stats_interval_with_frames(
+            &test,
+            PeerId(1),
+            Some(INTERVAL.as_millis() as u32),
+            Some(30),
+        )
+        .await;
+        // Reports without enabling them are ignored
+        set_stats_interval(&test, PeerId(2), Some(INTERVAL.as_millis() as u32)).await;
+
+        render_frame(&test, PeerId(1), 0).await;
+        render_frame(&test, PeerId(1), 30).await;
+        render_frame(&test, PeerId(2), 0).await;
+
+        flush(&test, &mut stats, start).await;
+        assert_eq!(frame_latency_samples(&sampling), vec![2]);
+        assert!(frame_latency_samples(&other).is_empty());
+
+        // Nothing was rendered in the next interval
+        flush(&test, &mut stats, start + INTERVAL).await;
+        assert!(frame_latency_samples(&sampling).is_empty());
     }
 }
@@ -1,20 +1,40 @@
 use actix_web::{
-    HttpResponse, delete, get, patch, post,
-    web::{Data, Json},
+    HttpRequest, HttpResponse, delete, get, patch, post, rt as actix_rt,
+    web::{Data, Json, Payload, Query},
 };
+use std::{
+    mem,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
 use common::api_bindings::{
-    DeleteUserRequest, DetailedUser, GetUsersResponse, PatchUserRequest, PostUserRequest,
+    AppImageCacheMetrics, AuditLogAction, AuditLogEntry, DeleteUserRequest, DetailedUser,
+    ExportedUser, GetAuditLogResponse, GetInactiveUsersQuery, GetMetricsResponse,
+    GetUsersExportQuery, GetUsersResponse, ImportUserResult, ImportedUser, PatchUserRequest,
+    PostImportMoonlightQtRequest, PostImportResponse, PostUserRequest, PostUsersImportQuery,
+    PostUsersImportResponse, UsersExportHeader,
 };
-use futures::future::join_all;
+use futures::{StreamExt, future::join_all};
 use log::warn;
+use tokio::spawn;
 
-use crate::app::{
-    App, AppError,
-    password::StoragePassword,
-    storage::{StorageUserAdd, StorageUserModify},
-    user::{Admin, AuthenticatedUser, Role, UserId},
+use crate::{
+    api::response_streaming::StreamedResponse,
+    app::{
+        App, AppError,
+        audit::AuditAction,
+        import::MoonlightQtConfig,
+        password::StoragePassword,
+        storage::{StorageUserAdd, StorageUserModify},
+        user::{Admin, AuthenticatedUser, Role, UserId, parse_pair_device_name},
+        user_transfer::{USER_EXPORT_VERSION, UserImportParser, UserImportRow},
+    },
 };
 
+/// Exporting the password hashes and the pair info must be confirmed with `true`
+pub const HEADER_CONFIRM_EXPORT_SECRETS: &str = "X-Confirm-Export-Secrets";
+
 #[post("/user")]
 pub async fn add_user(
     app: Data<App>,
@@ -46,6 +66,12 @@ pub async fn patch_user(
 ) -> Result<HttpResponse, AppError> {
     let target_user_id = UserId(request.id);
 
+    let pair_device_name = if request.change_pair_device_name {
+        Some(parse_pair_device_name(request.pair_device_name.clone())?)
+    } else {
+        None
+    };
+
     match Admin::try_from(user).await? {
         Ok(admin) => {
             let mut target_user = app.user_by_id(target_user_id).await?;
@@ -63,6 +89,8 @@ pub async fn patch_user(
                         password: Some(new_password),
                         role: request.role.map(Role::from),
                         client_unique_id: request.client_unique_id,
+                        pair_device_name,
+                        ..Default::default()
                     },
                 )
                 .await?;
@@ -72,12 +100,14 @@ pub async fn patch_user(
                 return Err(AppError::Forbidden);
             }
 
-            // Only allow changing the password
+            // Only allow changing the password and the pair device name
             let PatchUserRequest {
                 id: _,
                 password: _,
                 role,
                 client_unique_id,
+                change_pair_device_name: _,
+                pair_device_name: _,
             } = &request;
             if role.is_some() || client_unique_id.is_some() {
                 return Err(AppError::Forbidden);
@@ -87,6 +117,9 @@ pub async fn patch_user(
                 user.set_password(StoragePassword::new(&new_password)?)
                     .await?;
             }
+            if let Some(pair_device_name) = pair_device_name {
+                user.set_pair_device_name(pair_device_name).await?;
+            }
         }
     }
 
@@ -128,3 +161,271 @@ pub async fn list_users(app: Data<App>, admin: Admin) -> Result<Json<GetUsersRes
 
     Ok(Json(GetUsersResponse { users: out_users }))
 }
+
+#[get("/users/inactive")]
+pub async fn list_inactive_users(
+    app: Data<App>,
+    admin: Admin,
+    Query(query): Query<GetInactiveUsersQuery>,
+) -> Result<Json<GetUsersResponse>, AppError> {
+    let since = SystemTime::now()
+        .checked_sub(Duration::from_hours(query.days as u64 * 24))
+        .unwrap_or(UNIX_EPOCH);
+
+    let users = app.all_users(admin).await?;
+
+    let mut out_users = Vec::new();
+    for mut user in users {
+        match user.is_inactive_since(since).await {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(err) => {
+                warn!("Failed to query the activity of {user:?}: {err}");
+                continue;
+            }
+        }
+
+        match user.detailed_user_no_auth().await {
+            Ok(detailed_user) => out_users.push(detailed_user),
+            Err(err) => {
+                warn!("Failed to query detailed user of {user:?}: {err}");
+            }
+        }
+    }
+
+    Ok(Json(GetUsersResponse { users: out_users }))
+}
+
+#[get("/audit")]
+pub async fn get_audit_log(
+    app: Data<App>,
+    admin: Admin,
+) -> Result<Json<GetAuditLogResponse>, AppError> {
+    let entries = app.audit_log(&admin).await?;
+
+    Ok(Json(GetAuditLogResponse {
+        entries: entries
+            .into_iter()
+            .map(|entry| AuditLogEntry {
+                time: entry
+                    .time
+                    .duration_since(UNIX_EPOCH)
+                    .map(|time| time.as_millis() as u64)
+                    .unwrap_or_default(),
+                actor_id: entry.actor.0,
+                action: match entry.action {
+                    AuditAction::Impersonation { user, method, path } => {
+                        AuditLogAction::Impersonation {
+                            user_id: user.0,
+                            method,
+                            path,
+                        }
+                    }
+                    AuditAction::RemoteUnpair {
+                        host,
+                        address,
+                        result,
+                    } => AuditLogAction::RemoteUnpair {
+                        host_id: host.0,
+                        address,
+                        result,
+                    },
+                    AuditAction::StreamStarted {
+                        room,
+                        host,
+                        app_id,
+                        app_name,
+                    } => AuditLogAction::StreamStarted {
+                        room_id: room,
+                        host_id: host.0,
+                        app_id,
+                        app_name,
+                    },
+                    AuditAction::UsersExported { include_hosts } => {
+                        AuditLogAction::UsersExported { include_hosts }
+                    }
+                },
+            })
+            .collect(),
+    }))
+}
+
+#[get("/metrics")]
+pub async fn get_metrics(app: Data<App>, admin: Admin) -> Json<GetMetricsResponse> {
+    let app_image_cache = app.app_image_cache_stats(&admin).await;
+    let streamers = app.room_manager().streamer_metrics().await;
+
+    Json(GetMetricsResponse {
+        app_image_cache: AppImageCacheMetrics {
+            hits: app_image_cache.hits,
+            misses: app_image_cache.misses,
+            entries: app_image_cache.entries,
+            size: app_image_cache.size,
+        },
+        streamers,
+    })
+}
+
+/// Invalidates all invites of rooms by generating a new secret
+#[post("/invite_secret/rotate")]
+pub async fn rotate_invite_secret(app: Data<App>, admin: Admin) -> Result<HttpResponse, AppError> {
+    app.rotate_invite_secret(&admin).await?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[post("/import/moonlight-qt")]
+pub async fn import_moonlight_qt(
+    app: Data<App>,
+    admin: Admin,
+    Json(request): Json<PostImportMoonlightQtRequest>,
+) -> Result<Json<PostImportResponse>, AppError> {
+    let config = MoonlightQtConfig::load(Path::new(&request.path)).await?;
+
+    let hosts = app
+        .import_moonlight_qt(&admin, request.owner.map(UserId), config)
+        .await?;
+
+    Ok(Json(PostImportResponse { hosts }))
+}
+
+#[get("/users/export")]
+pub async fn export_users(
+    app: Data<App>,
+    admin: Admin,
+    request: HttpRequest,
+    Query(query): Query<GetUsersExportQuery>,
+) -> Result<StreamedResponse<UsersExportHeader, ExportedUser>, AppError> {
+    if query.include_secrets {
+        let confirmed = request
+            .headers()
+            .get(HEADER_CONFIRM_EXPORT_SECRETS)
+            .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"true"));
+        if !confirmed {
+            return Err(AppError::ExportSecretsNotConfirmed);
+        }
+    }
+
+    let mut users = app
+        .export_users(&admin, query.include_secrets, query.include_hosts)
+        .await?;
+
+    let (stream_response, stream_sender) = StreamedResponse::new(UsersExportHeader {
+        version: USER_EXPORT_VERSION,
+        include_secrets: query.include_secrets,
+        include_hosts: query.include_hosts,
+    });
+
+    spawn(async move {
+        loop {
+            let user = match users.next().await {
+                Ok(Some(user)) => user,
+                Ok(None) => return,
+                Err(err) => {
+                    warn!("Failed to export user: {err}");
+                    return;
+                }
+            };
+
+            if let Err(err) = stream_sender.send(user).await {
+                warn!("Failed to send exported user using response streaming: {err}");
+                return;
+            }
+        }
+    });
+
+    Ok(stream_response)
+}
+
+/// The body contains the lines of a user export, the result of every line is streamed back.
+/// The lines are imported while the body is received, so large imports aren't buffered.
+#[post("/users/import")]
+pub async fn import_users(
+    app: Data<App>,
+    admin: Admin,
+    Query(query): Query<PostUsersImportQuery>,
+    mut payload: Payload,
+) -> Result<StreamedResponse<PostUsersImportResponse, ImportedUser>, AppError> {
+    let (stream_response, stream_sender) = StreamedResponse::new(PostUsersImportResponse {
+        conflict: query.conflict,
+        include_hosts: query.include_hosts,
+    });
+
+    // The payload can only be read on this thread
+    actix_rt::spawn(async move {
+        let mut parser = UserImportParser::default();
+
+        let mut rows = Vec::new();
+        loop {
+            let finished = match payload.next().await {
+                Some(Ok(bytes)) => {
+                    rows.extend(parser.push(&bytes));
+                    false
+                }
+                Some(Err(err)) => {
+                    warn!("Failed to receive user import: {err}");
+                    return;
+                }
+                None => {
+                    rows.extend(mem::take(&mut parser).finish());
+                    true
+                }
+            };
+
+            for row in rows.drain(..) {
+                let imported = import_user_row(&app, &admin, &query, row).await;
+
+                if let Err(err) = stream_sender.send(imported).await {
+                    warn!("Failed to send imported user using response streaming: {err}");
+                    return;
+                }
+            }
+
+            if finished {
+                return;
+            }
+        }
+    });
+
+    Ok(stream_response)
+}
+
+async fn import_user_row(
+    app: &App,
+    admin: &Admin,
+    query: &PostUsersImportQuery,
+    (line, row): UserImportRow,
+) -> ImportedUser {
+    match row {
+        Ok(user) => {
+            let (result, hosts) = match app
+                .import_user(admin, &user, query.conflict, query.include_hosts)
+                .await
+            {
+                Ok(result) => result,
+                Err(err) => {
+                    warn!("Failed to import user {} of line {line}: {err}", user.name);
+                    (
+                        ImportUserResult::Failed {
+                            error: err.to_string(),
+                        },
+                        Vec::new(),
+                    )
+                }
+            };
+
+            ImportedUser {
+                line,
+                name: Some(user.name),
+                result,
+                hosts,
+            }
+        }
+        Err(error) => ImportedUser {
+            line,
+            name: None,
+            result: ImportUserResult::Failed { error },
+            hosts: Vec::new(),
+        },
+    }
+}
@@ -1,25 +1,56 @@
 use actix_web::{
-    Error, FromRequest, HttpRequest, HttpResponse,
+    Error, FromRequest, HttpMessage, HttpRequest, HttpResponse,
     body::MessageBody,
     cookie::{Cookie, Expiration, SameSite, time::OffsetDateTime},
     dev::{Payload, ServiceRequest, ServiceResponse},
     get,
+    http::{
+        Method,
+        header::{HeaderName, HeaderValue},
+    },
     middleware::Next,
     post,
-    web::{Data, Json},
+    web::{Data, Json, Query},
 };
 use common::api_bindings::PostLoginRequest;
 use futures::future::{Ready, ready};
+use log::{info, warn};
+use serde::Deserialize;
 use std::{pin::Pin, time::Duration};
 
-use crate::app::{
-    App, AppError,
-    auth::{SessionToken, UserAuth},
-    user::{Admin, AuthenticatedUser},
+use crate::{
+    app::{
+        App, AppError,
+        auth::{SessionExpiration, SessionToken, UserAuth},
+        user::{Admin, AuthenticatedUser, Impersonation},
+    },
+    client_ip::{client_ip, is_trusted_proxy, log_ip},
 };
 
 pub const COOKIE_SESSION_TOKEN_NAME: &str = "mlSession";
 
+/// Admins can send the name of a user in this header or the `as_user` query parameter to act as that user
+pub const HEADER_IMPERSONATE_USER: &str = "X-Impersonate-User";
+/// Contains the id of the impersonated user in the response
+pub const HEADER_IMPERSONATING_USER: HeaderName = HeaderName::from_static("x-impersonating-user");
+
+/// Impersonation only lets admins see what the user sees, all other requests are rejected.
+/// Only GET requests to these paths are allowed
+const IMPERSONATION_ALLOWED_PATHS: [&str; 12] = [
+    "/authenticate",
+    "/user",
+    "/user/settings",
+    "/hosts",
+    "/host",
+    "/host/uptime",
+    "/host/shares",
+    "/host/wake/status",
+    "/apps",
+    "/apps/detailed",
+    "/app/image",
+    "/capabilities",
+];
+
 impl FromRequest for UserAuth {
     type Error = AppError;
 
@@ -35,8 +66,12 @@ fn extract_user_auth(req: &HttpRequest) -> Result<UserAuth, AppError> {
         Some(value) => value,
     };
 
+    let trusted_proxies = &app.config().web_server.trusted_proxies;
+
+    // When trusted proxies are configured only they are allowed to set the username header
     if let Some(header_auth) = &app.config().web_server.forwarded_header
         && let Some(username) = req.headers().get(&header_auth.username_header)
+        && (trusted_proxies.is_empty() || is_trusted_proxy(req, trusted_proxies))
     {
         let Ok(username) = username.to_str() else {
             return Err(AppError::HeaderAuthMalformed);
@@ -80,10 +115,16 @@ impl FromRequest for AuthenticatedUser {
             Some(value) => value,
         };
 
+        let impersonation = req.extensions().get::<Impersonation>().copied();
         let auth_future = UserAuth::from_request(req, payload);
 
         let app = app.clone();
         Box::pin(async move {
+            // Set by the auth middleware after checking the admin
+            if let Some(impersonation) = impersonation {
+                return app.impersonated_user(impersonation).await;
+            }
+
             let auth = auth_future.await?;
 
             let user = app.user_by_auth(auth).await?;
@@ -112,8 +153,39 @@ impl FromRequest for Admin {
 #[post("/login")]
 async fn login(
     app: Data<App>,
+    req: HttpRequest,
     Json(request): Json<PostLoginRequest>,
 ) -> Result<HttpResponse, Error> {
+    let ip = log_ip(Some(client_ip(&req)), app.config().log.anonymize_ips);
+    let username = request.name.clone();
+
+    let result = login_user(&app, request).await;
+    match &result {
+        Ok(_) => info!("[Auth]: user \"{username}\" logged in from {ip}"),
+        Err(err) => warn!("[Auth]: failed login for user \"{username}\" from {ip}: {err}"),
+    }
+    let mut user = result?;
+
+    if let Err(err) = user.record_login().await {
+        warn!("[Auth]: failed to record the login of user \"{username}\": {err}");
+    }
+
+    let session_expiration = SessionExpiration::new(&app.config().web_server);
+
+    let session = user.new_session(session_expiration).await?;
+    let mut session_bytes = [0; _];
+    let session_str = session.encode(&mut session_bytes);
+
+    Ok(HttpResponse::Ok()
+        .cookie(build_cookie(
+            &app,
+            session_expiration.cookie_lifetime(),
+            session_str,
+        ))
+        .finish())
+}
+
+async fn login_user(app: &App, request: PostLoginRequest) -> Result<AuthenticatedUser, AppError> {
     let user = if app.config().web_server.first_login_create_admin {
         match app
             .try_add_first_login(request.name.clone(), request.password.clone())
@@ -127,7 +199,7 @@ async fn login(
                 })
                 .await?
             }
-            Err(err) => return Err(err.into()),
+            Err(err) => return Err(err),
         }
     } else {
         app.user_by_auth(UserAuth::UserPassword {
@@ -137,15 +209,7 @@ async fn login(
         .await?
     };
 
-    let session_expiration = app.config().web_server.session_cookie_expiration;
-
-    let session = user.new_session(session_expiration).await?;
-    let mut session_bytes = [0; _];
-    let session_str = session.encode(&mut session_bytes);
-
-    Ok(HttpResponse::Ok()
-        .cookie(build_cookie(&app, session_expiration, session_str))
-        .finish())
+    Ok(user)
 }
 
 #[post("/logout")]
@@ -174,7 +238,23 @@ pub async fn auth_middleware(
         return Err(AppError::AppDestroyed.into());
     };
 
+    let impersonation = match impersonated_user_name(&req)? {
+        Some(user_name) => {
+            let impersonation = impersonate(&app, &req, &user_name).await?;
+            req.extensions_mut().insert(impersonation);
+
+            Some(impersonation)
+        }
+        None => None,
+    };
+
     let mut response = next.call(req).await?;
+    if let Some(impersonation) = impersonation {
+        response.headers_mut().insert(
+            HEADER_IMPERSONATING_USER,
+            HeaderValue::from(impersonation.user().0),
+        );
+    }
     if let Some(err) = response.response().error()
         && let Some(AppError::SessionTokenNotFound) = err.as_error::<AppError>()
     {
@@ -186,6 +266,57 @@ pub async fn auth_middleware(
     Ok(response)
 }
 
+#[derive(Deserialize)]
+struct ImpersonateQuery {
+    as_user: Option<String>,
+}
+
+fn impersonated_user_name(req: &ServiceRequest) -> Result<Option<String>, AppError> {
+    if let Some(user_name) = req.headers().get(HEADER_IMPERSONATE_USER) {
+        let Ok(user_name) = user_name.to_str() else {
+            return Err(AppError::BadRequest);
+        };
+
+        return Ok(Some(user_name.to_string()));
+    }
+
+    // Other query parameters belong to the handler
+    let user_name = Query::<ImpersonateQuery>::from_query(req.query_string())
+        .ok()
+        .and_then(|query| query.into_inner().as_user);
+
+    Ok(user_name)
+}
+
+async fn impersonate(
+    app: &App,
+    req: &ServiceRequest,
+    user_name: &str,
+) -> Result<Impersonation, AppError> {
+    let admin = AuthenticatedUser::extract(req.request())
+        .await?
+        .into_admin()
+        .await?;
+
+    if req.method() != Method::GET
+        || !IMPERSONATION_ALLOWED_PATHS.contains(&req.match_info().unprocessed())
+    {
+        warn!(
+            "[Auth]: admin {:?} tried to impersonate user \"{user_name}\" for {} {}",
+            admin.id(),
+            req.method(),
+            req.path()
+        );
+        return Err(AppError::Forbidden);
+    }
+
+    let user = app.user_by_name(user_name).await?;
+
+    Ok(app
+        .impersonate(&admin, &user, req.method().as_str(), req.path())
+        .await)
+}
+
 pub fn build_cookie<'a>(app: &'a App, expiration: Duration, session_str: &'a str) -> Cookie<'a> {
     Cookie::build(COOKIE_SESSION_TOKEN_NAME, session_str)
         .path(&app.config().web_server.url_path_prefix)
@@ -200,3 +331,267 @@ pub fn build_cookie<'a>(app: &'a App, expiration: Duration, session_str: &'a str
 async fn authenticate(_user: AuthenticatedUser) -> HttpResponse {
     HttpResponse::Ok().finish()
 }
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use actix_web::{
+        App as ActixApp, Error,
+        dev::{Service, ServiceResponse},
+        http::{Method, StatusCode},
+        test::{TestRequest, call_service, init_service, read_body_json, try_call_service},
+        web::Data,
+    };
+    use common::api_bindings::DetailedUser;
+    use tempfile::TempDir;
+
+    use crate::{
+        api::{
+            api_service,
+            auth::{HEADER_IMPERSONATE_USER, HEADER_IMPERSONATING_USER},
+        },
+        app::{
+            App,
+            audit::AuditAction,
+            auth::SessionExpiration,
+            password::StoragePassword,
+            storage::StorageUserAdd,
+            user::{Admin, AuthenticatedUser, Role, UserId},
+        },
+        test_util::{test_config, test_dir},
+    };
+
+    struct TestApp {
+        app: Data<App>,
+        admin: Admin,
+        admin_token: String,
+        user_id: UserId,
+        user_token: String,
+        _dir: TempDir,
+    }
+
+    async fn create_app() -> TestApp {
+        let dir = test_dir();
+        let config = test_config(dir.path());
+        let app = App::new(config).await.expect("failed to create app");
+
+        let admin = app
+            .try_add_first_login("admin".to_string(), "password".to_string())
+            .await
+            .expect("failed to add admin")
+            .into_admin()
+            .await
+            .expect("the first user isn't an admin");
+        let user = app
+            .add_user(
+                &admin,
+                StorageUserAdd {
+                    role: Role::User,
+                    name: "user".to_string(),
+                    password: Some(StoragePassword::new("password").expect("invalid password")),
+                    client_unique_id: "user".to_string(),
+                },
+            )
+            .await
+            .expect("failed to add user");
+
+        TestApp {
+            app: Data::new(app),
+            admin_token: session_token(&admin).await,
+            admin,
+            user_id: user.id(),
+            user_token: session_token(&user).await,
+            _dir: dir,
+        }
+    }
+
+    async fn session_token(user: &AuthenticatedUser) -> String {
+        let session = user
+            .new_session(SessionExpiration {
+                expiration: Duration::from_secs(60),
+                sliding: false,
+                max_lifetime: Duration::from_secs(60),
+                idle_timeout: None,
+            })
+            .await
+            .expect("failed to create session");
+
+        let mut bytes = [0; _];
+        session.encode(&mut bytes).to_string()
+    }
+
+    fn request(uri: &str, token: &str) -> TestRequest {
+        TestRequest::get()
+            .uri(uri)
+            .insert_header(("Authorization", format!("Bearer {token}")))
+    }
+
+    /// Errors of the auth middleware aren't converted into responses by the test service
+    async fn call_status<R, B>(
+        service: &impl Service<R, Response = ServiceResponse<B>, Error = Error>,
+        request: R,
+    ) -> StatusCode {
+        match try_call_service(service, request).await {
+            Ok(response) => response.status(),
+            Err(err) => err.as_response_error().status_code(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_admin_impersonates_user() {
+        let test = create_app().await;
+        let service = init_service(
+            ActixApp::new()
+                .app_data(test.app.clone())
+                .service(api_service(test.app.config())),
+        )
+        .await;
+
+        for request in [
+            request("/api/user", &test.admin_token)
+                .insert_header((HEADER_IMPERSONATE_USER, "user")),
+            request("/api/user?as_user=user", &test.admin_token),
+        ] {
+            let response = call_service(&service, request.to_request()).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                response.headers().get(HEADER_IMPERSONATING_USER),
+                Some(&test.user_id.0.into())
+            );
+
+            let user: DetailedUser = read_body_json(response).await;
+            assert_eq!(user.name, "user");
+        }
+
+        let entries = test
+            .app
+            .audit_log(&test.admin)
+            .await
+            .expect("failed to get audit log");
+        assert_eq!(entries.len(), 2);
+        for entry in entries {
+            assert_eq!(entry.actor, test.admin.id());
+            let AuditAction::Impersonation { user, method, path } = entry.action else {
+                panic!("expected an impersonation, got {:?}", entry.action);
+            };
+            assert_eq!(user, test.user_id);
+            assert_eq!(method, "GET");
+            assert_eq!(path, "/api/user");
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_request_without_impersonation() {
+        let test = create_app().await;
+        let service = init_service(
+            ActixApp::new()
+                .app_data(test.app.clone())
+                .service(api_service(test.app.config())),
+        )
+        .await;
+
+        let response = call_service(
+            &service,
+            request("/api/user", &test.admin_token).to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get(HEADER_IMPERSONATING_USER).is_none());
+
+        let user: DetailedUser = read_body_json(response).await;
+        assert_eq!(user.name, "admin");
+        assert!(
+            test.app
+                .audit_log(&test.admin)
+                .await
+                .expect("failed to get audit log")
+                .is_empty()
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_user_cannot_impersonate() {
+        let test = create_app().await;
+        let service = init_service(
+            ActixApp::new()
+                .app_data(test.app.clone())
+                .service(api_service(test.app.config())),
+        )
+        .await;
+
+        let status = call_status(
+            &service,
+            request("/api/user", &test.user_token)
+                .insert_header((HEADER_IMPERSONATE_USER, "admin"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let status = call_status(
+            &service,
+            TestRequest::get()
+                .uri("/api/user?as_user=admin")
+                .to_request(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        assert!(
+            test.app
+                .audit_log(&test.admin)
+                .await
+                .expect("failed to get audit log")
+                .is_empty()
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_impersonation_rejected() {
+        let test = create_app().await;
+        let service = init_service(
+            ActixApp::new()
+                .app_data(test.app.clone())
+                .service(api_service(test.app.config())),
+        )
+        .await;
+
+        for (method, uri) in [
+            (Method::GET, "/api/host/stream"),
+            (Method::GET, "/api/guest/stream"),
+            (Method::GET, "/api/rooms"),
+            (Method::GET, "/api/users/export"),
+            (Method::POST, "/api/host/wake"),
+            (Method::DELETE, "/api/host"),
+            (Method::PUT, "/api/user/settings"),
+        ] {
+            let status = call_status(
+                &service,
+                request(uri, &test.admin_token)
+                    .method(method.clone())
+                    .insert_header((HEADER_IMPERSONATE_USER, "user"))
+                    .to_request(),
+            )
+            .await;
+            assert_eq!(status, StatusCode::FORBIDDEN, "{method} {uri}");
+        }
+
+        let status = call_status(
+            &service,
+            request("/api/user", &test.admin_token)
+                .insert_header((HEADER_IMPERSONATE_USER, "missing"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        assert!(
+            test.app
+                .audit_log(&test.admin)
+                .await
+                .expect("failed to get audit log")
+                .is_empty()
+        );
+    }
+}
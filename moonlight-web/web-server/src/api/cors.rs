@@ -0,0 +1,280 @@
+use actix_cors::Cors;
+use actix_web::{
+    HttpRequest,
+    dev::RequestHead,
+    http::{
+        Method,
+        header::{HOST, HeaderName, HeaderValue, ORIGIN},
+    },
+    middleware::Condition,
+};
+use common::config::Config;
+use ipnet::IpNet;
+use log::warn;
+
+use crate::{app::AppError, client_ip::is_trusted_peer};
+
+/// How long browsers can cache the result of a preflight request, in seconds
+const PREFLIGHT_MAX_AGE: usize = 60 * 60;
+
+/// The host the client requested, set by reverse proxies which change the `Host` header
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+
+/// The configured origins and the Discord Activity proxy
+fn allowed_origins(config: &Config) -> Vec<String> {
+    let mut origins = config.web_server.cors.allowed_origins.clone();
+
+    if let Some(discord) = &config.discord {
+        origins.push(format!("https://{}.discordsays.com", discord.client_id));
+    }
+
+    origins
+}
+
+/// The host the client sent the request to.
+/// `X-Forwarded-Host` is only used if the request comes from one of the `web_server.trusted_proxies`.
+fn request_host<'a>(request: &'a RequestHead, trusted_proxies: &[IpNet]) -> Option<&'a str> {
+    let header = |name| {
+        request
+            .headers()
+            .get(name)
+            .and_then(|host| host.to_str().ok())
+    };
+
+    if is_trusted_peer(request.peer_addr, trusted_proxies)
+        && let Some(forwarded_host) = header(&X_FORWARDED_HOST)
+    {
+        // The first proxy got the request of the client
+        return forwarded_host.split(',').next().map(str::trim);
+    }
+
+    header(&HOST)
+}
+
+/// Pages served by this web server can always use the api
+fn is_same_origin(origin: &str, request: &RequestHead, trusted_proxies: &[IpNet]) -> bool {
+    let Some((_, origin_host)) = origin.split_once("://") else {
+        return false;
+    };
+
+    request_host(request, trusted_proxies)
+        .is_some_and(|host| host.eq_ignore_ascii_case(origin_host))
+}
+
+fn is_allowed_origin(
+    origins: &[String],
+    trusted_proxies: &[IpNet],
+    origin: &HeaderValue,
+    request: &RequestHead,
+) -> bool {
+    let Ok(origin) = origin.to_str() else {
+        return false;
+    };
+
+    is_same_origin(origin, request, trusted_proxies)
+        || origins
+            .iter()
+            .any(|allowed| allowed.trim_end_matches('/').eq_ignore_ascii_case(origin))
+}
+
+/// Only enabled when other origins are allowed, without it browsers only let the same origin use the api
+pub fn cors(config: &Config) -> Condition<Cors> {
+    let origins = allowed_origins(config);
+    let trusted_proxies = config.web_server.trusted_proxies.clone();
+    let enabled = !origins.is_empty();
+
+    let cors = Cors::default()
+        .allowed_origin_fn(move |origin, request| {
+            is_allowed_origin(&origins, &trusted_proxies, origin, request)
+        })
+        .allowed_methods([
+            Method::GET,
+            Method::POST,
+            Method::PUT,
+            Method::PATCH,
+            Method::DELETE,
+        ])
+        .allow_any_header()
+        .expose_any_header()
+        // The other origins authenticate with the session cookie
+        .supports_credentials()
+        .max_age(PREFLIGHT_MAX_AGE);
+
+    Condition::new(enabled, cors)
+}
+
+/// Browsers don't apply CORS to WebSockets, so the origin of the upgrade request is checked here
+pub fn check_websocket_origin(config: &Config, request: &HttpRequest) -> Result<(), AppError> {
+    // Clients which aren't browsers don't send an origin
+    let Some(origin) = request.headers().get(ORIGIN) else {
+        return Ok(());
+    };
+
+    if is_allowed_origin(
+        &allowed_origins(config),
+        &config.web_server.trusted_proxies,
+        origin,
+        request.head(),
+    ) {
+        Ok(())
+    } else {
+        warn!("[Cors]: rejected a WebSocket from the origin {origin:?}");
+
+        Err(AppError::Forbidden)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::{
+        App as ActixApp, HttpResponse,
+        http::{
+            Method, StatusCode,
+            header::{
+                ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_ORIGIN,
+                ACCESS_CONTROL_REQUEST_METHOD, HOST, ORIGIN,
+            },
+        },
+        test::{TestRequest, call_service, init_service},
+        web,
+    };
+    use common::config::{Config, DiscordConfig};
+
+    use crate::api::cors::{check_websocket_origin, cors};
+
+    fn config(allowed_origins: &[&str]) -> Config {
+        let mut config = Config::default();
+        config.web_server.cors.allowed_origins = allowed_origins
+            .iter()
+            .map(|origin| origin.to_string())
+            .collect();
+        config
+    }
+
+    fn request(origin: &str) -> TestRequest {
+        TestRequest::get()
+            .uri("/api/host/stream")
+            .insert_header((HOST, "moonlight.example.com"))
+            .insert_header((ORIGIN, origin))
+    }
+
+    #[test]
+    fn test_websocket_origin() {
+        let config = config(&["https://frontend.example.com/"]);
+
+        for origin in [
+            "https://moonlight.example.com",
+            "https://frontend.example.com",
+        ] {
+            assert!(
+                check_websocket_origin(&config, &request(origin).to_http_request()).is_ok(),
+                "{origin}"
+            );
+        }
+        assert!(
+            check_websocket_origin(
+                &config,
+                &request("https://evil.example.com").to_http_request()
+            )
+            .is_err()
+        );
+        // Clients which aren't browsers
+        assert!(check_websocket_origin(&config, &TestRequest::get().to_http_request()).is_ok());
+
+        let mut config = Config::default();
+        config.discord = Some(DiscordConfig {
+            client_id: "1234".to_string(),
+            client_secret: "secret".to_string(),
+            redirect_uri: None,
+        });
+        assert!(
+            check_websocket_origin(
+                &config,
+                &request("https://1234.discordsays.com").to_http_request()
+            )
+            .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_websocket_origin_behind_proxy() {
+        let mut config = config(&[]);
+        config.web_server.trusted_proxies = vec!["10.0.0.0/8".parse().unwrap()];
+
+        // The proxy changed the host to the address of the web server
+        let proxied = |peer: &str, origin: &str| {
+            TestRequest::get()
+                .uri("/api/host/stream")
+                .peer_addr(peer.parse().unwrap())
+                .insert_header((HOST, "127.0.0.1:8080"))
+                .insert_header(("X-Forwarded-Host", "moonlight.example.com, 127.0.0.1"))
+                .insert_header((ORIGIN, origin))
+                .to_http_request()
+        };
+
+        assert!(
+            check_websocket_origin(
+                &config,
+                &proxied("10.0.0.2:1234", "https://moonlight.example.com")
+            )
+            .is_ok()
+        );
+        assert!(
+            check_websocket_origin(
+                &config,
+                &proxied("10.0.0.2:1234", "https://evil.example.com")
+            )
+            .is_err()
+        );
+        // Anyone else could set the header
+        assert!(
+            check_websocket_origin(
+                &config,
+                &proxied("192.168.0.2:1234", "https://moonlight.example.com")
+            )
+            .is_err()
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_preflight() {
+        let service = init_service(
+            ActixApp::new().service(
+                web::scope("/api")
+                    .wrap(cors(&config(&["https://frontend.example.com"])))
+                    .route("/user", web::patch().to(HttpResponse::Ok)),
+            ),
+        )
+        .await;
+
+        let preflight = |origin: &str| {
+            TestRequest::default()
+                .method(Method::OPTIONS)
+                .uri("/api/user")
+                .insert_header((HOST, "moonlight.example.com"))
+                .insert_header((ORIGIN, origin))
+                .insert_header((ACCESS_CONTROL_REQUEST_METHOD, "PATCH"))
+                .to_request()
+        };
+
+        let response = call_service(&service, preflight("https://frontend.example.com")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN),
+            Some(&"https://frontend.example.com".parse().unwrap())
+        );
+        // The frontend sends the session cookie
+        assert_eq!(
+            response.headers().get(ACCESS_CONTROL_ALLOW_CREDENTIALS),
+            Some(&"true".parse().unwrap())
+        );
+
+        let response = call_service(&service, preflight("https://evil.example.com")).await;
+        assert!(
+            response
+                .headers()
+                .get(ACCESS_CONTROL_ALLOW_ORIGIN)
+                .is_none()
+        );
+    }
+}
@@ -4,21 +4,44 @@
 
 use std::collections::HashMap;
 
-use actix_web::{get, post, web::{Data, Json, Query}};
+use actix_web::{
+    HttpRequest, HttpResponse, delete, get, post,
+    web::{Data, Json, Query},
+};
 use log::{debug, error, info, warn};
 use serde::Deserialize;
 use tokio::sync::RwLock;
 
-use crate::app::{App, AppError};
+use crate::{
+    app::{
+        App, AppError,
+        host::HostId,
+        user::{AuthenticatedUser, Role},
+    },
+    room::RoomOwner,
+};
 use common::api_bindings::{
-    GetDiscordRoomQuery, GetDiscordRoomResponse, PostDiscordRoomRequest, PostDiscordRoomResponse,
-    PostDiscordTokenRequest, PostDiscordTokenResponse,
+    DeleteDiscordRoomQuery, GetDiscordRoomQuery, GetDiscordRoomResponse, PostDiscordRoomRequest,
+    PostDiscordRoomResponse, PostDiscordTokenRequest, PostDiscordTokenResponse,
 };
 
+/// Header with the Discord access token of a user in the activity
+pub const HEADER_DISCORD_ACCESS_TOKEN: &str = "X-Discord-Access-Token";
+
+/// A running Discord Activity and the room it's streaming into
+#[derive(Debug, Clone)]
+pub struct DiscordInstance {
+    pub room_id: String,
+    /// The channel the activity was launched in
+    pub channel_id: Option<String>,
+    /// The guild the activity was launched in, None for dms
+    pub guild_id: Option<String>,
+}
+
 /// Maps Discord Activity instance IDs to room IDs
 pub struct DiscordInstanceManager {
-    /// Map from Discord instance_id to room_id
-    instances: RwLock<HashMap<String, String>>,
+    /// Map from Discord instance_id to the instance
+    instances: RwLock<HashMap<String, DiscordInstance>>,
 }
 
 impl DiscordInstanceManager {
@@ -29,35 +52,45 @@ impl DiscordInstanceManager {
     }
 
     /// Register a room for a Discord Activity instance
-    pub async fn register_instance(&self, instance_id: String, room_id: String) {
+    pub async fn register_instance(&self, instance_id: String, instance: DiscordInstance) {
         let mut instances = self.instances.write().await;
         info!(
-            "Registering Discord instance {} -> room {}",
-            instance_id, room_id
+            "Registering Discord instance {} -> room {} (channel: {:?}, guild: {:?})",
+            instance_id, instance.room_id, instance.channel_id, instance.guild_id
         );
-        instances.insert(instance_id, room_id);
+        instances.insert(instance_id, instance);
     }
 
     /// Get the room ID for a Discord Activity instance
     pub async fn get_room_id(&self, instance_id: &str) -> Option<String> {
         let instances = self.instances.read().await;
-        instances.get(instance_id).cloned()
+        instances
+            .get(instance_id)
+            .map(|instance| instance.room_id.clone())
     }
 
     /// Remove a Discord Activity instance mapping
-    #[allow(dead_code)]
-    pub async fn remove_instance(&self, instance_id: &str) {
+    pub async fn remove_instance(&self, instance_id: &str) -> Option<DiscordInstance> {
         let mut instances = self.instances.write().await;
-        if instances.remove(instance_id).is_some() {
+        let instance = instances.remove(instance_id);
+        if instance.is_some() {
             info!("Removed Discord instance {}", instance_id);
         }
+        instance
+    }
+
+    /// If an instance uses the room
+    pub async fn has_room(&self, room_id: &str) -> bool {
+        let instances = self.instances.read().await;
+        instances
+            .values()
+            .any(|instance| instance.room_id == room_id)
     }
 
     /// Remove all instances pointing to a room (when room is closed)
-    #[allow(dead_code)]
     pub async fn remove_room(&self, room_id: &str) {
         let mut instances = self.instances.write().await;
-        instances.retain(|_, rid| rid != room_id);
+        instances.retain(|_, instance| instance.room_id != room_id);
     }
 }
 
@@ -86,6 +119,62 @@ struct DiscordErrorResponse {
     error_description: Option<String>,
 }
 
+/// The user behind a Discord access token, returned by `/users/@me`
+#[derive(Debug, Clone, Deserialize)]
+pub struct DiscordUser {
+    pub id: String,
+    pub username: String,
+    /// The display name, None if the user didn't set one
+    pub global_name: Option<String>,
+    /// The avatar hash, None if the user uses a default avatar
+    pub avatar: Option<String>,
+}
+
+impl DiscordUser {
+    pub fn display_name(&self) -> &str {
+        self.global_name.as_deref().unwrap_or(&self.username)
+    }
+
+    pub fn avatar_url(&self) -> Option<String> {
+        self.avatar.as_ref().map(|avatar| {
+            format!(
+                "https://cdn.discordapp.com/avatars/{}/{avatar}.png",
+                self.id
+            )
+        })
+    }
+}
+
+/// Fetch the user which the access token belongs to
+pub async fn fetch_discord_user(access_token: &str) -> Result<DiscordUser, AppError> {
+    let client = reqwest::Client::new();
+
+    let response = client
+        .get("https://discord.com/api/users/@me")
+        .bearer_auth(access_token)
+        .send()
+        .await
+        .map_err(|e| {
+            error!("Failed to contact Discord API: {}", e);
+            AppError::ExternalService
+        })?;
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let error_body = response.text().await.unwrap_or_default();
+        warn!(
+            "Discord user request failed with status {}: {}",
+            status, error_body
+        );
+        return Err(AppError::ExternalService);
+    }
+
+    response.json().await.map_err(|e| {
+        error!("Failed to parse Discord user response: {}", e);
+        AppError::ExternalService
+    })
+}
+
 /// Exchange Discord OAuth2 authorization code for access token
 ///
 /// POST /api/discord/token
@@ -175,13 +264,15 @@ pub async fn get_discord_room(
 /// Create a room for a Discord Activity instance
 ///
 /// POST /api/discord/room
-/// Body: { "instance_id": "...", "host_id": 1, "app_id": 1 }
+/// Body: { "instance_id": "...", "host_id": 1, "app_id": 1, "channel_id": "...", "guild_id": "..." }
 /// Response: { "room_id": "ABC123" }
 ///
 /// This is called by the host to create a room when starting the Discord Activity.
+/// The user has to be allowed to use the host and may close the room again.
 #[post("/discord/room")]
 pub async fn create_discord_room(
     app: Data<App>,
+    mut user: AuthenticatedUser,
     Json(request): Json<PostDiscordRoomRequest>,
 ) -> Result<Json<PostDiscordRoomResponse>, AppError> {
     // Check if a room already exists for this instance
@@ -199,13 +290,17 @@ pub async fn create_discord_room(
         }));
     }
 
+    user.host(HostId(request.host_id)).await?;
+
     // Create a new room
+    let owner = RoomOwner::new(&mut user).await;
     let room = app
         .room_manager()
         .create_room(
             request.host_id,
             request.app_id,
             "Discord Activity".to_string(),
+            Some(owner),
         )
         .await;
 
@@ -216,7 +311,14 @@ pub async fn create_discord_room(
 
     // Register the instance -> room mapping
     app.discord_instances()
-        .register_instance(request.instance_id.clone(), room_id.clone())
+        .register_instance(
+            request.instance_id.clone(),
+            DiscordInstance {
+                room_id: room_id.clone(),
+                channel_id: request.channel_id,
+                guild_id: request.guild_id,
+            },
+        )
         .await;
 
     info!(
@@ -226,3 +328,93 @@ pub async fn create_discord_room(
 
     Ok(Json(PostDiscordRoomResponse { room_id }))
 }
+
+/// Remove the room of a Discord Activity instance
+///
+/// DELETE /api/discord/room?instance_id=...
+///
+/// This is called when the Discord Activity ends. The room is closed and the streamer is stopped.
+/// Only an admin, the user who created the room or a Discord user in the room may do this.
+/// Discord users send their access token in the `X-Discord-Access-Token` header.
+#[delete("/discord/room")]
+pub async fn delete_discord_room(
+    app: Data<App>,
+    req: HttpRequest,
+    user: Option<AuthenticatedUser>,
+    Query(query): Query<DeleteDiscordRoomQuery>,
+) -> Result<HttpResponse, AppError> {
+    let room_id = app
+        .discord_instances()
+        .get_room_id(&query.instance_id)
+        .await
+        .ok_or(AppError::RoomNotFound)?;
+
+    let discord_access_token = req
+        .headers()
+        .get(HEADER_DISCORD_ACCESS_TOKEN)
+        .and_then(|value| value.to_str().ok());
+
+    if !may_close_room(&app, &room_id, user, discord_access_token).await? {
+        return Err(AppError::Forbidden);
+    }
+
+    let instance = app
+        .discord_instances()
+        .remove_instance(&query.instance_id)
+        .await
+        .ok_or(AppError::RoomNotFound)?;
+
+    // Other instances can't use a closed room
+    app.discord_instances().remove_room(&instance.room_id).await;
+    app.room_manager().close_room(&instance.room_id).await;
+
+    info!(
+        "Closed Discord Activity room {} for instance {}",
+        instance.room_id, query.instance_id
+    );
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// If the user or the Discord user of the access token may close the room
+async fn may_close_room(
+    app: &App,
+    room_id: &str,
+    user: Option<AuthenticatedUser>,
+    discord_access_token: Option<&str>,
+) -> Result<bool, AppError> {
+    if let Some(mut user) = user {
+        if user.role().await? == Role::Admin {
+            return Ok(true);
+        }
+
+        let is_creator = match app.room_manager().get_room(room_id).await {
+            Some(room) => room
+                .lock()
+                .await
+                .created_by
+                .as_ref()
+                .is_some_and(|owner| owner.user_id == user.id().0),
+            None => false,
+        };
+        if is_creator {
+            return Ok(true);
+        }
+    }
+
+    let Some(access_token) = discord_access_token else {
+        return Ok(false);
+    };
+    let discord_user = fetch_discord_user(access_token).await?;
+
+    let Some(room) = app.room_manager().get_room(room_id).await else {
+        return Ok(false);
+    };
+    let in_room = room
+        .lock()
+        .await
+        .find_by_discord_id(&discord_user.id)
+        .is_some();
+
+    Ok(in_room)
+}
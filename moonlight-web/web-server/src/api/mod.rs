@@ -1,43 +1,95 @@
+use std::str::FromStr;
+
 use actix_web::{
     HttpResponse, delete,
     dev::HttpServiceFactory,
     get,
     middleware::from_fn,
-    patch, post, services,
+    patch, post, put, services,
     web::{self, Bytes, Data, Json, Query},
 };
-use futures::future::try_join_all;
+use futures::future::{join, try_join_all};
 use log::warn;
-use moonlight_common::PairPin;
-use tokio::spawn;
+use moonlight_common::{PairPin, mac::MacAddress};
+use tokio::{spawn, sync::mpsc::unbounded_channel};
 
 use crate::{
     api::{
-        admin::{add_user, delete_user, list_users, patch_user},
+        admin::{
+            HEADER_CONFIRM_EXPORT_SECRETS, add_user, delete_user, export_users, get_audit_log,
+            get_metrics, import_moonlight_qt, import_users, list_inactive_users, list_users,
+            patch_user, rotate_invite_secret,
+        },
         auth::auth_middleware,
+        cors::cors,
+        discord::HEADER_DISCORD_ACCESS_TOKEN,
+        openapi::ApiRoutes,
         response_streaming::StreamedResponse,
     },
     app::{
         App, AppError,
         host::{AppId, HostId},
+        host_address::HostAddress,
+        reachability::{DEFAULT_UPTIME_HOURS, MAX_UPTIME_HOURS},
+        settings::merge_settings,
         storage::StorageHostModify,
         user::{AuthenticatedUser, Role, UserId},
     },
 };
-use common::api_bindings::{
-    self, DeleteHostQuery, DetailedUser, GetAppImageQuery, GetAppsQuery, GetAppsResponse,
-    GetHostQuery, GetHostResponse, GetHostsResponse, GetUserQuery, PatchHostRequest,
-    PostHostRequest, PostHostResponse, PostPairRequest, PostPairResponse1, PostPairResponse2,
-    PostWakeUpRequest, UndetailedHost,
+use common::{
+    api_bindings::{
+        self, DeleteDiscordRoomQuery, DeleteHostQuery, DeleteUserRequest, DetailedApp,
+        DetailedUser, ExportedUser, GetAppImageQuery, GetAppsDetailedResponse, GetAppsQuery,
+        GetAppsResponse, GetAuditLogResponse, GetCapabilitiesResponse, GetDiscordRoomQuery,
+        GetDiscordRoomResponse, GetHostQuery, GetHostResponse, GetHostSharesQuery,
+        GetHostSharesResponse, GetHostUptimeQuery, GetHostUptimeResponse, GetHostsResponse,
+        GetInactiveUsersQuery, GetMetricsResponse, GetUserQuery, GetUserSettingsQuery,
+        GetUserSettingsResponse, GetUsersExportQuery, GetUsersResponse, GetWakeUpStatusQuery,
+        GetWakeUpStatusResponse, ImportedUser, PairStage, PatchHostRequest, PatchUserRequest,
+        PostCancelRequest, PostCancelResponse, PostDiscordRoomRequest, PostDiscordRoomResponse,
+        PostDiscordTokenRequest, PostDiscordTokenResponse, PostHostInviteRequest,
+        PostHostInviteResponse, PostHostRequest, PostHostResponse, PostHostUnpairRemoteRequest,
+        PostHostUnpairRemoteResponse, PostImportMoonlightQtRequest, PostImportResponse,
+        PostLoginRequest, PostPairRequest, PostPairResponse1, PostPairResponse2, PostUserRequest,
+        PostUsersImportQuery, PostUsersImportResponse, PostWakeUpRequest,
+        PutHostAppVisibilityRequest, PutHostSharesRequest, PutUserSettingsRequest, RoomInfo,
+        StreamClientMessage, StreamServerMessage, UndetailedHost, UsersExportHeader,
+    },
+    config::Config,
 };
 
 pub mod admin;
 pub mod auth;
+pub mod cors;
 pub mod discord;
+pub mod openapi;
 pub mod stream;
 
 pub mod response_streaming;
 
+/// Lets clients and scripts check what this server supports before logging in
+#[get("/capabilities")]
+async fn get_capabilities(app: Data<App>) -> Json<GetCapabilitiesResponse> {
+    let config = app.config();
+
+    Json(GetCapabilitiesResponse {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        transports: vec![
+            api_bindings::TransportType::WebRTC,
+            api_bindings::TransportType::WebSocket,
+        ],
+        video_codecs: api_bindings::HostVideoCodec::ALL
+            .iter()
+            .map(|(codec, _, _)| *codec)
+            .collect(),
+        max_players: api_bindings::PlayerSlot::MAX_PLAYERS as u8,
+        max_spectators: u32::try_from(config.rooms.max_spectators).unwrap_or(u32::MAX),
+        spectator_queue: config.rooms.spectator_queue,
+        discord: config.discord.is_some(),
+        forwarded_header_login: config.web_server.forwarded_header.is_some(),
+    })
+}
+
 #[get("/user")]
 async fn get_user(
     app: Data<App>,
@@ -70,6 +122,39 @@ async fn get_user(
     }
 }
 
+#[get("/user/settings")]
+async fn get_user_settings(
+    app: Data<App>,
+    mut user: AuthenticatedUser,
+    Query(query): Query<GetUserSettingsQuery>,
+) -> Result<Json<GetUserSettingsResponse>, AppError> {
+    let settings = match query.user_id {
+        None => user.settings_no_auth().await?,
+        Some(user_id) => {
+            let target_user = app.user_by_id(UserId(user_id)).await?;
+
+            target_user.settings(&mut user).await?
+        }
+    };
+
+    let merged_settings = merge_settings(app.config().default_settings.as_ref(), settings.as_ref());
+
+    Ok(Json(GetUserSettingsResponse {
+        settings: settings.map(Into::into),
+        merged_settings: merged_settings.map(Into::into),
+    }))
+}
+
+#[put("/user/settings")]
+async fn put_user_settings(
+    user: AuthenticatedUser,
+    Json(request): Json<PutUserSettingsRequest>,
+) -> Result<HttpResponse, AppError> {
+    user.set_settings(request.settings.map(Into::into)).await?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
 #[get("/hosts")]
 async fn list_hosts(
     mut user: AuthenticatedUser,
@@ -133,15 +218,35 @@ async fn get_host(
     Ok(Json(GetHostResponse { host: detailed }))
 }
 
+#[get("/host/uptime")]
+async fn get_host_uptime(
+    mut user: AuthenticatedUser,
+    Query(query): Query<GetHostUptimeQuery>,
+) -> Result<Json<GetHostUptimeResponse>, AppError> {
+    let host_id = HostId(query.host_id);
+    let hours = query
+        .hours
+        .unwrap_or(DEFAULT_UPTIME_HOURS)
+        .clamp(1, MAX_UPTIME_HOURS);
+
+    let host = user.host(host_id).await?;
+
+    let uptime = host.uptime(&mut user, hours).await?;
+
+    Ok(Json(uptime))
+}
+
 #[post("/host")]
 async fn post_host(
     app: Data<App>,
     mut user: AuthenticatedUser,
     Json(request): Json<PostHostRequest>,
 ) -> Result<Json<PostHostResponse>, AppError> {
+    let address = request.address.parse::<HostAddress>()?;
+
     let mut host = user
         .host_add(
-            request.address,
+            address,
             request
                 .http_port
                 .unwrap_or(app.config().moonlight.default_http_port),
@@ -175,6 +280,52 @@ async fn patch_host(
             }
         }
     }
+    if request.change_mac {
+        let mac = match request.mac {
+            Some(mac) => Some(MacAddress::from_str(&mac).map_err(|_| AppError::BadRequest)?),
+            None => None,
+        };
+
+        modify.cache_mac = Some(mac);
+    }
+    if request.change_display_name {
+        let display_name = request
+            .display_name
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty());
+
+        modify.display_name = Some(display_name);
+    }
+    if request.change_https_port_override {
+        if request.https_port_override == Some(0) {
+            return Err(AppError::BadRequest);
+        }
+
+        modify.https_port_override = Some(request.https_port_override);
+    }
+    if request.change_external_address {
+        let external_address = match request
+            .external_address
+            .as_deref()
+            .map(str::trim)
+            .filter(|address| !address.is_empty())
+        {
+            Some(address) => Some(address.parse::<HostAddress>()?.to_string()),
+            None => None,
+        };
+
+        modify.external_address = Some(external_address);
+    }
+    if request.change_reachability_probe {
+        match role {
+            Role::Admin => {
+                modify.reachability_probe = Some(request.reachability_probe);
+            }
+            Role::User => {
+                return Err(AppError::Forbidden);
+            }
+        }
+    }
 
     host.modify(&mut user, modify).await?;
 
@@ -193,6 +344,52 @@ async fn delete_host(
     Ok(HttpResponse::Ok().finish())
 }
 
+#[post("/host/unpair_remote")]
+async fn unpair_remote_host(
+    app: Data<App>,
+    mut user: AuthenticatedUser,
+    Json(request): Json<PostHostUnpairRemoteRequest>,
+) -> Result<Json<PostHostUnpairRemoteResponse>, AppError> {
+    let host_id = HostId(request.host_id);
+
+    let result = match user.host(host_id).await {
+        Ok(mut host) => host.unpair(&mut user).await?,
+        // The host was deleted while it was offline, so its unpair might still be pending
+        Err(AppError::HostNotFound) => app.retry_pending_unpair(&mut user, host_id).await?,
+        Err(err) => return Err(err),
+    };
+
+    Ok(Json(PostHostUnpairRemoteResponse { result }))
+}
+
+#[get("/host/shares")]
+async fn get_host_shares(
+    mut user: AuthenticatedUser,
+    Query(query): Query<GetHostSharesQuery>,
+) -> Result<Json<GetHostSharesResponse>, AppError> {
+    let host_id = HostId(query.host_id);
+
+    let host = user.host(host_id).await?;
+
+    let user_names = host.shared_with(&mut user).await?;
+
+    Ok(Json(GetHostSharesResponse { user_names }))
+}
+
+#[put("/host/shares")]
+async fn put_host_shares(
+    mut user: AuthenticatedUser,
+    Json(request): Json<PutHostSharesRequest>,
+) -> Result<HttpResponse, AppError> {
+    let host_id = HostId(request.host_id);
+
+    let mut host = user.host(host_id).await?;
+
+    host.set_shared_with(&mut user, request.user_names).await?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
 #[post("/pair")]
 async fn pair_host(
     mut user: AuthenticatedUser,
@@ -202,18 +399,45 @@ async fn pair_host(
 
     let mut host = user.host(host_id).await?;
 
-    let pin = PairPin::generate()?;
+    let pin = match request.pin {
+        Some(pin) => parse_pair_pin(&pin).ok_or(AppError::BadRequest)?,
+        None => PairPin::generate()?,
+    };
 
     let (stream_response, stream_sender) =
         StreamedResponse::new(PostPairResponse1::Pin(pin.to_string()));
 
     spawn(async move {
-        let result = host.pair(&mut user, pin).await;
+        let (stage_sender, mut stage_receiver) = unbounded_channel();
+
+        // Forward the stages in order, the last forwarded stage is the one pairing ended in
+        let forward_stages = async {
+            let mut last_stage = None;
+
+            while let Some(stage) = stage_receiver.recv().await {
+                last_stage = Some(stage);
 
-        let result = match result {
-            Ok(()) => host.detailed_host(&mut user).await,
-            Err(err) => Err(err),
+                if let Err(err) = stream_sender.send(PostPairResponse2::Stage(stage)).await {
+                    warn!("Failed to send pair stage: {err}");
+                }
+            }
+
+            last_stage
         };
+        let pair = async {
+            let result = host
+                .pair(&mut user, pin, move |stage| {
+                    let _ = stage_sender.send(PairStage::from(stage));
+                })
+                .await;
+
+            match result {
+                Ok(()) => host.detailed_host(&mut user).await,
+                Err(err) => Err(err),
+            }
+        };
+
+        let (last_stage, result) = join(forward_stages, pair).await;
 
         match result {
             Ok(detailed_host) => {
@@ -225,8 +449,11 @@ async fn pair_host(
                 }
             }
             Err(err) => {
-                warn!("Failed to pair host: {err}");
-                if let Err(err) = stream_sender.send(PostPairResponse2::PairError).await {
+                warn!("Failed to pair host in stage {last_stage:?}: {err}");
+                if let Err(err) = stream_sender
+                    .send(PostPairResponse2::PairError { stage: last_stage })
+                    .await
+                {
                     warn!("Failed to send pair failure: {err}");
                 }
             }
@@ -236,6 +463,16 @@ async fn pair_host(
     Ok(stream_response)
 }
 
+fn parse_pair_pin(pin: &str) -> Option<PairPin> {
+    let numbers = pin
+        .trim()
+        .chars()
+        .map(|c| c.to_digit(10).map(|number| number as u8))
+        .collect::<Option<Vec<_>>>()?;
+
+    PairPin::from_array(numbers.try_into().ok()?)
+}
+
 #[post("/host/wake")]
 async fn wake_host(
     mut user: AuthenticatedUser,
@@ -250,6 +487,20 @@ async fn wake_host(
     Ok(HttpResponse::Ok().finish())
 }
 
+#[get("/host/wake/status")]
+async fn wake_host_status(
+    mut user: AuthenticatedUser,
+    Query(query): Query<GetWakeUpStatusQuery>,
+) -> Result<Json<GetWakeUpStatusResponse>, AppError> {
+    let host_id = HostId(query.host_id);
+
+    let mut host = user.host(host_id).await?;
+
+    let status = host.wake_status(&mut user).await?;
+
+    Ok(Json(GetWakeUpStatusResponse { status }))
+}
+
 #[get("/apps")]
 async fn get_apps(
     mut user: AuthenticatedUser,
@@ -261,6 +512,10 @@ async fn get_apps(
 
     let apps = host.list_apps(&mut user).await?;
 
+    if let Err(err) = host.prefetch_app_images(&mut user, &apps).await {
+        warn!("failed to prefetch app images of host {host_id:?}: {err}");
+    }
+
     Ok(Json(GetAppsResponse {
         apps: apps
             .into_iter()
@@ -268,11 +523,62 @@ async fn get_apps(
                 app_id: app.id.0,
                 title: app.title,
                 is_hdr_supported: app.is_hdr_supported,
+                hidden: app.hidden,
+            })
+            .collect(),
+    }))
+}
+
+#[get("/apps/detailed")]
+async fn get_apps_detailed(
+    mut user: AuthenticatedUser,
+    Query(query): Query<GetAppsQuery>,
+) -> Result<Json<GetAppsDetailedResponse>, AppError> {
+    let host_id = HostId(query.host_id);
+
+    let mut host = user.host(host_id).await?;
+
+    let apps = host.list_apps(&mut user).await?;
+    let mut image_hashes = host.cached_app_image_hashes(&mut user).await?;
+
+    if let Err(err) = host.prefetch_app_images(&mut user, &apps).await {
+        warn!("failed to prefetch app images of host {host_id:?}: {err}");
+    }
+
+    Ok(Json(GetAppsDetailedResponse {
+        apps: apps
+            .into_iter()
+            .map(|app| {
+                let image_hash = image_hashes.remove(&app.id);
+
+                DetailedApp {
+                    app_id: app.id.0,
+                    title: app.title,
+                    is_hdr_supported: app.is_hdr_supported,
+                    image_cached: image_hash.is_some(),
+                    image_hash,
+                    hidden: app.hidden,
+                }
             })
             .collect(),
     }))
 }
 
+#[put("/host/apps/visibility")]
+async fn put_host_app_visibility(
+    mut user: AuthenticatedUser,
+    Json(request): Json<PutHostAppVisibilityRequest>,
+) -> Result<HttpResponse, AppError> {
+    let host_id = HostId(request.host_id);
+
+    let mut host = user.host(host_id).await?;
+
+    let hidden_app_ids = request.hidden_app_ids.into_iter().map(AppId).collect();
+    host.set_hidden_apps(&mut user, hidden_app_ids).await?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
 #[get("/app/image")]
 async fn get_app_image(
     mut user: AuthenticatedUser,
@@ -290,47 +596,370 @@ async fn get_app_image(
     Ok(image)
 }
 
-pub fn api_service() -> impl HttpServiceFactory {
+/// The documentation of every route registered in [api_service]
+fn api_routes(routes: &mut ApiRoutes) {
+    // -- Auth
+    routes.tag("Auth");
+    routes
+        .post("/login", "Log in, the session is stored in a cookie")
+        .public()
+        .json_body::<PostLoginRequest>()
+        .empty_response();
+    routes
+        .post("/logout", "Log out and remove the session")
+        .empty_response();
+    routes
+        .get("/authenticate", "Check if the session is still valid")
+        .empty_response();
+
+    // -- User
+    routes.tag("User");
+    routes
+        .get("/user", "Get the current user or another user")
+        .query::<GetUserQuery>()
+        .json_response::<DetailedUser>();
+    routes
+        .get("/user/settings", "Get the settings of a user")
+        .query::<GetUserSettingsQuery>()
+        .json_response::<GetUserSettingsResponse>();
+    routes
+        .put("/user/settings", "Replace the settings of the current user")
+        .json_body::<PutUserSettingsRequest>()
+        .empty_response();
+
+    // -- Host
+    routes.tag("Host");
+    routes
+        .get("/hosts", "List all hosts the user can use")
+        .streamed_response::<GetHostsResponse, UndetailedHost>();
+    routes
+        .get("/host", "Get the details of a host")
+        .query::<GetHostQuery>()
+        .json_response::<GetHostResponse>();
+    routes
+        .get(
+            "/host/uptime",
+            "Get the reachability samples and availability of a host",
+        )
+        .query::<GetHostUptimeQuery>()
+        .json_response::<GetHostUptimeResponse>();
+    routes
+        .post("/host", "Add a host")
+        .json_body::<PostHostRequest>()
+        .json_response::<PostHostResponse>();
+    routes
+        .patch("/host", "Change the settings of a host")
+        .json_body::<PatchHostRequest>()
+        .empty_response();
+    routes
+        .delete("/host", "Remove a host")
+        .query::<DeleteHostQuery>()
+        .empty_response();
+    routes
+        .get("/host/shares", "List the users a host is shared with")
+        .query::<GetHostSharesQuery>()
+        .json_response::<GetHostSharesResponse>();
+    routes
+        .put("/host/shares", "Replace the users a host is shared with")
+        .json_body::<PutHostSharesRequest>()
+        .empty_response();
+    routes
+        .post(
+            "/pair",
+            "Pair with a host using a generated pin or the pin shown by the host",
+        )
+        .json_body::<PostPairRequest>()
+        .streamed_response::<PostPairResponse1, PostPairResponse2>();
+    routes
+        .post("/host/wake", "Send a Wake-on-LAN packet to a host")
+        .json_body::<PostWakeUpRequest>()
+        .empty_response();
+    routes
+        .post(
+            "/host/unpair_remote",
+            "Remove the pairing on a host, or retry it if the host was offline when it was deleted",
+        )
+        .json_body::<PostHostUnpairRemoteRequest>()
+        .json_response::<PostHostUnpairRemoteResponse>();
+    routes
+        .get(
+            "/host/wake/status",
+            "Check if a host woke up after sending a Wake-on-LAN packet",
+        )
+        .query::<GetWakeUpStatusQuery>()
+        .json_response::<GetWakeUpStatusResponse>();
+
+    // -- Apps
+    routes.tag("Apps");
+    routes
+        .get("/apps", "List the apps of a host")
+        .query::<GetAppsQuery>()
+        .json_response::<GetAppsResponse>();
+    routes
+        .get(
+            "/apps/detailed",
+            "List the apps of a host with their cached images",
+        )
+        .query::<GetAppsQuery>()
+        .json_response::<GetAppsDetailedResponse>();
+    routes
+        .put(
+            "/host/apps/visibility",
+            "Replace the apps which are hidden from other users",
+        )
+        .json_body::<PutHostAppVisibilityRequest>()
+        .empty_response();
+    routes
+        .get("/app/image", "Get the image of an app")
+        .query::<GetAppImageQuery>()
+        .binary_response("application/octet-stream");
+
+    // -- Stream
+    routes.tag("Stream");
+    routes
+        .get(
+            "/host/stream",
+            "Create or join a room of a host over a WebSocket",
+        )
+        .web_socket::<StreamClientMessage, StreamServerMessage>();
+    routes
+        .get(
+            "/guest/stream",
+            "Join an existing room as a guest over a WebSocket",
+        )
+        .public()
+        .query_param("room_id", "string", true)
+        .query_param("player_name", "string", false)
+        .query_param("discord_auth", "boolean", false)
+        .query_param("spectate", "boolean", false)
+        .query_param("invite", "string", false)
+        .web_socket::<StreamClientMessage, StreamServerMessage>();
+    routes
+        .post("/host/cancel", "Quit the running app of a host")
+        .json_body::<PostCancelRequest>()
+        .json_response::<PostCancelResponse>();
+    routes
+        .post(
+            "/host/invite",
+            "Create an expiring invite link for a room, only its creator and admins can",
+        )
+        .json_body::<PostHostInviteRequest>()
+        .json_response::<PostHostInviteResponse>();
+    routes
+        .get("/rooms", "List all rooms, admins also see who created them")
+        .public()
+        .json_array_response::<RoomInfo>();
+
+    // -- Admin
+    routes.tag("Admin");
+    routes
+        .post("/user", "Add a user")
+        .admin()
+        .json_body::<PostUserRequest>()
+        .json_response::<DetailedUser>();
+    routes
+        .patch(
+            "/user",
+            "Change a user, users can only change their own password and pair device name",
+        )
+        .json_body::<PatchUserRequest>()
+        .empty_response();
+    routes
+        .delete("/user", "Remove a user")
+        .admin()
+        .json_body::<DeleteUserRequest>()
+        .empty_response();
+    routes
+        .get("/users", "List all users")
+        .admin()
+        .json_response::<GetUsersResponse>();
+    routes
+        .get(
+            "/users/inactive",
+            "List the users which neither logged in nor streamed for the given days",
+        )
+        .admin()
+        .query::<GetInactiveUsersQuery>()
+        .json_response::<GetUsersResponse>();
+    routes
+        .get(
+            "/audit",
+            "List the requests admins made while impersonating and the unpaired hosts",
+        )
+        .admin()
+        .json_response::<GetAuditLogResponse>();
+    routes
+        .get("/metrics", "Counters of the caches of the web server")
+        .admin()
+        .json_response::<GetMetricsResponse>();
+    routes
+        .post(
+            "/invite_secret/rotate",
+            "Generate a new invite secret, which invalidates all invite links",
+        )
+        .admin()
+        .empty_response();
+    routes
+        .post(
+            "/import/moonlight-qt",
+            "Import the hosts of a Moonlight Qt config",
+        )
+        .admin()
+        .json_body::<PostImportMoonlightQtRequest>()
+        .json_response::<PostImportResponse>();
+    routes
+        .get(
+            "/users/export",
+            "Export the users and their hosts, secrets need the confirmation header",
+        )
+        .admin()
+        .query::<GetUsersExportQuery>()
+        .header_param(HEADER_CONFIRM_EXPORT_SECRETS, "string", false)
+        .streamed_response::<UsersExportHeader, ExportedUser>();
+    routes
+        .post(
+            "/users/import",
+            "Import the lines of a user export, the result of every line is streamed back",
+        )
+        .admin()
+        .query::<PostUsersImportQuery>()
+        .ndjson_body::<ExportedUser>()
+        .streamed_response::<PostUsersImportResponse, ImportedUser>();
+
+    // -- Discord Activity
+    routes.tag("Discord");
+    routes
+        .post(
+            "/discord/token",
+            "Exchange a Discord authorization code for an access token",
+        )
+        .public()
+        .json_body::<PostDiscordTokenRequest>()
+        .json_response::<PostDiscordTokenResponse>();
+    routes
+        .get(
+            "/discord/room",
+            "Get the room of a Discord Activity instance",
+        )
+        .public()
+        .query::<GetDiscordRoomQuery>()
+        .json_response::<GetDiscordRoomResponse>();
+    routes
+        .post(
+            "/discord/room",
+            "Link a room to a Discord Activity instance",
+        )
+        .json_body::<PostDiscordRoomRequest>()
+        .json_response::<PostDiscordRoomResponse>();
+    routes
+        .delete(
+            "/discord/room",
+            "Unlink the room of a Discord Activity instance",
+        )
+        .public()
+        .query::<DeleteDiscordRoomQuery>()
+        .header_param(HEADER_DISCORD_ACCESS_TOKEN, "string", false)
+        .empty_response();
+
+    // -- Server
+    routes.tag("Server");
+    routes
+        .get(
+            "/capabilities",
+            "Get the version and the features of this server",
+        )
+        .public()
+        .json_response::<GetCapabilitiesResponse>();
+
+    // -- OpenAPI
+    routes.tag("OpenAPI");
+    routes
+        .get("/openapi.json", "Get this document")
+        .public()
+        .json_object_response();
+    routes
+        .get(
+            "/docs",
+            "Get the Swagger UI for this document, only available if enabled in the config",
+        )
+        .public()
+        .binary_response("text/html");
+}
+
+pub fn api_service(config: &Config) -> impl HttpServiceFactory {
     web::scope("/api")
         .wrap(from_fn(auth_middleware))
+        // Registered last so preflight requests are answered before authentication
+        .wrap(cors(config))
         .service(services![
             // -- Auth
             auth::login,
             auth::logout,
             auth::authenticate
         ])
+        .service(services![
+            // -- User
+            get_user_settings,
+            put_user_settings,
+        ])
         .service(services![
             // -- Host
             get_user,
             list_hosts,
             get_host,
+            get_host_uptime,
             post_host,
             patch_host,
             wake_host,
+            wake_host_status,
             delete_host,
+            unpair_remote_host,
             pair_host,
+            get_host_shares,
+            put_host_shares,
+        ])
+        .service(services![
+            // -- Apps
             get_apps,
+            get_apps_detailed,
+            put_host_app_visibility,
             get_app_image,
         ])
         .service(services![
             // -- Stream
             stream::start_host,
             stream::cancel_host,
+            stream::create_invite,
             stream::list_rooms,
         ])
         // Guest stream endpoint - no auth required
         .service(stream::guest_stream)
+        // Capabilities - no auth required
+        .service(get_capabilities)
         .service(services![
             // -- Admin
             add_user,
             patch_user,
             delete_user,
-            list_users
+            list_users,
+            list_inactive_users,
+            get_audit_log,
+            get_metrics,
+            rotate_invite_secret,
+            import_moonlight_qt,
+            export_users,
+            import_users
         ])
         .service(services![
             // -- Discord Activity
             discord::discord_token_exchange,
             discord::get_discord_room,
-            discord::create_discord_room
+            discord::create_discord_room,
+            discord::delete_discord_room
+        ])
+        .service(services![
+            // -- OpenAPI
+            openapi::get_openapi,
+            openapi::get_docs
         ])
 }
@@ -0,0 +1,509 @@
+//! The OpenAPI document of the REST API, served at `/api/openapi.json`.
+//!
+//! Every route registered in `api_service` is documented in `api_routes`, the tests make sure they match.
+//! The schemas are generated from the `api_bindings` types.
+
+use std::sync::LazyLock;
+
+use actix_web::{
+    HttpResponse, get,
+    http::Method,
+    web::{Data, Json},
+};
+use schemars::{JsonSchema, SchemaGenerator, generate::SchemaSettings};
+use serde_json::{Map, Value, json};
+
+use crate::{
+    api::{api_routes, auth::COOKIE_SESSION_TOKEN_NAME},
+    app::App,
+};
+
+static OPENAPI: LazyLock<Value> = LazyLock::new(openapi);
+
+#[get("/openapi.json")]
+pub async fn get_openapi() -> Json<&'static Value> {
+    Json(&OPENAPI)
+}
+
+/// The Swagger UI assets relative to `/api/docs`, `npm run build` copies them into the static files
+const SWAGGER_UI_PATH: &str = "../swagger-ui";
+
+#[get("/docs")]
+pub async fn get_docs(app: Data<App>) -> HttpResponse {
+    if !app.config().web_server.openapi_swagger_ui {
+        return HttpResponse::NotFound().finish();
+    }
+
+    let html = format!(
+        r##"<!DOCTYPE html>
+<html>
+<head>
+    <meta charset="utf-8">
+    <title>Moonlight Web API</title>
+    <link rel="stylesheet" href="{SWAGGER_UI_PATH}/swagger-ui.css">
+</head>
+<body>
+    <div id="swagger-ui"></div>
+    <script src="{SWAGGER_UI_PATH}/swagger-ui-bundle.js"></script>
+    <script>
+        SwaggerUIBundle({{ url: "openapi.json", dom_id: "#swagger-ui" }})
+    </script>
+</body>
+</html>"##
+    );
+
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(html)
+}
+
+/// Builds the document from the routes of the api
+pub fn openapi() -> Value {
+    let mut routes = ApiRoutes::new();
+    api_routes(&mut routes);
+    routes.build()
+}
+
+/// Documents the routes of the api
+pub struct ApiRoutes {
+    /// The tag of the following routes
+    tag: &'static str,
+    paths: Map<String, Value>,
+    schemas: SchemaGenerator,
+}
+
+impl ApiRoutes {
+    fn new() -> Self {
+        Self {
+            tag: "",
+            paths: Map::new(),
+            schemas: SchemaSettings::openapi3().into_generator(),
+        }
+    }
+
+    /// Groups the following routes in the document
+    pub fn tag(&mut self, tag: &'static str) {
+        self.tag = tag;
+    }
+
+    pub fn get(&mut self, path: &str, summary: &str) -> OperationBuilder<'_> {
+        self.route(Method::GET, path, summary)
+    }
+    pub fn post(&mut self, path: &str, summary: &str) -> OperationBuilder<'_> {
+        self.route(Method::POST, path, summary)
+    }
+    pub fn put(&mut self, path: &str, summary: &str) -> OperationBuilder<'_> {
+        self.route(Method::PUT, path, summary)
+    }
+    pub fn patch(&mut self, path: &str, summary: &str) -> OperationBuilder<'_> {
+        self.route(Method::PATCH, path, summary)
+    }
+    pub fn delete(&mut self, path: &str, summary: &str) -> OperationBuilder<'_> {
+        self.route(Method::DELETE, path, summary)
+    }
+
+    fn route(&mut self, method: Method, path: &str, summary: &str) -> OperationBuilder<'_> {
+        let operation_method = method.as_str().to_ascii_lowercase();
+
+        let path_item = self
+            .paths
+            .entry(path)
+            .or_insert_with(|| Value::Object(Map::new()));
+
+        let operation = json!({
+            "tags": [self.tag],
+            "summary": summary,
+            "responses": {
+                "default": {
+                    "description": "The request failed, the status code tells what went wrong",
+                    "content": {
+                        "text/plain": { "schema": { "type": "string" } }
+                    }
+                }
+            }
+        });
+
+        let Value::Object(path_item) = path_item else {
+            unreachable!()
+        };
+        let Value::Object(operation) = path_item.entry(operation_method).or_insert(operation)
+        else {
+            unreachable!()
+        };
+
+        OperationBuilder {
+            operation,
+            schemas: &mut self.schemas,
+        }
+    }
+
+    fn build(mut self) -> Value {
+        json!({
+            "openapi": "3.0.3",
+            "info": {
+                "title": "Moonlight Web",
+                "version": env!("CARGO_PKG_VERSION"),
+                "description": "Admins can act as another user by sending the user name in the `X-Impersonate-User` header or the `as_user` query parameter, except when streaming.",
+            },
+            // Relative to the location of this document, which includes the url path prefix
+            "servers": [{ "url": "./" }],
+            "security": [{ "session": [] }, { "bearer": [] }],
+            "paths": self.paths,
+            "components": {
+                "securitySchemes": {
+                    "session": {
+                        "type": "apiKey",
+                        "in": "cookie",
+                        "name": COOKIE_SESSION_TOKEN_NAME,
+                    },
+                    "bearer": {
+                        "type": "http",
+                        "scheme": "bearer",
+                    }
+                },
+                "schemas": self.schemas.take_definitions(true),
+            }
+        })
+    }
+}
+
+/// Documents a single route
+pub struct OperationBuilder<'a> {
+    operation: &'a mut Map<String, Value>,
+    schemas: &'a mut SchemaGenerator,
+}
+
+impl OperationBuilder<'_> {
+    fn response(self, status: &str, response: Value) -> Self {
+        if let Some(Value::Object(responses)) = self.operation.get_mut("responses") {
+            responses.insert(status.to_string(), response);
+        }
+        self
+    }
+    fn parameter(self, parameter: Value) -> Self {
+        let Value::Array(parameters) = self
+            .operation
+            .entry("parameters")
+            .or_insert_with(|| Value::Array(Vec::new()))
+        else {
+            unreachable!()
+        };
+        parameters.push(parameter);
+        self
+    }
+
+    /// Can be used without logging in
+    pub fn public(self) -> Self {
+        self.operation
+            .insert("security".to_string(), Value::Array(Vec::new()));
+        self
+    }
+    /// Only admins can use this
+    pub fn admin(self) -> Self {
+        self.response("403", json!({ "description": "The user isn't an admin" }))
+    }
+
+    /// The fields of `T` are the query parameters
+    pub fn query<T: JsonSchema>(self) -> Self {
+        let schema = reference::<T>(self.schemas);
+        self.parameter(json!({
+            "name": T::schema_name(),
+            "in": "query",
+            "style": "form",
+            "explode": true,
+            "required": true,
+            "schema": schema,
+        }))
+    }
+    pub fn query_param(self, name: &str, ty: &str, required: bool) -> Self {
+        self.parameter(json!({
+            "name": name,
+            "in": "query",
+            "required": required,
+            "schema": { "type": ty },
+        }))
+    }
+    pub fn header_param(self, name: &str, ty: &str, required: bool) -> Self {
+        self.parameter(json!({
+            "name": name,
+            "in": "header",
+            "required": required,
+            "schema": { "type": ty },
+        }))
+    }
+    pub fn json_body<T: JsonSchema>(self) -> Self {
+        let schema = reference::<T>(self.schemas);
+        self.operation.insert(
+            "requestBody".to_string(),
+            json!({
+                "required": true,
+                "content": {
+                    "application/json": { "schema": schema }
+                }
+            }),
+        );
+        self
+    }
+    /// Every line of the body is a `T`
+    pub fn ndjson_body<T: JsonSchema>(self) -> Self {
+        let schema = reference::<T>(self.schemas);
+        self.operation.insert(
+            "requestBody".to_string(),
+            json!({
+                "required": true,
+                "content": {
+                    "application/x-ndjson": { "schema": schema }
+                }
+            }),
+        );
+        self
+    }
+
+    pub fn empty_response(self) -> Self {
+        self.response("200", json!({ "description": "Success" }))
+    }
+    /// Any json object
+    pub fn json_object_response(self) -> Self {
+        self.response(
+            "200",
+            json!({
+                "description": "Success",
+                "content": {
+                    "application/json": { "schema": { "type": "object" } }
+                }
+            }),
+        )
+    }
+    pub fn json_response<T: JsonSchema>(self) -> Self {
+        let schema = reference::<T>(self.schemas);
+        self.response(
+            "200",
+            json!({
+                "description": "Success",
+                "content": {
+                    "application/json": { "schema": schema }
+                }
+            }),
+        )
+    }
+    pub fn json_array_response<T: JsonSchema>(self) -> Self {
+        let schema = reference::<T>(self.schemas);
+        self.response(
+            "200",
+            json!({
+                "description": "Success",
+                "content": {
+                    "application/json": {
+                        "schema": { "type": "array", "items": schema }
+                    }
+                }
+            }),
+        )
+    }
+    pub fn binary_response(self, content_type: &str) -> Self {
+        self.response(
+            "200",
+            json!({
+                "description": "Success",
+                "content": {
+                    content_type: {
+                        "schema": { "type": "string", "format": "binary" }
+                    }
+                }
+            }),
+        )
+    }
+    /// A json object per line, see `StreamedResponse`
+    pub fn streamed_response<Initial: JsonSchema, Other: JsonSchema>(self) -> Self {
+        let initial = reference::<Initial>(self.schemas);
+        let other = reference::<Other>(self.schemas);
+        self.response(
+            "200",
+            json!({
+                "description": format!(
+                    "The first line contains a {}, every following line a {}",
+                    Initial::schema_name(),
+                    Other::schema_name()
+                ),
+                "content": {
+                    "application/x-ndjson": {
+                        "schema": { "oneOf": [initial, other] }
+                    }
+                }
+            }),
+        )
+    }
+    pub fn web_socket<Client: JsonSchema, Server: JsonSchema>(self) {
+        let client = reference::<Client>(self.schemas);
+        let server = reference::<Server>(self.schemas);
+        self.response(
+            "101",
+            json!({
+                "description": format!(
+                    "Switches to a WebSocket, the client sends {} and the server sends {} as json text messages",
+                    Client::schema_name(),
+                    Server::schema_name()
+                ),
+                "x-websocket-client-messages": client,
+                "x-websocket-server-messages": server,
+            }),
+        );
+    }
+}
+
+/// The schema of `T`, api types are added to the components and referenced
+fn reference<T: JsonSchema>(schemas: &mut SchemaGenerator) -> Value {
+    schemas.subschema_for::<T>().to_value()
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+
+    use actix_web::{
+        App as ActixApp,
+        http::StatusCode,
+        test::{TestRequest, call_service, init_service, read_body_json},
+        web::Data,
+    };
+    use common::config::{Config, WebServerConfig};
+    use serde_json::Value;
+
+    use crate::{
+        api::{api_service, openapi::openapi},
+        app::App,
+        test_util::{test_config, test_dir},
+    };
+
+    /// The sources of the handlers registered in `api_service`
+    const HANDLER_SOURCES: [&str; 6] = [
+        include_str!("mod.rs"),
+        include_str!("admin.rs"),
+        include_str!("auth.rs"),
+        include_str!("discord.rs"),
+        include_str!("stream.rs"),
+        include_str!("openapi.rs"),
+    ];
+
+    /// Collects the method and path of every actix route macro outside of the test modules
+    fn route_macros() -> BTreeSet<(String, String)> {
+        let mut routes = BTreeSet::new();
+        for source in HANDLER_SOURCES {
+            let source = source
+                .split("#[cfg(test)]\nmod test")
+                .next()
+                .unwrap_or_default();
+
+            for line in source.lines() {
+                for method in ["get", "post", "put", "patch", "delete"] {
+                    let Some(path) = line.strip_prefix(&format!("#[{method}(\"")) else {
+                        continue;
+                    };
+                    let path = path.split('"').next().expect("invalid route macro");
+                    routes.insert((method.to_string(), path.to_string()));
+                }
+            }
+        }
+        routes
+    }
+
+    #[test]
+    fn test_openapi_documents_route_macros() {
+        let openapi = openapi();
+
+        let mut documented = BTreeSet::new();
+        for (path, path_item) in openapi["paths"].as_object().expect("missing paths") {
+            for method in path_item.as_object().expect("invalid path item").keys() {
+                documented.insert((method.clone(), path.clone()));
+            }
+        }
+
+        let registered = route_macros();
+        assert!(
+            registered.len() > 30,
+            "found too few route macros: {registered:?}"
+        );
+
+        let undocumented = registered.difference(&documented).collect::<Vec<_>>();
+        assert!(
+            undocumented.is_empty(),
+            "undocumented routes: {undocumented:?}"
+        );
+        let unknown = documented.difference(&registered).collect::<Vec<_>>();
+        assert!(
+            unknown.is_empty(),
+            "documented routes without a handler: {unknown:?}"
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_openapi_contains_all_routes() {
+        let dir = test_dir();
+        let config = Config {
+            web_server: WebServerConfig {
+                openapi_swagger_ui: true,
+                ..Default::default()
+            },
+            ..test_config(dir.path())
+        };
+
+        let app = App::new(config.clone())
+            .await
+            .expect("failed to create app");
+        let service = init_service(
+            ActixApp::new()
+                .app_data(Data::new(app))
+                .service(api_service(&config)),
+        )
+        .await;
+
+        let response = call_service(
+            &service,
+            TestRequest::get().uri("/api/openapi.json").to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let openapi: Value = read_body_json(response).await;
+
+        let mut documented = Vec::new();
+        for (path, path_item) in openapi["paths"].as_object().expect("missing paths") {
+            for method in path_item.as_object().expect("invalid path item").keys() {
+                documented.push((method.clone(), path.clone()));
+            }
+        }
+        assert!(
+            documented.len() > 30,
+            "found too few routes: {documented:?}"
+        );
+
+        // Every documented route must also be registered in the scope
+        for (method, path) in documented {
+            let request = TestRequest::default()
+                .method(method.to_uppercase().parse().expect("invalid method"))
+                .uri(&format!("/api{path}"))
+                .to_request();
+            let response = call_service(&service, request).await;
+            assert_ne!(
+                response.status(),
+                StatusCode::NOT_FOUND,
+                "{method} {path} isn't registered"
+            );
+        }
+
+        // Every referenced schema must be part of the document
+        let schemas = openapi["components"]["schemas"]
+            .as_object()
+            .expect("missing schemas");
+        let document = openapi.to_string();
+        for reference in document.split("#/components/schemas/").skip(1) {
+            let name = reference.split('"').next().expect("invalid reference");
+            assert!(schemas.contains_key(name), "schema {name} is missing");
+        }
+
+        // The schemas describe the json of the types
+        let login = &schemas["PostLoginRequest"];
+        assert_eq!(login["type"], "object");
+        assert_eq!(login["properties"]["name"]["type"], "string");
+        assert_eq!(login["properties"]["password"]["type"], "string");
+    }
+}
@@ -1,4 +1,8 @@
-use std::{process::Stdio, sync::Arc};
+use std::{
+    process::Stdio,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use actix_web::{
     Error, HttpRequest, HttpResponse, get, post, rt as actix_rt,
@@ -7,35 +11,47 @@ use actix_web::{
 use actix_ws::{Closed, Message, MessageStream, Session};
 use common::{
     api_bindings::{
-        LogMessageType, PlayerSlot, PostCancelRequest, PostCancelResponse, RoomInfo, RoomRole,
-        StreamClientMessage, StreamServerMessage,
+        GuestStreamQuery, LogMessageType, PeerQueueMetrics, PlayerSlot, PostCancelRequest,
+        PostCancelResponse, PostHostInviteRequest, PostHostInviteResponse, RoomInfo,
+        RoomJoinFailure, RoomRole, StreamClientMessage, StreamServerMessage,
+        StreamerResourceMetrics,
+    },
+    ipc::{
+        IpcReceiver, IpcSender, PeerId, STREAMER_IPC_CAPACITY_ARG, ServerIpcMessage,
+        StreamerConfig, StreamerIpcMessage, create_child_ipc,
     },
-    ipc::{PeerId, ServerIpcMessage, StreamerConfig, StreamerIpcMessage, create_child_ipc},
     serialize_json,
 };
 use log::{debug, error, info, warn};
-use serde::Deserialize;
-use tokio::{
-    process::{Child, Command},
-    spawn,
-    sync::Mutex,
-};
+use tokio::{process::Child, select, spawn, sync::Mutex, time::timeout};
 
 use crate::{
+    api::{
+        cors::check_websocket_origin,
+        discord::{DiscordUser, fetch_discord_user},
+    },
     app::{
         App, AppError,
-        host::{AppId, HostId},
-        user::AuthenticatedUser,
+        host::{App as HostApp, AppId, Host, HostId},
+        invite::GuestAdmission,
+        storage::StorageHostPairInfo,
+        user::{Admin, AuthenticatedUser},
     },
-    room::{Room, RoomClient},
+    display_mode::{
+        DisplayModeCheck, HostStreamLimits, ResolutionCheck, check_display_mode, check_resolution,
+    },
+    process_scheduling::apply_process_scheduling,
+    room::{
+        CLIENT_SEND_TIMEOUT, ClientActivity, OutgoingMessage, Room, RoomClient, RoomOwner,
+        SlowClients, SpectatorJoin, StopRequest,
+    },
+    room_state::RuntimeDirectory,
+    streamer_command::streamer_command,
 };
-
-/// Query parameters for guest stream endpoint
-#[derive(Debug, Deserialize)]
-pub struct GuestStreamQuery {
-    pub room_id: String,
-    pub player_name: Option<String>,
-}
+#[cfg(unix)]
+use common::ipc::{STREAMER_IPC_SOCKET_ARG, STREAMER_REATTACH_TIMEOUT_ARG, connect_child_ipc};
+#[cfg(unix)]
+use std::path::Path;
 
 /// Handle the initial WebSocket connection for streaming
 /// This can either create a new room (host/Player 1) or join an existing room (Players 2-4)
@@ -46,6 +62,8 @@ pub async fn start_host(
     request: HttpRequest,
     payload: Payload,
 ) -> Result<HttpResponse, Error> {
+    check_websocket_origin(web_app.config(), &request)?;
+
     let (response, session, stream) = actix_ws::handle(&request, payload)?;
 
     let client_unique_id = match user.host_unique_id().await {
@@ -65,7 +83,8 @@ pub async fn start_host(
 }
 
 /// Handle WebSocket connection for guests joining an existing room
-/// This endpoint does NOT require authentication - guests can join with just a room ID
+/// This endpoint does NOT require authentication - guests can join with a signed invite, as a Discord user of the activity of the room
+/// or, if invites aren't required, just a room ID
 #[get("/guest/stream")]
 pub async fn guest_stream(
     web_app: Data<App>,
@@ -73,6 +92,28 @@ pub async fn guest_stream(
     request: HttpRequest,
     payload: Payload,
 ) -> Result<HttpResponse, Error> {
+    check_websocket_origin(web_app.config(), &request)?;
+
+    // Slot invites belong to the room, their tokens are too long to be guessed for probing rooms
+    let slot_invite = match &query.invite {
+        Some(token) if !query.spectate => web_app
+            .room_manager()
+            .has_slot_invite(&query.room_id, token)
+            .await
+            .then(|| token.clone()),
+        _ => None,
+    };
+
+    // Checked before the room is touched so invalid invites can't be used to probe rooms
+    let admission = match slot_invite {
+        Some(_) => GuestAdmission::Admitted,
+        None => {
+            web_app
+                .verify_invite(&query.room_id, query.invite.as_deref(), query.discord_auth)
+                .await?
+        }
+    };
+
     let (response, session, stream) = actix_ws::handle(&request, payload)?;
 
     let web_app = web_app.clone();
@@ -83,6 +124,10 @@ pub async fn guest_stream(
             stream,
             query.room_id,
             query.player_name,
+            query.discord_auth,
+            query.spectate,
+            slot_invite,
+            admission,
         )
         .await;
     });
@@ -97,37 +142,106 @@ async fn handle_guest_connection(
     mut stream: MessageStream,
     room_id: String,
     player_name: Option<String>,
+    discord_auth: bool,
+    spectate: bool,
+    slot_invite: Option<String>,
+    admission: GuestAdmission,
 ) {
+    let discord_access_token = if discord_auth {
+        let Some(access_token) = receive_discord_access_token(&mut stream).await else {
+            warn!("Guest WebSocket didn't send DiscordAuth as first message, closing");
+            let _ = session.close(None).await;
+            return;
+        };
+        Some(access_token)
+    } else {
+        None
+    };
+
+    let discord_user = discord_user(discord_access_token).await;
+    if admission == GuestAdmission::DiscordUser && discord_user.is_none() {
+        warn!("Guest without an invite couldn't be verified as a Discord user, closing");
+        let _ = session.close(None).await;
+        return;
+    }
+
+    let player_name = discord_user
+        .as_ref()
+        .map(|user| user.display_name().to_string())
+        .or(player_name);
+
     // Default queue sizes for guests
     let video_frame_queue_size = 4;
     let audio_sample_queue_size = 4;
 
-    // Find the room
-    let Some(room) = web_app.room_manager().get_room(&room_id).await else {
-        let _ = send_ws_message(
-            &mut session,
-            StreamServerMessage::RoomJoinFailed {
-                reason: "Room not found".to_string(),
-            },
+    if spectate {
+        handle_join_spectator(
+            web_app,
+            session,
+            stream,
+            room_id,
+            player_name,
+            discord_user.as_ref().map(|user| user.id.clone()),
+            discord_user.as_ref().and_then(DiscordUser::avatar_url),
+            video_frame_queue_size,
+            audio_sample_queue_size,
         )
         .await;
+        return;
+    }
+
+    // Find the room
+    let Some(room) = web_app.room_manager().get_room(&room_id).await else {
+        let _ = send_ws_message(&mut session, RoomJoinFailure::NotFound.into()).await;
         let _ = session.close(None).await;
         return;
     };
 
-    // Get the next available player slot
-    let (peer_id, player_slot, role, room_info, ipc_sender, ice_servers, stream_state) = {
+    // Get the slot of the invite or the next available player slot
+    let (
+        peer_id,
+        player_slot,
+        player_name,
+        role,
+        room_info,
+        ipc_sender,
+        ice_servers,
+        debug_logs,
+        notice,
+    ) = {
         let mut room_guard = room.lock().await;
 
-        let Some(player_slot) = room_guard.next_available_slot() else {
+        // Taken while the room is locked, guests with a slot invite skipped the invite check
+        let slot_invite = match slot_invite {
+            Some(token) => match room_guard.take_slot_invite(&token, Instant::now()) {
+                Some(invite) => Some(invite),
+                None => {
+                    drop(room_guard);
+                    let _ = send_ws_message(&mut session, RoomJoinFailure::InviteUsed.into()).await;
+                    let _ = session.close(None).await;
+                    return;
+                }
+            },
+            None => None,
+        };
+        let (player_slot, player_name, notice) = match slot_invite {
+            Some(invite) if room_guard.is_slot_free(invite.slot) => {
+                (Some(invite.slot), Some(invite.name), None)
+            }
+            Some(invite) => (
+                room_guard.next_available_slot(),
+                player_name,
+                Some(format!(
+                    "Player {} of your invite is already taken, you joined with the next free slot",
+                    invite.slot.0 + 1
+                )),
+            ),
+            None => (room_guard.next_available_slot(), player_name, None),
+        };
+
+        let Some(player_slot) = player_slot else {
             drop(room_guard);
-            let _ = send_ws_message(
-                &mut session,
-                StreamServerMessage::RoomJoinFailed {
-                    reason: "Room is full".to_string(),
-                },
-            )
-            .await;
+            let _ = send_ws_message(&mut session, RoomJoinFailure::Full.into()).await;
             let _ = session.close(None).await;
             return;
         };
@@ -138,24 +252,21 @@ async fn handle_guest_connection(
         let client = RoomClient {
             peer_id,
             player_slot: Some(player_slot),
+            local_slots: Vec::new(),
             role: RoomRole::Player,
             player_name: player_name.clone(),
-            discord_user_id: None,
-            discord_avatar: None,
+            discord_user_id: discord_user.as_ref().map(|user| user.id.clone()),
+            discord_avatar: discord_user.as_ref().and_then(DiscordUser::avatar_url),
+            permissions: room_guard.default_permissions(RoomRole::Player),
             session: session.clone(),
+            activity: ClientActivity::default(),
             video_frame_queue_size,
             audio_sample_queue_size,
         };
 
         if !room_guard.add_client(client) {
             drop(room_guard);
-            let _ = send_ws_message(
-                &mut session,
-                StreamServerMessage::RoomJoinFailed {
-                    reason: "Failed to join room".to_string(),
-                },
-            )
-            .await;
+            let _ = send_ws_message(&mut session, RoomJoinFailure::Full.into()).await;
             let _ = session.close(None).await;
             return;
         }
@@ -163,9 +274,9 @@ async fn handle_guest_connection(
         let room_info = room_guard.to_room_info();
         let ipc_sender = room_guard.ipc_sender.clone();
         let ice_servers = room_guard.ice_servers.clone();
-        let stream_state = room_guard.stream_state.clone();
+        let debug_logs = room_guard.debug_log_history(RoomRole::Player);
 
-        (peer_id, Some(player_slot), RoomRole::Player, room_info, ipc_sender, ice_servers, stream_state)
+        (peer_id, Some(player_slot), player_name, RoomRole::Player, room_info, ipc_sender, ice_servers, debug_logs, notice)
     };
 
     // Register peer with room manager
@@ -175,51 +286,39 @@ async fn handle_guest_connection(
     let _ = send_ws_message(
         &mut session,
         StreamServerMessage::RoomJoined {
-            room: room_info.clone(),
+            room: room_info,
             player_slot: player_slot.expect("Player should have slot"),
         },
     )
     .await;
 
-    // Send Setup message with ICE servers so guest can initialize transport
-    if let Some(ice_servers) = ice_servers {
+    // Replay the logs of the stream startup which were sent before this client joined
+    for message in debug_logs {
+        let _ = send_ws_message(&mut session, message).await;
+    }
+
+    if let Some(message) = notice {
         let _ = send_ws_message(
             &mut session,
-            StreamServerMessage::Setup { ice_servers },
+            StreamServerMessage::DebugLog { message, ty: None },
         )
         .await;
     }
 
-    // If stream is already connected, send ConnectionComplete to guest
-    if let Some(state) = stream_state {
+    // Send Setup message with ICE servers so guest can initialize transport
+    if let Some(ice_servers) = ice_servers {
         let _ = send_ws_message(
             &mut session,
-            StreamServerMessage::ConnectionComplete {
-                capabilities: state.capabilities,
-                format: state.format,
-                width: state.width,
-                height: state.height,
-                fps: state.fps,
-                audio_sample_rate: state.audio_sample_rate,
-                audio_channel_count: state.audio_channel_count,
-                audio_streams: state.audio_streams,
-                audio_coupled_streams: state.audio_coupled_streams,
-                audio_samples_per_frame: state.audio_samples_per_frame,
-                audio_mapping: state.audio_mapping,
+            StreamServerMessage::Setup {
+                ice_servers,
+                data_channels: web_app.config().webrtc.data_channel_options(),
             },
         )
         .await;
     }
 
     // Broadcast room update to all existing players
-    {
-        let room_guard = room.lock().await;
-        room_guard
-            .broadcast(StreamServerMessage::RoomUpdated {
-                room: room_info,
-            })
-            .await;
-    }
+    room.lock().await.schedule_room_update();
 
     // Notify streamer about new peer
     if let Some(mut ipc_sender) = ipc_sender.clone() {
@@ -230,6 +329,7 @@ async fn handle_guest_connection(
                 role,
                 video_frame_queue_size,
                 audio_sample_queue_size,
+                name: player_name,
             })
             .await;
     }
@@ -267,9 +367,20 @@ async fn handle_stream_connection(
         StreamClientMessage::Init {
             host_id,
             app_id,
+            app_name,
             video_frame_queue_size,
             audio_sample_queue_size,
         } => {
+            let app = match (app_id, app_name) {
+                (Some(app_id), _) => AppSelector::Id(AppId(app_id)),
+                (None, Some(app_name)) => AppSelector::Name(app_name),
+                (None, None) => {
+                    let _ = session.close(None).await;
+                    warn!("WebSocket sent Init without an app id or name, closing");
+                    return;
+                }
+            };
+
             handle_init_room(
                 web_app,
                 &mut user,
@@ -277,7 +388,7 @@ async fn handle_stream_connection(
                 stream,
                 client_unique_id,
                 HostId(host_id),
-                AppId(app_id),
+                app,
                 video_frame_queue_size,
                 audio_sample_queue_size,
             )
@@ -286,15 +397,39 @@ async fn handle_stream_connection(
         StreamClientMessage::JoinRoom {
             room_id,
             player_name,
+            discord_access_token,
             video_frame_queue_size,
             audio_sample_queue_size,
         } => {
             handle_join_room(
+                web_app,
+                &user,
+                session,
+                stream,
+                room_id,
+                player_name,
+                discord_access_token,
+                video_frame_queue_size,
+                audio_sample_queue_size,
+            )
+            .await;
+        }
+        StreamClientMessage::JoinAsSpectator {
+            room_id,
+            player_name,
+            discord_user_id,
+            discord_avatar,
+            video_frame_queue_size,
+            audio_sample_queue_size,
+        } => {
+            handle_join_spectator(
                 web_app,
                 session,
                 stream,
                 room_id,
                 player_name,
+                discord_user_id,
+                discord_avatar,
                 video_frame_queue_size,
                 audio_sample_queue_size,
             )
@@ -302,7 +437,9 @@ async fn handle_stream_connection(
         }
         _ => {
             let _ = session.close(None).await;
-            warn!("WebSocket didn't send Init or JoinRoom as first message, closing");
+            warn!(
+                "WebSocket didn't send Init, JoinRoom or JoinAsSpectator as first message, closing"
+            );
         }
     }
 }
@@ -312,21 +449,39 @@ async fn handle_init_room(
     web_app: Data<App>,
     user: &mut AuthenticatedUser,
     mut session: Session,
-    mut stream: MessageStream,
+    stream: MessageStream,
     client_unique_id: String,
     host_id: HostId,
-    app_id: AppId,
+    app: AppSelector,
     video_frame_queue_size: usize,
     audio_sample_queue_size: usize,
 ) {
-    // Collect host data
-    let mut host = match user.host(host_id).await {
-        Ok(host) => host,
-        Err(AppError::HostNotFound) => {
+    let stream_host = match user_host(user, host_id).await {
+        Ok(mut host) => {
+            if web_app.config().moonlight.host_start_check && !host_online(user, &mut host).await {
+                info!("[Stream]: host {host_id:?} is offline, the streamer isn't started");
+
+                let _ = send_ws_message(
+                    &mut session,
+                    StreamServerMessage::HostOffline { host_id: host_id.0 },
+                )
+                .await;
+                let _ = session.close(None).await;
+                return;
+            }
+
+            // The same host is used so the host info of the check isn't requested again
+            stream_host(user, host, host_id, app).await
+        }
+        Err(description) => Err(description),
+    };
+    let stream_host = match stream_host {
+        Ok(stream_host) => stream_host,
+        Err(description) => {
             let _ = send_ws_message(
                 &mut session,
                 StreamServerMessage::DebugLog {
-                    message: "Failed to start stream because the host was not found".to_string(),
+                    message: description.to_string(),
                     ty: Some(LogMessageType::FatalDescription),
                 },
             )
@@ -334,66 +489,129 @@ async fn handle_init_room(
             let _ = session.close(None).await;
             return;
         }
+    };
+
+    // An admin looking at the user isn't activity of the user
+    if !user.is_impersonated()
+        && let Err(err) = user.record_stream().await
+    {
+        warn!(
+            "failed to record the stream start of {:?}: {err}",
+            user.id()
+        );
+    }
+
+    create_host_room(
+        web_app,
+        user,
+        session,
+        stream,
+        client_unique_id,
+        host_id,
+        stream_host,
+        video_frame_queue_size,
+        audio_sample_queue_size,
+    )
+    .await;
+}
+
+/// How long the host may take to respond before a stream start
+const HOST_START_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// False if the host doesn't respond within [HOST_START_CHECK_TIMEOUT].
+/// Other errors aren't about the reachability, [stream_host] reports them
+async fn host_online(user: &mut AuthenticatedUser, host: &mut Host) -> bool {
+    match timeout(HOST_START_CHECK_TIMEOUT, host.is_online(user)).await {
+        Ok(Ok(online)) => online,
+        Ok(Err(_)) => true,
+        Err(_) => false,
+    }
+}
+
+/// How the client selected the app of a new room
+enum AppSelector {
+    Id(AppId),
+    Name(String),
+}
+
+impl AppSelector {
+    /// The error is the description shown to the client
+    fn find(&self, apps: Vec<HostApp>) -> Result<HostApp, &'static str> {
+        let mut apps = apps.into_iter().filter(|app| match self {
+            Self::Id(app_id) => app.id == *app_id,
+            Self::Name(name) => app.title.to_lowercase() == name.trim().to_lowercase(),
+        });
+
+        let Some(app) = apps.next() else {
+            return Err("Failed to start stream because the app was not found");
+        };
+        if apps.next().is_some() {
+            return Err("Failed to start stream because multiple apps have this name");
+        }
+
+        Ok(app)
+    }
+}
+
+/// The host and app a new room streams
+struct StreamHost {
+    app: HostApp,
+    limits: HostStreamLimits,
+    address: String,
+    http_port: u16,
+    https_port_override: Option<u16>,
+    pair_info: StorageHostPairInfo,
+}
+
+/// The host of a new room, the error is the description shown to the client
+async fn user_host(user: &mut AuthenticatedUser, host_id: HostId) -> Result<Host, &'static str> {
+    match user.host(host_id).await {
+        Ok(host) => Ok(host),
+        Err(AppError::HostNotFound) => Err("Failed to start stream because the host was not found"),
         Err(err) => {
             warn!("failed to start stream for host {host_id:?} (at host): {err}");
-            let _ = send_ws_message(
-                &mut session,
-                StreamServerMessage::DebugLog {
-                    message: "Failed to start stream because of a server error".to_string(),
-                    ty: Some(LogMessageType::FatalDescription),
-                },
-            )
-            .await;
-            let _ = session.close(None).await;
-            return;
+            Err("Failed to start stream because of a server error")
         }
-    };
+    }
+}
 
+/// Collects the host data the streamer needs, the error is the description shown to the client
+async fn stream_host(
+    user: &mut AuthenticatedUser,
+    mut host: Host,
+    host_id: HostId,
+    app: AppSelector,
+) -> Result<StreamHost, &'static str> {
     let apps = match host.list_apps(user).await {
         Ok(apps) => apps,
+        Err(AppError::HostOffline) => {
+            return Err("Failed to start stream because the host is offline");
+        }
+        Err(AppError::HostPairRejected) => {
+            return Err(
+                "Failed to start stream because the host rejected the pairing, this happens when the host regenerated its certificate. Please pair the host again",
+            );
+        }
         Err(err) => {
             warn!("failed to start stream for host {host_id:?} (at list_apps): {err}");
-            let _ = send_ws_message(
-                &mut session,
-                StreamServerMessage::DebugLog {
-                    message: "Failed to start stream because of a server error".to_string(),
-                    ty: Some(LogMessageType::FatalDescription),
-                },
-            )
-            .await;
-            let _ = session.close(None).await;
-            return;
+            return Err("Failed to start stream because of a server error");
         }
     };
 
-    let Some(app) = apps.iter().find(|app| app.id == app_id).cloned() else {
-        warn!("failed to start stream for host {host_id:?} because the app couldn't be found!");
-        let _ = send_ws_message(
-            &mut session,
-            StreamServerMessage::DebugLog {
-                message: "Failed to start stream because the app was not found".to_string(),
-                ty: Some(LogMessageType::FatalDescription),
-            },
-        )
-        .await;
-        let _ = session.close(None).await;
-        return;
+    // Apps hidden from this user aren't listed, so they can't be started with a crafted Init message
+    let app = match app.find(apps) {
+        Ok(app) => app,
+        Err(description) => {
+            warn!("failed to start stream for host {host_id:?}: {description}");
+            return Err(description);
+        }
     };
 
-    let (address, http_port) = match host.address_port(user).await {
+    let (address, http_port, https_port_override) = match host.address_port(user).await {
         Ok(address_port) => address_port,
         Err(err) => {
             warn!("failed to start stream for host {host_id:?} (at get address_port): {err}");
-            let _ = send_ws_message(
-                &mut session,
-                StreamServerMessage::DebugLog {
-                    message: "Failed to start stream because of a server error".to_string(),
-                    ty: Some(LogMessageType::FatalDescription),
-                },
-            )
-            .await;
-            let _ = session.close(None).await;
-            return;
+            return Err("Failed to start stream because of a server error");
         }
     };
 
@@ -401,30 +619,70 @@ async fn handle_init_room(
         Ok(pair_info) => pair_info,
         Err(err) => {
             warn!("failed to start stream for host {host_id:?} (at get pair_info): {err}");
-            let _ = send_ws_message(
-                &mut session,
-                StreamServerMessage::DebugLog {
-                    message: "Failed to start stream because the host is not paired".to_string(),
-                    ty: Some(LogMessageType::FatalDescription),
-                },
-            )
-            .await;
-            let _ = session.close(None).await;
-            return;
+            return Err("Failed to start stream because the host is not paired");
+        }
+    };
+
+    // Requested stream modes are checked against these, without them every mode is accepted
+    let limits = match host.detailed_host(user).await {
+        Ok(host) => HostStreamLimits {
+            display_modes: host.display_modes,
+            capabilities: host.capabilities,
+            max_luma_pixels_hevc: host.max_luma_pixels_hevc,
+        },
+        Err(err) => {
+            warn!("failed to get the capabilities of host {host_id:?}: {err}");
+            HostStreamLimits::default()
         }
     };
 
+    Ok(StreamHost {
+        app,
+        limits,
+        address,
+        http_port,
+        https_port_override,
+        pair_info,
+    })
+}
+
+/// Creates the room of the host and starts its streamer
+async fn create_host_room(
+    web_app: Data<App>,
+    user: &mut AuthenticatedUser,
+    mut session: Session,
+    mut stream: MessageStream,
+    client_unique_id: String,
+    host_id: HostId,
+    stream_host: StreamHost,
+    video_frame_queue_size: usize,
+    audio_sample_queue_size: usize,
+) {
+    let StreamHost {
+        app,
+        limits,
+        address,
+        http_port,
+        https_port_override,
+        pair_info,
+    } = stream_host;
+    let app_id = app.id;
+
     // Send App info
     let _ = send_ws_message(
         &mut session,
-        StreamServerMessage::UpdateApp { app: app.clone().into() },
+        StreamServerMessage::UpdateApp {
+            app: app.clone().into(),
+            host_capabilities: limits.capabilities.clone(),
+        },
     )
     .await;
 
     // Create the room
+    let owner = RoomOwner::new(user).await;
     let room = web_app
         .room_manager()
-        .create_room(host_id.0, app_id.0, app.title.clone())
+        .create_room(host_id.0, app_id.0, app.title.clone(), Some(owner))
         .await;
 
     // Generate peer ID for the host
@@ -437,21 +695,30 @@ async fn handle_init_room(
         let client = RoomClient {
             peer_id,
             player_slot: Some(player_slot),
+            local_slots: Vec::new(),
             role: RoomRole::Host,
             player_name: Some("Host".to_string()),
             discord_user_id: None,
             discord_avatar: None,
+            permissions: room_guard.default_permissions(RoomRole::Host),
             session: session.clone(),
+            activity: ClientActivity::default(),
             video_frame_queue_size,
             audio_sample_queue_size,
         };
         room_guard.add_client(client);
+        room_guard.creator = Some(user.clone());
+        room_guard.host_limits = limits;
     }
 
     // Register peer with room manager
     let room_id = room.lock().await.room_id.clone();
     web_app.room_manager().register_peer(peer_id, &room_id).await;
 
+    web_app
+        .audit_stream_started(user, &room_id, host_id, app_id.0, &app.title)
+        .await;
+
     // Send room created message
     let room_info = room.lock().await.to_room_info();
     let _ = send_ws_message(
@@ -474,37 +741,10 @@ async fn handle_init_room(
     .await;
 
     // Spawn child process
-    let (mut child, stdin, stdout) = match Command::new(&web_app.config().streamer_path)
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .kill_on_drop(true)
-        .spawn()
-    {
-        Ok(mut child) => {
-            if let Some(stdin) = child.stdin.take()
-                && let Some(stdout) = child.stdout.take()
-            {
-                (child, stdin, stdout)
-            } else {
-                error!("[Stream]: streamer process didn't include a stdin or stdout");
-                let _ = send_ws_message(
-                    &mut session,
-                    StreamServerMessage::DebugLog {
-                        message: "Failed to start stream because of a server error".to_string(),
-                        ty: Some(LogMessageType::FatalDescription),
-                    },
-                )
-                .await;
-                let _ = session.close(None).await;
-                if let Err(err) = child.kill().await {
-                    warn!("[Stream]: failed to kill child: {err}");
-                }
-                return;
-            }
-        }
+    let (child, mut ipc_sender, mut ipc_receiver) = match spawn_streamer(&web_app, &room_id).await {
+        Ok(value) => value,
         Err(err) => {
-            error!("[Stream]: failed to spawn streamer process: {err}");
+            error!("[Stream]: failed to start the streamer: {err:?}");
             let _ = send_ws_message(
                 &mut session,
                 StreamServerMessage::DebugLog {
@@ -518,31 +758,24 @@ async fn handle_init_room(
         }
     };
 
-    // Create IPC
-    let (mut ipc_sender, mut ipc_receiver) =
-        create_child_ipc::<ServerIpcMessage, StreamerIpcMessage>(
-            "Streamer",
-            stdin,
-            stdout,
-            child.stderr.take(),
-        )
-        .await;
-
     // Store IPC sender in room
     {
         let mut room_guard = room.lock().await;
         room_guard.ipc_sender = Some(ipc_sender.clone());
+        room_guard.streamer_pid = child.as_ref().and_then(Child::id);
     }
 
     // Spawn task to handle IPC messages from streamer
     let room_for_ipc = room.clone();
     let web_app_for_ipc = web_app.clone();
     let room_id_for_ipc = room_id.clone();
+    let ipc_sender_for_ipc = ipc_sender.clone();
     spawn(async move {
         handle_streamer_ipc(
             &mut ipc_receiver,
+            &ipc_sender_for_ipc,
             room_for_ipc,
-            &mut child,
+            child,
             web_app_for_ipc,
             room_id_for_ipc,
         )
@@ -555,9 +788,14 @@ async fn handle_init_room(
             config: StreamerConfig {
                 webrtc: web_app.config().webrtc.clone(),
                 log_level: web_app.config().log.level_filter,
+                record_path: web_app.config().record_path.clone(),
+                stage_timeout: web_app.config().moonlight.stage_timeout,
+                memory_limit: web_app.config().streamer.memory_limit,
+                memory_grace_period: web_app.config().streamer.memory_grace_period,
             },
             host_address: address,
             host_http_port: http_port,
+            host_https_port_override: https_port_override,
             client_unique_id: Some(client_unique_id),
             client_private_key: pair_info.client_private_key,
             client_certificate: pair_info.client_certificate,
@@ -576,6 +814,7 @@ async fn handle_init_room(
             role: RoomRole::Host,
             video_frame_queue_size,
             audio_sample_queue_size,
+            name: Some("Host".to_string()),
         })
         .await;
 
@@ -592,45 +831,302 @@ async fn handle_init_room(
     .await;
 }
 
-/// Handle joining an existing room as Player 2-4
-async fn handle_join_room(
-    web_app: Data<App>,
-    mut session: Session,
-    mut stream: MessageStream,
-    room_id: String,
-    player_name: Option<String>,
-    video_frame_queue_size: usize,
-    audio_sample_queue_size: usize,
-) {
-    // Find the room
-    let Some(room) = web_app.room_manager().get_room(&room_id).await else {
-        let _ = send_ws_message(
-            &mut session,
-            StreamServerMessage::RoomJoinFailed {
-                reason: "Room not found".to_string(),
-            },
-        )
-        .await;
-        let _ = session.close(None).await;
-        return;
-    };
-
-    // Get the next available player slot
-    let (peer_id, player_slot, role, room_info, ipc_sender, ice_servers, stream_state) = {
-        let mut room_guard = room.lock().await;
+/// How long the web server tries to connect to the ipc socket of a new streamer
+#[cfg(unix)]
+const STREAMER_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Starts the streamer of a room, the child is None if the streamer doesn't run in a child process.
+/// If the rooms are persisted it listens on a socket in the runtime directory and outlives the web server.
+async fn spawn_streamer(
+    web_app: &App,
+    room_id: &str,
+) -> Result<
+    (
+        Option<Child>,
+        IpcSender<ServerIpcMessage>,
+        IpcReceiver<StreamerIpcMessage>,
+    ),
+    anyhow::Error,
+> {
+    let ipc_capacity = web_app.config().streamer_ipc_capacity;
+
+    // The tests can't stream from a real host, so they run a fake streamer in this process
+    #[cfg(test)]
+    if web_app.config().streamer.path == test::FAKE_STREAMER_PATH {
+        let (ipc_sender, ipc_receiver) = test::spawn_fake_streamer(ipc_capacity);
+        return Ok((None, ipc_sender, ipc_receiver));
+    }
 
-        let Some(player_slot) = room_guard.next_available_slot() else {
-            drop(room_guard);
-            let _ = send_ws_message(
-                &mut session,
-                StreamServerMessage::RoomJoinFailed {
-                    reason: "Room is full".to_string(),
-                },
+    #[cfg(unix)]
+    if let Some(directory) = RuntimeDirectory::from_config(&web_app.config().rooms) {
+        directory.create().await?;
+
+        let socket_path = directory.socket_path(room_id);
+        let log = std::fs::File::create(directory.log_path(room_id))?;
+
+        let mut child = streamer_command(&web_app.config().streamer, room_id)
+            .arg(STREAMER_IPC_SOCKET_ARG)
+            .arg(&socket_path)
+            .arg(STREAMER_IPC_CAPACITY_ARG)
+            .arg(ipc_capacity.to_string())
+            .arg(STREAMER_REATTACH_TIMEOUT_ARG)
+            .arg(
+                web_app
+                    .config()
+                    .rooms
+                    .streamer_reattach_timeout
+                    .as_secs()
+                    .to_string(),
             )
-            .await;
-            let _ = session.close(None).await;
-            return;
-        };
+            .stdin(Stdio::null())
+            .stdout(log.try_clone()?)
+            .stderr(log)
+            // Signals for the web server, e.g. ctrl+c in its terminal, must not reach the streamer
+            .process_group(0)
+            .kill_on_drop(false)
+            .spawn()?;
+        apply_process_scheduling(child.id(), &web_app.config().streamer);
+
+        return match connect_child_ipc(
+            &format!("Streamer {room_id}"),
+            &socket_path,
+            STREAMER_CONNECT_TIMEOUT,
+            ipc_capacity,
+        )
+        .await
+        {
+            Ok((ipc_sender, ipc_receiver)) => Ok((Some(child), ipc_sender, ipc_receiver)),
+            Err(err) => {
+                if let Err(err) = child.kill().await {
+                    warn!("[Stream]: failed to kill child: {err}");
+                }
+                directory.remove(room_id).await;
+                Err(err.into())
+            }
+        };
+    }
+
+    let mut child = streamer_command(&web_app.config().streamer, room_id)
+        .arg(STREAMER_IPC_CAPACITY_ARG)
+        .arg(ipc_capacity.to_string())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    apply_process_scheduling(child.id(), &web_app.config().streamer);
+
+    let (Some(stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+        if let Err(err) = child.kill().await {
+            warn!("[Stream]: failed to kill child: {err}");
+        }
+        anyhow::bail!("streamer process didn't include a stdin or stdout");
+    };
+
+    let (ipc_sender, ipc_receiver) = create_child_ipc(
+        &format!("Streamer {room_id}"),
+        ipc_capacity,
+        stdin,
+        stdout,
+        child.stderr.take(),
+    )
+    .await;
+
+    Ok((Some(child), ipc_sender, ipc_receiver))
+}
+
+/// Restores the rooms of the streamers which kept running while the web server restarted.
+/// Rooms whose streamer is gone are removed. The clients have to join the restored rooms again.
+#[cfg(unix)]
+pub async fn restore_rooms(web_app: Data<App>) {
+    let Some(directory) = RuntimeDirectory::from_config(&web_app.config().rooms) else {
+        return;
+    };
+
+    let states = match directory.load_all().await {
+        Ok(states) => states,
+        Err(err) => {
+            warn!("[Rooms]: failed to load the persisted rooms: {err}");
+            return;
+        }
+    };
+
+    for state in states {
+        let room_id = state.room_id.clone();
+
+        let socket_path = directory.socket_path(&room_id);
+        let (mut ipc_sender, mut ipc_receiver) = match connect_child_ipc(
+            &format!("Streamer {room_id}"),
+            &socket_path,
+            Duration::ZERO,
+            web_app.config().streamer_ipc_capacity,
+        )
+        .await
+        {
+            Ok(ipc) => ipc,
+            Err(err) => {
+                info!("[Rooms]: the streamer of room {room_id} is gone: {err}");
+                // It might still run without listening, e.g. if it hangs
+                if let Some(pid) = state.streamer_pid {
+                    kill_orphaned_streamer(pid, &socket_path).await;
+                }
+                directory.remove(&room_id).await;
+                continue;
+            }
+        };
+
+        let room = web_app.room_manager().restore_room(state).await;
+
+        // The streamer still has the peers and settings of before the restart
+        ipc_sender.send(ServerIpcMessage::Reattached).await;
+        {
+            let mut room_guard = room.lock().await;
+            let guests_keyboard_mouse_enabled = room_guard.guests_keyboard_mouse_enabled;
+            let spectator_delay = room_guard.spectator_delay;
+            let controller_mode = room_guard.controller_mode;
+
+            room_guard.ipc_sender = Some(ipc_sender.clone());
+            ipc_sender
+                .send(ServerIpcMessage::SetGuestsKeyboardMouseEnabled {
+                    enabled: guests_keyboard_mouse_enabled,
+                })
+                .await;
+            ipc_sender
+                .send(ServerIpcMessage::SetSpectatorDelay {
+                    delay: spectator_delay,
+                })
+                .await;
+            ipc_sender
+                .send(ServerIpcMessage::SetControllerMode {
+                    mode: controller_mode,
+                })
+                .await;
+        }
+
+        let web_app = web_app.clone();
+        spawn(async move {
+            handle_streamer_ipc(&mut ipc_receiver, &ipc_sender, room, None, web_app, room_id).await;
+        });
+    }
+}
+/// Kills a streamer the web server couldn't reattach to.
+/// The pid might belong to another process by now, so it's only killed if it was started with the same socket.
+#[cfg(unix)]
+async fn kill_orphaned_streamer(pid: u32, socket_path: &Path) {
+    let Ok(raw_pid) = libc::pid_t::try_from(pid) else {
+        return;
+    };
+
+    let Ok(command_line) = tokio::fs::read(format!("/proc/{pid}/cmdline")).await else {
+        return;
+    };
+    let is_streamer = command_line
+        .split(|byte| *byte == 0)
+        .any(|arg| arg == socket_path.as_os_str().as_encoded_bytes());
+    if !is_streamer {
+        return;
+    }
+
+    warn!("[Rooms]: killing the streamer {pid} which doesn't accept the ipc anymore");
+    // SAFETY: sending a signal has no memory safety preconditions
+    if unsafe { libc::kill(raw_pid, libc::SIGKILL) } != 0 {
+        warn!(
+            "[Rooms]: failed to kill the streamer {pid}: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn restore_rooms(web_app: Data<App>) {
+    if web_app.config().rooms.runtime_path.is_some() {
+        warn!("[Rooms]: persisting rooms is only supported on unix, the runtime path is ignored");
+    }
+}
+
+/// Fetches the Discord profile of a joining client, None if there's no token or the request failed
+/// Waits for the [StreamClientMessage::DiscordAuth] a guest sends first
+async fn receive_discord_access_token(stream: &mut MessageStream) -> Option<String> {
+    let message = loop {
+        match stream.recv().await {
+            Some(Ok(Message::Text(text))) => break text,
+            Some(Ok(Message::Binary(_))) => return None,
+            Some(Ok(_)) => continue,
+            Some(Err(_)) | None => return None,
+        }
+    };
+
+    match serde_json::from_str::<StreamClientMessage>(&message) {
+        Ok(StreamClientMessage::DiscordAuth { access_token }) => Some(access_token),
+        _ => None,
+    }
+}
+
+async fn discord_user(access_token: Option<String>) -> Option<DiscordUser> {
+    let access_token = access_token?;
+
+    match fetch_discord_user(&access_token).await {
+        Ok(user) => Some(user),
+        Err(err) => {
+            warn!(
+                "Failed to fetch the Discord user of a joining client, using the player name: {err}"
+            );
+            None
+        }
+    }
+}
+
+/// Handle joining an existing room as Player 2-4, the creator of a room without a host joins as the host
+async fn handle_join_room(
+    web_app: Data<App>,
+    user: &AuthenticatedUser,
+    mut session: Session,
+    mut stream: MessageStream,
+    room_id: String,
+    player_name: Option<String>,
+    discord_access_token: Option<String>,
+    video_frame_queue_size: usize,
+    audio_sample_queue_size: usize,
+) {
+    let discord_user = discord_user(discord_access_token).await;
+    let player_name = discord_user
+        .as_ref()
+        .map(|user| user.display_name().to_string())
+        .or(player_name);
+
+    // Find the room
+    let Some(room) = web_app.room_manager().get_room(&room_id).await else {
+        let _ = send_ws_message(&mut session, RoomJoinFailure::NotFound.into()).await;
+        let _ = session.close(None).await;
+        return;
+    };
+
+    // Get the next available player slot
+    let (peer_id, player_slot, role, room_info, ipc_sender, ice_servers, debug_logs, player_name) = {
+        let mut room_guard = room.lock().await;
+
+        // A restored room has no host until its creator joins again
+        let reclaims_host = room_guard.can_reclaim_host(user.id().0);
+        let (role, player_slot, player_name) = if reclaims_host {
+            (
+                RoomRole::Host,
+                Some(PlayerSlot::PLAYER_1),
+                Some("Host".to_string()),
+            )
+        } else {
+            (
+                RoomRole::Player,
+                room_guard.next_available_slot(),
+                player_name,
+            )
+        };
+
+        let Some(player_slot) = player_slot else {
+            drop(room_guard);
+            let _ = send_ws_message(&mut session, RoomJoinFailure::Full.into()).await;
+            let _ = session.close(None).await;
+            return;
+        };
 
         let peer_id = web_app.room_manager().generate_peer_id();
 
@@ -638,34 +1134,34 @@ async fn handle_join_room(
         let client = RoomClient {
             peer_id,
             player_slot: Some(player_slot),
-            role: RoomRole::Player,
+            local_slots: Vec::new(),
+            role,
             player_name: player_name.clone(),
-            discord_user_id: None,
-            discord_avatar: None,
+            discord_user_id: discord_user.as_ref().map(|user| user.id.clone()),
+            discord_avatar: discord_user.as_ref().and_then(DiscordUser::avatar_url),
+            permissions: room_guard.default_permissions(role),
             session: session.clone(),
+            activity: ClientActivity::default(),
             video_frame_queue_size,
             audio_sample_queue_size,
         };
 
         if !room_guard.add_client(client) {
             drop(room_guard);
-            let _ = send_ws_message(
-                &mut session,
-                StreamServerMessage::RoomJoinFailed {
-                    reason: "Failed to join room".to_string(),
-                },
-            )
-            .await;
+            let _ = send_ws_message(&mut session, RoomJoinFailure::Full.into()).await;
             let _ = session.close(None).await;
             return;
         }
+        if reclaims_host {
+            room_guard.creator = Some(user.clone());
+        }
 
         let room_info = room_guard.to_room_info();
         let ipc_sender = room_guard.ipc_sender.clone();
         let ice_servers = room_guard.ice_servers.clone();
-        let stream_state = room_guard.stream_state.clone();
+        let debug_logs = room_guard.debug_log_history(role);
 
-        (peer_id, Some(player_slot), RoomRole::Player, room_info, ipc_sender, ice_servers, stream_state)
+        (peer_id, Some(player_slot), role, room_info, ipc_sender, ice_servers, debug_logs, player_name)
     };
 
     // Register peer with room manager
@@ -675,69 +1171,199 @@ async fn handle_join_room(
     let _ = send_ws_message(
         &mut session,
         StreamServerMessage::RoomJoined {
-            room: room_info.clone(),
+            room: room_info,
             player_slot: player_slot.expect("Player should have slot"),
         },
     )
     .await;
 
+    // Replay the logs of the stream startup which were sent before this client joined
+    for message in debug_logs {
+        let _ = send_ws_message(&mut session, message).await;
+    }
+
     // Send Setup message with ICE servers so late-joining client can initialize transport
     if let Some(ice_servers) = ice_servers {
         let _ = send_ws_message(
             &mut session,
-            StreamServerMessage::Setup { ice_servers },
+            StreamServerMessage::Setup {
+                ice_servers,
+                data_channels: web_app.config().webrtc.data_channel_options(),
+            },
         )
         .await;
     }
 
-    // If stream is already connected, send ConnectionComplete to late-joining client
-    if let Some(state) = stream_state {
+    // Broadcast room update to all existing players
+    room.lock().await.schedule_room_update();
+
+    // Notify streamer about new peer
+    if let Some(mut ipc_sender) = ipc_sender.clone() {
+        ipc_sender
+            .send(ServerIpcMessage::PeerConnected {
+                peer_id,
+                player_slot,
+                role,
+                video_frame_queue_size,
+                audio_sample_queue_size,
+                name: player_name,
+            })
+            .await;
+    }
+
+    // Handle WebSocket messages from this client
+    if let Some(ipc_sender) = ipc_sender {
+        handle_client_websocket(web_app, room, peer_id, player_slot, role, &mut stream, ipc_sender)
+            .await;
+    }
+}
+
+/// Handle joining an existing room as a spectator, waits in the spectator queue if the room is full
+async fn handle_join_spectator(
+    web_app: Data<App>,
+    mut session: Session,
+    mut stream: MessageStream,
+    room_id: String,
+    player_name: Option<String>,
+    discord_user_id: Option<String>,
+    discord_avatar: Option<String>,
+    video_frame_queue_size: usize,
+    audio_sample_queue_size: usize,
+) {
+    // Find the room
+    let Some(room) = web_app.room_manager().get_room(&room_id).await else {
+        let _ = send_ws_message(&mut session, RoomJoinFailure::NotFound.into()).await;
+        let _ = session.close(None).await;
+        return;
+    };
+
+    let peer_id = web_app.room_manager().generate_peer_id();
+    let role = RoomRole::Spectator;
+
+    let join = {
+        let mut room_guard = room.lock().await;
+
+        let client = RoomClient {
+            peer_id,
+            player_slot: None,
+            local_slots: Vec::new(),
+            role,
+            player_name: player_name.clone(),
+            discord_user_id,
+            discord_avatar,
+            permissions: room_guard.default_permissions(role),
+            session: session.clone(),
+            activity: ClientActivity::default(),
+            video_frame_queue_size,
+            audio_sample_queue_size,
+        };
+
+        room_guard.join_spectator(client)
+    };
+
+    match join {
+        SpectatorJoin::Joined => {}
+        SpectatorJoin::Rejected => {
+            let _ = send_ws_message(&mut session, RoomJoinFailure::SpectatorLimit.into()).await;
+            let _ = session.close(None).await;
+            return;
+        }
+        SpectatorJoin::Queued {
+            position,
+            mut admitted,
+        } => {
+            let _ = send_ws_message(
+                &mut session,
+                StreamServerMessage::QueuePosition { position },
+            )
+            .await;
+
+            // Wait for a free slot while watching if the client leaves
+            let admitted = loop {
+                select! {
+                    result = &mut admitted => break result.is_ok(),
+                    message = stream.recv() => match message {
+                        Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                            let mut room_guard = room.lock().await;
+                            // The spectator might've been admitted at the same time
+                            if !room_guard.remove_queued_spectator(peer_id) {
+                                room_guard.remove_client(peer_id);
+                            }
+                            room_guard.update_spectator_queue().await;
+
+                            debug!("Queued spectator {:?} left room {}", peer_id, room_id);
+                            return;
+                        }
+                        // Keeps the connection alive while waiting
+                        Some(Ok(Message::Ping(bytes))) => {
+                            let _ = session.pong(&bytes).await;
+                        }
+                        Some(Ok(_)) => continue,
+                    },
+                }
+            };
+
+            if !admitted {
+                let _ = send_ws_message(&mut session, RoomJoinFailure::Closed.into()).await;
+                let _ = session.close(None).await;
+                return;
+            }
+        }
+    }
+
+    let (room_info, ipc_sender, ice_servers) = {
+        let room_guard = room.lock().await;
+        (
+            room_guard.to_room_info(),
+            room_guard.ipc_sender.clone(),
+            room_guard.ice_servers.clone(),
+        )
+    };
+
+    // Register peer with room manager
+    web_app.room_manager().register_peer(peer_id, &room_id).await;
+
+    // Send join success to the joining spectator
+    let _ = send_ws_message(
+        &mut session,
+        StreamServerMessage::SpectatorJoined {
+            room: room_info,
+        },
+    )
+    .await;
+
+    // Send Setup message with ICE servers so late-joining client can initialize transport
+    if let Some(ice_servers) = ice_servers {
         let _ = send_ws_message(
             &mut session,
-            StreamServerMessage::ConnectionComplete {
-                capabilities: state.capabilities,
-                format: state.format,
-                width: state.width,
-                height: state.height,
-                fps: state.fps,
-                audio_sample_rate: state.audio_sample_rate,
-                audio_channel_count: state.audio_channel_count,
-                audio_streams: state.audio_streams,
-                audio_coupled_streams: state.audio_coupled_streams,
-                audio_samples_per_frame: state.audio_samples_per_frame,
-                audio_mapping: state.audio_mapping,
+            StreamServerMessage::Setup {
+                ice_servers,
+                data_channels: web_app.config().webrtc.data_channel_options(),
             },
         )
         .await;
     }
 
-    // Broadcast room update to all existing players
-    {
-        let room_guard = room.lock().await;
-        room_guard
-            .broadcast(StreamServerMessage::RoomUpdated {
-                room: room_info,
-            })
-            .await;
-    }
+    // Broadcast room update to all clients
+    room.lock().await.schedule_room_update();
 
     // Notify streamer about new peer
     if let Some(mut ipc_sender) = ipc_sender.clone() {
         ipc_sender
             .send(ServerIpcMessage::PeerConnected {
                 peer_id,
-                player_slot,
+                player_slot: None,
                 role,
                 video_frame_queue_size,
                 audio_sample_queue_size,
+                name: player_name,
             })
             .await;
     }
 
     // Handle WebSocket messages from this client
     if let Some(ipc_sender) = ipc_sender {
-        handle_client_websocket(web_app, room, peer_id, player_slot, role, &mut stream, ipc_sender)
-            .await;
+        handle_client_websocket(web_app, room, peer_id, None, role, &mut stream, ipc_sender).await;
     }
 }
 
@@ -751,39 +1377,322 @@ async fn handle_client_websocket(
     stream: &mut MessageStream,
     mut ipc_sender: common::ipc::IpcSender<ServerIpcMessage>,
 ) {
+    let activity = room
+        .lock()
+        .await
+        .get_client(peer_id)
+        .map(|client| client.activity.clone());
+
     while let Some(Ok(message)) = stream.recv().await {
+        // Any message counts, only spectators can be disconnected for being idle
+        if let Some(activity) = &activity {
+            activity.record();
+        }
+
         match message {
             Message::Text(text) => {
-                let Ok(client_message) = serde_json::from_str::<StreamClientMessage>(&text)
+                let Ok(mut client_message) = serde_json::from_str::<StreamClientMessage>(&text)
                 else {
                     warn!("[Stream]: failed to deserialize from json");
                     continue;
                 };
 
-                // Handle leave room
-                if matches!(client_message, StreamClientMessage::LeaveRoom) {
-                    break;
+                // Handle leave room
+                if matches!(client_message, StreamClientMessage::LeaveRoom) {
+                    break;
+                }
+
+                // Only keeps the client from being idle, the streamer doesn't need it
+                if matches!(client_message, StreamClientMessage::KeepAlive) {
+                    continue;
+                }
+
+                // Handle host-only keyboard/mouse permission setting
+                if let StreamClientMessage::SetGuestsKeyboardMouseEnabled { enabled } = &client_message {
+                    // Only the host can change this setting
+                    if role.is_host() {
+                        let mut room_guard = room.lock().await;
+                        room_guard.set_guests_keyboard_mouse_enabled(*enabled).await;
+
+                        // Broadcast the change to all clients
+                        room_guard
+                            .broadcast(StreamServerMessage::GuestsKeyboardMouseEnabled {
+                                enabled: *enabled,
+                            })
+                            .await;
+                        // The permissions of the guests changed too
+                        room_guard.schedule_room_update();
+                    } else {
+                        warn!("Non-host player {:?} tried to change keyboard/mouse permission", peer_id);
+                    }
+                    continue;
+                }
+
+                // Handle host-only per player permission setting
+                if let StreamClientMessage::SetPeerPermissions {
+                    peer_id: target_peer_id,
+                    permissions,
+                } = &client_message
+                {
+                    // Only the host can change this setting
+                    if role.is_host() {
+                        let mut room_guard = room.lock().await;
+                        if room_guard
+                            .set_peer_permissions(*target_peer_id, *permissions)
+                            .await
+                        {
+                            // Broadcast the change to all clients
+                            room_guard.schedule_room_update();
+                        } else {
+                            warn!(
+                                "Host {:?} tried to change the permissions of {:?} which isn't a player",
+                                peer_id, target_peer_id
+                            );
+                        }
+                    } else {
+                        warn!(
+                            "Non-host player {:?} tried to change the permissions of {:?}",
+                            peer_id, target_peer_id
+                        );
+                    }
+                    continue;
+                }
+
+                // Handle host-only slot invites, only the host gets the token
+                if let StreamClientMessage::CreateSlotInvite {
+                    slot,
+                    name,
+                    expires_in,
+                } = &client_message
+                {
+                    if role.is_host() {
+                        let valid_for = Duration::from_secs(*expires_in as u64)
+                            .min(web_app.config().web_server.invite_lifetime);
+
+                        let mut room_guard = room.lock().await;
+                        let now = Instant::now();
+                        let outgoing = match room_guard.create_slot_invite(
+                            *slot,
+                            name.clone(),
+                            now + valid_for,
+                            now,
+                        ) {
+                            Some(token) => {
+                                let expires_at = (SystemTime::now() + valid_for)
+                                    .duration_since(UNIX_EPOCH)
+                                    .map(|time| time.as_millis() as u64)
+                                    .unwrap_or_default();
+
+                                room_guard.prepare_send_to_peer(
+                                    peer_id,
+                                    &StreamServerMessage::SlotInviteCreated {
+                                        token,
+                                        slot: *slot,
+                                        name: name.clone(),
+                                        expires_at,
+                                    },
+                                )
+                            }
+                            None => {
+                                warn!("Host {:?} tried to invite into slot {:?}", peer_id, slot);
+                                None
+                            }
+                        };
+                        drop(room_guard);
+
+                        if let Some(outgoing) = outgoing {
+                            outgoing.send().await;
+                        }
+                    } else {
+                        warn!(
+                            "Non-host player {:?} tried to create a slot invite",
+                            peer_id
+                        );
+                    }
+                    continue;
+                }
+
+                if let StreamClientMessage::RevokeInvite { token } = &client_message {
+                    // Only the host can revoke invites
+                    if role.is_host() {
+                        if !room.lock().await.revoke_slot_invite(token) {
+                            debug!(
+                                "Host {:?} revoked a slot invite which doesn't exist",
+                                peer_id
+                            );
+                        }
+                    } else {
+                        warn!(
+                            "Non-host player {:?} tried to revoke a slot invite",
+                            peer_id
+                        );
+                    }
+                    continue;
+                }
+
+                // Handle host-only spectator delay setting
+                if let StreamClientMessage::SetSpectatorDelay { seconds } = &client_message {
+                    // Only the host can change this setting
+                    if role.is_host() {
+                        let mut room_guard = room.lock().await;
+                        room_guard
+                            .set_spectator_delay(Duration::from_secs(*seconds as u64))
+                            .await;
+
+                        // Broadcast the change to all clients
+                        let seconds = room_guard.spectator_delay.as_secs() as u32;
+                        room_guard
+                            .broadcast(StreamServerMessage::SpectatorDelay { seconds })
+                            .await;
+                    } else {
+                        warn!(
+                            "Non-host player {:?} tried to change the spectator delay",
+                            peer_id
+                        );
+                    }
+                    continue;
+                }
+
+                // Handle host-only spectator limit setting
+                if let StreamClientMessage::SetMaxSpectators { max_spectators } = &client_message {
+                    // Only the host can change this setting
+                    if role.is_host() {
+                        let mut room_guard = room.lock().await;
+                        room_guard.set_max_spectators(*max_spectators);
+                        room_guard.update_spectator_queue().await;
+
+                        // Broadcast the change to all clients
+                        room_guard.schedule_room_update();
+                    } else {
+                        warn!(
+                            "Non-host player {:?} tried to change the spectator limit",
+                            peer_id
+                        );
+                    }
+                    continue;
+                }
+
+                // Handle host-only controller mode setting
+                if let StreamClientMessage::SetControllerMode { mode } = &client_message {
+                    // Only the host can change this setting
+                    if role.is_host() {
+                        let mut room_guard = room.lock().await;
+                        room_guard.set_controller_mode(*mode).await;
+
+                        // Broadcast the change to all clients
+                        room_guard.schedule_room_update();
+                    } else {
+                        warn!(
+                            "Non-host player {:?} tried to change the controller mode",
+                            peer_id
+                        );
+                    }
+                    continue;
+                }
+
+                // Handle host-only setting for multiple gamepads per player
+                if let StreamClientMessage::SetMultipleGamepadsPerPeer { enabled } = &client_message
+                {
+                    // Only the host can change this setting
+                    if role.is_host() {
+                        let mut room_guard = room.lock().await;
+                        room_guard
+                            .set_allow_multiple_gamepads_per_peer(*enabled)
+                            .await;
+
+                        // Broadcast the change to all clients
+                        room_guard.schedule_room_update();
+                    } else {
+                        warn!(
+                            "Non-host player {:?} tried to change whether players can use multiple gamepads",
+                            peer_id
+                        );
+                    }
+                    continue;
+                }
+
+                // The additional gamepads of a player take free player slots
+                if let StreamClientMessage::SetLocalGamepadCount { count } = &client_message {
+                    let mut room_guard = room.lock().await;
+                    if room_guard.set_local_gamepad_count(peer_id, *count).await {
+                        // Everyone sees the new players and the remaining slots
+                        room_guard.schedule_room_update();
+                    }
+                    continue;
                 }
 
-                // Handle host-only keyboard/mouse permission setting
-                if let StreamClientMessage::SetGuestsKeyboardMouseEnabled { enabled } = &client_message {
-                    // Only the host can change this setting
+                // Handle host-only stream stop
+                if let StreamClientMessage::StopStream { quit_app } = &client_message {
+                    // Only the host can stop the stream
                     if role.is_host() {
                         let mut room_guard = room.lock().await;
-                        room_guard.set_guests_keyboard_mouse_enabled(*enabled).await;
+                        room_guard.stop_request = Some(StopRequest {
+                            quit_app: *quit_app,
+                        });
+                        drop(room_guard);
 
-                        // Broadcast the change to all clients
-                        room_guard
-                            .broadcast(StreamServerMessage::GuestsKeyboardMouseEnabled {
-                                enabled: *enabled,
-                            })
+                        ipc_sender.send(ServerIpcMessage::StopStream).await;
+                    } else {
+                        warn!("Non-host player {:?} tried to stop the stream", peer_id);
+                    }
+                    continue;
+                }
+
+                // Handle host-only stream pause, the streamer tells the clients
+                if matches!(
+                    client_message,
+                    StreamClientMessage::PauseStream | StreamClientMessage::ResumeStream
+                ) {
+                    // Only the host can pause the stream
+                    if role.is_host() {
+                        let paused = matches!(client_message, StreamClientMessage::PauseStream);
+                        ipc_sender
+                            .send(ServerIpcMessage::SetStreamPaused { paused })
                             .await;
                     } else {
-                        warn!("Non-host player {:?} tried to change keyboard/mouse permission", peer_id);
+                        warn!("Non-host player {:?} tried to pause the stream", peer_id);
                     }
                     continue;
                 }
 
+                if let StreamClientMessage::StartStream {
+                    width,
+                    height,
+                    fps,
+                    video_supported_formats,
+                    ..
+                } = &mut client_message
+                {
+                    let mut room_guard = room.lock().await;
+
+                    let logs = match check_stream_mode(
+                        &mut room_guard,
+                        width,
+                        height,
+                        fps,
+                        video_supported_formats,
+                    ) {
+                        Ok(logs) => logs,
+                        Err(message) => {
+                            let outgoing = room_guard.prepare_send_to_peer(peer_id, &message);
+                            drop(room_guard);
+
+                            if let Some(outgoing) = outgoing {
+                                outgoing.send().await;
+                            }
+                            continue;
+                        }
+                    };
+
+                    // The streamer replaces the running stream, late joiners must wait for the new one
+                    room_guard.start_stream();
+                    drop(room_guard);
+
+                    for outgoing in logs {
+                        outgoing.send().await;
+                    }
+                }
+
                 // Send message to streamer with peer ID
                 ipc_sender
                     .send(ServerIpcMessage::PeerWebSocket {
@@ -812,6 +1721,94 @@ async fn handle_client_websocket(
     handle_client_disconnect(web_app, room, peer_id, player_slot, ipc_sender).await;
 }
 
+/// Checks the mode of a StartStream message against the host and changes it to a supported one if possible.
+/// The error is sent to the client instead of starting the stream,
+/// the logs about changed modes are returned to be broadcast after the room is unlocked.
+/// HEVC is removed from the video formats if the host can't encode the resolution with it
+fn check_stream_mode(
+    room: &mut Room,
+    width: &mut u32,
+    height: &mut u32,
+    fps: &mut u32,
+    video_supported_formats: &mut u32,
+) -> Result<Vec<OutgoingMessage>, StreamServerMessage> {
+    let mut logs = Vec::new();
+
+    let check = check_display_mode(&room.host_limits.display_modes, *width, *height, *fps);
+    match check {
+        DisplayModeCheck::Supported => {}
+        DisplayModeCheck::Substituted(mode) => {
+            let message = format!(
+                "The host doesn't support {width}x{height} at {fps} fps, using {}x{} at {} fps instead",
+                mode.width, mode.height, mode.refresh_rate
+            );
+            info!("[Stream]: room {}: {message}", room.room_id);
+
+            *width = mode.width;
+            *height = mode.height;
+            *fps = mode.refresh_rate;
+
+            room.push_debug_log(message.clone(), None);
+            logs.extend(
+                room.prepare_broadcast(&StreamServerMessage::DebugLog { message, ty: None }),
+            );
+        }
+        DisplayModeCheck::Unsupported => {
+            warn!(
+                "[Stream]: room {}: the host has no display mode close to {width}x{height} at {fps} fps",
+                room.room_id
+            );
+
+            return Err(StreamServerMessage::UnsupportedDisplayMode {
+                width: *width,
+                height: *height,
+                fps: *fps,
+                supported_modes: room.host_limits.display_modes.clone(),
+            });
+        }
+    }
+
+    let check = check_resolution(&room.host_limits, video_supported_formats, *width, *height);
+    match check {
+        ResolutionCheck::Supported => {}
+        ResolutionCheck::Clamped {
+            width: clamped_width,
+            height: clamped_height,
+        } => {
+            let message = format!(
+                "The host can't encode {width}x{height} with HEVC, using {clamped_width}x{clamped_height} instead"
+            );
+            info!("[Stream]: room {}: {message}", room.room_id);
+
+            *width = clamped_width;
+            *height = clamped_height;
+
+            room.push_debug_log(message.clone(), None);
+            logs.extend(
+                room.prepare_broadcast(&StreamServerMessage::DebugLog { message, ty: None }),
+            );
+        }
+        ResolutionCheck::Unsupported {
+            max_width,
+            max_height,
+        } => {
+            warn!(
+                "[Stream]: room {}: the host can't stream {width}x{height}",
+                room.room_id
+            );
+
+            return Err(StreamServerMessage::ResolutionTooHigh {
+                width: *width,
+                height: *height,
+                max_width,
+                max_height,
+            });
+        }
+    }
+
+    Ok(logs)
+}
+
 /// Handle client disconnection
 async fn handle_client_disconnect(
     web_app: Data<App>,
@@ -846,52 +1843,85 @@ async fn handle_client_disconnect(
             room_guard
                 .broadcast(StreamServerMessage::PlayerLeft { slot })
                 .await;
-            room_guard
-                .broadcast(StreamServerMessage::RoomUpdated {
-                    room: room_guard.to_room_info(),
-                })
-                .await;
+            room_guard.schedule_room_update();
             (room_guard.is_empty(), room_id)
         } else {
-            // Spectator left - just update the room info
-            room_guard
-                .broadcast(StreamServerMessage::RoomUpdated {
-                    room: room_guard.to_room_info(),
-                })
-                .await;
+            // Spectator left - admit the next queued spectator and update the room info
+            room_guard.update_spectator_queue().await;
+            room_guard.schedule_room_update();
             (room_guard.is_empty(), room_id)
         }
     };
 
     if should_close_room {
         web_app.room_manager().delete_room(&room_id).await;
+        web_app.discord_instances().remove_room(&room_id).await;
         // Stop the streamer
         ipc_sender.send(ServerIpcMessage::Stop).await;
     }
 }
 
-/// Handle IPC messages from the streamer
+/// Handle IPC messages from the streamer until it stops or the pipe to it broke
+/// The child is None if the streamer was started before the web server restarted
 async fn handle_streamer_ipc(
-    ipc_receiver: &mut common::ipc::IpcReceiver<StreamerIpcMessage>,
+    ipc_receiver: &mut IpcReceiver<StreamerIpcMessage>,
+    ipc_sender: &IpcSender<ServerIpcMessage>,
     room: Arc<Mutex<Room>>,
-    child: &mut Child,
+    child: Option<Child>,
     web_app: Data<App>,
     room_id: String,
 ) {
     use crate::room::StreamState;
 
-    while let Some(message) = ipc_receiver.recv().await {
+    let mut slow_clients = SlowClients::default();
+    // Set when the streamer stopped by itself, otherwise it crashed or the ipc broke
+    let mut stopped = false;
+
+    loop {
+        // A dead streamer might not close its output yet, e.g. while a child of it still holds it
+        let message = select! {
+            message = ipc_receiver.recv() => message,
+            _ = ipc_sender.broken() => {
+                warn!("[Ipc]: the pipe to the streamer of room {room_id} broke");
+                None
+            }
+        };
+        let Some(message) = message else {
+            break;
+        };
+
         match message {
             StreamerIpcMessage::WebSocket(server_message) => {
-                // Store Setup and ConnectionComplete for late-joining clients
+                let mut stopped = false;
+                if let StreamServerMessage::ConnectionTerminated {
+                    error_code,
+                    reason,
+                    hint,
+                } = &server_message
                 {
+                    let owner = room.lock().await.owner_name().to_string();
+                    warn!(
+                        "[Stream]: connection of room {room_id} owned by {owner} terminated with {reason:?} (error code {error_code}): {hint}"
+                    );
+
+                    stopped = finish_stop_request(&room).await;
+                }
+
+                // Store Setup, ConnectionComplete and the logs for late-joining clients
+                let outgoing = {
                     let mut room_guard = room.lock().await;
                     match &server_message {
-                        StreamServerMessage::Setup { ice_servers } => {
+                        StreamServerMessage::Setup { ice_servers, .. } => {
                             room_guard.ice_servers = Some(ice_servers.clone());
                         }
+                        StreamServerMessage::DebugLog { message, ty } => {
+                            room_guard.push_debug_log(message.clone(), *ty);
+                        }
                         StreamServerMessage::ConnectionComplete {
+                            epoch,
                             capabilities,
+                            available_stats,
+                            stage_timings,
                             format,
                             width,
                             height,
@@ -903,8 +1933,11 @@ async fn handle_streamer_ipc(
                             audio_samples_per_frame,
                             audio_mapping,
                         } => {
-                            room_guard.stream_state = Some(StreamState {
+                            let current = room_guard.complete_stream(StreamState {
+                                epoch: *epoch,
                                 capabilities: capabilities.clone(),
+                                available_stats: available_stats.clone(),
+                                stage_timings: stage_timings.clone(),
                                 format: *format,
                                 width: *width,
                                 height: *height,
@@ -916,49 +1949,103 @@ async fn handle_streamer_ipc(
                                 audio_samples_per_frame: *audio_samples_per_frame,
                                 audio_mapping: *audio_mapping,
                             });
+
+                            // The clients must not configure their decoders for a superseded start
+                            if !current {
+                                debug!(
+                                    "[Stream]: dropping the stale ConnectionComplete of epoch {epoch} in room {room_id}"
+                                );
+                                continue;
+                            }
+
+                            // The stream is running, it can be restored from now on
+                            if let Some(directory) =
+                                RuntimeDirectory::from_config(&web_app.config().rooms)
+                                && let Err(err) = directory.save(&room_guard.to_state()).await
+                            {
+                                warn!("[Rooms]: failed to persist room {room_id}: {err}");
+                            }
                         }
                         _ => {}
                     }
                     // Broadcast to all clients in the room
-                    room_guard.broadcast(server_message).await;
+                    room_guard.prepare_broadcast(&server_message)
+                };
+
+                if let Some(outgoing) = outgoing {
+                    send_outgoing(&room, outgoing, &mut slow_clients).await;
+                }
+
+                // The clients see the clean stop before the room is closed
+                if stopped {
+                    let outgoing = room
+                        .lock()
+                        .await
+                        .prepare_broadcast(&StreamServerMessage::RoomClosed);
+
+                    if let Some(outgoing) = outgoing {
+                        send_outgoing(&room, outgoing, &mut slow_clients).await;
+                    }
                 }
             }
             StreamerIpcMessage::PeerWebSocket { peer_id, message } => {
                 // Send to specific peer
-                let room_guard = room.lock().await;
-                room_guard.send_to_peer(peer_id, message).await;
+                let outgoing = room.lock().await.prepare_send_to_peer(peer_id, &message);
+
+                if let Some(outgoing) = outgoing {
+                    send_outgoing(&room, outgoing, &mut slow_clients).await;
+                }
             }
             StreamerIpcMessage::WebSocketTransport(data) => {
                 // Broadcast binary to all clients
-                let room_guard = room.lock().await;
-                for client in room_guard.clients.values() {
-                    let mut session = client.session.clone();
-                    if let Err(err) = session.binary(data.clone()).await {
-                        warn!(
-                            "Failed to send binary to peer {:?}: {:?}",
-                            client.peer_id, err
-                        );
-                    }
-                }
+                let outgoing = room.lock().await.prepare_broadcast_binary(data);
+
+                send_outgoing(&room, outgoing, &mut slow_clients).await;
             }
             StreamerIpcMessage::PeerWebSocketTransport { peer_id, data } => {
                 // Send binary to specific peer
-                let room_guard = room.lock().await;
-                if let Some(client) = room_guard.clients.get(&peer_id) {
-                    let mut session = client.session.clone();
-                    if let Err(err) = session.binary(data).await {
-                        warn!(
-                            "Failed to send binary to peer {:?}: {:?}",
-                            peer_id, err
-                        );
-                    }
-                }
+                let outgoing = room.lock().await.prepare_send_binary_to_peer(peer_id, data);
+
+                send_outgoing(&room, outgoing, &mut slow_clients).await;
             }
             StreamerIpcMessage::PeerReady { peer_id } => {
                 debug!("Peer {:?} is ready", peer_id);
+
+                // Late joiners get the running stream once their transport receives it,
+                // so the decoder of the client isn't set up before the first frame can arrive
+                let outgoing = {
+                    let room_guard = room.lock().await;
+                    // While the stream restarts the client gets the new one with its broadcast
+                    room_guard
+                        .late_join_connection_complete()
+                        .and_then(|message| room_guard.prepare_send_to_peer(peer_id, &message))
+                };
+
+                if let Some(outgoing) = outgoing {
+                    send_outgoing(&room, outgoing, &mut slow_clients).await;
+                }
+            }
+            StreamerIpcMessage::ResourceReport { rss_bytes, queues } => {
+                debug!(
+                    "[Ipc]: streamer of room {room_id} uses {rss_bytes:?} bytes, queues: {queues:?}"
+                );
+
+                room.lock().await.resources = Some(StreamerResourceMetrics {
+                    rss_bytes,
+                    queues: queues
+                        .into_iter()
+                        .map(|(peer_id, video, audio, delayed_bytes)| PeerQueueMetrics {
+                            peer_id,
+                            video,
+                            audio,
+                            delayed_bytes,
+                        })
+                        .collect(),
+                });
             }
             StreamerIpcMessage::Stop => {
                 debug!("[Ipc]: ipc receiver stopped by streamer");
+                stopped = true;
                 break;
             }
         }
@@ -969,6 +2056,19 @@ async fn handle_streamer_ipc(
     // Close all client sessions
     {
         let room_guard = room.lock().await;
+
+        if !stopped {
+            let owner = room_guard.owner_name();
+            warn!("[Stream]: the streamer of room {room_id} owned by {owner} stopped unexpectedly");
+
+            room_guard
+                .broadcast(StreamServerMessage::DebugLog {
+                    message: format!("The stream of {owner} stopped unexpectedly"),
+                    ty: Some(LogMessageType::FatalDescription),
+                })
+                .await;
+        }
+
         for client in room_guard.clients.values() {
             let session = client.session.clone();
             if let Err(err) = session.close(None).await {
@@ -979,13 +2079,89 @@ async fn handle_streamer_ipc(
 
     // Delete the room
     web_app.room_manager().delete_room(&room_id).await;
+    web_app.discord_instances().remove_room(&room_id).await;
+
+    if let Some(directory) = RuntimeDirectory::from_config(&web_app.config().rooms) {
+        directory.remove(&room_id).await;
+    }
 
-    // Kill the streamer
-    if let Err(err) = child.kill().await {
+    // Kill the streamer, a streamer of a restored room stops by itself when the ipc closes
+    if let Some(mut child) = child
+        && let Err(err) = child.kill().await
+    {
         warn!("Failed to kill streamer child: {err}");
     }
 }
 
+/// Sends the message without holding the room lock and closes the sessions of clients which can't keep up,
+/// they'll leave the room once their connection ends
+async fn send_outgoing(
+    room: &Mutex<Room>,
+    outgoing: OutgoingMessage,
+    slow_clients: &mut SlowClients,
+) {
+    let results = outgoing.send().await;
+
+    let slow_peers = slow_clients.record(&results);
+    if slow_peers.is_empty() {
+        return;
+    }
+
+    let sessions = {
+        let room_guard = room.lock().await;
+        slow_peers
+            .into_iter()
+            .filter_map(|peer_id| room_guard.get_client(peer_id))
+            .map(|client| (client.peer_id, client.session.clone()))
+            .collect::<Vec<_>>()
+    };
+
+    for (peer_id, session) in sessions {
+        warn!("[Stream]: closing the session of peer {peer_id:?} because it can't keep up");
+
+        // Closing has to wait for the congested connection too
+        if timeout(CLIENT_SEND_TIMEOUT, session.close(None))
+            .await
+            .is_err()
+        {
+            warn!("[Stream]: timed out closing the session of peer {peer_id:?}");
+        }
+    }
+}
+
+/// Quits the app if the host requested it together with stopping the stream.
+/// This happens before the streamer's `ConnectionTerminated` is broadcast,
+/// returns true if the host stopped the stream so the room is closed afterwards.
+async fn finish_stop_request(room: &Mutex<Room>) -> bool {
+    let (stop_request, creator, host_id) = {
+        let mut room_guard = room.lock().await;
+        (
+            room_guard.stop_request.take(),
+            room_guard.creator.clone(),
+            HostId(room_guard.host_id),
+        )
+    };
+
+    let Some(stop_request) = stop_request else {
+        return false;
+    };
+
+    if stop_request.quit_app
+        && let Some(mut creator) = creator
+    {
+        let result = match creator.host(host_id).await {
+            Ok(mut host) => host.cancel_app(&mut creator).await.map(|_| ()),
+            Err(err) => Err(err),
+        };
+
+        if let Err(err) = result {
+            warn!("Failed to quit the app of host {host_id:?} after the stream was stopped: {err}");
+        }
+    }
+
+    true
+}
+
 async fn send_ws_message(sender: &mut Session, message: StreamServerMessage) -> Result<(), Closed> {
     let Some(json) = serialize_json(&message) else {
         return Ok(());
@@ -1004,9 +2180,533 @@ pub async fn cancel_host(
     Ok(Json(PostCancelResponse { success: true }))
 }
 
-/// Get list of active rooms (for joining)
+/// Create a signed link which lets guests join the room until it expires
+#[post("/host/invite")]
+pub async fn create_invite(
+    web_app: Data<App>,
+    mut user: AuthenticatedUser,
+    Json(request): Json<PostHostInviteRequest>,
+) -> Result<Json<PostHostInviteResponse>, AppError> {
+    let (invite, expires_at) = web_app
+        .create_invite(
+            &mut user,
+            &request.room_id,
+            request.valid_for_secs.map(Duration::from_secs),
+        )
+        .await?;
+
+    let url = format!(
+        "{}/join.html?room={}&invite={}",
+        web_app.config().web_server.url_path_prefix,
+        request.room_id,
+        invite
+    );
+
+    Ok(Json(PostHostInviteResponse {
+        url,
+        invite,
+        expires_at: expires_at
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_millis() as u64)
+            .unwrap_or_default(),
+    }))
+}
+
+/// Get list of active rooms (for joining), admins also see who created them
 #[get("/rooms")]
-pub async fn list_rooms(web_app: Data<App>) -> Json<Vec<RoomInfo>> {
-    let rooms = web_app.room_manager().list_rooms().await;
+pub async fn list_rooms(web_app: Data<App>, admin: Option<Admin>) -> Json<Vec<RoomInfo>> {
+    let rooms = web_app.room_manager().list_rooms(admin.is_some()).await;
     Json(rooms)
 }
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::SocketAddr,
+        num::NonZeroUsize,
+        sync::{
+            Mutex,
+            atomic::{AtomicU32, Ordering},
+        },
+        time::Duration,
+    };
+
+    use actix_web::{App as ActixApp, HttpServer, dev::ServerHandle, rt as actix_rt, web::Data};
+    use common::{
+        api_bindings::{
+            GuestStreamQuery, PlayerSlot, RoomJoinFailure, StreamCapabilities, StreamClientMessage,
+            StreamServerMessage,
+        },
+        config::{Config, StreamerProcessConfig},
+        ipc::{IpcReceiver, IpcSender, ServerIpcMessage, StreamerIpcMessage, create_ipc},
+    };
+    use moonlight_web_client::{Client, ClientError, StreamEvent, StreamSession};
+    use tempfile::TempDir;
+    use tokio::{
+        io::{duplex, split},
+        spawn,
+        time::{sleep, timeout},
+    };
+
+    use crate::{
+        api::{api_service, stream::AppSelector},
+        app::{
+            App,
+            host::{App as HostApp, AppId, HostId},
+        },
+        test_util::{FAKE_HOST_APP, FakeHost, test_config, test_dir},
+    };
+
+    /// The streamer path which makes the web server run [spawn_fake_streamer] instead of a process
+    pub(super) const FAKE_STREAMER_PATH: &str = "fake-streamer";
+    /// The fake streamer logs its id with this prefix
+    const FAKE_STREAMER_ID: &str = "fake streamer ";
+
+    static NEXT_FAKE_STREAMER_ID: AtomicU32 = AtomicU32::new(0);
+    /// The ids of the fake streamers which the web server told to stop
+    static STOPPED_FAKE_STREAMERS: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+    const TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Starts a streamer in this process, the web server talks to it over an in memory ipc
+    pub(super) fn spawn_fake_streamer(
+        capacity: NonZeroUsize,
+    ) -> (IpcSender<ServerIpcMessage>, IpcReceiver<StreamerIpcMessage>) {
+        let (web_server, streamer) = duplex(64 * 1024);
+
+        let (read, write) = split(web_server);
+        let (ipc_sender, ipc_receiver) = create_ipc(read, write, capacity);
+
+        let (read, write) = split(streamer);
+        let (sender, receiver) = create_ipc(read, write, capacity);
+        spawn(run_fake_streamer(sender, receiver));
+
+        (ipc_sender, ipc_receiver)
+    }
+
+    /// Answers the web server like a streamer whose stream started immediately
+    async fn run_fake_streamer(
+        mut sender: IpcSender<StreamerIpcMessage>,
+        mut receiver: IpcReceiver<ServerIpcMessage>,
+    ) {
+        let id = NEXT_FAKE_STREAMER_ID.fetch_add(1, Ordering::Relaxed);
+
+        while let Some(message) = receiver.recv().await {
+            match message {
+                ServerIpcMessage::Init { .. } => {
+                    sender
+                        .send(StreamerIpcMessage::WebSocket(
+                            StreamServerMessage::DebugLog {
+                                message: format!("{FAKE_STREAMER_ID}{id}"),
+                                ty: None,
+                            },
+                        ))
+                        .await;
+                    sender
+                        .send(StreamerIpcMessage::WebSocket(StreamServerMessage::Setup {
+                            ice_servers: Vec::new(),
+                            data_channels: Default::default(),
+                        }))
+                        .await;
+                    sender
+                        .send(StreamerIpcMessage::WebSocket(
+                            StreamServerMessage::ConnectionComplete {
+                                epoch: 1,
+                                capabilities: StreamCapabilities { touch: false },
+                                available_stats: Vec::new(),
+                                stage_timings: Vec::new(),
+                                format: 1,
+                                width: 1280,
+                                height: 720,
+                                fps: 60,
+                                audio_sample_rate: 48000,
+                                audio_channel_count: 2,
+                                audio_streams: 1,
+                                audio_coupled_streams: 1,
+                                audio_samples_per_frame: 240,
+                                audio_mapping: [0, 1, 0, 0, 0, 0, 0, 0],
+                            },
+                        ))
+                        .await;
+                }
+                ServerIpcMessage::PeerConnected { peer_id, .. } => {
+                    sender.send(StreamerIpcMessage::PeerReady { peer_id }).await;
+                }
+                ServerIpcMessage::Stop => {
+                    STOPPED_FAKE_STREAMERS.lock().expect("poisoned").push(id);
+                    sender.send(StreamerIpcMessage::Stop).await;
+                    return;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn fake_streamer_stopped(id: u32) -> bool {
+        STOPPED_FAKE_STREAMERS
+            .lock()
+            .expect("poisoned")
+            .contains(&id)
+    }
+
+    #[test]
+    fn test_app_selector() {
+        let app = |id: u32, title: &str| HostApp {
+            id: AppId(id),
+            title: title.to_string(),
+            is_hdr_supported: false,
+            hidden: None,
+        };
+        let apps = || {
+            vec![
+                app(1, "Desktop"),
+                app(2, "Steam Big Picture"),
+                app(3, "Game"),
+                app(4, "game"),
+            ]
+        };
+
+        let found = |selector: AppSelector| selector.find(apps()).map(|app| app.id);
+
+        assert_eq!(found(AppSelector::Id(AppId(2))), Ok(AppId(2)));
+        assert!(found(AppSelector::Id(AppId(5))).is_err());
+        assert_eq!(
+            found(AppSelector::Name("steam big picture".to_string())),
+            Ok(AppId(2))
+        );
+        assert_eq!(
+            found(AppSelector::Name(" DESKTOP ".to_string())),
+            Ok(AppId(1))
+        );
+        // Ambiguous names aren't resolved
+        assert!(found(AppSelector::Name("Game".to_string())).is_err());
+        assert!(found(AppSelector::Name("Unknown".to_string())).is_err());
+    }
+
+    struct TestServer {
+        app: Data<App>,
+        address: SocketAddr,
+        handle: ServerHandle,
+        host: FakeHost,
+        host_id: HostId,
+        _dir: TempDir,
+    }
+
+    impl TestServer {
+        async fn start() -> Self {
+            let dir = test_dir();
+            let config = Config {
+                streamer: StreamerProcessConfig {
+                    path: FAKE_STREAMER_PATH.to_string(),
+                    ..Default::default()
+                },
+                ..test_config(dir.path())
+            };
+            let app = Data::new(App::new(config).await.expect("failed to create app"));
+
+            app.try_add_first_login("host".to_string(), "password".to_string())
+                .await
+                .expect("failed to add user");
+
+            let host = FakeHost::start().await;
+            let host_id = app
+                .storage()
+                .add_host(host.storage_host())
+                .await
+                .expect("failed to add host")
+                .id;
+
+            let server = HttpServer::new({
+                let app = app.clone();
+
+                move || {
+                    ActixApp::new()
+                        .app_data(app.clone())
+                        .service(api_service(app.config()))
+                }
+            })
+            .workers(1)
+            .disable_signals()
+            .bind(("127.0.0.1", 0))
+            .expect("failed to bind test server");
+
+            let address = server.addrs()[0];
+            let server = server.run();
+            let handle = server.handle();
+            actix_rt::spawn(server);
+
+            Self {
+                app,
+                address,
+                handle,
+                host,
+                host_id,
+                _dir: dir,
+            }
+        }
+
+        fn client(&self) -> Client {
+            Client::new(&format!("http://{}", self.address)).expect("invalid address")
+        }
+
+        /// Logs in as the user and connects to the stream endpoint with the first message
+        async fn stream(&self, first: StreamClientMessage) -> TestClient {
+            let mut client = self.client();
+            client
+                .login("host", "password")
+                .await
+                .expect("failed to log in");
+
+            let session = client.stream(&first).await.expect("failed to connect");
+
+            TestClient { session }
+        }
+
+        /// Starts the app of the fake host
+        fn init(&self) -> StreamClientMessage {
+            StreamClientMessage::Init {
+                host_id: self.host_id.0,
+                app_id: Some(FAKE_HOST_APP.0),
+                app_name: None,
+                video_frame_queue_size: 4,
+                audio_sample_queue_size: 4,
+            }
+        }
+
+        /// Creates a room and waits until its stream is running
+        async fn host(&self) -> (TestClient, String) {
+            let mut host = self.stream(self.init()).await;
+
+            let joined = timeout(TEST_TIMEOUT, host.session.joined())
+                .await
+                .expect("timed out waiting for the room")
+                .expect("failed to create the room");
+            assert_eq!(joined.player_slot, Some(PlayerSlot::PLAYER_1));
+
+            host.recv_until(|message| {
+                matches!(message, StreamServerMessage::ConnectionComplete { .. }).then_some(())
+            })
+            .await;
+
+            (host, joined.room.room_id)
+        }
+
+        /// Joins the room as a guest, returns the slot or the reason why joining failed
+        async fn join(&self, room_id: &str) -> (TestClient, Result<PlayerSlot, RoomJoinFailure>) {
+            let mut session = self
+                .client()
+                .guest_stream(&GuestStreamQuery {
+                    room_id: room_id.to_string(),
+                    player_name: None,
+                    discord_auth: false,
+                    spectate: false,
+                    invite: None,
+                })
+                .await
+                .expect("failed to connect");
+
+            let result = match timeout(TEST_TIMEOUT, session.joined())
+                .await
+                .expect("timed out waiting for the join")
+            {
+                Ok(joined) => Ok(joined.player_slot.expect("joined as a spectator")),
+                Err(ClientError::JoinFailed(kind)) => Err(kind),
+                Err(err) => panic!("failed to join: {err}"),
+            };
+
+            (TestClient { session }, result)
+        }
+
+        async fn stop(self) {
+            self.handle.stop(false).await;
+            self.host.stop().await;
+        }
+    }
+
+    struct TestClient {
+        session: StreamSession,
+    }
+
+    impl TestClient {
+        async fn send(&mut self, message: StreamClientMessage) {
+            self.session
+                .send(&message)
+                .await
+                .expect("failed to send message");
+        }
+
+        /// Skips messages until the filter returns a value
+        async fn recv_until<T>(
+            &mut self,
+            mut filter: impl FnMut(StreamServerMessage) -> Option<T>,
+        ) -> T {
+            timeout(TEST_TIMEOUT, async {
+                loop {
+                    match self.session.next().await {
+                        Some(Ok(StreamEvent::Message(message))) => {
+                            if let Some(value) = filter(message) {
+                                return value;
+                            }
+                        }
+                        Some(Ok(StreamEvent::Binary(_))) => {}
+                        Some(Err(_)) | None => {
+                            panic!("the connection closed before the message arrived")
+                        }
+                    }
+                }
+            })
+            .await
+            .expect("timed out waiting for a message")
+        }
+
+        /// Waits until the web server closed the connection
+        async fn closed(&mut self) {
+            timeout(TEST_TIMEOUT, async {
+                while let Some(Ok(_)) = self.session.next().await {}
+            })
+            .await
+            .expect("timed out waiting for the connection to close")
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_host_creates_room() {
+        let server = TestServer::start().await;
+
+        let (mut host, room_id) = server.host().await;
+
+        assert!(server.app.room_manager().get_room(&room_id).await.is_some());
+        host.recv_until(|message| {
+            matches!(
+                message,
+                StreamServerMessage::ConnectionComplete { epoch: 1, .. }
+            )
+            .then_some(())
+        })
+        .await;
+
+        server.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn test_guest_receives_stored_setup() {
+        let server = TestServer::start().await;
+        let (_host, room_id) = server.host().await;
+
+        let (mut guest, slot) = server.join(&room_id).await;
+        assert_eq!(slot, Ok(PlayerSlot(1)));
+
+        // The streamer sent both before the guest joined
+        guest
+            .recv_until(|message| {
+                matches!(message, StreamServerMessage::Setup { .. }).then_some(())
+            })
+            .await;
+        let size = guest
+            .recv_until(|message| match message {
+                StreamServerMessage::ConnectionComplete { width, height, .. } => {
+                    Some((width, height))
+                }
+                _ => None,
+            })
+            .await;
+        assert_eq!(size, (1280, 720));
+
+        server.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn test_room_full() {
+        let server = TestServer::start().await;
+        let (_host, room_id) = server.host().await;
+
+        let mut guests = Vec::new();
+        for slot in 1..PlayerSlot::MAX_PLAYERS {
+            let (guest, result) = server.join(&room_id).await;
+            assert_eq!(result, Ok(PlayerSlot(slot as u8)));
+            guests.push(guest);
+        }
+
+        let (mut guest, result) = server.join(&room_id).await;
+        assert_eq!(result, Err(RoomJoinFailure::Full));
+        guest.closed().await;
+
+        server.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn test_host_disconnect_closes_room() {
+        let server = TestServer::start().await;
+
+        let mut host = server.stream(server.init()).await;
+        let room_id = host
+            .recv_until(|message| match message {
+                StreamServerMessage::RoomCreated { room, .. } => Some(room.room_id),
+                _ => None,
+            })
+            .await;
+        let streamer_id = host
+            .recv_until(|message| match message {
+                StreamServerMessage::DebugLog { message, .. } => message
+                    .strip_prefix(FAKE_STREAMER_ID)
+                    .map(|id| id.parse::<u32>().expect("invalid fake streamer id")),
+                _ => None,
+            })
+            .await;
+        host.recv_until(|message| {
+            matches!(message, StreamServerMessage::ConnectionComplete { .. }).then_some(())
+        })
+        .await;
+
+        let (mut guest, slot) = server.join(&room_id).await;
+        assert_eq!(slot, Ok(PlayerSlot(1)));
+
+        assert!(!fake_streamer_stopped(streamer_id));
+        host.session.close().await.expect("failed to close host");
+
+        guest
+            .recv_until(|message| matches!(message, StreamServerMessage::RoomClosed).then_some(()))
+            .await;
+        guest.closed().await;
+
+        assert!(server.app.room_manager().get_room(&room_id).await.is_none());
+        timeout(TEST_TIMEOUT, async {
+            while !fake_streamer_stopped(streamer_id) {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the streamer wasn't stopped");
+
+        server.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn test_leave_room_frees_slot() {
+        let server = TestServer::start().await;
+        let (mut host, room_id) = server.host().await;
+
+        let mut guests = Vec::new();
+        for _ in 1..PlayerSlot::MAX_PLAYERS {
+            let (guest, result) = server.join(&room_id).await;
+            assert!(result.is_ok());
+            guests.push(guest);
+        }
+
+        let mut leaving = guests.remove(0);
+        leaving.send(StreamClientMessage::LeaveRoom).await;
+
+        let slot = host
+            .recv_until(|message| match message {
+                StreamServerMessage::PlayerLeft { slot } => Some(slot),
+                _ => None,
+            })
+            .await;
+        assert_eq!(slot, PlayerSlot(1));
+
+        let (_guest, result) = server.join(&room_id).await;
+        assert_eq!(result, Ok(PlayerSlot(1)));
+
+        server.stop().await;
+    }
+}
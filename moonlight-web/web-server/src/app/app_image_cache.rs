@@ -0,0 +1,358 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Instant,
+};
+
+use common::config::AppImageCacheConfig;
+use tokio::sync::Mutex;
+
+use crate::app::{
+    host::{AppId, AppImage, HostId},
+    user::UserId,
+};
+
+type AppImageKey = (UserId, HostId, AppId);
+
+/// Counters of the [AppImageCache] since the web server started
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AppImageCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    /// The total bytes of all cached images
+    pub size: usize,
+}
+
+/// The box arts of the apps of every user and host.
+///
+/// The least recently used images are evicted once the total size exceeds the configured maximum
+/// and images expire after the configured ttl, so the host is asked again for changed box arts.
+pub(super) struct AppImageCache {
+    inner: Mutex<AppImageCacheInner>,
+}
+
+impl AppImageCache {
+    pub(super) fn new(config: AppImageCacheConfig) -> Self {
+        Self {
+            inner: Mutex::new(AppImageCacheInner::new(config)),
+        }
+    }
+
+    pub(super) async fn get(&self, key: AppImageKey) -> Option<AppImage> {
+        self.inner.lock().await.get(key, Instant::now())
+    }
+    /// Doesn't count as a use of the image
+    pub(super) async fn contains(&self, key: AppImageKey) -> bool {
+        self.inner.lock().await.contains(key, Instant::now())
+    }
+
+    pub(super) async fn insert(&self, key: AppImageKey, image: AppImage) {
+        self.inner.lock().await.insert(key, image, Instant::now());
+    }
+    /// Keeps an image which was inserted in the meantime, e.g. by a request of the user
+    pub(super) async fn insert_if_missing(&self, key: AppImageKey, image: AppImage) {
+        let mut inner = self.inner.lock().await;
+
+        let now = Instant::now();
+        if !inner.contains(key, now) {
+            inner.insert(key, image, now);
+        }
+    }
+
+    /// The hashes of all images of this user and host
+    pub(super) async fn hashes(&self, user_id: UserId, host_id: HostId) -> HashMap<AppId, String> {
+        self.inner
+            .lock()
+            .await
+            .hashes(user_id, host_id, Instant::now())
+    }
+
+    pub(super) async fn remove_host(&self, host_id: HostId) {
+        self.inner
+            .lock()
+            .await
+            .retain(|(_, entry_host_id, _)| *entry_host_id != host_id);
+    }
+    pub(super) async fn remove_user(&self, user_id: UserId) {
+        self.inner
+            .lock()
+            .await
+            .retain(|(entry_user_id, _, _)| *entry_user_id != user_id);
+    }
+
+    pub(super) async fn stats(&self) -> AppImageCacheStats {
+        self.inner.lock().await.stats()
+    }
+}
+
+struct AppImageCacheEntry {
+    image: AppImage,
+    inserted: Instant,
+    /// The key of this entry in [AppImageCacheInner::usage]
+    last_used: u64,
+}
+
+struct AppImageCacheInner {
+    config: AppImageCacheConfig,
+    entries: HashMap<AppImageKey, AppImageCacheEntry>,
+    /// The entries ordered by their last use, the least recently used first
+    usage: BTreeMap<u64, AppImageKey>,
+    next_use: u64,
+    size: usize,
+    hits: u64,
+    misses: u64,
+}
+
+impl AppImageCacheInner {
+    fn new(config: AppImageCacheConfig) -> Self {
+        Self {
+            config,
+            entries: Default::default(),
+            usage: Default::default(),
+            next_use: 0,
+            size: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    fn get(&mut self, key: AppImageKey, now: Instant) -> Option<AppImage> {
+        if !self.contains(key, now) {
+            self.remove(key);
+            self.misses += 1;
+
+            return None;
+        }
+
+        let next_use = self.next_use();
+        let entry = self.entries.get_mut(&key)?;
+
+        self.usage.remove(&entry.last_used);
+        self.usage.insert(next_use, key);
+        entry.last_used = next_use;
+
+        self.hits += 1;
+
+        Some(entry.image.clone())
+    }
+
+    fn contains(&self, key: AppImageKey, now: Instant) -> bool {
+        self.entries
+            .get(&key)
+            .is_some_and(|entry| !self.is_expired(entry, now))
+    }
+
+    fn insert(&mut self, key: AppImageKey, image: AppImage, now: Instant) {
+        self.remove(key);
+
+        let size = image.data.len();
+        if size > self.config.max_size {
+            return;
+        }
+
+        // Expired entries are removed first so they don't evict images which are still valid
+        self.retain_at(now, |_| true);
+        while self.size + size > self.config.max_size {
+            let Some((_, oldest)) = self.usage.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&oldest) {
+                self.size -= entry.image.data.len();
+            }
+        }
+
+        let last_used = self.next_use();
+        self.usage.insert(last_used, key);
+        self.entries.insert(
+            key,
+            AppImageCacheEntry {
+                image,
+                inserted: now,
+                last_used,
+            },
+        );
+        self.size += size;
+    }
+
+    fn hashes(&self, user_id: UserId, host_id: HostId, now: Instant) -> HashMap<AppId, String> {
+        self.entries
+            .iter()
+            .filter(|((entry_user_id, entry_host_id, _), entry)| {
+                *entry_user_id == user_id
+                    && *entry_host_id == host_id
+                    && !self.is_expired(entry, now)
+            })
+            .map(|((_, _, app_id), entry)| (*app_id, entry.image.hash.clone()))
+            .collect()
+    }
+
+    fn retain(&mut self, keep: impl Fn(&AppImageKey) -> bool) {
+        self.retain_at(Instant::now(), keep);
+    }
+    /// Also removes all expired entries
+    fn retain_at(&mut self, now: Instant, keep: impl Fn(&AppImageKey) -> bool) {
+        let ttl = self.config.ttl;
+
+        let mut removed_size = 0;
+        self.entries.retain(|key, entry| {
+            let retain = keep(key) && now.duration_since(entry.inserted) < ttl;
+            if !retain {
+                removed_size += entry.image.data.len();
+                self.usage.remove(&entry.last_used);
+            }
+            retain
+        });
+        self.size -= removed_size;
+    }
+
+    fn remove(&mut self, key: AppImageKey) {
+        if let Some(entry) = self.entries.remove(&key) {
+            self.usage.remove(&entry.last_used);
+            self.size -= entry.image.data.len();
+        }
+    }
+
+    fn stats(&self) -> AppImageCacheStats {
+        AppImageCacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            entries: self.entries.len(),
+            size: self.size,
+        }
+    }
+
+    fn is_expired(&self, entry: &AppImageCacheEntry, now: Instant) -> bool {
+        now.duration_since(entry.inserted) >= self.config.ttl
+    }
+
+    fn next_use(&mut self) -> u64 {
+        let next_use = self.next_use;
+        self.next_use += 1;
+        next_use
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use actix_web::web::Bytes;
+    use common::config::AppImageCacheConfig;
+
+    use crate::app::{
+        app_image_cache::{AppImageCache, AppImageCacheInner, AppImageCacheStats},
+        host::{AppId, AppImage, HostId},
+        user::UserId,
+    };
+
+    fn image(size: usize) -> AppImage {
+        AppImage::new(Bytes::from(vec![0; size]))
+    }
+
+    fn cache(max_size: usize) -> AppImageCacheInner {
+        AppImageCacheInner::new(AppImageCacheConfig {
+            max_size,
+            ttl: Duration::from_secs(60),
+        })
+    }
+
+    #[test]
+    fn test_evict_least_recently_used() {
+        let now = Instant::now();
+        let mut cache = cache(30);
+
+        let a = (UserId(1), HostId(1), AppId(1));
+        let b = (UserId(1), HostId(1), AppId(2));
+        let c = (UserId(1), HostId(1), AppId(3));
+        let d = (UserId(1), HostId(1), AppId(4));
+
+        cache.insert(a, image(10), now);
+        cache.insert(b, image(10), now);
+        cache.insert(c, image(10), now);
+
+        // a is now used more recently than b
+        assert!(cache.get(a, now).is_some());
+
+        cache.insert(d, image(10), now);
+        assert!(cache.contains(a, now));
+        assert!(!cache.contains(b, now));
+        assert!(cache.contains(c, now));
+        assert!(cache.contains(d, now));
+
+        // Evicts c and d
+        cache.insert(b, image(20), now);
+        assert!(cache.contains(a, now));
+        assert!(cache.contains(b, now));
+        assert!(!cache.contains(c, now));
+        assert!(!cache.contains(d, now));
+
+        // Too large to be cached at all
+        cache.insert(c, image(31), now);
+        assert!(!cache.contains(c, now));
+        assert!(cache.contains(a, now));
+
+        assert_eq!(cache.size, 30);
+        assert_eq!(cache.usage.len(), cache.entries.len());
+    }
+
+    #[test]
+    fn test_expire_entries() {
+        let now = Instant::now();
+        let mut cache = cache(30);
+
+        let a = (UserId(1), HostId(1), AppId(1));
+        let b = (UserId(1), HostId(1), AppId(2));
+
+        cache.insert(a, image(10), now);
+        cache.insert(b, image(10), now + Duration::from_secs(30));
+
+        let later = now + Duration::from_secs(60);
+        assert!(cache.get(a, later).is_none());
+        assert!(cache.get(b, later).is_some());
+        assert_eq!(cache.hashes(UserId(1), HostId(1), later).len(), 1);
+
+        assert_eq!(
+            cache.stats(),
+            AppImageCacheStats {
+                hits: 1,
+                misses: 1,
+                entries: 1,
+                size: 10,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_remove_user_and_host() {
+        let cache = AppImageCache::new(AppImageCacheConfig {
+            max_size: 100,
+            ttl: Duration::from_secs(60),
+        });
+
+        cache
+            .insert((UserId(1), HostId(1), AppId(1)), image(10))
+            .await;
+        cache
+            .insert((UserId(1), HostId(2), AppId(1)), image(10))
+            .await;
+        cache
+            .insert((UserId(2), HostId(1), AppId(1)), image(10))
+            .await;
+        cache
+            .insert((UserId(2), HostId(2), AppId(1)), image(10))
+            .await;
+
+        cache.remove_user(UserId(1)).await;
+        assert!(cache.hashes(UserId(1), HostId(1)).await.is_empty());
+        assert!(cache.hashes(UserId(1), HostId(2)).await.is_empty());
+        assert_eq!(cache.hashes(UserId(2), HostId(1)).await.len(), 1);
+
+        cache.remove_host(HostId(2)).await;
+        assert_eq!(cache.hashes(UserId(2), HostId(1)).await.len(), 1);
+        assert!(cache.hashes(UserId(2), HostId(2)).await.is_empty());
+
+        let stats = cache.stats().await;
+        assert_eq!(stats.entries, 1);
+        assert_eq!(stats.size, 10);
+    }
+}
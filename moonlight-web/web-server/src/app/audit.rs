@@ -0,0 +1,105 @@
+use std::{sync::Arc, time::SystemTime};
+
+use common::api_bindings::RemoteUnpairResult;
+use log::{info, warn};
+
+use crate::app::{AppError, host::HostId, storage::Storage, user::UserId};
+
+/// The oldest entries are dropped when the log is full
+const AUDIT_LOG_CAPACITY: usize = 1000;
+
+/// An action admins can review later
+#[derive(Debug, Clone)]
+pub struct AuditEntry {
+    pub time: SystemTime,
+    /// The admin or user who made the request
+    pub actor: UserId,
+    pub action: AuditAction,
+}
+
+#[derive(Debug, Clone)]
+pub enum AuditAction {
+    /// A request an admin made while impersonating the user
+    Impersonation {
+        user: UserId,
+        method: String,
+        path: String,
+    },
+    /// The pairing of the web server was removed on the host
+    RemoteUnpair {
+        host: HostId,
+        address: String,
+        result: RemoteUnpairResult,
+    },
+    /// A user created a room and started a stream in it
+    StreamStarted {
+        room: String,
+        host: HostId,
+        app_id: u32,
+        app_name: String,
+    },
+    /// An admin exported the users with their password hashes and the pair info of their hosts
+    UsersExported { include_hosts: bool },
+}
+
+/// Persists the most recent audited actions so admins can review them, even after a restart
+pub struct AuditLog {
+    storage: Arc<dyn Storage + Send + Sync>,
+}
+
+impl AuditLog {
+    pub fn new(storage: Arc<dyn Storage + Send + Sync>) -> Self {
+        Self { storage }
+    }
+
+    pub async fn record(&self, entry: AuditEntry) {
+        match &entry.action {
+            AuditAction::Impersonation { user, method, path } => {
+                info!(
+                    "[Audit]: admin {:?} impersonated user {:?} for {method} {path}",
+                    entry.actor, user
+                );
+            }
+            AuditAction::RemoteUnpair {
+                host,
+                address,
+                result,
+            } => {
+                info!(
+                    "[Audit]: user {:?} unpaired host {host:?} at {address}: {result:?}",
+                    entry.actor
+                );
+            }
+            AuditAction::StreamStarted {
+                room,
+                host,
+                app_id,
+                app_name,
+            } => {
+                info!(
+                    "[Audit]: user {:?} started {app_name} ({app_id}) of host {host:?} in room {room}",
+                    entry.actor
+                );
+            }
+            AuditAction::UsersExported { include_hosts } => {
+                info!(
+                    "[Audit]: admin {:?} exported the users with their secrets (hosts: {include_hosts})",
+                    entry.actor
+                );
+            }
+        }
+
+        if let Err(err) = self
+            .storage
+            .add_audit_entry(entry, AUDIT_LOG_CAPACITY)
+            .await
+        {
+            warn!("[Audit]: failed to store audit entry: {err}");
+        }
+    }
+
+    /// All entries, oldest first
+    pub async fn entries(&self) -> Result<Vec<AuditEntry>, AppError> {
+        self.storage.list_audit_entries().await
+    }
+}
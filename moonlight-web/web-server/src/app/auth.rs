@@ -1,5 +1,9 @@
-use std::fmt;
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
 
+use common::config::WebServerConfig;
 use hex::FromHexError;
 use openssl::rand::rand_bytes;
 use serde::{
@@ -16,6 +20,51 @@ pub enum UserAuth {
     ForwardedHeaders { username: String },
 }
 
+/// When a session expires, the storage keeps it with the session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionExpiration {
+    /// How long the session lasts after the login, or after the last request if it's sliding
+    pub expiration: Duration,
+    pub sliding: bool,
+    /// Only used if the expiration is sliding
+    pub max_lifetime: Duration,
+    pub idle_timeout: Option<Duration>,
+}
+
+impl SessionExpiration {
+    pub fn new(config: &WebServerConfig) -> Self {
+        Self {
+            expiration: config.session_cookie_expiration,
+            sliding: config.session_sliding_expiration,
+            max_lifetime: config.session_max_lifetime,
+            idle_timeout: config.session_idle_timeout,
+        }
+    }
+
+    pub fn expires_at(&self, created_at: Instant, last_used: Instant) -> Instant {
+        let mut expires_at = if self.sliding {
+            (last_used + self.expiration).min(created_at + self.max_lifetime)
+        } else {
+            created_at + self.expiration
+        };
+
+        if let Some(idle_timeout) = self.idle_timeout {
+            expires_at = expires_at.min(last_used + idle_timeout);
+        }
+
+        expires_at
+    }
+
+    /// The cookie must outlive the session, the server decides when the session is no longer valid
+    pub fn cookie_lifetime(&self) -> Duration {
+        if self.sliding {
+            self.max_lifetime
+        } else {
+            self.expiration
+        }
+    }
+}
+
 const SESSION_TOKEN_SIZE: usize = 32;
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct SessionToken([u8; SESSION_TOKEN_SIZE]);
@@ -77,3 +126,49 @@ impl<'de> Deserialize<'de> for SessionToken {
         deserializer.deserialize_str(SessionTokenVisitor)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use crate::app::auth::SessionExpiration;
+
+    fn expiration(sliding: bool, idle_timeout: Option<Duration>) -> SessionExpiration {
+        SessionExpiration {
+            expiration: Duration::from_secs(60),
+            sliding,
+            max_lifetime: Duration::from_secs(300),
+            idle_timeout,
+        }
+    }
+
+    #[test]
+    fn test_session_expiration() {
+        let created_at = Instant::now();
+        let last_used = created_at + Duration::from_secs(50);
+
+        // Fixed sessions ignore when they were used
+        assert_eq!(
+            expiration(false, None).expires_at(created_at, last_used),
+            created_at + Duration::from_secs(60)
+        );
+
+        // Sliding sessions are extended by using them, up to the max lifetime
+        let sliding = expiration(true, None);
+        assert_eq!(
+            sliding.expires_at(created_at, last_used),
+            last_used + Duration::from_secs(60)
+        );
+        assert_eq!(
+            sliding.expires_at(created_at, created_at + Duration::from_secs(280)),
+            created_at + Duration::from_secs(300)
+        );
+        assert_eq!(sliding.cookie_lifetime(), Duration::from_secs(300));
+
+        // The idle timeout ends a session early if it isn't used
+        assert_eq!(
+            expiration(true, Some(Duration::from_secs(10))).expires_at(created_at, last_used),
+            last_used + Duration::from_secs(10)
+        );
+    }
+}
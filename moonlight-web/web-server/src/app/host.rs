@@ -1,26 +1,36 @@
 use std::{
+    collections::HashMap,
     fmt::{Debug, Formatter},
     str::FromStr,
+    time::{Duration, Instant},
 };
 
 use actix_web::web::Bytes;
-use common::api_bindings::{self, DetailedHost, HostOwner, HostState, PairStatus, UndetailedHost};
-use log::warn;
+use common::api_bindings::{
+    self, DetailedHost, GetHostUptimeResponse, HostCapabilities, HostOwner, HostState, PairStatus,
+    RemoteUnpairResult, UndetailedHost, WakeUpStatus,
+};
+use futures::{StreamExt, stream};
+use log::{debug, warn};
 use moonlight_common::{
     PairPin, ServerState,
-    high::broadcast_magic_packet,
+    high::{broadcast_magic_packet, send_magic_packet},
     network::{
         self, ApiError, ClientAppBoxArtRequest, ClientInfo, HostInfo, host_app_box_art,
         host_app_list, host_cancel, host_info,
         request_client::{RequestClient, RequestError},
     },
-    pair::{PairSuccess, generate_new_client, host_pair},
+    pair::{PairStage, PairSuccess, generate_new_client, host_pair},
 };
+use openssl::sha::sha256;
+use tokio::{spawn, time::timeout};
 use uuid::Uuid;
 
 use crate::app::{
     AppError, AppInner, AppRef, MoonlightClient,
+    host_address::{check_host_address, url_host},
     storage::{StorageHost, StorageHostModify, StorageHostPairInfo},
+    unpair::RemoteUnpair,
     user::{AuthenticatedUser, Role, UserId},
 };
 
@@ -43,11 +53,63 @@ impl Debug for Host {
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct AppId(pub u32);
 
+/// How many box arts are fetched at the same time by [Host::prefetch_app_images]
+const APP_IMAGE_PREFETCH_CONCURRENCY: usize = 4;
+/// The prefetch is cancelled after this time, e.g. when the user navigated away from the app grid
+const APP_IMAGE_PREFETCH_TTL: Duration = Duration::from_secs(30);
+
+/// How long the wake up status waits for the host to respond
+const WAKE_UP_STATUS_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long a host is reported as waking after the magic packet was sent
+const WAKE_UP_DURATION: Duration = Duration::from_secs(120);
+
+#[derive(Clone)]
+pub(super) struct AppImage {
+    pub(super) data: Bytes,
+    /// Hex encoded sha256 of the data
+    pub(super) hash: String,
+}
+
+impl AppImage {
+    pub(super) fn new(data: Bytes) -> Self {
+        let hash = hex::encode(sha256(&data));
+
+        Self { data, hash }
+    }
+}
+
+/// The host refused our paired client certificate.
+/// This happens when the device was removed from sunshine or sunshine regenerated its certificate.
+fn is_certificate_rejection<E: RequestError>(err: &ApiError<E>) -> bool {
+    match err {
+        ApiError::InvalidXmlStatusCode {
+            message: Some(message),
+        } => message.contains("Certificate"),
+        ApiError::RequestClient(err) => err.is_encryption(),
+        _ => false,
+    }
+}
+
+/// A rejected pairing overrides the status reported by the host, None if the host is offline
+fn pair_status(host: &StorageHost, reported: Option<PairStatus>) -> PairStatus {
+    if host.pair_info.is_some() && host.pair_invalid {
+        return PairStatus::PairedButRejected;
+    }
+
+    match reported {
+        Some(status) => status,
+        None if host.pair_info.is_some() => PairStatus::Paired,
+        None => PairStatus::NotPaired,
+    }
+}
+
 #[derive(Clone)]
 pub struct App {
     pub id: AppId,
     pub title: String,
     pub is_hdr_supported: bool,
+    /// If the app is hidden from other users, None if the user can't manage the host
+    pub hidden: Option<bool>,
 }
 
 impl From<network::App> for App {
@@ -56,6 +118,7 @@ impl From<network::App> for App {
             id: AppId(value.id),
             title: value.title,
             is_hdr_supported: value.is_hdr_supported,
+            hidden: None,
         }
     }
 }
@@ -65,6 +128,7 @@ impl From<App> for api_bindings::App {
             app_id: value.id.0,
             title: value.title,
             is_hdr_supported: value.is_hdr_supported,
+            hidden: value.hidden,
         }
     }
 }
@@ -76,8 +140,13 @@ impl Host {
     }
 
     async fn can_use(&self, user: &mut AuthenticatedUser) -> Result<(), AppError> {
-        let owner = self.owner().await?;
-        if owner.is_none() || owner == Some(user.id()) || matches!(user.role().await?, Role::Admin)
+        let app = self.app.access()?;
+
+        let host = self.storage_host(&app).await?;
+        if host.owner.is_none()
+            || host.owner == Some(user.id())
+            || host.shared_with.contains(&user.id())
+            || matches!(user.role().await?, Role::Admin)
         {
             Ok(())
         } else {
@@ -85,16 +154,34 @@ impl Host {
         }
     }
 
+    /// The owner and admins can manage the host, e.g. hide apps from other users
+    async fn can_manage(&self, user: &mut AuthenticatedUser) -> Result<bool, AppError> {
+        let owner = self.owner().await?;
+
+        Ok(owner == Some(user.id()) || matches!(user.role().await?, Role::Admin))
+    }
+
+    /// Users the host is shared with can use it, but only change it if it's global
+    async fn can_modify(&self, user: &mut AuthenticatedUser) -> Result<(), AppError> {
+        if self.owner().await?.is_none() || self.can_manage(user).await? {
+            Ok(())
+        } else {
+            Err(AppError::Forbidden)
+        }
+    }
+
     pub async fn modify(
         &mut self,
         user: &mut AuthenticatedUser,
         modify: StorageHostModify,
     ) -> Result<(), AppError> {
-        self.can_use(user).await?;
+        self.can_modify(user).await?;
 
         let app = self.app.access()?;
 
         self.cache_storage = None;
+        // The overrides change how the host info is requested
+        self.cache_host_info = None;
 
         app.storage.modify_host(self.id, modify).await?;
 
@@ -116,6 +203,7 @@ impl Host {
         Ok(match this.owner {
             None => HostOwner::Global,
             Some(user_id) if user.id() == user_id => HostOwner::ThisUser,
+            Some(_) if this.shared_with.contains(&user.id()) => HostOwner::Shared,
             _ => unreachable!(),
         })
     }
@@ -130,17 +218,16 @@ impl Host {
 
         let storage = self.storage_host(&app).await?;
         let owner = self.owner_info(user, &storage).await?;
+        let flapping = app.host_flapping(self.id).await?;
+        let paired = pair_status(&storage, None);
 
         Ok(UndetailedHost {
             host_id: storage.id.0,
-            name: storage.cache.name,
+            name: storage.display_name.unwrap_or(storage.cache.name),
             owner,
-            paired: if storage.pair_info.is_some() {
-                PairStatus::Paired
-            } else {
-                PairStatus::NotPaired
-            },
+            paired,
             server_state: None,
+            flapping,
         })
     }
 
@@ -155,12 +242,20 @@ impl Host {
         let user_unique_id = user.host_unique_id().await?;
         let host_data = self.storage_host(app).await?;
 
+        let address = if app.config.moonlight.block_private_host_ranges {
+            check_host_address(Self::connect_address(&host_data), host_data.http_port)
+                .await?
+                .to_string()
+        } else {
+            Self::connect_address(&host_data).to_string()
+        };
+
         let (mut client, https_capable) = if pairing {
             (
                 MoonlightClient::with_defaults_long_timeout().map_err(ApiError::RequestClient)?,
                 false,
             )
-        } else if let Some(pair_info) = host_data.pair_info {
+        } else if let Some(pair_info) = &host_data.pair_info {
             (
                 MoonlightClient::with_certificates(
                     &pair_info.client_private_key,
@@ -186,14 +281,18 @@ impl Host {
             self,
             https_capable,
             &mut client,
-            &host_data.address,
+            &address,
             host_data.http_port,
             info,
         )
         .await)
     }
-    fn build_hostport(host: &str, port: u16) -> String {
-        format!("{host}:{port}")
+    pub(super) fn build_hostport(host: &str, port: u16) -> String {
+        format!("{}:{port}", url_host(host))
+    }
+    /// The address used to connect to the host, the external address overrides the stored one
+    pub(super) fn connect_address(host: &StorageHost) -> &str {
+        host.external_address.as_deref().unwrap_or(&host.address)
     }
 
     async fn storage_host(&self, app: &AppInner) -> Result<StorageHost, AppError> {
@@ -204,17 +303,50 @@ impl Host {
         app.storage.get_host(self.id).await
     }
 
+    /// Remembers if the host rejects the stored pair info, so the user is asked to pair again
+    async fn set_pair_invalid(
+        &mut self,
+        app: &AppInner,
+        pair_invalid: bool,
+    ) -> Result<(), AppError> {
+        if self.storage_host(app).await?.pair_invalid == pair_invalid {
+            return Ok(());
+        }
+
+        self.cache_storage = None;
+
+        app.storage
+            .modify_host(
+                self.id,
+                StorageHostModify {
+                    pair_invalid: Some(pair_invalid),
+                    ..Default::default()
+                },
+            )
+            .await
+    }
+
+    /// The address, http port and https port override used to connect to the host
     pub async fn address_port(
         &self,
         user: &mut AuthenticatedUser,
-    ) -> Result<(String, u16), AppError> {
+    ) -> Result<(String, u16, Option<u16>), AppError> {
         self.can_use(user).await?;
 
         let app = self.app.access()?;
 
         let host = app.storage.get_host(self.id).await?;
 
-        Ok((host.address, host.http_port))
+        // The streamer connects to this address, it brackets IPv6 addresses itself when building urls
+        let address = if app.config.moonlight.block_private_host_ranges {
+            check_host_address(Self::connect_address(&host), host.http_port)
+                .await?
+                .to_string()
+        } else {
+            Self::connect_address(&host).to_string()
+        };
+
+        Ok((address, host.http_port, host.https_port_override))
     }
 
     pub async fn pair_info(
@@ -230,6 +362,19 @@ impl Host {
         host.pair_info.ok_or(AppError::HostNotPaired)
     }
 
+    /// The reachability samples of the last hours and how much of that time the host was online
+    pub async fn uptime(
+        &self,
+        user: &mut AuthenticatedUser,
+        hours: u32,
+    ) -> Result<GetHostUptimeResponse, AppError> {
+        self.can_use(user).await?;
+
+        let app = self.app.access()?;
+
+        app.host_uptime(self.id, hours).await
+    }
+
     fn is_offline<T>(
         &self,
         result: Result<T, ApiError<<MoonlightClient as RequestClient>::Error>>,
@@ -254,12 +399,15 @@ impl Host {
             return Ok(Some(cache.clone()));
         }
 
+        let https_port_override = self.storage_host(app).await?.https_port_override;
+
         self.use_client(
             app,
             user,
             false,
             async |this, https_capable, client, host, port, client_info| {
-                let mut info = match this.is_offline(
+                let start = Instant::now();
+                let result = this.is_offline(
                     host_info(
                         client,
                         false,
@@ -267,10 +415,24 @@ impl Host {
                         Some(client_info),
                     )
                     .await,
-                ) {
+                );
+                // Other errors don't tell if the host is reachable
+                match &result {
+                    Ok(Some(_)) => {
+                        app.record_host_reachability(this.id, Some(start.elapsed()))
+                            .await
+                    }
+                    Ok(None) => app.record_host_reachability(this.id, None).await,
+                    Err(_) => {}
+                }
+
+                let mut info = match result {
                     Ok(Some(value)) => value,
                     err => return err,
                 };
+                if let Some(https_port_override) = https_port_override {
+                    info.https_port = https_port_override;
+                }
 
                 if https_capable {
                     match host_info(
@@ -282,17 +444,17 @@ impl Host {
                     .await
                     {
                         Ok(new_info) => {
-                            info = new_info;
-                        }
-                        Err(ApiError::InvalidXmlStatusCode { message: Some(message) })
-                            if message.contains("Certificate") =>
-                        {
-                            // The host likely removed our paired certificate
-                            warn!("Host {this:?} has an error related to certificates. This likely happened because the device was removed from sunshine.");
+                            info = HostInfo {
+                                https_port: info.https_port,
+                                ..new_info
+                            };
+
+                            this.set_pair_invalid(app, false).await?;
                         }
-                        Err(ApiError::RequestClient(err)) if err.is_encryption()  => {
-                            // The host likely removed our paired certificate
-                            warn!("Host {this:?} has an error related to certificates. This likely happened because the device was removed from sunshine.");
+                        Err(err) if is_certificate_rejection(&err) => {
+                            warn!("Host {this:?} rejected our paired certificate. This likely happened because the device was removed from sunshine or sunshine regenerated its certificate.");
+
+                            this.set_pair_invalid(app, true).await?;
                         }
                         Err(err) => return Err(err.into()),
                     }
@@ -314,10 +476,15 @@ impl Host {
 
         let app = self.app.access()?;
 
+        // Requested first so this request is part of the flap detection
+        let result = self.host_info(&app, user).await;
+        let flapping = app.host_flapping(self.id).await?;
+
+        // Loaded after the host info because it might have rejected the pairing
         let storage = self.storage_host(&app).await?;
         let owner = self.owner_info(user, &storage).await?;
 
-        match self.host_info(&app, user).await {
+        match result {
             Ok(Some(info)) => {
                 let server_state = match ServerState::from_str(&info.state_string) {
                     Ok(state) => Some(state),
@@ -333,27 +500,23 @@ impl Host {
 
                 Ok(UndetailedHost {
                     host_id: self.id.0,
-                    name: info.host_name,
+                    paired: pair_status(&storage, Some(info.pair_status.into())),
+                    name: storage.display_name.unwrap_or(info.host_name),
                     owner,
-                    paired: info.pair_status.into(),
                     server_state: server_state.map(HostState::from),
+                    flapping,
                 })
             }
             Ok(None) => {
-                let host = self.storage_host(&app).await?;
-
-                let paired = if host.pair_info.is_some() {
-                    PairStatus::Paired
-                } else {
-                    PairStatus::NotPaired
-                };
+                let paired = pair_status(&storage, None);
 
                 Ok(UndetailedHost {
                     host_id: self.id.0,
-                    name: host.cache.name,
+                    name: storage.display_name.unwrap_or(storage.cache.name),
                     owner,
                     paired,
                     server_state: None,
+                    flapping,
                 })
             }
             Err(err) => Err(err),
@@ -367,12 +530,20 @@ impl Host {
 
         let app = self.app.access()?;
 
+        let result = self.host_info(&app, user).await;
+
+        // Loaded after the host info because it might have rejected the pairing
         let storage = self.storage_host(&app).await?;
+        let address = Self::connect_address(&storage).to_string();
 
         let owner = self.owner_info(user, &storage).await?;
 
-        match self.host_info(&app, user).await {
+        match result {
             Ok(Some(info)) => {
+                let current_game_name = self
+                    .current_game_name(&app, user, &info, &storage.hidden_app_ids)
+                    .await;
+
                 let server_state = match ServerState::from_str(&info.state_string) {
                     Ok(state) => Some(state),
                     Err(err) => {
@@ -388,48 +559,66 @@ impl Host {
                 Ok(DetailedHost {
                     host_id: self.id.0,
                     owner,
-                    name: info.host_name,
-                    paired: info.pair_status.into(),
+                    paired: pair_status(&storage, Some(info.pair_status.into())),
+                    name: storage
+                        .display_name
+                        .unwrap_or_else(|| info.host_name.clone()),
                     server_state: server_state.map(HostState::from),
-                    address: storage.address,
+                    host_name: info.host_name,
+                    address,
                     http_port: storage.http_port,
                     https_port: info.https_port,
                     external_port: info.external_port,
+                    https_port_override: storage.https_port_override,
+                    external_address: storage.external_address,
                     version: info.app_version.to_string(),
                     gfe_version: info.gfe_version,
                     unique_id: info.unique_id.to_string(),
                     mac: info.mac.map(|mac| mac.to_string()),
                     local_ip: info.local_ip,
                     current_game: info.current_game,
+                    current_game_name,
                     max_luma_pixels_hevc: info.max_luma_pixels_hevc,
                     server_codec_mode_support: info.server_codec_mode_support,
+                    capabilities: Some(HostCapabilities::new(
+                        info.server_codec_mode_support,
+                        // Only Nvidia's host software uses this codename in the state
+                        info.state_string.contains("Mjolnir"),
+                    )),
+                    reachability_probe: storage.reachability_probe,
+                    display_modes: info.display_modes.into_iter().map(Into::into).collect(),
                 })
             }
             Ok(None) => {
-                let paired = if storage.pair_info.is_some() {
-                    PairStatus::Paired
-                } else {
-                    PairStatus::NotPaired
-                };
+                let paired = pair_status(&storage, None);
 
                 Ok(DetailedHost {
                     host_id: self.id.0,
                     owner,
-                    name: storage.cache.name,
+                    name: storage
+                        .display_name
+                        .unwrap_or_else(|| storage.cache.name.clone()),
                     paired,
                     server_state: None,
-                    address: storage.address,
+                    host_name: storage.cache.name,
+                    address,
                     http_port: storage.http_port,
-                    https_port: 0,
+                    https_port: storage.https_port_override.unwrap_or(0),
                     external_port: 0,
+                    https_port_override: storage.https_port_override,
+                    external_address: storage.external_address,
                     version: "Offline".to_string(),
                     gfe_version: "Offline".to_string(),
                     unique_id: "Offline".to_string(),
                     mac: storage.cache.mac.map(|mac| mac.to_string()),
                     local_ip: "Offline".to_string(),
                     current_game: 0,
+                    current_game_name: None,
                     max_luma_pixels_hevc: 0,
                     server_codec_mode_support: 0,
+                    capabilities: None,
+                    reachability_probe: storage.reachability_probe,
+                    display_modes: Vec::new(),
                 })
             }
             Err(err) => Err(err),
@@ -455,12 +644,16 @@ impl Host {
         &mut self,
         user: &mut AuthenticatedUser,
         pin: PairPin,
+        on_stage: impl FnMut(PairStage),
     ) -> Result<(), AppError> {
         self.can_use(user).await?;
 
         let user_id = user.id();
+        let pair_device_name = user.pair_device_name().await?;
         let app = self.app.access()?;
 
+        let _pairing = app.lock_pairing(self.id)?;
+
         let info = self
             .host_info(&app, user)
             .await?
@@ -487,9 +680,10 @@ impl Host {
                         client_info,
                         &auth.private_key,
                         &auth.certificate,
-                        &app.config.moonlight.pair_device_name,
+                        &pair_device_name,
                         info.app_version,
                         pin,
+                        on_stage,
                     )
                     .await?;
 
@@ -520,6 +714,7 @@ impl Host {
                             client_certificate: auth.certificate,
                             server_certificate,
                         })),
+                        pair_invalid: Some(false),
                         cache_name: name,
                         cache_mac: mac,
                         ..Default::default()
@@ -531,11 +726,58 @@ impl Host {
         self.modify(user, modify).await
     }
 
-    #[allow(dead_code)]
-    pub async fn unpair(&self, user: &mut AuthenticatedUser) -> Result<Host, AppError> {
-        self.can_use(user).await?;
+    /// Removes the pairing on the host and in the storage, only the owner and admins can do this.
+    /// If the host isn't paired anymore, this retries an unpair which is still pending because the host was offline.
+    pub async fn unpair(
+        &mut self,
+        user: &mut AuthenticatedUser,
+    ) -> Result<RemoteUnpairResult, AppError> {
+        if !self.can_manage(user).await? {
+            return Err(AppError::Forbidden);
+        }
+
+        let app = self.app.access()?;
 
-        todo!()
+        let host = self.storage_host(&app).await?;
+        if host.pair_info.is_none() {
+            return match app.retry_pending_unpair(user, self.id).await {
+                Err(AppError::HostNotFound) => Err(AppError::HostNotPaired),
+                result => result,
+            };
+        }
+
+        let client_unique_id = Self::pairing_unique_id(&app, &host, user).await?;
+        let result = match RemoteUnpair::new(&host, client_unique_id, user.id()) {
+            Some(unpair) => app.unpair_remote(unpair).await,
+            None => return Err(AppError::HostNotPaired),
+        };
+
+        drop(app);
+        self.modify(
+            user,
+            StorageHostModify {
+                pair_info: Some(None),
+                pair_invalid: Some(false),
+                ..Default::default()
+            },
+        )
+        .await?;
+
+        Ok(result)
+    }
+
+    /// The unique id the host was most likely paired with, private hosts are paired by their owner
+    async fn pairing_unique_id(
+        app: &AppInner,
+        host: &StorageHost,
+        user: &mut AuthenticatedUser,
+    ) -> Result<String, AppError> {
+        match host.owner {
+            Some(owner) if owner != user.id() => {
+                Ok(app.storage.get_user(owner).await?.client_unique_id)
+            }
+            _ => user.host_unique_id().await,
+        }
     }
 
     pub async fn wake(&self, user: &mut AuthenticatedUser) -> Result<(), AppError> {
@@ -545,14 +787,67 @@ impl Host {
 
         let storage = self.storage_host(&app).await?;
 
-        if let Some(mac) = storage.cache.mac {
-            broadcast_magic_packet(mac).await?;
-            Ok(())
-        } else {
-            Err(AppError::HostNotFound)
+        let Some(mac) = storage.cache.mac else {
+            return Err(AppError::HostMacUnknown);
+        };
+
+        match app.config.moonlight.wake_on_lan_address {
+            Some(address) => send_magic_packet(mac, address).await?,
+            None => broadcast_magic_packet(mac).await?,
         }
+
+        app.wake_ups.lock().await.insert(self.id, Instant::now());
+
+        Ok(())
     }
 
+    pub async fn wake_status(
+        &mut self,
+        user: &mut AuthenticatedUser,
+    ) -> Result<WakeUpStatus, AppError> {
+        self.can_use(user).await?;
+
+        let app = self.app.access()?;
+
+        let online = match timeout(WAKE_UP_STATUS_TIMEOUT, self.host_info(&app, user)).await {
+            Ok(Ok(info)) => info.is_some(),
+            Ok(Err(err)) => {
+                debug!("Failed to get wake up status of host {self:?}: {err}");
+                false
+            }
+            Err(_) => false,
+        };
+
+        let mut wake_ups = app.wake_ups.lock().await;
+        if online {
+            wake_ups.remove(&self.id);
+
+            return Ok(WakeUpStatus::Online);
+        }
+
+        match wake_ups.get(&self.id) {
+            Some(sent) if sent.elapsed() < WAKE_UP_DURATION => Ok(WakeUpStatus::Waking),
+            Some(_) => {
+                wake_ups.remove(&self.id);
+
+                Ok(WakeUpStatus::Offline)
+            }
+            None => Ok(WakeUpStatus::Offline),
+        }
+    }
+
+    /// Requests the host info, false if the host doesn't respond.
+    /// The info is cached, so listing the apps of this host afterwards doesn't request it again
+    pub async fn is_online(&mut self, user: &mut AuthenticatedUser) -> Result<bool, AppError> {
+        self.can_use(user).await?;
+
+        let app = self.app.access()?;
+
+        Ok(self.host_info(&app, user).await?.is_some())
+    }
+
+    /// Apps hidden by the owner are left out for other users, the owner and admins see them marked as hidden.
+    /// Starting a stream also uses this list, so hidden apps can't be started by other users.
     pub async fn list_apps(&mut self, user: &mut AuthenticatedUser) -> Result<Vec<App>, AppError> {
         self.can_use(user).await?;
 
@@ -563,28 +858,195 @@ impl Host {
             .await?
             .ok_or(AppError::HostOffline)?;
 
-        self.use_client(
-            &app,
-            user,
-            false,
-            async |_this, https_capable, client, host, _port, client_info| {
-                if !https_capable {
-                    return Err(AppError::HostNotPaired);
-                }
+        if self.storage_host(&app).await?.pair_invalid {
+            return Err(AppError::HostPairRejected);
+        }
 
-                let apps = host_app_list(
-                    client,
-                    &Self::build_hostport(host, info.https_port),
-                    client_info,
-                )
-                .await?;
+        let apps = self.fetch_apps(&app, user, &info).await?;
+
+        self.apply_app_visibility(&app, user, apps).await
+    }
+
+    /// Fetches the app list of the host and remembers the titles for [Self::current_game_name]
+    async fn fetch_apps(
+        &mut self,
+        app: &AppInner,
+        user: &mut AuthenticatedUser,
+        info: &HostInfo,
+    ) -> Result<Vec<App>, AppError> {
+        let apps = self
+            .use_client(
+                app,
+                user,
+                false,
+                async |_this, https_capable, client, host, _port, client_info| {
+                    if !https_capable {
+                        return Err(AppError::HostNotPaired);
+                    }
 
-                let apps = apps.apps.into_iter().map(App::from).collect::<Vec<_>>();
+                    let apps = host_app_list(
+                        client,
+                        &Self::build_hostport(host, info.https_port),
+                        client_info,
+                    )
+                    .await?;
+
+                    let apps = apps.apps.into_iter().map(App::from).collect::<Vec<_>>();
+
+                    Ok(apps)
+                },
+            )
+            .await??;
+
+        let titles = apps.iter().map(|app| (app.id, app.title.clone())).collect();
+        app.app_titles.lock().await.insert(self.id, titles);
 
-                Ok(apps)
+        Ok(apps)
+    }
+
+    /// The title of the running app, the app list is only fetched if the app isn't known yet.
+    /// Apps hidden by the owner aren't named for other users.
+    async fn current_game_name(
+        &mut self,
+        app: &AppInner,
+        user: &mut AuthenticatedUser,
+        info: &HostInfo,
+        hidden_app_ids: &[AppId],
+    ) -> Option<String> {
+        if info.current_game == 0 {
+            return None;
+        }
+        let app_id = AppId(info.current_game);
+
+        let cached = app
+            .app_titles
+            .lock()
+            .await
+            .get(&self.id)
+            .and_then(|titles| titles.get(&app_id).cloned());
+
+        let title = match cached {
+            Some(title) => title,
+            None => match self.fetch_apps(app, user, info).await {
+                Ok(apps) => apps.into_iter().find(|app| app.id == app_id)?.title,
+                Err(err) => {
+                    debug!(
+                        "failed to fetch the apps of host {self:?} to name the current game: {err}"
+                    );
+                    return None;
+                }
             },
-        )
-        .await?
+        };
+
+        if hidden_app_ids.contains(&app_id) && !self.can_manage(user).await.unwrap_or(false) {
+            return None;
+        }
+
+        Some(title)
+    }
+
+    async fn apply_app_visibility(
+        &self,
+        app: &AppInner,
+        user: &mut AuthenticatedUser,
+        mut apps: Vec<App>,
+    ) -> Result<Vec<App>, AppError> {
+        let hidden_app_ids = self.storage_host(app).await?.hidden_app_ids;
+
+        if self.can_manage(user).await? {
+            for app in &mut apps {
+                app.hidden = Some(hidden_app_ids.contains(&app.id));
+            }
+        } else {
+            apps.retain(|app| !hidden_app_ids.contains(&app.id));
+        }
+
+        Ok(apps)
+    }
+
+    /// Replaces the apps which are hidden from other users, this also works while the host is offline
+    pub async fn set_hidden_apps(
+        &mut self,
+        user: &mut AuthenticatedUser,
+        hidden_app_ids: Vec<AppId>,
+    ) -> Result<(), AppError> {
+        if !self.can_manage(user).await? {
+            return Err(AppError::Forbidden);
+        }
+
+        let app = self.app.access()?;
+
+        self.cache_storage = None;
+
+        app.storage
+            .modify_host(
+                self.id,
+                StorageHostModify {
+                    hidden_app_ids: Some(hidden_app_ids),
+                    ..Default::default()
+                },
+            )
+            .await
+    }
+
+    /// The names of the users this host is shared with, users which were deleted are left out
+    pub async fn shared_with(&self, user: &mut AuthenticatedUser) -> Result<Vec<String>, AppError> {
+        if !self.can_manage(user).await? {
+            return Err(AppError::Forbidden);
+        }
+
+        let app = self.app.access()?;
+
+        let mut user_names = Vec::new();
+        for user_id in self.storage_host(&app).await?.shared_with {
+            match app.storage.get_user(user_id).await {
+                Ok(user) => user_names.push(user.name),
+                Err(AppError::UserNotFound) => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(user_names)
+    }
+
+    /// Replaces the users this host is shared with, the owner can't be part of this list
+    pub async fn set_shared_with(
+        &mut self,
+        user: &mut AuthenticatedUser,
+        user_names: Vec<String>,
+    ) -> Result<(), AppError> {
+        if !self.can_manage(user).await? {
+            return Err(AppError::Forbidden);
+        }
+
+        let app = self.app.access()?;
+
+        let owner = self.storage_host(&app).await?.owner;
+        if owner.is_none() {
+            // Everyone can already use a global host
+            return Err(AppError::BadRequest);
+        }
+
+        let mut shared_with = Vec::new();
+        for user_name in user_names {
+            let (user_id, _) = app.storage.get_user_by_name(user_name.trim()).await?;
+
+            if Some(user_id) != owner && !shared_with.contains(&user_id) {
+                shared_with.push(user_id);
+            }
+        }
+
+        self.cache_storage = None;
+
+        app.storage
+            .modify_host(
+                self.id,
+                StorageHostModify {
+                    shared_with: Some(shared_with),
+                    ..Default::default()
+                },
+            )
+            .await
     }
     pub async fn app_image(
         &mut self,
@@ -596,21 +1058,27 @@ impl Host {
 
         let app = self.app.access()?;
 
+        if !self.can_manage(user).await?
+            && self
+                .storage_host(&app)
+                .await?
+                .hidden_app_ids
+                .contains(&app_id)
+        {
+            return Err(AppError::AppNotFound);
+        }
+
+        // Cached images don't need the host, so the app grid doesn't ask it for its info per app
+        let cache_key = (user.id(), self.id, app_id);
+        if !force_refresh && let Some(app_image) = app.app_image_cache.get(cache_key).await {
+            return Ok(app_image.data);
+        }
+
         let info = self
             .host_info(&app, user)
             .await?
             .ok_or(AppError::HostOffline)?;
 
-        let cache_key = (user.id(), self.id, app_id);
-        if !force_refresh {
-            {
-                let app_images = app.app_image_cache.read().await;
-                if let Some(app_image) = app_images.get(&cache_key) {
-                    return Ok(app_image.clone());
-                }
-            }
-        }
-
         let app_image = self
             .use_client(
                 &app,
@@ -633,14 +1101,135 @@ impl Host {
                 },
             )
             .await??;
-        let app_image = Bytes::from_owner(app_image);
+        let app_image = AppImage::new(Bytes::from_owner(app_image));
 
-        {
-            let mut app_images = app.app_image_cache.write().await;
-            app_images.insert(cache_key, app_image.clone());
+        app.app_image_cache
+            .insert(cache_key, app_image.clone())
+            .await;
+
+        Ok(app_image.data)
+    }
+
+    /// Returns the hashes of all box arts of this host which are cached for the user.
+    pub async fn cached_app_image_hashes(
+        &self,
+        user: &mut AuthenticatedUser,
+    ) -> Result<HashMap<AppId, String>, AppError> {
+        self.can_use(user).await?;
+
+        let app = self.app.access()?;
+
+        Ok(app.app_image_cache.hashes(user.id(), self.id).await)
+    }
+
+    /// Fetches the box arts of all apps which aren't cached yet in the background.
+    ///
+    /// A previous prefetch of the same user and host is cancelled.
+    /// Nothing is fetched if the host is offline.
+    pub async fn prefetch_app_images(
+        &mut self,
+        user: &mut AuthenticatedUser,
+        apps: &[App],
+    ) -> Result<(), AppError> {
+        self.can_use(user).await?;
+
+        let app = self.app.access()?;
+
+        let user_id = user.id();
+        let host_id = self.id;
+
+        let mut missing = Vec::new();
+        for app_id in apps.iter().map(|app| app.id) {
+            if !app
+                .app_image_cache
+                .contains((user_id, host_id, app_id))
+                .await
+            {
+                missing.push(app_id);
+            }
+        }
+        if missing.is_empty() {
+            return Ok(());
         }
 
-        Ok(app_image)
+        // Uses the info cached by listing the apps
+        let Some(info) = self.host_info(&app, user).await? else {
+            return Ok(());
+        };
+
+        let Some((client, https_address, unique_id)) = self
+            .use_client(
+                &app,
+                user,
+                false,
+                async |_this, https_capable, client, host, _port, client_info| {
+                    https_capable.then(|| {
+                        (
+                            client.clone(),
+                            Self::build_hostport(host, info.https_port),
+                            client_info.unique_id.to_string(),
+                        )
+                    })
+                },
+            )
+            .await?
+        else {
+            return Ok(());
+        };
+
+        let app_ref = self.app.clone();
+        let task = spawn(async move {
+            let prefetch = stream::iter(missing).for_each_concurrent(
+                APP_IMAGE_PREFETCH_CONCURRENCY,
+                |app_id| {
+                    let mut client = client.clone();
+                    let app_ref = app_ref.clone();
+                    let https_address = &https_address;
+                    let unique_id = &unique_id;
+
+                    async move {
+                        let image = match host_app_box_art(
+                            &mut client,
+                            https_address,
+                            ClientInfo {
+                                unique_id,
+                                uuid: Uuid::new_v4(),
+                            },
+                            ClientAppBoxArtRequest { app_id: app_id.0 },
+                        )
+                        .await
+                        {
+                            Ok(image) => image,
+                            Err(err) => {
+                                debug!("[Apps]: failed to prefetch box art of app {app_id:?} from host {host_id:?}: {err}");
+                                return;
+                            }
+                        };
+
+                        let Ok(app) = app_ref.access() else {
+                            return;
+                        };
+                        app.app_image_cache
+                            .insert_if_missing(
+                                (user_id, host_id, app_id),
+                                AppImage::new(Bytes::from_owner(image)),
+                            )
+                            .await;
+                    }
+                },
+            );
+
+            if timeout(APP_IMAGE_PREFETCH_TTL, prefetch).await.is_err() {
+                debug!("[Apps]: box art prefetch for host {host_id:?} timed out");
+            }
+        });
+
+        let mut prefetches = app.app_image_prefetches.lock().await;
+        if let Some(previous) = prefetches.insert((user_id, host_id), task.abort_handle()) {
+            previous.abort();
+        }
+
+        Ok(())
     }
 
     pub async fn cancel_app(&mut self, user: &mut AuthenticatedUser) -> Result<bool, AppError> {
@@ -681,9 +1270,15 @@ impl Host {
         let host = app.storage.get_host(self.id).await?;
 
         if host.owner == Some(user.id()) || matches!(user.role().await?, Role::Admin) {
-            {
-                let mut app_images = app.app_image_cache.write().await;
-                app_images.retain(|(_, host_id, _), _| *host_id != self.id);
+            app.app_image_cache.remove_host(self.id).await;
+            app.app_titles.lock().await.remove(&self.id);
+            app.abort_app_image_prefetches(|_, host_id| host_id != self.id)
+                .await;
+
+            // Best effort, the host is deleted even if it couldn't be unpaired
+            let client_unique_id = Self::pairing_unique_id(&app, &host, user).await?;
+            if let Some(unpair) = RemoteUnpair::new(&host, client_unique_id, user.id()) {
+                app.unpair_remote(unpair).await;
             }
 
             drop(app);
@@ -696,7 +1291,278 @@ impl Host {
         let app = self.app.access()?;
 
         app.storage.remove_host(self.id).await?;
+        app.host_reachability.remove_host(self.id).await;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use common::api_bindings::PairStatus;
+    use moonlight_common::{
+        PairPin,
+        network::{
+            host_info,
+            request_client::{QueryParamsRef, RequestClient, RequestError},
+        },
+    };
+    use pem::Pem;
+
+    use crate::{
+        app::{
+            App,
+            host::{is_certificate_rejection, pair_status},
+            storage::{
+                Storage, StorageHostAdd, StorageHostCache, StorageHostModify, StorageHostPairInfo,
+                json::JsonStorage,
+            },
+        },
+        test_util::{FAKE_HOST_PIN, FakeHost, test_config, test_dir},
+    };
+
+    #[derive(Debug)]
+    struct MockError {
+        encryption: bool,
+    }
+
+    impl RequestError for MockError {
+        fn is_connect(&self) -> bool {
+            false
+        }
+        fn is_encryption(&self) -> bool {
+            self.encryption
+        }
+    }
+
+    /// Answers every https request like sunshine after it regenerated its certificate
+    struct MockClient {
+        response: Result<&'static str, MockError>,
+    }
+
+    impl MockClient {
+        fn respond(&self) -> Result<String, MockError> {
+            match &self.response {
+                Ok(text) => Ok(text.to_string()),
+                Err(err) => Err(MockError {
+                    encryption: err.encryption,
+                }),
+            }
+        }
+    }
+
+    impl RequestClient for MockClient {
+        type Error = MockError;
+
+        type Text = String;
+        type Bytes = Vec<u8>;
+
+        fn with_defaults() -> Result<Self, Self::Error> {
+            Err(MockError { encryption: false })
+        }
+        fn with_defaults_long_timeout() -> Result<Self, Self::Error> {
+            Err(MockError { encryption: false })
+        }
+        fn with_certificates(_: &Pem, _: &Pem, _: &Pem) -> Result<Self, Self::Error> {
+            Err(MockError { encryption: false })
+        }
+
+        async fn send_http_request_text_response(
+            &mut self,
+            _hostport: &str,
+            _path: &str,
+            _query_params: &QueryParamsRef<'_>,
+        ) -> Result<Self::Text, Self::Error> {
+            Err(MockError { encryption: false })
+        }
+        async fn send_https_request_text_response(
+            &mut self,
+            _hostport: &str,
+            _path: &str,
+            _query_params: &QueryParamsRef<'_>,
+        ) -> Result<Self::Text, Self::Error> {
+            self.respond()
+        }
+        async fn send_https_request_data_response(
+            &mut self,
+            _hostport: &str,
+            _path: &str,
+            _query_params: &QueryParamsRef<'_>,
+        ) -> Result<Self::Bytes, Self::Error> {
+            self.respond().map(String::into_bytes)
+        }
+    }
+
+    fn pair_info() -> StorageHostPairInfo {
+        StorageHostPairInfo {
+            client_private_key: Pem::new("PRIVATE KEY", Vec::new()),
+            client_certificate: Pem::new("CERTIFICATE", Vec::new()),
+            server_certificate: Pem::new("CERTIFICATE", Vec::new()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_certificate_rejection() {
+        let mut client = MockClient {
+            response: Ok(
+                r#"<?xml version="1.0" encoding="utf-8"?><root status_code="401" status_message="The client is not authorized. Certificate verification failed."/>"#,
+            ),
+        };
+        let Err(err) = host_info(&mut client, true, "127.0.0.1:47984", None).await else {
+            panic!("the host info was accepted");
+        };
+        assert!(is_certificate_rejection(&err));
+
+        // The tls handshake fails if our client certificate is unknown
+        let mut client = MockClient {
+            response: Err(MockError { encryption: true }),
+        };
+        let Err(err) = host_info(&mut client, true, "127.0.0.1:47984", None).await else {
+            panic!("the host info was accepted");
+        };
+        assert!(is_certificate_rejection(&err));
+
+        let mut client = MockClient {
+            response: Err(MockError { encryption: false }),
+        };
+        let Err(err) = host_info(&mut client, true, "127.0.0.1:47984", None).await else {
+            panic!("the host info was accepted");
+        };
+        assert!(!is_certificate_rejection(&err));
+
+        let dir = test_dir();
+        let storage = JsonStorage::load(
+            dir.path().join("data.json"),
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+        )
+        .await
+        .expect("failed to load storage");
+
+        let host = storage
+            .add_host(StorageHostAdd {
+                owner: None,
+                address: "127.0.0.1".to_string(),
+                http_port: 47989,
+                pair_info: Some(pair_info()),
+                cache: StorageHostCache {
+                    name: "Host".to_string(),
+                    mac: None,
+                },
+            })
+            .await
+            .expect("failed to add host");
+        assert!(matches!(pair_status(&host, None), PairStatus::Paired));
+
+        // What the host info request does when the host rejects the certificate
+        storage
+            .modify_host(
+                host.id,
+                StorageHostModify {
+                    pair_invalid: Some(true),
+                    ..Default::default()
+                },
+            )
+            .await
+            .expect("failed to modify host");
+        let host = storage.get_host(host.id).await.expect("failed to get host");
+        assert!(matches!(
+            pair_status(&host, Some(PairStatus::NotPaired)),
+            PairStatus::PairedButRejected
+        ));
+        assert!(matches!(
+            pair_status(&host, None),
+            PairStatus::PairedButRejected
+        ));
+
+        // Pairing again stores new pair info and clears the flag
+        storage
+            .modify_host(
+                host.id,
+                StorageHostModify {
+                    pair_info: Some(Some(pair_info())),
+                    pair_invalid: Some(false),
+                    ..Default::default()
+                },
+            )
+            .await
+            .expect("failed to modify host");
+        let host = storage.get_host(host.id).await.expect("failed to get host");
+        assert!(matches!(
+            pair_status(&host, Some(PairStatus::Paired)),
+            PairStatus::Paired
+        ));
+    }
+
+    #[actix_web::test]
+    async fn test_pair_invalid() {
+        let dir = test_dir();
+        let app = App::new(test_config(dir.path()))
+            .await
+            .expect("failed to create app");
+        let mut user = app
+            .try_add_first_login("admin".to_string(), "password".to_string())
+            .await
+            .expect("failed to add user");
+
+        let fake_host = FakeHost::start().await;
+        let host_id = app
+            .storage()
+            .add_host(fake_host.storage_host())
+            .await
+            .expect("failed to add host")
+            .id;
+
+        let pair_invalid = async || {
+            app.storage()
+                .get_host(host_id)
+                .await
+                .expect("failed to get host")
+                .pair_invalid
+        };
+
+        // The host info request accepts the stored pair info
+        let mut host = user.host(host_id).await.expect("failed to get host");
+        let inner = host.app.access().expect("app destroyed");
+        host.host_info(&inner, &mut user)
+            .await
+            .expect("failed to get host info")
+            .expect("host offline");
+        assert!(!pair_invalid().await);
+
+        // Sunshine regenerated its certificate
+        fake_host.set_reject_certificate(true);
+
+        let mut host = user.host(host_id).await.expect("failed to get host");
+        let info = host
+            .host_info(&inner, &mut user)
+            .await
+            .expect("failed to get host info")
+            .expect("host offline");
+        assert!(matches!(info.pair_status.into(), PairStatus::NotPaired));
+        assert!(pair_invalid().await);
+
+        // Pairing again clears the flag
+        let mut host = user.host(host_id).await.expect("failed to get host");
+        host.pair(
+            &mut user,
+            PairPin::from_array(FAKE_HOST_PIN).expect("invalid pin"),
+            |_| {},
+        )
+        .await
+        .expect("failed to pair");
+        assert!(!pair_invalid().await);
+
+        let mut host = user.host(host_id).await.expect("failed to get host");
+        host.host_info(&inner, &mut user)
+            .await
+            .expect("failed to get host info")
+            .expect("host offline");
+        assert!(!pair_invalid().await);
+
+        drop(inner);
+        fake_host.stop().await;
+    }
+}
@@ -0,0 +1,298 @@
+use std::{
+    borrow::Cow,
+    fmt::{self, Display, Formatter},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+use log::warn;
+use tokio::net::lookup_host;
+
+use crate::app::AppError;
+
+/// The longest hostname allowed by DNS
+const MAX_HOSTNAME_LENGTH: usize = 253;
+
+/// The address of a host as entered by a user, without a port.
+/// Its [Display] is the canonical form which is stored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostAddress {
+    Ipv4(Ipv4Addr),
+    Ipv6(Ipv6Addr),
+    /// A lowercase hostname without a trailing dot
+    Hostname(String),
+}
+
+impl FromStr for HostAddress {
+    type Err = AppError;
+
+    fn from_str(address: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| Err(AppError::HostAddressInvalid(reason.to_string()));
+
+        if address.is_empty() {
+            return invalid("the address is empty");
+        }
+        if address.chars().any(char::is_whitespace) {
+            return invalid("the address contains whitespace");
+        }
+        if address.contains("://") {
+            return invalid("the address must not contain a scheme");
+        }
+        if address.contains('/') {
+            return invalid("the address must not contain a path");
+        }
+
+        // Ipv6 addresses might be written in brackets like in urls
+        if let Some(bracketed) = address.strip_prefix('[') {
+            let Some(ip) = bracketed.strip_suffix(']') else {
+                return invalid("the address must not contain a port");
+            };
+            return match ip.parse() {
+                Ok(ip) => Ok(Self::Ipv6(ip)),
+                Err(_) => invalid("the address in brackets is not an ipv6 address"),
+            };
+        }
+
+        if let Ok(ip) = address.parse() {
+            return Ok(Self::Ipv4(ip));
+        }
+        if let Ok(ip) = address.parse() {
+            return Ok(Self::Ipv6(ip));
+        }
+        if address.contains(':') {
+            return invalid("the address must not contain a port");
+        }
+
+        let hostname = address.strip_suffix('.').unwrap_or(address);
+        if hostname.len() > MAX_HOSTNAME_LENGTH {
+            return invalid("the hostname is too long");
+        }
+        let valid_label = |label: &str| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .chars()
+                    .all(|char| char.is_ascii_alphanumeric() || char == '-' || char == '_')
+        };
+        if !hostname.split('.').all(valid_label) {
+            return invalid("the hostname is invalid");
+        }
+
+        Ok(Self::Hostname(hostname.to_ascii_lowercase()))
+    }
+}
+
+impl Display for HostAddress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ipv4(ip) => write!(f, "{ip}"),
+            Self::Ipv6(ip) => write!(f, "{ip}"),
+            Self::Hostname(hostname) => write!(f, "{hostname}"),
+        }
+    }
+}
+
+/// Returns the address as it's written in front of a port, ipv6 addresses are put in brackets.
+/// Stored addresses which were added before they were normalized might already be in brackets.
+pub fn url_host(address: &str) -> Cow<'_, str> {
+    match address.parse::<Ipv6Addr>() {
+        Ok(ip) => Cow::Owned(format!("[{ip}]")),
+        Err(_) => Cow::Borrowed(address),
+    }
+}
+
+/// Resolves the address of a host and rejects it if any of its ips are on this machine or in a private network.
+/// Used with `moonlight.block_private_host_ranges` so users can't make the server send requests into its own network.
+///
+/// The returned ip must be used for the connection, resolving the hostname again could return another ip (dns rebinding).
+pub async fn check_host_address(address: &str, port: u16) -> Result<IpAddr, AppError> {
+    // Ipv6 addresses might be written in brackets like in urls
+    let host = address
+        .strip_prefix('[')
+        .and_then(|address| address.strip_suffix(']'))
+        .unwrap_or(address);
+
+    let addresses = match lookup_host((host, port)).await {
+        Ok(addresses) => addresses,
+        Err(err) => {
+            warn!("[Host]: failed to resolve host address {address}: {err}");
+            return Err(AppError::HostNotFound);
+        }
+    };
+
+    let mut resolved = None;
+    for socket_address in addresses {
+        if is_private_ip(socket_address.ip()) {
+            warn!(
+                "[Host]: blocked host address {address} because it resolves to {}",
+                socket_address.ip()
+            );
+            return Err(AppError::HostAddressBlocked);
+        }
+
+        resolved.get_or_insert(socket_address.ip());
+    }
+
+    resolved.ok_or(AppError::HostNotFound)
+}
+
+/// The NAT64 prefix `64:ff9b::/96`, the last 32 bits are an ipv4 address
+const NAT64_PREFIX: [u16; 6] = [0x64, 0xff9b, 0, 0, 0, 0];
+
+/// Loopback, private, shared, link-local and unspecified addresses,
+/// including ipv4 addresses embedded into ipv6 addresses
+pub fn is_private_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_private_ipv4(ip),
+        IpAddr::V6(ip) => {
+            // Ipv4 mapped and compatible addresses
+            if let Some(ipv4) = ip.to_ipv4() {
+                return is_private_ipv4(ipv4) || is_private_ipv6(ip);
+            }
+            if ip.segments()[..6] == NAT64_PREFIX {
+                let [.., high, low] = ip.segments();
+                return is_private_ipv4(Ipv4Addr::from((u32::from(high) << 16) | u32::from(low)));
+            }
+
+            is_private_ipv6(ip)
+        }
+    }
+}
+
+fn is_private_ipv4(ip: Ipv4Addr) -> bool {
+    let [first, second, ..] = ip.octets();
+
+    // 0.0.0.0/8 is "this network", 100.64.0.0/10 is the shared address space of carrier-grade NAT
+    let this_network = first == 0;
+    let shared = first == 100 && (second & 0b1100_0000) == 64;
+
+    ip.is_loopback() || ip.is_private() || ip.is_link_local() || this_network || shared
+}
+
+fn is_private_ipv6(ip: Ipv6Addr) -> bool {
+    ip.is_loopback() || ip.is_unique_local() || ip.is_unicast_link_local() || ip.is_unspecified()
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use crate::app::{
+        AppError,
+        host_address::{HostAddress, check_host_address, is_private_ip, url_host},
+    };
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().expect("invalid ip")
+    }
+
+    fn parse(address: &str) -> Result<HostAddress, AppError> {
+        address.parse::<HostAddress>()
+    }
+
+    #[test]
+    fn test_parse_host_address() {
+        assert_eq!(
+            parse("192.168.1.10").unwrap(),
+            HostAddress::Ipv4(Ipv4Addr::new(192, 168, 1, 10))
+        );
+
+        let ipv6 = HostAddress::Ipv6("2001:db8::5".parse().unwrap());
+        assert_eq!(parse("2001:db8::5").unwrap(), ipv6);
+        assert_eq!(parse("[2001:db8::5]").unwrap(), ipv6);
+        assert_eq!(parse("2001:0db8:0:0:0:0:0:5").unwrap(), ipv6);
+        assert_eq!(ipv6.to_string(), "2001:db8::5");
+
+        let hostname = HostAddress::Hostname("gaming-pc.example.com".to_string());
+        assert_eq!(parse("gaming-pc.example.com").unwrap(), hostname);
+        assert_eq!(parse("Gaming-PC.example.com.").unwrap(), hostname);
+        assert_eq!(parse("localhost").unwrap().to_string(), "localhost");
+
+        for invalid in [
+            "",
+            "192.168.1.10:47989",
+            "[2001:db8::5]:47989",
+            "[gaming-pc]",
+            "gaming-pc:47989",
+            "gaming pc",
+            " 192.168.1.10",
+            "http://192.168.1.10",
+            "192.168.1.10/serverinfo",
+            "gaming-pc..example.com",
+            "-gaming-pc",
+            ".",
+        ] {
+            assert!(
+                matches!(parse(invalid), Err(AppError::HostAddressInvalid(_))),
+                "{invalid:?} should be invalid"
+            );
+        }
+    }
+
+    #[test]
+    fn test_url_host() {
+        assert_eq!(url_host("192.168.1.10"), "192.168.1.10");
+        assert_eq!(url_host("2001:db8::5"), "[2001:db8::5]");
+        assert_eq!(url_host("[2001:db8::5]"), "[2001:db8::5]");
+        assert_eq!(url_host("gaming-pc"), "gaming-pc");
+    }
+
+    #[test]
+    fn test_is_private_ip() {
+        for private in [
+            "127.0.0.1",
+            "10.0.0.5",
+            "172.16.3.4",
+            "192.168.1.10",
+            "169.254.169.254",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:192.168.1.10",
+            "0.1.2.3",
+            "100.64.0.1",
+            "100.127.255.254",
+            "::127.0.0.1",
+            "::10.0.0.5",
+            "64:ff9b::127.0.0.1",
+            "64:ff9b::a9fe:a9fe",
+        ] {
+            assert!(is_private_ip(ip(private)), "{private} should be private");
+        }
+
+        for public in [
+            "1.1.1.1",
+            "172.32.0.1",
+            "100.128.0.1",
+            "2606:4700::1111",
+            "::ffff:8.8.8.8",
+            "::8.8.8.8",
+            "64:ff9b::8.8.8.8",
+        ] {
+            assert!(!is_private_ip(ip(public)), "{public} should be public");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_host_address() {
+        assert!(matches!(
+            check_host_address("127.0.0.1", 47989).await,
+            Err(AppError::HostAddressBlocked)
+        ));
+        assert!(matches!(
+            check_host_address("[::1]", 47989).await,
+            Err(AppError::HostAddressBlocked)
+        ));
+        assert!(matches!(
+            check_host_address("localhost", 47989).await,
+            Err(AppError::HostAddressBlocked)
+        ));
+        assert_eq!(
+            check_host_address("8.8.8.8", 47989).await.ok(),
+            Some(ip("8.8.8.8"))
+        );
+    }
+}
@@ -0,0 +1,169 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use common::config::Config;
+use openssl::{hash::MessageDigest, memcmp, pkey::PKey, rand::rand_bytes, sign::Signer};
+
+use crate::app::{AppError, storage::Storage};
+
+pub const INVITE_SECRET_SIZE: usize = 32;
+
+/// If a guest can join after its invite was checked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuestAdmission {
+    Admitted,
+    /// The room streams into a Discord Activity, the guest has to sign in as a Discord user
+    DiscordUser,
+}
+
+/// Signs the invites of rooms, a new secret invalidates all invites signed by the old one
+#[derive(Clone)]
+pub struct InviteSecret {
+    key: Vec<u8>,
+}
+
+impl InviteSecret {
+    pub fn new(key: impl Into<Vec<u8>>) -> Self {
+        Self { key: key.into() }
+    }
+
+    pub fn generate() -> Result<[u8; INVITE_SECRET_SIZE], AppError> {
+        let mut bytes = [0; INVITE_SECRET_SIZE];
+
+        rand_bytes(&mut bytes)?;
+
+        Ok(bytes)
+    }
+
+    /// The invite is `{expires_at}.{signature}` with the seconds since the unix epoch and the hex encoded HMAC of the room and the expiration
+    pub fn sign(&self, room_id: &str, expires_at: SystemTime) -> Result<String, AppError> {
+        let expires_at = expires_at
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or_default();
+
+        let signature = self.signature(room_id, expires_at)?;
+
+        Ok(format!("{expires_at}.{}", hex::encode(signature)))
+    }
+
+    /// Checks that the invite was signed with this secret for the room and didn't expire yet
+    pub fn verify(&self, invite: &str, room_id: &str, now: SystemTime) -> Result<(), AppError> {
+        let (expires_at, signature) = invite.split_once('.').ok_or(AppError::InviteInvalid)?;
+        let expires_at = expires_at
+            .parse::<u64>()
+            .map_err(|_| AppError::InviteInvalid)?;
+        let signature = hex::decode(signature).map_err(|_| AppError::InviteInvalid)?;
+
+        let expected = self.signature(room_id, expires_at)?;
+        // memcmp::eq panics if the lengths are different
+        if signature.len() != expected.len() || !memcmp::eq(&signature, &expected) {
+            return Err(AppError::InviteInvalid);
+        }
+
+        if now >= UNIX_EPOCH + Duration::from_secs(expires_at) {
+            return Err(AppError::InviteExpired);
+        }
+
+        Ok(())
+    }
+
+    fn signature(&self, room_id: &str, expires_at: u64) -> Result<Vec<u8>, AppError> {
+        let key = PKey::hmac(&self.key)?;
+
+        let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+        signer.update(room_id.as_bytes())?;
+        signer.update(b"\n")?;
+        signer.update(expires_at.to_string().as_bytes())?;
+
+        Ok(signer.sign_to_vec()?)
+    }
+}
+
+/// The secret of the config, otherwise the stored secret which is generated on the first start
+pub async fn load_invite_secret(
+    config: &Config,
+    storage: &(dyn Storage + Send + Sync),
+) -> Result<InviteSecret, AppError> {
+    if let Some(secret) = &config.web_server.invite_secret {
+        return Ok(InviteSecret::new(secret.as_bytes()));
+    }
+
+    let secret = match storage.get_invite_secret().await? {
+        Some(secret) => secret,
+        None => {
+            let secret = InviteSecret::generate()?;
+            storage.set_invite_secret(secret).await?;
+            secret
+        }
+    };
+
+    Ok(InviteSecret::new(secret))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use crate::app::{
+        AppError,
+        invite::{INVITE_SECRET_SIZE, InviteSecret},
+    };
+
+    #[test]
+    fn test_invite() {
+        let secret =
+            InviteSecret::new(InviteSecret::generate().expect("failed to generate invite secret"));
+        assert_ne!(
+            InviteSecret::generate().expect("failed to generate invite secret"),
+            [0; INVITE_SECRET_SIZE]
+        );
+
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let invite = secret
+            .sign("ABC123", now + Duration::from_secs(60))
+            .expect("failed to sign invite");
+
+        assert!(secret.verify(&invite, "ABC123", now).is_ok());
+        assert!(matches!(
+            secret.verify(&invite, "ABC123", now + Duration::from_secs(60)),
+            Err(AppError::InviteExpired)
+        ));
+        assert!(matches!(
+            secret.verify(&invite, "XYZ789", now),
+            Err(AppError::InviteInvalid)
+        ));
+
+        // The expiration is part of the signature
+        let (_, signature) = invite.split_once('.').expect("invalid invite");
+        assert!(matches!(
+            secret.verify(&format!("2000000.{signature}"), "ABC123", now),
+            Err(AppError::InviteInvalid)
+        ));
+
+        for malformed in ["", "1000060", "abc.def", "1000060.", "1000060.00"] {
+            assert!(matches!(
+                secret.verify(malformed, "ABC123", now),
+                Err(AppError::InviteInvalid)
+            ));
+        }
+
+        // Rotating the secret invalidates the invite
+        let rotated =
+            InviteSecret::new(InviteSecret::generate().expect("failed to generate invite secret"));
+        assert!(matches!(
+            rotated.verify(&invite, "ABC123", now),
+            Err(AppError::InviteInvalid)
+        ));
+
+        // A secret from the config works the same way
+        let configured = InviteSecret::new("configured secret");
+        let invite = configured
+            .sign("ABC123", now + Duration::from_secs(60))
+            .expect("failed to sign invite");
+        assert!(
+            InviteSecret::new("configured secret")
+                .verify(&invite, "ABC123", now)
+                .is_ok()
+        );
+    }
+}
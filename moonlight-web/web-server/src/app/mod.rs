@@ -1,44 +1,67 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     io,
     ops::Deref,
-    sync::{Arc, Weak},
+    sync::{Arc, Mutex as StdMutex, Weak},
+    time::{Duration, Instant, SystemTime},
 };
 
-use actix_web::{ResponseError, http::StatusCode, web::Bytes};
-use common::config::Config;
+use actix_web::{ResponseError, http::StatusCode};
+use common::{api_bindings::RemoteUnpairResult, config::Config};
 use hex::FromHexError;
-use log::{error, warn};
+use log::{error, info, warn};
 use moonlight_common::{
     network::{ApiError, backend::reqwest::ReqwestClient, request_client::RequestClient},
     pair::PairError,
 };
 use openssl::error::ErrorStack;
 use thiserror::Error;
-use tokio::sync::RwLock;
+use tokio::{
+    spawn,
+    sync::{Mutex, OwnedSemaphorePermit, RwLock, Semaphore},
+    task::AbortHandle,
+    time::sleep,
+};
 
 use crate::{
     api::discord::DiscordInstanceManager,
     app::{
+        app_image_cache::{AppImageCache, AppImageCacheStats},
+        audit::{AuditAction, AuditEntry, AuditLog},
         auth::{SessionToken, UserAuth},
         host::{AppId, HostId},
+        import::ImportError,
+        invite::{GuestAdmission, InviteSecret, load_invite_secret},
         password::StoragePassword,
+        reachability::{HostReachability, persist_host_reachability, probe_hosts},
         storage::{Either, Storage, StorageHostModify, StorageUserAdd, create_storage},
-        user::{Admin, AuthenticatedUser, Role, User, UserId},
+        unpair::{PendingUnpairs, retry_pending_unpairs},
+        user::{Admin, AuthenticatedUser, Impersonation, Role, User, UserId},
     },
-    room::RoomManager,
+    room::{RoomManager, SPECTATOR_IDLE_SWEEP_INTERVAL},
 };
 
+pub mod app_image_cache;
+pub mod audit;
 pub mod auth;
 pub mod host;
+pub mod host_address;
+pub mod import;
+pub mod invite;
 pub mod password;
+pub mod reachability;
+pub mod settings;
 pub mod storage;
+pub mod unpair;
 pub mod user;
+pub mod user_transfer;
 
 #[derive(Debug, Error)]
 pub enum AppError {
     #[error("the app got destroyed")]
     AppDestroyed,
+    #[error("the default user {0} doesn't exist, change default_user_id in the config")]
+    DefaultUserMisconfigured(u32),
     #[error("the user was not found")]
     UserNotFound,
     #[error("more than one user already exists")]
@@ -53,8 +76,32 @@ pub enum AppError {
     HostPaired,
     #[error("the host must be paired for this action")]
     HostNotPaired,
+    #[error("the host rejected the paired certificate, pair it again")]
+    HostPairRejected,
     #[error("the host was offline, but the action requires that the host is online")]
     HostOffline,
+    #[error("the mac address of the host is unknown, set it manually or pair the host")]
+    HostMacUnknown,
+    #[error("the app was not found")]
+    AppNotFound,
+    #[error("the room was not found")]
+    RoomNotFound,
+    #[error("the invite is invalid or was revoked")]
+    InviteInvalid,
+    #[error("the invite expired")]
+    InviteExpired,
+    #[error("an invite is required to join the room")]
+    InviteRequired,
+    #[error("the invite secret is set in the config and can only be changed there")]
+    InviteSecretConfigured,
+    #[error("the host is already being paired")]
+    PairingInProgress,
+    #[error("too many hosts are being paired at the same time")]
+    TooManyPairings,
+    #[error("the host address is on this server or in a private network, which is not allowed")]
+    HostAddressBlocked,
+    #[error("the host address is invalid: {0}")]
+    HostAddressInvalid(String),
     // -- Unauthorized
     #[error("the credentials don't exists")]
     CredentialsWrong,
@@ -80,7 +127,17 @@ pub enum AppError {
     NameEmpty,
     #[error("the authorization header is not a bearer")]
     BadRequest,
+    #[error("the setting \"{0}\" is unknown or has an invalid type")]
+    SettingsInvalid(String),
+    #[error("the pair device name is invalid: {0}")]
+    PairDeviceNameInvalid(String),
+    #[error("the imported user is invalid: {0}")]
+    UserImportInvalid(String),
+    #[error("exporting secrets requires the X-Confirm-Export-Secrets: true header")]
+    ExportSecretsNotConfirmed,
     // --
+    #[error("the settings are too large")]
+    SettingsTooLarge,
     #[error("openssl error occured: {0}")]
     OpenSSL(#[from] ErrorStack),
     #[error("hex error occured: {0}")]
@@ -93,18 +150,33 @@ pub enum AppError {
     Pairing(#[from] PairError<<MoonlightClient as RequestClient>::Error>),
     #[error("external service error")]
     ExternalService,
+    #[error("import error: {0}")]
+    Import(#[from] ImportError),
 }
 
 impl ResponseError for AppError {
     fn status_code(&self) -> StatusCode {
         match self {
             Self::AppDestroyed => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::DefaultUserMisconfigured(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Self::FirstUserAlreadyExists => StatusCode::INTERNAL_SERVER_ERROR,
             Self::FirstLoginCreateAdminNotSet => StatusCode::INTERNAL_SERVER_ERROR,
             Self::HostNotFound => StatusCode::NOT_FOUND,
             Self::HostNotPaired => StatusCode::FORBIDDEN,
+            Self::HostPairRejected => StatusCode::FORBIDDEN,
             Self::HostPaired => StatusCode::NOT_MODIFIED,
             Self::HostOffline => StatusCode::GATEWAY_TIMEOUT,
+            Self::HostMacUnknown => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::AppNotFound => StatusCode::NOT_FOUND,
+            Self::RoomNotFound => StatusCode::NOT_FOUND,
+            Self::InviteInvalid => StatusCode::FORBIDDEN,
+            Self::InviteExpired => StatusCode::FORBIDDEN,
+            Self::InviteRequired => StatusCode::FORBIDDEN,
+            Self::InviteSecretConfigured => StatusCode::CONFLICT,
+            Self::PairingInProgress => StatusCode::CONFLICT,
+            Self::TooManyPairings => StatusCode::TOO_MANY_REQUESTS,
+            Self::HostAddressBlocked => StatusCode::FORBIDDEN,
+            Self::HostAddressInvalid(_) => StatusCode::BAD_REQUEST,
             Self::UserNotFound => StatusCode::NOT_FOUND,
             Self::UserAlreadyExists => StatusCode::CONFLICT,
             Self::CredentialsWrong => StatusCode::UNAUTHORIZED,
@@ -120,10 +192,16 @@ impl ResponseError for AppError {
             Self::PasswordEmpty => StatusCode::BAD_REQUEST,
             Self::NameEmpty => StatusCode::BAD_REQUEST,
             Self::BadRequest => StatusCode::BAD_REQUEST,
+            Self::SettingsInvalid(_) => StatusCode::BAD_REQUEST,
+            Self::PairDeviceNameInvalid(_) => StatusCode::BAD_REQUEST,
+            Self::UserImportInvalid(_) => StatusCode::BAD_REQUEST,
+            Self::ExportSecretsNotConfirmed => StatusCode::BAD_REQUEST,
+            Self::SettingsTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
             Self::MoonlightApi(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Self::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Self::Pairing(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Self::ExternalService => StatusCode::BAD_GATEWAY,
+            Self::Import(_) => StatusCode::BAD_REQUEST,
         }
     }
 }
@@ -142,11 +220,78 @@ impl AppRef {
 struct AppInner {
     config: Config,
     storage: Arc<dyn Storage + Send + Sync>,
-    app_image_cache: RwLock<HashMap<(UserId, HostId, AppId), Bytes>>,
+    app_image_cache: AppImageCache,
+    /// The running box art prefetch of every user and host
+    app_image_prefetches: Mutex<HashMap<(UserId, HostId), AbortHandle>>,
     /// Room manager for multi-player streaming sessions
     room_manager: RoomManager,
     /// Discord Activity instance manager
     discord_instances: DiscordInstanceManager,
+    /// The hosts which are paired right now because pairing is stateful on the host
+    pairing_hosts: Arc<StdMutex<HashSet<HostId>>>,
+    pairing_permits: Arc<Semaphore>,
+    /// When the last magic packet was sent to a host
+    wake_ups: Mutex<HashMap<HostId, Instant>>,
+    /// The app titles of every host, so the running app can be named without fetching the app list every time
+    app_titles: Mutex<HashMap<HostId, HashMap<AppId, String>>>,
+    /// Requests which admins made as another user and remote unpairs
+    audit_log: AuditLog,
+    pending_unpairs: PendingUnpairs,
+    host_reachability: HostReachability,
+    /// Signs the invites of rooms, it's replaced when rotating the secret
+    invite_secret: RwLock<InviteSecret>,
+}
+
+/// Released when pairing finished, failed or timed out
+struct PairingGuard {
+    hosts: Arc<StdMutex<HashSet<HostId>>>,
+    host_id: HostId,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Drop for PairingGuard {
+    fn drop(&mut self) {
+        let mut hosts = self.hosts.lock().expect("pairing hosts mutex poisoned");
+        hosts.remove(&self.host_id);
+    }
+}
+
+impl AppInner {
+    fn lock_pairing(&self, host_id: HostId) -> Result<PairingGuard, AppError> {
+        let mut hosts = self
+            .pairing_hosts
+            .lock()
+            .expect("pairing hosts mutex poisoned");
+        if hosts.contains(&host_id) {
+            return Err(AppError::PairingInProgress);
+        }
+
+        let permit = self
+            .pairing_permits
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| AppError::TooManyPairings)?;
+
+        hosts.insert(host_id);
+
+        Ok(PairingGuard {
+            hosts: self.pairing_hosts.clone(),
+            host_id,
+            _permit: permit,
+        })
+    }
+
+    /// Aborts the box art prefetches of the users and hosts which aren't kept
+    async fn abort_app_image_prefetches(&self, keep: impl Fn(UserId, HostId) -> bool) {
+        let mut prefetches = self.app_image_prefetches.lock().await;
+        prefetches.retain(|(user_id, host_id), prefetch| {
+            let keep = keep(*user_id, *host_id);
+            if !keep {
+                prefetch.abort();
+            }
+            keep
+        });
+    }
 }
 
 pub type MoonlightClient = ReqwestClient;
@@ -157,17 +302,55 @@ pub struct App {
 
 impl App {
     pub async fn new(config: Config) -> Result<Self, anyhow::Error> {
+        let storage = create_storage(config.data_storage.clone()).await?;
+        let invite_secret = load_invite_secret(&config, &*storage).await?;
+
         let app = AppInner {
-            storage: create_storage(config.data_storage.clone()).await?,
+            audit_log: AuditLog::new(storage.clone()),
+            pending_unpairs: PendingUnpairs::new(storage.clone()),
+            storage,
+            pairing_hosts: Default::default(),
+            pairing_permits: Arc::new(Semaphore::new(config.moonlight.max_concurrent_pairings)),
+            wake_ups: Default::default(),
+            app_titles: Default::default(),
+            host_reachability: Default::default(),
+            room_manager: RoomManager::new(config.rooms.clone()),
+            app_image_cache: AppImageCache::new(config.moonlight.app_image_cache.clone()),
             config,
-            app_image_cache: Default::default(),
-            room_manager: RoomManager::new(),
+            app_image_prefetches: Default::default(),
             discord_instances: DiscordInstanceManager::new(),
+            invite_secret: RwLock::new(invite_secret),
         };
 
-        Ok(Self {
+        let this = Self {
             inner: Arc::new(app),
-        })
+        };
+
+        // The user might be added later, so this doesn't prevent the start
+        if let Some(user_id) = this.config().web_server.default_user_id {
+            match this.user_by_id(UserId(user_id)).await {
+                Ok(_) => {}
+                Err(AppError::UserNotFound) => {
+                    error!(
+                        "the default user {user_id} doesn't exist, every request without a login will fail until default_user_id in the config is changed!"
+                    );
+                }
+                Err(err) => {
+                    warn!("failed to check if the default user {user_id} exists: {err}");
+                }
+            }
+        }
+
+        spawn(room_cleanup(
+            this.new_ref(),
+            this.config().rooms.cleanup_interval,
+        ));
+        spawn(retry_pending_unpairs(this.new_ref()));
+        spawn(spectator_idle_sweep(this.new_ref()));
+        spawn(persist_host_reachability(this.new_ref()));
+        spawn(probe_hosts(this.new_ref()));
+
+        Ok(this)
     }
 
     pub fn room_manager(&self) -> &RoomManager {
@@ -178,6 +361,105 @@ impl App {
         &self.inner.discord_instances
     }
 
+    pub async fn audit_log(&self, _: &Admin) -> Result<Vec<AuditEntry>, AppError> {
+        self.inner.audit_log.entries().await
+    }
+
+    /// Retries the unpair of a host which was offline when it was deleted
+    pub async fn retry_pending_unpair(
+        &self,
+        user: &mut AuthenticatedUser,
+        host_id: HostId,
+    ) -> Result<RemoteUnpairResult, AppError> {
+        self.inner.retry_pending_unpair(user, host_id).await
+    }
+
+    pub async fn app_image_cache_stats(&self, _: &Admin) -> AppImageCacheStats {
+        self.inner.app_image_cache.stats().await
+    }
+
+    /// Lets guests join the room until the returned expiration, only the creator of the room and admins can invite.
+    /// The invite is valid for at most the configured invite lifetime.
+    pub async fn create_invite(
+        &self,
+        user: &mut AuthenticatedUser,
+        room_id: &str,
+        valid_for: Option<Duration>,
+    ) -> Result<(String, SystemTime), AppError> {
+        let room = self
+            .room_manager()
+            .get_room(room_id)
+            .await
+            .ok_or(AppError::RoomNotFound)?;
+        let created_by = room
+            .lock()
+            .await
+            .created_by
+            .as_ref()
+            .map(|owner| owner.user_id);
+
+        if created_by != Some(user.id().0) && user.role().await? != Role::Admin {
+            return Err(AppError::Forbidden);
+        }
+
+        let lifetime = self.config().web_server.invite_lifetime;
+        let valid_for = valid_for.map_or(lifetime, |valid_for| valid_for.min(lifetime));
+        let expires_at = SystemTime::now() + valid_for;
+
+        let invite = self
+            .inner
+            .invite_secret
+            .read()
+            .await
+            .sign(room_id, expires_at)?;
+
+        Ok((invite, expires_at))
+    }
+
+    /// Checks the invite of a guest before the guest joins the room
+    pub async fn verify_invite(
+        &self,
+        room_id: &str,
+        invite: Option<&str>,
+        discord_auth: bool,
+    ) -> Result<GuestAdmission, AppError> {
+        match invite {
+            Some(invite) => {
+                self.inner
+                    .invite_secret
+                    .read()
+                    .await
+                    .verify(invite, room_id, SystemTime::now())?;
+
+                Ok(GuestAdmission::Admitted)
+            }
+            None if !self.config().web_server.require_guest_invites => Ok(GuestAdmission::Admitted),
+            // The room id of an activity isn't secret, so the guest still has to prove to be a Discord user
+            None if discord_auth && self.discord_instances().has_room(room_id).await => {
+                Ok(GuestAdmission::DiscordUser)
+            }
+            None => Err(AppError::InviteRequired),
+        }
+    }
+
+    /// Generates a new invite secret, which invalidates all invites
+    pub async fn rotate_invite_secret(&self, _: &Admin) -> Result<(), AppError> {
+        if self.config().web_server.invite_secret.is_some() {
+            return Err(AppError::InviteSecretConfigured);
+        }
+
+        // Held while storing so concurrent rotations can't store a different secret than the one in use
+        let mut invite_secret = self.inner.invite_secret.write().await;
+
+        let secret = InviteSecret::generate()?;
+        self.inner.storage.set_invite_secret(secret).await?;
+        *invite_secret = InviteSecret::new(secret);
+
+        info!("Rotated the invite secret, all invites are invalid now");
+
+        Ok(())
+    }
+
     fn new_ref(&self) -> AppRef {
         AppRef {
             inner: Arc::downgrade(&self.inner),
@@ -265,6 +547,7 @@ impl App {
                 id: user.id,
                 cache_storage: Some(user),
             },
+            impersonated: false,
         })
     }
 
@@ -277,7 +560,7 @@ impl App {
                         Ok(user) => user,
                         Err(AppError::UserNotFound) => {
                             error!("the default user {user_id:?} was not found!");
-                            return Err(AppError::UserNotFound);
+                            return Err(AppError::DefaultUserMisconfigured(user_id.0));
                         }
                         Err(err) => return Err(err),
                     };
@@ -298,8 +581,8 @@ impl App {
                 Ok(user)
             }
             UserAuth::ForwardedHeaders { ref username } => {
-                let user = match self.user_by_name(username).await {
-                    Ok(user) => user,
+                let mut user = match self.user_by_name(username).await {
+                    Ok(user) => user.authenticate(&auth).await?,
                     Err(AppError::UserNotFound) => {
                         let Some(config_forwarded_headers) =
                             &self.config().web_server.forwarded_header
@@ -311,21 +594,23 @@ impl App {
                             return Err(AppError::Unauthorized);
                         }
 
-                        let user = self
-                            .add_user_no_auth(StorageUserAdd {
-                                role: Role::User,
-                                name: username.clone(),
-                                password: None,
-                                client_unique_id: username.clone(),
-                            })
-                            .await?;
-
-                        return Ok(user);
+                        self.add_user_no_auth(StorageUserAdd {
+                            role: Role::User,
+                            name: username.clone(),
+                            password: None,
+                            client_unique_id: username.clone(),
+                        })
+                        .await?
                     }
                     Err(err) => return Err(err),
                 };
 
-                user.authenticate(&auth).await
+                // Every request of the proxy is a login, unlike a session there's nothing else to record it
+                if let Err(err) = user.record_login().await {
+                    warn!("failed to record the login of {:?}: {err}", user.id());
+                }
+
+                Ok(user)
             }
         }
     }
@@ -358,13 +643,20 @@ impl App {
             .get_user_by_session_token(session)
             .await?;
 
-        Ok(AuthenticatedUser {
+        let mut user = AuthenticatedUser {
             inner: User {
                 app: self.new_ref(),
                 id: user_id,
                 cache_storage: user,
             },
-        })
+            impersonated: false,
+        };
+
+        if let Err(err) = user.record_login().await {
+            warn!("failed to record the login of {:?}: {err}", user.id());
+        }
+
+        Ok(user)
     }
 
     pub async fn all_users(&self, _: Admin) -> Result<Vec<User>, AppError> {
@@ -395,4 +687,185 @@ impl App {
     pub async fn delete_session(&self, session: SessionToken) -> Result<(), AppError> {
         self.inner.storage.remove_session_token(session).await
     }
+
+    /// Lets the admin act as the user for one request, which is recorded in the audit log
+    pub async fn impersonate(
+        &self,
+        admin: &Admin,
+        user: &User,
+        method: &str,
+        path: &str,
+    ) -> Impersonation {
+        let impersonation = Impersonation {
+            admin: admin.id(),
+            user: user.id(),
+        };
+
+        self.inner
+            .audit_log
+            .record(AuditEntry {
+                time: SystemTime::now(),
+                actor: impersonation.admin,
+                action: AuditAction::Impersonation {
+                    user: impersonation.user,
+                    method: method.to_string(),
+                    path: path.to_string(),
+                },
+            })
+            .await;
+
+        impersonation
+    }
+
+    /// Records who started which stream, so admins can tell the rooms of the users apart
+    pub async fn audit_stream_started(
+        &self,
+        user: &AuthenticatedUser,
+        room_id: &str,
+        host_id: HostId,
+        app_id: u32,
+        app_name: &str,
+    ) {
+        self.inner
+            .audit_log
+            .record(AuditEntry {
+                time: SystemTime::now(),
+                actor: user.id(),
+                action: AuditAction::StreamStarted {
+                    room: room_id.to_string(),
+                    host: host_id,
+                    app_id,
+                    app_name: app_name.to_string(),
+                },
+            })
+            .await;
+    }
+
+    pub async fn impersonated_user(
+        &self,
+        impersonation: Impersonation,
+    ) -> Result<AuthenticatedUser, AppError> {
+        let user = self.user_by_id(impersonation.user).await?;
+
+        Ok(AuthenticatedUser {
+            inner: user,
+            impersonated: true,
+        })
+    }
+}
+
+/// Periodically removes rooms which weren't cleaned up, e.g. because their streamer died
+async fn room_cleanup(app: AppRef, interval: Duration) {
+    loop {
+        sleep(interval).await;
+
+        let Ok(app) = app.access() else {
+            return;
+        };
+
+        // Rooms get at least one interval to set up their clients and streamer
+        let removed_rooms = app.room_manager.remove_orphaned_rooms(interval).await;
+        for room_id in removed_rooms {
+            app.discord_instances.remove_room(&room_id).await;
+        }
+    }
+}
+
+/// Periodically warns and disconnects spectators which are idle
+async fn spectator_idle_sweep(app: AppRef) {
+    loop {
+        sleep(SPECTATOR_IDLE_SWEEP_INTERVAL).await;
+
+        let Ok(app) = app.access() else {
+            return;
+        };
+
+        let web_server = &app.config().web_server;
+        app.room_manager
+            .sweep_idle_spectators(
+                web_server.spectator_idle_warning,
+                web_server.spectator_idle_timeout,
+            )
+            .await;
+    }
+}
+
+#[cfg(test)]
+impl App {
+    /// Lets the tests add data which can't be created over the api, e.g. a host paired with a fake host
+    pub fn storage(&self) -> &(dyn Storage + Send + Sync) {
+        &*self.inner.storage
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use actix_web::{ResponseError, http::StatusCode};
+    use common::config::{Config, WebServerConfig};
+
+    use crate::{
+        api::discord::DiscordInstance,
+        app::{App, AppError, invite::GuestAdmission},
+        test_util::{test_config, test_dir},
+    };
+
+    #[actix_web::test]
+    async fn test_verify_discord_guest() {
+        let dir = test_dir();
+        let app = App::new(Config {
+            web_server: WebServerConfig {
+                require_guest_invites: true,
+                ..Default::default()
+            },
+            ..test_config(dir.path())
+        })
+        .await
+        .expect("failed to create app");
+
+        app.discord_instances()
+            .register_instance(
+                "instance".to_string(),
+                DiscordInstance {
+                    room_id: "activity".to_string(),
+                    channel_id: None,
+                    guild_id: None,
+                },
+            )
+            .await;
+
+        // Knowing the room id of an activity isn't enough
+        assert!(matches!(
+            app.verify_invite("activity", None, false).await,
+            Err(AppError::InviteRequired)
+        ));
+        assert!(matches!(
+            app.verify_invite("activity", None, true).await,
+            Ok(GuestAdmission::DiscordUser)
+        ));
+        assert!(matches!(
+            app.verify_invite("other", None, true).await,
+            Err(AppError::InviteRequired)
+        ));
+
+        let invite = app
+            .inner
+            .invite_secret
+            .read()
+            .await
+            .sign("other", SystemTime::now() + Duration::from_secs(60))
+            .expect("failed to sign invite");
+        assert!(matches!(
+            app.verify_invite("other", Some(&invite), false).await,
+            Ok(GuestAdmission::Admitted)
+        ));
+    }
+
+    #[test]
+    fn test_default_user_misconfigured_response() {
+        let response = AppError::DefaultUserMisconfigured(5).error_response();
+
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
 }
@@ -0,0 +1,463 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use common::api_bindings::{GetHostUptimeResponse, HostReachabilitySample};
+use log::{debug, warn};
+use moonlight_common::network::{ApiError, host_info, request_client::RequestError};
+use tokio::{sync::Mutex, time::sleep};
+
+use crate::app::{
+    AppError, AppInner, AppRef, MoonlightClient,
+    host::{Host, HostId},
+    host_address::check_host_address,
+    storage::{StorageHost, StorageHostReachabilitySample},
+};
+
+/// The samples of a host are capped at this, only reached by a host which changes its state all the time
+const MAX_SAMPLES: usize = 2048;
+/// Samples with the same state as the previous one are only recorded after this time
+const MIN_SAMPLE_INTERVAL: Duration = Duration::from_secs(60);
+/// Samples older than this are thinned out to [DOWNSAMPLED_INTERVAL], the changes of the state are always kept.
+/// It covers the [FLAP_WINDOW] so the flap detection sees every sample.
+const FULL_RESOLUTION: Duration = Duration::from_hours(1);
+/// The interval of the older samples with the same state as the previous one
+const DOWNSAMPLED_INTERVAL: Duration = Duration::from_mins(15);
+/// The samples are kept as long as the longest uptime which can be requested
+const RETENTION: Duration = Duration::from_hours(MAX_UPTIME_HOURS as u64);
+/// How often the recorded samples are written to the storage
+const PERSIST_INTERVAL: Duration = Duration::from_mins(5);
+/// The window in which the reconnects of a host are counted for the flap detection
+const FLAP_WINDOW: Duration = Duration::from_hours(1);
+
+pub const DEFAULT_UPTIME_HOURS: u32 = 24;
+pub const MAX_UPTIME_HOURS: u32 = 168;
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default()
+}
+
+#[derive(Default)]
+struct HostSamples {
+    /// Oldest first
+    samples: VecDeque<StorageHostReachabilitySample>,
+    /// Changed since it was last written to the storage
+    dirty: bool,
+}
+
+impl HostSamples {
+    fn push(&mut self, sample: StorageHostReachabilitySample) {
+        if let Some(last) = self.samples.back()
+            && last.online == sample.online
+            && sample.timestamp < last.timestamp + MIN_SAMPLE_INTERVAL.as_secs()
+        {
+            return;
+        }
+
+        let now = sample.timestamp;
+        self.samples.push_back(sample);
+        self.compact(now);
+
+        self.dirty = true;
+    }
+
+    /// Removes the samples older than [RETENTION] and downsamples the ones older than [FULL_RESOLUTION]
+    fn compact(&mut self, now: u64) {
+        let retain_since = now.saturating_sub(RETENTION.as_secs());
+        let full_since = now.saturating_sub(FULL_RESOLUTION.as_secs());
+
+        // The state of a sample lasts until the next one, so dropping a sample with the same state as the one before
+        // doesn't change the availability or the reconnects
+        let mut last_kept: Option<&StorageHostReachabilitySample> = None;
+        let mut keep = Vec::with_capacity(self.samples.len());
+        for sample in &self.samples {
+            let redundant = last_kept.is_some_and(|last| {
+                last.online == sample.online
+                    && sample.timestamp < last.timestamp + DOWNSAMPLED_INTERVAL.as_secs()
+            });
+            let kept =
+                sample.timestamp >= retain_since && (sample.timestamp >= full_since || !redundant);
+
+            if kept {
+                last_kept = Some(sample);
+            }
+            keep.push(kept);
+        }
+
+        let mut keep = keep.into_iter();
+        self.samples.retain(|_| keep.next().unwrap_or(true));
+
+        while self.samples.len() > MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+    }
+}
+
+/// How much of the time between `since` and `now` the host was online in percent.
+/// The state of a sample lasts until the next one, so the last sample before `since` is also taken into account.
+fn availability(
+    samples: &VecDeque<StorageHostReachabilitySample>,
+    since: u64,
+    now: u64,
+) -> Option<f64> {
+    let first = samples.front()?;
+    let start = first.timestamp.max(since);
+    if start >= now {
+        return samples
+            .back()
+            .map(|last| if last.online { 100.0 } else { 0.0 });
+    }
+
+    let mut online = 0;
+    for (index, sample) in samples.iter().enumerate() {
+        let from = sample.timestamp.max(start);
+        let to = samples
+            .get(index + 1)
+            .map(|next| next.timestamp)
+            .unwrap_or(now)
+            .min(now);
+
+        if sample.online && to > from {
+            online += to - from;
+        }
+    }
+
+    Some(online as f64 / (now - start) as f64 * 100.0)
+}
+
+/// How often the host came back online after being offline since `since`
+fn reconnects(samples: &VecDeque<StorageHostReachabilitySample>, since: u64) -> usize {
+    samples
+        .iter()
+        .zip(samples.iter().skip(1))
+        .filter(|(previous, sample)| sample.timestamp >= since && !previous.online && sample.online)
+        .count()
+}
+
+/// The reachability samples of every host which was requested since the start.
+/// They're loaded from the storage the first time they're needed and written back by [persist_host_reachability].
+#[derive(Default)]
+pub struct HostReachability {
+    hosts: Mutex<HashMap<HostId, HostSamples>>,
+}
+
+impl HostReachability {
+    pub(super) async fn remove_host(&self, host_id: HostId) {
+        self.hosts.lock().await.remove(&host_id);
+    }
+}
+
+impl AppInner {
+    async fn with_host_samples<R>(
+        &self,
+        host_id: HostId,
+        f: impl FnOnce(&mut HostSamples) -> R,
+    ) -> Result<R, AppError> {
+        if let Some(samples) = self.host_reachability.hosts.lock().await.get_mut(&host_id) {
+            return Ok(f(samples));
+        }
+
+        // The samples of the other hosts stay available while these are loaded
+        let samples = self.storage.get_host_reachability(host_id).await?;
+
+        // Another request could have loaded them in the meantime
+        let mut hosts = self.host_reachability.hosts.lock().await;
+        let samples = hosts.entry(host_id).or_insert_with(|| HostSamples {
+            samples: samples.into(),
+            dirty: false,
+        });
+
+        Ok(f(samples))
+    }
+
+    /// Records if the host responded to a request, the latency is None if it's offline
+    pub(super) async fn record_host_reachability(
+        &self,
+        host_id: HostId,
+        latency: Option<Duration>,
+    ) {
+        let sample = StorageHostReachabilitySample {
+            timestamp: unix_now(),
+            online: latency.is_some(),
+            latency_ms: latency.map(|latency| latency.as_millis().min(u32::MAX as u128) as u32),
+        };
+
+        if let Err(err) = self
+            .with_host_samples(host_id, |samples| samples.push(sample))
+            .await
+        {
+            debug!("[Reachability]: failed to record the reachability of host {host_id:?}: {err}");
+        }
+    }
+
+    /// If the host came back online more often than configured within the last hour
+    pub(super) async fn host_flapping(&self, host_id: HostId) -> Result<bool, AppError> {
+        let since = unix_now().saturating_sub(FLAP_WINDOW.as_secs());
+        let threshold = self.config.moonlight.host_flap_threshold;
+
+        self.with_host_samples(host_id, |samples| {
+            reconnects(&samples.samples, since) > threshold
+        })
+        .await
+    }
+
+    pub(super) async fn host_uptime(
+        &self,
+        host_id: HostId,
+        hours: u32,
+    ) -> Result<GetHostUptimeResponse, AppError> {
+        let now = unix_now();
+        let since = now.saturating_sub(hours as u64 * 60 * 60);
+        let flapping = self.host_flapping(host_id).await?;
+
+        self.with_host_samples(host_id, |samples| GetHostUptimeResponse {
+            samples: samples
+                .samples
+                .iter()
+                .filter(|sample| sample.timestamp >= since)
+                .map(|sample| HostReachabilitySample {
+                    timestamp: sample.timestamp,
+                    online: sample.online,
+                    latency_ms: sample.latency_ms,
+                })
+                .collect(),
+            availability_percent: availability(&samples.samples, since, now),
+            flapping,
+        })
+        .await
+    }
+
+    /// Requests the unauthenticated host info, None if the host is offline
+    async fn probe_host(&self, host: &StorageHost) -> Result<Option<Duration>, AppError> {
+        let address = if self.config.moonlight.block_private_host_ranges {
+            check_host_address(Host::connect_address(host), host.http_port)
+                .await?
+                .to_string()
+        } else {
+            Host::connect_address(host).to_string()
+        };
+
+        let mut client = MoonlightClient::with_defaults().map_err(ApiError::RequestClient)?;
+
+        let start = Instant::now();
+        match host_info(
+            &mut client,
+            false,
+            &Host::build_hostport(&address, host.http_port),
+            None,
+        )
+        .await
+        {
+            Ok(_) => Ok(Some(start.elapsed())),
+            Err(ApiError::RequestClient(err)) if err.is_connect() => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// Periodically writes the changed samples to the storage
+pub(super) async fn persist_host_reachability(app: AppRef) {
+    loop {
+        sleep(PERSIST_INTERVAL).await;
+
+        let Ok(app) = app.access() else {
+            return;
+        };
+
+        let changed = {
+            let mut hosts = app.host_reachability.hosts.lock().await;
+
+            hosts
+                .iter_mut()
+                .filter(|(_, samples)| samples.dirty)
+                .map(|(host_id, samples)| {
+                    samples.dirty = false;
+
+                    (
+                        *host_id,
+                        samples.samples.iter().copied().collect::<Vec<_>>(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        for (host_id, samples) in changed {
+            match app.storage.set_host_reachability(host_id, samples).await {
+                Ok(()) => {}
+                Err(AppError::HostNotFound) => {
+                    app.host_reachability.remove_host(host_id).await;
+                }
+                Err(err) => {
+                    warn!(
+                        "[Reachability]: failed to store the reachability of host {host_id:?}: {err}"
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Periodically checks the hosts an admin enabled the reachability probe for
+pub(super) async fn probe_hosts(app: AppRef) {
+    let interval = match app.access() {
+        Ok(app) => app.config.moonlight.host_probe_interval,
+        Err(_) => return,
+    };
+
+    loop {
+        sleep(interval).await;
+
+        let Ok(app) = app.access() else {
+            return;
+        };
+
+        let hosts = match app.storage.list_hosts().await {
+            Ok(hosts) => hosts,
+            Err(err) => {
+                warn!("[Reachability]: failed to list the hosts to probe: {err}");
+                continue;
+            }
+        };
+
+        for (host_id, host) in hosts {
+            let host = match host {
+                Some(host) => host,
+                None => match app.storage.get_host(host_id).await {
+                    Ok(host) => host,
+                    Err(_) => continue,
+                },
+            };
+            if !host.reachability_probe {
+                continue;
+            }
+
+            match app.probe_host(&host).await {
+                Ok(latency) => app.record_host_reachability(host_id, latency).await,
+                Err(err) => {
+                    debug!("[Reachability]: failed to probe host {host_id:?}: {err}");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+
+    use crate::app::{
+        reachability::{
+            DOWNSAMPLED_INTERVAL, FULL_RESOLUTION, HostSamples, MAX_SAMPLES, RETENTION,
+            availability, reconnects,
+        },
+        storage::StorageHostReachabilitySample,
+    };
+
+    fn sample(timestamp: u64, online: bool) -> StorageHostReachabilitySample {
+        StorageHostReachabilitySample {
+            timestamp,
+            online,
+            latency_ms: online.then_some(5),
+        }
+    }
+
+    #[test]
+    fn test_push_samples() {
+        let mut samples = HostSamples::default();
+
+        samples.push(sample(0, true));
+        // The same state is only recorded again after a minute
+        samples.push(sample(30, true));
+        samples.push(sample(60, true));
+        // A change is always recorded
+        samples.push(sample(61, false));
+        assert_eq!(samples.samples.len(), 3);
+        assert!(samples.dirty);
+
+        for index in 0..MAX_SAMPLES as u64 {
+            samples.push(sample(1000 + index * 60, index % 2 == 0));
+        }
+        assert_eq!(samples.samples.len(), MAX_SAMPLES);
+        assert_eq!(
+            samples.samples.front().map(|sample| sample.timestamp),
+            Some(1000)
+        );
+    }
+
+    #[test]
+    fn test_downsample_samples() {
+        let mut samples = HostSamples::default();
+
+        // Two hours online and a short outage, sampled every minute
+        let start = 1_000_000;
+        for minute in 0..120 {
+            samples.push(sample(start + minute * 60, minute != 10));
+        }
+        let now = start + 119 * 60;
+
+        let full_since = now - FULL_RESOLUTION.as_secs();
+        let old = samples
+            .samples
+            .iter()
+            .filter(|sample| sample.timestamp < full_since)
+            .collect::<Vec<_>>();
+        // The outage is kept, the rest is thinned out
+        assert!(old.len() < 20);
+        assert!(old.iter().any(|sample| !sample.online));
+        assert!(old.windows(2).all(|pair| {
+            pair[0].online != pair[1].online
+                || pair[1].timestamp - pair[0].timestamp >= DOWNSAMPLED_INTERVAL.as_secs()
+        }));
+        // The last hour is kept as it was sampled
+        assert_eq!(
+            samples
+                .samples
+                .iter()
+                .filter(|sample| sample.timestamp >= full_since)
+                .count(),
+            61
+        );
+        let availability = availability(&samples.samples, start, now).expect("no availability");
+        assert!((availability - 7080.0 / 7140.0 * 100.0).abs() < 0.001);
+
+        // Samples older than the longest uptime window are removed
+        samples.push(sample(now + RETENTION.as_secs() + 1, true));
+        assert_eq!(samples.samples.len(), 1);
+    }
+
+    #[test]
+    fn test_availability() {
+        let samples = VecDeque::from([sample(0, true), sample(50, false), sample(75, true)]);
+
+        assert_eq!(availability(&VecDeque::new(), 0, 100), None);
+        assert_eq!(availability(&samples, 0, 100), Some(75.0));
+        // The host was offline at the start of the window
+        assert_eq!(availability(&samples, 60, 100), Some(62.5));
+        // Time before the first sample isn't counted
+        assert_eq!(
+            availability(&VecDeque::from([sample(50, false)]), 0, 100),
+            Some(0.0)
+        );
+    }
+
+    #[test]
+    fn test_reconnects() {
+        let samples = VecDeque::from([
+            sample(0, false),
+            sample(10, true),
+            sample(20, false),
+            sample(30, true),
+            sample(40, true),
+            sample(50, false),
+            sample(60, true),
+        ]);
+
+        assert_eq!(reconnects(&samples, 0), 3);
+        assert_eq!(reconnects(&samples, 25), 2);
+        assert_eq!(reconnects(&samples, 61), 0);
+    }
+}
@@ -0,0 +1,171 @@
+use serde_json::{Map, Value};
+
+use crate::app::AppError;
+
+/// The maximum size of the serialized settings of one user
+pub const MAX_USER_SETTINGS_SIZE: usize = 16 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+enum SettingType {
+    Number,
+    Bool,
+    String,
+    Object,
+}
+
+impl SettingType {
+    fn matches(&self, value: &Value) -> bool {
+        match self {
+            Self::Number => value.is_number(),
+            Self::Bool => value.is_boolean(),
+            Self::String => value.is_string(),
+            Self::Object => value.is_object(),
+        }
+    }
+}
+
+/// The settings of the frontend, when updated also update the `Settings` type in the frontend
+const USER_SETTINGS_SCHEMA: [(&str, SettingType); 21] = [
+    ("sidebarEdge", SettingType::String),
+    ("bitrate", SettingType::Number),
+    ("packetSize", SettingType::Number),
+    ("fps", SettingType::Number),
+    ("videoFrameQueueSize", SettingType::Number),
+    ("videoSize", SettingType::String),
+    ("videoSizeCustom", SettingType::Object),
+    ("videoCodec", SettingType::String),
+    ("forceVideoElementRenderer", SettingType::Bool),
+    ("canvasRenderer", SettingType::Bool),
+    ("playAudioLocal", SettingType::Bool),
+    ("audioSampleQueueSize", SettingType::Number),
+    ("mouseScrollMode", SettingType::String),
+    ("keyboardMode", SettingType::String),
+    ("keyboardLayout", SettingType::String),
+    ("keyboardLayoutCustom", SettingType::Object),
+    ("keyOverrides", SettingType::Object),
+    ("controllerConfig", SettingType::Object),
+    ("dataTransport", SettingType::String),
+    ("toggleFullscreenWithKeybind", SettingType::Bool),
+    ("pageStyle", SettingType::String),
+];
+
+/// Checks that the settings are an object which only contains known settings with the correct type
+pub fn validate_user_settings(settings: &Value) -> Result<(), AppError> {
+    let size = serde_json::to_vec(settings)
+        .map_err(|_| AppError::BadRequest)?
+        .len();
+    if size > MAX_USER_SETTINGS_SIZE {
+        return Err(AppError::SettingsTooLarge);
+    }
+
+    let Some(settings) = settings.as_object() else {
+        return Err(AppError::SettingsInvalid("settings".to_string()));
+    };
+
+    for (key, value) in settings {
+        let Some((_, ty)) = USER_SETTINGS_SCHEMA.iter().find(|(name, _)| name == key) else {
+            return Err(AppError::SettingsInvalid(key.clone()));
+        };
+
+        if !ty.matches(value) {
+            return Err(AppError::SettingsInvalid(key.clone()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Applies the user settings over the global default settings, nested objects are merged too
+pub fn merge_settings(default: Option<&Value>, user: Option<&Value>) -> Option<Value> {
+    match (default, user) {
+        (Some(default), Some(user)) => {
+            let mut merged = default.clone();
+            merge_value(&mut merged, user);
+            Some(merged)
+        }
+        (default, user) => user.or(default).cloned(),
+    }
+}
+
+fn merge_value(target: &mut Value, source: &Value) {
+    match (target.as_object_mut(), source.as_object()) {
+        (Some(target), Some(source)) => merge_object(target, source),
+        _ => *target = source.clone(),
+    }
+}
+
+fn merge_object(target: &mut Map<String, Value>, source: &Map<String, Value>) {
+    for (key, value) in source {
+        match target.get_mut(key) {
+            Some(target_value) => merge_value(target_value, value),
+            None => {
+                target.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use crate::app::{
+        AppError,
+        settings::{MAX_USER_SETTINGS_SIZE, merge_settings, validate_user_settings},
+    };
+
+    #[test]
+    fn test_validate_user_settings() {
+        assert!(
+            validate_user_settings(&json!({
+                "bitrate": 50000,
+                "videoSize": "4k",
+                "playAudioLocal": true,
+                "controllerConfig": { "invertAB": true },
+            }))
+            .is_ok()
+        );
+
+        assert!(matches!(
+            validate_user_settings(&json!({ "unknown": 1 })),
+            Err(AppError::SettingsInvalid(key)) if key == "unknown"
+        ));
+        assert!(matches!(
+            validate_user_settings(&json!({ "bitrate": "fast" })),
+            Err(AppError::SettingsInvalid(key)) if key == "bitrate"
+        ));
+        assert!(matches!(
+            validate_user_settings(&json!([1, 2])),
+            Err(AppError::SettingsInvalid(_))
+        ));
+        assert!(matches!(
+            validate_user_settings(&json!({ "pageStyle": "a".repeat(MAX_USER_SETTINGS_SIZE) })),
+            Err(AppError::SettingsTooLarge)
+        ));
+    }
+
+    #[test]
+    fn test_merge_settings() {
+        let default = json!({
+            "bitrate": 10000,
+            "fps": 60,
+            "controllerConfig": { "invertAB": false, "invertXY": false },
+        });
+        let user = json!({
+            "bitrate": 50000,
+            "controllerConfig": { "invertAB": true },
+        });
+
+        assert_eq!(
+            merge_settings(Some(&default), Some(&user)),
+            Some(json!({
+                "bitrate": 50000,
+                "fps": 60,
+                "controllerConfig": { "invertAB": true, "invertXY": false },
+            }))
+        );
+        assert_eq!(merge_settings(None, Some(&user)), Some(user.clone()));
+        assert_eq!(merge_settings(Some(&default), None), Some(default));
+        assert_eq!(merge_settings(None, None), None);
+    }
+}
@@ -1,9 +1,9 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     io::ErrorKind,
     path::PathBuf,
     sync::Arc,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::anyhow;
@@ -11,10 +11,11 @@ use async_trait::async_trait;
 use futures::future::join_all;
 use log::{debug, error};
 use openssl::rand::rand_bytes;
+use serde_json::Value;
 use tokio::{
     fs, spawn,
     sync::{
-        RwLock,
+        Mutex, RwLock,
         mpsc::{self, Receiver, Sender, error::TrySendError},
         oneshot,
     },
@@ -24,17 +25,20 @@ use tokio::{
 
 use crate::app::{
     AppError,
-    auth::SessionToken,
-    host::HostId,
+    audit::{AuditAction, AuditEntry},
+    auth::{SessionExpiration, SessionToken},
+    host::{AppId, HostId},
     password::StoragePassword,
     storage::{
         Either, Storage, StorageHost, StorageHostAdd, StorageHostCache, StorageHostModify,
-        StorageHostPairInfo, StorageQueryHosts, StorageUser, StorageUserAdd, StorageUserModify,
+        StorageHostPairInfo, StorageHostReachabilitySample, StorageQueryHosts, StorageUser,
+        StorageUserAdd, StorageUserModify,
         json::versions::{
-            Json, V2, V2Host, V2HostCache, V2HostPairInfo, V2User, V2UserPassword,
-            migrate_to_latest,
+            Json, V2, V2AuditAction, V2AuditEntry, V2Host, V2HostCache, V2HostPairInfo,
+            V2HostReachabilitySample, V2PendingUnpair, V2User, V2UserPassword, migrate_to_latest,
         },
     },
+    unpair::RemoteUnpair,
     user::UserId,
 };
 
@@ -44,10 +48,15 @@ mod versions;
 pub struct JsonStorage {
     file: PathBuf,
     store_sender: Sender<()>,
+    /// Only one write to the file can happen at a time
+    store_lock: Mutex<()>,
     session_expiration_checker: JoinHandle<()>,
     users: RwLock<HashMap<u32, RwLock<V2User>>>,
     hosts: RwLock<HashMap<u32, RwLock<V2Host>>>,
     sessions: RwLock<HashMap<SessionToken, Session>>,
+    invite_secret: RwLock<Option<[u8; 32]>>,
+    audit_log: RwLock<VecDeque<V2AuditEntry>>,
+    pending_unpairs: RwLock<Vec<V2PendingUnpair>>,
 }
 
 impl Drop for JsonStorage {
@@ -58,14 +67,22 @@ impl Drop for JsonStorage {
 
 struct Session {
     created_at: Instant,
-    expiration: Duration,
+    last_used: Instant,
+    expiration: SessionExpiration,
     user_id: u32,
 }
 
+impl Session {
+    fn is_expired(&self, now: Instant) -> bool {
+        now >= self.expiration.expires_at(self.created_at, self.last_used)
+    }
+}
+
 impl JsonStorage {
     pub async fn load(
         file: PathBuf,
         session_expiration_check_interval: Duration,
+        write_interval: Duration,
     ) -> Result<Arc<Self>, anyhow::Error> {
         let (store_sender, store_receiver) = mpsc::channel(1);
 
@@ -89,21 +106,21 @@ impl JsonStorage {
                 let mut sessions = this.sessions.write().await;
 
                 let now = Instant::now();
-                sessions.retain(|_, session| {
-                    let current_session_length = now - session.created_at;
-
-                    current_session_length < session.expiration
-                });
+                sessions.retain(|_, session| !session.is_expired(now));
             }
         });
 
         let this = Self {
             file,
             store_sender,
+            store_lock: Default::default(),
             session_expiration_checker,
             hosts: Default::default(),
             users: Default::default(),
             sessions: Default::default(),
+            invite_secret: Default::default(),
+            audit_log: Default::default(),
+            pending_unpairs: Default::default(),
         };
         let this = Arc::new(this);
 
@@ -118,7 +135,7 @@ impl JsonStorage {
         spawn({
             let this = this.clone();
 
-            async move { file_writer(store_receiver, this).await }
+            async move { file_writer(store_receiver, this, write_interval).await }
         });
 
         Ok(this)
@@ -164,11 +181,17 @@ impl JsonStorage {
                 .into_iter()
                 .map(|(id, host)| (id, RwLock::new(host)))
                 .collect();
+            *self.invite_secret.write().await = data.invite_secret;
+            *self.audit_log.write().await = data.audit_log.into();
+            *self.pending_unpairs.write().await = data.pending_unpairs;
         }
 
         Ok(())
     }
     async fn store(&self) {
+        // Taken before the snapshot so an older snapshot can't overwrite a newer one
+        let _store_guard = self.store_lock.lock().await;
+
         let json = {
             let users = self.users.read().await;
             let hosts = self.hosts.read().await;
@@ -190,6 +213,9 @@ impl JsonStorage {
             Json::V2(V2 {
                 users: users_json,
                 hosts: hosts_json,
+                invite_secret: *self.invite_secret.read().await,
+                audit_log: self.audit_log.read().await.iter().cloned().collect(),
+                pending_unpairs: self.pending_unpairs.read().await.clone(),
             })
         };
 
@@ -201,22 +227,47 @@ impl JsonStorage {
             }
         };
 
-        if let Err(err) = fs::write(&self.file, text).await {
+        // Write to a temporary file first so a crash while writing doesn't corrupt the data
+        let mut temp_file = self.file.clone().into_os_string();
+        temp_file.push(".tmp");
+
+        if let Err(err) = fs::write(&temp_file, text).await {
             error!("Failed to write data to file: {err:?}");
+            return;
+        }
+        if let Err(err) = fs::rename(&temp_file, &self.file).await {
+            error!("Failed to replace data file: {err:?}");
         }
     }
 }
 
-async fn file_writer(mut store_receiver: Receiver<()>, json: Arc<JsonStorage>) {
+async fn file_writer(
+    mut store_receiver: Receiver<()>,
+    json: Arc<JsonStorage>,
+    write_interval: Duration,
+) {
     loop {
         if store_receiver.recv().await.is_none() {
             return;
         }
 
+        // Batch all changes that happen until the next write
+        sleep(write_interval).await;
+        while store_receiver.try_recv().is_ok() {}
+
         json.store().await;
     }
 }
 
+fn time_to_json(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default()
+}
+fn time_from_json(secs: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
 fn user_from_json(user_id: UserId, user: &V2User) -> StorageUser {
     StorageUser {
         id: user_id,
@@ -227,6 +278,9 @@ fn user_from_json(user_id: UserId, user: &V2User) -> StorageUser {
         }),
         role: user.role,
         client_unique_id: user.client_unique_id.clone(),
+        pair_device_name: user.pair_device_name.clone(),
+        last_login: user.last_login.map(time_from_json),
+        last_stream: user.last_stream.map(time_from_json),
     }
 }
 
@@ -241,10 +295,17 @@ fn host_from_json(host_id: HostId, host: &V2Host) -> StorageHost {
             client_private_key: pair_info.client_private_key,
             server_certificate: pair_info.server_certificate,
         }),
+        pair_invalid: host.pair_invalid,
         cache: StorageHostCache {
             name: host.cache.name.clone(),
             mac: host.cache.mac,
         },
+        display_name: host.display_name.clone(),
+        https_port_override: host.https_port_override,
+        external_address: host.external_address.clone(),
+        hidden_app_ids: host.hidden_app_ids.iter().copied().map(AppId).collect(),
+        shared_with: host.shared_with.iter().copied().map(UserId).collect(),
+        reachability_probe: host.reachability_probe,
     }
 }
 
@@ -259,20 +320,21 @@ impl Storage for JsonStorage {
                 hash: password.hash,
             }),
             client_unique_id: user.client_unique_id,
+            settings: None,
+            pair_device_name: None,
+            last_login: None,
+            last_stream: None,
         };
 
-        {
-            match self.get_user_by_name(&user.name).await {
-                Err(AppError::UserNotFound) => {
-                    // Fallthrough
-                }
-                Ok(_) => return Err(AppError::UserAlreadyExists),
-                Err(err) => return Err(err),
+        let mut users = self.users.write().await;
+
+        // Checked while holding the write lock so no user with the same name can be added in the meantime
+        for existing_user in users.values() {
+            if existing_user.read().await.name == user.name {
+                return Err(AppError::UserAlreadyExists);
             }
         }
 
-        let mut users = self.users.write().await;
-
         let mut id;
         loop {
             let mut id_bytes = [0u8; 4];
@@ -298,6 +360,9 @@ impl Storage for JsonStorage {
             }),
             role: user.role,
             client_unique_id: user.client_unique_id,
+            pair_device_name: user.pair_device_name,
+            last_login: None,
+            last_stream: None,
         })
     }
     async fn modify_user(
@@ -322,6 +387,15 @@ impl Storage for JsonStorage {
         if let Some(client_unique_id) = modify.client_unique_id {
             user.client_unique_id = client_unique_id;
         }
+        if let Some(pair_device_name) = modify.pair_device_name {
+            user.pair_device_name = pair_device_name;
+        }
+        if let Some(last_login) = modify.last_login {
+            user.last_login = Some(time_to_json(last_login));
+        }
+        if let Some(last_stream) = modify.last_stream {
+            user.last_stream = Some(time_to_json(last_stream));
+        }
 
         drop(user);
         drop(users);
@@ -338,6 +412,33 @@ impl Storage for JsonStorage {
 
         Ok(user_from_json(user_id, &user))
     }
+    async fn get_user_settings(&self, user_id: UserId) -> Result<Option<Value>, AppError> {
+        let users = self.users.read().await;
+
+        let user_lock = users.get(&user_id.0).ok_or(AppError::UserNotFound)?;
+        let user = user_lock.read().await;
+
+        Ok(user.settings.clone())
+    }
+    async fn set_user_settings(
+        &self,
+        user_id: UserId,
+        settings: Option<Value>,
+    ) -> Result<(), AppError> {
+        let users = self.users.read().await;
+
+        let user_lock = users.get(&user_id.0).ok_or(AppError::UserNotFound)?;
+        let mut user = user_lock.write().await;
+
+        user.settings = settings;
+
+        drop(user);
+        drop(users);
+
+        self.force_write();
+
+        Ok(())
+    }
     async fn get_user_by_name(
         &self,
         name: &str,
@@ -395,7 +496,7 @@ impl Storage for JsonStorage {
     async fn create_session_token(
         &self,
         user_id: UserId,
-        expiration: Duration,
+        expiration: SessionExpiration,
     ) -> Result<SessionToken, AppError> {
         let mut token;
         {
@@ -411,10 +512,12 @@ impl Storage for JsonStorage {
 
         let mut sessions = self.sessions.write().await;
 
+        let now = Instant::now();
         sessions.insert(
             token,
             Session {
-                created_at: Instant::now(),
+                created_at: now,
+                last_used: now,
                 expiration,
                 user_id: user_id.0,
             },
@@ -440,12 +543,17 @@ impl Storage for JsonStorage {
         &self,
         session: SessionToken,
     ) -> Result<(UserId, Option<StorageUser>), AppError> {
-        let sessions = self.sessions.read().await;
+        let mut sessions = self.sessions.write().await;
+
+        let now = Instant::now();
+        // Expired sessions might not be cleared yet
+        let session = sessions
+            .get_mut(&session)
+            .filter(|session| !session.is_expired(now))
+            .ok_or(AppError::SessionTokenNotFound)?;
+        session.last_used = now;
 
-        sessions
-            .get(&session)
-            .map(|session| (UserId(session.user_id), None))
-            .ok_or(AppError::SessionTokenNotFound)
+        Ok((UserId(session.user_id), None))
     }
 
     async fn add_host(&self, host: StorageHostAdd) -> Result<StorageHost, AppError> {
@@ -458,10 +566,18 @@ impl Storage for JsonStorage {
                 client_certificate: pair_info.client_certificate,
                 server_certificate: pair_info.server_certificate,
             }),
+            pair_invalid: false,
             cache: V2HostCache {
                 name: host.cache.name,
                 mac: host.cache.mac,
             },
+            display_name: None,
+            https_port_override: None,
+            external_address: None,
+            hidden_app_ids: Vec::new(),
+            shared_with: Vec::new(),
+            reachability_probe: false,
+            reachability: Vec::new(),
         };
 
         let mut hosts = self.hosts.write().await;
@@ -490,10 +606,17 @@ impl Storage for JsonStorage {
                 client_certificate: pair_info.client_certificate,
                 server_certificate: pair_info.server_certificate,
             }),
+            pair_invalid: host.pair_invalid,
             cache: StorageHostCache {
                 name: host.cache.name,
                 mac: host.cache.mac,
             },
+            display_name: host.display_name,
+            https_port_override: host.https_port_override,
+            external_address: host.external_address,
+            hidden_app_ids: Vec::new(),
+            shared_with: Vec::new(),
+            reachability_probe: host.reachability_probe,
         })
     }
     async fn modify_host(
@@ -522,12 +645,39 @@ impl Storage for JsonStorage {
                 server_certificate: new_pair_info.server_certificate,
             });
         }
+        if let Some(new_pair_invalid) = modify.pair_invalid {
+            host.pair_invalid = new_pair_invalid;
+        }
         if let Some(new_cache_name) = modify.cache_name {
             host.cache.name = new_cache_name;
         }
         if let Some(new_cache_mac) = modify.cache_mac {
             host.cache.mac = new_cache_mac;
         }
+        if let Some(new_display_name) = modify.display_name {
+            host.display_name = new_display_name;
+        }
+        if let Some(new_https_port_override) = modify.https_port_override {
+            host.https_port_override = new_https_port_override;
+        }
+        if let Some(new_external_address) = modify.external_address {
+            host.external_address = new_external_address;
+        }
+        if let Some(new_hidden_app_ids) = modify.hidden_app_ids {
+            host.hidden_app_ids = new_hidden_app_ids
+                .into_iter()
+                .map(|app_id| app_id.0)
+                .collect();
+        }
+        if let Some(new_shared_with) = modify.shared_with {
+            host.shared_with = new_shared_with
+                .into_iter()
+                .map(|user_id| user_id.0)
+                .collect();
+        }
+        if let Some(new_reachability_probe) = modify.reachability_probe {
+            host.reachability_probe = new_reachability_probe;
+        }
 
         self.force_write();
 
@@ -552,6 +702,51 @@ impl Storage for JsonStorage {
 
         Ok(())
     }
+    async fn get_host_reachability(
+        &self,
+        host_id: HostId,
+    ) -> Result<Vec<StorageHostReachabilitySample>, AppError> {
+        let hosts = self.hosts.read().await;
+
+        let host = hosts.get(&host_id.0).ok_or(AppError::HostNotFound)?;
+        let host = host.read().await;
+
+        Ok(host
+            .reachability
+            .iter()
+            .map(|sample| StorageHostReachabilitySample {
+                timestamp: sample.timestamp,
+                online: sample.online,
+                latency_ms: sample.latency_ms,
+            })
+            .collect())
+    }
+    async fn set_host_reachability(
+        &self,
+        host_id: HostId,
+        samples: Vec<StorageHostReachabilitySample>,
+    ) -> Result<(), AppError> {
+        let hosts = self.hosts.read().await;
+
+        let host = hosts.get(&host_id.0).ok_or(AppError::HostNotFound)?;
+        let mut host = host.write().await;
+
+        host.reachability = samples
+            .into_iter()
+            .map(|sample| V2HostReachabilitySample {
+                timestamp: sample.timestamp,
+                online: sample.online,
+                latency_ms: sample.latency_ms,
+            })
+            .collect();
+
+        drop(host);
+        drop(hosts);
+
+        self.force_write();
+
+        Ok(())
+    }
 
     async fn list_user_hosts(
         &self,
@@ -564,11 +759,650 @@ impl Storage for JsonStorage {
             let host_id = HostId(*host_id);
             let host = host.read().await;
 
-            if host.owner.is_none() || host.owner.map(UserId) == Some(query.user_id) {
+            if host.owner.is_none()
+                || host.owner.map(UserId) == Some(query.user_id)
+                || host.shared_with.contains(&query.user_id.0)
+            {
                 user_hosts.push((host_id, Some(host_from_json(host_id, &host))));
             }
         }
 
         Ok(user_hosts)
     }
+    async fn list_hosts(&self) -> Result<Vec<(HostId, Option<StorageHost>)>, AppError> {
+        let hosts = self.hosts.read().await;
+
+        let mut all_hosts = Vec::new();
+        for (host_id, host) in &*hosts {
+            let host_id = HostId(*host_id);
+            let host = host.read().await;
+
+            all_hosts.push((host_id, Some(host_from_json(host_id, &host))));
+        }
+
+        Ok(all_hosts)
+    }
+
+    async fn get_invite_secret(&self) -> Result<Option<[u8; 32]>, AppError> {
+        Ok(*self.invite_secret.read().await)
+    }
+    async fn set_invite_secret(&self, secret: [u8; 32]) -> Result<(), AppError> {
+        *self.invite_secret.write().await = Some(secret);
+
+        self.force_write();
+
+        Ok(())
+    }
+
+    async fn list_audit_entries(&self) -> Result<Vec<AuditEntry>, AppError> {
+        let audit_log = self.audit_log.read().await;
+
+        Ok(audit_log
+            .iter()
+            .cloned()
+            .map(audit_entry_from_json)
+            .collect())
+    }
+    async fn add_audit_entry(&self, entry: AuditEntry, capacity: usize) -> Result<(), AppError> {
+        let mut audit_log = self.audit_log.write().await;
+
+        audit_log.push_back(audit_entry_to_json(entry));
+        while audit_log.len() > capacity {
+            audit_log.pop_front();
+        }
+
+        drop(audit_log);
+
+        self.force_write();
+
+        Ok(())
+    }
+
+    async fn list_pending_unpairs(&self) -> Result<Vec<RemoteUnpair>, AppError> {
+        let pending_unpairs = self.pending_unpairs.read().await;
+
+        Ok(pending_unpairs
+            .iter()
+            .cloned()
+            .map(pending_unpair_from_json)
+            .collect())
+    }
+    async fn set_pending_unpairs(&self, unpairs: Vec<RemoteUnpair>) -> Result<(), AppError> {
+        *self.pending_unpairs.write().await =
+            unpairs.into_iter().map(pending_unpair_to_json).collect();
+
+        self.force_write();
+
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<(), AppError> {
+        self.store().await;
+
+        Ok(())
+    }
+}
+
+fn pending_unpair_to_json(unpair: RemoteUnpair) -> V2PendingUnpair {
+    V2PendingUnpair {
+        host: unpair.host_id.0,
+        address: unpair.address,
+        http_port: unpair.http_port,
+        client_unique_id: unpair.client_unique_id,
+        pair_info: V2HostPairInfo {
+            client_private_key: unpair.pair_info.client_private_key,
+            client_certificate: unpair.pair_info.client_certificate,
+            server_certificate: unpair.pair_info.server_certificate,
+        },
+        actor: unpair.actor.0,
+    }
+}
+fn pending_unpair_from_json(unpair: V2PendingUnpair) -> RemoteUnpair {
+    RemoteUnpair {
+        host_id: HostId(unpair.host),
+        address: unpair.address,
+        http_port: unpair.http_port,
+        client_unique_id: unpair.client_unique_id,
+        pair_info: StorageHostPairInfo {
+            client_private_key: unpair.pair_info.client_private_key,
+            client_certificate: unpair.pair_info.client_certificate,
+            server_certificate: unpair.pair_info.server_certificate,
+        },
+        actor: UserId(unpair.actor),
+    }
+}
+
+fn audit_entry_to_json(entry: AuditEntry) -> V2AuditEntry {
+    V2AuditEntry {
+        time: time_to_json(entry.time),
+        actor: entry.actor.0,
+        action: match entry.action {
+            AuditAction::Impersonation { user, method, path } => V2AuditAction::Impersonation {
+                user: user.0,
+                method,
+                path,
+            },
+            AuditAction::RemoteUnpair {
+                host,
+                address,
+                result,
+            } => V2AuditAction::RemoteUnpair {
+                host: host.0,
+                address,
+                result,
+            },
+            AuditAction::StreamStarted {
+                room,
+                host,
+                app_id,
+                app_name,
+            } => V2AuditAction::StreamStarted {
+                room,
+                host: host.0,
+                app_id,
+                app_name,
+            },
+            AuditAction::UsersExported { include_hosts } => {
+                V2AuditAction::UsersExported { include_hosts }
+            }
+        },
+    }
+}
+fn audit_entry_from_json(entry: V2AuditEntry) -> AuditEntry {
+    AuditEntry {
+        time: time_from_json(entry.time),
+        actor: UserId(entry.actor),
+        action: match entry.action {
+            V2AuditAction::Impersonation { user, method, path } => AuditAction::Impersonation {
+                user: UserId(user),
+                method,
+                path,
+            },
+            V2AuditAction::RemoteUnpair {
+                host,
+                address,
+                result,
+            } => AuditAction::RemoteUnpair {
+                host: HostId(host),
+                address,
+                result,
+            },
+            V2AuditAction::StreamStarted {
+                room,
+                host,
+                app_id,
+                app_name,
+            } => AuditAction::StreamStarted {
+                room,
+                host: HostId(host),
+                app_id,
+                app_name,
+            },
+            V2AuditAction::UsersExported { include_hosts } => {
+                AuditAction::UsersExported { include_hosts }
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use futures::future::join_all;
+
+    use crate::{
+        app::{
+            AppError,
+            audit::{AuditAction, AuditEntry},
+            host::{AppId, HostId},
+            storage::{
+                Storage, StorageHostModify, StorageHostReachabilitySample, StorageQueryHosts,
+                StorageUserAdd, StorageUserModify, json::JsonStorage,
+            },
+            user::{Role, UserId},
+        },
+        test_util::test_dir,
+    };
+
+    fn user(name: &str) -> StorageUserAdd {
+        StorageUserAdd {
+            role: Role::User,
+            name: name.to_string(),
+            password: None,
+            client_unique_id: "test".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_changes() {
+        let dir = test_dir();
+        let file = dir.path().join("data.json");
+
+        let storage = JsonStorage::load(
+            file.clone(),
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+        )
+        .await
+        .expect("failed to load storage");
+
+        // Only one user with the same name can be added
+        let results = join_all((0..8).map(|_| storage.add_user(user("same")))).await;
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+        assert!(
+            results
+                .iter()
+                .all(|result| result.is_ok() || matches!(result, Err(AppError::UserAlreadyExists)))
+        );
+
+        join_all((0..8).map(|index| storage.add_user(user(&format!("user{index}"))))).await;
+
+        // The changes are batched, nothing is written until the interval passed or a flush
+        assert!(!file.exists());
+
+        storage.flush().await.expect("failed to flush storage");
+
+        let storage = JsonStorage::load(
+            file.clone(),
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+        )
+        .await
+        .expect("failed to reload storage");
+
+        assert!(
+            storage
+                .any_user_exists()
+                .await
+                .expect("failed to query users")
+        );
+        assert!(storage.get_user_by_name("same").await.is_ok());
+        assert!(storage.get_user_by_name("user7").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_host_overrides() {
+        let dir = test_dir();
+        let file = dir.path().join("data.json");
+        // A data file written before the overrides existed
+        fs::write(
+            &file,
+            r#"{
+                "version": "2",
+                "users": {},
+                "hosts": {
+                    "0": {
+                        "owner": null,
+                        "address": "192.168.0.2",
+                        "http_port": 47989,
+                        "pair_info": null,
+                        "cache": { "name": "Host", "mac": null }
+                    }
+                }
+            }"#,
+        )
+        .expect("failed to write storage file");
+
+        let load = || JsonStorage::load(file.clone(), Duration::from_secs(60), Duration::ZERO);
+
+        let storage = load().await.expect("failed to load storage");
+        let host = storage
+            .get_host(HostId(0))
+            .await
+            .expect("failed to get host");
+        assert_eq!(host.https_port_override, None);
+        assert_eq!(host.external_address, None);
+        assert!(host.hidden_app_ids.is_empty());
+
+        storage
+            .modify_host(
+                HostId(0),
+                StorageHostModify {
+                    https_port_override: Some(Some(48000)),
+                    external_address: Some(Some("example.com".to_string())),
+                    hidden_app_ids: Some(vec![AppId(1), AppId(5)]),
+                    ..Default::default()
+                },
+            )
+            .await
+            .expect("failed to modify host");
+        storage.flush().await.expect("failed to flush storage");
+
+        let storage = load().await.expect("failed to reload storage");
+        let host = storage
+            .get_host(HostId(0))
+            .await
+            .expect("failed to get host");
+        assert_eq!(host.https_port_override, Some(48000));
+        assert_eq!(host.external_address.as_deref(), Some("example.com"));
+        assert_eq!(host.hidden_app_ids, vec![AppId(1), AppId(5)]);
+
+        // Clearing the overrides reverts to the reported values
+        storage
+            .modify_host(
+                HostId(0),
+                StorageHostModify {
+                    https_port_override: Some(None),
+                    external_address: Some(None),
+                    ..Default::default()
+                },
+            )
+            .await
+            .expect("failed to modify host");
+        let host = storage
+            .get_host(HostId(0))
+            .await
+            .expect("failed to get host");
+        assert_eq!(host.https_port_override, None);
+        assert_eq!(host.external_address, None);
+        assert_eq!(host.address, "192.168.0.2");
+    }
+
+    #[tokio::test]
+    async fn test_user_pair_device_name() {
+        let dir = test_dir();
+        let file = dir.path().join("data.json");
+        // A data file written before the users had a device name
+        fs::write(
+            &file,
+            r#"{
+                "version": "2",
+                "users": {
+                    "1": {
+                        "role": "User",
+                        "name": "alice",
+                        "password": null,
+                        "client_unique_id": "alice"
+                    }
+                },
+                "hosts": {}
+            }"#,
+        )
+        .expect("failed to write storage file");
+
+        let load = || JsonStorage::load(file.clone(), Duration::from_secs(60), Duration::ZERO);
+
+        let storage = load().await.expect("failed to load storage");
+        let user = storage
+            .get_user(UserId(1))
+            .await
+            .expect("failed to get user");
+        assert_eq!(user.pair_device_name, None);
+        assert_eq!(user.last_login, None);
+
+        storage
+            .modify_user(
+                UserId(1),
+                StorageUserModify {
+                    pair_device_name: Some(Some("Living Room".to_string())),
+                    last_login: Some(UNIX_EPOCH + Duration::from_secs(1000)),
+                    ..Default::default()
+                },
+            )
+            .await
+            .expect("failed to modify user");
+        storage.flush().await.expect("failed to flush storage");
+
+        let storage = load().await.expect("failed to reload storage");
+        let user = storage
+            .get_user(UserId(1))
+            .await
+            .expect("failed to get user");
+        assert_eq!(user.pair_device_name.as_deref(), Some("Living Room"));
+        assert_eq!(
+            user.last_login,
+            Some(UNIX_EPOCH + Duration::from_secs(1000))
+        );
+        assert_eq!(user.last_stream, None);
+    }
+
+    #[tokio::test]
+    async fn test_host_shared_with() {
+        let dir = test_dir();
+        let file = dir.path().join("data.json");
+        fs::write(
+            &file,
+            r#"{
+                "version": "2",
+                "users": {},
+                "hosts": {
+                    "0": {
+                        "owner": null,
+                        "address": "192.168.0.2",
+                        "http_port": 47989,
+                        "pair_info": null,
+                        "cache": { "name": "Global", "mac": null }
+                    },
+                    "1": {
+                        "owner": 1,
+                        "address": "192.168.0.3",
+                        "http_port": 47989,
+                        "pair_info": null,
+                        "cache": { "name": "Private", "mac": null }
+                    }
+                }
+            }"#,
+        )
+        .expect("failed to write storage file");
+
+        let load = || JsonStorage::load(file.clone(), Duration::from_secs(60), Duration::ZERO);
+        let user_hosts = async |storage: &JsonStorage, user_id: u32| {
+            let mut host_ids = storage
+                .list_user_hosts(StorageQueryHosts {
+                    user_id: UserId(user_id),
+                })
+                .await
+                .expect("failed to list hosts")
+                .into_iter()
+                .map(|(host_id, _)| host_id.0)
+                .collect::<Vec<_>>();
+            host_ids.sort();
+            host_ids
+        };
+
+        let storage = load().await.expect("failed to load storage");
+        assert!(
+            storage
+                .get_host(HostId(1))
+                .await
+                .expect("failed to get host")
+                .shared_with
+                .is_empty()
+        );
+        assert_eq!(user_hosts(&storage, 1).await, vec![0, 1]);
+        assert_eq!(user_hosts(&storage, 2).await, vec![0]);
+
+        storage
+            .modify_host(
+                HostId(1),
+                StorageHostModify {
+                    shared_with: Some(vec![UserId(2)]),
+                    ..Default::default()
+                },
+            )
+            .await
+            .expect("failed to modify host");
+        storage.flush().await.expect("failed to flush storage");
+
+        let storage = load().await.expect("failed to reload storage");
+        assert_eq!(
+            storage
+                .get_host(HostId(1))
+                .await
+                .expect("failed to get host")
+                .shared_with,
+            vec![UserId(2)]
+        );
+        assert_eq!(user_hosts(&storage, 2).await, vec![0, 1]);
+        assert_eq!(user_hosts(&storage, 3).await, vec![0]);
+    }
+
+    #[tokio::test]
+    async fn test_host_reachability() {
+        let dir = test_dir();
+        let file = dir.path().join("data.json");
+        // A data file written before the reachability was recorded
+        fs::write(
+            &file,
+            r#"{
+                "version": "2",
+                "users": {},
+                "hosts": {
+                    "0": {
+                        "owner": null,
+                        "address": "192.168.0.2",
+                        "http_port": 47989,
+                        "pair_info": null,
+                        "cache": { "name": "Host", "mac": null }
+                    }
+                }
+            }"#,
+        )
+        .expect("failed to write storage file");
+
+        let load = || JsonStorage::load(file.clone(), Duration::from_secs(60), Duration::ZERO);
+
+        let storage = load().await.expect("failed to load storage");
+        assert!(
+            !storage
+                .get_host(HostId(0))
+                .await
+                .expect("failed to get host")
+                .reachability_probe
+        );
+        assert!(
+            storage
+                .get_host_reachability(HostId(0))
+                .await
+                .expect("failed to get reachability")
+                .is_empty()
+        );
+
+        let samples = vec![
+            StorageHostReachabilitySample {
+                timestamp: 100,
+                online: true,
+                latency_ms: Some(12),
+            },
+            StorageHostReachabilitySample {
+                timestamp: 160,
+                online: false,
+                latency_ms: None,
+            },
+        ];
+        storage
+            .set_host_reachability(HostId(0), samples.clone())
+            .await
+            .expect("failed to set reachability");
+        storage
+            .modify_host(
+                HostId(0),
+                StorageHostModify {
+                    reachability_probe: Some(true),
+                    ..Default::default()
+                },
+            )
+            .await
+            .expect("failed to modify host");
+        storage.flush().await.expect("failed to flush storage");
+
+        let storage = load().await.expect("failed to reload storage");
+        assert!(
+            storage
+                .get_host(HostId(0))
+                .await
+                .expect("failed to get host")
+                .reachability_probe
+        );
+        assert_eq!(
+            storage
+                .get_host_reachability(HostId(0))
+                .await
+                .expect("failed to get reachability"),
+            samples
+        );
+    }
+
+    #[tokio::test]
+    async fn test_invite_secret() {
+        let dir = test_dir();
+        let file = dir.path().join("data.json");
+
+        let load = || JsonStorage::load(file.clone(), Duration::from_secs(60), Duration::ZERO);
+
+        let storage = load().await.expect("failed to load storage");
+        assert_eq!(
+            storage
+                .get_invite_secret()
+                .await
+                .expect("failed to get invite secret"),
+            None
+        );
+
+        storage
+            .set_invite_secret([7; 32])
+            .await
+            .expect("failed to set invite secret");
+        storage.flush().await.expect("failed to flush storage");
+
+        let text = fs::read_to_string(&file).expect("failed to read storage file");
+        assert!(text.contains(&"07".repeat(32)));
+
+        let storage = load().await.expect("failed to reload storage");
+        assert_eq!(
+            storage
+                .get_invite_secret()
+                .await
+                .expect("failed to get invite secret"),
+            Some([7; 32])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_audit_log() {
+        let dir = test_dir();
+        let file = dir.path().join("data.json");
+
+        let load = || JsonStorage::load(file.clone(), Duration::from_secs(60), Duration::ZERO);
+
+        let entry = |app_id| AuditEntry {
+            time: UNIX_EPOCH + Duration::from_secs(1000 + app_id as u64),
+            actor: UserId(1),
+            action: AuditAction::StreamStarted {
+                room: "room".to_string(),
+                host: HostId(0),
+                app_id,
+                app_name: "Desktop".to_string(),
+            },
+        };
+
+        let storage = load().await.expect("failed to load storage");
+        for app_id in 0..3 {
+            storage
+                .add_audit_entry(entry(app_id), 2)
+                .await
+                .expect("failed to add audit entry");
+        }
+        storage.flush().await.expect("failed to flush storage");
+
+        let storage = load().await.expect("failed to reload storage");
+        let entries = storage
+            .list_audit_entries()
+            .await
+            .expect("failed to list audit entries");
+        assert_eq!(entries.len(), 2);
+        for (entry, app_id) in entries.into_iter().zip(1..) {
+            let expected = entry(app_id);
+            assert_eq!(entry.time, expected.time);
+            assert_eq!(entry.actor, expected.actor);
+            assert!(matches!(
+                entry.action,
+                AuditAction::StreamStarted { app_id: id, .. } if id == app_id
+            ));
+        }
+    }
 }
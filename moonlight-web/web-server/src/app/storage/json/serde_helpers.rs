@@ -116,3 +116,33 @@ pub mod hex_array {
             .map_err(|_| de::Error::custom(format!("invalid length: expected {N} bytes")))
     }
 }
+
+pub mod option_hex_array {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use crate::app::storage::json::serde_helpers::hex_array;
+
+    pub fn serialize<S, const N: usize>(
+        bytes: &Option<[u8; N]>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match bytes {
+            Some(bytes) => hex_array::serialize(bytes, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D, const N: usize>(deserializer: D) -> Result<Option<[u8; N]>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct Wrapper<const N: usize>(#[serde(with = "hex_array")] [u8; N]);
+
+        let wrapper = Option::<Wrapper<N>>::deserialize(deserializer)?;
+        Ok(wrapper.map(|Wrapper(bytes)| bytes))
+    }
+}
@@ -1,9 +1,11 @@
 use std::collections::HashMap;
 
+use common::api_bindings::RemoteUnpairResult;
 use log::error;
 use moonlight_common::mac::MacAddress;
 use pem::Pem;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 use crate::app::user::Role;
 
@@ -70,10 +72,18 @@ pub fn migrate_v1_to_v2(old: V1) -> V2 {
                         None
                     }
                 }),
+            pair_invalid: false,
             cache: V2HostCache {
                 name: old_host.cache.name.unwrap_or_else(|| "Unknown".to_string()),
                 mac: old_host.cache.mac,
             },
+            display_name: None,
+            https_port_override: None,
+            external_address: None,
+            hidden_app_ids: Vec::new(),
+            shared_with: Vec::new(),
+            reachability_probe: false,
+            reachability: Vec::new(),
         };
 
         v2_hosts.insert(id as u32, v2_host);
@@ -82,12 +92,15 @@ pub fn migrate_v1_to_v2(old: V1) -> V2 {
     V2 {
         users: Default::default(),
         hosts: v2_hosts,
+        invite_secret: None,
+        audit_log: Vec::new(),
+        pending_unpairs: Vec::new(),
     }
 }
 
 // -- V2
 
-use crate::app::storage::json::serde_helpers::{de_int_key, hex_array};
+use crate::app::storage::json::serde_helpers::{de_int_key, hex_array, option_hex_array};
 
 #[derive(Serialize, Deserialize)]
 pub struct V2 {
@@ -95,6 +108,17 @@ pub struct V2 {
     pub users: HashMap<u32, V2User>,
     #[serde(deserialize_with = "de_int_key")]
     pub hosts: HashMap<u32, V2Host>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "option_hex_array"
+    )]
+    pub invite_secret: Option<[u8; 32]>,
+    /// Oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub audit_log: Vec<V2AuditEntry>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pending_unpairs: Vec<V2PendingUnpair>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -103,6 +127,16 @@ pub struct V2User {
     pub name: String,
     pub password: Option<V2UserPassword>,
     pub client_unique_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub settings: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pair_device_name: Option<String>,
+    /// Seconds since the unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_login: Option<u64>,
+    /// Seconds since the unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_stream: Option<u64>,
 }
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct V2UserPassword {
@@ -118,7 +152,23 @@ pub struct V2Host {
     pub address: String,
     pub http_port: u16,
     pub pair_info: Option<V2HostPairInfo>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pair_invalid: bool,
     pub cache: V2HostCache,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub https_port_override: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub external_address: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hidden_app_ids: Vec<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub shared_with: Vec<u32>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub reachability_probe: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reachability: Vec<V2HostReachabilitySample>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -128,6 +178,56 @@ pub struct V2HostPairInfo {
     pub server_certificate: Pem,
 }
 
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct V2HostReachabilitySample {
+    pub timestamp: u64,
+    pub online: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct V2AuditEntry {
+    /// Seconds since the unix epoch
+    pub time: u64,
+    pub actor: u32,
+    pub action: V2AuditAction,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum V2AuditAction {
+    Impersonation {
+        user: u32,
+        method: String,
+        path: String,
+    },
+    RemoteUnpair {
+        host: u32,
+        address: String,
+        result: RemoteUnpairResult,
+    },
+    StreamStarted {
+        room: String,
+        host: u32,
+        app_id: u32,
+        app_name: String,
+    },
+    UsersExported {
+        include_hosts: bool,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct V2PendingUnpair {
+    pub host: u32,
+    pub address: String,
+    pub http_port: u16,
+    pub client_unique_id: String,
+    pub pair_info: V2HostPairInfo,
+    pub actor: u32,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct V2HostCache {
     pub name: String,
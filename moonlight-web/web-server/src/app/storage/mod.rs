@@ -1,16 +1,19 @@
-use std::{sync::Arc, time::Duration};
+use std::{sync::Arc, time::SystemTime};
 
 use async_trait::async_trait;
 use common::config::StorageConfig;
 use moonlight_common::mac::MacAddress;
 use pem::Pem;
+use serde_json::Value;
 
 use crate::app::{
     AppError,
-    auth::SessionToken,
-    host::HostId,
+    audit::AuditEntry,
+    auth::{SessionExpiration, SessionToken},
+    host::{AppId, HostId},
     password::StoragePassword,
     storage::json::JsonStorage,
+    unpair::RemoteUnpair,
     user::{Role, UserId},
 };
 
@@ -23,8 +26,14 @@ pub async fn create_storage(
         StorageConfig::Json {
             path,
             session_expiration_check_interval,
+            write_interval,
         } => {
-            let storage = JsonStorage::load(path.into(), session_expiration_check_interval).await?;
+            let storage = JsonStorage::load(
+                path.into(),
+                session_expiration_check_interval,
+                write_interval,
+            )
+            .await?;
 
             Ok(storage)
         }
@@ -41,6 +50,10 @@ pub struct StorageUser {
     pub password: Option<StoragePassword>,
     pub role: Role,
     pub client_unique_id: String,
+    /// The device name hosts show for the pairings of this user, None uses the default
+    pub pair_device_name: Option<String>,
+    pub last_login: Option<SystemTime>,
+    pub last_stream: Option<SystemTime>,
 }
 #[derive(Clone)]
 pub struct StorageUserAdd {
@@ -54,6 +67,9 @@ pub struct StorageUserModify {
     pub role: Option<Role>,
     pub password: Option<Option<StoragePassword>>,
     pub client_unique_id: Option<String>,
+    pub pair_device_name: Option<Option<String>>,
+    pub last_login: Option<SystemTime>,
+    pub last_stream: Option<SystemTime>,
 }
 
 #[derive(Clone)]
@@ -64,7 +80,21 @@ pub struct StorageHost {
     pub address: String,
     pub http_port: u16,
     pub pair_info: Option<StorageHostPairInfo>,
+    /// The host rejected the pair info, e.g. because it regenerated its certificate
+    pub pair_invalid: bool,
     pub cache: StorageHostCache,
+    /// Set by the user, this overrides the name reported by the host
+    pub display_name: Option<String>,
+    /// Set by the user, this overrides the https port reported by the host
+    pub https_port_override: Option<u16>,
+    /// Set by the user, this address is used instead of `address` to connect to the host
+    pub external_address: Option<String>,
+    /// Set by the owner or an admin, these apps can't be seen or started by other users
+    pub hidden_app_ids: Vec<AppId>,
+    /// Set by the owner or an admin, these users can use the host without owning it
+    pub shared_with: Vec<UserId>,
+    /// Set by an admin, the host is periodically queried in the background to record its reachability
+    pub reachability_probe: bool,
 }
 #[derive(Clone)]
 pub struct StorageHostAdd {
@@ -91,8 +121,22 @@ pub struct StorageHostModify {
     pub address: Option<String>,
     pub http_port: Option<u16>,
     pub pair_info: Option<Option<StorageHostPairInfo>>,
+    pub pair_invalid: Option<bool>,
     pub cache_name: Option<String>,
     pub cache_mac: Option<Option<MacAddress>>,
+    pub display_name: Option<Option<String>>,
+    pub https_port_override: Option<Option<u16>>,
+    pub external_address: Option<Option<String>>,
+    pub hidden_app_ids: Option<Vec<AppId>>,
+    pub shared_with: Option<Vec<UserId>>,
+    pub reachability_probe: Option<bool>,
+}
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StorageHostReachabilitySample {
+    /// Unix timestamp in seconds
+    pub timestamp: u64,
+    pub online: bool,
+    pub latency_ms: Option<u32>,
 }
 
 #[derive(Clone)]
@@ -112,6 +156,13 @@ pub trait Storage {
     async fn add_user(&self, user: StorageUserAdd) -> Result<StorageUser, AppError>;
     async fn modify_user(&self, user_id: UserId, user: StorageUserModify) -> Result<(), AppError>;
     async fn get_user(&self, user_id: UserId) -> Result<StorageUser, AppError>;
+    /// The default stream settings of the user, these are already validated
+    async fn get_user_settings(&self, user_id: UserId) -> Result<Option<Value>, AppError>;
+    async fn set_user_settings(
+        &self,
+        user_id: UserId,
+        settings: Option<Value>,
+    ) -> Result<(), AppError>;
     /// The returned tuple can contain a StorageUser if the Storage thinks it's more efficient to query all data directly
     async fn get_user_by_name(&self, name: &str)
     -> Result<(UserId, Option<StorageUser>), AppError>;
@@ -123,12 +174,13 @@ pub trait Storage {
     async fn create_session_token(
         &self,
         user_id: UserId,
-        expires_after: Duration,
+        expiration: SessionExpiration,
     ) -> Result<SessionToken, AppError>;
     async fn remove_session_token(&self, session: SessionToken) -> Result<(), AppError>;
     #[allow(dead_code)]
     async fn remove_all_user_session_tokens(&self, user_id: UserId) -> Result<(), AppError>;
-    /// The returned tuple can contain a StorageUser if the Storage thinks it's more efficient to query all data directly
+    /// The returned tuple can contain a StorageUser if the Storage thinks it's more efficient to query all data directly.
+    /// Counts as a use of the session, which extends sliding sessions and resets the idle timeout
     async fn get_user_by_session_token(
         &self,
         session: SessionToken,
@@ -138,12 +190,42 @@ pub trait Storage {
     async fn modify_host(&self, host_id: HostId, host: StorageHostModify) -> Result<(), AppError>;
     async fn get_host(&self, host_id: HostId) -> Result<StorageHost, AppError>;
     async fn remove_host(&self, host_id: HostId) -> Result<(), AppError>;
+    /// The recorded reachability samples of the host, oldest first
+    async fn get_host_reachability(
+        &self,
+        host_id: HostId,
+    ) -> Result<Vec<StorageHostReachabilitySample>, AppError>;
+    async fn set_host_reachability(
+        &self,
+        host_id: HostId,
+        samples: Vec<StorageHostReachabilitySample>,
+    ) -> Result<(), AppError>;
 
-    /// Returns all hosts that either have no owner (global) or have the specified user_id as an owner
+    /// Returns all hosts that either have no owner (global), have the specified user_id as an owner or are shared with it
     ///
     /// The returned tuple in the Vec can contain a StorageHost if the Storage thinks it's more efficient to query all data directly
     async fn list_user_hosts(
         &self,
         query: StorageQueryHosts,
     ) -> Result<Vec<(HostId, Option<StorageHost>)>, AppError>;
+    /// Returns all hosts regardless of the owner
+    ///
+    /// The returned tuple in the Vec can contain a StorageHost if the Storage thinks it's more efficient to query all data directly
+    async fn list_hosts(&self) -> Result<Vec<(HostId, Option<StorageHost>)>, AppError>;
+
+    /// The generated secret which signs invite links, None if it wasn't generated yet
+    async fn get_invite_secret(&self) -> Result<Option<[u8; 32]>, AppError>;
+    async fn set_invite_secret(&self, secret: [u8; 32]) -> Result<(), AppError>;
+
+    /// The audited actions, oldest first
+    async fn list_audit_entries(&self) -> Result<Vec<AuditEntry>, AppError>;
+    /// Drops the oldest entries when more than `capacity` entries are stored
+    async fn add_audit_entry(&self, entry: AuditEntry, capacity: usize) -> Result<(), AppError>;
+
+    /// The unpairs of hosts which were offline when they were deleted or unpaired
+    async fn list_pending_unpairs(&self) -> Result<Vec<RemoteUnpair>, AppError>;
+    async fn set_pending_unpairs(&self, unpairs: Vec<RemoteUnpair>) -> Result<(), AppError>;
+
+    /// Waits until all changes are persisted
+    async fn flush(&self) -> Result<(), AppError>;
 }
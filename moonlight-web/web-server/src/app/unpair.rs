@@ -0,0 +1,341 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use common::api_bindings::RemoteUnpairResult;
+use log::{info, warn};
+use moonlight_common::network::{
+    ApiError, ClientInfo, pair::host_unpair, request_client::RequestError,
+};
+use tokio::{sync::Mutex, time::sleep};
+use uuid::Uuid;
+
+use crate::app::{
+    AppError, AppInner, AppRef, MoonlightClient,
+    audit::{AuditAction, AuditEntry},
+    host::HostId,
+    host_address::{check_host_address, url_host},
+    storage::{Storage, StorageHost, StorageHostPairInfo},
+    user::{AuthenticatedUser, Role, UserId},
+};
+
+/// How often the unpairs of offline hosts are retried
+const PENDING_UNPAIR_RETRY_INTERVAL: Duration = Duration::from_mins(5);
+
+/// Everything needed to remove the pairing on the host after it was removed from the storage
+#[derive(Clone)]
+pub struct RemoteUnpair {
+    pub host_id: HostId,
+    pub address: String,
+    pub http_port: u16,
+    /// The unique id of the client the host was paired with
+    pub client_unique_id: String,
+    pub pair_info: StorageHostPairInfo,
+    /// The user who deleted or unpaired the host
+    pub actor: UserId,
+}
+
+impl RemoteUnpair {
+    /// None if the host isn't paired
+    pub fn new(host: &StorageHost, client_unique_id: String, actor: UserId) -> Option<Self> {
+        let pair_info = host.pair_info.clone()?;
+
+        Some(Self {
+            host_id: host.id,
+            address: host
+                .external_address
+                .clone()
+                .unwrap_or_else(|| host.address.clone()),
+            http_port: host.http_port,
+            client_unique_id,
+            pair_info,
+            actor,
+        })
+    }
+}
+
+/// Unpairs of hosts which were offline, retried by [retry_pending_unpairs].
+/// They're persisted, so the host software doesn't keep listing the client when the web server restarts in between.
+pub struct PendingUnpairs {
+    storage: Arc<dyn Storage + Send + Sync>,
+    /// Every change reads and writes all unpairs
+    lock: Mutex<()>,
+}
+
+impl PendingUnpairs {
+    pub fn new(storage: Arc<dyn Storage + Send + Sync>) -> Self {
+        Self {
+            storage,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Replaces an earlier unpair of the same host
+    pub async fn push(&self, unpair: RemoteUnpair) -> Result<(), AppError> {
+        let _guard = self.lock.lock().await;
+
+        let mut unpairs = self.storage.list_pending_unpairs().await?;
+        unpairs.retain(|pending| pending.host_id != unpair.host_id);
+        unpairs.push(unpair);
+
+        self.storage.set_pending_unpairs(unpairs).await
+    }
+
+    pub async fn take(&self, host_id: HostId) -> Result<Option<RemoteUnpair>, AppError> {
+        let _guard = self.lock.lock().await;
+
+        let mut unpairs = self.storage.list_pending_unpairs().await?;
+        let Some(index) = unpairs
+            .iter()
+            .position(|pending| pending.host_id == host_id)
+        else {
+            return Ok(None);
+        };
+        let unpair = unpairs.remove(index);
+
+        self.storage.set_pending_unpairs(unpairs).await?;
+
+        Ok(Some(unpair))
+    }
+
+    pub async fn take_all(&self) -> Result<Vec<RemoteUnpair>, AppError> {
+        let _guard = self.lock.lock().await;
+
+        let unpairs = self.storage.list_pending_unpairs().await?;
+        if !unpairs.is_empty() {
+            self.storage.set_pending_unpairs(Vec::new()).await?;
+        }
+
+        Ok(unpairs)
+    }
+}
+
+impl AppInner {
+    /// Removes the pairing on the host before it's removed from the storage.
+    /// The unpair is retried in the background if the host is offline, other errors are only logged.
+    pub(super) async fn unpair_remote(&self, unpair: RemoteUnpair) -> RemoteUnpairResult {
+        let result = self.try_unpair_remote(&unpair).await;
+
+        self.record_unpair(&unpair, result).await;
+
+        if matches!(result, RemoteUnpairResult::HostOffline) {
+            self.push_pending_unpair(unpair).await;
+        }
+
+        result
+    }
+
+    async fn push_pending_unpair(&self, unpair: RemoteUnpair) {
+        let host_id = unpair.host_id;
+
+        if let Err(err) = self.pending_unpairs.push(unpair).await {
+            warn!("[Unpair]: failed to store the pending unpair of host {host_id:?}: {err}");
+        }
+    }
+
+    /// Retries the pending unpair of a host, e.g. after it was deleted
+    pub(super) async fn retry_pending_unpair(
+        &self,
+        user: &mut AuthenticatedUser,
+        host_id: HostId,
+    ) -> Result<RemoteUnpairResult, AppError> {
+        let unpair = self
+            .pending_unpairs
+            .take(host_id)
+            .await?
+            .ok_or(AppError::HostNotFound)?;
+
+        if unpair.actor != user.id() && !matches!(user.role().await?, Role::Admin) {
+            self.pending_unpairs.push(unpair).await?;
+
+            return Err(AppError::Forbidden);
+        }
+
+        Ok(self.unpair_remote(unpair).await)
+    }
+
+    async fn try_unpair_remote(&self, unpair: &RemoteUnpair) -> RemoteUnpairResult {
+        match self.send_unpair(unpair).await {
+            Ok(()) => {
+                info!("[Unpair]: removed the pairing on host {:?}", unpair.host_id);
+
+                RemoteUnpairResult::Unpaired
+            }
+            Err(AppError::MoonlightApi(ApiError::RequestClient(err))) if err.is_connect() => {
+                warn!(
+                    "[Unpair]: host {:?} is offline, retrying the unpair later",
+                    unpair.host_id
+                );
+
+                RemoteUnpairResult::HostOffline
+            }
+            Err(err) => {
+                warn!(
+                    "[Unpair]: failed to remove the pairing on host {:?}, it must be removed in the host software: {err}",
+                    unpair.host_id
+                );
+
+                RemoteUnpairResult::Failed
+            }
+        }
+    }
+
+    async fn send_unpair(&self, unpair: &RemoteUnpair) -> Result<(), AppError> {
+        let address = if self.config.moonlight.block_private_host_ranges {
+            check_host_address(&unpair.address, unpair.http_port)
+                .await?
+                .to_string()
+        } else {
+            unpair.address.clone()
+        };
+
+        let mut client = MoonlightClient::with_certificates(
+            &unpair.pair_info.client_private_key,
+            &unpair.pair_info.client_certificate,
+            &unpair.pair_info.server_certificate,
+        )
+        .map_err(ApiError::RequestClient)?;
+
+        host_unpair(
+            &mut client,
+            &format!("{}:{}", url_host(&address), unpair.http_port),
+            ClientInfo {
+                unique_id: &unpair.client_unique_id,
+                uuid: Uuid::new_v4(),
+            },
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn record_unpair(&self, unpair: &RemoteUnpair, result: RemoteUnpairResult) {
+        self.audit_log
+            .record(AuditEntry {
+                time: SystemTime::now(),
+                actor: unpair.actor,
+                action: AuditAction::RemoteUnpair {
+                    host: unpair.host_id,
+                    address: unpair.address.clone(),
+                    result,
+                },
+            })
+            .await;
+    }
+}
+
+/// Periodically retries the unpairs of hosts which were offline
+pub(super) async fn retry_pending_unpairs(app: AppRef) {
+    loop {
+        sleep(PENDING_UNPAIR_RETRY_INTERVAL).await;
+
+        let Ok(app) = app.access() else {
+            return;
+        };
+
+        let unpairs = match app.pending_unpairs.take_all().await {
+            Ok(unpairs) => unpairs,
+            Err(err) => {
+                warn!("[Unpair]: failed to load the pending unpairs: {err}");
+                continue;
+            }
+        };
+
+        for unpair in unpairs {
+            // Only the final result is audited, the host being offline was already recorded
+            match app.try_unpair_remote(&unpair).await {
+                RemoteUnpairResult::HostOffline => app.push_pending_unpair(unpair).await,
+                result => app.record_unpair(&unpair, result).await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use moonlight_common::pair::generate_new_client;
+
+    use crate::{
+        app::{
+            host::HostId,
+            storage::{StorageHostPairInfo, json::JsonStorage},
+            unpair::{PendingUnpairs, RemoteUnpair},
+            user::UserId,
+        },
+        test_util::test_dir,
+    };
+
+    fn unpair(host_id: u32, client_unique_id: &str) -> RemoteUnpair {
+        let client = generate_new_client().expect("failed to generate client certificate");
+
+        RemoteUnpair {
+            host_id: HostId(host_id),
+            address: "127.0.0.1".to_string(),
+            http_port: 47989,
+            client_unique_id: client_unique_id.to_string(),
+            pair_info: StorageHostPairInfo {
+                client_private_key: client.private_key,
+                client_certificate: client.certificate.clone(),
+                server_certificate: client.certificate,
+            },
+            actor: UserId(1),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pending_unpairs() {
+        let dir = test_dir();
+        let file = dir.path().join("data.json");
+
+        let load = || JsonStorage::load(file.clone(), Duration::from_secs(60), Duration::ZERO);
+
+        let storage = load().await.expect("failed to load storage");
+        let pending = PendingUnpairs::new(storage.clone());
+
+        pending
+            .push(unpair(1, "first"))
+            .await
+            .expect("failed to push unpair");
+        pending
+            .push(unpair(2, "other"))
+            .await
+            .expect("failed to push unpair");
+        // A newer unpair of the same host replaces the old one
+        pending
+            .push(unpair(1, "second"))
+            .await
+            .expect("failed to push unpair");
+        storage.flush().await.expect("failed to flush storage");
+
+        // The unpairs are still pending after a restart
+        let pending = PendingUnpairs::new(load().await.expect("failed to reload storage"));
+
+        let taken = pending
+            .take(HostId(1))
+            .await
+            .expect("failed to take unpair")
+            .expect("no pending unpair");
+        assert_eq!(taken.client_unique_id, "second");
+        assert!(
+            pending
+                .take(HostId(1))
+                .await
+                .expect("failed to take unpair")
+                .is_none()
+        );
+
+        let rest = pending.take_all().await.expect("failed to take unpairs");
+        assert_eq!(rest.len(), 1);
+        assert_eq!(rest[0].host_id, HostId(2));
+        assert!(
+            pending
+                .take_all()
+                .await
+                .expect("failed to take unpairs")
+                .is_empty()
+        );
+    }
+}
@@ -1,27 +1,39 @@
 use std::{
     fmt::{Debug, Formatter},
     ops::{Deref, DerefMut},
-    time::Duration,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use common::api_bindings::{self, DetailedUser};
+use futures::future::join_all;
 use moonlight_common::network::{
     ApiError, ClientInfo, host_info,
     request_client::{RequestClient, RequestError},
 };
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::spawn;
 use uuid::Uuid;
 
 use crate::app::{
     AppError, AppRef, MoonlightClient,
-    auth::{SessionToken, UserAuth},
+    auth::{SessionExpiration, SessionToken, UserAuth},
     host::{Host, HostId},
+    host_address::{HostAddress, check_host_address, url_host},
     password::StoragePassword,
+    settings::{merge_settings, validate_user_settings},
     storage::{
-        StorageHostAdd, StorageHostCache, StorageQueryHosts, StorageUser, StorageUserModify,
+        StorageHostAdd, StorageHostCache, StorageHostModify, StorageQueryHosts, StorageUser,
+        StorageUserModify,
     },
+    unpair::RemoteUnpair,
 };
 
+/// The longest device name hosts show for the pairings of a user, in characters
+const MAX_PAIR_DEVICE_NAME_LENGTH: usize = 64;
+/// The last login is only stored again after this time because sessions are checked on every request
+const LAST_LOGIN_UPDATE_INTERVAL: Duration = Duration::from_mins(10);
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
 pub enum Role {
     User,
@@ -51,6 +63,51 @@ impl From<api_bindings::UserRole> for Role {
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct UserId(pub u32);
 
+/// Validates a device name from a request, an empty name resets it to the default
+pub fn parse_pair_device_name(name: Option<String>) -> Result<Option<String>, AppError> {
+    let Some(name) = name else {
+        return Ok(None);
+    };
+
+    let name = name.trim();
+    if name.is_empty() {
+        return Ok(None);
+    }
+
+    if name.chars().count() > MAX_PAIR_DEVICE_NAME_LENGTH {
+        return Err(AppError::PairDeviceNameInvalid(format!(
+            "it's longer than {MAX_PAIR_DEVICE_NAME_LENGTH} characters"
+        )));
+    }
+    if name.chars().any(char::is_control) {
+        return Err(AppError::PairDeviceNameInvalid(
+            "it contains characters which can't be printed".to_string(),
+        ));
+    }
+
+    Ok(Some(name.to_string()))
+}
+
+/// The device name of the user, by default the configured name with the user name so the hosts can tell the users apart
+fn pair_device_name(default_name: &str, user: &StorageUser) -> String {
+    user.pair_device_name
+        .clone()
+        .unwrap_or_else(|| format!("{default_name} ({})", user.name))
+}
+
+/// If the user neither logged in nor started a stream since the time
+fn inactive_since(user: &StorageUser, since: SystemTime) -> bool {
+    user.last_login
+        .max(user.last_stream)
+        .is_none_or(|last_seen| last_seen < since)
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|time| time.as_millis() as u64)
+        .unwrap_or_default()
+}
+
 #[derive(Clone)]
 pub struct User {
     pub(super) app: AppRef,
@@ -84,6 +141,12 @@ impl User {
         Ok(user)
     }
 
+    pub async fn name(&mut self) -> Result<String, AppError> {
+        let storage = self.storage_user().await?;
+
+        Ok(storage.name)
+    }
+
     pub async fn is_default_user(&self) -> Result<bool, AppError> {
         let app = self.app.access()?;
 
@@ -103,15 +166,96 @@ impl User {
     pub async fn detailed_user_no_auth(&mut self) -> Result<DetailedUser, AppError> {
         let storage = self.storage_user().await?;
 
+        let settings = self.settings_no_auth().await?;
+        let (default_settings, pair_device_name) = {
+            let app = self.app.access()?;
+            (
+                merge_settings(app.config.default_settings.as_ref(), settings.as_ref()),
+                pair_device_name(&app.config.moonlight.pair_device_name, &storage),
+            )
+        };
+
         Ok(DetailedUser {
             id: self.id.0,
             is_default_user: self.is_default_user().await?,
             name: storage.name,
             role: storage.role.into(),
             client_unique_id: storage.client_unique_id,
+            pair_device_name,
+            default_settings: default_settings.map(Into::into),
+            last_login: storage.last_login.map(unix_millis),
+            last_stream: storage.last_stream.map(unix_millis),
         })
     }
 
+    pub async fn is_inactive_since(&mut self, since: SystemTime) -> Result<bool, AppError> {
+        let storage = self.storage_user().await?;
+
+        Ok(inactive_since(&storage, since))
+    }
+
+    /// Remembers when the user logged in or used a session, so admins can find abandoned accounts
+    pub async fn record_login(&mut self) -> Result<(), AppError> {
+        let storage = self.storage_user().await?;
+
+        let now = SystemTime::now();
+        if let Some(last_login) = storage.last_login
+            && now
+                .duration_since(last_login)
+                .is_ok_and(|elapsed| elapsed < LAST_LOGIN_UPDATE_INTERVAL)
+        {
+            return Ok(());
+        }
+
+        self.modify_activity(StorageUserModify {
+            last_login: Some(now),
+            ..Default::default()
+        })
+        .await
+    }
+    pub async fn record_stream(&mut self) -> Result<(), AppError> {
+        self.modify_activity(StorageUserModify {
+            last_stream: Some(SystemTime::now()),
+            ..Default::default()
+        })
+        .await
+    }
+    async fn modify_activity(&mut self, modify: StorageUserModify) -> Result<(), AppError> {
+        let app = self.app.access()?;
+
+        self.cache_storage = None;
+
+        app.storage.modify_user(self.id, modify).await
+    }
+
+    /// The device name the hosts show for the pairings of this user
+    pub async fn pair_device_name(&mut self) -> Result<String, AppError> {
+        let storage = self.storage_user().await?;
+
+        let app = self.app.access()?;
+
+        Ok(pair_device_name(
+            &app.config.moonlight.pair_device_name,
+            &storage,
+        ))
+    }
+
+    pub async fn settings(
+        &self,
+        requesting_user: &mut AuthenticatedUser,
+    ) -> Result<Option<Value>, AppError> {
+        if requesting_user.role().await? == Role::Admin || self.id() == requesting_user.id() {
+            self.settings_no_auth().await
+        } else {
+            Err(AppError::Forbidden)
+        }
+    }
+    pub async fn settings_no_auth(&self) -> Result<Option<Value>, AppError> {
+        let app = self.app.access()?;
+
+        app.storage.get_user_settings(self.id).await
+    }
+
     pub async fn modify(&mut self, _: &Admin, modify: StorageUserModify) -> Result<(), AppError> {
         let app = self.app.access()?;
 
@@ -121,19 +265,67 @@ impl User {
 
         Ok(())
     }
-    pub async fn delete(self, _: &Admin) -> Result<(), AppError> {
+    pub async fn delete(mut self, admin: &Admin) -> Result<(), AppError> {
+        let client_unique_id = self.storage_user().await?.client_unique_id;
+
         let app = self.app.access()?;
 
+        // The hosts of the user were paired by them, so their pairing is removed on the hosts.
+        // This is best effort, the user is deleted even if a host couldn't be unpaired
+        let mut unpairs = Vec::new();
+        for (host_id, host) in app.storage.list_hosts().await? {
+            let host = match host {
+                Some(host) => host,
+                None => app.storage.get_host(host_id).await?,
+            };
+            if host.owner != Some(self.id) {
+                continue;
+            }
+
+            let Some(unpair) = RemoteUnpair::new(&host, client_unique_id.clone(), admin.id())
+            else {
+                continue;
+            };
+
+            unpairs.push(unpair);
+            app.storage
+                .modify_host(
+                    host_id,
+                    StorageHostModify {
+                        pair_info: Some(None),
+                        ..Default::default()
+                    },
+                )
+                .await?;
+        }
+
         app.storage.remove_user(self.id).await?;
 
+        // Offline hosts take until the request times out, so the hosts are unpaired at the same time in the background
+        if !unpairs.is_empty() {
+            let app_ref = self.app.clone();
+            spawn(async move {
+                let Ok(app) = app_ref.access() else {
+                    return;
+                };
+
+                join_all(unpairs.into_iter().map(|unpair| app.unpair_remote(unpair))).await;
+            });
+        }
+
+        app.app_image_cache.remove_user(self.id).await;
+        app.abort_app_image_prefetches(|user_id, _| user_id != self.id)
+            .await;
+
         Ok(())
     }
 
     pub async fn authenticate(mut self, auth: &UserAuth) -> Result<AuthenticatedUser, AppError> {
         match auth {
-            UserAuth::None if self.is_default_user().await? => {
-                Ok(AuthenticatedUser { inner: self })
-            }
+            UserAuth::None if self.is_default_user().await? => Ok(AuthenticatedUser {
+                inner: self,
+                impersonated: false,
+            }),
             UserAuth::UserPassword { username, password } => {
                 let storage = self.storage_user().await?;
 
@@ -145,7 +337,10 @@ impl User {
                 if let Some(storage_password) = storage.password
                     && storage_password.verify(password)?
                 {
-                    Ok(AuthenticatedUser { inner: self })
+                    Ok(AuthenticatedUser {
+                        inner: self,
+                        impersonated: false,
+                    })
                 } else {
                     Err(AppError::CredentialsWrong)
                 }
@@ -161,7 +356,10 @@ impl User {
 
                 self.cache_storage = self.cache_storage.or(user);
 
-                Ok(AuthenticatedUser { inner: self })
+                Ok(AuthenticatedUser {
+                    inner: self,
+                    impersonated: false,
+                })
             }
             UserAuth::ForwardedHeaders { username } => {
                 let app = self.app.access()?;
@@ -172,7 +370,10 @@ impl User {
 
                 let storage = self.storage_user().await?;
                 if storage.name.as_str() == username.as_str() {
-                    Ok(AuthenticatedUser { inner: self })
+                    Ok(AuthenticatedUser {
+                        inner: self,
+                        impersonated: false,
+                    })
                 } else {
                     Err(AppError::Forbidden)
                 }
@@ -185,6 +386,8 @@ impl User {
 #[derive(Clone)]
 pub struct AuthenticatedUser {
     pub(super) inner: User,
+    /// An admin acts as this user, see [Impersonation]
+    pub(super) impersonated: bool,
 }
 
 impl Deref for AuthenticatedUser {
@@ -201,6 +404,10 @@ impl DerefMut for AuthenticatedUser {
 }
 
 impl AuthenticatedUser {
+    pub fn is_impersonated(&self) -> bool {
+        self.impersonated
+    }
+
     pub async fn detailed_user(&mut self) -> Result<DetailedUser, AppError> {
         self.detailed_user_no_auth().await
     }
@@ -229,7 +436,40 @@ impl AuthenticatedUser {
         Ok(())
     }
 
-    pub async fn new_session(&self, expiration: Duration) -> Result<SessionToken, AppError> {
+    /// Changes the device name of the pairings made from now on, None resets it to the default
+    pub async fn set_pair_device_name(&mut self, name: Option<String>) -> Result<(), AppError> {
+        let app = self.app.access()?;
+
+        self.cache_storage = None;
+
+        app.storage
+            .modify_user(
+                self.id,
+                StorageUserModify {
+                    pair_device_name: Some(name),
+                    ..Default::default()
+                },
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Replaces the default stream settings of this user, `None` resets them to the global defaults
+    pub async fn set_settings(&self, settings: Option<Value>) -> Result<(), AppError> {
+        if let Some(settings) = &settings {
+            validate_user_settings(settings)?;
+        }
+
+        let app = self.app.access()?;
+
+        app.storage.set_user_settings(self.id, settings).await
+    }
+
+    pub async fn new_session(
+        &self,
+        expiration: SessionExpiration,
+    ) -> Result<SessionToken, AppError> {
         let app = self.app.access()?;
 
         let token = app
@@ -270,7 +510,10 @@ impl AuthenticatedUser {
 
         let host = app.storage.get_host(host_id).await?;
 
-        if host.owner.is_none() || host.owner == Some(self.id) {
+        if host.owner.is_none()
+            || host.owner == Some(self.id)
+            || host.shared_with.contains(&self.id)
+        {
             Ok(Host {
                 app: self.app.clone(),
                 id: host.id,
@@ -282,17 +525,29 @@ impl AuthenticatedUser {
         }
     }
 
-    pub async fn host_add(&mut self, address: String, http_port: u16) -> Result<Host, AppError> {
+    pub async fn host_add(
+        &mut self,
+        address: HostAddress,
+        http_port: u16,
+    ) -> Result<Host, AppError> {
         let app = self.app.access()?;
+        let address = address.to_string();
 
         let unique_id = self.host_unique_id().await?;
 
+        // The address is stored, but the request goes to the checked ip
+        let connect_address = if app.config.moonlight.block_private_host_ranges {
+            check_host_address(&address, http_port).await?.to_string()
+        } else {
+            address.clone()
+        };
+
         let mut client = MoonlightClient::with_defaults().map_err(ApiError::RequestClient)?;
 
         let info = match host_info(
             &mut client,
             false,
-            &format!("{}:{}", address, http_port),
+            &format!("{}:{}", url_host(&connect_address), http_port),
             Some(ClientInfo {
                 uuid: Uuid::new_v4(),
                 unique_id: &unique_id,
@@ -366,3 +621,86 @@ impl Deref for Admin {
         &self.0
     }
 }
+
+/// An admin acting as another user, created by [crate::app::App::impersonate] after the admin was checked
+#[derive(Debug, Clone, Copy)]
+pub struct Impersonation {
+    pub(super) admin: UserId,
+    pub(super) user: UserId,
+}
+
+impl Impersonation {
+    pub fn user(&self) -> UserId {
+        self.user
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use crate::app::{
+        AppError,
+        storage::StorageUser,
+        user::{Role, UserId, inactive_since, pair_device_name, parse_pair_device_name},
+    };
+
+    #[test]
+    fn test_pair_device_name() {
+        let mut user = StorageUser {
+            id: UserId(1),
+            name: "alice".to_string(),
+            password: None,
+            role: Role::User,
+            client_unique_id: "alice".to_string(),
+            pair_device_name: None,
+            last_login: None,
+            last_stream: None,
+        };
+        assert_eq!(pair_device_name("roth", &user), "roth (alice)");
+
+        user.pair_device_name = parse_pair_device_name(Some("  Living Room ".to_string()))
+            .expect("valid name was rejected");
+        assert_eq!(pair_device_name("roth", &user), "Living Room");
+
+        // An empty name resets it to the default
+        assert_eq!(
+            parse_pair_device_name(Some(" ".to_string())).ok(),
+            Some(None)
+        );
+        assert!(matches!(
+            parse_pair_device_name(Some("a".repeat(65))),
+            Err(AppError::PairDeviceNameInvalid(_))
+        ));
+        assert!(matches!(
+            parse_pair_device_name(Some("tab\tname".to_string())),
+            Err(AppError::PairDeviceNameInvalid(_))
+        ));
+    }
+
+    #[test]
+    fn test_inactive_since() {
+        let now = SystemTime::now();
+        let since = now - Duration::from_hours(24 * 30);
+
+        let mut user = StorageUser {
+            id: UserId(1),
+            name: "alice".to_string(),
+            password: None,
+            role: Role::User,
+            client_unique_id: "alice".to_string(),
+            pair_device_name: None,
+            last_login: None,
+            last_stream: None,
+        };
+        // Users which never logged in are inactive
+        assert!(inactive_since(&user, since));
+
+        user.last_login = Some(now - Duration::from_hours(24 * 60));
+        assert!(inactive_since(&user, since));
+
+        // A recent stream counts as activity too
+        user.last_stream = Some(now - Duration::from_hours(24));
+        assert!(!inactive_since(&user, since));
+    }
+}
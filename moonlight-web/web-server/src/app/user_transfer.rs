@@ -0,0 +1,809 @@
+//! Exports the users and their hosts so they can be imported on another server,
+//! e.g. when moving to another storage or merging two servers.
+
+use std::{collections::HashMap, mem, time::SystemTime, vec};
+
+use common::api_bindings::{
+    ExportedHost, ExportedPairInfo, ExportedPassword, ExportedUser, ImportHostResult,
+    ImportUserResult, UserImportConflict, UsersExportHeader,
+};
+use log::{info, warn};
+use moonlight_common::mac::MacAddress;
+
+use crate::app::{
+    App, AppError, AppRef,
+    audit::{AuditAction, AuditEntry},
+    host::HostId,
+    password::StoragePassword,
+    storage::{
+        Either, StorageHost, StorageHostAdd, StorageHostCache, StorageHostPairInfo, StorageUser,
+        StorageUserAdd, StorageUserModify,
+    },
+    user::{Admin, UserId},
+};
+
+/// Increased when the lines of the export change in a way older servers can't import
+pub const USER_EXPORT_VERSION: u32 = 1;
+
+/// How many names are tried when renaming a user, e.g. `alice-2` to `alice-100`
+const MAX_RENAME_ATTEMPTS: usize = 100;
+
+/// Longer lines of an import are reported as malformed instead of being buffered
+pub const MAX_IMPORT_LINE_LENGTH: usize = 1024 * 1024;
+
+/// The line number starting at 1 with the user or the reason the line is malformed
+pub type UserImportRow = (u32, Result<ExportedUser, String>);
+
+/// Parses the lines of an import while they are received, so only the current line is buffered.
+/// The header line of an export and empty lines are skipped.
+#[derive(Default)]
+pub struct UserImportParser {
+    buffer: Vec<u8>,
+    line: u32,
+    /// The rest of the current line is dropped
+    too_long: bool,
+    any_rows: bool,
+}
+
+impl UserImportParser {
+    /// Returns the rows of the lines which were completed by these bytes
+    pub fn push(&mut self, mut bytes: &[u8]) -> Vec<UserImportRow> {
+        let mut rows = Vec::new();
+
+        while let Some(end) = bytes.iter().position(|byte| *byte == b'\n') {
+            self.append(&bytes[..end], &mut rows);
+            self.end_line(&mut rows);
+
+            bytes = &bytes[end + 1..];
+        }
+        self.append(bytes, &mut rows);
+
+        rows
+    }
+
+    /// The row of the last line if the import doesn't end with a new line
+    pub fn finish(mut self) -> Vec<UserImportRow> {
+        let mut rows = Vec::new();
+        if !self.buffer.is_empty() {
+            self.end_line(&mut rows);
+        }
+
+        rows
+    }
+
+    fn append(&mut self, bytes: &[u8], rows: &mut Vec<UserImportRow>) {
+        if self.too_long {
+            return;
+        }
+
+        if self.buffer.len() + bytes.len() > MAX_IMPORT_LINE_LENGTH {
+            self.too_long = true;
+            self.any_rows = true;
+            self.buffer = Vec::new();
+
+            rows.push((
+                self.line + 1,
+                Err(format!(
+                    "the line is longer than {MAX_IMPORT_LINE_LENGTH} bytes"
+                )),
+            ));
+            return;
+        }
+
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    fn end_line(&mut self, rows: &mut Vec<UserImportRow>) {
+        self.line += 1;
+
+        let buffer = mem::take(&mut self.buffer);
+        if mem::take(&mut self.too_long) {
+            return;
+        }
+
+        let line = match std::str::from_utf8(&buffer) {
+            Ok(line) => line.trim(),
+            Err(err) => {
+                self.any_rows = true;
+                rows.push((self.line, Err(format!("malformed line: {err}"))));
+                return;
+            }
+        };
+        if line.is_empty() {
+            return;
+        }
+
+        let row = match serde_json::from_str::<ExportedUser>(line) {
+            Ok(user) => Ok(user),
+            // The header is only valid as the first line
+            Err(_) if !self.any_rows && is_export_header(line) => return,
+            Err(err) => Err(format!("malformed line: {err}")),
+        };
+
+        self.any_rows = true;
+        rows.push((self.line, row));
+    }
+}
+
+fn is_export_header(line: &str) -> bool {
+    serde_json::from_str::<UsersExportHeader>(line).is_ok()
+}
+
+fn export_password(password: &StoragePassword) -> ExportedPassword {
+    ExportedPassword {
+        salt: hex::encode(password.salt),
+        hash: hex::encode(password.hash),
+    }
+}
+
+fn import_password(password: &ExportedPassword) -> Result<StoragePassword, AppError> {
+    let invalid = || AppError::UserImportInvalid("the password hash is invalid".to_string());
+
+    let salt = hex::decode(&password.salt).map_err(|_| invalid())?;
+    let hash = hex::decode(&password.hash).map_err(|_| invalid())?;
+
+    Ok(StoragePassword {
+        salt: salt.try_into().map_err(|_| invalid())?,
+        hash: hash.try_into().map_err(|_| invalid())?,
+    })
+}
+
+fn export_host(host: StorageHost, include_secrets: bool) -> ExportedHost {
+    ExportedHost {
+        name: host.cache.name,
+        address: host.address,
+        http_port: host.http_port,
+        mac: host.cache.mac.map(|mac| mac.to_string()),
+        pair_info: host
+            .pair_info
+            .filter(|_| include_secrets)
+            .map(|pair_info| ExportedPairInfo {
+                client_private_key: pem::encode(&pair_info.client_private_key),
+                client_certificate: pem::encode(&pair_info.client_certificate),
+                server_certificate: pem::encode(&pair_info.server_certificate),
+            }),
+    }
+}
+
+fn import_pair_info(pair_info: &ExportedPairInfo) -> Result<StorageHostPairInfo, AppError> {
+    let parse = |text: &str| {
+        pem::parse(text).map_err(|err| {
+            AppError::UserImportInvalid(format!("the pair info of a host is invalid: {err}"))
+        })
+    };
+
+    Ok(StorageHostPairInfo {
+        client_private_key: parse(&pair_info.client_private_key)?,
+        client_certificate: parse(&pair_info.client_certificate)?,
+        server_certificate: parse(&pair_info.server_certificate)?,
+    })
+}
+
+/// The users of an export, every user is loaded from the storage when it's exported
+pub struct UsersExport {
+    app: AppRef,
+    include_secrets: bool,
+    hosts: HashMap<UserId, Vec<ExportedHost>>,
+    users: Either<vec::IntoIter<UserId>, vec::IntoIter<StorageUser>>,
+}
+
+impl UsersExport {
+    /// None after the last user
+    pub async fn next(&mut self) -> Result<Option<ExportedUser>, AppError> {
+        let user = match &mut self.users {
+            Either::Left(user_ids) => loop {
+                let Some(user_id) = user_ids.next() else {
+                    return Ok(None);
+                };
+
+                match self.app.access()?.storage.get_user(user_id).await {
+                    Ok(user) => break user,
+                    // Deleted while exporting
+                    Err(AppError::UserNotFound) => continue,
+                    Err(err) => return Err(err),
+                }
+            },
+            Either::Right(users) => match users.next() {
+                Some(user) => user,
+                None => return Ok(None),
+            },
+        };
+
+        Ok(Some(ExportedUser {
+            id: user.id.0,
+            name: user.name,
+            role: user.role.into(),
+            client_unique_id: user.client_unique_id,
+            password: user
+                .password
+                .as_ref()
+                .filter(|_| self.include_secrets)
+                .map(export_password),
+            hosts: self.hosts.remove(&user.id).unwrap_or_default(),
+        }))
+    }
+}
+
+impl App {
+    /// admin: The admin that tries to do this action
+    ///
+    /// The passwords and the pair info are only exported with include_secrets, which is audited
+    pub async fn export_users(
+        &self,
+        admin: &Admin,
+        include_secrets: bool,
+        include_hosts: bool,
+    ) -> Result<UsersExport, AppError> {
+        let mut hosts = HashMap::<UserId, Vec<ExportedHost>>::new();
+        if include_hosts {
+            for host in self.all_storage_hosts().await? {
+                if let Some(owner) = host.owner {
+                    hosts
+                        .entry(owner)
+                        .or_default()
+                        .push(export_host(host, include_secrets));
+                }
+            }
+        }
+
+        let users = match self.inner.storage.list_users().await? {
+            Either::Left(user_ids) => Either::Left(user_ids.into_iter()),
+            Either::Right(users) => Either::Right(users.into_iter()),
+        };
+
+        if include_secrets {
+            self.inner
+                .audit_log
+                .record(AuditEntry {
+                    time: SystemTime::now(),
+                    actor: admin.id(),
+                    action: AuditAction::UsersExported { include_hosts },
+                })
+                .await;
+        }
+
+        Ok(UsersExport {
+            app: self.new_ref(),
+            include_secrets,
+            hosts,
+            users,
+        })
+    }
+
+    /// admin: The admin that tries to do this action
+    ///
+    /// Returns the result of the user and of every host, the hosts are only imported with include_hosts
+    pub async fn import_user(
+        &self,
+        admin: &Admin,
+        user: &ExportedUser,
+        conflict: UserImportConflict,
+        include_hosts: bool,
+    ) -> Result<(ImportUserResult, Vec<ImportHostResult>), AppError> {
+        if user.name.is_empty() {
+            return Err(AppError::NameEmpty);
+        }
+        let password = user.password.as_ref().map(import_password).transpose()?;
+
+        let existing = match self.inner.storage.get_user_by_name(&user.name).await {
+            Ok((user_id, _)) => Some(user_id),
+            Err(AppError::UserNotFound) => None,
+            Err(err) => return Err(err),
+        };
+
+        let (user_id, result) = match (existing, conflict) {
+            (None, _) => {
+                let user_id = self
+                    .import_new_user(user, user.name.clone(), password)
+                    .await?;
+
+                (user_id, ImportUserResult::Created { user_id: user_id.0 })
+            }
+            (Some(user_id), UserImportConflict::Skip) => {
+                return Ok((
+                    ImportUserResult::Skipped {
+                        reason: format!("the user already exists as {}", user_id.0),
+                    },
+                    Vec::new(),
+                ));
+            }
+            (Some(user_id), UserImportConflict::Overwrite) => {
+                // The admin could lose the admin role otherwise
+                if user_id == admin.id() {
+                    return Ok((
+                        ImportUserResult::Skipped {
+                            reason: "the importing admin can't be overwritten".to_string(),
+                        },
+                        Vec::new(),
+                    ));
+                }
+
+                self.inner
+                    .storage
+                    .modify_user(
+                        user_id,
+                        StorageUserModify {
+                            role: Some(user.role.into()),
+                            client_unique_id: Some(user.client_unique_id.clone()),
+                            // Users without an exported password keep their password
+                            password: password.map(Some),
+                            ..Default::default()
+                        },
+                    )
+                    .await?;
+
+                (
+                    user_id,
+                    ImportUserResult::Overwritten { user_id: user_id.0 },
+                )
+            }
+            (Some(_), UserImportConflict::Rename) => {
+                let name = self.free_user_name(&user.name).await?;
+                let user_id = self.import_new_user(user, name.clone(), password).await?;
+
+                (
+                    user_id,
+                    ImportUserResult::Renamed {
+                        user_id: user_id.0,
+                        name,
+                    },
+                )
+            }
+        };
+
+        info!("Imported user {} as {user_id:?}", user.name);
+
+        let mut hosts = Vec::new();
+        if include_hosts && !user.hosts.is_empty() {
+            let mut existing_hosts = self
+                .all_storage_hosts()
+                .await?
+                .into_iter()
+                .filter(|host| host.owner == Some(user_id))
+                .map(|host| (host.id, host.address, host.http_port))
+                .collect::<Vec<_>>();
+
+            for host in &user.hosts {
+                let result = match self.import_user_host(user_id, host, &existing_hosts).await {
+                    Ok(result) => result,
+                    Err(err) => {
+                        warn!(
+                            "Failed to import host {} of {}: {err}",
+                            host.name, user.name
+                        );
+                        ImportHostResult::Failed {
+                            error: err.to_string(),
+                        }
+                    }
+                };
+
+                if let ImportHostResult::Imported { host_id, .. } = &result {
+                    existing_hosts.push((HostId(*host_id), host.address.clone(), host.http_port));
+                }
+                hosts.push(result);
+            }
+        }
+
+        Ok((result, hosts))
+    }
+
+    async fn import_new_user(
+        &self,
+        user: &ExportedUser,
+        name: String,
+        password: Option<StoragePassword>,
+    ) -> Result<UserId, AppError> {
+        let user = self
+            .add_user_no_auth(StorageUserAdd {
+                role: user.role.into(),
+                name,
+                password,
+                client_unique_id: user.client_unique_id.clone(),
+            })
+            .await?;
+
+        Ok(user.id())
+    }
+
+    async fn free_user_name(&self, name: &str) -> Result<String, AppError> {
+        for i in 2..=MAX_RENAME_ATTEMPTS {
+            let candidate = format!("{name}-{i}");
+
+            match self.inner.storage.get_user_by_name(&candidate).await {
+                Ok(_) => continue,
+                Err(AppError::UserNotFound) => return Ok(candidate),
+                Err(err) => return Err(err),
+            }
+        }
+
+        Err(AppError::UserAlreadyExists)
+    }
+
+    async fn import_user_host(
+        &self,
+        owner: UserId,
+        host: &ExportedHost,
+        existing_hosts: &[(HostId, String, u16)],
+    ) -> Result<ImportHostResult, AppError> {
+        if let Some((host_id, _, _)) = existing_hosts.iter().find(|(_, address, http_port)| {
+            *address == host.address && *http_port == host.http_port
+        }) {
+            return Ok(ImportHostResult::Skipped {
+                reason: format!("duplicate of host {}", host_id.0),
+            });
+        }
+
+        let pair_info = host.pair_info.as_ref().map(import_pair_info).transpose()?;
+        let paired = pair_info.is_some();
+
+        let storage_host = self
+            .inner
+            .storage
+            .add_host(StorageHostAdd {
+                owner: Some(owner),
+                address: host.address.clone(),
+                http_port: host.http_port,
+                pair_info,
+                cache: StorageHostCache {
+                    name: host.name.clone(),
+                    mac: host
+                        .mac
+                        .as_deref()
+                        .and_then(|mac| mac.parse::<MacAddress>().ok()),
+                },
+            })
+            .await?;
+
+        Ok(ImportHostResult::Imported {
+            host_id: storage_host.id.0,
+            paired,
+        })
+    }
+
+    async fn all_storage_hosts(&self) -> Result<Vec<StorageHost>, AppError> {
+        let mut hosts = Vec::new();
+        for (host_id, host) in self.inner.storage.list_hosts().await? {
+            hosts.push(match host {
+                Some(host) => host,
+                None => self.inner.storage.get_host(host_id).await?,
+            });
+        }
+
+        Ok(hosts)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use common::api_bindings::{
+        ExportedHost, ExportedPairInfo, ExportedPassword, ExportedUser, ImportHostResult,
+        ImportUserResult, UserImportConflict, UserRole,
+    };
+    use tempfile::TempDir;
+
+    use crate::{
+        app::{
+            App, AppError,
+            audit::AuditAction,
+            auth::UserAuth,
+            password::StoragePassword,
+            storage::StorageUserAdd,
+            user::{Admin, Role},
+            user_transfer::{MAX_IMPORT_LINE_LENGTH, UserImportParser, UsersExport},
+        },
+        test_util::{test_config, test_dir},
+    };
+
+    /// The directory holds the data of the app and has to be kept until the test ends
+    async fn create_app() -> (App, Admin, TempDir) {
+        let dir = test_dir();
+        let config = test_config(dir.path());
+        let app = App::new(config).await.expect("failed to create app");
+
+        let admin = app
+            .try_add_first_login("admin".to_string(), "password".to_string())
+            .await
+            .expect("failed to add admin")
+            .into_admin()
+            .await
+            .expect("the first user isn't an admin");
+        app.add_user(
+            &admin,
+            StorageUserAdd {
+                role: Role::User,
+                name: "alice".to_string(),
+                password: Some(StoragePassword::new("password").expect("invalid password")),
+                client_unique_id: "alice".to_string(),
+            },
+        )
+        .await
+        .expect("failed to add user");
+
+        (app, admin, dir)
+    }
+
+    fn exported_user(name: &str, role: UserRole) -> ExportedUser {
+        ExportedUser {
+            id: 100,
+            name: name.to_string(),
+            role,
+            client_unique_id: name.to_string(),
+            password: None,
+            hosts: Vec::new(),
+        }
+    }
+
+    async fn collect(mut export: UsersExport) -> Vec<ExportedUser> {
+        let mut users = Vec::new();
+        while let Some(user) = export.next().await.expect("failed to export user") {
+            users.push(user);
+        }
+
+        users
+    }
+
+    fn password_auth(name: &str) -> UserAuth {
+        UserAuth::UserPassword {
+            username: name.to_string(),
+            password: "password".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_parse_user_import() {
+        let text = "{\"version\":1,\"include_secrets\":false,\"include_hosts\":false}\n\
+            {\"id\":1,\"name\":\"alice\",\"role\":\"User\",\"client_unique_id\":\"alice\"}\n\
+            \n\
+            {\"id\":2,\"name\":\"bob\"\n\
+            {\"id\":3,\"role\":\"User\",\"client_unique_id\":\"carol\"}\n\
+            {\"version\":1,\"include_secrets\":false,\"include_hosts\":false}\n";
+
+        // The lines are split across the received chunks
+        let mut parser = UserImportParser::default();
+        let mut rows = Vec::new();
+        for chunk in text.as_bytes().chunks(7) {
+            rows.extend(parser.push(chunk));
+        }
+        rows.extend(parser.finish());
+        assert_eq!(rows.len(), 4);
+
+        let (line, Ok(user)) = &rows[0] else {
+            panic!("the first user wasn't parsed");
+        };
+        assert_eq!(*line, 2);
+        assert_eq!(user.name, "alice");
+        assert!(user.password.is_none() && user.hosts.is_empty());
+
+        // Incomplete json, a missing name and a header which isn't the first line
+        for (row, expected_line) in rows[1..].iter().zip([4, 5, 6]) {
+            assert_eq!(row.0, expected_line);
+            assert!(row.1.is_err());
+        }
+
+        // A line without a new line at the end and a line which is too long
+        let mut parser = UserImportParser::default();
+        let mut rows = parser.push(&vec![b' '; MAX_IMPORT_LINE_LENGTH + 1]);
+        rows.extend(parser.push(
+            b"\n{\"id\":1,\"name\":\"alice\",\"role\":\"User\",\"client_unique_id\":\"alice\"}",
+        ));
+        rows.extend(parser.finish());
+        assert_eq!(rows.len(), 2);
+        assert!(matches!(&rows[0], (1, Err(_))));
+        assert!(matches!(&rows[1], (2, Ok(user)) if user.name == "alice"));
+    }
+
+    #[tokio::test]
+    async fn test_export_users() {
+        let (app, admin, _dir) = create_app().await;
+
+        let users = collect(
+            app.export_users(&admin, false, false)
+                .await
+                .expect("failed to export users"),
+        )
+        .await;
+        assert_eq!(users.len(), 2);
+        assert!(users.iter().all(|user| user.password.is_none()));
+        assert!(
+            app.audit_log(&admin)
+                .await
+                .expect("failed to get audit log")
+                .is_empty()
+        );
+
+        let users = collect(
+            app.export_users(&admin, true, false)
+                .await
+                .expect("failed to export users"),
+        )
+        .await;
+        // The secrets leave the server, so admins can review who exported them
+        let entries = app
+            .audit_log(&admin)
+            .await
+            .expect("failed to get audit log");
+        assert!(matches!(
+            entries.as_slice(),
+            [entry] if entry.actor == admin.id()
+                && matches!(entry.action, AuditAction::UsersExported { include_hosts: false })
+        ));
+        let alice = users
+            .iter()
+            .find(|user| user.name == "alice")
+            .expect("alice wasn't exported");
+        assert_eq!(alice.role, UserRole::User);
+
+        // The exported hash still verifies the password after importing it elsewhere
+        let (other_app, other_admin, _other_dir) = create_app().await;
+        let mut bob = exported_user("bob", UserRole::User);
+        bob.password = alice.password.as_ref().map(|password| ExportedPassword {
+            salt: password.salt.clone(),
+            hash: password.hash.clone(),
+        });
+        other_app
+            .import_user(&other_admin, &bob, UserImportConflict::Skip, false)
+            .await
+            .expect("failed to import user");
+        let user = other_app
+            .user_by_name("bob")
+            .await
+            .expect("the user wasn't imported");
+        assert!(user.authenticate(&password_auth("bob")).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_import_conflicts() {
+        let (app, admin, _dir) = create_app().await;
+        let alice = app
+            .user_by_name("alice")
+            .await
+            .expect("alice doesn't exist")
+            .id();
+
+        let new_user = exported_user("bob", UserRole::User);
+        let (result, _) = app
+            .import_user(&admin, &new_user, UserImportConflict::Skip, false)
+            .await
+            .expect("failed to import user");
+        assert!(matches!(result, ImportUserResult::Created { .. }));
+
+        let existing = exported_user("alice", UserRole::Admin);
+
+        let (result, _) = app
+            .import_user(&admin, &existing, UserImportConflict::Skip, false)
+            .await
+            .expect("failed to import user");
+        assert!(matches!(result, ImportUserResult::Skipped { .. }));
+
+        let (result, _) = app
+            .import_user(&admin, &existing, UserImportConflict::Overwrite, false)
+            .await
+            .expect("failed to import user");
+        let ImportUserResult::Overwritten { user_id } = result else {
+            panic!("expected an overwrite, got {result:?}");
+        };
+        assert_eq!(user_id, alice.0);
+        // Overwriting without an exported password keeps the password
+        let mut user = app
+            .user_by_id(alice)
+            .await
+            .expect("alice was removed")
+            .authenticate(&password_auth("alice"))
+            .await
+            .expect("the password of alice changed");
+        assert!(matches!(user.role().await, Ok(Role::Admin)));
+
+        // The importing admin keeps the admin role
+        let (result, _) = app
+            .import_user(
+                &admin,
+                &exported_user("admin", UserRole::User),
+                UserImportConflict::Overwrite,
+                false,
+            )
+            .await
+            .expect("failed to import user");
+        assert!(matches!(result, ImportUserResult::Skipped { .. }));
+
+        for expected in ["alice-2", "alice-3"] {
+            let (result, _) = app
+                .import_user(&admin, &existing, UserImportConflict::Rename, false)
+                .await
+                .expect("failed to import user");
+            let ImportUserResult::Renamed { name, user_id } = result else {
+                panic!("expected a rename, got {result:?}");
+            };
+            assert_eq!(name, expected);
+            assert_ne!(user_id, alice.0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_import_malformed_users() {
+        let (app, admin, _dir) = create_app().await;
+
+        assert!(matches!(
+            app.import_user(
+                &admin,
+                &exported_user("", UserRole::User),
+                UserImportConflict::Skip,
+                false
+            )
+            .await,
+            Err(AppError::NameEmpty)
+        ));
+
+        let mut user = exported_user("bob", UserRole::User);
+        user.password = Some(ExportedPassword {
+            salt: "not hex".to_string(),
+            hash: "00".repeat(32),
+        });
+        assert!(matches!(
+            app.import_user(&admin, &user, UserImportConflict::Skip, false)
+                .await,
+            Err(AppError::UserImportInvalid(_))
+        ));
+        // The salt has the wrong length
+        user.password = Some(ExportedPassword {
+            salt: "00".repeat(8),
+            hash: "00".repeat(32),
+        });
+        assert!(matches!(
+            app.import_user(&admin, &user, UserImportConflict::Skip, false)
+                .await,
+            Err(AppError::UserImportInvalid(_))
+        ));
+        assert!(matches!(
+            app.user_by_name("bob").await,
+            Err(AppError::UserNotFound)
+        ));
+
+        // A malformed host doesn't stop the import of the user
+        let host = |address: &str, pair_info| ExportedHost {
+            name: "Desktop".to_string(),
+            address: address.to_string(),
+            http_port: 47989,
+            mac: Some("00:11:22:33:44:55".to_string()),
+            pair_info,
+        };
+        user.password = None;
+        user.hosts = vec![
+            host("192.168.1.5", None),
+            host("192.168.1.5", None),
+            host(
+                "192.168.1.6",
+                Some(ExportedPairInfo {
+                    client_private_key: "not a pem".to_string(),
+                    client_certificate: "not a pem".to_string(),
+                    server_certificate: "not a pem".to_string(),
+                }),
+            ),
+        ];
+        let (result, hosts) = app
+            .import_user(&admin, &user, UserImportConflict::Skip, true)
+            .await
+            .expect("failed to import user");
+        assert!(matches!(result, ImportUserResult::Created { .. }));
+        assert!(matches!(
+            hosts.as_slice(),
+            [
+                ImportHostResult::Imported { paired: false, .. },
+                ImportHostResult::Skipped { .. },
+                ImportHostResult::Failed { .. }
+            ]
+        ));
+
+        let exported = collect(
+            app.export_users(&admin, false, true)
+                .await
+                .expect("failed to export users"),
+        )
+        .await;
+        let bob = exported
+            .iter()
+            .find(|user| user.name == "bob")
+            .expect("bob wasn't exported");
+        assert_eq!(bob.hosts.len(), 1);
+        assert_eq!(bob.hosts[0].mac.as_deref(), Some("00:11:22:33:44:55"));
+    }
+}
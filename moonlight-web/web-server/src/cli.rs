@@ -11,6 +11,7 @@ use common::{
         WebRtcNat1To1Mapping, WebRtcNetworkType,
     },
 };
+use ipnet::IpNet;
 use log::LevelFilter;
 
 impl Cli {
@@ -65,6 +66,26 @@ pub enum Command {
     Run,
     /// Prints the config into stdout in json format
     PrintConfig,
+    /// Checks if the server can start with the config, e.g. if the certificate and the streamer exist.
+    /// Exits with a nonzero code and prints the problems if it can't.
+    CheckConfig,
+    /// Imports hosts and their pair info from another Moonlight client
+    #[command(subcommand)]
+    Import(ImportCommand),
+}
+
+#[derive(Subcommand)]
+pub enum ImportCommand {
+    /// Imports the hosts of Moonlight Qt. Hosts that cannot be verified are imported as not paired.
+    MoonlightQt {
+        /// The Moonlight Qt config directory or the config file itself.
+        /// On Linux this is usually "~/.config/Moonlight Game Streaming Project".
+        #[arg(long)]
+        path: String,
+        /// The name of the user that will own the hosts. The hosts will be global if not set.
+        #[arg(long)]
+        user: Option<String>,
+    },
 }
 
 #[derive(Args)]
@@ -84,6 +105,9 @@ pub struct CliConfig {
     /// Overwrites `web_server.bind_address`.
     #[arg(long, env = "BIND_ADDRESS")]
     pub bind_address: Option<SocketAddr>,
+    /// Overwrites `web_server.bind_unix`.
+    #[arg(long, env = "BIND_UNIX")]
+    pub bind_unix: Option<String>,
     /// Overwrites `web_server.certificate.certificate_pem`.
     #[arg(long, env = "SSL_CERTIFICATE")]
     pub ssl_certificate: Option<String>,
@@ -96,14 +120,24 @@ pub struct CliConfig {
     /// Overwrites `web_server.forwarded_header.username_header`.
     #[arg(long, env = "FORWARDED_HEADER")]
     pub forwarded_header: Option<String>,
+    /// Overwrites `web_server.trusted_proxies`. Example: "127.0.0.1/32,10.0.0.0/8"
+    #[arg(long, env = "TRUSTED_PROXIES", value_delimiter = ',')]
+    pub trusted_proxies: Option<Vec<IpNet>>,
     /// Overwrites `log.level_filter`.
     #[arg(long, env = "LOG_LEVEL")]
     pub log_level_filter: Option<LevelFilter>,
     /// Overwrites `log.log_file_path`.
     #[arg(long, env = "LOG_FILE")]
     pub log_file: Option<String>,
+    /// Overwrites `log.anonymize_ips`.
+    #[arg(long, env = "LOG_ANONYMIZE_IPS")]
+    pub log_anonymize_ips: Option<bool>,
+    /// Overwrites `streamer.path`.
     #[arg(long, env = "STREAMER_PATH")]
     pub streamer_path: Option<String>,
+    /// Overwrites `record_path`.
+    #[arg(long, env = "RECORD_PATH")]
+    pub record_path: Option<String>,
     /// Disables the STUN ice server which are bundled by default.
     /// This only disables the generation of them in the first config.
     /// After the config.json has been generated the ice servers in the config will be used regardless if this is set.
@@ -137,6 +171,9 @@ impl CliConfig {
         if let Some(bind_address) = self.bind_address {
             config.web_server.bind_address = bind_address;
         }
+        if let Some(bind_unix) = self.bind_unix {
+            config.web_server.bind_unix = Some(bind_unix);
+        }
         match (self.ssl_certificate, self.ssl_private_key) {
             (Some(certificate), Some(private_key)) => {
                 config.web_server.certificate = Some(ConfigSsl {
@@ -163,14 +200,23 @@ impl CliConfig {
                     .auto_create_missing_user,
             });
         }
+        if let Some(trusted_proxies) = self.trusted_proxies {
+            config.web_server.trusted_proxies = trusted_proxies;
+        }
         if let Some(log_level_filter) = self.log_level_filter {
             config.log.level_filter = log_level_filter;
         }
         if let Some(log_file) = self.log_file {
             config.log.file_path = Some(log_file);
         }
+        if let Some(log_anonymize_ips) = self.log_anonymize_ips {
+            config.log.anonymize_ips = log_anonymize_ips;
+        }
         if let Some(streamer_path) = self.streamer_path {
-            config.streamer_path = streamer_path;
+            config.streamer.path = streamer_path;
+        }
+        if let Some(record_path) = self.record_path {
+            config.record_path = Some(record_path);
         }
         if self.disable_default_webrtc_ice_servers {
             config
@@ -0,0 +1,304 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use actix_web::{
+    Error, HttpMessage, HttpRequest,
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{self, HeaderMap},
+    middleware::Next,
+    web::Data,
+};
+use ipnet::IpNet;
+
+use crate::app::App;
+
+/// The resolved ip of the client, inserted into the request extensions by [client_ip_middleware]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+/// Resolves the client ip once per request so that logging, auth and rate limiting use the same address.
+pub async fn client_ip_middleware(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let trusted_proxies = req
+        .app_data::<Data<App>>()
+        .map(|app| app.config().web_server.trusted_proxies.as_slice())
+        .unwrap_or_default();
+
+    let ip = resolve_client_ip(req.request(), trusted_proxies);
+    req.extensions_mut().insert(ClientIp(ip));
+
+    next.call(req).await
+}
+
+/// Returns the ip of the client which made this request.
+///
+/// The `X-Forwarded-For` and `Forwarded` headers are only used if the direct peer is one of the `web_server.trusted_proxies`.
+pub fn client_ip(req: &HttpRequest) -> IpAddr {
+    if let Some(ClientIp(ip)) = req.extensions().get::<ClientIp>() {
+        return *ip;
+    }
+
+    let trusted_proxies = req
+        .app_data::<Data<App>>()
+        .map(|app| app.config().web_server.trusted_proxies.as_slice())
+        .unwrap_or_default();
+
+    resolve_client_ip(req, trusted_proxies)
+}
+
+/// Formats the ip for the log, anonymized if `log.anonymize_ips` is set.
+pub fn log_ip(ip: Option<IpAddr>, anonymize: bool) -> String {
+    match ip {
+        Some(ip) if anonymize => anonymize_ip(ip).to_string(),
+        Some(ip) => ip.to_string(),
+        None => "-".to_string(),
+    }
+}
+
+/// Removes the last octet of ipv4 addresses and the interface identifier (last 64 bits) of ipv6 addresses.
+pub fn anonymize_ip(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            IpAddr::V4(Ipv4Addr::new(a, b, c, 0))
+        }
+        IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from_bits(ip.to_bits() & !(u64::MAX as u128))),
+    }
+}
+
+pub fn resolve_client_ip(req: &HttpRequest, trusted_proxies: &[IpNet]) -> IpAddr {
+    resolve_forwarded_chain(peer_ip(req), req.headers(), trusted_proxies)
+}
+
+/// Returns true if the direct peer of this request is a trusted proxy.
+pub fn is_trusted_proxy(req: &HttpRequest, trusted_proxies: &[IpNet]) -> bool {
+    is_trusted(peer_ip(req), trusted_proxies)
+}
+
+/// Returns true if the peer with this address is a trusted proxy, e.g. the peer of a [actix_web::dev::RequestHead].
+pub fn is_trusted_peer(peer: Option<SocketAddr>, trusted_proxies: &[IpNet]) -> bool {
+    is_trusted(peer_addr_ip(peer), trusted_proxies)
+}
+
+fn peer_ip(req: &HttpRequest) -> IpAddr {
+    peer_addr_ip(req.peer_addr())
+}
+
+/// Requests over a unix socket (`web_server.bind_unix`) have no peer address, they can only come from this machine
+fn peer_addr_ip(peer: Option<SocketAddr>) -> IpAddr {
+    peer.map(|peer| peer.ip())
+        .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST))
+}
+
+fn is_trusted(ip: IpAddr, trusted_proxies: &[IpNet]) -> bool {
+    let ip = ip.to_canonical();
+
+    trusted_proxies.iter().any(|net| net.contains(&ip))
+}
+
+/// Walks the forwarded chain from right to left, starting at the peer.
+/// The first address which isn't trusted is the client.
+fn resolve_forwarded_chain(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpNet]) -> IpAddr {
+    let mut client = peer.to_canonical();
+    if !is_trusted(client, trusted_proxies) {
+        return client;
+    }
+
+    // The Forwarded header is the standardized version, so prefer it
+    let mut chain = forwarded_chain(headers);
+    if chain.is_empty() {
+        chain = x_forwarded_for_chain(headers);
+    }
+
+    for node in chain.into_iter().rev() {
+        if !is_trusted(client, trusted_proxies) {
+            break;
+        }
+
+        // An obfuscated or malformed entry was set by a proxy we trust,
+        // so that proxy is the best guess we have
+        let Some(ip) = node else {
+            break;
+        };
+
+        client = ip.to_canonical();
+    }
+
+    client
+}
+
+fn x_forwarded_for_chain(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    headers
+        .get_all("X-Forwarded-For")
+        .flat_map(|value| value.to_str().unwrap_or_default().split(','))
+        .map(str::trim)
+        .filter(|node| !node.is_empty())
+        .map(parse_node)
+        .collect()
+}
+
+fn forwarded_chain(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    headers
+        .get_all(header::FORWARDED)
+        .flat_map(|value| value.to_str().unwrap_or_default().split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.split_once('=')?;
+
+                key.trim()
+                    .eq_ignore_ascii_case("for")
+                    .then(|| parse_node(value))
+            })
+        })
+        .collect()
+}
+
+/// Parses a node like `192.0.2.60`, `192.0.2.60:4711`, `"[2001:db8:cafe::17]:4711"` or `2001:db8:cafe::17`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    if let Ok(address) = node.parse::<SocketAddr>() {
+        return Some(address.ip());
+    }
+
+    // Ipv6 without port, but with brackets
+    node.strip_prefix('[')
+        .and_then(|node| node.strip_suffix(']'))
+        .and_then(|ip| ip.parse().ok())
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+
+    use actix_web::{http::header::HeaderMap, test::TestRequest};
+    use ipnet::IpNet;
+
+    use crate::client_ip::{anonymize_ip, parse_node, resolve_client_ip, resolve_forwarded_chain};
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().expect("invalid ip")
+    }
+
+    fn trusted() -> Vec<IpNet> {
+        vec![
+            "10.0.0.0/8".parse().expect("invalid net"),
+            "::1/128".parse().expect("invalid net"),
+        ]
+    }
+
+    #[test]
+    fn test_parse_node() {
+        assert_eq!(parse_node("192.0.2.60"), Some(ip("192.0.2.60")));
+        assert_eq!(parse_node(" 192.0.2.60:4711 "), Some(ip("192.0.2.60")));
+        assert_eq!(
+            parse_node("\"[2001:db8:cafe::17]:4711\""),
+            Some(ip("2001:db8:cafe::17"))
+        );
+        assert_eq!(parse_node("[2001:db8::1]"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("unknown"), None);
+        assert_eq!(parse_node("_hidden"), None);
+    }
+
+    #[test]
+    fn test_untrusted_peer_ignores_headers() {
+        let req = TestRequest::default()
+            .peer_addr("203.0.113.5:1234".parse().expect("invalid address"))
+            .insert_header(("X-Forwarded-For", "1.1.1.1"))
+            .insert_header(("Forwarded", "for=2.2.2.2"))
+            .to_http_request();
+
+        assert_eq!(resolve_client_ip(&req, &trusted()), ip("203.0.113.5"));
+    }
+
+    #[test]
+    fn test_trusted_peer_x_forwarded_for() {
+        let req = TestRequest::default()
+            .peer_addr("10.0.0.2:1234".parse().expect("invalid address"))
+            .insert_header(("X-Forwarded-For", "6.6.6.6, 198.51.100.7, 10.0.0.3"))
+            .to_http_request();
+
+        // 6.6.6.6 could be spoofed by the client, the rightmost untrusted entry is the client
+        assert_eq!(resolve_client_ip(&req, &trusted()), ip("198.51.100.7"));
+    }
+
+    #[test]
+    fn test_trusted_peer_forwarded() {
+        let req = TestRequest::default()
+            .peer_addr("[::1]:1234".parse().expect("invalid address"))
+            .insert_header((
+                "Forwarded",
+                "for=192.0.2.43, for=\"[2001:db8:cafe::17]:4711\";proto=https",
+            ))
+            .insert_header(("X-Forwarded-For", "1.1.1.1"))
+            .to_http_request();
+
+        assert_eq!(resolve_client_ip(&req, &trusted()), ip("2001:db8:cafe::17"));
+    }
+
+    #[test]
+    fn test_all_trusted_uses_leftmost() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "X-Forwarded-For".parse().expect("invalid header name"),
+            "10.1.1.1, 10.2.2.2".parse().expect("invalid header value"),
+        );
+
+        assert_eq!(
+            resolve_forwarded_chain(ip("10.0.0.1"), &headers, &trusted()),
+            ip("10.1.1.1")
+        );
+    }
+
+    #[test]
+    fn test_malformed_entry_stops_at_proxy() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "X-Forwarded-For".parse().expect("invalid header name"),
+            "1.1.1.1, unknown".parse().expect("invalid header value"),
+        );
+
+        assert_eq!(
+            resolve_forwarded_chain(ip("10.0.0.1"), &headers, &trusted()),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn test_unix_socket_peer() {
+        let req = TestRequest::default()
+            .insert_header(("X-Forwarded-For", "198.51.100.7"))
+            .to_http_request();
+
+        assert_eq!(resolve_client_ip(&req, &[]), ip("127.0.0.1"));
+        assert_eq!(
+            resolve_client_ip(&req, &["127.0.0.1/32".parse().expect("invalid net")]),
+            ip("198.51.100.7")
+        );
+    }
+
+    #[test]
+    fn test_no_trusted_proxies() {
+        let req = TestRequest::default()
+            .peer_addr("10.0.0.2:1234".parse().expect("invalid address"))
+            .insert_header(("X-Forwarded-For", "1.1.1.1"))
+            .to_http_request();
+
+        assert_eq!(resolve_client_ip(&req, &[]), ip("10.0.0.2"));
+    }
+
+    #[test]
+    fn test_anonymize_ip() {
+        assert_eq!(anonymize_ip(ip("203.0.113.57")), ip("203.0.113.0"));
+        assert_eq!(anonymize_ip(ip("::ffff:203.0.113.57")), ip("203.0.113.0"));
+        assert_eq!(
+            anonymize_ip(ip("2001:db8:cafe:1:2:3:4:5")),
+            ip("2001:db8:cafe:1::")
+        );
+    }
+}
@@ -0,0 +1,214 @@
+//! Checks if the config can be used, without starting the server.
+//! The server runs the same checks on startup, the `check-config` command only prints their result.
+
+use std::{
+    io::ErrorKind,
+    net::{SocketAddr, TcpListener},
+    path::Path,
+};
+
+use common::{
+    api_bindings::RtcIceServer,
+    config::{Config, PortRange},
+};
+
+use crate::{streamer_command::resolve_streamer_path, tls::TlsCertificate};
+
+/// Every problem of the config, empty if it's usable
+pub async fn check_config(config: &Config) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    if let Err(err) = resolve_streamer_path(&config.streamer) {
+        problems.push(format!("streamer: {err:#}"));
+    }
+
+    let web_server = &config.web_server;
+    if let Some(socket_path) = &web_server.bind_unix {
+        if let Some(problem) = check_unix_socket(socket_path) {
+            problems.push(problem);
+        }
+    } else {
+        if let Some(problem) = check_bind_address(web_server.bind_address) {
+            problems.push(problem);
+        }
+
+        // The certificate is ignored when listening on a unix socket
+        if let Some(certificate) = &web_server.certificate
+            && let Err(err) = TlsCertificate::load(certificate.clone()).await
+        {
+            problems.push(format!("web_server.certificate: {err}"));
+        }
+    }
+
+    if web_server.invite_secret.as_deref() == Some("") {
+        problems.push("web_server.invite_secret: the secret is empty".to_string());
+    }
+
+    for ice_server in &config.webrtc.ice_servers {
+        problems.extend(check_ice_server(ice_server));
+    }
+
+    if let Some(port_range) = &config.webrtc.port_range
+        && let Some(problem) = check_port_range(port_range)
+    {
+        problems.push(problem);
+    }
+
+    problems
+}
+
+fn check_bind_address(address: SocketAddr) -> Option<String> {
+    match TcpListener::bind(address) {
+        Ok(_) => None,
+        // The running server might use the address, e.g. when the config is checked before a restart
+        Err(err) if err.kind() == ErrorKind::AddrInUse => None,
+        Err(err) => Some(format!(
+            "web_server.bind_address: can't bind {address}: {err}"
+        )),
+    }
+}
+
+fn check_unix_socket(socket_path: &str) -> Option<String> {
+    let directory = match Path::new(socket_path).parent() {
+        Some(directory) if directory.as_os_str().is_empty() => Path::new("."),
+        Some(directory) => directory,
+        None => {
+            return Some(format!(
+                "web_server.bind_unix: {socket_path} isn't a file path"
+            ));
+        }
+    };
+
+    if directory.is_dir() {
+        None
+    } else {
+        Some(format!(
+            "web_server.bind_unix: the directory {} doesn't exist",
+            directory.display()
+        ))
+    }
+}
+
+fn check_ice_server(ice_server: &RtcIceServer) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    if ice_server.urls.is_empty() {
+        problems.push("webrtc.ice_servers: an ice server has no urls".to_string());
+    }
+
+    for url in &ice_server.urls {
+        let Some((scheme, address)) = url.split_once(':') else {
+            problems.push(format!("webrtc.ice_servers: {url} has no scheme"));
+            continue;
+        };
+        // e.g. "?transport=tcp"
+        let address = address.split('?').next().unwrap_or_default();
+
+        match scheme {
+            "stun" | "stuns" => {}
+            "turn" | "turns" => {
+                if ice_server.username.is_empty() || ice_server.credential.is_empty() {
+                    problems.push(format!(
+                        "webrtc.ice_servers: {url} needs a username and a credential"
+                    ));
+                }
+            }
+            _ => {
+                problems.push(format!(
+                    "webrtc.ice_servers: {url} must start with stun:, stuns:, turn: or turns:"
+                ));
+                continue;
+            }
+        }
+
+        if address.is_empty() || address.starts_with("//") {
+            problems.push(format!(
+                "webrtc.ice_servers: {url} must be like {scheme}:host:port"
+            ));
+        }
+    }
+
+    problems
+}
+
+fn check_port_range(port_range: &PortRange) -> Option<String> {
+    if port_range.min > port_range.max {
+        Some(format!(
+            "webrtc.port_range: the min {} is larger than the max {}",
+            port_range.min, port_range.max
+        ))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use common::{api_bindings::RtcIceServer, config::PortRange};
+
+    use crate::config_check::{check_ice_server, check_port_range, check_unix_socket};
+
+    fn ice_server(urls: &[&str], username: &str) -> RtcIceServer {
+        RtcIceServer {
+            is_default: false,
+            urls: urls.iter().map(|url| url.to_string()).collect(),
+            username: username.to_string(),
+            credential: username.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_check_ice_server() {
+        assert!(check_ice_server(&ice_server(&["stun:stun.l.google.com:19302"], "")).is_empty());
+        assert!(
+            check_ice_server(&ice_server(
+                &[
+                    "turn:turn.example.com:3478?transport=tcp",
+                    "turns:turn.example.com"
+                ],
+                "user"
+            ))
+            .is_empty()
+        );
+
+        // Turn servers need credentials
+        assert_eq!(
+            check_ice_server(&ice_server(&["turn:turn.example.com:3478"], "")).len(),
+            1
+        );
+        assert_eq!(check_ice_server(&ice_server(&[], "")).len(), 1);
+        assert_eq!(
+            check_ice_server(&ice_server(
+                &[
+                    "stun.l.google.com:19302",
+                    "stun://stun.example.com",
+                    "stun:"
+                ],
+                ""
+            ))
+            .len(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_check_other_options() {
+        assert!(
+            check_port_range(&PortRange {
+                min: 40000,
+                max: 40100
+            })
+            .is_none()
+        );
+        assert!(
+            check_port_range(&PortRange {
+                min: 40100,
+                max: 40000
+            })
+            .is_some()
+        );
+
+        assert!(check_unix_socket("moonlight-web.sock").is_none());
+        assert!(check_unix_socket("/this/directory/does/not/exist/moonlight-web.sock").is_some());
+    }
+}
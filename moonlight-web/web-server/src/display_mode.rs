@@ -0,0 +1,274 @@
+//! Checks the resolution and fps a client requests against the display modes and codecs of the host.
+//! Hosts which don't report their display modes or capabilities accept every request.
+
+use common::api_bindings::{HostCapabilities, HostDisplayMode};
+use moonlight_common::stream::bindings::SupportedVideoFormats;
+use serde::{Deserialize, Serialize};
+
+/// How much the aspect ratio of a mode can differ from the requested one, relative to the requested one
+const MAX_ASPECT_RATIO_DIFFERENCE: f64 = 0.02;
+/// The largest width and height of hosts which can't stream above 4K
+const MAX_4K_DIMENSION: u32 = 4096;
+
+/// What the host of a room can stream, collected when the room is created
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HostStreamLimits {
+    pub display_modes: Vec<HostDisplayMode>,
+    /// None if the host couldn't be reached
+    pub capabilities: Option<HostCapabilities>,
+    /// The most pixels the host can encode with HEVC, zero if unknown
+    pub max_luma_pixels_hevc: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisplayModeCheck {
+    /// The host supports the requested mode or doesn't report its modes
+    Supported,
+    /// The closest mode with the same aspect ratio
+    Substituted(HostDisplayMode),
+    /// The host has no mode with the same aspect ratio
+    Unsupported,
+}
+
+fn aspect_ratio(width: u32, height: u32) -> f64 {
+    width as f64 / height as f64
+}
+
+pub fn check_display_mode(
+    modes: &[HostDisplayMode],
+    width: u32,
+    height: u32,
+    fps: u32,
+) -> DisplayModeCheck {
+    if modes.is_empty() {
+        return DisplayModeCheck::Supported;
+    }
+    if width == 0 || height == 0 {
+        return DisplayModeCheck::Unsupported;
+    }
+
+    if modes
+        .iter()
+        .any(|mode| mode.width == width && mode.height == height && mode.refresh_rate == fps)
+    {
+        return DisplayModeCheck::Supported;
+    }
+
+    let requested_aspect_ratio = aspect_ratio(width, height);
+    let requested_pixels = width as u64 * height as u64;
+
+    // The resolution with the closest pixel count, then the closest refresh rate of it.
+    // Ties prefer the larger mode so the stream doesn't get worse than necessary
+    let closest = modes
+        .iter()
+        .filter(|mode| mode.width > 0 && mode.height > 0)
+        .filter(|mode| {
+            let difference = (aspect_ratio(mode.width, mode.height) - requested_aspect_ratio).abs();
+
+            difference / requested_aspect_ratio <= MAX_ASPECT_RATIO_DIFFERENCE
+        })
+        .min_by_key(|mode| {
+            let pixels = mode.width as u64 * mode.height as u64;
+
+            (
+                pixels.abs_diff(requested_pixels),
+                u64::MAX - pixels,
+                mode.refresh_rate.abs_diff(fps),
+                u32::MAX - mode.refresh_rate,
+            )
+        });
+
+    match closest {
+        Some(mode) => DisplayModeCheck::Substituted(*mode),
+        None => DisplayModeCheck::Unsupported,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResolutionCheck {
+    /// The host can encode the resolution or doesn't report its capabilities
+    Supported,
+    /// The largest resolution with the same aspect ratio the host can encode with the codecs of the stream
+    Clamped { width: u32, height: u32 },
+    /// The host can't stream above 4K
+    Unsupported { max_width: u32, max_height: u32 },
+}
+
+/// `video_supported_formats` are the formats the client can decode as flags of `SupportedVideoFormats`,
+/// HEVC is removed from them if the stream has to use another codec for the resolution
+pub fn check_resolution(
+    limits: &HostStreamLimits,
+    video_supported_formats: &mut u32,
+    width: u32,
+    height: u32,
+) -> ResolutionCheck {
+    let Some(capabilities) = &limits.capabilities else {
+        return ResolutionCheck::Supported;
+    };
+
+    if !capabilities.above_4k && (width > MAX_4K_DIMENSION || height > MAX_4K_DIMENSION) {
+        return ResolutionCheck::Unsupported {
+            max_width: MAX_4K_DIMENSION,
+            max_height: MAX_4K_DIMENSION,
+        };
+    }
+
+    let formats = SupportedVideoFormats::from_bits_truncate(
+        *video_supported_formats & capabilities.supported_video_formats,
+    );
+    let pixels = width as u64 * height as u64;
+    let max_pixels = limits.max_luma_pixels_hevc as u64;
+
+    // Only HEVC has a pixel limit
+    if max_pixels == 0 || pixels <= max_pixels || formats.is_empty() {
+        return ResolutionCheck::Supported;
+    }
+
+    // The stream can use another codec if both sides support one
+    if !SupportedVideoFormats::MASK_H265.contains(formats) {
+        *video_supported_formats &= !SupportedVideoFormats::MASK_H265.bits();
+        return ResolutionCheck::Supported;
+    }
+
+    // Encoders need even dimensions
+    let scale = (max_pixels as f64 / pixels as f64).sqrt();
+    ResolutionCheck::Clamped {
+        width: (width as f64 * scale) as u32 & !1,
+        height: (height as f64 * scale) as u32 & !1,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use common::api_bindings::{HostCapabilities, HostDisplayMode};
+    use moonlight_common::stream::bindings::SupportedVideoFormats;
+
+    use crate::display_mode::{
+        DisplayModeCheck, HostStreamLimits, ResolutionCheck, check_display_mode, check_resolution,
+    };
+
+    fn mode(width: u32, height: u32, refresh_rate: u32) -> HostDisplayMode {
+        HostDisplayMode {
+            width,
+            height,
+            refresh_rate,
+        }
+    }
+
+    #[test]
+    fn test_check_display_mode() {
+        let modes = [
+            mode(1280, 720, 60),
+            mode(1920, 1080, 60),
+            mode(1920, 1080, 120),
+            mode(3840, 2160, 60),
+        ];
+
+        // Hosts which don't report their modes accept everything
+        assert_eq!(
+            check_display_mode(&[], 1366, 768, 60),
+            DisplayModeCheck::Supported
+        );
+        assert_eq!(
+            check_display_mode(&modes, 1920, 1080, 120),
+            DisplayModeCheck::Supported
+        );
+
+        assert_eq!(
+            check_display_mode(&modes, 1366, 768, 60),
+            DisplayModeCheck::Substituted(mode(1280, 720, 60))
+        );
+        assert_eq!(
+            check_display_mode(&modes, 1920, 1080, 144),
+            DisplayModeCheck::Substituted(mode(1920, 1080, 120))
+        );
+        assert_eq!(
+            check_display_mode(&modes, 2560, 1440, 60),
+            DisplayModeCheck::Substituted(mode(1920, 1080, 60))
+        );
+
+        // Nothing has the aspect ratio of 4:3
+        assert_eq!(
+            check_display_mode(&modes, 1024, 768, 60),
+            DisplayModeCheck::Unsupported
+        );
+        assert_eq!(
+            check_display_mode(&modes, 0, 0, 60),
+            DisplayModeCheck::Unsupported
+        );
+    }
+
+    #[test]
+    fn test_check_resolution() {
+        let hevc_only = HostStreamLimits {
+            display_modes: Vec::new(),
+            capabilities: Some(HostCapabilities {
+                video_codecs: Vec::new(),
+                supported_video_formats: SupportedVideoFormats::H265.bits(),
+                hdr: false,
+                yuv444: false,
+                above_4k: false,
+            }),
+            max_luma_pixels_hevc: 1920 * 1080,
+        };
+        let all_formats = SupportedVideoFormats::all().bits();
+        let mut formats = all_formats;
+
+        // Hosts which don't report their capabilities accept everything
+        assert_eq!(
+            check_resolution(&HostStreamLimits::default(), &mut formats, 7680, 4320),
+            ResolutionCheck::Supported
+        );
+        assert_eq!(
+            check_resolution(&hevc_only, &mut formats, 1920, 1080),
+            ResolutionCheck::Supported
+        );
+
+        assert_eq!(
+            check_resolution(&hevc_only, &mut formats, 3840, 2160),
+            ResolutionCheck::Clamped {
+                width: 1920,
+                height: 1080
+            }
+        );
+        assert_eq!(
+            check_resolution(&hevc_only, &mut formats, 5120, 2880),
+            ResolutionCheck::Unsupported {
+                max_width: 4096,
+                max_height: 4096
+            }
+        );
+
+        // The stream can use H.264 instead
+        let mut h264_and_hevc = hevc_only.clone();
+        if let Some(capabilities) = &mut h264_and_hevc.capabilities {
+            capabilities.supported_video_formats =
+                (SupportedVideoFormats::H264 | SupportedVideoFormats::H265).bits();
+        }
+        assert_eq!(
+            check_resolution(&h264_and_hevc, &mut formats, 3840, 2160),
+            ResolutionCheck::Supported
+        );
+        assert_eq!(
+            formats,
+            all_formats & !SupportedVideoFormats::MASK_H265.bits()
+        );
+
+        // HEVC stays available below the limit
+        let mut formats = all_formats;
+        assert_eq!(
+            check_resolution(&h264_and_hevc, &mut formats, 1920, 1080),
+            ResolutionCheck::Supported
+        );
+        assert_eq!(formats, all_formats);
+
+        let mut formats = SupportedVideoFormats::H265.bits();
+        assert_eq!(
+            check_resolution(&h264_and_hevc, &mut formats, 3840, 2160),
+            ResolutionCheck::Clamped {
+                width: 1920,
+                height: 1080
+            }
+        );
+    }
+}
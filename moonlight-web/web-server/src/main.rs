@@ -1,31 +1,64 @@
 use common::config::Config;
-use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
-use std::{io::ErrorKind, path::PathBuf, str::FromStr};
-use tokio::fs::{self, File};
+#[cfg(unix)]
+use std::{fs::Permissions, os::unix::fs::PermissionsExt};
+use std::{
+    io::{self, ErrorKind},
+    path::PathBuf,
+    process,
+    str::FromStr,
+    time::Duration,
+};
+use tokio::{
+    fs::{self, File},
+    signal::ctrl_c,
+    spawn,
+};
+#[cfg(unix)]
+use tokio::{
+    select,
+    signal::unix::{SignalKind, signal},
+};
 
 use actix_web::{
-    App as ActixApp, HttpServer,
-    middleware::{self, Logger},
+    App as ActixApp, HttpMessage, HttpServer,
+    dev::{Server, ServerHandle},
+    middleware::{self, Logger, from_fn},
     web::{Data, scope},
 };
-use log::{Level, error, info};
+use log::{Level, error, info, warn};
 use simplelog::{ColorChoice, CombinedLogger, SharedLogger, TermLogger, TerminalMode, WriteLogger};
 
 use crate::{
-    api::api_service,
-    app::App,
-    cli::{Cli, Command},
+    api::{api_service, stream::restore_rooms},
+    app::{
+        App,
+        import::{MoonlightQtConfig, format_import_table},
+    },
+    cli::{Cli, Command, ImportCommand},
+    client_ip::{ClientIp, client_ip_middleware, log_ip},
+    config_check::check_config,
     human_json::preprocess_human_json,
+    streamer_command::resolve_streamer_path,
+    tls::{TlsCertificate, watch_certificate},
     web::{web_config_js_service, web_service},
 };
 
 mod api;
 mod app;
 mod room;
+mod room_state;
 mod web;
 
 mod cli;
+mod client_ip;
+mod config_check;
+mod display_mode;
 mod human_json;
+mod process_scheduling;
+mod streamer_command;
+#[cfg(test)]
+mod test_util;
+mod tls;
 
 #[actix_web::main]
 async fn main() {
@@ -33,13 +66,14 @@ async fn main() {
 
     // Load Config
     let config_path = PathBuf::from_str(&cli.config_path).expect("invalid config file path");
-    let config = match fs::read_to_string(&config_path).await {
+    let (config, deprecated_options) = match fs::read_to_string(&config_path).await {
         Ok(mut value) => {
             value = preprocess_human_json(value);
 
-            let mut config = serde_json::from_str(&value).expect("invalid file");
+            let mut config: Config = serde_json::from_str(&value).expect("invalid file");
+            let deprecated_options = config.migrate_deprecated();
             cli.options.apply(&mut config);
-            config
+            (config, deprecated_options)
         }
         Err(err) if err.kind() == ErrorKind::NotFound => {
             let mut new_config = Config::default();
@@ -57,7 +91,7 @@ async fn main() {
                 .await
                 .expect("failed to write default file");
 
-            new_config
+            (new_config, Vec::new())
         }
         Err(err) => panic!("failed to read file: {err}"),
     };
@@ -69,14 +103,11 @@ async fn main() {
             println!("{json}");
             return;
         }
-        None | Some(Command::Run) => {
+        Some(Command::Import(_)) | Some(Command::CheckConfig) | None | Some(Command::Run) => {
             // Fallthrough
         }
     }
 
-    // TODO: log config: anonymize ips when enabled in file
-    // TODO: https://www.reddit.com/r/csharp/comments/166xgcl/comment/jynybpe/
-
     let log_config = simplelog::ConfigBuilder::new()
         .add_filter_ignore_str("actix_http::h1")
         .build();
@@ -103,26 +134,88 @@ async fn main() {
 
     CombinedLogger::init(loggers).expect("failed to init combined logger");
 
+    for option in deprecated_options {
+        warn!("[Config]: \"{option}\" is deprecated, see the README for its replacement");
+    }
+
+    if let Some(Command::CheckConfig) = cli.command {
+        let problems = check_config(&config).await;
+        if problems.is_empty() {
+            println!("The config is valid");
+            return;
+        }
+
+        println!("The config has {} problem(s):", problems.len());
+        for problem in problems {
+            println!("- {problem}");
+        }
+        process::exit(1);
+    }
+
+    if let Some(Command::Import(command)) = cli.command {
+        if let Err(err) = import(config, command).await {
+            error!("{err:?}");
+        }
+        return;
+    }
+
     if let Err(err) = start(config).await {
         error!("{err:?}");
     }
 }
 
-async fn start(config: Config) -> Result<(), anyhow::Error> {
+async fn import(config: Config, command: ImportCommand) -> Result<(), anyhow::Error> {
+    let app = App::new(config).await?;
+
+    let hosts = match command {
+        ImportCommand::MoonlightQt { path, user } => {
+            let owner = match user {
+                Some(name) => Some(app.user_by_name(&name).await?.id()),
+                None => None,
+            };
+
+            let config = MoonlightQtConfig::load(&PathBuf::from(path)).await?;
+
+            app.import_moonlight_qt_no_auth(owner, config).await?
+        }
+    };
+
+    app.flush_storage().await?;
+
+    print!("{}", format_import_table(&hosts));
+
+    Ok(())
+}
+
+async fn start(mut config: Config) -> Result<(), anyhow::Error> {
+    // e.g. without a streamer no room can be created, so don't start at all
+    let problems = check_config(&config).await;
+    if !problems.is_empty() {
+        anyhow::bail!("the config can't be used:\n{}", problems.join("\n"));
+    }
+
+    config.streamer.path = resolve_streamer_path(&config.streamer)?;
+
     let app = App::new(config.clone()).await?;
     let app = Data::new(app);
 
+    restore_rooms(app.clone()).await;
+
     let bind_address = app.config().web_server.bind_address;
     let server = HttpServer::new({
         let url_path_prefix = config.web_server.url_path_prefix.clone();
+        let anonymize_ips = config.log.anonymize_ips;
         let app = app.clone();
 
         move || {
-            ActixApp::new().service(
+            ActixApp::new().app_data(app.clone()).service(
                 scope(&url_path_prefix)
-                    .app_data(app.clone())
                     .wrap(
-                        Logger::new("%r took %D ms")
+                        Logger::new("%{client_ip}xi %r took %D ms")
+                            .custom_request_replace("client_ip", move |req| {
+                                let ip = req.extensions().get::<ClientIp>().map(|ip| ip.0);
+                                log_ip(ip, anonymize_ips)
+                            })
                             .log_target("http_server")
                             .log_level(Level::Debug),
                     )
@@ -136,29 +229,116 @@ async fn start(config: Config) -> Result<(), anyhow::Error> {
                             .add(("Pragma", "no-cache"))
                             .add(("Expires", "0")),
                     )
-                    .service(api_service())
+                    // Registered last so it runs before the logger
+                    .wrap(from_fn(client_ip_middleware))
+                    .service(api_service(app.config()))
                     .service(web_config_js_service())
                     .service(web_service()),
             )
         }
-    });
+    })
+    // Signals are handled by shutdown_on_signal so the rooms are drained first
+    .disable_signals()
+    .shutdown_timeout(SHUTDOWN_TIMEOUT.as_secs());
 
-    if let Some(certificate) = app.config().web_server.certificate.as_ref() {
+    if let Some(socket_path) = app.config().web_server.bind_unix.clone() {
+        if app.config().web_server.certificate.is_some() {
+            warn!("[Server]: The certificate is ignored when listening on a unix socket");
+        }
+
+        #[cfg(unix)]
+        {
+            info!("[Server]: Running Http Server on unix socket {socket_path}");
+
+            remove_stale_unix_socket(&socket_path).await?;
+
+            let server = server.bind_uds(&socket_path)?;
+            fs::set_permissions(&socket_path, Permissions::from_mode(UNIX_SOCKET_MODE)).await?;
+
+            let result = run_server(&app, server.run()).await;
+
+            if let Err(err) = fs::remove_file(&socket_path).await {
+                warn!("[Server]: failed to remove unix socket {socket_path}: {err}");
+            }
+            result?;
+        }
+        #[cfg(not(unix))]
+        {
+            drop(server);
+            anyhow::bail!("Unix sockets are not supported on this platform: {socket_path}");
+        }
+    } else if let Some(certificate) = app.config().web_server.certificate.clone() {
         info!("[Server]: Running Https Server with ssl tls");
 
-        let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())
-            .expect("failed to create ssl tls acceptor");
-        builder
-            .set_private_key_file(&certificate.private_key_pem, SslFiletype::PEM)
-            .expect("failed to set private key");
-        builder
-            .set_certificate_chain_file(&certificate.certificate_pem)
-            .expect("failed to set certificate");
+        let certificate = TlsCertificate::load(certificate).await?;
+        let acceptor = certificate.acceptor()?;
+        // Renewed certificates are used for new connections without a restart
+        spawn(watch_certificate(certificate));
 
-        server.bind_openssl(bind_address, builder)?.run().await?;
+        run_server(&app, server.bind_openssl(bind_address, acceptor)?.run()).await?;
     } else {
-        server.bind(bind_address)?.run().await?;
+        run_server(&app, server.bind(bind_address)?.run()).await?;
     }
 
+    // Write the changes that are still batched
+    app.flush_storage().await?;
+
     Ok(())
 }
+
+/// How long streamers and connections get to close on shutdown
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+async fn run_server(app: &Data<App>, server: Server) -> Result<(), io::Error> {
+    spawn(shutdown_on_signal(app.clone(), server.handle()));
+
+    server.await
+}
+
+/// Stops accepting connections, stops the streamers of all rooms and then stops the server
+async fn shutdown_on_signal(app: Data<App>, server: ServerHandle) {
+    if let Err(err) = wait_for_shutdown_signal().await {
+        error!("[Server]: failed to listen for shutdown signals: {err}");
+        return;
+    }
+
+    info!("[Server]: Shutting down");
+
+    server.pause().await;
+    app.room_manager().shutdown(SHUTDOWN_TIMEOUT).await;
+    server.stop(true).await;
+}
+
+#[cfg(unix)]
+async fn wait_for_shutdown_signal() -> Result<(), io::Error> {
+    let mut terminate = signal(SignalKind::terminate())?;
+
+    select! {
+        result = ctrl_c() => result,
+        _ = terminate.recv() => Ok(()),
+    }
+}
+#[cfg(not(unix))]
+async fn wait_for_shutdown_signal() -> Result<(), io::Error> {
+    ctrl_c().await
+}
+
+/// Owner and group can read and write, the reverse proxy has to be in the group of the web server
+#[cfg(unix)]
+const UNIX_SOCKET_MODE: u32 = 0o660;
+
+/// A socket left behind by a previous run which wasn't shut down cleanly would fail the bind
+#[cfg(unix)]
+async fn remove_stale_unix_socket(path: &str) -> Result<(), anyhow::Error> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match fs::symlink_metadata(path).await {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            fs::remove_file(path).await?;
+            Ok(())
+        }
+        Ok(_) => anyhow::bail!("{path} already exists and isn't a unix socket"),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
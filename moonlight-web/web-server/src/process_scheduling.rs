@@ -0,0 +1,183 @@
+use common::config::StreamerProcessConfig;
+use log::warn;
+
+/// Applies the niceness and cpu affinity of the config to a spawned streamer.
+/// The streamer also works without them, so failures are only logged.
+pub fn apply_process_scheduling(pid: Option<u32>, config: &StreamerProcessConfig) {
+    if config.nice.is_none() && config.cpu_affinity.is_none() {
+        return;
+    }
+
+    let Some(pid) = pid else {
+        warn!(
+            "[Stream]: failed to configure the scheduling of the streamer because it already exited"
+        );
+        return;
+    };
+
+    platform::apply(pid, config);
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use std::{fs, io, mem};
+
+    use common::config::StreamerProcessConfig;
+    use log::warn;
+
+    /// On Linux the niceness and cpu affinity belong to a thread and new threads inherit them from their creator.
+    /// The streamer might have created threads already, so all of them are changed.
+    pub fn apply(pid: u32, config: &StreamerProcessConfig) {
+        for thread_id in thread_ids(pid) {
+            if let Some(nice) = config.nice
+                && let Err(err) = set_nice(thread_id, nice)
+            {
+                warn!("[Stream]: failed to set the niceness of the streamer to {nice}: {err}");
+            }
+            if let Some(cpus) = &config.cpu_affinity
+                && let Err(err) = set_cpu_affinity(thread_id, cpus)
+            {
+                warn!(
+                    "[Stream]: failed to set the cpu affinity of the streamer to {cpus:?}: {err}"
+                );
+            }
+        }
+    }
+
+    fn thread_ids(pid: u32) -> Vec<u32> {
+        let tasks = match fs::read_dir(format!("/proc/{pid}/task")) {
+            Ok(tasks) => tasks,
+            Err(err) => {
+                warn!("[Stream]: failed to list the threads of the streamer: {err}");
+                return vec![pid];
+            }
+        };
+
+        tasks
+            .filter_map(|task| task.ok()?.file_name().to_str()?.parse().ok())
+            .collect()
+    }
+
+    fn set_nice(thread_id: u32, nice: i32) -> io::Result<()> {
+        // SAFETY: setpriority has no preconditions
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, thread_id, nice) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    fn set_cpu_affinity(thread_id: u32, cpus: &[usize]) -> io::Result<()> {
+        if cpus.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the streamer needs at least one cpu",
+            ));
+        }
+
+        // SAFETY: a zeroed cpu set is empty
+        let mut set = unsafe { mem::zeroed::<libc::cpu_set_t>() };
+        for &cpu in cpus {
+            if cpu >= libc::CPU_SETSIZE as usize {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("cpu {cpu} is too big"),
+                ));
+            }
+
+            // SAFETY: the cpu is inside of the set
+            unsafe { libc::CPU_SET(cpu, &mut set) };
+        }
+
+        // SAFETY: the set is valid for its size
+        if unsafe {
+            libc::sched_setaffinity(thread_id as libc::pid_t, mem::size_of_val(&set), &set)
+        } < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+mod platform {
+    use std::io;
+
+    use common::config::StreamerProcessConfig;
+    use log::warn;
+
+    pub fn apply(pid: u32, config: &StreamerProcessConfig) {
+        if let Some(nice) = config.nice {
+            // SAFETY: setpriority has no preconditions
+            if unsafe { libc::setpriority(libc::PRIO_PROCESS, pid, nice) } < 0 {
+                let err = io::Error::last_os_error();
+                warn!("[Stream]: failed to set the niceness of the streamer to {nice}: {err}");
+            }
+        }
+        if config.cpu_affinity.is_some() {
+            warn!(
+                "[Stream]: setting the cpu affinity of the streamer isn't supported on this platform"
+            );
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod platform {
+    use common::config::StreamerProcessConfig;
+    use log::warn;
+
+    pub fn apply(_pid: u32, _config: &StreamerProcessConfig) {
+        warn!(
+            "[Stream]: setting the niceness and cpu affinity of the streamer isn't supported on this platform"
+        );
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use std::{mem, process::Command};
+
+    use common::config::StreamerProcessConfig;
+
+    use crate::process_scheduling::apply_process_scheduling;
+
+    #[test]
+    fn test_apply_process_scheduling() {
+        let mut child = Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .expect("failed to spawn child");
+        let pid = child.id();
+
+        apply_process_scheduling(
+            Some(pid),
+            &StreamerProcessConfig {
+                // Lowering the priority is always allowed
+                nice: Some(19),
+                cpu_affinity: Some(vec![0]),
+                ..Default::default()
+            },
+        );
+
+        // SAFETY: getpriority has no preconditions
+        let nice = unsafe { libc::getpriority(libc::PRIO_PROCESS, pid) };
+
+        // SAFETY: a zeroed cpu set is empty and valid for its size
+        let mut set = unsafe { mem::zeroed::<libc::cpu_set_t>() };
+        let result = unsafe {
+            libc::sched_getaffinity(pid as libc::pid_t, mem::size_of_val(&set), &mut set)
+        };
+
+        child.kill().expect("failed to kill child");
+        let _ = child.wait();
+
+        assert_eq!(nice, 19);
+        assert_eq!(result, 0);
+        // SAFETY: the cpus are inside of the set
+        assert!(unsafe { libc::CPU_ISSET(0, &set) });
+        assert!(!unsafe { libc::CPU_ISSET(1, &set) });
+    }
+}
@@ -1,24 +1,65 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     sync::{
         atomic::{AtomicU64, Ordering},
-        Arc,
+        Arc, Mutex as StdMutex, PoisonError, Weak,
     },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+use actix_web::web::Bytes;
 use actix_ws::Session;
+use bytestring::ByteString;
 use common::{
-    api_bindings::{PlayerSlot, RoomInfo, RoomParticipant, RoomPlayer, RoomRole, RtcIceServer, StreamCapabilities, StreamServerMessage},
+    api_bindings::{ControllerMode, LogMessageType, PeerPermissions, PlayerSlot, RoomInfo, RoomParticipant, RoomPlayer, RoomRole, RtcIceServer, StreamCapabilities, StreamServerMessage, StreamStageTiming, StreamerMetrics, StreamerResourceMetrics, StreamerStatsType},
+    config::RoomConfig,
     ipc::{PeerId, ServerIpcMessage},
     serialize_json,
 };
+use futures::future::join_all;
 use log::{debug, info, warn};
-use tokio::sync::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    spawn,
+    sync::{Mutex, RwLock, mpsc, oneshot},
+    time::{sleep, timeout},
+};
+
+use crate::{
+    app::user::AuthenticatedUser,
+    display_mode::HostStreamLimits,
+    room_state::{RoomState, RuntimeDirectory},
+};
+
+/// The longest delay a host can set for spectators
+pub const MAX_SPECTATOR_DELAY: Duration = Duration::from_secs(120);
+
+/// How long the changes of a room are collected before they are broadcast in one `RoomUpdated`
+pub const ROOM_UPDATE_DEBOUNCE: Duration = Duration::from_millis(100);
+
+/// How often the shutdown checks if all streamers exited
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How many debug logs of the stream are kept for late joiners
+const MAX_DEBUG_LOG_HISTORY: usize = 100;
+
+/// How many gamepads a player can use from one browser, every one of them takes a player slot
+pub const MAX_GAMEPADS_PER_PEER: usize = PlayerSlot::MAX_PLAYERS;
+
+/// How often the spectators are checked for being idle
+pub const SPECTATOR_IDLE_SWEEP_INTERVAL: Duration = Duration::from_secs(10);
 
 /// Stored stream state so late-joining clients can get connection info
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct StreamState {
+    /// Which start of the stream this state belongs to
+    #[serde(default)]
+    pub epoch: u32,
     pub capabilities: StreamCapabilities,
+    #[serde(default)]
+    pub available_stats: Vec<StreamerStatsType>,
+    #[serde(default)]
+    pub stage_timings: Vec<StreamStageTiming>,
     pub format: u32,
     pub width: u32,
     pub height: u32,
@@ -31,6 +72,71 @@ pub struct StreamState {
     pub audio_mapping: [u8; 8],
 }
 
+impl StreamState {
+    pub fn connection_complete(&self) -> StreamServerMessage {
+        StreamServerMessage::ConnectionComplete {
+            epoch: self.epoch,
+            capabilities: self.capabilities.clone(),
+            available_stats: self.available_stats.clone(),
+            stage_timings: self.stage_timings.clone(),
+            format: self.format,
+            width: self.width,
+            height: self.height,
+            fps: self.fps,
+            audio_sample_rate: self.audio_sample_rate,
+            audio_channel_count: self.audio_channel_count,
+            audio_streams: self.audio_streams,
+            audio_coupled_streams: self.audio_coupled_streams,
+            audio_samples_per_frame: self.audio_samples_per_frame,
+            audio_mapping: self.audio_mapping,
+        }
+    }
+}
+
+/// Whether the stream of a room is running.
+/// Only the `ConnectionComplete` of a running stream is replayed to late joiners.
+#[derive(Clone)]
+pub enum RoomStreamState {
+    /// No stream was started yet
+    NoStream,
+    /// The stream is (re)starting, clients get the new `ConnectionComplete` with its broadcast
+    Starting {
+        /// The epoch of the last start before this one, its `ConnectionComplete` and older ones are stale
+        superseded_epoch: Option<u32>,
+    },
+    Active(StreamState),
+}
+
+impl RoomStreamState {
+    pub fn active(&self) -> Option<&StreamState> {
+        match self {
+            Self::Active(state) => Some(state),
+            Self::NoStream | Self::Starting { .. } => None,
+        }
+    }
+}
+
+/// A debug log of the stream, replayed to late joiners
+#[derive(Clone)]
+struct DebugLogEntry {
+    message: String,
+    ty: Option<LogMessageType>,
+}
+
+/// The host requested to stop the stream
+#[derive(Clone, Copy)]
+pub struct StopRequest {
+    pub quit_app: bool,
+}
+
+/// A personal invite of the host which puts a guest into a player slot with a name
+#[derive(Debug, Clone)]
+pub struct SlotInvite {
+    pub slot: PlayerSlot,
+    pub name: String,
+    pub expires_at: Instant,
+}
+
 /// Global counter for generating unique peer IDs
 static PEER_ID_COUNTER: AtomicU64 = AtomicU64::new(1);
 
@@ -55,11 +161,20 @@ fn generate_room_id() -> String {
     chars
 }
 
+/// Generate the token of a slot invite, it's long enough that it can't be guessed
+fn generate_invite_token() -> String {
+    use rand::Rng;
+    let bytes: [u8; 16] = rand::thread_rng().r#gen();
+    hex::encode(bytes)
+}
+
 /// Represents a connected participant in a room (player or spectator)
 pub struct RoomClient {
     pub peer_id: PeerId,
     /// Player slot if this is a player, None if spectator
     pub player_slot: Option<PlayerSlot>,
+    /// The player slots of the additional gamepads of this player on the same machine
+    pub local_slots: Vec<PlayerSlot>,
     /// Role in the room
     pub role: RoomRole,
     pub player_name: Option<String>,
@@ -67,7 +182,11 @@ pub struct RoomClient {
     pub discord_user_id: Option<String>,
     /// Discord avatar URL
     pub discord_avatar: Option<String>,
+    /// Which input this client may send, enforced by the streamer
+    pub permissions: PeerPermissions,
     pub session: Session,
+    /// When the client last sent a message, updated by the task reading its web socket
+    pub activity: ClientActivity,
     #[allow(dead_code)]
     pub video_frame_queue_size: usize,
     #[allow(dead_code)]
@@ -75,21 +194,37 @@ pub struct RoomClient {
 }
 
 impl RoomClient {
-    pub fn to_room_player(&self) -> Option<RoomPlayer> {
-        self.player_slot.map(|slot| RoomPlayer {
-            slot,
+    /// The player of this client followed by the players of its additional gamepads
+    pub fn to_room_players(&self) -> Vec<RoomPlayer> {
+        let Some(player_slot) = self.player_slot else {
+            return Vec::new();
+        };
+
+        let player = RoomPlayer {
+            slot: player_slot,
             name: self.player_name.clone(),
             is_host: self.role.is_host(),
-        })
+            shares_connection_with: None,
+        };
+        let local_players = self.local_slots.iter().map(|slot| RoomPlayer {
+            slot: *slot,
+            name: self.player_name.clone(),
+            is_host: false,
+            shares_connection_with: Some(player_slot),
+        });
+
+        std::iter::once(player).chain(local_players).collect()
     }
 
     pub fn to_participant(&self) -> RoomParticipant {
         RoomParticipant {
+            peer_id: self.peer_id,
             slot: self.player_slot,
             role: self.role,
             name: self.player_name.clone(),
             discord_user_id: self.discord_user_id.clone(),
             discord_avatar: self.discord_avatar.clone(),
+            permissions: self.permissions,
         }
     }
 
@@ -103,9 +238,130 @@ impl RoomClient {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IdleState {
+    Active,
+    Warned,
+    Disconnected,
+}
+
+#[derive(Debug)]
+struct Activity {
+    last: tokio::time::Instant,
+    state: IdleState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IdleAction {
+    Warn { seconds_remaining: u32 },
+    Disconnect,
+}
+
+/// When a client last sent a message, shared between the room and the task reading its web socket.
+/// It's removed together with the client, so nothing is left behind when the client leaves.
+#[derive(Debug, Clone)]
+pub struct ClientActivity(Arc<StdMutex<Activity>>);
+
+impl Default for ClientActivity {
+    fn default() -> Self {
+        Self(Arc::new(StdMutex::new(Activity {
+            last: tokio::time::Instant::now(),
+            state: IdleState::Active,
+        })))
+    }
+}
+
+impl ClientActivity {
+    /// The client sent something, a warned client isn't disconnected anymore
+    pub fn record(&self) {
+        let mut activity = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        activity.last = tokio::time::Instant::now();
+        activity.state = IdleState::Active;
+    }
+
+    /// What to do with the client, every client is only warned and disconnected once
+    fn check(
+        &self,
+        now: tokio::time::Instant,
+        warning: Duration,
+        timeout: Duration,
+    ) -> Option<IdleAction> {
+        let mut activity = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        let idle = now.saturating_duration_since(activity.last);
+
+        if idle >= timeout {
+            if activity.state == IdleState::Disconnected {
+                return None;
+            }
+            activity.state = IdleState::Disconnected;
+
+            Some(IdleAction::Disconnect)
+        } else if idle >= warning && activity.state == IdleState::Active {
+            activity.state = IdleState::Warned;
+
+            Some(IdleAction::Warn {
+                seconds_remaining: (timeout - idle).as_secs() as u32,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+/// A spectator waiting for a free spectator slot
+struct QueuedSpectator {
+    client: RoomClient,
+    admitted: oneshot::Sender<()>,
+}
+
+/// The result of a spectator trying to join a room
+pub enum SpectatorJoin {
+    /// The spectator was added to the room
+    Joined,
+    /// The room is full, the receiver completes once the spectator was added to the room.
+    /// If the sender is dropped the room was closed.
+    Queued {
+        position: usize,
+        admitted: oneshot::Receiver<()>,
+    },
+    /// The room is full and queueing is disabled
+    Rejected,
+}
+
+/// The user who started the stream of a room
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomOwner {
+    pub user_id: u32,
+    /// None if the name couldn't be loaded when the room was created
+    pub name: Option<String>,
+}
+
+impl RoomOwner {
+    pub async fn new(user: &mut AuthenticatedUser) -> Self {
+        Self {
+            user_id: user.id().0,
+            name: match user.name().await {
+                Ok(name) => Some(name),
+                Err(err) => {
+                    warn!(
+                        "[Stream]: failed to get the name of user {:?}: {err}",
+                        user.id()
+                    );
+                    None
+                }
+            },
+        }
+    }
+}
+
 /// Represents an active streaming room
 pub struct Room {
     pub room_id: String,
+    created_at: Instant,
+    /// The wall clock time of `created_at`, it's kept when the room is restored
+    pub created_time: SystemTime,
+    /// None if the room was restored without its creator
+    pub created_by: Option<RoomOwner>,
     pub host_id: u32,
     pub app_id: u32,
     pub app_name: String,
@@ -118,16 +374,56 @@ pub struct Room {
     occupied_slots: [bool; PlayerSlot::MAX_PLAYERS],
     /// Whether guests (non-host players) can use keyboard/mouse
     pub guests_keyboard_mouse_enabled: bool,
+    /// How long spectators see the stream after the players, zero if they see it live
+    pub spectator_delay: Duration,
+    /// How many spectators can watch at the same time, changed with [Room::set_max_spectators]
+    max_spectators: usize,
+    /// How the gamepads of the players are mapped to the gamepads of the host
+    pub controller_mode: ControllerMode,
+    /// Whether a player can use multiple gamepads from one browser
+    pub allow_multiple_gamepads_per_peer: bool,
+    /// Whether spectators joining a full room wait for a free slot
+    spectator_queue_enabled: bool,
+    /// Spectators waiting for a free slot, the first one is admitted next
+    spectator_queue: VecDeque<QueuedSpectator>,
     /// ICE servers for WebRTC - stored so late-joining clients can get them
     pub ice_servers: Option<Vec<RtcIceServer>>,
-    /// Stream state - stored when ConnectionComplete is received so late joiners can get it
-    pub stream_state: Option<StreamState>,
+    /// What the host can stream, the requested mode of a stream is checked against it
+    pub host_limits: HostStreamLimits,
+    /// Stream state - stored when ConnectionComplete is received so late joiners can get it,
+    /// cleared when the stream restarts
+    pub stream_state: RoomStreamState,
+    /// The most recent debug logs of the stream so late joiners get the startup context
+    debug_logs: VecDeque<DebugLogEntry>,
+    /// The user that created the room, used to quit the app on the host
+    pub creator: Option<AuthenticatedUser>,
+    /// The process of the streamer, persisted so a streamer which can't be reattached is killed
+    pub streamer_pid: Option<u32>,
+    /// Set when the host requested to stop the stream until the streamer terminated it
+    pub stop_request: Option<StopRequest>,
+    /// The unused slot invites of the host by their token, they're gone when the room closes
+    slot_invites: HashMap<String, SlotInvite>,
+    /// The last `ResourceReport` of the streamer
+    pub resources: Option<StreamerResourceMetrics>,
+    /// Increased by every scheduled room update, sent in the [RoomInfo]
+    revision: u64,
+    /// Wakes the task which broadcasts `RoomUpdated`, None until the room is shared
+    room_updates: Option<mpsc::Sender<()>>,
 }
 
 impl Room {
-    pub fn new(room_id: String, host_id: u32, app_id: u32, app_name: String) -> Self {
+    pub fn new(
+        room_id: String,
+        host_id: u32,
+        app_id: u32,
+        app_name: String,
+        config: &RoomConfig,
+    ) -> Self {
         Self {
             room_id,
+            created_at: Instant::now(),
+            created_time: SystemTime::now(),
+            created_by: None,
             host_id,
             app_id,
             app_name,
@@ -136,15 +432,91 @@ impl Room {
             ipc_sender: None,
             occupied_slots: [false; PlayerSlot::MAX_PLAYERS],
             guests_keyboard_mouse_enabled: false, // Default: guests cannot use KB/mouse
+            spectator_delay: Duration::ZERO,
+            max_spectators: config.max_spectators,
+            controller_mode: ControllerMode::PerPlayer,
+            allow_multiple_gamepads_per_peer: false,
+            spectator_queue_enabled: config.spectator_queue,
+            spectator_queue: VecDeque::new(),
             ice_servers: None,
-            stream_state: None,
+            host_limits: HostStreamLimits::default(),
+            stream_state: RoomStreamState::NoStream,
+            debug_logs: VecDeque::new(),
+            creator: None,
+            streamer_pid: None,
+            stop_request: None,
+            slot_invites: HashMap::new(),
+            resources: None,
+            revision: 0,
+            room_updates: None,
+        }
+    }
+
+    /// The state to restore this room after a restart of the web server
+    pub fn to_state(&self) -> RoomState {
+        RoomState {
+            room_id: self.room_id.clone(),
+            host_id: self.host_id,
+            app_id: self.app_id,
+            app_name: self.app_name.clone(),
+            ice_servers: self.ice_servers.clone(),
+            stream_state: self.stream_state.active().cloned(),
+            created_by: self.created_by.clone(),
+            created_at: Some(self.created_time),
+            host_limits: self.host_limits.clone(),
+            streamer_pid: self.streamer_pid,
+        }
+    }
+
+    /// The host sent `StartStream`, the running stream is replaced by a new one.
+    /// Late joiners don't get the old `ConnectionComplete` anymore because it would misconfigure their decoder.
+    pub fn start_stream(&mut self) {
+        let superseded_epoch = match &self.stream_state {
+            RoomStreamState::NoStream => None,
+            // The start which is still connecting is superseded too, the streamer numbers every start
+            RoomStreamState::Starting { superseded_epoch } => {
+                Some(superseded_epoch.map_or(1, |epoch| epoch + 1))
+            }
+            RoomStreamState::Active(state) => Some(state.epoch),
+        };
+
+        self.stream_state = RoomStreamState::Starting { superseded_epoch };
+    }
+
+    /// Store the state of a `ConnectionComplete`, false if it belongs to a superseded start of the stream
+    pub fn complete_stream(&mut self, state: StreamState) -> bool {
+        let current = match &self.stream_state {
+            RoomStreamState::NoStream => true,
+            RoomStreamState::Starting { superseded_epoch } => {
+                superseded_epoch.is_none_or(|epoch| epoch < state.epoch)
+            }
+            RoomStreamState::Active(active) => active.epoch <= state.epoch,
+        };
+        if !current {
+            return false;
         }
+
+        self.stream_state = RoomStreamState::Active(state);
+        true
+    }
+
+    /// The `ConnectionComplete` for a client whose transport became ready, None if the stream isn't running
+    pub fn late_join_connection_complete(&self) -> Option<StreamServerMessage> {
+        self.stream_state
+            .active()
+            .map(|state| state.connection_complete())
     }
 
     /// Set whether guests can use keyboard/mouse and notify the streamer
     pub async fn set_guests_keyboard_mouse_enabled(&mut self, enabled: bool) {
         self.guests_keyboard_mouse_enabled = enabled;
 
+        for client in self.clients.values_mut() {
+            if client.role == RoomRole::Player {
+                client.permissions.set_keyboard_mouse(enabled);
+            }
+        }
+
         // Notify the streamer
         if let Some(mut ipc_sender) = self.ipc_sender.clone() {
             ipc_sender
@@ -153,28 +525,226 @@ impl Room {
         }
     }
 
+    /// Set which input a player can use and notify the streamer, false if the client isn't a player
+    pub async fn set_peer_permissions(
+        &mut self,
+        peer_id: PeerId,
+        permissions: PeerPermissions,
+    ) -> bool {
+        let Some(client) = self.clients.get_mut(&peer_id) else {
+            return false;
+        };
+        // The host always has full control and spectators never have any
+        if client.role != RoomRole::Player {
+            return false;
+        }
+        client.permissions = permissions;
+
+        // Notify the streamer
+        if let Some(mut ipc_sender) = self.ipc_sender.clone() {
+            ipc_sender
+                .send(ServerIpcMessage::SetPeerPermissions {
+                    peer_id,
+                    permissions,
+                })
+                .await;
+        }
+
+        true
+    }
+
+    /// The permissions of a client joining with this role
+    pub fn default_permissions(&self, role: RoomRole) -> PeerPermissions {
+        PeerPermissions::default_for(role, self.guests_keyboard_mouse_enabled)
+    }
+
+    /// Set the stream delay of spectators and notify the streamer
+    pub async fn set_spectator_delay(&mut self, delay: Duration) {
+        self.spectator_delay = delay.min(MAX_SPECTATOR_DELAY);
+
+        // Notify the streamer
+        if let Some(mut ipc_sender) = self.ipc_sender.clone() {
+            ipc_sender
+                .send(ServerIpcMessage::SetSpectatorDelay {
+                    delay: self.spectator_delay,
+                })
+                .await;
+        }
+    }
+
+    /// Set how the gamepads of the players are mapped and notify the streamer
+    pub async fn set_controller_mode(&mut self, mode: ControllerMode) {
+        self.controller_mode = mode;
+
+        // Notify the streamer
+        if let Some(mut ipc_sender) = self.ipc_sender.clone() {
+            ipc_sender
+                .send(ServerIpcMessage::SetControllerMode { mode })
+                .await;
+        }
+    }
+
+    /// Set whether a player can use multiple gamepads, disabling it releases all additional slots
+    pub async fn set_allow_multiple_gamepads_per_peer(&mut self, enabled: bool) {
+        self.allow_multiple_gamepads_per_peer = enabled;
+        if enabled {
+            return;
+        }
+
+        let peer_ids = self
+            .clients
+            .values()
+            .filter(|client| !client.local_slots.is_empty())
+            .map(|client| client.peer_id)
+            .collect::<Vec<_>>();
+        for peer_id in peer_ids {
+            self.set_local_gamepad_count(peer_id, 1).await;
+        }
+    }
+
+    /// Give a player a slot for every gamepad after the first one, as far as the room allows it,
+    /// and notify the streamer. Returns true if the slots of the player changed.
+    pub async fn set_local_gamepad_count(&mut self, peer_id: PeerId, count: u8) -> bool {
+        let Some(slots) = self.resize_local_slots(peer_id, count) else {
+            return false;
+        };
+
+        // Notify the streamer
+        if let Some(mut ipc_sender) = self.ipc_sender.clone() {
+            ipc_sender
+                .send(ServerIpcMessage::SetLocalGamepadSlots { peer_id, slots })
+                .await;
+        }
+        true
+    }
+
+    /// Releases or takes the next free slots until the player has one for every gamepad.
+    /// Returns the new local slots, None if they didn't change
+    fn resize_local_slots(&mut self, peer_id: PeerId, count: u8) -> Option<Vec<PlayerSlot>> {
+        let client = self.clients.get_mut(&peer_id)?;
+        // Spectators don't have a slot for their first gamepad
+        if client.player_slot.is_none() {
+            return None;
+        }
+
+        let local_count = if self.allow_multiple_gamepads_per_peer {
+            (count as usize)
+                .min(MAX_GAMEPADS_PER_PEER)
+                .saturating_sub(1)
+        } else {
+            0
+        };
+        let old_slots = client.local_slots.clone();
+
+        while client.local_slots.len() > local_count
+            && let Some(slot) = client.local_slots.pop()
+        {
+            self.occupied_slots[slot.0 as usize] = false;
+        }
+        while client.local_slots.len() < local_count
+            && let Some(slot_idx) = self.occupied_slots.iter().position(|occupied| !occupied)
+        {
+            self.occupied_slots[slot_idx] = true;
+            client.local_slots.push(PlayerSlot(slot_idx as u8));
+        }
+
+        (client.local_slots != old_slots).then(|| client.local_slots.clone())
+    }
+
     pub fn to_room_info(&self) -> RoomInfo {
         RoomInfo {
             room_id: self.room_id.clone(),
             host_id: self.host_id,
             app_id: self.app_id,
             app_name: self.app_name.clone(),
-            players: self.clients.values().filter_map(|c| c.to_room_player()).collect(),
+            players: self.clients.values().flat_map(|c| c.to_room_players()).collect(),
             max_players: self.max_players,
             participants: self.clients.values().map(|c| c.to_participant()).collect(),
             spectator_count: self.spectator_count(),
+            max_spectators: self.max_spectators,
+            controller_mode: self.controller_mode,
+            allow_multiple_gamepads_per_peer: self.allow_multiple_gamepads_per_peer,
+            created_by: None,
+            created_by_name: None,
+            created_at: None,
+            resources: None,
+            revision: self.revision,
+        }
+    }
+
+    /// Broadcasts `RoomUpdated` after [ROOM_UPDATE_DEBOUNCE],
+    /// all changes until then are sent in the same message
+    pub fn schedule_room_update(&mut self) {
+        self.revision += 1;
+
+        if let Some(room_updates) = &self.room_updates {
+            // The channel is full if an update is already scheduled
+            let _ = room_updates.try_send(());
+        }
+    }
+
+    /// Also includes who created the room, which only admins can see
+    pub fn to_admin_room_info(&self) -> RoomInfo {
+        RoomInfo {
+            created_by: self.created_by.as_ref().map(|owner| owner.user_id),
+            created_by_name: self
+                .created_by
+                .as_ref()
+                .and_then(|owner| owner.name.clone()),
+            created_at: self
+                .created_time
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_millis() as u64)
+                .ok(),
+            resources: self.resources.clone(),
+            ..self.to_room_info()
         }
     }
 
+    /// The name of the owner for logs
+    pub fn owner_name(&self) -> &str {
+        self.created_by
+            .as_ref()
+            .and_then(|owner| owner.name.as_deref())
+            .unwrap_or("unknown")
+    }
+
     /// Count the number of spectators
     pub fn spectator_count(&self) -> usize {
         self.clients.values().filter(|c| c.is_spectator()).count()
     }
 
-    /// Count the number of players (non-spectators)
+    /// Count the number of players (non-spectators), including the additional gamepads of a player
     #[allow(dead_code)]
     pub fn player_count(&self) -> usize {
-        self.clients.values().filter(|c| c.is_player()).count()
+        self.clients
+            .values()
+            .filter(|c| c.is_player())
+            .map(|c| 1 + c.local_slots.len())
+            .sum()
+    }
+
+    /// Remember a debug log of the stream, the oldest logs are dropped when the history is full
+    pub fn push_debug_log(&mut self, message: String, ty: Option<LogMessageType>) {
+        if self.debug_logs.len() >= MAX_DEBUG_LOG_HISTORY {
+            self.debug_logs.pop_front();
+        }
+        self.debug_logs.push_back(DebugLogEntry { message, ty });
+    }
+
+    /// The debug logs to replay to a client joining with this role, spectators don't get any
+    pub fn debug_log_history(&self, role: RoomRole) -> Vec<StreamServerMessage> {
+        if role.is_spectator() {
+            return Vec::new();
+        }
+
+        self.debug_logs
+            .iter()
+            .map(|entry| StreamServerMessage::DebugLog {
+                message: entry.message.clone(),
+                ty: entry.ty,
+            })
+            .collect()
     }
 
     /// Get the next available player slot
@@ -187,6 +757,58 @@ impl Room {
         None
     }
 
+    pub fn is_slot_free(&self, slot: PlayerSlot) -> bool {
+        self.occupied_slots
+            .get(slot.0 as usize)
+            .is_some_and(|occupied| !occupied)
+    }
+
+    /// Creates a single use invite into the slot, None for the slot of the host
+    pub fn create_slot_invite(
+        &mut self,
+        slot: PlayerSlot,
+        name: String,
+        expires_at: Instant,
+        now: Instant,
+    ) -> Option<String> {
+        if slot.is_host() || slot.0 as usize >= PlayerSlot::MAX_PLAYERS {
+            return None;
+        }
+
+        self.slot_invites
+            .retain(|_, invite| invite.expires_at > now);
+
+        let token = generate_invite_token();
+        self.slot_invites.insert(
+            token.clone(),
+            SlotInvite {
+                slot,
+                name,
+                expires_at,
+            },
+        );
+
+        Some(token)
+    }
+
+    /// False if the invite was already used or revoked
+    pub fn revoke_slot_invite(&mut self, token: &str) -> bool {
+        self.slot_invites.remove(token).is_some()
+    }
+
+    pub fn has_slot_invite(&self, token: &str, now: Instant) -> bool {
+        self.slot_invites
+            .get(token)
+            .is_some_and(|invite| invite.expires_at > now)
+    }
+
+    /// Uses up the invite, None if it doesn't exist or expired
+    pub fn take_slot_invite(&mut self, token: &str, now: Instant) -> Option<SlotInvite> {
+        self.slot_invites
+            .remove(token)
+            .filter(|invite| invite.expires_at > now)
+    }
+
     /// Add a client (player or spectator) to the room
     pub fn add_client(&mut self, client: RoomClient) -> bool {
         // If client has a player slot, check and mark it as occupied
@@ -197,28 +819,104 @@ impl Room {
             }
             self.occupied_slots[slot_idx] = true;
         }
-        // Spectators don't need a slot - unlimited spectators allowed
+        // Spectators don't need a slot, they join with add_spectator which checks the spectator limit
 
         self.clients.insert(client.peer_id, client);
         true
     }
 
-    /// Add a spectator to the room
-    #[allow(dead_code)]
+    /// Add a spectator to the room, false if the spectator limit is reached.
+    /// Every spectator gets the stream broadcast by the room, so the limit protects the room from too many of them.
     pub fn add_spectator(&mut self, client: RoomClient) -> bool {
         // Spectators should not have a player slot
         if client.player_slot.is_some() || !client.is_spectator() {
             return false;
         }
+        if self.spectator_count() >= self.max_spectators {
+            return false;
+        }
         self.clients.insert(client.peer_id, client);
         true
     }
 
+    /// Add a spectator to the room if a spectator slot is free, otherwise queue or reject them
+    pub fn join_spectator(&mut self, client: RoomClient) -> SpectatorJoin {
+        // Queued spectators are admitted first
+        if self.spectator_queue.is_empty() && self.spectator_count() < self.max_spectators {
+            if self.add_spectator(client) {
+                return SpectatorJoin::Joined;
+            }
+            return SpectatorJoin::Rejected;
+        }
+
+        if !self.spectator_queue_enabled {
+            return SpectatorJoin::Rejected;
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        self.spectator_queue.push_back(QueuedSpectator {
+            client,
+            admitted: sender,
+        });
+
+        SpectatorJoin::Queued {
+            position: self.spectator_queue.len(),
+            admitted: receiver,
+        }
+    }
+
+    /// Remove a spectator from the queue, false if they aren't queued
+    pub fn remove_queued_spectator(&mut self, peer_id: PeerId) -> bool {
+        let len = self.spectator_queue.len();
+        self.spectator_queue
+            .retain(|queued| queued.client.peer_id != peer_id);
+        self.spectator_queue.len() != len
+    }
+
+    /// Change how many spectators can watch, spectators that already watch aren't removed
+    pub fn set_max_spectators(&mut self, max_spectators: u32) {
+        self.max_spectators = usize::try_from(max_spectators).unwrap_or(usize::MAX);
+    }
+
+    /// Admit queued spectators into free slots and send the remaining ones their new position
+    pub async fn update_spectator_queue(&mut self) {
+        while self.spectator_count() < self.max_spectators
+            && let Some(queued) = self.spectator_queue.pop_front()
+        {
+            let peer_id = queued.client.peer_id;
+            self.clients.insert(peer_id, queued.client);
+
+            // The spectator stopped waiting in the meantime
+            if queued.admitted.send(()).is_err() {
+                self.clients.remove(&peer_id);
+            }
+        }
+
+        // Every spectator gets their own position, a slow one mustn't hold back the others
+        let outgoing = self
+            .spectator_queue
+            .iter()
+            .enumerate()
+            .filter_map(|(index, queued)| {
+                let json = serialize_json(&StreamServerMessage::QueuePosition {
+                    position: index + 1,
+                })?;
+
+                Some(OutgoingMessage::new(
+                    OutgoingPayload::Text(json.into()),
+                    [&queued.client],
+                ))
+            })
+            .collect::<Vec<_>>();
+
+        join_all(outgoing.into_iter().map(OutgoingMessage::send)).await;
+    }
+
     /// Remove a client from the room
     pub fn remove_client(&mut self, peer_id: PeerId) -> Option<RoomClient> {
         if let Some(client) = self.clients.remove(&peer_id) {
-            // Free up the player slot if this was a player
-            if let Some(slot) = client.player_slot {
+            // Free up the player slots if this was a player
+            for slot in client.player_slot.iter().chain(&client.local_slots) {
                 let slot_idx = slot.0 as usize;
                 if slot_idx < PlayerSlot::MAX_PLAYERS {
                     self.occupied_slots[slot_idx] = false;
@@ -244,6 +942,8 @@ impl Room {
 
         client.role = RoomRole::Player;
         client.player_slot = Some(slot);
+        client.permissions =
+            PeerPermissions::default_for(RoomRole::Player, self.guests_keyboard_mouse_enabled);
         self.occupied_slots[slot.0 as usize] = true;
 
         Some(slot)
@@ -262,8 +962,13 @@ impl Room {
             return false;
         }
 
-        // Free up the player slot
-        if let Some(slot) = client.player_slot.take() {
+        // Free up the player slots
+        for slot in client
+            .player_slot
+            .take()
+            .into_iter()
+            .chain(client.local_slots.drain(..))
+        {
             let slot_idx = slot.0 as usize;
             if slot_idx < PlayerSlot::MAX_PLAYERS {
                 self.occupied_slots[slot_idx] = false;
@@ -271,11 +976,11 @@ impl Room {
         }
 
         client.role = RoomRole::Spectator;
+        client.permissions = PeerPermissions::NONE;
         true
     }
 
     /// Find a client by Discord user ID
-    #[allow(dead_code)]
     pub fn find_by_discord_id(&self, discord_user_id: &str) -> Option<PeerId> {
         self.clients
             .iter()
@@ -288,14 +993,45 @@ impl Room {
         self.clients.is_empty()
     }
 
+    /// Check if the room has no clients or its streamer exited without closing the room.
+    /// Rooms younger than `min_age` are never orphaned because they might still be set up.
+    pub fn is_orphaned(&self, min_age: Duration) -> bool {
+        if self.created_at.elapsed() < min_age {
+            return false;
+        }
+
+        let streamer_exited = self
+            .ipc_sender
+            .as_ref()
+            .is_some_and(|ipc_sender| ipc_sender.is_closed());
+
+        self.is_empty() || streamer_exited
+    }
+
+    /// Check if the streamer of this room is still running
+    pub fn has_running_streamer(&self) -> bool {
+        self.ipc_sender
+            .as_ref()
+            .is_some_and(|ipc_sender| !ipc_sender.is_closed())
+    }
+
     /// Check if the host is still connected
-    #[allow(dead_code)]
     pub fn has_host(&self) -> bool {
         self.clients
             .values()
             .any(|c| c.role.is_host())
     }
 
+    /// The creator of a restored room takes the host role back when joining
+    pub fn can_reclaim_host(&self, user_id: u32) -> bool {
+        !self.has_host()
+            && self.is_slot_free(PlayerSlot::PLAYER_1)
+            && self
+                .created_by
+                .as_ref()
+                .is_some_and(|owner| owner.user_id == user_id)
+    }
+
     /// Get a client by peer ID
     #[allow(dead_code)]
     pub fn get_client(&self, peer_id: PeerId) -> Option<&RoomClient> {
@@ -309,36 +1045,188 @@ impl Room {
     }
 
     /// Broadcast a message to all clients
-    pub async fn broadcast(&self, message: StreamServerMessage) {
-        let Some(json) = serialize_json(&message) else {
-            return;
-        };
+    pub async fn broadcast(&self, message: StreamServerMessage) -> Vec<SendResult> {
+        match self.prepare_broadcast(&message) {
+            Some(outgoing) => outgoing.send().await,
+            None => Vec::new(),
+        }
+    }
+
+    /// Takes a message for all clients out of the room, send it after releasing the room lock
+    pub fn prepare_broadcast(&self, message: &StreamServerMessage) -> Option<OutgoingMessage> {
+        let json = serialize_json(message)?;
+
+        Some(OutgoingMessage::new(
+            OutgoingPayload::Text(json.into()),
+            self.clients.values(),
+        ))
+    }
+
+    /// Takes a message for one peer out of the room, send it after releasing the room lock
+    pub fn prepare_send_to_peer(
+        &self,
+        peer_id: PeerId,
+        message: &StreamServerMessage,
+    ) -> Option<OutgoingMessage> {
+        let json = serialize_json(message)?;
+
+        Some(OutgoingMessage::new(
+            OutgoingPayload::Text(json.into()),
+            self.clients.get(&peer_id),
+        ))
+    }
+
+    /// Takes binary data for all clients out of the room, send it after releasing the room lock
+    pub fn prepare_broadcast_binary(&self, data: Bytes) -> OutgoingMessage {
+        OutgoingMessage::new(OutgoingPayload::Binary(data), self.clients.values())
+    }
+
+    /// Takes binary data for one peer out of the room, send it after releasing the room lock
+    pub fn prepare_send_binary_to_peer(&self, peer_id: PeerId, data: Bytes) -> OutgoingMessage {
+        OutgoingMessage::new(OutgoingPayload::Binary(data), self.clients.get(&peer_id))
+    }
+}
+
+/// How long a message may wait for one client before it's given up for that client,
+/// a client with a congested connection would otherwise hold back everyone else
+pub const CLIENT_SEND_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// After this many timed out sends in a row a client can't keep up with the stream
+pub const MAX_CONSECUTIVE_SEND_TIMEOUTS: u32 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendError {
+    /// The session of the client is closed
+    Closed,
+    /// The client didn't accept the message within the timeout
+    Timeout,
+}
+
+pub type SendResult = (PeerId, Result<(), SendError>);
+
+#[derive(Clone)]
+enum OutgoingPayload {
+    /// Serialized once and shared between all sessions
+    Text(ByteString),
+    Binary(Bytes),
+}
+
+/// A message together with the sessions it's sent to.
+/// The sessions are cloned out of the room so the room lock isn't held while waiting for slow clients.
+#[must_use = "the message is only sent when calling `send`"]
+pub struct OutgoingMessage {
+    payload: OutgoingPayload,
+    sessions: Vec<(PeerId, Session)>,
+}
+
+impl OutgoingMessage {
+    fn new<'a>(
+        payload: OutgoingPayload,
+        clients: impl IntoIterator<Item = &'a RoomClient>,
+    ) -> Self {
+        Self {
+            payload,
+            sessions: clients
+                .into_iter()
+                .map(|client| (client.peer_id, client.session.clone()))
+                .collect(),
+        }
+    }
 
-        for client in self.clients.values() {
-            let mut session = client.session.clone();
-            if let Err(err) = session.text(json.clone()).await {
-                warn!(
-                    "Failed to send message to peer {:?}: {:?}",
-                    client.peer_id, err
-                );
+    /// Sends the message to all sessions at the same time and returns the result of every peer
+    pub async fn send(self) -> Vec<SendResult> {
+        self.send_with_timeout(CLIENT_SEND_TIMEOUT).await
+    }
+
+    async fn send_with_timeout(self, send_timeout: Duration) -> Vec<SendResult> {
+        let payload = self.payload;
+
+        join_all(self.sessions.into_iter().map(|(peer_id, mut session)| {
+            let payload = payload.clone();
+
+            async move {
+                let send = async {
+                    match payload {
+                        OutgoingPayload::Text(text) => session.text(text).await,
+                        OutgoingPayload::Binary(data) => session.binary(data).await,
+                    }
+                };
+
+                let result = match timeout(send_timeout, send).await {
+                    Ok(Ok(())) => Ok(()),
+                    Ok(Err(_)) => Err(SendError::Closed),
+                    Err(_) => Err(SendError::Timeout),
+                };
+                if let Err(err) = result {
+                    warn!("Failed to send message to peer {peer_id:?}: {err:?}");
+                }
+
+                (peer_id, result)
+            }
+        }))
+        .await
+    }
+}
+
+/// Counts the timed out sends in a row of every peer to find the clients which can't keep up
+#[derive(Default)]
+pub struct SlowClients {
+    timeouts: HashMap<PeerId, u32>,
+}
+
+impl SlowClients {
+    /// Returns the peers which just reached [MAX_CONSECUTIVE_SEND_TIMEOUTS]
+    pub fn record(&mut self, results: &[SendResult]) -> Vec<PeerId> {
+        let mut slow = Vec::new();
+
+        for (peer_id, result) in results {
+            match result {
+                Err(SendError::Timeout) => {
+                    let timeouts = self.timeouts.entry(*peer_id).or_default();
+                    *timeouts += 1;
+
+                    if *timeouts == MAX_CONSECUTIVE_SEND_TIMEOUTS {
+                        slow.push(*peer_id);
+                    }
+                }
+                Ok(()) | Err(SendError::Closed) => {
+                    self.timeouts.remove(peer_id);
+                }
             }
         }
+
+        slow
     }
+}
+
+/// Starts the task which sends the scheduled room updates, it stops once the room is dropped
+fn share_room(mut room: Room) -> Arc<Mutex<Room>> {
+    let (room_updates, receiver) = mpsc::channel(1);
+    room.room_updates = Some(room_updates);
 
-    /// Send a message to a specific peer
-    pub async fn send_to_peer(&self, peer_id: PeerId, message: StreamServerMessage) {
-        let Some(json) = serialize_json(&message) else {
+    let room = Arc::new(Mutex::new(room));
+    spawn(send_room_updates(Arc::downgrade(&room), receiver));
+    room
+}
+
+async fn send_room_updates(room: Weak<Mutex<Room>>, mut receiver: mpsc::Receiver<()>) {
+    while receiver.recv().await.is_some() {
+        sleep(ROOM_UPDATE_DEBOUNCE).await;
+        // Changes while waiting are included in this update
+        while receiver.try_recv().is_ok() {}
+
+        let Some(room) = room.upgrade() else {
             return;
         };
+        let outgoing = {
+            let room_guard = room.lock().await;
+            room_guard.prepare_broadcast(&StreamServerMessage::RoomUpdated {
+                room: room_guard.to_room_info(),
+            })
+        };
 
-        if let Some(client) = self.clients.get(&peer_id) {
-            let mut session = client.session.clone();
-            if let Err(err) = session.text(json).await {
-                warn!(
-                    "Failed to send message to peer {:?}: {:?}",
-                    peer_id, err
-                );
-            }
+        if let Some(outgoing) = outgoing {
+            outgoing.send().await;
         }
     }
 }
@@ -349,13 +1237,16 @@ pub struct RoomManager {
     rooms: RwLock<HashMap<String, Arc<Mutex<Room>>>>,
     /// Map peer_id to room_id for quick lookup
     peer_to_room: RwLock<HashMap<PeerId, String>>,
+    /// The defaults of new rooms
+    config: RoomConfig,
 }
 
 impl RoomManager {
-    pub fn new() -> Self {
+    pub fn new(config: RoomConfig) -> Self {
         Self {
             rooms: RwLock::new(HashMap::new()),
             peer_to_room: RwLock::new(HashMap::new()),
+            config,
         }
     }
 
@@ -365,19 +1256,48 @@ impl RoomManager {
         host_id: u32,
         app_id: u32,
         app_name: String,
+        created_by: Option<RoomOwner>,
     ) -> Arc<Mutex<Room>> {
         let room_id = generate_room_id();
-        let room = Arc::new(Mutex::new(Room::new(
-            room_id.clone(),
-            host_id,
-            app_id,
-            app_name,
-        )));
+
+        let mut room = Room::new(room_id.clone(), host_id, app_id, app_name, &self.config);
+        room.created_by = created_by;
+        info!("Created room {} for {}", room_id, room.owner_name());
+        let room = share_room(room);
 
         let mut rooms = self.rooms.write().await;
         rooms.insert(room_id.clone(), room.clone());
 
-        info!("Created room {}", room_id);
+        room
+    }
+
+    /// Add a room whose streamer kept running while the web server restarted
+    pub async fn restore_room(&self, state: RoomState) -> Arc<Mutex<Room>> {
+        let room_id = state.room_id.clone();
+
+        let mut room = Room::new(
+            state.room_id,
+            state.host_id,
+            state.app_id,
+            state.app_name,
+            &self.config,
+        );
+        room.ice_servers = state.ice_servers;
+        room.host_limits = state.host_limits;
+        room.created_by = state.created_by;
+        room.streamer_pid = state.streamer_pid;
+        if let Some(created_time) = state.created_at {
+            room.created_time = created_time;
+        }
+        room.stream_state = state
+            .stream_state
+            .map_or(RoomStreamState::NoStream, RoomStreamState::Active);
+        let room = share_room(room);
+
+        let mut rooms = self.rooms.write().await;
+        rooms.insert(room_id.clone(), room.clone());
+
+        info!("Restored room {}", room_id);
         room
     }
 
@@ -429,7 +1349,10 @@ impl RoomManager {
     /// Delete a room
     pub async fn delete_room(&self, room_id: &str) {
         let mut rooms = self.rooms.write().await;
-        if rooms.remove(room_id).is_some() {
+        if let Some(room) = rooms.remove(room_id) {
+            // Dropping the queue tells the waiting spectators that the room is closed
+            room.lock().await.spectator_queue.clear();
+
             info!("Deleted room {}", room_id);
         }
 
@@ -438,19 +1361,232 @@ impl RoomManager {
         peer_to_room.retain(|_, rid| rid != room_id);
     }
 
+    /// Close a room: notify all clients, stop the streamer and delete the room
+    pub async fn close_room(&self, room_id: &str) {
+        if let Some(room) = self.get_room(room_id).await {
+            let room_guard = room.lock().await;
+
+            room_guard.broadcast(StreamServerMessage::RoomClosed).await;
+
+            if let Some(mut ipc_sender) = room_guard.ipc_sender.clone() {
+                ipc_sender.send(ServerIpcMessage::Stop).await;
+            }
+        }
+
+        self.delete_room(room_id).await;
+    }
+
+    /// Delete all orphaned rooms and peers which belong to a deleted room, returns the ids of the deleted rooms
+    pub async fn remove_orphaned_rooms(&self, min_age: Duration) -> Vec<String> {
+        let mut orphaned_rooms = Vec::new();
+        {
+            let rooms = self.rooms.read().await;
+            for (room_id, room) in rooms.iter() {
+                let room_guard = room.lock().await;
+                if room_guard.is_orphaned(min_age) {
+                    room_guard.broadcast(StreamServerMessage::RoomClosed).await;
+
+                    // A streamer of a persisted room would wait for the ipc to reconnect
+                    if let Some(mut ipc_sender) = room_guard.ipc_sender.clone()
+                        && !ipc_sender.is_closed()
+                    {
+                        ipc_sender.send(ServerIpcMessage::Stop).await;
+                    }
+
+                    orphaned_rooms.push(room_id.clone());
+                }
+            }
+        }
+
+        for room_id in &orphaned_rooms {
+            warn!("Removing orphaned room {}", room_id);
+            self.delete_room(room_id).await;
+        }
+
+        // Peers of rooms that were deleted without removing them
+        let rooms = self.rooms.read().await;
+        let mut peer_to_room = self.peer_to_room.write().await;
+        peer_to_room.retain(|peer_id, room_id| {
+            let exists = rooms.contains_key(room_id);
+            if !exists {
+                debug!("Removing peer {:?} of missing room {}", peer_id, room_id);
+            }
+            exists
+        });
+
+        orphaned_rooms
+    }
+
+    /// Tells all clients that the server is shutting down and stops every streamer.
+    /// Waits until the streamers exited or the timeout passed, then closes the remaining sessions and deletes all rooms.
+    ///
+    /// If the rooms are persisted the streamers keep running so the rooms can be restored after the restart.
+    pub async fn shutdown(&self, timeout: Duration) {
+        if RuntimeDirectory::from_config(&self.config).is_some() {
+            self.detach().await;
+            return;
+        }
+
+        {
+            let rooms = self.rooms.read().await;
+            for room in rooms.values() {
+                let room_guard = room.lock().await;
+
+                room_guard
+                    .broadcast(StreamServerMessage::DebugLog {
+                        message: "Server shutting down".to_string(),
+                        ty: Some(LogMessageType::FatalDescription),
+                    })
+                    .await;
+
+                if let Some(mut ipc_sender) = room_guard.ipc_sender.clone() {
+                    ipc_sender.send(ServerIpcMessage::Stop).await;
+                }
+            }
+        }
+
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline && self.has_running_streamers().await {
+            sleep(SHUTDOWN_POLL_INTERVAL).await;
+        }
+
+        let rooms = self.rooms.read().await.clone();
+        for (room_id, room) in rooms {
+            {
+                let room_guard = room.lock().await;
+                if room_guard.has_running_streamer() {
+                    warn!("Streamer of room {} didn't stop in time", room_id);
+                }
+
+                for client in room_guard.clients.values() {
+                    let _ = client.session.clone().close(None).await;
+                }
+            }
+
+            self.delete_room(&room_id).await;
+        }
+    }
+
+    /// Closes all sessions and deletes the rooms without stopping their streamers
+    async fn detach(&self) {
+        let rooms = self.rooms.read().await.clone();
+        for (room_id, room) in rooms {
+            {
+                let room_guard = room.lock().await;
+
+                room_guard
+                    .broadcast(StreamServerMessage::DebugLog {
+                        message: "Server restarting, rejoin the room in a moment".to_string(),
+                        ty: Some(LogMessageType::FatalDescription),
+                    })
+                    .await;
+
+                for client in room_guard.clients.values() {
+                    let _ = client.session.clone().close(None).await;
+                }
+            }
+
+            info!("Detached from the streamer of room {}", room_id);
+            self.delete_room(&room_id).await;
+        }
+    }
+
+    async fn has_running_streamers(&self) -> bool {
+        let rooms = self.rooms.read().await;
+        for room in rooms.values() {
+            if room.lock().await.has_running_streamer() {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Warns the spectators which didn't send anything for `warning` and disconnects them after `timeout`.
+    /// Closing the session ends their web socket task, which removes them like any other leaving client.
+    pub async fn sweep_idle_spectators(&self, warning: Duration, timeout: Duration) {
+        let now = tokio::time::Instant::now();
+
+        let rooms = self.rooms.read().await.clone();
+        for (room_id, room) in rooms {
+            let mut warnings = Vec::new();
+            let mut disconnects = Vec::new();
+            {
+                let room_guard = room.lock().await;
+                for client in room_guard.clients.values() {
+                    if !client.is_spectator() {
+                        continue;
+                    }
+
+                    match client.activity.check(now, warning, timeout) {
+                        Some(IdleAction::Warn { seconds_remaining }) => {
+                            warnings.extend(room_guard.prepare_send_to_peer(
+                                client.peer_id,
+                                &StreamServerMessage::IdleWarning { seconds_remaining },
+                            ));
+                        }
+                        Some(IdleAction::Disconnect) => {
+                            disconnects.push((client.peer_id, client.session.clone()));
+                        }
+                        None => {}
+                    }
+                }
+            }
+
+            for outgoing in warnings {
+                outgoing.send().await;
+            }
+            for (peer_id, session) in disconnects {
+                info!("Disconnecting idle spectator {peer_id:?} of room {room_id}");
+                let _ = session.close(None).await;
+            }
+        }
+    }
+
     /// Generate a new unique peer ID
     pub fn generate_peer_id(&self) -> PeerId {
         generate_peer_id()
     }
 
-    /// List all active rooms (for admin/debugging)
-    pub async fn list_rooms(&self) -> Vec<RoomInfo> {
+    /// If the room has an unexpired slot invite with this token
+    pub async fn has_slot_invite(&self, room_id: &str, token: &str) -> bool {
+        let Some(room) = self.get_room(room_id).await else {
+            return false;
+        };
+
+        room.lock().await.has_slot_invite(token, Instant::now())
+    }
+
+    /// The last resources of every streamer which already reported them
+    pub async fn streamer_metrics(&self) -> Vec<StreamerMetrics> {
+        let rooms = self.rooms.read().await;
+        let mut result = Vec::new();
+
+        for room in rooms.values() {
+            let room_guard = room.lock().await;
+            if let Some(resources) = &room_guard.resources {
+                result.push(StreamerMetrics {
+                    room_id: room_guard.room_id.clone(),
+                    resources: resources.clone(),
+                });
+            }
+        }
+
+        result
+    }
+
+    /// List all active rooms (for admin/debugging), only admins see who created them
+    pub async fn list_rooms(&self, admin: bool) -> Vec<RoomInfo> {
         let rooms = self.rooms.read().await;
         let mut result = Vec::new();
 
         for room in rooms.values() {
             let room_guard = room.lock().await;
-            result.push(room_guard.to_room_info());
+            result.push(if admin {
+                room_guard.to_admin_room_info()
+            } else {
+                room_guard.to_room_info()
+            });
         }
 
         result
@@ -459,6 +1595,618 @@ impl RoomManager {
 
 impl Default for RoomManager {
     fn default() -> Self {
-        Self::new()
+        Self::new(RoomConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        future::poll_fn,
+        pin::Pin,
+        time::{Duration, Instant},
+    };
+
+    use actix_web::{
+        FromRequest,
+        body::{BoxBody, MessageBody},
+        http::header,
+        test::TestRequest,
+        web::{self, Bytes},
+    };
+    use actix_ws::Session;
+    use common::{
+        api_bindings::{
+            LogMessageType, PeerPermissions, PlayerSlot, RoomRole, StreamCapabilities,
+            StreamServerMessage,
+        },
+        config::RoomConfig,
+        ipc::PeerId,
+    };
+    use tokio::{
+        spawn,
+        time::{advance, timeout},
+    };
+
+    use crate::room::{
+        ClientActivity, MAX_CONSECUTIVE_SEND_TIMEOUTS, MAX_DEBUG_LOG_HISTORY, ROOM_UPDATE_DEBOUNCE,
+        Room, RoomClient, RoomManager, RoomOwner, SendError, SlowClients, SpectatorJoin,
+        StreamState,
+    };
+
+    /// A web socket session together with the body the client reads from
+    async fn test_session() -> (Session, BoxBody) {
+        let (request, mut payload) = TestRequest::default()
+            .insert_header((header::UPGRADE, "websocket"))
+            .insert_header((header::CONNECTION, "upgrade"))
+            .insert_header((header::SEC_WEBSOCKET_VERSION, "13"))
+            .insert_header((header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ=="))
+            .to_http_parts();
+        let payload = web::Payload::from_request(&request, &mut payload)
+            .await
+            .expect("failed to get payload");
+
+        let (response, session, _) =
+            actix_ws::handle(&request, payload).expect("failed to create web socket session");
+
+        (session, response.into_body())
+    }
+
+    async fn next_frame(body: &mut BoxBody) -> Option<Bytes> {
+        poll_fn(|cx| Pin::new(&mut *body).poll_next(cx))
+            .await
+            .map(|frame| frame.expect("failed to read frame"))
+    }
+
+    fn test_client(peer_id: PeerId, slot: u8, session: Session) -> RoomClient {
+        RoomClient {
+            peer_id,
+            player_slot: Some(PlayerSlot(slot)),
+            local_slots: Vec::new(),
+            role: RoomRole::Player,
+            player_name: None,
+            discord_user_id: None,
+            discord_avatar: None,
+            permissions: PeerPermissions::NONE,
+            session,
+            activity: ClientActivity::default(),
+            video_frame_queue_size: 4,
+            audio_sample_queue_size: 4,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_remove_orphaned_rooms() {
+        let manager = RoomManager::new(RoomConfig::default());
+
+        let room = manager.create_room(1, 1, "App".to_string(), None).await;
+        let room_id = room.lock().await.room_id.clone();
+        manager.register_peer(PeerId(1), &room_id).await;
+        manager.register_peer(PeerId(2), "missing").await;
+
+        // Young rooms are still being set up
+        assert!(
+            manager
+                .remove_orphaned_rooms(Duration::from_secs(60))
+                .await
+                .is_empty()
+        );
+        assert!(manager.get_room(&room_id).await.is_some());
+        assert!(manager.get_peer_room(PeerId(1)).await.is_some());
+        assert!(!manager.peer_to_room.read().await.contains_key(&PeerId(2)));
+
+        assert_eq!(
+            manager.remove_orphaned_rooms(Duration::ZERO).await,
+            vec![room_id.clone()]
+        );
+        assert!(manager.get_room(&room_id).await.is_none());
+        assert!(manager.peer_to_room.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let manager = RoomManager::new(RoomConfig::default());
+
+        let room = manager.create_room(1, 1, "App".to_string(), None).await;
+        let room_id = room.lock().await.room_id.clone();
+        manager.register_peer(PeerId(1), &room_id).await;
+
+        // Rooms without a streamer don't delay the shutdown
+        timeout(
+            Duration::from_secs(1),
+            manager.shutdown(Duration::from_secs(60)),
+        )
+        .await
+        .expect("shutdown waited for a room without a streamer");
+
+        assert!(manager.get_room(&room_id).await.is_none());
+        assert!(manager.peer_to_room.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_room_owner() {
+        let manager = RoomManager::new(RoomConfig::default());
+
+        let room = manager
+            .create_room(
+                1,
+                1,
+                "App".to_string(),
+                Some(RoomOwner {
+                    user_id: 7,
+                    name: Some("alice".to_string()),
+                }),
+            )
+            .await;
+
+        let [room_info] = &manager.list_rooms(false).await[..] else {
+            panic!("expected one room");
+        };
+        assert_eq!(room_info.created_by, None);
+        assert_eq!(room_info.created_by_name, None);
+        assert_eq!(room_info.created_at, None);
+
+        let [room_info] = &manager.list_rooms(true).await[..] else {
+            panic!("expected one room");
+        };
+        assert_eq!(room_info.created_by, Some(7));
+        assert_eq!(room_info.created_by_name.as_deref(), Some("alice"));
+        assert!(room_info.created_at.is_some());
+
+        // The owner survives a restart of the web server
+        let state = room.lock().await.to_state();
+        manager.delete_room(&state.room_id).await;
+        let restored = manager.restore_room(state).await;
+        let restored = restored.lock().await;
+        assert_eq!(restored.owner_name(), "alice");
+        assert_eq!(
+            restored.to_admin_room_info().created_at,
+            room_info.created_at
+        );
+
+        // Only the owner gets the host role of the restored room back
+        assert!(restored.can_reclaim_host(7));
+        assert!(!restored.can_reclaim_host(8));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_spectators() {
+        const WARNING: Duration = Duration::from_mins(10);
+        const TIMEOUT: Duration = Duration::from_mins(15);
+
+        let manager = RoomManager::new(RoomConfig::default());
+
+        let (mut player_session, mut player_body) = test_session().await;
+        let (mut spectator_session, mut spectator_body) = test_session().await;
+        let room = manager.create_room(1, 1, "App".to_string(), None).await;
+        let activity = {
+            let mut room_guard = room.lock().await;
+            assert!(room_guard.add_client(test_client(PeerId(1), 0, player_session.clone())));
+            assert!(room_guard.add_spectator(RoomClient {
+                player_slot: None,
+                role: RoomRole::Spectator,
+                ..test_client(PeerId(2), 0, spectator_session.clone())
+            }));
+
+            room_guard
+                .get_client(PeerId(2))
+                .expect("missing spectator")
+                .activity
+                .clone()
+        };
+
+        advance(Duration::from_mins(10)).await;
+        manager.sweep_idle_spectators(WARNING, TIMEOUT).await;
+        let frame = next_frame(&mut spectator_body)
+            .await
+            .expect("the session was closed");
+        assert!(frame.ends_with(br#"{"IdleWarning":{"seconds_remaining":300}}"#));
+
+        // The warning is only sent once
+        advance(Duration::from_mins(1)).await;
+        manager.sweep_idle_spectators(WARNING, TIMEOUT).await;
+        spectator_session
+            .text("marker")
+            .await
+            .expect("the spectator was disconnected");
+        let frame = next_frame(&mut spectator_body)
+            .await
+            .expect("the session was closed");
+        assert!(frame.ends_with(b"marker"));
+
+        // Activity after the warning starts over
+        activity.record();
+        advance(Duration::from_mins(14)).await;
+        manager.sweep_idle_spectators(WARNING, TIMEOUT).await;
+        let frame = next_frame(&mut spectator_body)
+            .await
+            .expect("the session was closed");
+        assert!(frame.ends_with(br#"{"IdleWarning":{"seconds_remaining":60}}"#));
+
+        advance(Duration::from_mins(1)).await;
+        manager.sweep_idle_spectators(WARNING, TIMEOUT).await;
+        assert!(spectator_session.text("marker").await.is_err());
+
+        // Players are never idle
+        player_session
+            .text("marker")
+            .await
+            .expect("the player was disconnected");
+        let frame = next_frame(&mut player_body)
+            .await
+            .expect("the session was closed");
+        assert!(frame.ends_with(b"marker"));
+    }
+
+    #[test]
+    fn test_debug_log_history() {
+        let mut room = Room::new(
+            "room".to_string(),
+            1,
+            1,
+            "App".to_string(),
+            &RoomConfig::default(),
+        );
+
+        room.push_debug_log("Starting stream".to_string(), None);
+        for i in 0..MAX_DEBUG_LOG_HISTORY {
+            room.push_debug_log(format!("Log {i}"), Some(LogMessageType::InformError));
+        }
+
+        let history = room.debug_log_history(RoomRole::Player);
+        assert_eq!(history.len(), MAX_DEBUG_LOG_HISTORY);
+        // The oldest log was dropped
+        assert!(matches!(
+            &history[0],
+            StreamServerMessage::DebugLog { message, ty: Some(LogMessageType::InformError) } if message == "Log 0"
+        ));
+
+        assert!(room.debug_log_history(RoomRole::Spectator).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_spectator_limit() {
+        let (session, _body) = test_session().await;
+        let spectator = |peer_id| RoomClient {
+            player_slot: None,
+            role: RoomRole::Spectator,
+            ..test_client(PeerId(peer_id), 0, session.clone())
+        };
+
+        let mut room = Room::new(
+            "room".to_string(),
+            1,
+            1,
+            "App".to_string(),
+            &RoomConfig {
+                max_spectators: 2,
+                spectator_queue: false,
+                ..Default::default()
+            },
+        );
+
+        assert!(matches!(
+            room.join_spectator(spectator(1)),
+            SpectatorJoin::Joined
+        ));
+        assert!(matches!(
+            room.join_spectator(spectator(2)),
+            SpectatorJoin::Joined
+        ));
+        assert!(matches!(
+            room.join_spectator(spectator(3)),
+            SpectatorJoin::Rejected
+        ));
+        // Adding directly can't bypass the limit either
+        assert!(!room.add_spectator(spectator(4)));
+        assert_eq!(room.spectator_count(), 2);
+
+        // A lower limit doesn't remove spectators, but no new ones can join
+        room.set_max_spectators(1);
+        room.remove_client(PeerId(1));
+        assert!(!room.add_spectator(spectator(5)));
+        room.set_max_spectators(2);
+        assert!(room.add_spectator(spectator(5)));
+    }
+
+    #[tokio::test]
+    async fn test_slot_invites() {
+        let mut room = Room::new(
+            "room".to_string(),
+            1,
+            1,
+            "App".to_string(),
+            &RoomConfig::default(),
+        );
+        let now = Instant::now();
+        let expires_at = now + Duration::from_secs(60);
+
+        // The slot of the host can't be given away
+        assert!(
+            room.create_slot_invite(PlayerSlot::PLAYER_1, "Host".to_string(), expires_at, now)
+                .is_none()
+        );
+        assert!(
+            room.create_slot_invite(PlayerSlot(4), "Nobody".to_string(), expires_at, now)
+                .is_none()
+        );
+
+        // An invite can only be used once
+        let token = room
+            .create_slot_invite(PlayerSlot::PLAYER_3, "Alice".to_string(), expires_at, now)
+            .expect("failed to create slot invite");
+        assert!(room.has_slot_invite(&token, now));
+        assert!(!room.has_slot_invite("unknown", now));
+
+        let invite = room
+            .take_slot_invite(&token, now)
+            .expect("slot invite not found");
+        assert_eq!(invite.slot, PlayerSlot::PLAYER_3);
+        assert_eq!(invite.name, "Alice");
+        assert!(room.take_slot_invite(&token, now).is_none());
+
+        // Another player can take the slot before the invite is used
+        let token = room
+            .create_slot_invite(PlayerSlot::PLAYER_2, "Bob".to_string(), expires_at, now)
+            .expect("failed to create slot invite");
+        let (session, _body) = test_session().await;
+        assert!(room.add_client(test_client(PeerId(1), 0, session.clone())));
+        assert!(room.add_client(test_client(PeerId(2), 1, session.clone())));
+
+        let invite = room
+            .take_slot_invite(&token, now)
+            .expect("slot invite not found");
+        assert!(!room.is_slot_free(invite.slot));
+        assert_eq!(room.next_available_slot(), Some(PlayerSlot::PLAYER_3));
+
+        // Expired invites can't be used
+        let token = room
+            .create_slot_invite(PlayerSlot::PLAYER_4, "Carol".to_string(), expires_at, now)
+            .expect("failed to create slot invite");
+        assert!(!room.has_slot_invite(&token, expires_at));
+        assert!(room.take_slot_invite(&token, expires_at).is_none());
+
+        // Nor revoked ones
+        let token = room
+            .create_slot_invite(PlayerSlot::PLAYER_4, "Dave".to_string(), expires_at, now)
+            .expect("failed to create slot invite");
+        assert!(room.revoke_slot_invite(&token));
+        assert!(!room.revoke_slot_invite(&token));
+        assert!(room.take_slot_invite(&token, now).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_local_gamepad_slots() {
+        let mut room = Room::new(
+            "room".to_string(),
+            1,
+            1,
+            "App".to_string(),
+            &RoomConfig::default(),
+        );
+
+        let (session, _body) = test_session().await;
+        assert!(room.add_client(test_client(PeerId(1), 0, session.clone())));
+        assert!(room.add_client(test_client(PeerId(2), 1, session.clone())));
+
+        // Additional gamepads must be allowed by the host
+        assert!(!room.set_local_gamepad_count(PeerId(1), 2).await);
+        assert_eq!(room.player_count(), 2);
+
+        // The second and third gamepad of the first player take the next free slots
+        room.set_allow_multiple_gamepads_per_peer(true).await;
+        assert!(room.set_local_gamepad_count(PeerId(1), 3).await);
+        assert_eq!(
+            room.clients[&PeerId(1)].local_slots,
+            vec![PlayerSlot(2), PlayerSlot(3)]
+        );
+        assert_eq!(room.player_count(), 4);
+        assert_eq!(room.next_available_slot(), None);
+
+        // The additional players are listed with the player whose connection they share
+        let room_info = room.to_room_info();
+        assert_eq!(room_info.players.len(), 4);
+        assert!(
+            room_info
+                .players
+                .iter()
+                .any(|player| player.slot == PlayerSlot(3)
+                    && player.shares_connection_with == Some(PlayerSlot(0)))
+        );
+
+        // The room is full, the second player can't add a gamepad
+        assert!(!room.set_local_gamepad_count(PeerId(2), 2).await);
+
+        // Unplugging a gamepad frees its slot
+        assert!(room.set_local_gamepad_count(PeerId(1), 2).await);
+        assert_eq!(room.next_available_slot(), Some(PlayerSlot(3)));
+        assert!(room.set_local_gamepad_count(PeerId(2), 2).await);
+        assert_eq!(room.clients[&PeerId(2)].local_slots, vec![PlayerSlot(3)]);
+
+        // Leaving frees all slots of the player
+        room.remove_client(PeerId(1));
+        assert_eq!(room.next_available_slot(), Some(PlayerSlot(0)));
+
+        // Disabling it releases the additional slots
+        room.set_allow_multiple_gamepads_per_peer(false).await;
+        assert!(room.clients[&PeerId(2)].local_slots.is_empty());
+        assert_eq!(room.player_count(), 1);
+    }
+
+    fn stream_state(epoch: u32, width: u32, height: u32) -> StreamState {
+        StreamState {
+            epoch,
+            capabilities: StreamCapabilities { touch: false },
+            available_stats: Vec::new(),
+            stage_timings: Vec::new(),
+            format: 1,
+            width,
+            height,
+            fps: 60,
+            audio_sample_rate: 48000,
+            audio_channel_count: 2,
+            audio_streams: 1,
+            audio_coupled_streams: 1,
+            audio_samples_per_frame: 240,
+            audio_mapping: [0, 1, 0, 0, 0, 0, 0, 0],
+        }
+    }
+
+    fn late_join_size(room: &Room) -> Option<(u32, u32, u32)> {
+        match room.late_join_connection_complete()? {
+            StreamServerMessage::ConnectionComplete {
+                epoch,
+                width,
+                height,
+                ..
+            } => Some((epoch, width, height)),
+            message => panic!("unexpected late join message {message:?}"),
+        }
+    }
+
+    #[test]
+    fn test_restart_while_joining() {
+        let mut room = Room::new(
+            "room".to_string(),
+            1,
+            1,
+            "App".to_string(),
+            &RoomConfig::default(),
+        );
+
+        // Nothing to replay before the stream started
+        assert_eq!(late_join_size(&room), None);
+        room.start_stream();
+        assert_eq!(late_join_size(&room), None);
+
+        assert!(room.complete_stream(stream_state(1, 1280, 720)));
+        assert_eq!(late_join_size(&room), Some((1, 1280, 720)));
+
+        // The host changed the resolution, a client joining now must not get the old stream
+        room.start_stream();
+        assert_eq!(late_join_size(&room), None);
+        // The host restarts again before the stream completed
+        room.start_stream();
+
+        // A delayed ConnectionComplete of the old stream is stale
+        assert!(!room.complete_stream(stream_state(1, 1280, 720)));
+        assert_eq!(late_join_size(&room), None);
+        assert!(room.to_state().stream_state.is_none());
+        // So is the one of the superseded restart
+        assert!(!room.complete_stream(stream_state(2, 1600, 900)));
+        assert_eq!(late_join_size(&room), None);
+
+        assert!(room.complete_stream(stream_state(3, 1920, 1080)));
+        assert_eq!(late_join_size(&room), Some((3, 1920, 1080)));
+        assert!(room.to_state().stream_state.is_some());
+
+        // An older start can't replace the running stream
+        assert!(!room.complete_stream(stream_state(2, 1600, 900)));
+        assert_eq!(late_join_size(&room), Some((3, 1920, 1080)));
+
+        // Restarting twice before the first stream completed
+        let mut room = Room::new(
+            "room".to_string(),
+            1,
+            1,
+            "App".to_string(),
+            &RoomConfig::default(),
+        );
+        room.start_stream();
+        room.start_stream();
+        assert!(!room.complete_stream(stream_state(1, 1280, 720)));
+        assert!(room.complete_stream(stream_state(2, 1920, 1080)));
+        assert_eq!(late_join_size(&room), Some((2, 1920, 1080)));
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_with_stalled_client() {
+        let mut room = Room::new(
+            "room".to_string(),
+            1,
+            1,
+            "App".to_string(),
+            &RoomConfig::default(),
+        );
+
+        let (mut stalled_session, _stalled_body) = test_session().await;
+        let (session, mut body) = test_session().await;
+        assert!(room.add_client(test_client(PeerId(1), 0, stalled_session.clone())));
+        assert!(room.add_client(test_client(PeerId(2), 1, session)));
+
+        // The stalled client never reads, so its send buffer fills up
+        while timeout(Duration::from_millis(10), stalled_session.text("filler"))
+            .await
+            .is_ok()
+        {}
+
+        let outgoing = room
+            .prepare_broadcast(&StreamServerMessage::RoomClosed)
+            .expect("failed to serialize message");
+        let send = spawn(outgoing.send_with_timeout(Duration::from_millis(500)));
+
+        // The other client isn't held back by the stalled one
+        let frame = timeout(Duration::from_millis(100), next_frame(&mut body))
+            .await
+            .expect("the message was held back by the stalled client")
+            .expect("the session was closed");
+        assert!(frame.ends_with(b"\"RoomClosed\""));
+
+        let mut results = send.await.expect("failed to send");
+        results.sort_by_key(|(peer_id, _)| peer_id.0);
+        assert_eq!(
+            results,
+            vec![(PeerId(1), Err(SendError::Timeout)), (PeerId(2), Ok(()))]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_room_updates_are_coalesced() {
+        let manager = RoomManager::new(RoomConfig::default());
+
+        let (session, mut body) = test_session().await;
+        let room = manager.create_room(1, 1, "App".to_string(), None).await;
+        {
+            let mut room_guard = room.lock().await;
+            assert!(room_guard.add_client(test_client(PeerId(1), 0, session.clone())));
+
+            for max_spectators in 1..=3 {
+                room_guard.set_max_spectators(max_spectators);
+                room_guard.schedule_room_update();
+            }
+        }
+
+        advance(ROOM_UPDATE_DEBOUNCE).await;
+        let frame = next_frame(&mut body).await.expect("the session was closed");
+        let contains = |needle: &[u8]| frame.windows(needle.len()).any(|window| window == needle);
+        assert!(contains(b"\"RoomUpdated\""));
+        assert!(contains(b"\"max_spectators\":3"));
+        assert!(contains(b"\"revision\":3"));
+
+        // All changes were sent in the first update
+        let mut marker_session = session.clone();
+        marker_session
+            .text("marker")
+            .await
+            .expect("the client was disconnected");
+        let frame = next_frame(&mut body).await.expect("the session was closed");
+        assert!(frame.ends_with(b"marker"));
+    }
+
+    #[test]
+    fn test_slow_clients() {
+        let mut slow_clients = SlowClients::default();
+
+        let results = [(PeerId(1), Err(SendError::Timeout)), (PeerId(2), Ok(()))];
+        for _ in 1..MAX_CONSECUTIVE_SEND_TIMEOUTS {
+            assert!(slow_clients.record(&results).is_empty());
+        }
+        assert_eq!(slow_clients.record(&results), vec![PeerId(1)]);
+        // A slow client is only reported once
+        assert!(slow_clients.record(&results).is_empty());
+
+        // A successful send starts counting again
+        slow_clients.record(&[(PeerId(1), Ok(()))]);
+        assert!(slow_clients.record(&results).is_empty());
     }
 }
@@ -0,0 +1,224 @@
+//! Persists the rooms into `rooms.runtime_path` so their streamers keep running when the web server restarts.
+//!
+//! Every room has up to three files in the runtime directory:
+//! - `<room_id>.json`: the [RoomState] to restore the room
+//! - `<room_id>.sock`: the unix socket the streamer listens on for the ipc
+//! - `<room_id>.log`: the output of the streamer, it can't log through the web server anymore
+
+use std::{
+    io::{self, ErrorKind},
+    path::PathBuf,
+    time::SystemTime,
+};
+
+use common::{api_bindings::RtcIceServer, config::RoomConfig};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::{
+    display_mode::HostStreamLimits,
+    room::{RoomOwner, StreamState},
+};
+
+/// Everything needed to restore a room after a restart, it's saved once the stream is running
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RoomState {
+    pub room_id: String,
+    pub host_id: u32,
+    pub app_id: u32,
+    pub app_name: String,
+    pub ice_servers: Option<Vec<RtcIceServer>>,
+    pub stream_state: Option<StreamState>,
+    #[serde(default)]
+    pub created_by: Option<RoomOwner>,
+    #[serde(default)]
+    pub created_at: Option<SystemTime>,
+    #[serde(default)]
+    pub host_limits: HostStreamLimits,
+    /// The process of the streamer, it's killed if the web server can't reattach to it
+    #[serde(default)]
+    pub streamer_pid: Option<u32>,
+}
+
+/// Only the user of the web server can access the sockets and logs of the streamers
+#[cfg(unix)]
+const RUNTIME_DIRECTORY_MODE: u32 = 0o700;
+
+pub struct RuntimeDirectory {
+    path: PathBuf,
+}
+
+impl RuntimeDirectory {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// None if rooms aren't persisted, this is only supported on unix
+    pub fn from_config(config: &RoomConfig) -> Option<Self> {
+        if cfg!(unix) {
+            config.runtime_path.as_ref().map(Self::new)
+        } else {
+            None
+        }
+    }
+
+    pub fn socket_path(&self, room_id: &str) -> PathBuf {
+        self.path.join(format!("{room_id}.sock"))
+    }
+    pub fn log_path(&self, room_id: &str) -> PathBuf {
+        self.path.join(format!("{room_id}.log"))
+    }
+    fn state_path(&self, room_id: &str) -> PathBuf {
+        self.path.join(format!("{room_id}.json"))
+    }
+
+    /// Creates the runtime directory if it doesn't exist, only the user of the web server can access it
+    pub async fn create(&self) -> io::Result<()> {
+        fs::create_dir_all(&self.path).await?;
+
+        #[cfg(unix)]
+        {
+            use std::{fs::Permissions, os::unix::fs::PermissionsExt};
+
+            fs::set_permissions(&self.path, Permissions::from_mode(RUNTIME_DIRECTORY_MODE)).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn save(&self, state: &RoomState) -> io::Result<()> {
+        let json = serde_json::to_string(state)?;
+
+        fs::write(self.state_path(&state.room_id), json).await
+    }
+
+    /// Removes all files of the room
+    pub async fn remove(&self, room_id: &str) {
+        for path in [
+            self.state_path(room_id),
+            self.socket_path(room_id),
+            self.log_path(room_id),
+        ] {
+            match fs::remove_file(&path).await {
+                Ok(()) => {}
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => warn!("[Rooms]: failed to remove {path:?}: {err}"),
+            }
+        }
+    }
+
+    /// All persisted rooms, files which aren't a valid state are skipped
+    pub async fn load_all(&self) -> io::Result<Vec<RoomState>> {
+        let mut entries = match fs::read_dir(&self.path).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+
+        let mut states = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+
+            let state = match fs::read(&path).await {
+                Ok(json) => serde_json::from_slice::<RoomState>(&json),
+                Err(err) => {
+                    warn!("[Rooms]: failed to read {path:?}: {err}");
+                    continue;
+                }
+            };
+
+            match state {
+                // The room id is used in paths, so it must match the file it came from
+                Ok(state)
+                    if path
+                        .file_stem()
+                        .is_some_and(|stem| stem == state.room_id.as_str())
+                        && state.room_id.chars().all(|c| c.is_ascii_alphanumeric()) =>
+                {
+                    states.push(state);
+                }
+                Ok(_) => warn!("[Rooms]: room state {path:?} doesn't match its file name"),
+                Err(err) => warn!("[Rooms]: invalid room state {path:?}: {err}"),
+            }
+        }
+
+        Ok(states)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        display_mode::HostStreamLimits,
+        room_state::{RoomState, RuntimeDirectory},
+        test_util::test_dir,
+    };
+
+    #[tokio::test]
+    async fn test_save_load_room_states() {
+        let dir = test_dir();
+        let path = dir.path().join("rooms");
+        let directory = RuntimeDirectory::new(&path);
+
+        let room_ids = async || -> Vec<String> {
+            let states = directory.load_all().await.expect("failed to load rooms");
+            states.into_iter().map(|state| state.room_id).collect()
+        };
+
+        // A missing directory has no rooms
+        assert!(room_ids().await.is_empty());
+
+        directory
+            .create()
+            .await
+            .expect("failed to create directory");
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let metadata = std::fs::metadata(&path).expect("failed to read the directory metadata");
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o700);
+        }
+
+        let state = RoomState {
+            room_id: "ABC123".to_string(),
+            host_id: 1,
+            app_id: 2,
+            app_name: "Desktop".to_string(),
+            ice_servers: None,
+            stream_state: None,
+            created_by: None,
+            created_at: None,
+            host_limits: HostStreamLimits::default(),
+            streamer_pid: None,
+        };
+        directory.save(&state).await.expect("failed to save room");
+        tokio::fs::write(directory.log_path(&state.room_id), "log")
+            .await
+            .expect("failed to write log");
+
+        // A renamed state could point to the files of another room
+        tokio::fs::write(
+            path.join("OTHER1.json"),
+            serde_json::to_string(&state).expect("failed to serialize"),
+        )
+        .await
+        .expect("failed to write state");
+        tokio::fs::write(path.join("BROKEN.json"), "{")
+            .await
+            .expect("failed to write state");
+
+        assert_eq!(room_ids().await, vec![state.room_id.clone()]);
+
+        directory.remove(&state.room_id).await;
+        assert!(!directory.log_path(&state.room_id).exists());
+        directory.remove("OTHER1").await;
+        directory.remove("BROKEN").await;
+        assert!(room_ids().await.is_empty());
+    }
+}
@@ -0,0 +1,125 @@
+use std::{
+    env::{self, consts::EXE_EXTENSION},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, bail};
+use common::{config::StreamerProcessConfig, ipc::STREAMER_ROOM_ID_ENV};
+use tokio::process::Command;
+
+/// The command which starts the streamer of a room.
+/// The arguments of the web server still have to be appended.
+pub fn streamer_command(config: &StreamerProcessConfig, room_id: &str) -> Command {
+    let mut command = Command::new(&config.path);
+    command
+        .args(&config.args)
+        .envs(&config.env)
+        .env(STREAMER_ROOM_ID_ENV, room_id);
+
+    if let Some(working_dir) = &config.working_dir {
+        command.current_dir(working_dir);
+    }
+
+    command
+}
+
+/// Resolves the streamer path to an absolute path and checks that it's executable.
+/// Relative paths would otherwise depend on the working directory of the streamer.
+pub fn resolve_streamer_path(config: &StreamerProcessConfig) -> Result<String, anyhow::Error> {
+    if let Some(working_dir) = &config.working_dir
+        && !Path::new(working_dir).is_dir()
+    {
+        bail!("the streamer working directory {working_dir} doesn't exist");
+    }
+
+    let path = Path::new(&config.path);
+    let resolved = if path.is_absolute() || path.components().count() > 1 {
+        executable(std::path::absolute(path)?)
+    } else {
+        env::var_os("PATH").and_then(|paths| {
+            env::split_paths(&paths).find_map(|directory| executable(directory.join(path)))
+        })
+    };
+
+    let Some(resolved) = resolved else {
+        bail!(
+            "the streamer {} doesn't exist or isn't executable",
+            config.path
+        );
+    };
+
+    resolved
+        .into_os_string()
+        .into_string()
+        .ok()
+        .context("the streamer path isn't valid utf-8")
+}
+
+fn executable(path: PathBuf) -> Option<PathBuf> {
+    if is_executable(&path) {
+        return Some(path);
+    }
+
+    // Windows executables can be started without their extension
+    if !EXE_EXTENSION.is_empty() && path.extension().is_none() {
+        let path = path.with_extension(EXE_EXTENSION);
+        if is_executable(&path) {
+            return Some(path);
+        }
+    }
+
+    None
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    path.metadata()
+        .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use std::path::Path;
+
+    use common::config::StreamerProcessConfig;
+
+    use crate::streamer_command::resolve_streamer_path;
+
+    fn config(path: &str) -> StreamerProcessConfig {
+        StreamerProcessConfig {
+            path: path.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_resolve_streamer_path() {
+        let resolved = resolve_streamer_path(&config("sh")).expect("sh should be in the PATH");
+        assert!(Path::new(&resolved).is_absolute());
+
+        assert_eq!(
+            resolve_streamer_path(&config("/bin/sh")).expect("sh should be executable"),
+            "/bin/sh"
+        );
+    }
+
+    #[test]
+    fn test_resolve_invalid_streamer_path() {
+        assert!(resolve_streamer_path(&config("./does-not-exist")).is_err());
+        // Exists but isn't executable
+        assert!(
+            resolve_streamer_path(&config(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml")))
+                .is_err()
+        );
+
+        let mut config = config("/bin/sh");
+        config.working_dir = Some("/does-not-exist".to_string());
+        assert!(resolve_streamer_path(&config).is_err());
+    }
+}
@@ -0,0 +1,338 @@
+//! Fixtures shared by the tests
+
+use std::{
+    collections::HashMap,
+    net::TcpListener,
+    path::Path,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use actix_web::{
+    App as ActixApp, HttpRequest, HttpResponse, HttpServer,
+    dev::ServerHandle,
+    rt as actix_rt,
+    web::{self, Data, Query},
+};
+use common::config::{Config, StorageConfig};
+use moonlight_common::{PairPin, pair::generate_new_client};
+use openssl::{
+    hash::MessageDigest,
+    pkey::{PKey, Private},
+    rand::rand_bytes,
+    sha::sha256,
+    sign::Signer,
+    ssl::{SslAcceptor, SslMethod},
+    symm::{Cipher, Crypter, Mode},
+    x509::X509,
+};
+use tempfile::TempDir;
+use uuid::Uuid;
+
+use crate::app::storage::{StorageHostAdd, StorageHostCache, StorageHostPairInfo};
+
+/// A directory for the files of a test, it's removed together with its files when dropped
+pub fn test_dir() -> TempDir {
+    tempfile::Builder::new()
+        .prefix("web-server-test-")
+        .tempdir()
+        .expect("failed to create test directory")
+}
+
+/// Stores the data in the directory, nothing is written until the storage is flushed
+pub fn test_config(dir: &Path) -> Config {
+    Config {
+        data_storage: StorageConfig::Json {
+            path: dir.join("data.json").to_string_lossy().into_owned(),
+            session_expiration_check_interval: Duration::from_secs(60),
+            write_interval: Duration::from_secs(60),
+        },
+        ..Default::default()
+    }
+}
+
+/// The only app of the [FakeHost]
+pub const FAKE_HOST_APP: (u32, &str) = (1, "Desktop");
+/// The pin the [FakeHost] expects when pairing
+pub const FAKE_HOST_PIN: [u8; 4] = [1, 2, 3, 4];
+
+/// Answers the requests of the web server like a Sunshine host which is paired with it.
+/// It can't stream, the tests have to use a fake streamer.
+pub struct FakeHost {
+    pub http_port: u16,
+    /// The web server is paired with these
+    pub pair_info: StorageHostPairInfo,
+    info: Data<FakeHostInfo>,
+    handle: ServerHandle,
+}
+
+struct FakeHostInfo {
+    unique_id: Uuid,
+    https_port: u16,
+    server_private_key: PKey<Private>,
+    server_certificate: X509,
+    /// Like Sunshine after it regenerated its certificate, until the web server pairs again
+    reject_certificate: AtomicBool,
+    /// The salt and server secret of the running pairing
+    pairing: Mutex<FakePairing>,
+}
+
+#[derive(Default)]
+struct FakePairing {
+    salt: Vec<u8>,
+    server_secret: [u8; 16],
+}
+
+impl FakeHost {
+    pub async fn start() -> Self {
+        let client = generate_new_client().expect("failed to generate client certificate");
+        let server = generate_new_client().expect("failed to generate server certificate");
+
+        let mut acceptor =
+            SslAcceptor::mozilla_intermediate(SslMethod::tls()).expect("failed to create acceptor");
+        acceptor
+            .set_private_key(
+                &PKey::private_key_from_pem(server.private_key.to_string().as_bytes())
+                    .expect("invalid server private key"),
+            )
+            .expect("failed to set server private key");
+        acceptor
+            .set_certificate(
+                &X509::from_pem(server.certificate.to_string().as_bytes())
+                    .expect("invalid server certificate"),
+            )
+            .expect("failed to set server certificate");
+
+        let http = TcpListener::bind(("127.0.0.1", 0)).expect("failed to bind http port");
+        let https = TcpListener::bind(("127.0.0.1", 0)).expect("failed to bind https port");
+        let http_port = http.local_addr().expect("no http address").port();
+
+        let info = Data::new(FakeHostInfo {
+            unique_id: Uuid::new_v4(),
+            https_port: https.local_addr().expect("no https address").port(),
+            server_private_key: PKey::private_key_from_pem(
+                server.private_key.to_string().as_bytes(),
+            )
+            .expect("invalid server private key"),
+            server_certificate: X509::from_pem(server.certificate.to_string().as_bytes())
+                .expect("invalid server certificate"),
+            reject_certificate: AtomicBool::new(false),
+            pairing: Mutex::new(FakePairing::default()),
+        });
+
+        let server_handle = HttpServer::new({
+            let info = info.clone();
+
+            move || {
+                ActixApp::new()
+                    .app_data(info.clone())
+                    .route("/serverinfo", web::get().to(fake_server_info))
+                    .route("/applist", web::get().to(fake_app_list))
+                    .route("/pair", web::get().to(fake_pair))
+            }
+        })
+        .workers(1)
+        .disable_signals()
+        .listen(http)
+        .expect("failed to listen on http port")
+        .listen_openssl(https, acceptor)
+        .expect("failed to listen on https port")
+        .run();
+        let handle = server_handle.handle();
+        actix_rt::spawn(server_handle);
+
+        Self {
+            http_port,
+            pair_info: StorageHostPairInfo {
+                client_private_key: client.private_key,
+                client_certificate: client.certificate,
+                server_certificate: server.certificate,
+            },
+            info,
+            handle,
+        }
+    }
+
+    /// Rejects the certificate of the web server like Sunshine after it regenerated its own.
+    /// Pairing again with [FAKE_HOST_PIN] makes the host accept the web server.
+    pub fn set_reject_certificate(&self, reject: bool) {
+        self.info.reject_certificate.store(reject, Ordering::SeqCst);
+    }
+
+    /// A global host which is paired with the web server
+    pub fn storage_host(&self) -> StorageHostAdd {
+        StorageHostAdd {
+            owner: None,
+            address: "127.0.0.1".to_string(),
+            http_port: self.http_port,
+            pair_info: Some(self.pair_info.clone()),
+            cache: StorageHostCache {
+                name: "Fake Host".to_string(),
+                mac: None,
+            },
+        }
+    }
+
+    pub async fn stop(self) {
+        self.handle.stop(false).await;
+    }
+}
+
+fn fake_xml(body: String) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/xml")
+        .body(format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<root status_code=\"200\">{body}</root>"
+        ))
+}
+
+async fn fake_server_info(request: HttpRequest, info: Data<FakeHostInfo>) -> HttpResponse {
+    let reject_certificate = info.reject_certificate.load(Ordering::SeqCst);
+    if reject_certificate && request.app_config().secure() {
+        return HttpResponse::Ok().content_type("application/xml").body(
+            r#"<?xml version="1.0" encoding="utf-8"?><root status_code="401" status_message="The client is not authorized. Certificate verification failed."/>"#,
+        );
+    }
+
+    HttpResponse::Ok()
+        .content_type("application/xml")
+        .body(format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<root status_code="200">
+<hostname>Fake Host</hostname>
+<appversion>7.1.431.-1</appversion>
+<GfeVersion>3.23.0.74</GfeVersion>
+<uniqueid>{}</uniqueid>
+<HttpsPort>{}</HttpsPort>
+<ExternalPort>47989</ExternalPort>
+<MaxLumaPixelsHEVC>1869449984</MaxLumaPixelsHEVC>
+<mac>00:00:00:00:00:00</mac>
+<LocalIP>127.0.0.1</LocalIP>
+<ServerCodecModeSupport>3</ServerCodecModeSupport>
+<PairStatus>{}</PairStatus>
+<currentgame>0</currentgame>
+<state>SUNSHINE_SERVER_FREE</state>
+</root>"#,
+            info.unique_id,
+            info.https_port,
+            if reject_certificate { 0 } else { 1 }
+        ))
+}
+
+async fn fake_app_list() -> HttpResponse {
+    let (id, title) = FAKE_HOST_APP;
+
+    HttpResponse::Ok()
+        .content_type("application/xml")
+        .body(format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<root status_code="200">
+<App><AppTitle>{title}</AppTitle><ID>{id}</ID><IsHdrSupported>0</IsHdrSupported></App>
+</root>"#
+        ))
+}
+
+/// AES-128-ECB without padding like the pairing of Sunshine
+fn fake_aes(mode: Mode, key: &[u8], data: &[u8]) -> Vec<u8> {
+    let cipher = Cipher::aes_128_ecb();
+    let mut crypter = Crypter::new(cipher, mode, key, None).expect("failed to create crypter");
+    crypter.pad(false);
+
+    let mut output = vec![0; data.len() + cipher.block_size()];
+    let mut count = crypter
+        .update(data, &mut output)
+        .expect("failed to update crypter");
+    count += crypter
+        .finalize(&mut output[count..])
+        .expect("failed to finalize crypter");
+    output.truncate(count);
+
+    output
+}
+
+/// The server side of the pairing, it trusts the client but proves that it knows the pin
+async fn fake_pair(
+    request: HttpRequest,
+    info: Data<FakeHostInfo>,
+    query: Query<HashMap<String, String>>,
+) -> HttpResponse {
+    let param = |name: &str| {
+        query
+            .get(name)
+            .map(|value| hex::decode(value).expect("invalid hex in pair request"))
+    };
+    let mut pairing = info.pairing.lock().expect("fake pairing poisoned");
+
+    let aes_key = |salt: &[u8]| {
+        let pin = PairPin::from_array(FAKE_HOST_PIN).expect("invalid fake host pin");
+
+        let mut salted = salt.to_vec();
+        salted.extend(pin.array().map(|digit| b'0' + digit));
+        sha256(&salted)[..16].to_vec()
+    };
+
+    if request.app_config().secure() {
+        // The last stage uses the new certificate of the client
+        if query.get("phrase").map(String::as_str) == Some("pairchallenge") {
+            info.reject_certificate.store(false, Ordering::SeqCst);
+        }
+        return fake_xml("<paired>1</paired>".to_string());
+    }
+
+    if query.get("phrase").map(String::as_str) == Some("getservercert") {
+        pairing.salt = param("salt").expect("missing salt");
+
+        let certificate = info
+            .server_certificate
+            .to_pem()
+            .expect("failed to encode server certificate");
+        return fake_xml(format!(
+            "<paired>1</paired><plaincert>{}</plaincert>",
+            hex::encode_upper(certificate)
+        ));
+    }
+
+    if let Some(encrypted_challenge) = param("clientchallenge") {
+        let key = aes_key(&pairing.salt);
+        let challenge = fake_aes(Mode::Decrypt, &key, &encrypted_challenge);
+
+        rand_bytes(&mut pairing.server_secret).expect("failed to generate server secret");
+        let mut server_challenge = [0; 16];
+        rand_bytes(&mut server_challenge).expect("failed to generate server challenge");
+
+        let mut response = challenge;
+        response.extend_from_slice(info.server_certificate.signature().as_slice());
+        response.extend_from_slice(&pairing.server_secret);
+
+        let mut plain_response = sha256(&response).to_vec();
+        plain_response.extend_from_slice(&server_challenge);
+
+        return fake_xml(format!(
+            "<paired>1</paired><challengeresponse>{}</challengeresponse>",
+            hex::encode_upper(fake_aes(Mode::Encrypt, &key, &plain_response))
+        ));
+    }
+
+    if param("serverchallengeresp").is_some() {
+        let mut signer = Signer::new(MessageDigest::sha256(), &info.server_private_key)
+            .expect("failed to create signer");
+        signer
+            .update(&pairing.server_secret)
+            .expect("failed to sign server secret");
+        let signature = signer.sign_to_vec().expect("failed to sign server secret");
+
+        let mut pairing_secret = pairing.server_secret.to_vec();
+        pairing_secret.extend_from_slice(&signature);
+
+        return fake_xml(format!(
+            "<paired>1</paired><pairingsecret>{}</pairingsecret>",
+            hex::encode_upper(pairing_secret)
+        ));
+    }
+
+    // The client pairing secret
+    fake_xml("<paired>1</paired>".to_string())
+}
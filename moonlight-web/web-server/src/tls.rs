@@ -0,0 +1,279 @@
+//! The certificate of the https server, reloaded when its files change so renewed certificates don't need a restart.
+
+use std::{
+    io,
+    sync::{Arc, PoisonError, RwLock},
+    time::Duration,
+};
+
+use common::config::ConfigSsl;
+use log::{info, warn};
+use openssl::{
+    error::ErrorStack,
+    pkey::{PKey, Private},
+    ssl::{SniError, SslAcceptor, SslAcceptorBuilder, SslContext, SslMethod},
+    x509::X509,
+};
+use thiserror::Error;
+use tokio::{fs, time::sleep};
+
+/// How often the certificate files are checked for changes
+const CERTIFICATE_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Error)]
+pub enum TlsError {
+    #[error("failed to read {path}: {source}")]
+    Read { path: String, source: io::Error },
+    #[error("failed to parse {path}: {source}")]
+    Parse { path: String, source: ErrorStack },
+    #[error("{path} contains no certificate")]
+    NoCertificate { path: String },
+    #[error("the private key {private_key} doesn't match the certificate {certificate}")]
+    KeyMismatch {
+        private_key: String,
+        certificate: String,
+    },
+    #[error("openssl error occured: {0}")]
+    Openssl(#[from] ErrorStack),
+}
+
+/// The private key and the certificate chain, starting with the certificate of the server
+struct Certificate {
+    private_key: PKey<Private>,
+    chain: Vec<X509>,
+}
+
+impl Certificate {
+    fn parse(config: &ConfigSsl, files: &CertificateFiles) -> Result<Self, TlsError> {
+        let private_key =
+            PKey::private_key_from_pem(&files.private_key).map_err(|source| TlsError::Parse {
+                path: config.private_key_pem.clone(),
+                source,
+            })?;
+
+        let chain = X509::stack_from_pem(&files.certificate).map_err(|source| TlsError::Parse {
+            path: config.certificate_pem.clone(),
+            source,
+        })?;
+        if chain.is_empty() {
+            return Err(TlsError::NoCertificate {
+                path: config.certificate_pem.clone(),
+            });
+        }
+
+        Ok(Self { private_key, chain })
+    }
+
+    /// An acceptor with the settings of the https server, fails if the key doesn't belong to the certificate
+    fn acceptor(&self, config: &ConfigSsl) -> Result<SslAcceptorBuilder, TlsError> {
+        let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+
+        builder.set_private_key(&self.private_key)?;
+        builder.set_certificate(&self.chain[0])?;
+        for certificate in &self.chain[1..] {
+            builder.add_extra_chain_cert(certificate.clone())?;
+        }
+
+        builder
+            .check_private_key()
+            .map_err(|_| TlsError::KeyMismatch {
+                private_key: config.private_key_pem.clone(),
+                certificate: config.certificate_pem.clone(),
+            })?;
+
+        Ok(builder)
+    }
+
+    fn log_loaded(&self, config: &ConfigSsl) {
+        info!(
+            "[Server]: Loaded the certificate {}, valid until {}",
+            config.certificate_pem,
+            self.chain[0].not_after()
+        );
+    }
+}
+
+async fn read(path: &str) -> Result<Vec<u8>, TlsError> {
+    fs::read(path).await.map_err(|source| TlsError::Read {
+        path: path.to_string(),
+        source,
+    })
+}
+
+/// The contents of the private key and certificate files.
+/// They're compared to find changes because the modification times can have a coarse resolution.
+#[derive(PartialEq, Eq)]
+struct CertificateFiles {
+    private_key: Vec<u8>,
+    certificate: Vec<u8>,
+}
+
+impl CertificateFiles {
+    async fn read(config: &ConfigSsl) -> Result<Self, TlsError> {
+        Ok(Self {
+            private_key: read(&config.private_key_pem).await?,
+            certificate: read(&config.certificate_pem).await?,
+        })
+    }
+}
+
+/// The certificate new handshakes use, existing connections keep the certificate they started with
+pub struct TlsCertificate {
+    config: ConfigSsl,
+    certificate: Certificate,
+    context: Arc<RwLock<SslContext>>,
+    files: CertificateFiles,
+}
+
+impl TlsCertificate {
+    /// Loads and validates the certificate, the error names the file which couldn't be used
+    pub async fn load(config: ConfigSsl) -> Result<Self, TlsError> {
+        let files = CertificateFiles::read(&config).await?;
+
+        let certificate = Certificate::parse(&config, &files)?;
+        let context = certificate.acceptor(&config)?.build().into_context();
+        certificate.log_loaded(&config);
+
+        Ok(Self {
+            config,
+            certificate,
+            context: Arc::new(RwLock::new(context)),
+            files,
+        })
+    }
+
+    /// The acceptor for the https server, every handshake switches to the current certificate
+    pub fn acceptor(&self) -> Result<SslAcceptorBuilder, TlsError> {
+        let mut builder = self.certificate.acceptor(&self.config)?;
+
+        // The callback runs for every client hello, also when the client sent no server name
+        let context = self.context.clone();
+        builder.set_servername_callback(move |ssl, _alert| {
+            let context = context.read().unwrap_or_else(PoisonError::into_inner);
+
+            ssl.set_ssl_context(&context)
+                .map_err(|_| SniError::ALERT_FATAL)
+        });
+
+        Ok(builder)
+    }
+
+    /// Reloads the certificate if one of its files changed.
+    /// An invalid certificate is ignored and retried on the next check, e.g. when only one of the files was written yet.
+    async fn reload_if_changed(&mut self) -> Result<bool, TlsError> {
+        let files = CertificateFiles::read(&self.config).await?;
+        if files == self.files {
+            return Ok(false);
+        }
+
+        let certificate = Certificate::parse(&self.config, &files)?;
+        let context = certificate.acceptor(&self.config)?.build().into_context();
+        certificate.log_loaded(&self.config);
+
+        *self.context.write().unwrap_or_else(PoisonError::into_inner) = context;
+        self.certificate = certificate;
+        self.files = files;
+
+        Ok(true)
+    }
+}
+
+/// Checks the certificate files for changes, e.g. after a renewal
+pub async fn watch_certificate(mut certificate: TlsCertificate) {
+    loop {
+        sleep(CERTIFICATE_POLL_INTERVAL).await;
+
+        if let Err(err) = certificate.reload_if_changed().await {
+            warn!("[Server]: Failed to reload the certificate, keeping the old one: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use common::config::ConfigSsl;
+    use moonlight_common::pair::generate_new_client;
+    use tokio::fs;
+
+    use crate::{
+        test_util::test_dir,
+        tls::{TlsCertificate, TlsError},
+    };
+
+    /// Writes a new self signed certificate and returns its der
+    async fn write_certificate(config: &ConfigSsl) -> Vec<u8> {
+        let auth = generate_new_client().expect("failed to generate certificate");
+
+        fs::write(&config.private_key_pem, pem::encode(&auth.private_key))
+            .await
+            .expect("failed to write private key");
+        fs::write(&config.certificate_pem, pem::encode(&auth.certificate))
+            .await
+            .expect("failed to write certificate");
+
+        auth.certificate.contents().to_vec()
+    }
+
+    fn current_certificate(certificate: &TlsCertificate) -> Vec<u8> {
+        let context = certificate.context.read().expect("context poisoned");
+
+        context
+            .certificate()
+            .expect("no certificate")
+            .to_der()
+            .expect("failed to encode certificate")
+    }
+
+    #[tokio::test]
+    async fn test_certificate_reload() {
+        let temp = test_dir();
+        let dir = temp.path();
+        let config = ConfigSsl {
+            private_key_pem: dir.join("key.pem").to_string_lossy().into_owned(),
+            certificate_pem: dir.join("cert.pem").to_string_lossy().into_owned(),
+        };
+
+        // Missing files are named
+        let Err(TlsError::Read { path, .. }) = TlsCertificate::load(config.clone()).await else {
+            panic!("loaded a missing certificate");
+        };
+        assert_eq!(path, config.private_key_pem);
+
+        let first = write_certificate(&config).await;
+        let mut certificate = TlsCertificate::load(config.clone())
+            .await
+            .expect("failed to load certificate");
+        assert!(certificate.acceptor().is_ok());
+        assert_eq!(current_certificate(&certificate), first);
+        assert!(
+            !certificate
+                .reload_if_changed()
+                .await
+                .expect("failed to check")
+        );
+
+        // A key of another certificate is rejected and the old certificate is kept
+        let key_of_first = fs::read(&config.private_key_pem)
+            .await
+            .expect("failed to read key");
+        write_certificate(&config).await;
+        fs::write(&config.private_key_pem, &key_of_first)
+            .await
+            .expect("failed to write key");
+        assert!(matches!(
+            certificate.reload_if_changed().await,
+            Err(TlsError::KeyMismatch { .. })
+        ));
+        assert_eq!(current_certificate(&certificate), first);
+
+        // The renewed certificate is used for new handshakes
+        let renewed = write_certificate(&config).await;
+        assert!(
+            certificate
+                .reload_if_changed()
+                .await
+                .expect("failed to reload")
+        );
+        assert_eq!(current_certificate(&certificate), renewed);
+    }
+}
@@ -1,18 +1,205 @@
-use actix_files::Files;
-use actix_web::{HttpResponse, dev::HttpServiceFactory, get, services, web::Data};
+use std::path::{Component, Path, PathBuf};
+
+use actix_files::NamedFile;
+use actix_web::{
+    HttpRequest, HttpResponse,
+    dev::HttpServiceFactory,
+    get, guard,
+    http::header::{ACCEPT_ENCODING, CACHE_CONTROL, ContentEncoding, HeaderValue, VARY},
+    services,
+    web::{self, Data},
+};
 use common::{api_bindings::ConfigJs, api_bindings_ext::TsAny};
 use log::warn;
+use tokio::fs::File;
 
 use crate::app::App;
 
+/// Served for directories and for client side routes which aren't files
+const INDEX_FILE: &str = "index.html";
+/// Hashed file names change with their content, so they can be cached forever
+const IMMUTABLE_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+/// The pre-compressed variants next to a file, in the order they're preferred
+const PRECOMPRESSED: [(ContentEncoding, &str, &str); 2] = [
+    (ContentEncoding::Brotli, "br", "br"),
+    (ContentEncoding::Gzip, "gzip", "gz"),
+];
+
+struct WebRoot(PathBuf);
+
 pub fn web_service() -> impl HttpServiceFactory {
     #[cfg(debug_assertions)]
-    let files = Files::new("/", "dist").index_file("index.html");
+    let root = "dist";
 
     #[cfg(not(debug_assertions))]
-    let files = Files::new("/", "static").index_file("index.html");
+    let root = "static";
+
+    web_files_service(root)
+}
 
-    files
+/// Serves the files in the root, registered last because it matches every path below the url path prefix
+fn web_files_service(root: impl Into<PathBuf>) -> impl HttpServiceFactory {
+    web::resource("/{path:.*}")
+        .app_data(Data::new(WebRoot(root.into())))
+        .route(
+            web::route()
+                .guard(guard::Any(guard::Get()).or(guard::Head()))
+                .to(web_file),
+        )
+}
+
+async fn web_file(
+    req: HttpRequest,
+    root: Data<WebRoot>,
+    path: web::Path<String>,
+) -> actix_web::Result<HttpResponse> {
+    let Some(relative) = sanitize_path(&path) else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+
+    let mut file = root.0.join(&relative);
+    if file.is_dir() {
+        file.push(INDEX_FILE);
+    }
+    if !file.is_file() {
+        if !is_client_route(&relative) {
+            return Ok(HttpResponse::NotFound().finish());
+        }
+
+        // Reloading a client side route loads the app, which shows the route
+        file = root.0.join(INDEX_FILE);
+    }
+
+    let accept_encoding = req
+        .headers()
+        .get(ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok());
+
+    let mut has_variants = false;
+    let mut named_file = None;
+    for (encoding, name, extension) in PRECOMPRESSED {
+        let variant = append_extension(&file, extension);
+        if !variant.is_file() {
+            continue;
+        }
+        has_variants = true;
+
+        if named_file.is_none() && accepts_encoding(accept_encoding, name) {
+            // The content type is guessed from the path of the uncompressed file
+            let variant = File::open(&variant).await?.into_std().await;
+
+            named_file = Some(NamedFile::from_file(variant, &file)?.set_content_encoding(encoding));
+        }
+    }
+    let named_file = match named_file {
+        Some(named_file) => named_file,
+        None => NamedFile::open_async(&file).await?,
+    };
+
+    let mut response = named_file.into_response(&req);
+
+    if has_variants {
+        response
+            .headers_mut()
+            .insert(VARY, HeaderValue::from_static("accept-encoding"));
+    }
+    // Everything else keeps the no-store policy of the server, e.g. the html files which reference the hashed assets
+    if file
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(is_hashed_file_name)
+    {
+        response.headers_mut().insert(
+            CACHE_CONTROL,
+            HeaderValue::from_static(IMMUTABLE_CACHE_CONTROL),
+        );
+    }
+
+    Ok(response)
+}
+
+/// The path relative to the root, None if it would leave the root or access hidden files
+fn sanitize_path(path: &str) -> Option<PathBuf> {
+    let mut relative = PathBuf::new();
+
+    for segment in path.split('/') {
+        if segment.is_empty() {
+            continue;
+        }
+        if segment.starts_with('.') || segment.contains('\\') {
+            return None;
+        }
+
+        match Path::new(segment).components().next() {
+            Some(Component::Normal(_)) => relative.push(segment),
+            _ => return None,
+        }
+    }
+
+    Some(relative)
+}
+
+/// Paths without a file extension are routes of the app, missing assets are reported as missing
+fn is_client_route(relative: &Path) -> bool {
+    if relative.starts_with("api") {
+        return false;
+    }
+
+    relative.extension().is_none()
+}
+
+fn append_extension(file: &Path, extension: &str) -> PathBuf {
+    let mut file = file.as_os_str().to_owned();
+    file.push(".");
+    file.push(extension);
+
+    file.into()
+}
+
+/// If the Accept-Encoding header allows the encoding, a quality of 0 forbids it
+fn accepts_encoding(accept_encoding: Option<&str>, encoding: &str) -> bool {
+    let Some(accept_encoding) = accept_encoding else {
+        return false;
+    };
+
+    let mut wildcard = false;
+    for entry in accept_encoding.split(',') {
+        let mut parts = entry.split(';');
+        let name = parts.next().unwrap_or_default().trim();
+
+        let allowed = parts
+            .filter_map(|parameter| parameter.trim().strip_prefix("q="))
+            .all(|quality| {
+                quality
+                    .trim()
+                    .parse::<f32>()
+                    .is_ok_and(|quality| quality > 0.0)
+            });
+
+        if name.eq_ignore_ascii_case(encoding) {
+            return allowed;
+        }
+        if name == "*" {
+            wildcard = allowed;
+        }
+    }
+
+    wildcard
+}
+
+/// Bundlers add the hash of the content to the name, e.g. `index-3f2a9c1b.js` or `app.3f2a9c1b.css`
+fn is_hashed_file_name(name: &str) -> bool {
+    let Some((stem, _)) = name.rsplit_once('.') else {
+        return false;
+    };
+
+    stem.rsplit(['-', '.']).next().is_some_and(|hash| {
+        stem.len() > hash.len()
+            && hash.len() >= 8
+            && hash.chars().all(|char| char.is_ascii_alphanumeric())
+            && hash.chars().any(|char| char.is_ascii_digit())
+    })
 }
 
 pub fn web_config_js_service() -> impl HttpServiceFactory {
@@ -39,3 +226,178 @@ async fn config_js(app: Data<App>) -> HttpResponse {
         .append_header(("Content-Type", "text/javascript"))
         .body(config_js)
 }
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use actix_web::{
+        App as ActixApp,
+        dev::ServiceResponse,
+        http::{
+            StatusCode,
+            header::{ACCEPT_ENCODING, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_TYPE, VARY},
+        },
+        test::{TestRequest, call_service, init_service, read_body},
+        web::scope,
+    };
+
+    use tempfile::TempDir;
+
+    use crate::{
+        test_util::test_dir,
+        web::{accepts_encoding, is_hashed_file_name, web_files_service},
+    };
+
+    /// A web root with an index, a hashed asset and its pre-compressed variants
+    fn web_root() -> TempDir {
+        let dir = test_dir();
+        let root = dir.path();
+
+        fs::create_dir_all(root.join("assets")).expect("failed to create web root");
+        fs::write(root.join("index.html"), "index").expect("failed to write index");
+        fs::write(root.join("stream.html"), "stream").expect("failed to write page");
+        fs::write(root.join("assets/app-3f2a9c1b.js"), "plain").expect("failed to write asset");
+        fs::write(root.join("assets/app-3f2a9c1b.js.br"), "brotli").expect("failed to write asset");
+        fs::write(root.join("assets/app-3f2a9c1b.js.gz"), "gzip").expect("failed to write asset");
+
+        dir
+    }
+
+    #[actix_web::test]
+    async fn test_client_route_fallback() {
+        let root = web_root();
+        let service = init_service(ActixApp::new().service(web_files_service(root.path()))).await;
+
+        for (uri, body) in [
+            ("/", "index"),
+            ("/stream.html", "stream"),
+            // Reloading a route of the app
+            ("/stream/ABC123", "index"),
+        ] {
+            let response = call_service(&service, TestRequest::get().uri(uri).to_request()).await;
+            assert_eq!(response.status(), StatusCode::OK, "{uri}");
+            assert!(response.headers().get(CACHE_CONTROL).is_none(), "{uri}");
+            assert_eq!(read_body(response).await, body, "{uri}");
+        }
+
+        // Missing assets, api routes and paths outside of the root aren't the app
+        for uri in ["/missing.js", "/api/unknown", "/../index.html", "/.hidden"] {
+            let response = call_service(&service, TestRequest::get().uri(uri).to_request()).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{uri}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_url_path_prefix() {
+        let root = web_root();
+        let service = init_service(
+            ActixApp::new().service(scope("/moonlight").service(web_files_service(root.path()))),
+        )
+        .await;
+
+        let response = call_service(
+            &service,
+            TestRequest::get()
+                .uri("/moonlight/stream/ABC123")
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(read_body(response).await, "index");
+
+        let response = call_service(
+            &service,
+            TestRequest::get().uri("/stream/ABC123").to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn test_precompressed_assets() {
+        let root = web_root();
+        let service = init_service(ActixApp::new().service(web_files_service(root.path()))).await;
+
+        let content_type = |response: &ServiceResponse| {
+            response
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let plain_content_type = content_type(
+            &call_service(
+                &service,
+                TestRequest::get()
+                    .uri("/assets/app-3f2a9c1b.js")
+                    .to_request(),
+            )
+            .await,
+        );
+        assert!(
+            plain_content_type
+                .as_deref()
+                .is_some_and(|content_type| content_type.contains("javascript"))
+        );
+
+        for (accept_encoding, content_encoding, body) in [
+            (Some("gzip, deflate, br"), Some("br"), "brotli"),
+            (Some("gzip"), Some("gzip"), "gzip"),
+            (Some("br;q=0, gzip;q=0.5"), Some("gzip"), "gzip"),
+            (Some("identity"), None, "plain"),
+            (None, None, "plain"),
+        ] {
+            let mut request = TestRequest::get().uri("/assets/app-3f2a9c1b.js");
+            if let Some(accept_encoding) = accept_encoding {
+                request = request.insert_header((ACCEPT_ENCODING, accept_encoding));
+            }
+
+            let response = call_service(&service, request.to_request()).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                response
+                    .headers()
+                    .get(CONTENT_ENCODING)
+                    .and_then(|value| value.to_str().ok()),
+                content_encoding
+            );
+            assert_eq!(content_type(&response), plain_content_type);
+            assert_eq!(
+                response
+                    .headers()
+                    .get(VARY)
+                    .and_then(|value| value.to_str().ok()),
+                Some("accept-encoding")
+            );
+            assert!(
+                response
+                    .headers()
+                    .get(CACHE_CONTROL)
+                    .and_then(|value| value.to_str().ok())
+                    .is_some_and(|value| value.contains("immutable"))
+            );
+            assert_eq!(read_body(response).await, body);
+        }
+    }
+
+    #[test]
+    fn test_accepts_encoding() {
+        assert!(accepts_encoding(Some("gzip, br"), "br"));
+        assert!(accepts_encoding(Some("BR;q=0.1"), "br"));
+        assert!(accepts_encoding(Some("*"), "gzip"));
+        assert!(!accepts_encoding(Some("*, gzip;q=0"), "gzip"));
+        assert!(!accepts_encoding(Some("gzip"), "br"));
+        assert!(!accepts_encoding(None, "gzip"));
+    }
+
+    #[test]
+    fn test_hashed_file_name() {
+        assert!(is_hashed_file_name("index-3f2a9c1b.js"));
+        assert!(is_hashed_file_name("app.3f2a9c1b.css"));
+        assert!(!is_hashed_file_name("index.html"));
+        assert!(!is_hashed_file_name("api_bindings.js"));
+        assert!(!is_hashed_file_name("3f2a9c1b.js"));
+        assert!(!is_hashed_file_name("screen-keyboard.js"));
+    }
+}
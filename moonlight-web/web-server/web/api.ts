@@ -1,4 +1,4 @@
-import { App, DeleteHostQuery, DeleteUserRequest, DetailedHost, DetailedUser, GetAppImageQuery, GetAppsQuery, GetAppsResponse, GetHostQuery, GetHostResponse, GetHostsResponse, GetUserQuery, GetUsersResponse, PatchUserRequest, PostCancelRequest, PostCancelResponse, PostLoginRequest, PostPairRequest, PostPairResponse1, PostPairResponse2, PostUserRequest, PostWakeUpRequest, PostHostRequest, PostHostResponse, UndetailedHost, PatchHostRequest } from "./api_bindings.js";
+import { App, DeleteHostQuery, DetailedApp, DeleteUserRequest, DetailedHost, DetailedUser, GetAppImageQuery, GetAppsQuery, GetAppsResponse, GetAppsDetailedResponse, GetHostQuery, GetHostResponse, GetHostsResponse, GetUserQuery, GetUsersResponse, PatchUserRequest, PostCancelRequest, PostCancelResponse, PostLoginRequest, PostPairRequest, PostPairResponse1, PostPairResponse2, PostUserRequest, PostWakeUpRequest, PostHostRequest, PostHostResponse, UndetailedHost, PatchHostRequest, PostImportMoonlightQtRequest, PostImportResponse, GetUserSettingsQuery, GetUserSettingsResponse, PutUserSettingsRequest, GetWakeUpStatusQuery, GetWakeUpStatusResponse, PutHostAppVisibilityRequest, GetHostSharesQuery, GetHostSharesResponse, PutHostSharesRequest, PostHostUnpairRemoteRequest, PostHostUnpairRemoteResponse, GetHostUptimeQuery, GetHostUptimeResponse, PostHostInviteRequest, PostHostInviteResponse } from "./api_bindings.js";
 import { showErrorPopup } from "./component/error.js";
 import { showMessage, showModal } from "./component/modal/index.js";
 import { ApiUserPasswordPrompt } from "./component/modal/login.js";
@@ -82,6 +82,7 @@ export async function tryLogin(): Promise<Api | null> {
 const GET = "GET"
 const POST = "POST"
 const PATCH = "PATCH"
+const PUT = "PUT"
 const DELETE = "DELETE"
 
 export type Api = {
@@ -333,6 +334,27 @@ export async function apiDeleteUser(api: Api, data: DeleteUserRequest): Promise<
         response: "ignore"
     })
 }
+export async function apiImportMoonlightQt(api: Api, data: PostImportMoonlightQtRequest): Promise<PostImportResponse> {
+    const response = await fetchApi(api, "/import/moonlight-qt", POST, { json: data })
+
+    return response as PostImportResponse
+}
+
+export async function apiRotateInviteSecret(api: Api): Promise<void> {
+    await fetchApi(api, "/invite_secret/rotate", POST, { response: "ignore" })
+}
+
+export async function apiGetUserSettings(api: Api, query: GetUserSettingsQuery): Promise<GetUserSettingsResponse> {
+    const response = await fetchApi(api, "/user/settings", GET, { query })
+
+    return response as GetUserSettingsResponse
+}
+export async function apiPutUserSettings(api: Api, data: PutUserSettingsRequest): Promise<void> {
+    await fetchApi(api, "/user/settings", PUT, {
+        json: data,
+        response: "ignore"
+    })
+}
 
 export async function apiGetHosts(api: Api): Promise<StreamedJsonResponse<GetHostsResponse, UndetailedHost>> {
     return await fetchApi<GetHostsResponse, UndetailedHost>(api, "/hosts", GET, { response: "jsonStreaming" })
@@ -347,6 +369,20 @@ export async function apiPostHost(api: Api, data: PostHostRequest): Promise<Deta
 
     return (response as PostHostResponse).host
 }
+export async function apiGetHostShares(api: Api, query: GetHostSharesQuery): Promise<Array<string>> {
+    const response = await fetchApi(api, "/host/shares", GET, { query }) as GetHostSharesResponse
+
+    return response.user_names
+}
+export async function apiGetHostUptime(api: Api, query: GetHostUptimeQuery): Promise<GetHostUptimeResponse> {
+    return await fetchApi(api, "/host/uptime", GET, { query }) as GetHostUptimeResponse
+}
+export async function apiPutHostShares(api: Api, data: PutHostSharesRequest): Promise<void> {
+    await fetchApi(api, "/host/shares", PUT, {
+        json: data,
+        response: "ignore"
+    })
+}
 export async function apiPatchHost(api: Api, data: PatchHostRequest): Promise<void> {
     await fetchApi(api, "/host", PATCH, {
         json: data,
@@ -357,6 +393,14 @@ export async function apiDeleteHost(api: Api, query: DeleteHostQuery): Promise<v
     await fetchApi(api, "/host", "delete", { query, response: "ignore" })
 }
 
+export async function apiPostHostUnpairRemote(api: Api, request: PostHostUnpairRemoteRequest): Promise<PostHostUnpairRemoteResponse> {
+    const response = await fetchApi(api, "/host/unpair_remote", POST, {
+        json: request
+    })
+
+    return response as PostHostUnpairRemoteResponse
+}
+
 export async function apiPostPair(api: Api, request: PostPairRequest): Promise<StreamedJsonResponse<PostPairResponse1, PostPairResponse2>> {
     return await fetchApi(api, "/pair", "post", {
         json: request,
@@ -371,6 +415,11 @@ export async function apiWakeUp(api: Api, request: PostWakeUpRequest): Promise<v
         response: "ignore"
     })
 }
+export async function apiGetWakeUpStatus(api: Api, query: GetWakeUpStatusQuery): Promise<GetWakeUpStatusResponse> {
+    const response = await fetchApi(api, "/host/wake/status", GET, { query })
+
+    return response as GetWakeUpStatusResponse
+}
 
 export async function apiGetApps(api: Api, query: GetAppsQuery): Promise<Array<App>> {
     const response = await fetchApi(api, "/apps", GET, { query }) as GetAppsResponse
@@ -378,6 +427,19 @@ export async function apiGetApps(api: Api, query: GetAppsQuery): Promise<Array<A
     return response.apps
 }
 
+export async function apiGetAppsDetailed(api: Api, query: GetAppsQuery): Promise<Array<DetailedApp>> {
+    const response = await fetchApi(api, "/apps/detailed", GET, { query }) as GetAppsDetailedResponse
+
+    return response.apps
+}
+
+export async function apiPutHostAppVisibility(api: Api, data: PutHostAppVisibilityRequest): Promise<void> {
+    await fetchApi(api, "/host/apps/visibility", PUT, {
+        json: data,
+        response: "ignore"
+    })
+}
+
 export async function apiGetAppImage(api: Api, query: GetAppImageQuery): Promise<Blob> {
     const response = await fetchApi(api, "/app/image", GET, {
         query,
@@ -393,4 +455,12 @@ export async function apiHostCancel(api: Api, request: PostCancelRequest): Promi
     })
 
     return response as PostCancelResponse
+}
+
+export async function apiCreateInvite(api: Api, request: PostHostInviteRequest): Promise<PostHostInviteResponse> {
+    const response = await fetchApi(api, "/host/invite", POST, {
+        json: request
+    })
+
+    return response as PostHostInviteResponse
 }
\ No newline at end of file
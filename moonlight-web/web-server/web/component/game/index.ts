@@ -6,9 +6,13 @@ import { showMessage } from "../modal/index.js";
 import { APP_NO_IMAGE } from "../../resources/index.js";
 import { buildUrl } from "../../config_.js";
 
-export type GameCache = App & { activeApp: number | null }
+export type GameCache = App & { activeApp: number | null, image_hash?: string | null }
+
+// Images which were already fetched, keyed by host and app id
+const APP_IMAGE_CACHE: Map<string, { hash: string | null, blob: Blob }> = new Map()
 
 export type GameEventListener = (event: ComponentEvent<Game>) => void
+export type SetAppHidden = (appId: number, hidden: boolean) => Promise<void>
 
 export class Game implements Component {
     private api: Api
@@ -24,9 +28,11 @@ export class Game implements Component {
     private imageElement: HTMLImageElement = document.createElement("img")
 
     private cache: GameCache
+    private setHidden: SetAppHidden | null
 
-    constructor(api: Api, hostId: number, appId: number, cache: GameCache) {
+    constructor(api: Api, hostId: number, appId: number, cache: GameCache, setHidden?: SetAppHidden) {
         this.api = api
+        this.setHidden = setHidden ?? null
 
         this.hostId = hostId
         this.appId = appId
@@ -37,7 +43,7 @@ export class Game implements Component {
         this.imageElement.classList.add("app-image")
         this.imageElement.src = APP_NO_IMAGE
 
-        this.forceLoadImage(false)
+        this.loadImage()
 
         // Configure div
         this.divElement.classList.add("app")
@@ -50,12 +56,32 @@ export class Game implements Component {
         this.updateCache(cache)
     }
 
+    private imageCacheKey(): string {
+        return `${this.hostId}:${this.appId}`
+    }
+
+    async loadImage() {
+        const hash = this.cache.image_hash ?? null
+
+        // Skip the request if we already have this image
+        const cached = APP_IMAGE_CACHE.get(this.imageCacheKey())
+        if (cached && hash != null && cached.hash == hash) {
+            this.imageBlob = cached.blob
+            this.updateImage()
+            return
+        }
+
+        await this.forceLoadImage(false)
+    }
     async forceLoadImage(forceServerRefresh: boolean) {
+        const hash = forceServerRefresh ? null : (this.cache.image_hash ?? null)
+
         this.imageBlob = await apiGetAppImage(this.api, {
             host_id: this.hostId,
             app_id: this.appId,
             force_refresh: forceServerRefresh
         })
+        APP_IMAGE_CACHE.set(this.imageCacheKey(), { hash, blob: this.imageBlob })
 
         this.updateImage()
     }
@@ -79,10 +105,17 @@ export class Game implements Component {
     }
 
     updateCache(cache: GameCache) {
+        const previousHash = this.cache.image_hash ?? null
         this.cache = cache
 
+        const hash = cache.image_hash ?? null
+        if (previousHash != null && hash != null && previousHash != hash) {
+            this.loadImage()
+        }
+
         this.divElement.classList.remove("app-inactive")
         this.divElement.classList.remove("app-active")
+        this.divElement.classList.toggle("app-hidden", this.cache.hidden == true)
 
         if (this.isActive()) {
             this.divElement.classList.add("app-active")
@@ -165,6 +198,18 @@ export class Game implements Component {
             }
         })
 
+        // Only the owner of the host and admins can hide apps
+        const hidden = this.cache.hidden
+        const setHidden = this.setHidden
+        if (hidden != null && setHidden) {
+            elements.push({
+                name: hidden ? "Show to Other Users" : "Hide from Other Users",
+                callback: async () => {
+                    await setHidden(this.appId, !hidden)
+                }
+            })
+        }
+
         setContextMenu(event, {
             elements
         })
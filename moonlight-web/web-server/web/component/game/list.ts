@@ -1,4 +1,4 @@
-import { Api, apiGetApps } from "../../api.js";
+import { Api, apiGetAppsDetailed, apiPutHostAppVisibility } from "../../api.js";
 import { App } from "../../api_bindings.js";
 import { showErrorPopup } from "../error.js";
 import { FetchListComponent } from "../fetch_list.js";
@@ -12,6 +12,7 @@ export class GameList extends FetchListComponent<App, Game> {
 
     private hostId: number
     private activeApp: number | null = null
+    private hiddenApps: Set<number> = new Set()
 
     constructor(api: Api, hostId: number, cache: App[] | null) {
         super({
@@ -38,13 +39,19 @@ export class GameList extends FetchListComponent<App, Game> {
     }
 
     async forceFetch() {
-        const apps = await apiGetApps(this.api, {
+        const apps = await apiGetAppsDetailed(this.api, {
             host_id: this.hostId,
         })
 
         this.updateCache(apps)
     }
     private createCache(data: App): GameCache {
+        if (data.hidden) {
+            this.hiddenApps.add(data.app_id)
+        } else {
+            this.hiddenApps.delete(data.app_id)
+        }
+
         const cache = data as GameCache
         cache.activeApp = this.activeApp
         return cache
@@ -64,12 +71,33 @@ export class GameList extends FetchListComponent<App, Game> {
     protected insertList(dataId: number, data: App): void {
         const cache = this.createCache(data)
 
-        const game = new Game(this.api, this.hostId, dataId, cache)
+        const game = new Game(this.api, this.hostId, dataId, cache, this.setAppHidden.bind(this))
         game.addForceReloadListener(this.onForceReload.bind(this))
 
         this.list.append(game)
     }
 
+    private async setAppHidden(appId: number, hidden: boolean) {
+        const hiddenApps = new Set(this.hiddenApps)
+        if (hidden) {
+            hiddenApps.add(appId)
+        } else {
+            hiddenApps.delete(appId)
+        }
+
+        try {
+            await apiPutHostAppVisibility(this.api, {
+                host_id: this.hostId,
+                hidden_app_ids: Array.from(hiddenApps)
+            })
+        } catch (e) {
+            showErrorPopup(`Failed to change the visibility of the app: ${e}`)
+            return
+        }
+
+        await this.forceFetch()
+    }
+
     private onForceReload(event: ComponentEvent<Game>) {
         this.eventTarget.dispatchEvent(new ComponentEvent("ml-gamereload", event.component))
     }
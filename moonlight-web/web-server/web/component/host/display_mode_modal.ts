@@ -0,0 +1,48 @@
+import { HostDisplayMode } from "../../api_bindings.js"
+import { SelectComponent } from "../input.js"
+import { FormModal } from "../modal/form.js"
+
+export class DisplayModeModal extends FormModal<HostDisplayMode> {
+
+    private header: HTMLElement = document.createElement("h2")
+
+    private displayModes: Array<HostDisplayMode>
+    private resolution: SelectComponent
+
+    constructor(hostName: string, displayModes: Array<HostDisplayMode>, current: HostDisplayMode | null) {
+        super()
+
+        this.header.innerText = `Display Mode of ${hostName}`
+
+        this.displayModes = displayModes
+
+        const currentIndex = displayModes.findIndex(mode =>
+            current != null && mode.width == current.width && mode.height == current.height && mode.refresh_rate == current.refresh_rate
+        )
+
+        this.resolution = new SelectComponent("displayMode",
+            displayModes.map((mode, index) => ({
+                value: index.toString(),
+                name: `${mode.width}x${mode.height} at ${mode.refresh_rate} fps`
+            })),
+            {
+                displayName: "Resolution",
+                preSelectedOption: Math.max(currentIndex, 0).toString()
+            }
+        )
+    }
+
+    reset(): void {
+        this.resolution.reset()
+    }
+    submit(): HostDisplayMode | null {
+        const index = parseInt(this.resolution.getValue() ?? "")
+
+        return this.displayModes[index] ?? null
+    }
+
+    mountForm(form: HTMLFormElement): void {
+        form.appendChild(this.header)
+        this.resolution.mount(form)
+    }
+}
@@ -1,11 +1,23 @@
-import { DetailedHost, DetailedUser, UndetailedHost } from "../../api_bindings.js"
-import { Api, apiDeleteHost, apiGetHost, isDetailedHost, apiPostPair, apiWakeUp, apiGetUser, apiPatchHost } from "../../api.js"
+import { DetailedHost, DetailedUser, HostDisplayMode, PairStage, UndetailedHost } from "../../api_bindings.js"
+import { Api, apiDeleteHost, apiGetHost, isDetailedHost, apiPostPair, apiWakeUp, apiGetUser, apiPatchHost, apiGetWakeUpStatus, apiGetHostShares, apiPutHostShares, apiPostHostUnpairRemote, apiGetHostUptime } from "../../api.js"
 import { Component, ComponentEvent } from "../index.js"
 import { setContextMenu } from "../context_menu.js"
 import { showErrorPopup } from "../error.js"
-import { showMessage } from "../modal/index.js"
+import { showMessage, showModal, showPrompt } from "../modal/index.js"
+import { DisplayModeModal } from "./display_mode_modal.js"
+import { getLocalStreamSettings } from "../settings_menu.js"
 import { HOST_IMAGE, HOST_OVERLAY_LOCK, HOST_OVERLAY_NONE, HOST_OVERLAY_OFFLINE } from "../../resources/index.js"
 
+const PAIR_STAGE_NAMES: Record<PairStage, string> = {
+    ServerCertificate: "exchanging certificates",
+    ClientChallenge: "sending the challenge",
+    ServerChallenge: "verifying the pin",
+    ClientPairingSecret: "sending the pairing secret",
+    PairingConfirm: "confirming the pairing",
+}
+
+const WAKE_UP_POLL_INTERVAL = 2000
+
 export type HostEventListener = (event: ComponentEvent<Host>) => void
 
 export class Host implements Component {
@@ -14,6 +26,7 @@ export class Host implements Component {
     private hostId: number
     private userCache: DetailedUser | null = null
     private cache: UndetailedHost | DetailedHost | null = null
+    private displayMode: HostDisplayMode | null = null
 
     private divElement: HTMLDivElement = document.createElement("div")
 
@@ -80,6 +93,10 @@ export class Host implements Component {
             this.onContextMenu(event)
         } else if (this.cache?.paired == "Paired") {
             this.divElement.dispatchEvent(new ComponentEvent("ml-hostopen", this))
+        } else if (this.cache?.paired == "PairedButRejected") {
+            // The host regenerated its certificate or this device was removed from it
+            await showMessage(`${this.cache.name} rejected the pairing, please pair it again.`)
+            await this.pair()
         } else {
             await this.pair()
         }
@@ -98,11 +115,28 @@ export class Host implements Component {
                 name: "Open",
                 callback: this.onClick.bind(this)
             })
-        } else if (this.cache?.paired == "Paired") {
-            elements.push({
-                name: "Send Wake Up Packet",
-                callback: this.wakeUp.bind(this)
-            })
+
+            if (this.cache && isDetailedHost(this.cache) && this.cache.display_modes.length > 0) {
+                elements.push({
+                    name: "Choose Resolution",
+                    callback: this.chooseDisplayMode.bind(this)
+                })
+            }
+        } else {
+            if (this.cache?.paired == "Paired") {
+                elements.push({
+                    name: "Send Wake Up Packet",
+                    callback: this.wakeUp.bind(this)
+                })
+            }
+
+            // Shared hosts can only be changed by the owner
+            if (this.cache?.owner != "Shared") {
+                elements.push({
+                    name: "Set MAC Address",
+                    callback: this.setMac.bind(this)
+                })
+            }
         }
 
         elements.push({
@@ -110,10 +144,26 @@ export class Host implements Component {
             callback: async () => this.forceFetch()
         })
 
-        if (this.cache?.server_state != null && this.cache?.paired == "NotPaired") {
+        if (this.cache?.owner != "Shared") {
+            elements.push({
+                name: "Rename",
+                callback: this.rename.bind(this)
+            })
+
+            elements.push({
+                name: "Set External Address",
+                callback: this.setExternalAddress.bind(this)
+            })
+        }
+
+        if (this.cache?.server_state != null && this.cache?.paired != "Paired") {
             elements.push({
                 name: "Pair",
-                callback: this.pair.bind(this)
+                callback: () => this.pair()
+            })
+            elements.push({
+                name: "Pair with Pin from Host",
+                callback: this.pairWithHostPin.bind(this)
             })
         }
 
@@ -134,6 +184,28 @@ export class Host implements Component {
             }
         }
 
+        if (this.userCache?.role == "Admin") {
+            elements.push({
+                name: "Toggle Reachability Probe",
+                callback: this.toggleReachabilityProbe.bind(this)
+            })
+        }
+
+        if (this.cache?.owner == "ThisUser") {
+            elements.push({
+                name: "Share with Users",
+                callback: this.share.bind(this)
+            })
+        }
+
+        if (this.cache?.paired != "NotPaired" && (this.cache?.owner == "Global" || this.cache?.owner == "ThisUser" || this.userCache?.role == "Admin")) {
+            elements.push({
+                name: "Unpair",
+                callback: this.unpair.bind(this),
+                classes: ["context-menu-element-red"]
+            })
+        }
+
         if (this.cache?.owner == "ThisUser" || this.userCache?.role == "Admin") {
             elements.push({
                 name: "Remove Host",
@@ -146,6 +218,30 @@ export class Host implements Component {
         })
     }
 
+    private async chooseDisplayMode() {
+        const host = this.cache && isDetailedHost(this.cache) ? this.cache : null
+        if (!host) {
+            return
+        }
+
+        const settings = getLocalStreamSettings()
+        const current = settings?.videoSize == "custom" ? {
+            width: settings.videoSizeCustom.width,
+            height: settings.videoSizeCustom.height,
+            refresh_rate: settings.fps,
+        } : null
+
+        const modal = new DisplayModeModal(host.name, host.display_modes, current)
+        const displayMode = await showModal(modal)
+        if (!displayMode) {
+            return
+        }
+
+        // The stream settings will use this as the custom video size and fps
+        this.displayMode = displayMode
+        this.divElement.dispatchEvent(new ComponentEvent("ml-hostdisplaymode", this))
+    }
+
     private async showDetails() {
         let host = this.cache;
         if (!host || !isDetailedHost(host)) {
@@ -159,23 +255,41 @@ export class Host implements Component {
         }
         this.updateCache(host, this.userCache)
 
+        const uptime = await apiGetHostUptime(this.api, {
+            host_id: this.hostId,
+            hours: null,
+        })
+
         await showMessage(
             `Web Id: ${host.host_id}\n` +
             `Name: ${host.name}\n` +
+            `Host Name: ${host.host_name}\n` +
             `Pair Status: ${host.paired}\n` +
             `State: ${host.server_state}\n` +
-            `Address: ${host.address}\n` +
+            `Address: ${host.address}${host.external_address != null ? " (external)" : ""}\n` +
             `Http Port: ${host.http_port}\n` +
-            `Https Port: ${host.https_port}\n` +
+            `Https Port: ${host.https_port}${host.https_port_override != null ? " (override)" : ""}\n` +
             `External Port: ${host.external_port}\n` +
             `Version: ${host.version}\n` +
             `Gfe Version: ${host.gfe_version}\n` +
             `Unique ID: ${host.unique_id}\n` +
             `MAC: ${host.mac}\n` +
             `Local IP: ${host.local_ip}\n` +
-            `Current Game: ${host.current_game}\n` +
+            `Current Game: ${host.current_game_name != null ? `${host.current_game_name} (${host.current_game})` : host.current_game}\n` +
             `Max Luma Pixels Hevc: ${host.max_luma_pixels_hevc}\n` +
-            `Server Codec Mode Support: ${host.server_codec_mode_support}`
+            `Server Codec Mode Support: ${host.server_codec_mode_support}` +
+            (host.capabilities ?
+                `\nVideo Codecs: ${host.capabilities.video_codecs.join(", ")}\n` +
+                `HDR: ${host.capabilities.hdr}\n` +
+                `YUV 4:4:4: ${host.capabilities.yuv444}\n` +
+                `Above 4K: ${host.capabilities.above_4k}`
+                : "") +
+            `\nAvailability (24h): ${uptime.availability_percent != null ? `${uptime.availability_percent.toFixed(1)}%` : "Unknown"}` +
+            `\nFlapping: ${uptime.flapping}\n` +
+            `Reachability Probe: ${host.reachability_probe}` +
+            (host.display_modes.length > 0 ?
+                `\nDisplay Modes: ${host.display_modes.map(mode => `${mode.width}x${mode.height}@${mode.refresh_rate}`).join(", ")}`
+                : "")
         )
     }
 
@@ -193,11 +307,28 @@ export class Host implements Component {
         this.divElement.removeEventListener("ml-hostopen", listener as any, options)
     }
 
+    addHostDisplayModeListener(listener: HostEventListener, options?: EventListenerOptions) {
+        this.divElement.addEventListener("ml-hostdisplaymode", listener as any, options)
+    }
+    removeHostDisplayModeListener(listener: HostEventListener, options?: EventListenerOptions) {
+        this.divElement.removeEventListener("ml-hostdisplaymode", listener as any, options)
+    }
+
     private async makeGlobal() {
         await apiPatchHost(this.api, {
             host_id: this.hostId,
             change_owner: true,
             owner: null,
+            change_mac: false,
+            mac: null,
+            change_display_name: false,
+            display_name: null,
+            change_https_port_override: false,
+            https_port_override: null,
+            change_external_address: false,
+            external_address: null,
+            change_reachability_probe: false,
+            reachability_probe: false,
         })
 
         if (this.cache) {
@@ -211,6 +342,16 @@ export class Host implements Component {
             host_id: this.hostId,
             change_owner: true,
             owner: user.id,
+            change_mac: false,
+            mac: null,
+            change_display_name: false,
+            display_name: null,
+            change_https_port_override: false,
+            https_port_override: null,
+            change_external_address: false,
+            external_address: null,
+            change_reachability_probe: false,
+            reachability_probe: false,
         })
 
         if (this.cache) {
@@ -218,6 +359,48 @@ export class Host implements Component {
         }
     }
 
+    private async toggleReachabilityProbe() {
+        const host = await apiGetHost(this.api, {
+            host_id: this.hostId,
+        })
+
+        await apiPatchHost(this.api, {
+            host_id: this.hostId,
+            change_owner: false,
+            owner: null,
+            change_mac: false,
+            mac: null,
+            change_display_name: false,
+            display_name: null,
+            change_https_port_override: false,
+            https_port_override: null,
+            change_external_address: false,
+            external_address: null,
+            change_reachability_probe: true,
+            reachability_probe: !host.reachability_probe,
+        })
+
+        await showMessage(`The reachability probe of ${host.name} is ${host.reachability_probe ? "disabled" : "enabled"}.`)
+    }
+
+    private async share() {
+        const userNames = await apiGetHostShares(this.api, {
+            host_id: this.hostId
+        })
+
+        const input = await showPrompt("Names of the users which can use this host, separated by commas", {
+            defaultValue: userNames.join(", "),
+        })
+        if (input == null) {
+            return
+        }
+
+        await apiPutHostShares(this.api, {
+            host_id: this.hostId,
+            user_names: input.split(",").map(name => name.trim()).filter(name => name.length > 0)
+        })
+    }
+
     private async remove() {
         await apiDeleteHost(this.api, {
             host_id: this.getHostId()
@@ -225,14 +408,148 @@ export class Host implements Component {
 
         this.divElement.dispatchEvent(new ComponentEvent("ml-hostremove", this))
     }
+    private async unpair() {
+        const { result } = await apiPostHostUnpairRemote(this.api, {
+            host_id: this.getHostId()
+        })
+
+        if (result == "HostOffline") {
+            await showMessage(`${this.getCache()?.name} is offline, it will be unpaired once it's online again.`)
+        } else if (result == "Failed") {
+            await showMessage(`${this.getCache()?.name} rejected the unpair, remove this client in the settings of the host.`)
+        }
+
+        await this.forceFetch()
+    }
     private async wakeUp() {
         await apiWakeUp(this.api, {
             host_id: this.getHostId()
         })
 
-        await showMessage("Sent Wake Up packet. It might take a moment for your pc to start.")
+        // Poll the status until the host is online or the user closed the message
+        let closed = false
+        const messageAbort = new AbortController()
+        showMessage("Sent Wake Up packet. It might take a moment for your pc to start.", { signal: messageAbort.signal })
+            .then(() => closed = true)
+
+        let status = "Waking"
+        while (status == "Waking") {
+            await new Promise(resolve => setTimeout(resolve, WAKE_UP_POLL_INTERVAL))
+            if (closed) {
+                return
+            }
+
+            status = (await apiGetWakeUpStatus(this.api, { host_id: this.getHostId() })).status
+        }
+        messageAbort.abort()
+
+        if (status == "Online") {
+            await this.forceFetch()
+
+            await showMessage(`${this.getCache()?.name} is online.`)
+        } else {
+            await showMessage(`${this.getCache()?.name} didn't wake up. Make sure Wake-on-LAN is enabled on the host.`)
+        }
     }
-    private async pair() {
+    private async setMac() {
+        const mac = await showPrompt("MAC address of the host (leave empty to remove it)", {
+            defaultValue: this.cache && isDetailedHost(this.cache) ? this.cache.mac ?? "" : "",
+        })
+        if (mac == null) {
+            return
+        }
+
+        await apiPatchHost(this.api, {
+            host_id: this.hostId,
+            change_owner: false,
+            owner: null,
+            change_mac: true,
+            mac: mac.trim() || null,
+            change_display_name: false,
+            display_name: null,
+            change_https_port_override: false,
+            https_port_override: null,
+            change_external_address: false,
+            external_address: null,
+            change_reachability_probe: false,
+            reachability_probe: false,
+        })
+    }
+    private async rename() {
+        const name = await showPrompt("Display name of the host (leave empty to use the name of the host)", {
+            defaultValue: this.cache?.name,
+        })
+        if (name == null) {
+            return
+        }
+
+        await apiPatchHost(this.api, {
+            host_id: this.hostId,
+            change_owner: false,
+            owner: null,
+            change_mac: false,
+            mac: null,
+            change_display_name: true,
+            display_name: name.trim() || null,
+            change_https_port_override: false,
+            https_port_override: null,
+            change_external_address: false,
+            external_address: null,
+            change_reachability_probe: false,
+            reachability_probe: false,
+        })
+
+        await this.forceFetch()
+    }
+    private async setExternalAddress() {
+        const host = this.cache && isDetailedHost(this.cache) ? this.cache : null
+
+        const address = await showPrompt("Address used to reach the host, e.g. behind a NAT (leave empty to use the added address)", {
+            defaultValue: host?.external_address ?? "",
+        })
+        if (address == null) {
+            return
+        }
+        const httpsPort = await showPrompt("Https port used to reach the host (leave empty to use the port reported by the host)", {
+            defaultValue: host?.https_port_override?.toString() ?? "",
+        })
+        if (httpsPort == null) {
+            return
+        }
+
+        const httpsPortOverride = httpsPort.trim() ? parseInt(httpsPort.trim()) : null
+        if (httpsPortOverride != null && (isNaN(httpsPortOverride) || httpsPortOverride <= 0 || httpsPortOverride > 65535)) {
+            showErrorPopup(`invalid https port: ${httpsPort}`)
+            return
+        }
+
+        await apiPatchHost(this.api, {
+            host_id: this.hostId,
+            change_owner: false,
+            owner: null,
+            change_mac: false,
+            mac: null,
+            change_display_name: false,
+            display_name: null,
+            change_https_port_override: true,
+            https_port_override: httpsPortOverride,
+            change_external_address: true,
+            external_address: address.trim() || null,
+            change_reachability_probe: false,
+            reachability_probe: false,
+        })
+
+        await this.forceFetch()
+    }
+    private async pairWithHostPin() {
+        const pin = await showPrompt(`Enter the pin shown by ${this.getCache()?.name}`, { name: "Pin" })
+        if (pin == null) {
+            return
+        }
+
+        await this.pair(pin.trim())
+    }
+    private async pair(hostPin?: string) {
         if (this.cache?.paired == "Paired") {
             await this.forceFetch()
 
@@ -243,23 +560,42 @@ export class Host implements Component {
         }
 
         const responseStream = await apiPostPair(this.api, {
-            host_id: this.getHostId()
+            host_id: this.getHostId(),
+            pin: hostPin ?? null
         })
 
         if (typeof responseStream.response == "string") {
             throw `failed to pair (stage 1): ${responseStream.response}`
         }
 
-        const messageAbort = new AbortController()
-        showMessage(`Please pair your host ${this.getCache()?.name} with this pin:\nPin: ${responseStream.response.Pin}`, { signal: messageAbort.signal })
+        const pin = responseStream.response.Pin
+
+        let messageAbort = new AbortController()
+        if (hostPin == null) {
+            showMessage(`Please pair your host ${this.getCache()?.name} with this pin:\nPin: ${pin}`, { signal: messageAbort.signal })
+        } else {
+            showMessage(`Pairing with ${this.getCache()?.name} using the pin ${pin}`, { signal: messageAbort.signal })
+        }
+
+        let resultResponse = await responseStream.next()
+        while (resultResponse && "Stage" in resultResponse) {
+            // The host only continues after the pin was entered, so keep showing it until then
+            if (resultResponse.Stage != "ServerCertificate") {
+                messageAbort.abort()
+
+                messageAbort = new AbortController()
+                showMessage(`Pairing with ${this.getCache()?.name}: ${PAIR_STAGE_NAMES[resultResponse.Stage]}`, { signal: messageAbort.signal })
+            }
 
-        const resultResponse = await responseStream.next()
+            resultResponse = await responseStream.next()
+        }
         messageAbort.abort()
 
         if (!resultResponse) {
             throw "missing stage 2 of pairing"
-        } else if (typeof resultResponse == "string") {
-            throw `failed to pair (stage 2): ${resultResponse}`
+        } else if (!("Paired" in resultResponse)) {
+            const stage = "PairError" in resultResponse ? resultResponse.PairError.stage : null
+            throw `failed to pair (stage 2): ${stage ? `failed while ${PAIR_STAGE_NAMES[stage]}` : "PairError"}`
         }
 
         this.updateCache(resultResponse.Paired, null)
@@ -269,6 +605,10 @@ export class Host implements Component {
         return this.hostId
     }
 
+    getDisplayMode(): HostDisplayMode | null {
+        return this.displayMode
+    }
+
     getCache(): DetailedHost | UndetailedHost | null {
         return this.cache
     }
@@ -298,6 +638,10 @@ export class Host implements Component {
         // Update Elements
         this.nameElement.innerText = this.cache.name
 
+        // The host came back online too often within the last hour
+        this.nameElement.classList.toggle("host-name-flapping", this.cache.flapping)
+        this.nameElement.title = this.cache.flapping ? "This host keeps going offline" : ""
+
         if (this.cache.server_state == null) {
             this.imageOverlayElement.src = HOST_OVERLAY_OFFLINE
         } else if (this.cache.paired != "Paired") {
@@ -49,12 +49,14 @@ export class HostList extends FetchListComponent<DetailedHost | UndetailedHost,
 
         newHost.addHostRemoveListener(this.removeHostListener.bind(this))
         newHost.addHostOpenListener(this.onHostOpenEvent.bind(this))
+        newHost.addHostDisplayModeListener(this.onHostDisplayModeEvent.bind(this))
     }
     public removeList(listIndex: number): void {
         const hostComponent = this.list.remove(listIndex)
 
         hostComponent?.addHostOpenListener(this.onHostOpenEvent.bind(this))
         hostComponent?.removeHostRemoveListener(this.removeHostListener.bind(this))
+        hostComponent?.removeHostDisplayModeListener(this.onHostDisplayModeEvent.bind(this))
     }
 
     private removeHostListener(event: ComponentEvent<Host>) {
@@ -78,6 +80,17 @@ export class HostList extends FetchListComponent<DetailedHost | UndetailedHost,
         this.eventTarget.removeEventListener("ml-hostopen", listener as EventListenerOrEventListenerObject, options)
     }
 
+    private onHostDisplayModeEvent(event: ComponentEvent<Host>) {
+        this.eventTarget.dispatchEvent(new ComponentEvent("ml-hostdisplaymode", event.component))
+    }
+
+    addHostDisplayModeListener(listener: HostEventListener, options?: EventListenerOptions) {
+        this.eventTarget.addEventListener("ml-hostdisplaymode", listener as EventListenerOrEventListenerObject, options)
+    }
+    removeHostDisplayModeListener(listener: HostEventListener, options?: EventListenerOptions) {
+        this.eventTarget.removeEventListener("ml-hostdisplaymode", listener as EventListenerOrEventListenerObject, options)
+    }
+
     mount(parent: Element): void {
         this.list.mount(parent)
     }
@@ -290,6 +290,16 @@ export class SelectComponent extends ElementWithLabel {
         }
     }
 
+    setValue(value: string) {
+        if (this.strategy == "datalist") {
+            const inputElement = (this.inputElement as HTMLInputElement)
+            inputElement.value = this.options.find(option => option.value == value)?.name ?? ""
+        } else {
+            const selectElement = (this.optionRoot as HTMLSelectElement)
+            selectElement.value = value
+        }
+    }
+
     getValue(): string | null {
         if (this.strategy == "datalist") {
             const name = (this.inputElement as HTMLInputElement).value
@@ -1,6 +1,8 @@
+import { KeyboardLayout, KeyboardMode, KeyOverride } from "../api_bindings.js";
 import { ControllerConfig } from "../stream/gamepad.js";
 import { MouseScrollMode } from "../stream/input.js";
 import { PageStyle } from "../styles/index.js";
+import { showErrorPopup } from "./error.js";
 import { Component, ComponentEvent } from "./index.js";
 import { InputComponent, SelectComponent } from "./input.js";
 import { SidebarEdge } from "./sidebar/index.js";
@@ -22,7 +24,18 @@ export type Settings = {
     playAudioLocal: boolean
     audioSampleQueueSize: number
     mouseScrollMode: MouseScrollMode
+    keyboardMode: KeyboardMode
+    keyboardLayout: KeyboardLayout
+    // key = the virtual key code sent by the browser, value = the virtual key code sent to the host
+    keyboardLayoutCustom: Record<number, number>
+    // key = the virtual key code sent by the browser
+    keyOverrides: Record<number, KeyOverride>
     controllerConfig: ControllerConfig
+    // The input types the host receives in this stream
+    allowKeyboard: boolean
+    allowMouse: boolean
+    allowGamepad: boolean
+    allowTouch: boolean
     dataTransport: TransportType
     toggleFullscreenWithKeybind: boolean
     pageStyle: PageStyle
@@ -93,11 +106,23 @@ export class StreamSettingsComponent implements Component {
     private mouseHeader: HTMLHeadingElement = document.createElement("h2")
     private mouseScrollMode: SelectComponent
 
+    private keyboardHeader: HTMLHeadingElement = document.createElement("h2")
+    private keyboardMode: SelectComponent
+    private keyboardLayout: SelectComponent
+    private keyboardLayoutCustomFile: InputComponent
+    private keyboardLayoutCustom: Record<number, number>
+
     private controllerHeader: HTMLHeadingElement = document.createElement("h2")
     private controllerInvertAB: InputComponent
     private controllerInvertXY: InputComponent
     private controllerSendIntervalOverride: InputComponent
 
+    private allowedInputHeader: HTMLHeadingElement = document.createElement("h2")
+    private allowKeyboard: InputComponent
+    private allowMouse: InputComponent
+    private allowGamepad: InputComponent
+    private allowTouch: InputComponent
+
     private otherHeader: HTMLHeadingElement = document.createElement("h2")
     private dataTransport: SelectComponent
     private toggleFullscreenWithKeybind: InputComponent
@@ -266,6 +291,43 @@ export class StreamSettingsComponent implements Component {
         this.mouseScrollMode.addChangeListener(this.onSettingsChange.bind(this))
         this.mouseScrollMode.mount(this.divElement)
 
+        // Keyboard
+        this.keyboardHeader.innerText = "Keyboard"
+        this.divElement.appendChild(this.keyboardHeader)
+
+        this.keyboardMode = new SelectComponent("keyboardMode",
+            [
+                { value: "vk_passthrough", name: "Key Codes (US Layout)" },
+                { value: "text_fallback", name: "Type Characters (Any Layout)" }
+            ],
+            {
+                displayName: "Keyboard Mode",
+                preSelectedOption: settings?.keyboardMode || defaultSettings_.keyboardMode
+            }
+        )
+        this.keyboardMode.addChangeListener(this.onSettingsChange.bind(this))
+        this.keyboardMode.mount(this.divElement)
+
+        this.keyboardLayout = new SelectComponent("keyboardLayout",
+            [
+                { value: "passthrough", name: "None" },
+                { value: "azerty", name: "AZERTY" },
+                { value: "qwertz", name: "QWERTZ" },
+                { value: "custom", name: "Custom" }
+            ],
+            {
+                displayName: "Keyboard Layout",
+                preSelectedOption: settings?.keyboardLayout || defaultSettings_.keyboardLayout
+            }
+        )
+        this.keyboardLayout.addChangeListener(this.onSettingsChange.bind(this))
+        this.keyboardLayout.mount(this.divElement)
+
+        this.keyboardLayoutCustom = settings?.keyboardLayoutCustom ?? defaultSettings_.keyboardLayoutCustom
+        this.keyboardLayoutCustomFile = new InputComponent("keyboardLayoutCustom", "file", "Custom Keyboard Layout (JSON)", { accept: ".json" })
+        this.keyboardLayoutCustomFile.addChangeListener(this.setCustomKeyboardLayout.bind(this))
+        this.keyboardLayoutCustomFile.mount(this.divElement)
+
         // Controller
         if (window.isSecureContext) {
             this.controllerHeader.innerText = "Controller"
@@ -305,6 +367,34 @@ export class StreamSettingsComponent implements Component {
             this.controllerInvertXY.setEnabled(false)
         }
 
+        // Allowed Input
+        this.allowedInputHeader.innerText = "Allowed Input"
+        this.divElement.appendChild(this.allowedInputHeader)
+
+        this.allowKeyboard = new InputComponent("allowKeyboard", "checkbox", "Allow Keyboard", {
+            checked: settings?.allowKeyboard ?? defaultSettings_.allowKeyboard
+        })
+        this.allowKeyboard.addChangeListener(this.onSettingsChange.bind(this))
+        this.allowKeyboard.mount(this.divElement)
+
+        this.allowMouse = new InputComponent("allowMouse", "checkbox", "Allow Mouse", {
+            checked: settings?.allowMouse ?? defaultSettings_.allowMouse
+        })
+        this.allowMouse.addChangeListener(this.onSettingsChange.bind(this))
+        this.allowMouse.mount(this.divElement)
+
+        this.allowGamepad = new InputComponent("allowGamepad", "checkbox", "Allow Controllers", {
+            checked: settings?.allowGamepad ?? defaultSettings_.allowGamepad
+        })
+        this.allowGamepad.addChangeListener(this.onSettingsChange.bind(this))
+        this.allowGamepad.mount(this.divElement)
+
+        this.allowTouch = new InputComponent("allowTouch", "checkbox", "Allow Touch", {
+            checked: settings?.allowTouch ?? defaultSettings_.allowTouch
+        })
+        this.allowTouch.addChangeListener(this.onSettingsChange.bind(this))
+        this.allowTouch.mount(this.divElement)
+
         // Other
         this.otherHeader.innerText = "Other"
         this.divElement.appendChild(this.otherHeader)
@@ -339,7 +429,37 @@ export class StreamSettingsComponent implements Component {
         this.onSettingsChange()
     }
 
+    private async setCustomKeyboardLayout(event: ComponentEvent<InputComponent>) {
+        const file = event.component.getFiles()?.[0]
+        if (!file) {
+            return
+        }
+
+        let layout
+        try {
+            layout = JSON.parse(await file.text())
+        } catch (e) {
+            showErrorPopup("Failed to parse the custom keyboard layout", false, e)
+            return
+        }
+
+        // Only keep mappings from a virtual key code to a virtual key code
+        const keyboardLayoutCustom: Record<number, number> = {}
+        for (const key in layout) {
+            const keyCode = parseInt(key)
+            const value = layout[key]
+            if (Number.isInteger(keyCode) && Number.isInteger(value)) {
+                keyboardLayoutCustom[keyCode] = value
+            }
+        }
+
+        this.keyboardLayoutCustom = keyboardLayoutCustom
+        this.onSettingsChange()
+    }
+
     private onSettingsChange() {
+        this.keyboardLayoutCustomFile.setEnabled(this.keyboardLayout.getValue() == "custom")
+
         if (this.videoSize.getValue() == "custom") {
             this.videoSizeWidth.setEnabled(true)
             this.videoSizeHeight.setEnabled(true)
@@ -351,6 +471,15 @@ export class StreamSettingsComponent implements Component {
         this.divElement.dispatchEvent(new ComponentEvent("ml-settingschange", this))
     }
 
+    setVideoSize(width: number, height: number, fps: number) {
+        this.videoSize.setValue("custom")
+        this.videoSizeWidth.setValue(width.toString())
+        this.videoSizeHeight.setValue(height.toString())
+        this.fps.setValue(fps.toString())
+
+        this.onSettingsChange()
+    }
+
     addChangeListener(listener: StreamSettingsChangeListener) {
         this.divElement.addEventListener("ml-settingschange", listener as any)
     }
@@ -380,6 +509,10 @@ export class StreamSettingsComponent implements Component {
 
         settings.mouseScrollMode = this.mouseScrollMode.getValue() as any
 
+        settings.keyboardMode = this.keyboardMode.getValue() as any
+        settings.keyboardLayout = this.keyboardLayout.getValue() as any
+        settings.keyboardLayoutCustom = this.keyboardLayoutCustom
+
         settings.controllerConfig.invertAB = this.controllerInvertAB.isChecked()
         settings.controllerConfig.invertXY = this.controllerInvertXY.isChecked()
         if (this.controllerSendIntervalOverride.isEnabled()) {
@@ -388,6 +521,11 @@ export class StreamSettingsComponent implements Component {
             settings.controllerConfig.sendIntervalOverride = null
         }
 
+        settings.allowKeyboard = this.allowKeyboard.isChecked()
+        settings.allowMouse = this.allowMouse.isChecked()
+        settings.allowGamepad = this.allowGamepad.isChecked()
+        settings.allowTouch = this.allowTouch.isChecked()
+
         settings.dataTransport = this.dataTransport.getValue() as any
 
         settings.toggleFullscreenWithKeybind = this.toggleFullscreenWithKeybind.isChecked()
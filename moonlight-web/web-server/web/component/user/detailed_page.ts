@@ -5,6 +5,10 @@ import { InputComponent, SelectComponent } from "../input.js";
 import { createSelectRoleInput } from "./role_select.js";
 import { tryDeleteUser, UserEventListener } from "./index.js";
 
+function formatLastSeen(time: number | null): string {
+    return time != null ? new Date(time).toLocaleString() : "Never"
+}
+
 export class DetailedUserPage implements Component {
 
     private api: Api
@@ -18,6 +22,8 @@ export class DetailedUserPage implements Component {
     private password: InputComponent
     private role: SelectComponent
     private clientUniqueId: InputComponent
+    private pairDeviceName: InputComponent
+    private initialPairDeviceName: string
 
     private applyButton = document.createElement("button")
     private deleteButton = document.createElement("button")
@@ -56,6 +62,27 @@ export class DetailedUserPage implements Component {
         })
         this.clientUniqueId.mount(this.formRoot)
 
+        // Only used when pairing a host, the hosts list the pairings with this name
+        this.initialPairDeviceName = user.pair_device_name
+        this.pairDeviceName = new InputComponent("userPairDeviceName", "text", "Pair Device Name", {
+            defaultValue: user.pair_device_name,
+            placeholer: "Default Name",
+        })
+        this.pairDeviceName.mount(this.formRoot)
+
+        // Helps to find abandoned accounts
+        const lastLogin = new InputComponent("userLastLogin", "text", "Last Login", {
+            defaultValue: formatLastSeen(user.last_login),
+        })
+        lastLogin.setEnabled(false)
+        lastLogin.mount(this.formRoot)
+
+        const lastStream = new InputComponent("userLastStream", "text", "Last Stream", {
+            defaultValue: formatLastSeen(user.last_stream),
+        })
+        lastStream.setEnabled(false)
+        lastStream.mount(this.formRoot)
+
         this.applyButton.innerText = "Apply"
         this.applyButton.type = "submit"
         this.formRoot.appendChild(this.applyButton)
@@ -77,14 +104,21 @@ export class DetailedUserPage implements Component {
             password = this.password.getValue()
         }
 
+        // The default name would be stored as a custom name otherwise
+        const pairDeviceName = this.pairDeviceName.getValue()
+        const changePairDeviceName = pairDeviceName != this.initialPairDeviceName
+
         const request: PatchUserRequest = {
             id: this.id,
             role: this.role.getValue() as UserRole,
             password,
-            client_unique_id: this.clientUniqueId.getValue()
+            client_unique_id: this.clientUniqueId.getValue(),
+            change_pair_device_name: changePairDeviceName,
+            pair_device_name: changePairDeviceName ? pairDeviceName : null,
         };
 
         await apiPatchUser(this.api, request)
+        this.initialPairDeviceName = pairDeviceName
     }
 
     private async delete() {
@@ -26,12 +26,25 @@ const trueDefaultSettings: Settings =
     "audioSampleQueueSize": 20,
     // possible values: "highres", "normal"
     "mouseScrollMode": "highres",
+    // possible values: "vk_passthrough", "text_fallback"
+    "keyboardMode": "vk_passthrough",
+    // possible values: "passthrough", "azerty", "qwertz", "custom"
+    "keyboardLayout": "passthrough",
+    // used by the "custom" keyboard layout, key = virtual key code, value = the virtual key code sent to the host
+    "keyboardLayoutCustom": {},
+    // key = virtual key code, value = { "Key": <virtual key code> } or { "Text": "<text>" }
+    "keyOverrides": {},
     "controllerConfig": {
         "invertAB": false,
         "invertXY": false,
         // possible values: null or a number, example: 60, 120
         "sendIntervalOverride": null
     },
+    // the input types the host receives in the stream
+    "allowKeyboard": true,
+    "allowMouse": true,
+    "allowGamepad": true,
+    "allowTouch": true,
     // possible values: "auto", "webrtc", "websocket"
     "dataTransport": "auto",
     "toggleFullscreenWithKeybind": false,
@@ -58,4 +71,32 @@ if (CONFIG?.default_settings) {
     assignIfMissing(defaultSettings.videoSizeCustom, trueDefaultSettings.videoSizeCustom)
 }
 
+// The default settings of the logged in user stored on the server
+const USER_DEFAULT_SETTINGS_KEY = "mlUserDefaultSettings"
+
+function applyUserDefaultSettings(settings: any) {
+    Object.assign(defaultSettings, settings)
+
+    assignIfMissing(defaultSettings.controllerConfig, trueDefaultSettings.controllerConfig)
+    assignIfMissing(defaultSettings.videoSizeCustom, trueDefaultSettings.videoSizeCustom)
+}
+
+try {
+    const userSettingsJson = localStorage.getItem(USER_DEFAULT_SETTINGS_KEY)
+    if (userSettingsJson != null) {
+        applyUserDefaultSettings(JSON.parse(userSettingsJson))
+    }
+} catch (e) {
+    localStorage.removeItem(USER_DEFAULT_SETTINGS_KEY)
+}
+
+export function setUserDefaultSettings(settings: any | null) {
+    if (settings) {
+        localStorage.setItem(USER_DEFAULT_SETTINGS_KEY, JSON.stringify(settings))
+        applyUserDefaultSettings(settings)
+    } else {
+        localStorage.removeItem(USER_DEFAULT_SETTINGS_KEY)
+    }
+}
+
 export default defaultSettings as Settings
\ No newline at end of file
@@ -12,6 +12,7 @@
 import { DiscordSDK, DiscordSDKMock } from "@discord/embedded-app-sdk";
 import type { Types } from "@discord/embedded-app-sdk";
 import {
+    PeerVideoMode,
     RoomInfo,
     RoomParticipant,
     RoomRole,
@@ -37,11 +38,16 @@ interface ActivityState {
     playerSlot: PlayerSlot | null;
     participants: RoomParticipant[];
     discordUser: Types.GetActivityInstanceConnectedParticipantsResponse["participants"][0] | null;
+    discordAccessToken: string | null;
     ws: WebSocket | null;
     peerConnection: RTCPeerConnection | null;
     iceServers: RtcIceServer[];
     videoElement: HTMLVideoElement | null;
     capabilities: StreamCapabilities | null;
+    /** The newest start of the stream, older setup was superseded */
+    connectionEpoch: number;
+    /** Spectators can save bandwidth by only receiving a keyframe every few seconds */
+    videoMode: PeerVideoMode;
 }
 
 const state: ActivityState = {
@@ -51,11 +57,14 @@ const state: ActivityState = {
     playerSlot: null,
     participants: [],
     discordUser: null,
+    discordAccessToken: null,
     ws: null,
     peerConnection: null,
     iceServers: [],
     videoElement: null,
     capabilities: null,
+    connectionEpoch: 0,
+    videoMode: "Full",
 };
 
 // DOM Elements
@@ -73,6 +82,7 @@ function getElements() {
         participantCount: document.getElementById("participant-count") as HTMLDivElement,
         participantsCountBadge: document.getElementById("participants-count-badge") as HTMLSpanElement,
         fullscreenBtn: document.getElementById("fullscreen-btn") as HTMLButtonElement,
+        videoModeBtn: document.getElementById("video-mode-btn") as HTMLButtonElement,
         activityContainer: document.getElementById("activity-container") as HTMLDivElement,
     };
 }
@@ -151,6 +161,7 @@ async function authenticateWithDiscord(): Promise<void> {
         }
 
         const { access_token } = await response.json();
+        state.discordAccessToken = access_token;
 
         // Authenticate with Discord SDK
         await discordSdk.commands.authenticate({ access_token });
@@ -195,27 +206,21 @@ async function joinRoomAsSpectator(roomId: string): Promise<void> {
 
     // Connect via WebSocket to the guest stream endpoint
     const protocol = window.location.protocol === "https:" ? "wss:" : "ws:";
-    const wsUrl = `${protocol}//${window.location.host}/guest/stream?room=${encodeURIComponent(roomId)}`;
+    // The server fetches our Discord profile with the access token, it's sent as the first message so it isn't logged with the url
+    const discordAccessToken = state.discordAccessToken;
+    const authParam = discordAccessToken ? "&discord_auth=true" : "";
+    const nameParam = `&player_name=${encodeURIComponent(state.discordUser?.username ?? "Guest")}`;
+    const wsUrl = `${protocol}//${window.location.host}/guest/stream?room_id=${encodeURIComponent(roomId)}&spectate=true${nameParam}${authParam}`;
 
     state.ws = new WebSocket(wsUrl);
 
     state.ws.onopen = () => {
+        // The server joins us as a spectator because of the spectate parameter
         console.log("WebSocket connected");
 
-        // Send join as spectator message
-        const message: StreamClientMessage = {
-            JoinAsSpectator: {
-                room_id: roomId,
-                player_name: state.discordUser?.username ?? "Guest",
-                discord_user_id: state.discordUser?.id ?? null,
-                discord_avatar: state.discordUser?.avatar
-                    ? `https://cdn.discordapp.com/avatars/${state.discordUser.id}/${state.discordUser.avatar}.png`
-                    : null,
-                video_frame_queue_size: 4,
-                audio_sample_queue_size: 4,
-            },
-        };
-        sendWsMessage(message);
+        if (discordAccessToken) {
+            sendWsMessage({ DiscordAuth: { access_token: discordAccessToken } });
+        }
     };
 
     state.ws.onmessage = (event) => {
@@ -264,6 +269,19 @@ function handleServerMessage(message: StreamServerMessage): void {
         hideLoading();
 
         console.log("Joined as spectator:", room);
+    } else if (typeof message === "object" && "QueuePosition" in message) {
+        const { position } = message.QueuePosition;
+        updateLoadingState(`Spectator limit reached, waiting for a free spot (position ${position} in queue)...`);
+    } else if (typeof message === "object" && "IdleWarning" in message) {
+        const { seconds_remaining } = message.IdleWarning;
+        console.warn(`Idle for too long, disconnecting in ${seconds_remaining} seconds`);
+
+        // Any interaction with the activity keeps watching
+        const keepAlive = () => sendWsMessage("KeepAlive");
+        document.addEventListener("pointerdown", keepAlive, { once: true });
+        document.addEventListener("keydown", keepAlive, { once: true });
+    } else if (typeof message === "object" && "RoomJoinFailed" in message) {
+        updateLoadingState(`Failed to join: ${message.RoomJoinFailed.reason}`);
     } else if (typeof message === "object" && "RoomJoined" in message) {
         const { room, player_slot } = message.RoomJoined;
         state.isConnected = true;
@@ -302,6 +320,11 @@ function handleServerMessage(message: StreamServerMessage): void {
         disableInput();
 
         console.log("Demoted to spectator");
+    } else if (typeof message === "object" && "VideoModeChanged" in message) {
+        state.videoMode = message.VideoModeChanged.mode;
+        updateUI();
+
+        console.log("Video mode changed:", state.videoMode);
     } else if (typeof message === "object" && "PlayerSlotRequestResult" in message) {
         const { granted, player_slot, reason } = message.PlayerSlotRequestResult;
         if (granted && player_slot) {
@@ -322,6 +345,10 @@ function handleServerMessage(message: StreamServerMessage): void {
         state.participants = room.participants;
         updateParticipantsList();
     } else if (typeof message === "object" && "ConnectionComplete" in message) {
+        if (message.ConnectionComplete.epoch < state.connectionEpoch) {
+            return;
+        }
+        state.connectionEpoch = message.ConnectionComplete.epoch;
         state.capabilities = message.ConnectionComplete.capabilities;
         console.log("Stream connection complete:", message.ConnectionComplete);
     } else if (message === "RoomClosed") {
@@ -446,6 +473,19 @@ function releasePlayerSlot(): void {
     sendWsMessage("ReleasePlayerSlot");
 }
 
+/**
+ * Switch between the full stream and only the keyframes, spectators only
+ */
+function toggleVideoMode(): void {
+    if (!state.ws || state.role !== "Spectator") return;
+
+    sendWsMessage({
+        SetVideoMode: {
+            mode: state.videoMode === "Full" ? "Thumbnail" : "Full",
+        },
+    });
+}
+
 /**
  * Update the UI based on current state
  */
@@ -459,6 +499,10 @@ function updateUI(): void {
         elements.leaveBtn.style.display = "flex";
     }
 
+    // Players always get the full stream
+    elements.videoModeBtn.style.display = state.role === "Spectator" ? "flex" : "none";
+    elements.videoModeBtn.textContent = state.videoMode === "Thumbnail" ? "Full Video" : "Save Data";
+
     // Update participant counts
     updateParticipantsList();
 }
@@ -554,6 +598,7 @@ document.addEventListener("DOMContentLoaded", () => {
     elements.leaveBtn.addEventListener("click", releasePlayerSlot);
     elements.participantsBtn.addEventListener("click", toggleParticipantsPanel);
     elements.fullscreenBtn.addEventListener("click", toggleFullscreen);
+    elements.videoModeBtn.addEventListener("click", toggleVideoMode);
 
     // Handle fullscreen changes
     document.addEventListener("fullscreenchange", () => {
@@ -1,5 +1,5 @@
 import "./polyfill/index.js"
-import { Api, getApi, apiPostHost, FetchError, apiLogout, apiGetUser, tryLogin, apiGetHost } from "./api.js";
+import { Api, getApi, apiPostHost, FetchError, apiLogout, apiGetUser, tryLogin, apiGetHost, apiGetUserSettings } from "./api.js";
 import { AddHostModal } from "./component/host/add_modal.js";
 import { HostList } from "./component/host/list.js";
 import { Component, ComponentEvent } from "./component/index.js";
@@ -12,6 +12,7 @@ import { App, DetailedUser } from "./api_bindings.js";
 import { getLocalStreamSettings, setLocalStreamSettings, StreamSettingsComponent } from "./component/settings_menu.js";
 import { setTouchContextMenuEnabled } from "./polyfill/ios_right_click.js";
 import { buildUrl } from "./config_.js";
+import { setUserDefaultSettings } from "./default_settings.js";
 import { setStyle as setPageStyle } from "./styles/index.js";
 
 async function startApp() {
@@ -19,6 +20,13 @@ async function startApp() {
 
     const api = await getApi()
 
+    try {
+        const userSettings = await apiGetUserSettings(api, { user_id: null })
+        setUserDefaultSettings(userSettings.settings)
+    } catch (e) {
+        console.warn("failed to load the default settings of the user", e)
+    }
+
     const rootElement = document.getElementById("root");
     if (rootElement == null) {
         showErrorPopup("couldn't find root element", true)
@@ -143,6 +151,7 @@ class MainApp implements Component {
         // Host list
         this.hostList = new HostList(api)
         this.hostList.addHostOpenListener(this.onHostOpen.bind(this))
+        this.hostList.addHostDisplayModeListener(this.onHostDisplayMode.bind(this))
 
         // Settings Button
         this.settingsButton.classList.add("open-settings")
@@ -217,6 +226,15 @@ class MainApp implements Component {
         this.setCurrentDisplay("games", { hostId })
     }
 
+    private onHostDisplayMode(event: ComponentEvent<Host>) {
+        const displayMode = event.component.getDisplayMode()
+        if (!displayMode) {
+            return
+        }
+
+        this.settings.setVideoSize(displayMode.width, displayMode.height, displayMode.refresh_rate)
+    }
+
     private onSettingsChange() {
         const newSettings = this.settings.getStreamSettings()
 
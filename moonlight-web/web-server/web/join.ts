@@ -27,6 +27,8 @@ async function startApp() {
 
     const roomId = queryParams.get("room")
     const playerName = queryParams.get("name")
+    // Signed by the server, required to join if the server doesn't allow joining with just the room id
+    const invite = queryParams.get("invite")
 
     if (roomId == null) {
         await showMessage("No Room ID found. Please use a valid guest link.")
@@ -45,7 +47,7 @@ async function startApp() {
     }
 
     // Start and Mount App
-    const app = new GuestViewerApp(api, roomId, playerName)
+    const app = new GuestViewerApp(api, roomId, playerName, invite)
     app.mount(rootElement)
 }
 
@@ -76,7 +78,7 @@ class GuestViewerApp implements Component {
     private toggleFullscreenWithKeybind: boolean
     private hasShownFullscreenEscapeWarning = false
 
-    constructor(api: Api, roomId: string, playerName: string | null) {
+    constructor(api: Api, roomId: string, playerName: string | null, invite: string | null) {
         this.api = api
 
         // Configure sidebar
@@ -107,7 +109,7 @@ class GuestViewerApp implements Component {
 
         this.previousMouseMode = this.inputConfig.mouseMode
         this.toggleFullscreenWithKeybind = settings.toggleFullscreenWithKeybind
-        this.startGuestStream(roomId, playerName, settings, [browserWidth, browserHeight])
+        this.startGuestStream(roomId, playerName, invite, settings, [browserWidth, browserHeight])
 
         this.settings = settings
 
@@ -159,10 +161,10 @@ class GuestViewerApp implements Component {
         return new DOMRect(0, 0, window.innerWidth, window.innerHeight)
     }
 
-    startGuestStream(roomId: string, playerName: string | null, settings: Settings, viewerScreenSize: [number, number]) {
+    startGuestStream(roomId: string, playerName: string | null, invite: string | null, settings: Settings, viewerScreenSize: [number, number]) {
         document.title = `Joining Room: ${roomId}`
 
-        const stream = new GuestStream(this.api, roomId, playerName, settings, viewerScreenSize)
+        const stream = new GuestStream(this.api, roomId, playerName, invite, settings, viewerScreenSize)
 
         stream.addInfoListener(this.onInfo.bind(this))
 
@@ -188,12 +190,24 @@ class GuestViewerApp implements Component {
             const playerSlot = this.stream?.getPlayerSlot() ?? 0
             this.sidebar.updateRoomInfo(room.room_id, playerSlot, room.players.length, room.max_players, false)
         } else if (data.type == "roomJoinFailed") {
-            await showMessage(`Failed to join room: ${data.reason}`)
+            if (data.kind == "Full") {
+                await showMessage("The room is full, try again once a player left")
+            } else {
+                await showMessage(`Failed to join room: ${data.reason}`)
+            }
         } else if (data.type == "roomClosed") {
             await showMessage("Room closed by host")
             this.sidebar.hideRoomSection()
         } else if (data.type == "guestsKeyboardMouseEnabled") {
             this.sidebar.updateGuestsKeyboardMouseEnabled(data.enabled)
+        } else if (data.type == "streamPaused") {
+            if (data.paused) {
+                showErrorPopup("The host paused the stream")
+            }
+        } else if (data.type == "inputPermissionChanged") {
+            if (!data.keyboardMouse) {
+                showErrorPopup("Input disabled by the host, your keyboard and mouse are ignored")
+            }
         } else if (data.type == "addDebugLine") {
             if (data.additional?.type === "fatal" || data.additional?.type === "fatalDescription") {
                 showErrorPopup(data.line, data.additional?.type === "fatal")
@@ -1,5 +1,5 @@
 import "./polyfill/index.js"
-import { Api, getApi } from "./api.js";
+import { Api, apiCreateInvite, getApi } from "./api.js";
 import { Component } from "./component/index.js";
 import { showErrorPopup } from "./component/error.js";
 import { InfoEvent, Stream } from "./stream/index.js"
@@ -8,7 +8,7 @@ import { getSidebarRoot, setSidebar, setSidebarExtended, setSidebarStyle, Sideba
 import { defaultStreamInputConfig, MouseMode, ScreenKeyboardSetVisibleEvent, StreamInputConfig } from "./stream/input.js";
 import { defaultSettings, getLocalStreamSettings, Settings } from "./component/settings_menu.js";
 import { SelectComponent } from "./component/input.js";
-import { LogMessageType, StreamCapabilities, StreamKeys } from "./api_bindings.js";
+import { ControllerMode, LogMessageType, PlayerSlot, QualityLevel, RoomEventKind, StreamCapabilities, StreamKeys } from "./api_bindings.js";
 import { ScreenKeyboard, TextEvent } from "./screen_keyboard.js";
 import { FormModal } from "./component/modal/form.js";
 import { streamStatsToText } from "./stream/stats.js";
@@ -27,14 +27,16 @@ async function startApp() {
 
     const hostIdStr = queryParams.get("hostId")
     const appIdStr = queryParams.get("appId")
-    if (hostIdStr == null || appIdStr == null) {
+    // The app name is resolved by the server, so links keep working when the app ids of the host change
+    const appName = queryParams.get("appName")
+    if (hostIdStr == null || (appIdStr == null && appName == null)) {
         await showMessage("No Host or no App Id found")
 
         window.close()
         return
     }
     const hostId = Number.parseInt(hostIdStr)
-    const appId = Number.parseInt(appIdStr)
+    const appId = appIdStr != null ? Number.parseInt(appIdStr) : null
 
     // event propagation on overlays
     const sidebarRoot = getSidebarRoot()
@@ -48,7 +50,7 @@ async function startApp() {
     }
 
     // Start and Mount App
-    const app = new ViewerApp(api, hostId, appId)
+    const app = new ViewerApp(api, hostId, appId, appName)
     app.mount(rootElement)
 }
 
@@ -63,6 +65,30 @@ window.requestAnimationFrame(() => {
 
 startApp()
 
+const TOAST_DURATION_MS = 4000
+
+function roomEventText(kind: RoomEventKind, name: string | null, slot: PlayerSlot | null): string {
+    const who = name ?? (slot != null ? `Player ${slot + 1}` : "A spectator")
+
+    if (kind == "Joined") {
+        return `${who} joined`
+    } else if (kind == "Left") {
+        return `${who} left`
+    } else if (kind == "Promoted") {
+        return `${who} is now a player`
+    } else {
+        return `The permissions of ${who} changed`
+    }
+}
+
+// The number of bars shown by the quality indicator
+const QUALITY_LEVEL_BARS: Record<QualityLevel, number> = {
+    Poor: 1,
+    Fair: 2,
+    Good: 3,
+    Excellent: 4,
+}
+
 class ViewerApp implements Component {
     private api: Api
 
@@ -71,6 +97,8 @@ class ViewerApp implements Component {
     private div = document.createElement("div")
 
     private statsDiv = document.createElement("div")
+    private toastDiv = document.createElement("div")
+    private qualityDiv = document.createElement("div")
     private stream: Stream | null = null
 
     private settings: Settings
@@ -80,7 +108,7 @@ class ViewerApp implements Component {
     private toggleFullscreenWithKeybind: boolean
     private hasShownFullscreenEscapeWarning = false
 
-    constructor(api: Api, hostId: number, appId: number) {
+    constructor(api: Api, hostId: number, appId: number | null, appName: string | null) {
         this.api = api
 
         // Configure sidebar
@@ -105,6 +133,20 @@ class ViewerApp implements Component {
         }, 100)
         this.div.appendChild(this.statsDiv)
 
+        // Room events are shown over the stream, e.g. while in fullscreen
+        this.toastDiv.classList.add("toast-container")
+        this.div.appendChild(this.toastDiv)
+
+        // Shown once the streamer reported the quality of the connection
+        this.qualityDiv.hidden = true
+        this.qualityDiv.classList.add("quality-indicator")
+        for (let i = 0; i < QUALITY_LEVEL_BARS.Excellent; i++) {
+            const bar = document.createElement("span")
+            bar.classList.add("quality-bar")
+            this.qualityDiv.appendChild(bar)
+        }
+        this.div.appendChild(this.qualityDiv)
+
         // Configure stream
         const settings = getLocalStreamSettings() ?? defaultSettings()
 
@@ -113,7 +155,7 @@ class ViewerApp implements Component {
 
         this.previousMouseMode = this.inputConfig.mouseMode
         this.toggleFullscreenWithKeybind = settings.toggleFullscreenWithKeybind
-        this.startStream(hostId, appId, settings, [browserWidth, browserHeight])
+        this.startStream(hostId, appId, appName, settings, [browserWidth, browserHeight])
 
         this.settings = settings
 
@@ -158,12 +200,12 @@ class ViewerApp implements Component {
         element.addEventListener("touchmove", this.onTouchMove.bind(this), { passive: false })
     }
 
-    private async startStream(hostId: number, appId: number, settings: Settings, browserSize: [number, number]) {
+    private async startStream(hostId: number, appId: number | null, appName: string | null, settings: Settings, browserSize: [number, number]) {
         setSidebarStyle({
             edge: settings.sidebarEdge,
         })
 
-        this.stream = new Stream(this.api, hostId, appId, settings, browserSize)
+        this.stream = new Stream(this.api, hostId, appId, appName, settings, browserSize)
 
         // Add app info listener
         this.stream.addInfoListener(this.onInfo.bind(this))
@@ -196,19 +238,60 @@ class ViewerApp implements Component {
             const playerSlot = data.playerSlot
             const isHost = playerSlot === 0
             this.sidebar.updateRoomInfo(room.room_id, playerSlot, room.players.length, room.max_players, isHost)
+            this.sidebar.updateControllerMode(room.controller_mode)
+            this.sidebar.updateMultipleGamepadsPerPeer(room.allow_multiple_gamepads_per_peer)
         } else if (data.type == "roomUpdated") {
             const room = data.room
             const stream = this.stream
             const playerSlot = stream?.getPlayerSlot() ?? 0
             const isHost = stream?.isHost() ?? false
             this.sidebar.updateRoomInfo(room.room_id, playerSlot, room.players.length, room.max_players, isHost)
+            this.sidebar.updateControllerMode(room.controller_mode)
+            this.sidebar.updateMultipleGamepadsPerPeer(room.allow_multiple_gamepads_per_peer)
         } else if (data.type == "guestsKeyboardMouseEnabled") {
             this.sidebar.updateGuestsKeyboardMouseEnabled(data.enabled)
         } else if (data.type == "roomClosed") {
             this.sidebar.hideRoomSection()
+        } else if (data.type == "roomEvent") {
+            this.showToast(roomEventText(data.kind, data.name, data.slot))
+        } else if (data.type == "streamPaused") {
+            this.sidebar.updateStreamPaused(data.paused)
+            this.showToast(data.paused ? "The stream is paused" : "The stream was resumed")
+        } else if (data.type == "connectionQuality") {
+            this.showQuality(data.level, data.rttMs, data.lossPct)
         }
     }
 
+    private showQuality(level: QualityLevel, rttMs: number | null, lossPct: number) {
+        this.qualityDiv.hidden = false
+        this.qualityDiv.dataset.level = level
+
+        const bars = QUALITY_LEVEL_BARS[level]
+        this.qualityDiv.childNodes.forEach((bar, index) => {
+            (bar as HTMLElement).classList.toggle("active", index < bars)
+        })
+
+        const rtt = rttMs != null ? `${rttMs.toFixed(0)} ms` : "unknown"
+        this.qualityDiv.title = `Connection: ${level}, RTT: ${rtt}, Lost frames: ${lossPct.toFixed(1)}%`
+    }
+
+    private showToast(message: string) {
+        const toast = document.createElement("div")
+        toast.classList.add("toast")
+
+        const text = document.createElement("span")
+        text.classList.add("toast-message")
+        text.innerText = message
+        toast.appendChild(text)
+
+        this.toastDiv.appendChild(toast)
+
+        setTimeout(() => {
+            toast.classList.add("exiting")
+            toast.addEventListener("animationend", () => toast.remove())
+        }, TOAST_DURATION_MS)
+    }
+
     private focusInput() {
         if (this.stream?.getInput().getCurrentPredictedTouchAction() != "screenKeyboard" && !this.sidebar.getScreenKeyboard().isVisible()) {
             const inputElement = document.getElementById("input") as HTMLDivElement
@@ -362,9 +445,11 @@ class ViewerApp implements Component {
     }
     onGamepadAdd(gamepad: Gamepad) {
         this.stream?.getInput().onGamepadConnect(gamepad)
+        this.stream?.syncLocalGamepadCount()
     }
     onGamepadDisconnect(event: GamepadEvent) {
         this.stream?.getInput().onGamepadDisconnect(event)
+        this.stream?.syncLocalGamepadCount()
     }
     onGamepadUpdate() {
         this.stream?.getInput().onGamepadUpdate()
@@ -527,6 +612,9 @@ class ViewerApp implements Component {
     getStream(): Stream | null {
         return this.stream
     }
+    getApi(): Api {
+        return this.api
+    }
 }
 
 class ConnectionInfoModal implements Modal<void> {
@@ -538,6 +626,8 @@ class ConnectionInfoModal implements Modal<void> {
     private textTy: LogMessageType | null = null
     private text = document.createElement("p")
 
+    private retryButton = document.createElement("button")
+
     private debugDetailButton = document.createElement("button")
     private debugDetail = "" // We store this seperate because line breaks don't work when the element is not mounted on the dom
     private debugDetailDisplay = document.createElement("div")
@@ -554,6 +644,10 @@ class ConnectionInfoModal implements Modal<void> {
 
         this.debugDetailDisplay.classList.add("textlike")
         this.debugDetailDisplay.classList.add("modal-video-connect-debug")
+
+        // Only shown when starting again might work
+        this.retryButton.innerText = "Retry"
+        this.retryButton.addEventListener("click", () => window.location.reload())
     }
 
     private onDebugDetailClick() {
@@ -609,6 +703,8 @@ class ConnectionInfoModal implements Modal<void> {
             const text = `Server: ${data.message}`
             this.text.innerText = text
             this.debugLog(text)
+        } else if (data.type == "stageTimeout") {
+            this.root.insertBefore(this.retryButton, this.debugDetailButton)
         }
     }
 
@@ -654,6 +750,14 @@ class ViewerSidebar implements Component, Sidebar {
     private copyGuestUrlButton = document.createElement("button")
     private guestKeyboardMouseToggle = document.createElement("button")
     private guestsKeyboardMouseEnabled = false
+    private controllerModeToggle = document.createElement("button")
+    private controllerMode: ControllerMode = "PerPlayer"
+    private multipleGamepadsToggle = document.createElement("button")
+    private multipleGamepadsPerPeer = false
+    private roomEventsToggle = document.createElement("button")
+    private roomEventsEnabled = true
+    private pauseToggle = document.createElement("button")
+    private streamPaused = false
     private currentRoomId: string | null = null
 
     constructor(app: ViewerApp) {
@@ -772,13 +876,50 @@ class ViewerSidebar implements Component, Sidebar {
         })
         this.roomSection.appendChild(this.guestKeyboardMouseToggle)
 
+        // All players control the same gamepad, e.g. for party games
+        this.controllerModeToggle.innerText = "Share Controller"
+        this.controllerModeToggle.style.display = "none" // Only visible for host
+        this.controllerModeToggle.addEventListener("click", () => {
+            const newMode = this.controllerMode == "Shared" ? "PerPlayer" : "Shared"
+            this.app.getStream()?.setControllerMode(newMode)
+        })
+        this.roomSection.appendChild(this.controllerModeToggle)
+
+        // Couch co-op: every gamepad of a browser takes its own player slot
+        this.multipleGamepadsToggle.innerText = "Allow Multiple Gamepads"
+        this.multipleGamepadsToggle.style.display = "none" // Only visible for host
+        this.multipleGamepadsToggle.addEventListener("click", () => {
+            this.app.getStream()?.setMultipleGamepadsPerPeer(!this.multipleGamepadsPerPeer)
+        })
+        this.roomSection.appendChild(this.multipleGamepadsToggle)
+
+        // Notifications when someone joins or leaves, shown over the stream
+        this.roomEventsToggle.innerText = "Mute Join Notifications"
+        this.roomEventsToggle.style.display = "none" // Only visible for host
+        this.roomEventsToggle.addEventListener("click", () => {
+            this.roomEventsEnabled = !this.roomEventsEnabled
+            this.app.getStream()?.setRoomEventsEnabled(this.roomEventsEnabled)
+            this.roomEventsToggle.innerText = this.roomEventsEnabled
+                ? "Mute Join Notifications"
+                : "Unmute Join Notifications"
+        })
+        this.roomSection.appendChild(this.roomEventsToggle)
+
+        // Short breaks without stopping the stream, the players see a still image
+        this.pauseToggle.innerText = "Pause Stream"
+        this.pauseToggle.style.display = "none" // Only visible for host
+        this.pauseToggle.addEventListener("click", () => {
+            this.app.getStream()?.setStreamPaused(!this.streamPaused)
+        })
+        this.roomSection.appendChild(this.pauseToggle)
+
         // Copy guest URL button (host only)
         this.copyGuestUrlButton.innerText = "Copy Guest URL"
         this.copyGuestUrlButton.style.display = "none" // Only visible for host
         this.copyGuestUrlButton.style.marginTop = "8px"
         this.copyGuestUrlButton.addEventListener("click", async () => {
             if (this.currentRoomId) {
-                const guestUrl = this.buildGuestUrl(this.currentRoomId)
+                const guestUrl = await this.buildGuestUrl(this.currentRoomId)
                 try {
                     await navigator.clipboard.writeText(guestUrl)
                     this.copyGuestUrlButton.innerText = "Copied!"
@@ -794,7 +935,15 @@ class ViewerSidebar implements Component, Sidebar {
         this.roomSection.appendChild(this.copyGuestUrlButton)
     }
 
-    private buildGuestUrl(roomId: string): string {
+    private async buildGuestUrl(roomId: string): Promise<string> {
+        // Signed invites expire and can be revoked, unlike the room id
+        try {
+            const invite = await apiCreateInvite(this.app.getApi(), { room_id: roomId, valid_for_secs: null })
+            return window.location.origin + invite.url
+        } catch (err) {
+            console.warn("Failed to create an invite, sharing the room id instead", err)
+        }
+
         const baseUrl = window.location.origin + window.location.pathname.replace(/\/[^\/]*$/, '')
         return `${baseUrl}/join.html?room=${encodeURIComponent(roomId)}`
     }
@@ -813,6 +962,10 @@ class ViewerSidebar implements Component, Sidebar {
 
         // Show/hide host-only controls
         this.guestKeyboardMouseToggle.style.display = isHost ? "block" : "none"
+        this.controllerModeToggle.style.display = isHost ? "block" : "none"
+        this.multipleGamepadsToggle.style.display = isHost ? "block" : "none"
+        this.roomEventsToggle.style.display = isHost ? "block" : "none"
+        this.pauseToggle.style.display = isHost ? "block" : "none"
         this.copyGuestUrlButton.style.display = isHost ? "block" : "none"
     }
 
@@ -823,6 +976,25 @@ class ViewerSidebar implements Component, Sidebar {
             : "Enable Guest KB/Mouse"
     }
 
+    updateStreamPaused(paused: boolean) {
+        this.streamPaused = paused
+        this.pauseToggle.innerText = paused ? "Resume Stream" : "Pause Stream"
+    }
+
+    updateControllerMode(mode: ControllerMode) {
+        this.controllerMode = mode
+        this.controllerModeToggle.innerText = mode == "Shared"
+            ? "Separate Controllers"
+            : "Share Controller"
+    }
+
+    updateMultipleGamepadsPerPeer(enabled: boolean) {
+        this.multipleGamepadsPerPeer = enabled
+        this.multipleGamepadsToggle.innerText = enabled
+            ? "Single Gamepad Per Player"
+            : "Allow Multiple Gamepads"
+    }
+
     hideRoomSection() {
         this.roomSection.style.display = "none"
     }
@@ -1,5 +1,5 @@
 import { Api } from "../api.js"
-import { PlayerSlot, RoomInfo, StreamCapabilities, StreamClientMessage, StreamServerMessage, TransportChannelId } from "../api_bindings.js"
+import { GeneralServerMessage, PlayerSlot, RoomInfo, StreamCapabilities, StreamClientMessage, StreamServerMessage, TransportChannelId } from "../api_bindings.js"
 import { showErrorPopup } from "../component/error.js"
 import { Component } from "../component/index.js"
 import { Settings } from "../component/settings_menu.js"
@@ -48,6 +48,8 @@ export class GuestStream implements Component {
 
     private ws: WebSocket
     private iceServers: Array<RTCIceServer> | null = null
+    // The newest start of the stream, older setup was superseded
+    private connectionEpoch = 0
 
     private videoRenderer: VideoRenderer | null = null
     private audioPlayer: AudioPlayer | null = null
@@ -62,8 +64,9 @@ export class GuestStream implements Component {
     private roomInfo: RoomInfo | null = null
     private playerSlot: PlayerSlot | null = null
     private guestsKeyboardMouseEnabled: boolean = false
+    private idleKeepAlive: AbortController | null = null
 
-    constructor(api: Api, roomId: string, playerName: string | null, settings: Settings, viewerScreenSize: [number, number]) {
+    constructor(api: Api, roomId: string, playerName: string | null, invite: string | null, settings: Settings, viewerScreenSize: [number, number]) {
         this.logger.addInfoListener((info, type) => {
             this.debugLog(info, { type: type ?? undefined })
         })
@@ -77,7 +80,8 @@ export class GuestStream implements Component {
         // Configure web socket - connect to guest endpoint
         const wsApiHost = api.host_url.replace(/^http(s)?:/, "ws$1:")
         const nameParam = playerName ? `&player_name=${encodeURIComponent(playerName)}` : ""
-        this.ws = new WebSocket(`${wsApiHost}/guest/stream?room_id=${encodeURIComponent(roomId)}${nameParam}`)
+        const inviteParam = invite ? `&invite=${encodeURIComponent(invite)}` : ""
+        this.ws = new WebSocket(`${wsApiHost}/guest/stream?room_id=${encodeURIComponent(roomId)}${nameParam}${inviteParam}`)
         this.ws.addEventListener("error", this.onError.bind(this))
         this.ws.addEventListener("open", this.onWsOpen.bind(this))
         this.ws.addEventListener("close", this.onWsClose.bind(this))
@@ -113,6 +117,11 @@ export class GuestStream implements Component {
             })
             this.eventTarget.dispatchEvent(event)
         } else if (typeof message === "object" && "ConnectionComplete" in message) {
+            if (message.ConnectionComplete.epoch < this.connectionEpoch) {
+                return
+            }
+            this.connectionEpoch = message.ConnectionComplete.epoch
+
             const capabilities = message.ConnectionComplete.capabilities
             const formatRaw = message.ConnectionComplete.format
             const width = message.ConnectionComplete.width
@@ -139,6 +148,8 @@ export class GuestStream implements Component {
 
             this.input.onStreamStart(capabilities, [width, height])
             this.stats.setVideoInfo(format ?? "Unknown", width, height, fps)
+            this.stats.setAvailableStats(message.ConnectionComplete.available_stats)
+            this.stats.setStageTimings(message.ConnectionComplete.stage_timings)
 
             await Promise.all([
                 this.videoRenderer?.setup({
@@ -157,7 +168,8 @@ export class GuestStream implements Component {
                 })
             ])
         } else if (typeof message === "object" && "ConnectionTerminated" in message) {
-            this.debugLog(`ConnectionTerminated with code ${message.ConnectionTerminated.error_code}`, { type: "fatalDescription" })
+            const { error_code: code, hint } = message.ConnectionTerminated
+            this.debugLog(`${hint} (error code ${code})`, { type: "fatalDescription" })
         } else if (typeof message === "object" && "Setup" in message) {
             this.iceServers = message.Setup.ice_servers
             this.debugLog(`Received ICE servers, starting connection`)
@@ -171,6 +183,9 @@ export class GuestStream implements Component {
             })
             this.eventTarget.dispatchEvent(event)
         } else if (typeof message === "object" && "RoomUpdated" in message) {
+            if (this.roomInfo && message.RoomUpdated.room.revision < this.roomInfo.revision) {
+                return
+            }
             this.roomInfo = message.RoomUpdated.room
             this.debugLog(`Room updated: ${this.roomInfo.players.length} players connected`)
             const event: InfoEvent = new CustomEvent("stream-info", {
@@ -180,7 +195,7 @@ export class GuestStream implements Component {
         } else if (typeof message === "object" && "RoomJoinFailed" in message) {
             this.debugLog(`Failed to join room: ${message.RoomJoinFailed.reason}`, { type: "fatal" })
             const event: InfoEvent = new CustomEvent("stream-info", {
-                detail: { type: "roomJoinFailed", reason: message.RoomJoinFailed.reason }
+                detail: { type: "roomJoinFailed", kind: message.RoomJoinFailed.kind, reason: message.RoomJoinFailed.reason }
             })
             this.eventTarget.dispatchEvent(event)
         } else if (typeof message === "object" && "PlayerLeft" in message) {
@@ -195,6 +210,13 @@ export class GuestStream implements Component {
                 detail: { type: "roomClosed" }
             })
             this.eventTarget.dispatchEvent(event)
+        } else if (typeof message === "object" && "StreamPaused" in message) {
+            const paused = message.StreamPaused.paused
+            this.debugLog(`The host ${paused ? "paused" : "resumed"} the stream`)
+            const event: InfoEvent = new CustomEvent("stream-info", {
+                detail: { type: "streamPaused", paused }
+            })
+            this.eventTarget.dispatchEvent(event)
         } else if (typeof message === "object" && "GuestsKeyboardMouseEnabled" in message) {
             this.guestsKeyboardMouseEnabled = message.GuestsKeyboardMouseEnabled.enabled
             this.debugLog(`Guests keyboard/mouse ${this.guestsKeyboardMouseEnabled ? "enabled" : "disabled"}`)
@@ -210,7 +232,25 @@ export class GuestStream implements Component {
             } else {
                 this.debugLog(`Received WebRTC message but transport is currently ${this.transport?.implementationName}`)
             }
+        } else if (typeof message === "object" && "IdleWarning" in message) {
+            this.onIdleWarning(message.IdleWarning.seconds_remaining)
+        }
+    }
+
+    private onIdleWarning(secondsRemaining: number) {
+        this.debugLog(`You were idle for too long and will be disconnected in ${secondsRemaining}s, interact with the page to keep watching`, { type: "informError" })
+
+        // Any interaction with the page keeps watching, the listeners of an earlier warning are replaced
+        this.idleKeepAlive?.abort()
+        const idleKeepAlive = new AbortController()
+        this.idleKeepAlive = idleKeepAlive
+
+        const keepAlive = () => {
+            idleKeepAlive.abort()
+            this.sendWsMessage("KeepAlive")
         }
+        document.addEventListener("pointerdown", keepAlive, { signal: idleKeepAlive.signal })
+        document.addEventListener("keydown", keepAlive, { signal: idleKeepAlive.signal })
     }
 
     private async startConnection() {
@@ -241,6 +281,33 @@ export class GuestStream implements Component {
 
         this.input.setTransport(this.transport)
         this.stats.setTransport(this.transport)
+
+        const general = this.transport.getChannel(TransportChannelId.GENERAL)
+        if (general.type == "data") {
+            general.addReceiveListener(this.onGeneralData.bind(this))
+        }
+    }
+
+    private onGeneralData(data: ArrayBuffer) {
+        const buffer = BIG_BUFFER
+        buffer.reset()
+        buffer.putU8Array(new Uint8Array(data))
+        buffer.flip()
+
+        const textLength = buffer.getU16()
+        const message: GeneralServerMessage = JSON.parse(buffer.getUtf8Raw(textLength))
+
+        if ("InputPermissionChanged" in message) {
+            const keyboardMouse = message.InputPermissionChanged.keyboard_mouse
+            this.debugLog(`Keyboard/mouse ${keyboardMouse ? "enabled" : "disabled"} by the host`)
+
+            const event: InfoEvent = new CustomEvent("stream-info", {
+                detail: { type: "inputPermissionChanged", keyboardMouse }
+            })
+            this.eventTarget.dispatchEvent(event)
+        } else if ("MouseModeChanged" in message) {
+            this.input.onMouseModeChanged(message.MouseModeChanged.relative)
+        }
     }
 
     private async tryWebRTCTransport(): Promise<TransportShutdown> {
@@ -361,6 +428,9 @@ export class GuestStream implements Component {
             video.addTrackListener((track) => {
                 videoRenderer.setTrack(track)
             })
+            videoRenderer.setFrameRenderedListener((rtpTimestamp, renderTime) => {
+                this.stats.onFrameRendered(rtpTimestamp, renderTime)
+            })
 
             this.videoRenderer = videoRenderer
         } else if (video.type == "data") {
@@ -374,8 +444,12 @@ export class GuestStream implements Component {
             videoRenderer.mount(this.divElement)
 
             video.addReceiveListener((data) => {
+                this.stats.onVideoPacket(data)
                 videoRenderer.submitPacket(data)
             })
+            videoRenderer.setFrameRenderedListener((timestamp, renderTime) => {
+                this.stats.onDataFrameRendered(timestamp, renderTime)
+            })
 
             this.videoRenderer = videoRenderer
         } else {
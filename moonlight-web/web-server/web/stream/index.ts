@@ -1,5 +1,5 @@
 import { Api } from "../api.js"
-import { App, ConnectionStatus, PlayerSlot, RoomInfo, StreamCapabilities, StreamClientMessage, StreamServerMessage, TransportChannelId } from "../api_bindings.js"
+import { App, ConnectionStatus, ControllerMode, DataChannelOptions, GeneralServerMessage, HostCapabilities, PeerId, PeerPermissions, PlayerSlot, QualityLevel, RoomEventKind, RoomInfo, RoomJoinFailure, StreamCapabilities, StreamClientMessage, StreamServerMessage, TransportChannelId } from "../api_bindings.js"
 import { showErrorPopup } from "../component/error.js"
 import { Component } from "../component/index.js"
 import { Settings } from "../component/settings_menu.js"
@@ -13,7 +13,7 @@ import { StreamStats } from "./stats.js"
 import { Transport, TransportShutdown } from "./transport/index.js"
 import { WebSocketTransport } from "./transport/web_socket.js"
 import { WebRTCTransport } from "./transport/webrtc.js"
-import { allVideoCodecs, andVideoCodecs, createSupportedVideoFormatsBits, emptyVideoCodecs, getSelectedVideoCodec, hasAnyCodec, VideoCodecSupport } from "./video.js"
+import { allVideoCodecs, andVideoCodecs, createSupportedVideoFormatsBits, emptyVideoCodecs, getSelectedVideoCodec, hasAnyCodec, VideoCodecSupport, videoFormatNames } from "./video.js"
 import { VideoRenderer } from "./video/index.js"
 import { buildVideoPipeline, VideoPipelineOptions } from "./video/pipeline.js"
 
@@ -31,10 +31,23 @@ export type InfoEvent = CustomEvent<
     { type: "roomCreated", room: RoomInfo, playerSlot: PlayerSlot } |
     { type: "roomJoined", room: RoomInfo, playerSlot: PlayerSlot } |
     { type: "roomUpdated", room: RoomInfo } |
-    { type: "roomJoinFailed", reason: string } |
+    { type: "roomJoinFailed", kind: RoomJoinFailure, reason: string } |
     { type: "playerLeft", slot: PlayerSlot } |
     { type: "roomClosed" } |
-    { type: "guestsKeyboardMouseEnabled", enabled: boolean }
+    { type: "guestsKeyboardMouseEnabled", enabled: boolean } |
+    { type: "spectatorDelay", seconds: number } |
+    // expiresAt is in milliseconds since the unix epoch
+    { type: "slotInviteCreated", token: string, slot: PlayerSlot, name: string, expiresAt: number } |
+    // currentGame is the app id running on the host, null if unknown
+    { type: "hostBusy", currentGame: number | null } |
+    { type: "appAlreadyRunning", currentGame: number } |
+    // A stage of the stream start got stuck, starting again might work
+    { type: "stageTimeout" } |
+    { type: "roomEvent", kind: RoomEventKind, name: string | null, slot: PlayerSlot | null } |
+    // rttMs is null if the streamer couldn't estimate it yet
+    { type: "connectionQuality", level: QualityLevel, rttMs: number | null, lossPct: number } |
+    { type: "inputPermissionChanged", keyboardMouse: boolean } |
+    { type: "streamPaused", paused: boolean }
 >
 export type InfoEventListener = (event: InfoEvent) => void
 
@@ -90,7 +103,8 @@ export class Stream implements Component {
     private api: Api
 
     private hostId: number
-    private appId: number
+    private appId: number | null
+    private appName: string | null
 
     private settings: Settings
 
@@ -99,6 +113,10 @@ export class Stream implements Component {
 
     private ws: WebSocket
     private iceServers: Array<RTCIceServer> | null = null
+    // The newest start of the stream, older setup was superseded
+    private connectionEpoch = 0
+    private dataChannelOptions: Record<string, DataChannelOptions> = {}
+    private hostCapabilities: HostCapabilities | null = null
 
     private videoRenderer: VideoRenderer | null = null
     private audioPlayer: AudioPlayer | null = null
@@ -110,10 +128,14 @@ export class Stream implements Component {
 
     // Room state
     private roomInfo: RoomInfo | null = null
+    // The gamepad count the room knows about, null if the room doesn't give slots to additional gamepads
+    private localGamepadCount: number | null = null
     private playerSlot: PlayerSlot | null = null
     private guestsKeyboardMouseEnabled: boolean = false
+    private spectatorDelaySeconds: number = 0
+    private idleKeepAlive: AbortController | null = null
 
-    constructor(api: Api, hostId: number, appId: number, settings: Settings, viewerScreenSize: [number, number]) {
+    constructor(api: Api, hostId: number, appId: number | null, appName: string | null, settings: Settings, viewerScreenSize: [number, number]) {
         this.logger.addInfoListener((info, type) => {
             this.debugLog(info, { type: type ?? undefined })
         })
@@ -122,6 +144,7 @@ export class Stream implements Component {
 
         this.hostId = hostId
         this.appId = appId
+        this.appName = appName
 
         this.settings = settings
 
@@ -140,6 +163,7 @@ export class Stream implements Component {
             Init: {
                 host_id: this.hostId,
                 app_id: this.appId,
+                app_name: this.appName,
                 video_frame_queue_size: this.settings.videoFrameQueueSize,
                 audio_sample_queue_size: this.settings.audioSampleQueueSize,
             }
@@ -175,12 +199,23 @@ export class Stream implements Component {
                 type: debugLog.ty ?? undefined
             })
         } else if (typeof message === "object" && "UpdateApp" in message) {
+            const hostCapabilities = message.UpdateApp.host_capabilities
+            if (hostCapabilities) {
+                this.hostCapabilities = hostCapabilities
+                this.debugLog(`Host video codecs: ${createPrettyList(hostCapabilities.video_codecs)}`)
+            }
+
             const event: InfoEvent = new CustomEvent("stream-info", {
                 detail: { type: "app", app: message.UpdateApp.app }
             })
 
             this.eventTarget.dispatchEvent(event)
         } else if (typeof message === "object" && "ConnectionComplete" in message) {
+            if (message.ConnectionComplete.epoch < this.connectionEpoch) {
+                return
+            }
+            this.connectionEpoch = message.ConnectionComplete.epoch
+
             const capabilities = message.ConnectionComplete.capabilities
             const formatRaw = message.ConnectionComplete.format
             const width = message.ConnectionComplete.width
@@ -207,8 +242,11 @@ export class Stream implements Component {
             this.eventTarget.dispatchEvent(event)
 
             this.input.onStreamStart(capabilities, [width, height])
+            this.syncLocalGamepadCount()
 
             this.stats.setVideoInfo(format ?? "Unknown", width, height, fps)
+            this.stats.setAvailableStats(message.ConnectionComplete.available_stats)
+            this.stats.setStageTimings(message.ConnectionComplete.stage_timings)
 
             // we should allow streaming without audio
             if (!this.audioPlayer) {
@@ -232,15 +270,69 @@ export class Stream implements Component {
                 })
             ])
         } else if (typeof message === "object" && "ConnectionTerminated" in message) {
-            const code = message.ConnectionTerminated.error_code
+            const { error_code: code, reason, hint } = message.ConnectionTerminated
+
+            if (reason == "Graceful") {
+                this.debugLog("Stream stopped by the host")
+            } else {
+                this.debugLog(`${hint} (error code ${code})`, { type: "fatalDescription" })
+            }
+
+            if (reason == "StageTimeout") {
+                const event: InfoEvent = new CustomEvent("stream-info", {
+                    detail: { type: "stageTimeout" }
+                })
+                this.eventTarget.dispatchEvent(event)
+            }
+        } else if (typeof message === "object" && "NoCommonVideoCodec" in message) {
+            const { client_video_formats: client, host_video_formats: host } = message.NoCommonVideoCodec
+
+            this.debugLog(
+                `The host can't encode any video codec this browser can decode (browser: ${createPrettyList(videoFormatNames(client))}, host: ${createPrettyList(videoFormatNames(host))})`,
+                { type: "fatalDescription" }
+            )
+        } else if (typeof message === "object" && "HostOffline" in message) {
+            this.debugLog("The host is offline, check if it's running and reachable from the server", { type: "fatalDescription" })
+        } else if (typeof message === "object" && "HostBusy" in message) {
+            const currentGame = message.HostBusy.current_game
 
-            this.debugLog(`ConnectionTerminated with code ${code}`, { type: "fatalDescription" })
+            this.debugLog("The host is already streaming to another session", { type: "fatalDescription" })
+
+            const event: InfoEvent = new CustomEvent("stream-info", {
+                detail: { type: "hostBusy", currentGame }
+            })
+            this.eventTarget.dispatchEvent(event)
+        } else if (typeof message === "object" && "UnsupportedDisplayMode" in message) {
+            const { width, height, fps, supported_modes } = message.UnsupportedDisplayMode
+            const modes = supported_modes.map(mode => `${mode.width}x${mode.height}@${mode.refresh_rate}`)
+
+            this.debugLog(
+                `The host doesn't support ${width}x${height} at ${fps} fps or anything close to it, choose one of these resolutions: ${createPrettyList(modes)}`,
+                { type: "fatalDescription" }
+            )
+        } else if (typeof message === "object" && "ResolutionTooHigh" in message) {
+            const { width, height, max_width, max_height } = message.ResolutionTooHigh
+
+            this.debugLog(
+                `The host can't stream ${width}x${height}, choose a resolution of at most ${max_width}x${max_height}`,
+                { type: "fatalDescription" }
+            )
+        } else if (typeof message === "object" && "AppAlreadyRunning" in message) {
+            const currentGame = message.AppAlreadyRunning.current_game
+
+            this.debugLog(`The host is already running another app (app id ${currentGame}), close it before starting this one`, { type: "fatalDescription" })
+
+            const event: InfoEvent = new CustomEvent("stream-info", {
+                detail: { type: "appAlreadyRunning", currentGame }
+            })
+            this.eventTarget.dispatchEvent(event)
         }
         // -- WebRTC Config
         else if (typeof message === "object" && "Setup" in message) {
             const iceServers = message.Setup.ice_servers
 
             this.iceServers = iceServers
+            this.dataChannelOptions = message.Setup.data_channels as Record<string, DataChannelOptions>
 
             this.debugLog(`Using WebRTC Ice Servers: ${createPrettyList(
                 iceServers.map(server => server.urls).reduce((list, url) => list.concat(url), [])
@@ -268,6 +360,8 @@ export class Stream implements Component {
                 detail: { type: "roomCreated", room: this.roomInfo, playerSlot: this.playerSlot }
             })
             this.eventTarget.dispatchEvent(event)
+
+            this.syncLocalGamepadCount()
         }
         else if (typeof message === "object" && "RoomJoined" in message) {
             this.roomInfo = message.RoomJoined.room
@@ -279,8 +373,14 @@ export class Stream implements Component {
                 detail: { type: "roomJoined", room: this.roomInfo, playerSlot: this.playerSlot }
             })
             this.eventTarget.dispatchEvent(event)
+
+            this.syncLocalGamepadCount()
         }
         else if (typeof message === "object" && "RoomUpdated" in message) {
+            // Updates can overtake each other, the room info we have might be newer
+            if (this.roomInfo && message.RoomUpdated.room.revision < this.roomInfo.revision) {
+                return
+            }
             this.roomInfo = message.RoomUpdated.room
 
             this.debugLog(`Room updated: ${this.roomInfo.players.length} players connected`)
@@ -289,12 +389,14 @@ export class Stream implements Component {
                 detail: { type: "roomUpdated", room: this.roomInfo }
             })
             this.eventTarget.dispatchEvent(event)
+
+            this.syncLocalGamepadCount()
         }
         else if (typeof message === "object" && "RoomJoinFailed" in message) {
             this.debugLog(`Failed to join room: ${message.RoomJoinFailed.reason}`, { type: "fatal" })
 
             const event: InfoEvent = new CustomEvent("stream-info", {
-                detail: { type: "roomJoinFailed", reason: message.RoomJoinFailed.reason }
+                detail: { type: "roomJoinFailed", kind: message.RoomJoinFailed.kind, reason: message.RoomJoinFailed.reason }
             })
             this.eventTarget.dispatchEvent(event)
         }
@@ -326,6 +428,53 @@ export class Stream implements Component {
             })
             this.eventTarget.dispatchEvent(event)
         }
+        else if (typeof message === "object" && "StreamPaused" in message) {
+            const paused = message.StreamPaused.paused
+
+            this.debugLog(`The host ${paused ? "paused" : "resumed"} the stream`)
+
+            const event: InfoEvent = new CustomEvent("stream-info", {
+                detail: { type: "streamPaused", paused }
+            })
+            this.eventTarget.dispatchEvent(event)
+        }
+        else if (typeof message === "object" && "SlotInviteCreated" in message) {
+            const { token, slot, name, expires_at: expiresAt } = message.SlotInviteCreated
+
+            const event: InfoEvent = new CustomEvent("stream-info", {
+                detail: { type: "slotInviteCreated", token, slot, name, expiresAt }
+            })
+            this.eventTarget.dispatchEvent(event)
+        }
+        else if (typeof message === "object" && "SpectatorDelay" in message) {
+            this.spectatorDelaySeconds = message.SpectatorDelay.seconds
+
+            this.debugLog(`Spectator delay set to ${this.spectatorDelaySeconds}s`)
+
+            const event: InfoEvent = new CustomEvent("stream-info", {
+                detail: { type: "spectatorDelay", seconds: this.spectatorDelaySeconds }
+            })
+            this.eventTarget.dispatchEvent(event)
+        }
+        else if (typeof message === "object" && "IdleWarning" in message) {
+            this.onIdleWarning(message.IdleWarning.seconds_remaining)
+        }
+    }
+
+    private onIdleWarning(secondsRemaining: number) {
+        this.debugLog(`You were idle for too long and will be disconnected in ${secondsRemaining}s, interact with the page to keep watching`, { type: "informError" })
+
+        // Any interaction with the page keeps watching, the listeners of an earlier warning are replaced
+        this.idleKeepAlive?.abort()
+        const idleKeepAlive = new AbortController()
+        this.idleKeepAlive = idleKeepAlive
+
+        const keepAlive = () => {
+            idleKeepAlive.abort()
+            this.sendWsMessage("KeepAlive")
+        }
+        document.addEventListener("pointerdown", keepAlive, { signal: idleKeepAlive.signal })
+        document.addEventListener("keydown", keepAlive, { signal: idleKeepAlive.signal })
     }
 
     async startConnection() {
@@ -358,6 +507,47 @@ export class Stream implements Component {
 
         this.input.setTransport(this.transport)
         this.stats.setTransport(this.transport)
+
+        const general = this.transport.getChannel(TransportChannelId.GENERAL)
+        if (general.type == "data") {
+            general.addReceiveListener(this.onGeneralData.bind(this))
+        }
+    }
+
+    private onGeneralData(data: ArrayBuffer) {
+        const buffer = BIG_BUFFER
+        buffer.reset()
+        buffer.putU8Array(new Uint8Array(data))
+        buffer.flip()
+
+        const textLength = buffer.getU16()
+        const message: GeneralServerMessage = JSON.parse(buffer.getUtf8Raw(textLength))
+
+        if ("RoomEvent" in message) {
+            const { kind, name, slot } = message.RoomEvent
+
+            const event: InfoEvent = new CustomEvent("stream-info", {
+                detail: { type: "roomEvent", kind, name, slot }
+            })
+            this.eventTarget.dispatchEvent(event)
+        } else if ("QualityReport" in message) {
+            const { level, rtt_ms, loss_pct } = message.QualityReport
+
+            const event: InfoEvent = new CustomEvent("stream-info", {
+                detail: { type: "connectionQuality", level, rttMs: rtt_ms, lossPct: loss_pct }
+            })
+            this.eventTarget.dispatchEvent(event)
+        } else if ("InputPermissionChanged" in message) {
+            const keyboardMouse = message.InputPermissionChanged.keyboard_mouse
+            this.debugLog(`Keyboard/mouse ${keyboardMouse ? "enabled" : "disabled"} by the host`)
+
+            const event: InfoEvent = new CustomEvent("stream-info", {
+                detail: { type: "inputPermissionChanged", keyboardMouse }
+            })
+            this.eventTarget.dispatchEvent(event)
+        } else if ("MouseModeChanged" in message) {
+            this.input.onMouseModeChanged(message.MouseModeChanged.relative)
+        }
     }
 
     private async tryWebRTCTransport(): Promise<TransportShutdown> {
@@ -372,7 +562,7 @@ export class Stream implements Component {
             return "failednoconnect"
         }
 
-        const transport = new WebRTCTransport(this.logger)
+        const transport = new WebRTCTransport(this.logger, this.dataChannelOptions)
         transport.onsendmessage = (message) => this.sendWsMessage({ WebRtc: message })
 
         transport.initPeer({
@@ -517,6 +707,9 @@ export class Stream implements Component {
             video.addTrackListener((track) => {
                 videoRenderer.setTrack(track)
             })
+            videoRenderer.setFrameRenderedListener((rtpTimestamp, renderTime) => {
+                this.stats.onFrameRendered(rtpTimestamp, renderTime)
+            })
 
             this.videoRenderer = videoRenderer
         } else if (video.type == "data") {
@@ -530,8 +723,12 @@ export class Stream implements Component {
             videoRenderer.mount(this.divElement)
 
             video.addReceiveListener((data) => {
+                this.stats.onVideoPacket(data)
                 videoRenderer.submitPacket(data)
             })
+            videoRenderer.setFrameRenderedListener((timestamp, renderTime) => {
+                this.stats.onDataFrameRendered(timestamp, renderTime)
+            })
 
             this.videoRenderer = videoRenderer
         } else {
@@ -609,11 +806,23 @@ export class Stream implements Component {
                 video_supported_formats: createSupportedVideoFormatsBits(videoCodecSupport),
                 video_colorspace: "Rec709",
                 video_color_range_full: false,
+                keyboard_mode: this.settings.keyboardMode,
+                keyboard_layout: this.settings.keyboardLayout,
+                custom_keyboard_layout: this.settings.keyboardLayoutCustom,
+                key_overrides: this.settings.keyOverrides,
+                allow_keyboard: this.settings.allowKeyboard,
+                allow_mouse: this.settings.allowMouse,
+                allow_gamepad: this.settings.allowGamepad,
+                allow_touch: this.settings.allowTouch,
             }
         }
         this.debugLog(`Starting stream with info: ${JSON.stringify(message)}`)
         this.debugLog(`Stream video codec info: ${JSON.stringify(videoCodecSupport)}`)
 
+        if (this.hostCapabilities && (message.StartStream.video_supported_formats & this.hostCapabilities.supported_video_formats) == 0) {
+            this.debugLog("None of the video codecs of this browser are supported by the host")
+        }
+
         this.sendWsMessage(message)
     }
 
@@ -724,6 +933,187 @@ export class Stream implements Component {
         })
     }
 
+    /**
+     * Host-only: Set whether the host is told when someone joins or leaves the room
+     */
+    setRoomEventsEnabled(enabled: boolean): void {
+        if (!this.isHost()) {
+            console.warn("Only the host can receive room events")
+            return
+        }
+        this.input.sendGeneralMessage({
+            SetRoomEventsEnabled: {
+                enabled
+            }
+        })
+    }
+
+    /**
+     * Host-only: Set which input a single player can use
+     */
+    setPeerPermissions(peerId: PeerId, permissions: PeerPermissions): void {
+        if (!this.isHost()) {
+            console.warn("Only the host can change the permissions of players")
+            return
+        }
+        this.sendWsMessage({
+            SetPeerPermissions: {
+                peer_id: peerId,
+                permissions
+            }
+        })
+    }
+
+    getSpectatorDelaySeconds(): number {
+        return this.spectatorDelaySeconds
+    }
+
+    /**
+     * Host-only: Delay the stream of all spectators, 0 disables the delay
+     */
+    setSpectatorDelay(seconds: number): void {
+        if (!this.isHost()) {
+            console.warn("Only the host can change the spectator delay")
+            return
+        }
+        this.sendWsMessage({
+            SetSpectatorDelay: {
+                seconds
+            }
+        })
+    }
+
+    /**
+     * Host-only: Create a single use invite which puts the guest into the slot with the name,
+     * the token is received in a "slotInviteCreated" event
+     */
+    createSlotInvite(slot: PlayerSlot, name: string, expiresInSeconds: number): void {
+        if (!this.isHost()) {
+            console.warn("Only the host can create slot invites")
+            return
+        }
+        this.sendWsMessage({
+            CreateSlotInvite: {
+                slot,
+                name,
+                expires_in: expiresInSeconds
+            }
+        })
+    }
+
+    /**
+     * Host-only: Invalidate a slot invite which wasn't used yet
+     */
+    revokeInvite(token: string): void {
+        if (!this.isHost()) {
+            console.warn("Only the host can revoke invites")
+            return
+        }
+        this.sendWsMessage({
+            RevokeInvite: {
+                token
+            }
+        })
+    }
+
+    /**
+     * Host-only: Change how many spectators can watch at the same time
+     */
+    setMaxSpectators(maxSpectators: number): void {
+        if (!this.isHost()) {
+            console.warn("Only the host can change the spectator limit")
+            return
+        }
+        this.sendWsMessage({
+            SetMaxSpectators: {
+                max_spectators: maxSpectators
+            }
+        })
+    }
+
+    getControllerMode(): ControllerMode {
+        return this.roomInfo?.controller_mode ?? "PerPlayer"
+    }
+
+    /**
+     * Host-only: Change how the gamepads of the players are mapped, "Shared" lets all players control the first gamepad
+     */
+    setControllerMode(mode: ControllerMode): void {
+        if (!this.isHost()) {
+            console.warn("Only the host can change the controller mode")
+            return
+        }
+        this.sendWsMessage({
+            SetControllerMode: {
+                mode
+            }
+        })
+    }
+
+    /**
+     * Host-only: Set whether a player can use multiple gamepads from one browser, each one takes a player slot
+     */
+    setMultipleGamepadsPerPeer(enabled: boolean): void {
+        if (!this.isHost()) {
+            console.warn("Only the host can allow multiple gamepads per player")
+            return
+        }
+        this.sendWsMessage({
+            SetMultipleGamepadsPerPeer: {
+                enabled
+            }
+        })
+    }
+
+    /**
+     * Tell the room how many gamepads are connected, every additional one gets a player slot if the host allows it
+     */
+    syncLocalGamepadCount(): void {
+        if (!this.roomInfo?.allow_multiple_gamepads_per_peer) {
+            // The room released the slots of the additional gamepads
+            this.localGamepadCount = null
+            return
+        }
+
+        const count = this.input.getGamepadCount()
+        if (count == this.localGamepadCount) {
+            return
+        }
+        this.localGamepadCount = count
+
+        this.sendWsMessage({
+            SetLocalGamepadCount: {
+                count
+            }
+        })
+    }
+
+    /**
+     * Host-only: Stop the stream for everyone, optionally quitting the app on the host
+     */
+    stopStream(quitApp: boolean): void {
+        if (!this.isHost()) {
+            console.warn("Only the host can stop the stream")
+            return
+        }
+        this.sendWsMessage({
+            StopStream: {
+                quit_app: quitApp
+            }
+        })
+    }
+
+    /**
+     * Host-only: Stop sending video and audio to everyone without ending the stream
+     */
+    setStreamPaused(paused: boolean): void {
+        if (!this.isHost()) {
+            console.warn("Only the host can pause the stream")
+            return
+        }
+        this.sendWsMessage(paused ? "PauseStream" : "ResumeStream")
+    }
+
     /**
      * Create a Stream that joins an existing room
      */
@@ -734,7 +1124,7 @@ export class Stream implements Component {
         settings: Settings,
         viewerScreenSize: [number, number]
     ): Stream {
-        const stream = new Stream(api, 0, 0, settings, viewerScreenSize)
+        const stream = new Stream(api, 0, 0, null, settings, viewerScreenSize)
 
         // Override the init message with a join room message
         stream.wsSendBuffer.length = 0 // Clear the Init message
@@ -742,6 +1132,7 @@ export class Stream implements Component {
             JoinRoom: {
                 room_id: roomId,
                 player_name: playerName,
+                discord_access_token: null,
                 video_frame_queue_size: settings.videoFrameQueueSize,
                 audio_sample_queue_size: settings.audioSampleQueueSize,
             }
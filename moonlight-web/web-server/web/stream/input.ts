@@ -1,4 +1,4 @@
-import { StreamCapabilities, StreamControllerCapabilities, StreamMouseButton, TransportChannelId } from "../api_bindings.js"
+import { GeneralClientMessage, StreamCapabilities, StreamControllerCapabilities, StreamMouseButton, TransportChannelId } from "../api_bindings.js"
 import { ByteBuffer, I16_MAX, U16_MAX, U8_MAX } from "./buffer.js"
 import { ControllerConfig, emptyGamepadState, extractGamepadState, GamepadState, SUPPORTED_BUTTONS } from "./gamepad.js"
 import { convertToKey, convertToModifiers } from "./keyboard.js"
@@ -71,6 +71,7 @@ export class StreamInput {
     // Size of the streamer device
     private streamerSize: [number, number] = [0, 0]
 
+    private general: DataTransportChannel | null = null
     private keyboard: DataTransportChannel | null = null
     private mouseReliable: DataTransportChannel | null = null
     private mouseAbsolute: DataTransportChannel | null = null
@@ -96,6 +97,10 @@ export class StreamInput {
         throw `Failed to get channel ${id} as data transport channel`
     }
     setTransport(transport: Transport) {
+        this.general = this.getDataChannel(transport, TransportChannelId.GENERAL)
+        // The streamer accepts both until we tell it otherwise
+        this.relativeMouse = null
+
         this.keyboard = this.getDataChannel(transport, TransportChannelId.KEYBOARD)
 
         this.mouseReliable = this.getDataChannel(transport, TransportChannelId.MOUSE_RELIABLE)
@@ -125,6 +130,8 @@ export class StreamInput {
     setConfig(config: StreamInputConfig) {
         Object.assign(this.config, config)
 
+        this.setRelativeMouse(this.relativeMouseOf(this.config.mouseMode))
+
         // Touch
         this.primaryTouch = null
         this.touchTracker.clear()
@@ -191,7 +198,10 @@ export class StreamInput {
                 convertToModifiers(event).toString(16)
             )
         }
-        this.sendKey(isDown, key, modifiers)
+        // The character typed on the layout of the client, used by the text fallback keyboard mode
+        const text = [...event.key].length == 1 ? event.key : undefined
+
+        this.sendKey(isDown, key, modifiers, text)
     }
 
     raiseAllKeys() {
@@ -202,7 +212,7 @@ export class StreamInput {
     }
 
     // Note: key = StreamKeys.VK_, modifiers = StreamKeyModifiers.
-    sendKey(isDown: boolean, key: number, modifiers: number) {
+    sendKey(isDown: boolean, key: number, modifiers: number, text?: string) {
         this.buffer.reset()
 
         this.buffer.putU8(0)
@@ -211,6 +221,12 @@ export class StreamInput {
         this.buffer.putU8(modifiers)
         this.buffer.putU16(key)
 
+        if (text != null) {
+            // The streamer reads the text by characters
+            this.buffer.putU8([...text].length)
+            this.buffer.putUtf8Raw(text)
+        }
+
         trySendChannel(this.keyboard, this.buffer)
     }
     sendText(text: string) {
@@ -222,6 +238,46 @@ export class StreamInput {
         trySendChannel(this.keyboard, this.buffer)
     }
 
+    // -- Mouse Mode
+    // The streamer ignores the movement which doesn't match this, null if both are sent
+    private relativeMouse: boolean | null = null
+
+    private relativeMouseOf(mouseMode: MouseMode): boolean | null {
+        if (mouseMode == "relative") {
+            return true
+        } else if (mouseMode == "follow") {
+            return false
+        }
+        // Point and drag sends positions and movement
+        return null
+    }
+    private setRelativeMouse(relative: boolean | null) {
+        if (this.relativeMouse === relative) {
+            return
+        }
+        this.relativeMouse = relative
+
+        this.sendGeneralMessage({ SetMouseMode: { relative } })
+    }
+    onMouseModeChanged(relative: boolean | null) {
+        // Another mode was requested in the meantime or the request was lost, e.g. while the transport changed
+        if (relative !== this.relativeMouse) {
+            this.sendGeneralMessage({ SetMouseMode: { relative: this.relativeMouse } })
+        }
+    }
+
+    sendGeneralMessage(message: GeneralClientMessage) {
+        const text = JSON.stringify(message)
+
+        this.buffer.reset()
+
+        // The streamer reads the text by characters
+        this.buffer.putU16([...text].length)
+        this.buffer.putUtf8Raw(text)
+
+        trySendChannel(this.general, this.buffer)
+    }
+
     // -- Mouse
     onMouseDown(event: MouseEvent, rect: DOMRect) {
         const button = convertToButton(event)
@@ -248,6 +304,9 @@ export class StreamInput {
         }
     }
     onMouseMove(event: MouseEvent, rect: DOMRect) {
+        // A touch could have changed the mode
+        this.setRelativeMouse(this.relativeMouseOf(this.config.mouseMode))
+
         if (this.config.mouseMode == "relative") {
             this.sendMouseMoveClientCoordinates(event.movementX, event.movementY, rect)
         } else if (this.config.mouseMode == "follow") {
@@ -403,6 +462,9 @@ export class StreamInput {
                 this.sendTouch(0, touch, rect)
             }
         } else if (this.config.touchMode == "mouseRelative" || this.config.touchMode == "pointAndDrag") {
+            // Touches send positions and movement
+            this.setRelativeMouse(null)
+
             for (const touch of event.changedTouches) {
                 if (this.primaryTouch == null) {
                     this.primaryTouch = touch.identifier
@@ -695,7 +757,7 @@ export class StreamInput {
             }
         }
 
-        this.sendControllerAdd(this.gamepads.length - 1, SUPPORTED_BUTTONS, capabilities)
+        this.sendControllerAdd(id, SUPPORTED_BUTTONS, capabilities)
 
         if (gamepad.mapping != "standard") {
             console.warn(`[Gamepad]: Unable to read values of gamepad with mapping ${gamepad.mapping}`)
@@ -704,14 +766,23 @@ export class StreamInput {
     onGamepadDisconnect(event: GamepadEvent) {
         const index = this.gamepads.findIndex(value => value?.gamepadIndex == event.gamepad.index)
         if (index != -1) {
-            const id = this.gamepads[index]?.gamepadIndex
-            if (id != null) {
-                this.sendControllerRemove(id)
-            }
+            this.sendControllerRemove(index)
 
             this.gamepads[index] = null
         }
     }
+    /**
+     * How many gamepad ids are in use, including disconnected gamepads in front of the last one
+     */
+    getGamepadCount(): number {
+        let count = 0
+        for (let id = 0; id < this.gamepads.length; id++) {
+            if (this.gamepads[id] != null) {
+                count = id + 1
+            }
+        }
+        return count
+    }
 
     private lastGamepadUpdate: number = performance.now()
     onGamepadUpdate() {
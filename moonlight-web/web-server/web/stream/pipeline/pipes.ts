@@ -21,6 +21,7 @@ export function addPipePassthrough(pipe: Pipe, overwrite?: Array<string>) {
     passthrough("setup", false)
     passthrough("cleanup", false)
     passthrough("getStreamRect", false)
+    passthrough("setFrameRenderedListener", false)
     passthrough("onUserInteraction", false)
     passthrough("mount", false)
     passthrough("unmount", false)
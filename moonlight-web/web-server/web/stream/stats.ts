@@ -1,7 +1,8 @@
-import { StreamerStatsUpdate, TransportChannelId } from "../api_bindings.js"
+import { StreamerStatsType, StreamerStatsUpdate, StreamStageTiming, TransportChannelId } from "../api_bindings.js"
 import { BIG_BUFFER, ByteBuffer } from "./buffer.js"
 import { Logger } from "./log.js"
 import { DataTransportChannel, Transport } from "./transport/index.js"
+import { VIDEO_HEADER_SIZE } from "./video/depackitize_video_pipe.js"
 
 export type StreamStatsData = {
     videoCodec: string | null
@@ -18,6 +19,11 @@ export type StreamStatsData = {
     minStreamerProcessingTimeMs: number | null
     maxStreamerProcessingTimeMs: number | null
     avgStreamerProcessingTimeMs: number | null
+    frameLatencyP50Ms: number | null
+    frameLatencyP95Ms: number | null
+    frameLatencyP99Ms: number | null
+    frameLatencySamples: number | null
+    stageTimings: Array<StreamStageTiming>
     transport: Record<string, string>
 }
 
@@ -37,7 +43,11 @@ audio pipeline: ${statsData.audioPipeline}
 streamer round trip time: ${num(statsData.streamerRttMs, "ms")} (variance: ${num(statsData.streamerRttVarianceMs, "ms")})
 host processing latency min/max/avg: ${num(statsData.minHostProcessingLatencyMs, "ms")} / ${num(statsData.maxHostProcessingLatencyMs, "ms")} / ${num(statsData.avgHostProcessingLatencyMs, "ms")}
 streamer processing latency min/max/avg: ${num(statsData.minStreamerProcessingTimeMs, "ms")} / ${num(statsData.maxStreamerProcessingTimeMs, "ms")} / ${num(statsData.avgStreamerProcessingTimeMs, "ms")}
+streamer to screen latency p50/p95/p99: ${num(statsData.frameLatencyP50Ms, "ms")} / ${num(statsData.frameLatencyP95Ms, "ms")} / ${num(statsData.frameLatencyP99Ms, "ms")} (${statsData.frameLatencySamples ?? 0} frames)
 `
+    if (statsData.stageTimings.length > 0) {
+        text += `stream start: ${statsData.stageTimings.map(timing => `${timing.stage} ${timing.duration_ms}ms`).join(", ")}\n`
+    }
     for (const key in statsData.transport) {
         const value = statsData.transport[key]
         let valuePretty = value
@@ -52,6 +62,13 @@ streamer processing latency min/max/avg: ${num(statsData.minStreamerProcessingTi
     return text
 }
 
+// How often the streamer sends its stats while they are shown
+const STATS_INTERVAL_MS = 1000
+// Every how many shown frames one is reported to the streamer for the latency stats
+const FRAME_SAMPLE_INTERVAL = 10
+// How many frame ids of the video packets are remembered until their frame is shown
+const FRAME_IDS_CAPACITY = 256
+
 export class StreamStats {
 
     private logger: Logger | null = null
@@ -60,6 +77,13 @@ export class StreamStats {
     private transport: Transport | null = null
     private statsChannel: DataTransportChannel | null = null
     private updateIntervalId: number | null = null
+    // The streamer only sends stats after they were requested
+    private availableStats: Array<StreamerStatsType> = []
+    private requestBuffer: ByteBuffer = new ByteBuffer(16)
+    // The frame ids of the received video packets by their presentation time, only used by data video channels
+    private frameIds: Map<number, number> = new Map()
+    private renderedFrames: number = 0
+    private onRawDataListener = this.onRawData.bind(this)
 
     private statsData: StreamStatsData = {
         videoCodec: null,
@@ -76,6 +100,11 @@ export class StreamStats {
         minStreamerProcessingTimeMs: null,
         maxStreamerProcessingTimeMs: null,
         avgStreamerProcessingTimeMs: null,
+        frameLatencyP50Ms: null,
+        frameLatencyP95Ms: null,
+        frameLatencyP99Ms: null,
+        frameLatencySamples: null,
+        stageTimings: [],
         transport: {}
     }
 
@@ -86,26 +115,34 @@ export class StreamStats {
     }
 
     setTransport(transport: Transport) {
+        if (this.statsChannel) {
+            this.statsChannel.removeReceiveListener(this.onRawDataListener)
+            this.statsChannel = null
+        }
         this.transport = transport
 
         this.checkEnabled()
     }
+    setAvailableStats(stats: Array<StreamerStatsType>) {
+        this.availableStats = stats
+
+        this.requestStats()
+    }
     private checkEnabled() {
-        if (this.enabled) {
-            if (this.statsChannel) {
-                this.statsChannel.removeReceiveListener(this.onRawData.bind(this))
-                this.statsChannel = null
+        // The channel is also needed to stop the stats
+        if (!this.statsChannel && this.transport) {
+            const channel = this.transport.getChannel(TransportChannelId.STATS)
+            if (channel.type != "data") {
+                this.logger?.debug(`Failed initialize debug transport channel because type is "${channel.type}" and not "data"`)
+                return
             }
+            channel.addReceiveListener(this.onRawDataListener)
+            this.statsChannel = channel
+        }
 
-            if (!this.statsChannel && this.transport) {
-                const channel = this.transport.getChannel(TransportChannelId.STATS)
-                if (channel.type != "data") {
-                    this.logger?.debug(`Failed initialize debug transport channel because type is "${channel.type}" and not "data"`)
-                    return
-                }
-                channel.addReceiveListener(this.onRawData.bind(this))
-                this.statsChannel = channel
-            }
+        this.requestStats()
+
+        if (this.enabled) {
             if (this.updateIntervalId == null) {
                 this.updateIntervalId = setInterval(this.updateLocalStats.bind(this), 1000)
             }
@@ -116,6 +153,22 @@ export class StreamStats {
             }
         }
     }
+    private requestStats() {
+        if (!this.statsChannel || this.availableStats.length == 0) {
+            return
+        }
+
+        this.requestBuffer.reset()
+
+        this.requestBuffer.putU8(0)
+        // Zero stops the stats
+        this.requestBuffer.putU32(this.enabled ? STATS_INTERVAL_MS : 0)
+        // Zero stops the reports of the shown frames
+        this.requestBuffer.putU16(this.enabled && this.availableStats.includes("FrameLatency") ? FRAME_SAMPLE_INTERVAL : 0)
+
+        this.requestBuffer.flip()
+        this.statsChannel.send(this.requestBuffer.getRemainingBuffer().buffer)
+    }
 
     setEnabled(enabled: boolean) {
         this.enabled = enabled
@@ -160,7 +213,63 @@ export class StreamStats {
             this.statsData.minStreamerProcessingTimeMs = msg.Video.min_streamer_processing_time_ms
             this.statsData.maxStreamerProcessingTimeMs = msg.Video.max_streamer_processing_time_ms
             this.statsData.avgStreamerProcessingTimeMs = msg.Video.avg_streamer_processing_time_ms
+        } else if ("FrameLatency" in msg) {
+            this.statsData.frameLatencyP50Ms = msg.FrameLatency.p50_ms
+            this.statsData.frameLatencyP95Ms = msg.FrameLatency.p95_ms
+            this.statsData.frameLatencyP99Ms = msg.FrameLatency.p99_ms
+            this.statsData.frameLatencySamples = msg.FrameLatency.samples
+        }
+    }
+
+    /// Remembers the frame id of a video packet of a data channel, the renderer only knows the presentation time of its frame
+    onVideoPacket(data: ArrayBuffer) {
+        if (!this.enabled || data.byteLength < VIDEO_HEADER_SIZE) {
+            return
+        }
+
+        const view = new DataView(data)
+        const timestamp = view.getUint32(1)
+        const frameId = view.getUint32(5)
+
+        if (this.frameIds.size >= FRAME_IDS_CAPACITY) {
+            // The oldest entry
+            const oldest = this.frameIds.keys().next().value
+            if (oldest != undefined) {
+                this.frameIds.delete(oldest)
+            }
         }
+        this.frameIds.set(timestamp, frameId)
+    }
+    /// A frame of a data video channel was shown, the timestamp is its presentation time in microseconds
+    onDataFrameRendered(timestamp: number, renderTime: number) {
+        const frameId = this.frameIds.get(timestamp)
+        if (frameId == undefined) {
+            return
+        }
+        this.frameIds.delete(timestamp)
+
+        this.onFrameRendered(frameId, renderTime)
+    }
+    /// A frame was shown, the id is the frame id or the rtp timestamp for video tracks
+    onFrameRendered(frameId: number, renderTime: number) {
+        if (!this.enabled || !this.statsChannel || !this.availableStats.includes("FrameLatency")) {
+            return
+        }
+
+        this.renderedFrames += 1
+        if (this.renderedFrames % FRAME_SAMPLE_INTERVAL != 0) {
+            return
+        }
+
+        this.requestBuffer.reset()
+
+        this.requestBuffer.putU8(1)
+        this.requestBuffer.putU32(frameId)
+        // Only the differences between the render times are used by the streamer
+        this.requestBuffer.putU32(Math.floor(renderTime) >>> 0)
+
+        this.requestBuffer.flip()
+        this.statsChannel.send(this.requestBuffer.getRemainingBuffer().buffer)
     }
 
     private async updateLocalStats() {
@@ -183,6 +292,9 @@ export class StreamStats {
         this.statsData.videoHeight = height
         this.statsData.videoFps = fps
     }
+    setStageTimings(timings: Array<StreamStageTiming>) {
+        this.statsData.stageTimings = timings
+    }
     setVideoPipelineName(name: string) {
         this.statsData.videoPipeline = name
     }
@@ -27,6 +27,7 @@ export type TransportChannelOption = {
     ordered: boolean
     reliable: boolean
 }
+// Only used for channels the server didn't send options for in the Setup message
 export const TRANSPORT_CHANNEL_OPTIONS: Record<keyof typeof TransportChannelId, TransportChannelOption> = {
     GENERAL: { reliable: true, ordered: true },
     STATS: { reliable: true, ordered: true },
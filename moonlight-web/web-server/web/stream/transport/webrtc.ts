@@ -1,7 +1,7 @@
-import { StreamSignalingMessage, TransportChannelId } from "../../api_bindings.js";
+import { DataChannelOptions, StreamSignalingMessage, TransportChannelId } from "../../api_bindings.js";
 import { Logger } from "../log.js";
 import { allVideoCodecs, CAPABILITIES_CODECS, emptyVideoCodecs, maybeVideoCodecs, VideoCodecSupport } from "../video.js";
-import { DataTransportChannel, Transport, TRANSPORT_CHANNEL_OPTIONS, TransportAudioSetup, TransportChannel, TransportChannelIdKey, TransportChannelIdValue, TransportVideoSetup, AudioTrackTransportChannel, VideoTrackTransportChannel, TrackTransportChannel, TransportShutdown } from "./index.js";
+import { DataTransportChannel, Transport, TRANSPORT_CHANNEL_OPTIONS, TransportAudioSetup, TransportChannelOption, TransportChannel, TransportChannelIdKey, TransportChannelIdValue, TransportVideoSetup, AudioTrackTransportChannel, VideoTrackTransportChannel, TrackTransportChannel, TransportShutdown } from "./index.js";
 
 export class WebRTCTransport implements Transport {
     implementationName: string = "webrtc"
@@ -9,9 +9,12 @@ export class WebRTCTransport implements Transport {
     private logger: Logger | null
 
     private peer: RTCPeerConnection | null = null
+    private dataChannelOptions: Record<string, DataChannelOptions>
 
-    constructor(logger?: Logger) {
+    // The data channel options are sent by the server, channels without options use TRANSPORT_CHANNEL_OPTIONS
+    constructor(logger?: Logger, dataChannelOptions?: Record<string, DataChannelOptions>) {
         this.logger = logger ?? null
+        this.dataChannelOptions = dataChannelOptions ?? {}
     }
 
     async initPeer(configuration?: RTCConfiguration) {
@@ -289,15 +292,30 @@ export class WebRTCTransport implements Transport {
             }
 
             const id = TransportChannelId[channel]
-            const dataChannel = this.peer.createDataChannel(channel.toLowerCase(), {
-                ordered: options.ordered,
-                maxRetransmits: options.reliable ? undefined : 0
-            })
+            const label = channel.toLowerCase()
+            const dataChannel = this.peer.createDataChannel(label, this.getDataChannelInit(label, options))
 
             this.channels[id] = new WebRTCDataTransportChannel(channel, dataChannel)
         }
     }
 
+    private getDataChannelInit(label: string, fallback: TransportChannelOption): RTCDataChannelInit {
+        const options = this.dataChannelOptions[label]
+        if (!options) {
+            return {
+                ordered: fallback.ordered,
+                maxRetransmits: fallback.reliable ? undefined : 0
+            }
+        }
+
+        const retransmits = options.retransmits
+        return {
+            ordered: options.ordered,
+            maxRetransmits: retransmits && "MaxRetransmits" in retransmits ? retransmits.MaxRetransmits : undefined,
+            maxPacketLifeTime: retransmits && "MaxPacketLifeTime" in retransmits ? retransmits.MaxPacketLifeTime : undefined
+        }
+    }
+
     private videoTrackHolder: TrackHolder = { ontrack: null, track: null }
     private videoReceiver: RTCRtpReceiver | null = null
 
@@ -161,6 +161,19 @@ export function createSupportedVideoFormatsBits(support: VideoCodecSupport): num
     return mask
 }
 
+/// The names of all codecs in the flags of StreamSupportedVideoCodecs
+export function videoFormatNames(videoFormats: number): Array<keyof typeof StreamSupportedVideoCodecs> {
+    const names: Array<keyof typeof StreamSupportedVideoCodecs> = []
+    for (const name in StreamSupportedVideoCodecs) {
+        const key = name as keyof typeof StreamSupportedVideoCodecs
+        if ((videoFormats & StreamSupportedVideoCodecs[key]) != 0) {
+            names.push(key)
+        }
+    }
+
+    return names
+}
+
 export function getSelectedVideoCodec(videoCodec: number): keyof typeof StreamSupportedVideoCodecs | null {
     if (videoCodec == StreamSupportedVideoCodecs.H264) {
         return "H264"
@@ -1,12 +1,13 @@
 import { globalObject, Pipe, PipeInfo } from "../pipeline/index.js"
 import { allVideoCodecs } from "../video.js"
-import { FrameVideoRenderer, getStreamRectCorrected, VideoRenderer, VideoRendererSetup } from "./index.js"
+import { FrameRenderedListener, FrameVideoRenderer, getStreamRectCorrected, VideoRenderer, VideoRendererSetup } from "./index.js"
 
 export abstract class BaseCanvasVideoRenderer implements VideoRenderer {
 
     protected canvas: HTMLCanvasElement = document.createElement("canvas")
 
     private videoSize: [number, number] | null = null
+    protected frameRenderedListener: FrameRenderedListener | null = null
 
     readonly implementationName: string
 
@@ -41,6 +42,10 @@ export abstract class BaseCanvasVideoRenderer implements VideoRenderer {
         return getStreamRectCorrected(this.canvas.getBoundingClientRect(), this.videoSize)
     }
 
+    setFrameRenderedListener(listener: FrameRenderedListener | null): void {
+        this.frameRenderedListener = listener
+    }
+
     getBase(): Pipe | null {
         return null
     }
@@ -62,6 +67,7 @@ export class CanvasVideoRenderer extends BaseCanvasVideoRenderer implements Fram
     private animationFrameRequest: number | null = null
 
     private currentFrame: VideoFrame | null = null
+    private frameDrawn: boolean = false
 
     constructor() {
         super("canvas")
@@ -103,6 +109,7 @@ export class CanvasVideoRenderer extends BaseCanvasVideoRenderer implements Fram
         this.currentFrame?.close()
 
         this.currentFrame = frame
+        this.frameDrawn = false
     }
 
     private onAnimationFrame() {
@@ -115,6 +122,12 @@ export class CanvasVideoRenderer extends BaseCanvasVideoRenderer implements Fram
             // Clear the canvas before drawing the new frame to prevent artifacts
             this.context.clearRect(0, 0, this.canvas.width, this.canvas.height)
             this.context.drawImage(frame, 0, 0, this.canvas.width, this.canvas.height)
+
+            // The same frame is drawn again until a new one arrives
+            if (!this.frameDrawn) {
+                this.frameDrawn = true
+                this.frameRenderedListener?.(frame.timestamp, performance.now())
+            }
         }
 
         this.animationFrameRequest = requestAnimationFrame(this.onAnimationFrame.bind(this))
@@ -5,6 +5,9 @@ import { addPipePassthrough, DataPipe } from "../pipeline/pipes.js";
 import { allVideoCodecs } from "../video.js";
 import { DataVideoRenderer, VideoRendererSetup } from "./index.js";
 
+// Frame type, presentation time and frame id
+export const VIDEO_HEADER_SIZE = 9
+
 export class DepacketizeVideoPipe implements DataPipe {
 
     static readonly baseType = "videodata"
@@ -23,7 +26,7 @@ export class DepacketizeVideoPipe implements DataPipe {
     private base: DataVideoRenderer
 
     private frameDurationMicroseconds = 0
-    private buffer = new ByteBuffer(5)
+    private buffer = new ByteBuffer(VIDEO_HEADER_SIZE)
 
     constructor(base: DataVideoRenderer, logger?: Logger) {
         this.implementationName = `depacketize_video -> ${base.implementationName}`
@@ -37,7 +40,7 @@ export class DepacketizeVideoPipe implements DataPipe {
 
         this.buffer.reset()
 
-        this.buffer.putU8Array(array.slice(0, 5))
+        this.buffer.putU8Array(array.slice(0, VIDEO_HEADER_SIZE))
 
         this.buffer.flip()
 
@@ -46,7 +49,7 @@ export class DepacketizeVideoPipe implements DataPipe {
 
         this.base.submitDecodeUnit({
             type: frameType == 0 ? "delta" : "key",
-            data: array.slice(5).buffer,
+            data: array.slice(VIDEO_HEADER_SIZE).buffer,
             durationMicroseconds: this.frameDurationMicroseconds,
             timestampMicroseconds: timestamp,
         })
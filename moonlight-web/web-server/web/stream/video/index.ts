@@ -9,6 +9,10 @@ export type VideoRendererSetup = {
     fps: number
 }
 
+/// Called once a new frame is shown with the time it was shown at (performance.now()).
+/// The timestamp is the rtp timestamp of track frames and the presentation time in microseconds of the other frames.
+export type FrameRenderedListener = (timestamp: number, renderTime: number) => void
+
 export interface VideoRenderer extends Component, Pipe {
     readonly implementationName: string
 
@@ -20,6 +24,8 @@ export interface VideoRenderer extends Component, Pipe {
     onUserInteraction(): void
     /// Don't work inside a worker
     getStreamRect(): DOMRect
+    /// Don't work inside a worker, renderers which can't tell when a frame is shown never call it
+    setFrameRenderedListener(listener: FrameRenderedListener | null): void
 
     /// Don't work inside a worker
     mount(parent: HTMLElement): void
@@ -1,6 +1,6 @@
 import { globalObject, Pipe, PipeInfo } from "../pipeline/index.js";
 import { emptyVideoCodecs, maybeVideoCodecs, VIDEO_DECODER_CODECS, VideoCodecSupport } from "../video.js";
-import { getStreamRectCorrected, TrackVideoRenderer, VideoRenderer, VideoRendererSetup } from "./index.js";
+import { FrameRenderedListener, getStreamRectCorrected, TrackVideoRenderer, VideoRenderer, VideoRendererSetup } from "./index.js";
 
 function detectCodecs(): VideoCodecSupport {
     if (!("canPlayType" in HTMLVideoElement.prototype)) {
@@ -47,6 +47,9 @@ export class VideoElementRenderer implements TrackVideoRenderer, VideoRenderer {
 
     private size: [number, number] | null = null
 
+    private frameRenderedListener: FrameRenderedListener | null = null
+    private frameCallbackId: number | null = null
+
     constructor() {
         this.videoElement.classList.add("video-stream")
         this.videoElement.preload = "none"
@@ -113,6 +116,29 @@ export class VideoElementRenderer implements TrackVideoRenderer, VideoRenderer {
         return getStreamRectCorrected(this.videoElement.getBoundingClientRect(), this.size)
     }
 
+    setFrameRenderedListener(listener: FrameRenderedListener | null): void {
+        this.frameRenderedListener = listener
+
+        // Not supported by every browser
+        if (!("requestVideoFrameCallback" in this.videoElement)) {
+            return
+        }
+
+        if (listener && this.frameCallbackId == null) {
+            this.frameCallbackId = this.videoElement.requestVideoFrameCallback(this.onVideoFrame.bind(this))
+        } else if (!listener && this.frameCallbackId != null) {
+            this.videoElement.cancelVideoFrameCallback(this.frameCallbackId)
+            this.frameCallbackId = null
+        }
+    }
+    private onVideoFrame(_now: number, metadata: VideoFrameCallbackMetadata) {
+        if (metadata.rtpTimestamp != null) {
+            this.frameRenderedListener?.(metadata.rtpTimestamp, metadata.expectedDisplayTime)
+        }
+
+        this.frameCallbackId = this.videoElement.requestVideoFrameCallback(this.onVideoFrame.bind(this))
+    }
+
     getBase(): Pipe | null {
         return null
     }